@@ -0,0 +1,70 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How fast data chunks can be framed and pushed through a unix socket,
+//! which is what every byte of shell output goes through on its way to
+//! the client. Run with
+//!
+//!   cargo bench -p libshpool --bench chunks
+
+use std::{os::unix::net::UnixStream, thread, time::Instant};
+
+use libshpool::bench::ChunkExt as _;
+use shpool_protocol::{Chunk, ChunkKind};
+
+// Enough chunks that setting up the socket is noise, without taking
+// forever when the chunks are tiny.
+const TOTAL_BYTES: usize = 256 * 1024 * 1024;
+const MAX_CHUNKS: usize = 1_000_000;
+const RUNS: usize = 5;
+
+fn main() -> anyhow::Result<()> {
+    // Small chunks are what interactive use looks like (a keystroke
+    // echoed back), big ones are what a chatty build looks like.
+    for chunk_len in [16, 256, 4096] {
+        let chunks = (TOTAL_BYTES / chunk_len).min(MAX_CHUNKS);
+        let mut best = f64::MAX;
+        for _ in 0..RUNS {
+            best = best.min(run(chunk_len, chunks)?);
+        }
+        println!(
+            "{chunk_len} byte chunks: {:.0} MB/s, {:.0}k chunks/s",
+            (chunks * chunk_len) as f64 / best / 1_000_000.0,
+            chunks as f64 / best / 1_000.0,
+        );
+    }
+
+    Ok(())
+}
+
+/// Send the given number of chunks from one end of a socket pair to
+/// the other, returning how many seconds it took.
+fn run(chunk_len: usize, chunks: usize) -> anyhow::Result<f64> {
+    let (mut tx, mut rx) = UnixStream::pair()?;
+    let data = vec![b'x'; chunk_len];
+    let start = Instant::now();
+    let writer = thread::spawn(move || -> anyhow::Result<()> {
+        for _ in 0..chunks {
+            Chunk { kind: ChunkKind::Data, buf: &data }.write_to(&mut tx)?;
+        }
+        Ok(())
+    });
+    let mut buf = vec![0; chunk_len];
+    for _ in 0..chunks {
+        Chunk::read_into(&mut rx, &mut buf)?;
+    }
+    writer.join().expect("writer thread panicked")?;
+
+    Ok(start.elapsed().as_secs_f64())
+}
@@ -0,0 +1,53 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How fast the in-memory spools can soak up output. Run with
+//!
+//!   cargo bench -p libshpool --bench spool
+
+use std::{hint::black_box, path::Path, time::Instant};
+
+use libshpool::bench::new_spool;
+use shpool_protocol::TtySize;
+
+// Enough output to cycle even the biggest spool a few times over.
+const TOTAL_BYTES: usize = 512 * 1024 * 1024;
+const RUNS: usize = 5;
+
+fn main() -> anyhow::Result<()> {
+    let line = b"\x1b[32mok\x1b[0m some moderately long line of build output \xe2\x82\xac\r\n";
+    let write: Vec<u8> = line.iter().copied().cycle().take(4096).collect();
+    let size = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+
+    for config in ["10KB", "1MB", "100MB"] {
+        let mut best = f64::MAX;
+        let mut memory_usage = 0;
+        for _ in 0..RUNS {
+            let mut spool = new_spool(config, &size, Path::new("/nonexistent"))?;
+            let start = Instant::now();
+            for _ in 0..TOTAL_BYTES / write.len() {
+                spool.process(black_box(&write));
+            }
+            best = best.min(start.elapsed().as_secs_f64());
+            memory_usage = spool.memory_usage();
+        }
+        println!(
+            "session_restore = \"{config}\": {:.0} MB/s ({} bytes in use)",
+            TOTAL_BYTES as f64 / best / 1_000_000.0,
+            memory_usage
+        );
+    }
+
+    Ok(())
+}
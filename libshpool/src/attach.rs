@@ -12,26 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{env, fmt, io, path::PathBuf, thread, time};
+use std::{
+    cmp, env, fmt, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread, time,
+};
 
 use anyhow::{anyhow, bail, Context};
 use shpool_protocol::{
-    AttachHeader, AttachReplyHeader, ConnectHeader, DetachReply, DetachRequest, ResizeReply,
-    ResizeRequest, SessionMessageReply, SessionMessageRequest, SessionMessageRequestPayload,
-    TtySize,
+    capability, AttachHeader, AttachReplyHeader, ConnectHeader, DetachReply, DetachRequest,
+    ResizeReply, ResizeRequest, SessionMessageReply, SessionMessageRequest,
+    SessionMessageRequestPayload, TtySize,
 };
 use tracing::{error, info, warn};
 
-use super::{config, duration, protocol, protocol::ClientResult, test_hooks, tty::TtySizeExt as _};
+use super::{
+    auto_name, config, duration, list, protocol,
+    protocol::{ClientResult, PipeEnd},
+    session_restore, supervise, term_compat, test_hooks, tty,
+    tty::TtySizeExt as _,
+};
 
 const MAX_FORCE_RETRIES: usize = 20;
 
+/// How many times to ask for the passphrase of a locked session.
+const MAX_PASSPHRASE_TRIES: usize = 3;
+
+/// The session name that stands for the session most recently detached
+/// from, as in `shpool attach -`.
+pub const LAST_SESSION: &str = "-";
+
+const RECONNECT_MIN_BACKOFF: time::Duration = time::Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: time::Duration = time::Duration::from_secs(5);
+// How often to check for a ^C while waiting to reconnect.
+const RECONNECT_POLL_DUR: time::Duration = time::Duration::from_millis(50);
+
 /// Resolve the working directory for the new shell session based on priority:
 /// 1. Command line --dir parameter (highest priority)
-/// 2. Config file start_directory setting
+/// 2. Config file setting, either the session's dir or start_directory
 /// 3. Current working directory (new default behavior)
 /// 4. Home directory (fallback)
-fn resolve_working_directory(
+pub fn resolve_working_directory(
     cmd_dir: Option<&str>,
     config_directory: Option<&str>,
 ) -> anyhow::Result<PathBuf> {
@@ -44,7 +66,13 @@ fn resolve_working_directory(
     
     // 2. Config file setting
     if let Some(dir) = config_directory {
-        return Ok(PathBuf::from(dir));
+        return match dir.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                let user_info = user::info().context("getting user info")?;
+                Ok(PathBuf::from(format!("{}{}", user_info.home_dir, rest)))
+            }
+            _ => Ok(PathBuf::from(dir)),
+        };
     }
     
     // 3. Current working directory (new default behavior)
@@ -57,18 +85,37 @@ fn resolve_working_directory(
     Ok(PathBuf::from(user_info.home_dir))
 }
 
+#[derive(Default)]
 pub struct AttachOptions {
     pub name: String,
+    /// Ignore `name` and make one up for a new session.
+    pub auto_name: bool,
     pub force: bool,
+    /// Never take the session over, even if the config says to.
+    pub no_steal: bool,
+    pub mirror: bool,
+    pub read_only: bool,
+    pub auto_reconnect: bool,
     pub ttl: Option<String>,
+    pub idle_ttl: Option<String>,
     pub cmd: Option<String>,
+    /// When to restart `cmd` if it exits.
+    pub restart: Option<supervise::Policy>,
     pub dir: Option<String>,
+    pub shell: Option<String>,
     pub restore: Option<String>,
+    pub tags: Vec<String>,
+    pub log_output: Option<String>,
+    pub no_motd: bool,
+    /// The session we are switching away from, if any.
+    pub previous: Option<String>,
+    /// The passphrase to unlock the session with, if it is locked.
+    pub passphrase: Option<String>,
 }
 
 pub fn run(
     config_manager: config::Manager,
-    options: AttachOptions,
+    mut options: AttachOptions,
     socket: PathBuf,
 ) -> anyhow::Result<()> {
     info!("\n\n======================== STARTING ATTACH ============================\n\n");
@@ -80,6 +127,20 @@ pub fn run(
         std::process::exit(1);
     }
 
+    if options.auto_name {
+        let sessions = list::fetch(socket.clone(), &[]).context("listing sessions")?;
+        let scheme = config_manager.get().auto_name.unwrap_or_default();
+        let cwd = env::current_dir().ok();
+        options.name = auto_name::generate(scheme, cwd.as_deref(), &sessions);
+        eprintln!("shpool: attaching to new session '{}'", options.name);
+    } else if options.name == LAST_SESSION {
+        let sessions = list::fetch(socket.clone(), &[]).context("listing sessions")?;
+        match list::last_detached(&sessions) {
+            Some(session) => options.name = session.name.clone(),
+            None => bail!("no recently detached session to attach to"),
+        }
+    }
+
     if options.name.is_empty() {
         eprintln!("blank session names are not allowed");
         return Ok(());
@@ -88,10 +149,20 @@ pub fn run(
         eprintln!("whitespace is not allowed in session names");
         return Ok(());
     }
+    if let Some(restore) = &options.restore
+        && let Err(e) = session_restore::replay::Override::parse(restore)
+    {
+        eprintln!("bad --restore value '{restore}': {e:#}");
+        return Ok(());
+    }
 
-    SignalHandler::new(options.name.clone(), socket.clone()).spawn()?;
+    // The session can get renamed out from under us while we are
+    // attached, so the name gets shared with the signal handler and
+    // updated if the daemon tells us about a rename.
+    let session_name = Arc::new(Mutex::new(options.name.clone()));
+    SignalHandler::new(Arc::clone(&session_name), socket.clone()).spawn()?;
 
-    let ttl = match &options.ttl {
+    let mut ttl = match &options.ttl {
         Some(src) => match duration::parse(src.as_str()) {
             Ok(d) => Some(d),
             Err(e) => {
@@ -100,10 +171,85 @@ pub fn run(
         },
         None => None,
     };
+    let mut idle_ttl = match &options.idle_ttl {
+        Some(src) => match duration::parse(src.as_str()) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                bail!("could not parse idle ttl: {:?}", e);
+            }
+        },
+        None => None,
+    };
+
+    if !options.no_steal && config_manager.get().force_attach.unwrap_or(false) {
+        options.force = true;
+    }
 
+    let mut reconnect = Reconnect::new(options.auto_reconnect);
     let mut detached = false;
     let mut tries = 0;
-    while let Err(err) = do_attach(&config_manager, &options, &ttl, &socket) {
+    let mut passphrase_tries = 0;
+    loop {
+        let err = match do_attach(
+            &config_manager,
+            &options,
+            &ttl,
+            &idle_ttl,
+            &session_name,
+            &socket,
+            &mut reconnect,
+        ) {
+            Ok(target) => {
+                // The flags passed to this attach were about the old
+                // session, so don't apply them to the one we switch to.
+                info!("switching to '{}'", target);
+                let previous =
+                    std::mem::replace(&mut *session_name.lock().unwrap(), target.clone());
+                options = AttachOptions {
+                    name: target,
+                    auto_reconnect: options.auto_reconnect,
+                    previous: Some(previous),
+                    ..Default::default()
+                };
+                ttl = None;
+                idle_ttl = None;
+                detached = false;
+                tries = 0;
+                continue;
+            }
+            Err(err) => err,
+        };
+
+        if reconnect.should_retry(&err) {
+            if !reconnect.wait() {
+                drop(reconnect.tty_guard.take());
+                eprintln!("\nshpool: gave up reconnecting to the daemon");
+                return Ok(());
+            }
+            continue;
+        }
+
+        let err = match err.downcast::<LockedError>() {
+            Ok(LockedError { wrong_passphrase }) => {
+                // Once the terminal belongs to a session we have no
+                // way to prompt, which can happen after a switch.
+                let can_prompt = reconnect.stdin.is_none();
+                drop(reconnect.tty_guard.take());
+                if wrong_passphrase {
+                    eprintln!("wrong passphrase");
+                }
+                if !can_prompt || passphrase_tries >= MAX_PASSPHRASE_TRIES {
+                    eprintln!("session '{}' is locked", options.name);
+                    return Err(anyhow!("session '{}' is locked", options.name));
+                }
+                passphrase_tries += 1;
+                let prompt = format!("passphrase for '{}': ", options.name);
+                options.passphrase = Some(tty::read_passphrase(&prompt)?);
+                continue;
+            }
+            Err(err) => err,
+        };
+
         match err.downcast() {
             Ok(BusyError) if !options.force => {
                 eprintln!("session '{}' already has a terminal attached", options.name);
@@ -111,10 +257,12 @@ pub fn run(
             }
             Ok(BusyError) => {
                 if !detached {
-                    let mut client = dial_client(&socket)?;
+                    let mut client = dial_client(&socket, true)?;
                     client
                         .write_connect_header(ConnectHeader::Detach(DetachRequest {
                             sessions: vec![options.name.clone()],
+                            tags: vec![],
+                            taken_over_by: Some(describe_client()),
                         }))
                         .context("writing detach request header")?;
                     let detach_reply: DetachReply = client.read_reply().context("reading reply")?;
@@ -135,8 +283,6 @@ pub fn run(
             Err(err) => return Err(err),
         }
     }
-
-    Ok(())
 }
 
 #[derive(Debug)]
@@ -148,56 +294,231 @@ impl fmt::Display for BusyError {
 }
 impl std::error::Error for BusyError {}
 
-fn do_attach(
-    config: &config::Manager,
-    options: &AttachOptions,
-    ttl: &Option<time::Duration>,
-    socket: &PathBuf,
-) -> anyhow::Result<()> {
-    let mut client = dial_client(socket)?;
+#[derive(Debug)]
+struct LockedError {
+    wrong_passphrase: bool,
+}
+impl fmt::Display for LockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LockedError")
+    }
+}
+impl std::error::Error for LockedError {}
 
-    let tty_size = match TtySize::from_fd(0) {
+#[derive(Debug)]
+struct ConnectionLost;
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ConnectionLost")
+    }
+}
+impl std::error::Error for ConnectionLost {}
+
+/// The state which has to outlive any single connection to the daemon,
+/// both so that `--auto-reconnect` can reconnect and so that we can
+/// attach to another session when the user switches.
+struct Reconnect {
+    /// True if we should reconnect when we lose the connection.
+    enabled: bool,
+    // Both of these get set up the first time we successfully attach,
+    // so errors before then get reported as usual rather than retried.
+    stdin: Option<protocol::StdinForwarder>,
+    tty_guard: Option<tty::AttachFlagsGuard<'static>>,
+    backoff: time::Duration,
+    reconnecting: bool,
+}
+
+impl Reconnect {
+    fn new(enabled: bool) -> Self {
+        Reconnect {
+            enabled,
+            stdin: None,
+            tty_guard: None,
+            backoff: RECONNECT_MIN_BACKOFF,
+            reconnecting: false,
+        }
+    }
+
+    /// Called once the daemon has accepted an attach. Returns the
+    /// forwarder to hook the new connection up to.
+    fn attached(&mut self) -> anyhow::Result<&protocol::StdinForwarder> {
+        if self.tty_guard.is_none() {
+            self.tty_guard = Some(tty::set_attach_flags()?);
+        }
+        if self.stdin.is_none() {
+            self.stdin = Some(protocol::StdinForwarder::spawn()?);
+        }
+        if self.reconnecting {
+            info!("reconnected");
+            self.reconnecting = false;
+        }
+        self.backoff = RECONNECT_MIN_BACKOFF;
+        Ok(self.stdin.as_ref().unwrap())
+    }
+
+    /// True if the error means we lost track of the daemon rather than
+    /// that the daemon turned us away.
+    fn should_retry(&self, err: &anyhow::Error) -> bool {
+        if !self.enabled || self.stdin.is_none() {
+            return false;
+        }
+        // A busy session is most likely still holding on to our own
+        // dead connection, which the daemon will notice soon enough.
+        err.is::<ConnectionLost>()
+            || err.is::<BusyError>()
+            || err.chain().any(|e| e.is::<io::Error>() || e.is::<rmp_serde::decode::Error>())
+    }
+
+    /// Wait before the next attempt, returning false if the user hit ^C
+    /// to give up instead.
+    fn wait(&mut self) -> bool {
+        if !self.reconnecting {
+            eprint!(
+                "\r\nshpool: lost connection to the daemon, reconnecting (^C to give up)...\r\n"
+            );
+            self.reconnecting = true;
+        }
+        info!("reconnecting in {:?}", self.backoff);
+
+        let deadline = time::Instant::now() + self.backoff;
+        while time::Instant::now() < deadline {
+            if self.stdin.as_ref().map(|s| s.is_interrupted()).unwrap_or(false) {
+                return false;
+            }
+            thread::sleep(RECONNECT_POLL_DUR);
+        }
+        self.backoff = cmp::min(self.backoff * 2, RECONNECT_MAX_BACKOFF);
+        true
+    }
+}
+
+/// The size of the terminal we are running in, or a standard 80x24 if
+/// stdin is not a terminal.
+pub fn local_tty_size() -> TtySize {
+    match TtySize::from_fd(0) {
         Ok(s) => s,
         Err(e) => {
             warn!("stdin is not a tty, using default size (err: {e:?})");
             TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 }
         }
-    };
+    }
+}
 
-    let forward_env = config.get().forward_env.clone();
-    let mut local_env_keys = vec!["TERM", "DISPLAY", "LANG", "SSH_AUTH_SOCK"];
-    if let Some(fenv) = &forward_env {
-        for var in fenv.iter() {
+/// The variables from our environment that get passed along to the
+/// daemon when creating or attaching to a session.
+pub fn local_env(config: &config::Config) -> Vec<(String, String)> {
+    let mut local_env_keys = vec![
+        "TERM",
+        "COLORTERM",
+        "DISPLAY",
+        "WAYLAND_DISPLAY",
+        "XAUTHORITY",
+        "LANG",
+        "SSH_AUTH_SOCK",
+    ];
+    for var in config
+        .forward_env
+        .iter()
+        .chain(config.refresh_env.iter())
+        .chain(config.forward_sockets.iter())
+        .flatten()
+    {
+        if !local_env_keys.contains(&var.as_str()) {
             local_env_keys.push(var);
         }
     }
+    local_env_keys
+        .into_iter()
+        .filter_map(|var| {
+            let val = env::var(var).context("resolving var").ok()?;
+            Some((String::from(var), val))
+        })
+        .collect::<Vec<_>>()
+}
+
+/// Attach to the session and pump bytes until it is over. Since the
+/// process exits once the session is done, this only returns if
+/// something goes wrong or the user asks to switch to another session,
+/// in which case it returns the name of that session.
+fn do_attach(
+    config: &config::Manager,
+    options: &AttachOptions,
+    ttl: &Option<time::Duration>,
+    idle_ttl: &Option<time::Duration>,
+    session_name: &Mutex<String>,
+    socket: &PathBuf,
+    reconnect: &mut Reconnect,
+) -> anyhow::Result<String> {
+    // Once we have attached, stdin belongs to the forwarder thread
+    // and the terminal is in raw mode, so we can't prompt the user.
+    let interactive = reconnect.stdin.is_none();
+    let mut client = dial_client(socket, interactive)?;
+    if options.mirror {
+        client.require(capability::MIRROR, "attaching alongside another client")?;
+    }
+    if options.read_only {
+        client.require(capability::READ_ONLY, "attaching read-only")?;
+    }
+    if options.shell.is_some() {
+        client.require(capability::SHELL, "picking the shell with --shell")?;
+    }
+    if options.restart.is_some() {
+        client.require(capability::RESTART, "restarting the command with --restart")?;
+    }
+
+    let tty_size = local_tty_size();
 
     // Resolve the working directory based on priority
     let config_binding = config.get();
-    let config_start_dir = config_binding.start_directory.as_deref();
+    let name = session_name.lock().unwrap().clone();
+    let config_start_dir = config_binding
+        .session(&name)
+        .and_then(|s| s.dir.as_deref())
+        .or(config_binding.start_directory.as_deref());
     let working_directory = resolve_working_directory(options.dir.as_deref(), config_start_dir)
         .context("resolving working directory")?;
+    // The daemon has its own working directory, so relative paths
+    // need to be resolved here.
+    let log_output = match &options.log_output {
+        Some(dir) => Some(
+            std::path::absolute(dir).context("resolving log output dir")?.to_string_lossy().into(),
+        ),
+        None => None,
+    };
 
     client
         .write_connect_header(ConnectHeader::Attach(AttachHeader {
-            name: options.name.clone(),
+            name,
             local_tty_size: tty_size,
-            local_env: local_env_keys
-                .into_iter()
-                .filter_map(|var| {
-                    let val = env::var(var).context("resolving var").ok()?;
-                    Some((String::from(var), val))
-                })
-                .collect::<Vec<_>>(),
+            local_env: local_env(&config_binding),
             ttl_secs: ttl.map(|d| d.as_secs()),
+            idle_ttl_secs: idle_ttl.map(|d| d.as_secs()),
             cmd: options.cmd.clone(),
+            restart: options.restart.map(|policy| String::from(policy.as_str())),
             working_directory: Some(working_directory.to_string_lossy().to_string()),
             restore_override: options.restore.clone(),
+            mirror: options.mirror,
+            read_only: options.read_only,
+            tags: options.tags.clone(),
+            log_output,
+            shell: options.shell.clone(),
+            client_tty: nix::unistd::ttyname(io::stdin())
+                .ok()
+                .map(|tty| tty.to_string_lossy().into_owned()),
+            switch_in_place: true,
+            previous: options.previous.clone(),
+            passphrase: options.passphrase.clone(),
+            no_motd: options.no_motd,
+            evicted_notice: true,
+            ..Default::default()
         }))
         .context("writing attach header")?;
 
     let attach_resp: AttachReplyHeader = client.read_reply().context("reading attach reply")?;
     info!("attach_resp.status={:?}", attach_resp.status);
+    if let Some(banner) = &attach_resp.banner {
+        eprintln!("{banner}");
+    }
 
     {
         use shpool_protocol::AttachStatus::*;
@@ -205,10 +526,16 @@ fn do_attach(
             Busy => {
                 return Err(BusyError.into());
             }
+            Locked { wrong_passphrase } => {
+                return Err(LockedError { wrong_passphrase }.into());
+            }
             Forbidden(reason) => {
                 eprintln!("forbidden: {reason}");
                 return Err(anyhow!("forbidden: {reason}"));
             }
+            LimitReached(reason) => {
+                return Err(anyhow!("could not create '{}': {reason}", options.name));
+            }
             Attached { warnings } => {
                 for warning in warnings.into_iter() {
                     eprintln!("shpool: warn: {warning}");
@@ -227,16 +554,62 @@ fn do_attach(
         }
     }
 
-    match client.pipe_bytes() {
-        Ok(exit_status) => std::process::exit(exit_status),
-        Err(e) => Err(e),
+    let term = term_compat::Local::from_env(config.get().term_mismatch.unwrap_or_default());
+    let (warning, colors) = match &attach_resp.term {
+        Some(session_term) => term.negotiate(session_term),
+        None => (None, None),
+    };
+    if let Some(warning) = warning {
+        eprintln!("shpool: warn: {warning}");
+    }
+
+    let stdin = reconnect.attached()?;
+    match client.pipe_bytes(session_name, stdin, &term, colors)? {
+        PipeEnd::Exit(exit_status) => {
+            // make sure the tty gets restored since exit skips destructors
+            drop(reconnect.tty_guard.take());
+            std::process::exit(exit_status)
+        }
+        PipeEnd::Evicted(notice) => {
+            drop(reconnect.tty_guard.take());
+            eprintln!("shpool: {notice}");
+            std::process::exit(0)
+        }
+        PipeEnd::Switch(target) => Ok(target),
+        PipeEnd::ConnectionLost if reconnect.enabled => Err(ConnectionLost.into()),
+        PipeEnd::ConnectionLost => {
+            drop(reconnect.tty_guard.take());
+            std::process::exit(1)
+        }
+    }
+}
+
+/// Who we are, as in "pts/4 on host", so the client we take a
+/// session over from can say who took it. Over ssh, the host is the
+/// machine the connection came from.
+fn describe_client() -> String {
+    let tty = nix::unistd::ttyname(io::stdin())
+        .map(|tty| tty.to_string_lossy().trim_start_matches("/dev/").to_string())
+        .unwrap_or_else(|_| String::from("a client with no tty"));
+    let host = env::var("SSH_CONNECTION")
+        .ok()
+        .and_then(|conn| conn.split_whitespace().next().map(String::from))
+        .or_else(|| nix::unistd::gethostname().ok().map(|h| h.to_string_lossy().into_owned()));
+    match host {
+        Some(host) => format!("{tty} on {host}"),
+        None => tty,
     }
 }
 
-fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
+fn dial_client(socket: &PathBuf, interactive: bool) -> anyhow::Result<protocol::Client> {
     match protocol::Client::new(socket) {
         Ok(ClientResult::JustClient(c)) => Ok(c),
+        Ok(ClientResult::VersionMismatch { client, .. }) if !interactive => Ok(client),
         Ok(ClientResult::VersionMismatch { warning, client }) => {
+            // no point in offering to continue if it can't work
+            if client.incompatible().is_some() {
+                return Err(anyhow!(warning));
+            }
             eprintln!("warning: {warning}, try restarting your daemon");
             eprintln!("hit enter to continue anyway or ^C to exit");
 
@@ -249,7 +622,7 @@ fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
         }
         Err(err) => {
             let io_err = err.downcast::<io::Error>()?;
-            if io_err.kind() == io::ErrorKind::NotFound {
+            if interactive && io_err.kind() == io::ErrorKind::NotFound {
                 eprintln!("could not connect to daemon");
             }
             Err(io_err).context("connecting to daemon")
@@ -262,12 +635,12 @@ fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
 //
 
 struct SignalHandler {
-    session_name: String,
+    session_name: Arc<Mutex<String>>,
     socket: PathBuf,
 }
 
 impl SignalHandler {
-    fn new(session_name: String, socket: PathBuf) -> Self {
+    fn new(session_name: Arc<Mutex<String>>, socket: PathBuf) -> Self {
         SignalHandler { session_name, socket }
     }
 
@@ -307,11 +680,12 @@ impl SignalHandler {
 
         let tty_size = TtySize::from_fd(0).context("getting tty size")?;
         info!("handle_sigwinch: tty_size={:?}", tty_size);
+        let session_name = self.session_name.lock().unwrap().clone();
 
         // write the request on a new, seperate connection
         client
             .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
-                session_name: self.session_name.clone(),
+                session_name: session_name.clone(),
                 payload: SessionMessageRequestPayload::Resize(ResizeRequest {
                     tty_size: tty_size.clone(),
                 }),
@@ -324,11 +698,11 @@ impl SignalHandler {
             SessionMessageReply::NotFound => {
                 warn!(
                     "handle_sigwinch: sent resize for session '{}', but the daemon has no record of that session",
-                    self.session_name
+                    session_name
                 );
             }
             SessionMessageReply::Resize(ResizeReply::Ok) => {
-                info!("handle_sigwinch: resized session '{}' to {:?}", self.session_name, tty_size);
+                info!("handle_sigwinch: resized session '{}' to {:?}", session_name, tty_size);
             }
             reply => {
                 warn!("handle_sigwinch: unexpected resize reply: {:?}", reply);
@@ -0,0 +1,171 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Names for sessions started with a plain `shpool attach`.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher as _, Hasher as _},
+    path::Path,
+};
+
+use shpool_protocol::Session;
+
+use super::config::AutoName;
+
+/// Used when the current directory has no usable name, like `/`.
+const FALLBACK: &str = "shell";
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "brave", "brisk", "calm", "clever", "cosmic", "crisp", "daring", "dusty", "eager",
+    "fancy", "gentle", "golden", "happy", "hidden", "jolly", "keen", "lively", "lucky", "mellow",
+    "misty", "nimble", "noble", "plucky", "proud", "quiet", "rapid", "rustic", "silent", "sleepy",
+    "snowy", "spry", "sunny", "swift", "tidy", "vivid", "witty", "zesty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beaver", "bison", "cobra", "crane", "dingo", "falcon", "ferret", "finch", "gecko",
+    "heron", "ibis", "jackal", "koala", "lemur", "lynx", "marmot", "moose", "newt", "ocelot",
+    "otter", "panda", "puffin", "quail", "raven", "robin", "salmon", "seal", "sloth", "swan",
+    "tapir", "toad", "viper", "walrus", "wombat", "yak", "zebra",
+];
+
+/// Make up a name for a new session that doesn't clash with any of
+/// `sessions`, using the given scheme. `cwd` is the directory
+/// `shpool attach` was run in.
+pub fn generate(scheme: AutoName, cwd: Option<&Path>, sessions: &[Session]) -> String {
+    let taken = |name: &str| sessions.iter().any(|s| s.name == name);
+    let base = match scheme {
+        AutoName::Dir => cwd.and_then(dir_name).unwrap_or_else(|| String::from(FALLBACK)),
+        AutoName::Counter => {
+            return (1..).map(|n: usize| n.to_string()).find(|name| !taken(name)).unwrap();
+        }
+        AutoName::Pet => {
+            let mut seed = RandomState::new().build_hasher().finish();
+            let mut name = pet_name(seed);
+            // Try a few more before falling back to a numbered one.
+            for _ in 0..8 {
+                if !taken(&name) {
+                    break;
+                }
+                seed = seed.rotate_left(17) ^ 0x9e37_79b9_7f4a_7c15;
+                name = pet_name(seed);
+            }
+            name
+        }
+    };
+
+    let mut name = base.clone();
+    let mut n = 1;
+    while taken(&name) {
+        n += 1;
+        name = format!("{base}-{n}");
+    }
+    name
+}
+
+/// The last component of `dir`, cleaned up to make a good session
+/// name: anything other than letters, digits, `-`, `_` and `.` turns
+/// into a `-`.
+fn dir_name(dir: &Path) -> Option<String> {
+    let raw = dir.file_name()?.to_string_lossy();
+    let mut name = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        if c.is_alphanumeric() || c == '_' || c == '.' {
+            name.push(c);
+        } else if !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    let name = name.trim_matches(|c| c == '-' || c == '.');
+    if name.is_empty() {
+        None
+    } else {
+        Some(String::from(name))
+    }
+}
+
+fn pet_name(seed: u64) -> String {
+    let adjective = ADJECTIVES[(seed % ADJECTIVES.len() as u64) as usize];
+    let noun = NOUNS[((seed >> 32) % NOUNS.len() as u64) as usize];
+    format!("{adjective}-{noun}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shpool_protocol::{SessionStatus, TtySize};
+
+    fn session(name: &str) -> Session {
+        Session {
+            name: String::from(name),
+            started_at_unix_ms: 0,
+            status: SessionStatus::Disconnected,
+            pid: 1234,
+            tty_size: TtySize::default(),
+            tags: vec![],
+            exit_status: None,
+            throttled: false,
+            activity: false,
+            bell: false,
+            client_tty: None,
+            last_active_unix_ms: 0,
+            spool_bytes: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            attach_count: 1,
+            last_attached_unix_ms: None,
+            last_detached_unix_ms: None,
+            last_detach_reason: None,
+            locked: false,
+            cwd: None,
+            note: None,
+            watch_matches: 0,
+            last_watch_match: None,
+        }
+    }
+
+    #[test]
+    fn dir_names() {
+        let cases = [
+            ("/home/me/src/proj-api", Some("proj-api")),
+            ("/home/me/My Project", Some("My-Project")),
+            ("/home/me/a  b::c", Some("a-b-c")),
+            ("/home/me/.config", Some("config")),
+            ("/home/me/v1.2", Some("v1.2")),
+            ("/home/me/---", None),
+            ("/", None),
+        ];
+        for (dir, want) in cases {
+            assert_eq!(dir_name(Path::new(dir)).as_deref(), want, "{dir}");
+        }
+    }
+
+    #[test]
+    fn generated_names() {
+        let cwd = Path::new("/src/api");
+        let sessions = vec![session("api"), session("api-2"), session("1"), session("3")];
+        assert_eq!(generate(AutoName::Dir, Some(cwd), &[]), "api");
+        assert_eq!(generate(AutoName::Dir, Some(cwd), &sessions), "api-3");
+        assert_eq!(generate(AutoName::Dir, Some(Path::new("/")), &[]), "shell");
+        assert_eq!(generate(AutoName::Dir, None, &[]), "shell");
+        assert_eq!(generate(AutoName::Counter, None, &[]), "1");
+        assert_eq!(generate(AutoName::Counter, None, &sessions), "2");
+
+        let pet = generate(AutoName::Pet, None, &sessions);
+        let (adjective, noun) = pet.split_once('-').unwrap();
+        assert!(ADJECTIVES.contains(&adjective), "{pet}");
+        assert!(NOUNS.contains(&noun.split('-').next().unwrap()), "{pet}");
+    }
+}
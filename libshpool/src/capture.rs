@@ -0,0 +1,82 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs,
+    io::{self, Write as _},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, CaptureReply, CaptureRequest, ConnectHeader};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(
+    session: String,
+    output: Option<PathBuf>,
+    scrollback: bool,
+    strip_ansi: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::CAPTURE, "capturing output")?;
+    client
+        .write_connect_header(ConnectHeader::Capture(CaptureRequest {
+            session: session.clone(),
+            scrollback,
+        }))
+        .context("writing capture request header")?;
+
+    let reply: CaptureReply = client.read_reply().context("reading reply")?;
+    let mut data = match reply {
+        CaptureReply::Captured { data } => data,
+        CaptureReply::NotFound => {
+            eprintln!("not found: {session}");
+            return Err(anyhow!("not found: {}", session));
+        }
+        CaptureReply::Locked => {
+            eprintln!("session '{session}' is locked");
+            return Err(anyhow!("session '{}' is locked", session));
+        }
+    };
+    if strip_ansi {
+        data = strip_ansi_escapes::strip(&data);
+    }
+
+    match output {
+        Some(path) => fs::write(&path, &data).context("writing capture file")?,
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&data).context("writing capture")?;
+            stdout.flush().context("flushing capture")?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,220 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A typed client for driving a running shpool daemon from Rust.
+
+Tools that want to manage sessions would otherwise have to shell out
+to the `shpool` binary and scrape its output. This module talks the
+same socket protocol the subcommands do instead:
+
+```no_run
+let client = libshpool::client::Client::new(libshpool::client::default_socket()?);
+for session in client.list_sessions()? {
+    println!("{}", session.name);
+}
+client.send_input("main", b"make\r")?;
+for event in client.subscribe_events()? {
+    println!("{:?}", event?);
+}
+# Ok::<(), anyhow::Error>(())
+```
+
+Nothing in here prints anything. A daemon of a different version is
+tolerated as long as it supports what is being asked of it, and an
+error is returned if it doesn't.
+*/
+
+use std::{
+    io::{self, BufRead, BufReader},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{
+    capability, ConnectHeader, ControlHeader, ExecReply, ExecRequest, KillRequest, ListReply,
+    LockReply, LockRequest, SignalTarget,
+};
+pub use shpool_protocol::{ControlEvent, KillReply, Session, SessionStatus};
+use tracing::warn;
+
+use crate::{namespace, protocol, protocol::ClientResult};
+
+/// The socket the `shpool` subcommands talk to when given no
+/// `--socket` or `--namespace` flags. Like them, this picks the
+/// namespace out of $SHPOOL_NAMESPACE when it is set, so a tool run
+/// from inside a session finds the daemon that session belongs to.
+pub fn default_socket() -> anyhow::Result<PathBuf> {
+    let base = namespace::base_runtime_dir()?;
+    let ns = namespace::from_env().unwrap_or_else(|| String::from(namespace::DEFAULT));
+    namespace::validate(&ns)?;
+    Ok(namespace::socket(&base, &ns))
+}
+
+/// A handle on a daemon. Every call makes its own connection, so a
+/// Client is cheap to keep around and does not care if the daemon
+/// gets restarted in between calls.
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket: PathBuf,
+}
+
+impl Client {
+    pub fn new<P: Into<PathBuf>>(socket: P) -> Self {
+        Client { socket: socket.into() }
+    }
+
+    /// All the sessions the daemon knows about.
+    pub fn list_sessions(&self) -> anyhow::Result<Vec<Session>> {
+        let mut client = self.connect()?;
+        client.write_connect_header(ConnectHeader::List).context("sending list header")?;
+        let reply: ListReply = client.read_reply().context("reading list reply")?;
+        Ok(reply.sessions)
+    }
+
+    /// Kill the given sessions. Names the daemon did not know about
+    /// come back in `not_found_sessions` rather than as an error.
+    pub fn kill<S: AsRef<str>>(&self, sessions: &[S]) -> anyhow::Result<KillReply> {
+        let mut client = self.connect()?;
+        client
+            .write_connect_header(ConnectHeader::Kill(KillRequest {
+                sessions: sessions.iter().map(|s| String::from(s.as_ref())).collect(),
+                patterns: vec![],
+                all: false,
+                tags: vec![],
+                signal: None,
+                signal_target: SignalTarget::Shell,
+            }))
+            .context("sending kill header")?;
+        client.read_reply().context("reading kill reply")
+    }
+
+    /// Type `data` into a session as if it came from an attached
+    /// terminal. Nothing gets added, so include a `\r` to hit enter.
+    pub fn send_input(&self, session: &str, data: &[u8]) -> anyhow::Result<()> {
+        let mut client = self.connect()?;
+        client.require(capability::EXEC, "sending input")?;
+        client
+            .write_connect_header(ConnectHeader::Exec(ExecRequest {
+                session: String::from(session),
+                input: data.to_vec(),
+                until: None,
+                timeout_ms: None,
+            }))
+            .context("sending exec header")?;
+        match client.read_reply().context("reading exec reply")? {
+            ExecReply::Sent => Ok(()),
+            ExecReply::NotFound => Err(anyhow!("not found: {}", session)),
+            ExecReply::Locked => Err(anyhow!("session '{}' is locked", session)),
+        }
+    }
+
+    /// Lock a session so that attaching to it takes a passphrase. The
+    /// passphrase only gets used if the session does not have one yet,
+    /// otherwise the session gets locked with the one it already has.
+    pub fn lock(&self, session: &str, passphrase: &str) -> anyhow::Result<()> {
+        let mut client = self.connect()?;
+        client.require(capability::LOCK, "locking sessions")?;
+        client
+            .write_connect_header(ConnectHeader::Lock(LockRequest {
+                session: String::from(session),
+                passphrase: Some(String::from(passphrase)),
+                remove: false,
+            }))
+            .context("sending lock header")?;
+        match client.read_reply().context("reading lock reply")? {
+            LockReply::Locked => Ok(()),
+            LockReply::NotFound => Err(anyhow!("not found: {}", session)),
+            reply => Err(anyhow!("unexpected lock reply: {:?}", reply)),
+        }
+    }
+
+    /// Open a control mode connection and hand back the events the
+    /// daemon sends on it, starting with a Created event for every
+    /// existing session. The iterator ends when the daemon hangs up.
+    pub fn subscribe_events(&self) -> anyhow::Result<Events> {
+        let client = self.connect()?;
+        client.require(capability::CONTROL, "control mode")?;
+        client
+            .write_connect_header(ConnectHeader::Control(ControlHeader::default()))
+            .context("sending control header")?;
+        Ok(Events { lines: BufReader::new(client.into_stream()).lines() })
+    }
+
+    fn connect(&self) -> anyhow::Result<protocol::Client> {
+        match protocol::Client::new(&self.socket) {
+            Ok(ClientResult::JustClient(c)) => Ok(c),
+            Ok(ClientResult::VersionMismatch { warning, client }) => {
+                warn!("{}", warning);
+                Ok(client)
+            }
+            Err(err) => Err(err).with_context(|| format!("connecting to {:?}", self.socket)),
+        }
+    }
+}
+
+/// The events from a control mode connection, see
+/// `Client::subscribe_events`.
+pub struct Events {
+    lines: io::Lines<BufReader<UnixStream>>,
+}
+
+impl Iterator for Events {
+    type Item = anyhow::Result<ControlEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e).context("reading control event")),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(serde_json::from_str(&line).context("parsing control event"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn events() {
+        let (mut daemon, client) = UnixStream::pair().unwrap();
+        let mut events = Events { lines: BufReader::new(client).lines() };
+        daemon
+            .write_all(
+                b"{\"event\": \"created\", \"session\": \"main\"}\n\n\
+                  {\"event\": \"exited\", \"session\": \"main\", \"exit_status\": 0}\n\
+                  not json\n",
+            )
+            .unwrap();
+        drop(daemon);
+
+        assert_eq!(
+            events.next().unwrap().unwrap(),
+            ControlEvent::Created { session: String::from("main") }
+        );
+        assert_eq!(
+            events.next().unwrap().unwrap(),
+            ControlEvent::Exited { session: String::from("main"), exit_status: Some(0) }
+        );
+        assert!(events.next().unwrap().is_err());
+        assert!(events.next().is_none());
+    }
+}
@@ -14,13 +14,13 @@
 
 //! The common module is a grab bag of shared utility functions.
 
-use std::env;
+use std::{env, ffi::OsStr, fs, path::Path};
 
 use anyhow::anyhow;
 
 pub fn resolve_sessions(sessions: &mut Vec<String>, action: &str) -> anyhow::Result<()> {
     if sessions.is_empty()
-        && let Ok(current_session) = env::var("SHPOOL_SESSION_NAME") {
+        && let Some(current_session) = current_session() {
             sessions.push(current_session);
         }
 
@@ -31,3 +31,22 @@ pub fn resolve_sessions(sessions: &mut Vec<String>, action: &str) -> anyhow::Res
 
     Ok(())
 }
+
+/// The name of the session we are running inside of, if any.
+///
+/// A session's shell can't have its environment changed from the
+/// outside, so if the session has been renamed SHPOOL_SESSION_NAME
+/// will be stale. The daemon moves the session dir over to the new
+/// name and leaves a symlink behind at the old path, so resolving
+/// SHPOOL_SESSION_DIR gives us the current name.
+pub fn current_session() -> Option<String> {
+    let name = env::var("SHPOOL_SESSION_NAME").ok()?;
+    if let Ok(dir) = env::var("SHPOOL_SESSION_DIR")
+        && Path::new(&dir).file_name() == Some(OsStr::new(&name))
+        && let Ok(dir) = fs::canonicalize(dir)
+        && let Some(current) = dir.file_name()
+    {
+        return Some(current.to_string_lossy().into_owned());
+    }
+    Some(name)
+}
@@ -0,0 +1,521 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Shell completion scripts.
+
+  `shpool completion <shell>` prints a completion script for the given
+  shell. Subcommands and flags come straight out of the clap definitions,
+  but session names can't be known ahead of time, so the scripts shell
+  back out to the hidden `shpool complete-sessions` subcommand, which
+  asks the daemon for the current session list. The scripts hand it
+  whatever global flags come before the subcommand on the line being
+  completed, so `shpool -s other.socket attach <TAB>` lists the
+  sessions of that daemon. If there is no daemon running, it prints
+  nothing and the scripts just don't offer any session names.
+*/
+
+use std::{fmt::Write as _, path::PathBuf};
+
+use clap::CommandFactory as _;
+use shpool_protocol::{ConnectHeader, ListReply};
+
+use crate::{protocol, protocol::ClientResult, Args};
+
+/// The shells we can generate completions for.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Subcommands that take session names as positional arguments, along
+/// with whether they take more than one.
+const SESSION_CMDS: &[(&str, bool)] = &[
+    ("attach", false),
+    ("capture", false),
+    ("detach", true),
+    ("exec", false),
+    ("kill", true),
+    ("lock", false),
+    ("migrate", false),
+    ("note", false),
+    ("ping", false),
+    ("pipe", false),
+    ("rename", false),
+    ("ssh-attach", false),
+    ("stat", false),
+    ("switch", false),
+    ("tail", false),
+    ("wait", false),
+    ("watch", false),
+];
+
+/// The command the scripts run to find out the current session names,
+/// given the shell syntax for the global flags on the command line
+/// being completed, so that `--socket` and friends pick the same
+/// daemon. It must never launch a daemon just to complete a word.
+fn sessions_cmd(globals: &str) -> String {
+    format!("shpool {globals} --no-daemonize complete-sessions 2>/dev/null")
+}
+
+pub fn run(shell: Shell) -> anyhow::Result<()> {
+    let spec = Spec::new();
+    print!(
+        "{}",
+        match shell {
+            Shell::Bash => bash(&spec),
+            Shell::Zsh => zsh(&spec),
+            Shell::Fish => fish(&spec),
+        }
+    );
+    Ok(())
+}
+
+/// Print the names of the running sessions, one per line. This is
+/// only ever called from completion scripts, so failing to reach the
+/// daemon is not an error.
+pub fn list_sessions(socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { client, .. }) => client,
+        Err(_) => return Ok(()),
+    };
+    if client.write_connect_header(ConnectHeader::List).is_err() {
+        return Ok(());
+    }
+    let Ok(reply) = client.read_reply::<ListReply>() else {
+        return Ok(());
+    };
+    for session in reply.sessions {
+        println!("{}", session.name);
+    }
+    Ok(())
+}
+
+struct Flag {
+    short: Option<char>,
+    long: Option<String>,
+    takes_value: bool,
+    help: String,
+}
+
+impl Flag {
+    fn spellings(&self) -> Vec<String> {
+        let mut spellings = vec![];
+        if let Some(short) = self.short {
+            spellings.push(format!("-{short}"));
+        }
+        if let Some(long) = &self.long {
+            spellings.push(format!("--{long}"));
+        }
+        spellings
+    }
+}
+
+struct Subcommand {
+    name: String,
+    about: String,
+    flags: Vec<Flag>,
+    // None if the subcommand does not take session names, otherwise
+    // whether it takes more than one.
+    sessions: Option<bool>,
+}
+
+/// Everything the scripts need to know about the cli, pulled out of
+/// the clap definitions.
+struct Spec {
+    global_flags: Vec<Flag>,
+    subcommands: Vec<Subcommand>,
+}
+
+impl Spec {
+    fn new() -> Self {
+        let mut cmd = Args::command();
+        cmd.build();
+        let subcommands = cmd
+            .get_subcommands()
+            .filter(|sub| !sub.is_hide_set())
+            .map(|sub| Subcommand {
+                name: String::from(sub.get_name()),
+                about: first_line(sub.get_about().map(|a| a.to_string())),
+                flags: flags(sub),
+                sessions: SESSION_CMDS
+                    .iter()
+                    .find(|(name, _)| *name == sub.get_name())
+                    .map(|(_, multi)| *multi),
+            })
+            .collect();
+        Spec { global_flags: flags(&cmd), subcommands }
+    }
+
+    /// All the flags that take a value, from every subcommand.
+    fn value_flags(&self) -> Vec<String> {
+        let mut value_flags: Vec<String> = self
+            .global_flags
+            .iter()
+            .chain(self.subcommands.iter().flat_map(|sub| sub.flags.iter()))
+            .filter(|flag| flag.takes_value)
+            .flat_map(Flag::spellings)
+            .collect();
+        value_flags.sort();
+        value_flags.dedup();
+        value_flags
+    }
+
+    fn global_value_flags(&self) -> Vec<String> {
+        self.global_flags.iter().filter(|f| f.takes_value).flat_map(Flag::spellings).collect()
+    }
+
+    fn session_cmds(&self, multi: bool) -> Vec<&str> {
+        self.subcommands
+            .iter()
+            .filter(|sub| sub.sessions == Some(multi))
+            .map(|sub| sub.name.as_str())
+            .collect()
+    }
+}
+
+fn flags(cmd: &clap::Command) -> Vec<Flag> {
+    cmd.get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+        .map(|arg| Flag {
+            short: arg.get_short(),
+            long: arg.get_long().map(String::from),
+            takes_value: arg.get_action().takes_values(),
+            help: first_line(arg.get_help().or(arg.get_long_help()).map(|h| h.to_string())),
+        })
+        .collect()
+}
+
+fn first_line(text: Option<String>) -> String {
+    text.unwrap_or_default().lines().next().unwrap_or_default().to_string()
+}
+
+fn bash(spec: &Spec) -> String {
+    let mut top_words: Vec<String> = spec.global_flags.iter().flat_map(Flag::spellings).collect();
+    top_words.extend(spec.subcommands.iter().map(|sub| sub.name.clone()));
+
+    let mut flag_cases = String::new();
+    for sub in spec.subcommands.iter() {
+        let spellings: Vec<String> = sub.flags.iter().flat_map(Flag::spellings).collect();
+        writeln!(flag_cases, "            {}) flags=\"{}\" ;;", sub.name, spellings.join(" "))
+            .unwrap();
+    }
+
+    format!(
+        r#"# bash completion for shpool
+
+_shpool() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local cmd="" npos=0 i
+    local -a globals=()
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        [[ -z "$cmd" && "${{COMP_WORDS[i]}}" == -* ]] && globals+=("${{COMP_WORDS[i]}}")
+        case "${{COMP_WORDS[i]}}" in
+            {global_value_flags})
+                [[ -z "$cmd" ]] && globals+=("${{COMP_WORDS[i+1]}}")
+                ((i++))
+                ;;
+            -*) ;;
+            *)
+                if [[ -z "$cmd" ]]; then
+                    cmd="${{COMP_WORDS[i]}}"
+                else
+                    case "${{COMP_WORDS[i-1]}}" in
+                        {value_flags}) ;;
+                        *) ((npos++)) ;;
+                    esac
+                fi
+                ;;
+        esac
+    done
+
+    case "$prev" in
+        {value_flags})
+            COMPREPLY=($(compgen -f -- "$cur"))
+            return
+            ;;
+    esac
+
+    if [[ -z "$cmd" ]]; then
+        COMPREPLY=($(compgen -W "{top_words}" -- "$cur"))
+        return
+    fi
+
+    if [[ "$cur" == -* ]]; then
+        local flags=""
+        case "$cmd" in
+{flag_cases}        esac
+        COMPREPLY=($(compgen -W "$flags" -- "$cur"))
+        return
+    fi
+
+    case "$cmd" in
+        {multi_cmds}) ;;
+        {single_cmds}) [[ $npos -eq 0 ]] || return ;;
+        *) return ;;
+    esac
+    COMPREPLY=($(compgen -W "$({sessions_cmd})" -- "$cur"))
+}}
+
+complete -F _shpool shpool
+"#,
+        global_value_flags = alternatives(&spec.global_value_flags()),
+        value_flags = alternatives(&spec.value_flags()),
+        top_words = top_words.join(" "),
+        flag_cases = flag_cases,
+        multi_cmds = alternatives(&spec.session_cmds(true)),
+        single_cmds = alternatives(&spec.session_cmds(false)),
+        sessions_cmd = sessions_cmd(r#""${globals[@]}""#),
+    )
+}
+
+fn zsh(spec: &Spec) -> String {
+    let mut subcmds = String::new();
+    for sub in spec.subcommands.iter() {
+        writeln!(
+            subcmds,
+            "        {}",
+            single_quote(&format!("{}:{}", sub.name, escape_colons(&sub.about)))
+        )
+        .unwrap();
+    }
+
+    let mut flag_cases = String::new();
+    for sub in spec.subcommands.iter() {
+        let spellings: Vec<String> = sub.flags.iter().flat_map(Flag::spellings).collect();
+        writeln!(flag_cases, "            ({}) compadd -- {} ;;", sub.name, spellings.join(" "))
+            .unwrap();
+    }
+    let global_spellings: Vec<String> =
+        spec.global_flags.iter().flat_map(Flag::spellings).collect();
+
+    format!(
+        r#"#compdef shpool
+
+_shpool() {{
+    local cmd="" npos=0 i
+    local -a subcmds globals
+    subcmds=(
+{subcmds}    )
+
+    for ((i = 2; i < CURRENT; i++)); do
+        [[ -z "$cmd" && "$words[i]" == -* ]] && globals+=("$words[i]")
+        case "$words[i]" in
+            ({global_value_flags})
+                [[ -z "$cmd" ]] && globals+=("$words[i+1]")
+                ((i++))
+                ;;
+            (-*) ;;
+            (*)
+                if [[ -z "$cmd" ]]; then
+                    cmd="$words[i]"
+                else
+                    case "$words[i-1]" in
+                        ({value_flags}) ;;
+                        (*) ((npos++)) ;;
+                    esac
+                fi
+                ;;
+        esac
+    done
+
+    case "$words[CURRENT-1]" in
+        ({value_flags})
+            _files
+            return
+            ;;
+    esac
+
+    if [[ -z "$cmd" ]]; then
+        if [[ "$PREFIX" == -* ]]; then
+            compadd -- {global_spellings}
+        else
+            _describe 'command' subcmds
+        fi
+        return
+    fi
+
+    if [[ "$PREFIX" == -* ]]; then
+        case "$cmd" in
+{flag_cases}        esac
+        return
+    fi
+
+    case "$cmd" in
+        ({multi_cmds}) ;;
+        ({single_cmds}) (( npos == 0 )) || return ;;
+        (*) return ;;
+    esac
+    local -a sessions
+    sessions=(${{(f)"$({sessions_cmd})"}})
+    compadd -- $sessions
+}}
+
+if [[ "$zsh_eval_context[-1]" == loadautofunc ]]; then
+    _shpool "$@"
+else
+    compdef _shpool shpool
+fi
+"#,
+        subcmds = subcmds,
+        global_value_flags = alternatives(&spec.global_value_flags()),
+        value_flags = alternatives(&spec.value_flags()),
+        global_spellings = global_spellings.join(" "),
+        flag_cases = flag_cases,
+        multi_cmds = alternatives(&spec.session_cmds(true)),
+        single_cmds = alternatives(&spec.session_cmds(false)),
+        sessions_cmd = sessions_cmd(r#""${globals[@]}""#),
+    )
+}
+
+fn fish(spec: &Spec) -> String {
+    let mut out = String::from("# fish completion for shpool\n\n");
+    let global_value_flags: Vec<String> =
+        spec.global_value_flags().iter().map(|f| single_quote(f)).collect();
+    write!(
+        out,
+        r#"function __shpool_sessions
+    set -l globals
+    set -l tokens (commandline -opc)
+    set -e tokens[1]
+    while set -q tokens[1]
+        switch $tokens[1]
+            case {}
+                set -a globals $tokens[1..2]
+                set -e tokens[1]
+            case '-*'
+                set -a globals $tokens[1]
+            case '*'
+                break
+        end
+        set -e tokens[1]
+    end
+    {}
+end
+
+"#,
+        global_value_flags.join(" "),
+        sessions_cmd("$globals"),
+    )
+    .unwrap();
+    out.push_str("complete -c shpool -f\n");
+
+    for flag in spec.global_flags.iter() {
+        writeln!(out, "complete -c shpool -n __fish_use_subcommand{}", fish_flag(flag)).unwrap();
+    }
+    for sub in spec.subcommands.iter() {
+        writeln!(
+            out,
+            "complete -c shpool -n __fish_use_subcommand -a {} -d {}",
+            sub.name,
+            single_quote(&sub.about)
+        )
+        .unwrap();
+    }
+    for sub in spec.subcommands.iter() {
+        let cond = single_quote(&format!("__fish_seen_subcommand_from {}", sub.name));
+        for flag in sub.flags.iter() {
+            writeln!(out, "complete -c shpool -n {}{}", cond, fish_flag(flag)).unwrap();
+        }
+        if sub.sessions.is_some() {
+            writeln!(out, "complete -c shpool -n {cond} -a '(__shpool_sessions)'").unwrap();
+        }
+    }
+    out
+}
+
+fn fish_flag(flag: &Flag) -> String {
+    let mut out = String::new();
+    if let Some(short) = flag.short {
+        write!(out, " -s {short}").unwrap();
+    }
+    if let Some(long) = &flag.long {
+        write!(out, " -l {long}").unwrap();
+    }
+    if flag.takes_value {
+        out.push_str(" -r -F");
+    }
+    if !flag.help.is_empty() {
+        write!(out, " -d {}", single_quote(&flag.help)).unwrap();
+    }
+    out
+}
+
+/// Join words into a case pattern, e.g. `-s|--socket`.
+fn alternatives<S: AsRef<str>>(words: &[S]) -> String {
+    if words.is_empty() {
+        // a pattern that can never match a real word
+        return String::from("''");
+    }
+    words.iter().map(|w| w.as_ref()).collect::<Vec<_>>().join("|")
+}
+
+fn single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn escape_colons(s: &str) -> String {
+    s.replace(':', r"\:")
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn spec() {
+        let spec = Spec::new();
+        let attach = spec.subcommands.iter().find(|s| s.name == "attach").expect("attach");
+        assert_eq!(attach.sessions, Some(false));
+        assert!(attach.flags.iter().any(|f| f.long.as_deref() == Some("ttl") && f.takes_value));
+        assert!(attach.flags.iter().any(|f| f.long.as_deref() == Some("force") && !f.takes_value));
+        assert!(!spec.subcommands.iter().any(|s| s.name == "complete-sessions"));
+        assert_eq!(spec.session_cmds(true), vec!["detach", "kill"]);
+        assert!(spec.global_value_flags().contains(&String::from("--socket")));
+        assert!(!spec.global_value_flags().contains(&String::from("--daemonize")));
+    }
+
+    #[test]
+    fn bash_syntax() -> anyhow::Result<()> {
+        let script = bash(&Spec::new());
+        assert!(script.contains(&sessions_cmd(r#""${globals[@]}""#)));
+        let out = Command::new("bash").arg("-n").arg("-c").arg(&script).output()?;
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        Ok(())
+    }
+
+    #[test]
+    fn fish_script() {
+        let script = fish(&Spec::new());
+        assert!(script.contains(
+            "complete -c shpool -n '__fish_seen_subcommand_from kill' -a '(__shpool_sessions)'"
+        ));
+        assert!(script.contains("complete -c shpool -n __fish_use_subcommand -s s -l socket -r -F"));
+        assert!(script.contains(&sessions_cmd("$globals")));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(single_quote("it's"), r"'it'\''s'");
+        assert_eq!(escape_colons("a:b"), r"a\:b");
+        assert_eq!(alternatives(&["-s", "--socket"]), "-s|--socket");
+        assert_eq!(alternatives::<&str>(&[]), "''");
+    }
+}
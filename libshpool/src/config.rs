@@ -13,28 +13,41 @@
 // limitations under the License.
 
 use std::{
-    borrow::Cow,
-    collections::HashMap,
-    fs,
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs,
     path::{Path, PathBuf},
-    sync::{Arc, RwLock, RwLockReadGuard},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard,
+    },
+    thread, time,
 };
 
-#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
-use std::env;
-
-use anyhow::{Context as _, Result};
-use serde_derive::Deserialize;
+use anyhow::{anyhow, Context as _, Result};
+use serde_derive::{Deserialize, Serialize};
 use tracing::{info, warn};
 
 use crate::{daemon::keybindings, user};
 
+// How long to wait for a burst of file events to die down before
+// reloading. Editors often write a file in several steps.
+const RELOAD_DEBOUNCE: time::Duration = time::Duration::from_millis(200);
+
+/// Environment variables starting with this override config keys, for
+/// example `SHPOOL_SESSION_RESTORE` for `session_restore`.
+const ENV_PREFIX: &str = "SHPOOL_";
+
 /// Exposes the shpool config file.
-/// Configuration changes require daemon restart to take effect.
+/// The daemon reloads the config when the file changes or when it
+/// gets a SIGHUP, so most settings take effect on the next attach.
 #[derive(Clone)]
 pub struct Manager {
     /// The config value.
     config: Arc<RwLock<Config>>,
+    /// The files the config gets loaded from, in priority order.
+    config_files: Arc<Vec<PathBuf>>,
+    /// Bumped on every successful reload.
+    generation: Arc<AtomicU64>,
 }
 
 impl Manager {
@@ -47,24 +60,14 @@ impl Manager {
     /// - User level config: $XDG_CONFIG_HOME/shpool/config.toml or
     ///   $HOME/.config/shpool/config.toml if $XDG_CONFIG_HOME is not set
     ///
+    /// On top of the files come `SHPOOL_<KEY>` environment variables,
+    /// see `Manager::env_layer`.
+    ///
     /// For each top level field, values read later will overrides those read
     /// eariler. The exact merging strategy is as defined in
     /// `Config::merge`.
     pub fn new(config_file: Option<&str>) -> Result<Self> {
-        let config_dir = Self::config_dir()?;
-
-        let config_files = match config_file {
-            None => {
-                vec![
-                    Cow::from(Path::new("/etc/shpool/config.toml")),
-                    Cow::from(config_dir.join("config.toml")),
-                ]
-            }
-            Some(config_file) => {
-                info!("parsing explicitly passed in config ({})", config_file);
-                vec![Cow::from(Path::new(config_file))]
-            }
-        };
+        let config_files = Self::config_files(config_file)?;
 
         let config = Self::load(&config_files).context("loading initial config")?;
         
@@ -73,26 +76,147 @@ impl Manager {
         
         info!("starting with config: {:?}", config);
         let config = Arc::new(RwLock::new(config));
-        let manager = Manager { config };
+        let manager = Manager {
+            config,
+            config_files: Arc::new(config_files),
+            generation: Arc::new(AtomicU64::new(0)),
+        };
 
         Ok(manager)
     }
 
+    /// The files the config gets read from, lowest priority first. See
+    /// `Manager::new` for where they live.
+    pub fn config_files(config_file: Option<&str>) -> Result<Vec<PathBuf>> {
+        Ok(match config_file {
+            None => vec![
+                PathBuf::from("/etc/shpool/config.toml"),
+                Self::config_dir()?.join("config.toml"),
+            ],
+            Some(config_file) => {
+                info!("parsing explicitly passed in config ({})", config_file);
+                vec![PathBuf::from(config_file)]
+            }
+        })
+    }
+
+    /// Read just the log settings. This happens before logging is set
+    /// up, so any problems with the config get ignored here and are
+    /// reported once it is loaded for real.
+    pub fn log_config(config_file: Option<&str>) -> LogConfig {
+        Self::config_files(config_file)
+            .and_then(Self::load)
+            .ok()
+            .and_then(|config| config.log)
+            .unwrap_or_default()
+    }
+
     /// Get the current config value.
     pub fn get(&self) -> RwLockReadGuard<'_, Config> {
         self.config.read().unwrap()
     }
 
+    /// Re-read the config files, replacing the current config. If the
+    /// new config is bad, the old one stays in place.
+    pub fn reload(&self) -> Result<()> {
+        let config = Self::load(self.config_files.iter()).context("reloading config")?;
+        if Self::has_deprecated_options(&config) {
+            return Err(anyhow!(
+                "config uses deprecated options, restart the daemon to see migration instructions"
+            ));
+        }
+        info!("reloaded config: {:?}", config);
+        *self.config.write().unwrap() = config;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// How many times the config has been reloaded, so that things
+    /// derived from it can tell when to rebuild themselves.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Spawn a thread that reloads the config whenever one of the config
+    /// files changes. We watch the directories containing the config files
+    /// rather than the files themselves so that we notice files that get
+    /// created later or replaced by editors that write a new file and
+    /// rename it into place.
+    pub fn watch(&self) -> Result<()> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut watcher = notify::recommended_watcher(tx).context("creating config watcher")?;
+        let mut watched_dirs: Vec<&Path> = vec![];
+        for path in self.config_files.iter() {
+            let dir = match path.parent() {
+                Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+                Some(dir) => dir,
+                None => continue,
+            };
+            if watched_dirs.contains(&dir) || !dir.exists() {
+                continue;
+            }
+            notify::Watcher::watch(&mut watcher, dir, notify::RecursiveMode::NonRecursive)
+                .with_context(|| format!("watching {dir:?}"))?;
+            watched_dirs.push(dir);
+        }
+
+        let manager = self.clone();
+        thread::Builder::new()
+            .name(String::from("config_watcher"))
+            .spawn(move || {
+                // keep the watcher alive for as long as we are listening
+                let _watcher = watcher;
+                while let Ok(event) = rx.recv() {
+                    if !manager.is_config_event(event) {
+                        continue;
+                    }
+                    while rx.recv_timeout(RELOAD_DEBOUNCE).is_ok() {}
+                    if let Err(e) = manager.reload() {
+                        warn!("config changed on disk but could not be reloaded: {:?}", e);
+                    }
+                }
+            })
+            .context("spawning config watcher thread")?;
+
+        Ok(())
+    }
+
+    fn is_config_event(&self, event: notify::Result<notify::Event>) -> bool {
+        match event {
+            Ok(event) => {
+                !event.kind.is_access()
+                    && event.paths.iter().any(|p| self.config_files.iter().any(|f| p.ends_with(f)))
+            }
+            Err(e) => {
+                warn!("watching config: {:?}", e);
+                false
+            }
+        }
+    }
+
     /// Load config by merging configurations from a list of Paths.
     ///
-    /// Paths come later in the list takes higher priority.
+    /// Paths come later in the list takes higher priority, and the
+    /// environment variables take priority over all of them.
     /// Merge strategy is as defined in `Config::merge`.
     fn load<T>(config_files: T) -> Result<Config>
+    where
+        T: IntoIterator,
+        T::Item: AsRef<Path>,
+    {
+        Ok(Self::load_with_origins(config_files)?.0)
+    }
+
+    /// Like `load`, but also says where each top level key of the
+    /// resulting config came from.
+    pub fn load_with_origins<T>(config_files: T) -> Result<(Config, BTreeMap<String, Origin>)>
     where
         T: IntoIterator,
         T::Item: AsRef<Path>,
     {
         let mut config = Config::default();
+        let mut origins: BTreeMap<String, Origin> =
+            set_keys(&config).into_iter().map(|key| (key, Origin::Default)).collect();
         for path in config_files {
             let path = path.as_ref();
             info!("loading config from {:?}", path);
@@ -112,9 +236,74 @@ impl Manager {
                 }
                 Ok(c) => c,
             };
+            for key in set_keys(&new_config) {
+                origins.insert(key, Origin::File(PathBuf::from(path)));
+            }
             config = new_config.merge(config);
         }
-        Ok(config)
+
+        let env_layer = Self::env_layer(env::vars())?;
+        for (key, var) in env_layer.names {
+            origins.insert(key, Origin::Env(var));
+        }
+        Ok((env_layer.config.merge(config), origins))
+    }
+
+    /// The config set through environment variables, along with the
+    /// variable each key came from. `SHPOOL_<KEY>` sets the top level
+    /// key `<key>`, and its value is read as a toml value, or as a
+    /// plain string if that isn't what the key takes, so
+    /// `SHPOOL_NORC=true`, `SHPOOL_SESSION_RESTORE=10MiB` and
+    /// `SHPOOL_PROMPT_PREFIX=1` all work. Variables that don't name a
+    /// config key, like `SHPOOL_SESSION_NAME`, are left alone, and ones
+    /// with a value their key can't take get skipped with a warning
+    /// rather than taking the whole config down. The skipped variables
+    /// come back along with the reason they were skipped.
+    pub fn env_layer<I>(vars: I) -> Result<EnvLayer>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut table = toml::Table::new();
+        let mut names = BTreeMap::new();
+        let mut skipped = vec![];
+        for (var, value) in vars {
+            let Some(key) = var.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let key = key.to_lowercase();
+            let typed = match toml::from_str::<toml::Table>(&format!("value = {value}")) {
+                Ok(mut parsed) => parsed.remove("value"),
+                Err(_) => None,
+            };
+            let mut err = None;
+            for candidate in typed.into_iter().chain([toml::Value::String(value)]) {
+                let single = toml::Table::from_iter([(key.clone(), candidate.clone())]);
+                match toml::Value::Table(single).try_into::<Config>() {
+                    Ok(_) => {
+                        table.insert(key.clone(), candidate);
+                        err = None;
+                        break;
+                    }
+                    Err(e) => err = Some(e),
+                }
+            }
+            match err {
+                None => {
+                    names.insert(key, var);
+                }
+                Some(e) => {
+                    warn!("ignoring {}: {}", var, e);
+                    skipped.push((var, e.to_string()));
+                }
+            }
+        }
+
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .context("parsing config from SHPOOL_* environment variables")?;
+        let set = set_keys(&config);
+        names.retain(|key, _| set.contains(key));
+        Ok(EnvLayer { config, names, skipped })
     }
 
     fn config_dir() -> anyhow::Result<PathBuf> {
@@ -133,7 +322,7 @@ impl Manager {
 
     #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
     fn config_base_dir() -> anyhow::Result<PathBuf> {
-        match env::var("XDG_CONFIG_DIR") {
+        match env::var("XDG_CONFIG_HOME") {
             Ok(v) => Ok(PathBuf::from(v)),
             Err(_) => {
                 let user_info = user::info().context("getting user info")?;
@@ -144,12 +333,36 @@ impl Manager {
         }
     }
     
+    fn has_deprecated_options(config: &Config) -> bool {
+        config.output_spool_lines.is_some()
+            || config.vt100_output_spool_width.is_some()
+            || config.session_restore_mode.is_some()
+    }
+
     /// Check for deprecated configuration options and exit if found
     fn check_deprecated_config(config: &Config) -> Result<()> {
-        let mut warnings: Vec<(String, String)> = Vec::new();
+        let warnings = Self::deprecation_warnings(config);
+        if !warnings.is_empty() {
+            eprintln!("Configuration Migration Required:");
+            for (_, warning, suggestion) in warnings {
+                eprintln!("  Warning: {}", warning);
+                eprintln!("  Suggestion: {}", suggestion);
+            }
+            eprintln!("\nPlease update your ~/.config/shpool/config.toml and restart shpool.");
+            std::process::exit(1);
+        }
+        
+        Ok(())
+    }
+
+    /// The key, a warning and a suggested replacement for each deprecated
+    /// option the config uses.
+    pub fn deprecation_warnings(config: &Config) -> Vec<(&'static str, String, String)> {
+        let mut warnings: Vec<(&'static str, String, String)> = Vec::new();
         
         if config.output_spool_lines.is_some() {
             warnings.push((
+                "output_spool_lines",
                 "'output_spool_lines' is deprecated".to_string(), 
                 "Use 'session_restore = \"2MB\"' instead".to_string()
             ));
@@ -157,6 +370,7 @@ impl Manager {
         
         if config.vt100_output_spool_width.is_some() {
             warnings.push((
+                "vt100_output_spool_width",
                 "'vt100_output_spool_width' is deprecated".to_string(),
                 "This setting is no longer needed".to_string()
             ));
@@ -165,36 +379,66 @@ impl Manager {
         if let Some(mode) = &config.session_restore_mode {
             match mode {
                 SessionRestoreMode::Simple => warnings.push((
+                    "session_restore_mode",
                     "'session_restore_mode = \"simple\"' is deprecated".to_string(),
                     "Use 'session_restore = \"0\"' instead".to_string()
                 )),
                 SessionRestoreMode::Screen => warnings.push((
+                    "session_restore_mode",
                     "'session_restore_mode = \"screen\"' is deprecated".to_string(),
-                    "Use 'session_restore = \"1MB\"' instead".to_string()
+                    "Use 'session_restore = \"screen\"' instead".to_string()
                 )),
                 SessionRestoreMode::Lines(n) => {
                     let mb = std::cmp::max(1, (*n as usize * 200) / (1024 * 1024));
                     let warning_msg = format!("'session_restore_mode = {{ lines = {} }}' is deprecated", n);
                     let suggestion_msg = format!("Use 'session_restore = \"{}MB\"' instead", mb);
-                    warnings.push((warning_msg, suggestion_msg));
+                    warnings.push(("session_restore_mode", warning_msg, suggestion_msg));
                 }
             }
         }
-        
-        if !warnings.is_empty() {
-            eprintln!("Configuration Migration Required:");
-            for (warning, suggestion) in warnings {
-                eprintln!("  Warning: {}", warning);
-                eprintln!("  Suggestion: {}", suggestion);
-            }
-            eprintln!("\nPlease update your ~/.config/shpool/config.toml and restart shpool.");
-            std::process::exit(1);
+
+        warnings
+    }
+}
+
+/// Where the value of a config key came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// Nobody set it, so it has its default value.
+    Default,
+    /// A config file.
+    File(PathBuf),
+    /// An environment variable.
+    Env(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Env(var) => write!(f, "${var}"),
         }
-        
-        Ok(())
     }
 }
 
+/// The top level keys a config sets.
+fn set_keys(config: &Config) -> Vec<String> {
+    match toml::Value::try_from(config) {
+        Ok(toml::Value::Table(table)) => table.keys().cloned().collect(),
+        _ => vec![],
+    }
+}
+
+/// The config set through `SHPOOL_*` environment variables.
+pub struct EnvLayer {
+    pub config: Config,
+    /// The variable each key that got set came from.
+    pub names: BTreeMap<String, String>,
+    /// The variables that got skipped, and why.
+    pub skipped: Vec<(String, String)>,
+}
+
 impl std::fmt::Debug for Manager {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         let config = self.config.read().unwrap();
@@ -204,7 +448,7 @@ impl std::fmt::Debug for Manager {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     /// norc makes it so that new shells do not load rc files
     /// when they spawn. Only works with bash.
@@ -245,6 +489,14 @@ pub struct Config {
     /// daemon to come up and will instead spin forever.
     pub nodaemonize_timeout: Option<bool>,
 
+    /// Whether to start a daemon when a command can't reach one, and
+    /// how. `true` forks one in the background, just like
+    /// autodaemonization does, `"systemd"` starts the `shpool.service`
+    /// systemd user unit instead, and `false` gives up with an error.
+    /// Takes priority over `nodaemonize` when set, though the
+    /// -d/--daemonize and -D/--no-daemonize flags still win.
+    pub autostart_daemon: Option<AutostartDaemon>,
+
     /// shell overrides the user's default shell
     pub shell: Option<String>,
 
@@ -258,6 +510,20 @@ pub struct Config {
     /// reattaching to an existing shell.
     pub forward_env: Option<Vec<String>>,
 
+    /// A list of environment variables to refresh on every attach.
+    /// Their values from the environment of `shpool attach` get written
+    /// to `$SHPOOL_SESSION_DIR/refresh.env` as a script the shell can
+    /// source to pick up the latest values after a reconnect. DISPLAY,
+    /// WAYLAND_DISPLAY and XAUTHORITY are always included.
+    pub refresh_env: Option<Vec<String>>,
+
+    /// A list of environment variables holding socket paths, like
+    /// SSH_AUTH_SOCK, which should be forwarded through a symlink in
+    /// the session dir that gets re-pointed at the latest client's
+    /// socket on every attach. SSH_AUTH_SOCK is always handled this
+    /// way unless `nosymlink_ssh_auth_sock` is set.
+    pub forward_sockets: Option<Vec<String>>,
+
     /// The initial path to spawn shell processes with. By default
     /// `/usr/bin:/bin:/usr/sbin:/sbin` (copying openssh). This
     /// value is often overridden by /etc/environment even if you
@@ -266,8 +532,162 @@ pub struct Config {
 
     /// Controls how much terminal output shpool keeps in memory for session restoration.
     /// Accepts memory sizes like "5MB", "1GB", "512KB", or "0" for no caching (SIGWINCH only).
-    /// Default: "5MB"
+    /// Default: "5MiB"
     pub session_restore: Option<String>,
+
+    /// How long a session must be detached with no output before its
+    /// session restore buffer gets compressed and swapped out to disk.
+    /// Accepts durations like "30m", "2h" or "01:00:00". By default
+    /// spools are never swapped out.
+    pub session_restore_swap_after: Option<String>,
+
+    /// The most output to replay when reattaching, counting back from
+    /// the most recent. Accepts memory sizes like "5MB". Default: "2MiB"
+    pub session_restore_max_replay: Option<String>,
+
+    /// How fast to replay output when reattaching, as a memory size
+    /// per second like "4MB". By default the replay is not paced.
+    pub session_restore_replay_rate: Option<String>,
+
+    /// How to get programs to repaint themselves when reattaching.
+    pub reattach_redraw: Option<ReattachRedraw>,
+
+    /// How to name a session when `shpool attach` is run without a
+    /// name. Default: "dir"
+    pub auto_name: Option<AutoName>,
+
+    /// What to do when reattaching to a session from a terminal with a
+    /// different TERM than the session was started with.
+    pub term_mismatch: Option<TermMismatch>,
+
+    /// If true, sessions whose shell has not set a terminal title of
+    /// its own get titled `shpool: <session>` when you attach.
+    pub default_title: Option<bool>,
+
+    /// How long a session must go with no client attached and no
+    /// output before the daemon kills it. Accepts durations like "72h"
+    /// or "3d". Can be overridden per session with `attach --idle-ttl`.
+    /// By default idle sessions are kept around forever.
+    pub auto_kill_after_idle: Option<String>,
+
+    /// How long a session that has been given a passphrase with
+    /// `shpool lock` may go with no client attached before it locks
+    /// again. Accepts durations like "30m". By default sessions only
+    /// lock when asked to.
+    pub auto_lock_after_idle: Option<String>,
+
+    /// How long an attached client may go without typing anything
+    /// before its screen gets blanked and locked, as a duration like
+    /// "15m". By default screens are never locked.
+    pub screen_lock_after_idle: Option<String>,
+
+    /// The most output per second a session may produce while no
+    /// client is attached, as a memory size like "1MB". Past that, the
+    /// daemon stops reading the session's output for the rest of the
+    /// second, which blocks the program doing the printing. By default
+    /// detached output is not limited.
+    pub detached_output_limit: Option<String>,
+
+    /// The most output that may be waiting to be written to a client
+    /// that is slow to read it, as a memory size like "1MB". Past that,
+    /// the oldest output gets dropped, and the client gets sent a fresh
+    /// restore buffer once it catches up. Default: "1MiB"
+    pub client_output_queue: Option<String>,
+
+    /// How often the daemon checks on attached clients by sending them
+    /// a heartbeat, as a duration like "2s". Default: "500ms"
+    pub heartbeat_interval: Option<String>,
+
+    /// How long an attached client may go without reading anything
+    /// before the daemon decides it has vanished and detaches it, as a
+    /// duration like "2m". Default: "60s"
+    pub heartbeat_timeout: Option<String>,
+
+    /// Print a line on reattach saying how long you were away, how much
+    /// output the session produced in the meantime and whether the
+    /// restore buffer still has all of it. Default: false
+    pub reconnect_summary: Option<bool>,
+
+    /// Escape sequences to strip out of session output before they
+    /// reach any client's terminal.
+    pub escape_filter: Option<EscapeFilter>,
+
+    /// Keep inline images in the restore buffer so that they get
+    /// replayed on reattach. Off by default, since images go stale and
+    /// a replayed one lands wherever the cursor happens to be.
+    pub replay_graphics: Option<bool>,
+
+    /// How commands like `list` and `stat` format output meant for
+    /// people.
+    pub output: Option<Output>,
+
+    /// Patterns to look for in the output of detached sessions, as
+    /// `[[watch]]` tables. See `shpool watch`.
+    pub watch: Option<Vec<Watch>>,
+
+    /// What to do with a session once its last client detaches: "keep"
+    /// it running, "stop" its processes until a client attaches again,
+    /// "kill" it, or kill it once it has gone without a client for a
+    /// duration like "30m". Default: "keep"
+    pub detach_policy: Option<String>,
+
+    /// Resource limits to apply to the shell of every new session, and
+    /// so to everything run from it. By default there are none beyond
+    /// the daemon's own.
+    pub rlimits: Option<Rlimits>,
+
+    /// Ceilings on how many sessions the daemon runs and how much
+    /// memory their session restore spools may use. By default there
+    /// are none.
+    pub limits: Option<Limits>,
+
+    /// Shell commands to run when sessions are created, attached to,
+    /// detached from, or exit.
+    pub hooks: Option<HookCmds>,
+
+    /// Desktop notifications for things that happen in detached
+    /// sessions.
+    pub notifications: Option<Notifications>,
+
+    /// How many lines of output to keep around for scroll mode.
+    /// Default: 1000
+    pub scrollback_lines: Option<usize>,
+
+    /// Also accept connections over TCP. Only read at daemon startup.
+    pub tcp: Option<TcpConfig>,
+
+    /// Also serve newline-delimited JSON-RPC requests for listing,
+    /// killing, detaching and stats on this socket. Relative paths
+    /// are taken relative to the runtime directory. Only read at
+    /// daemon startup.
+    pub json_socket: Option<String>,
+
+    /// The permission bits to give the daemon's socket, for example
+    /// `0o660`. Only read at daemon startup.
+    pub socket_mode: Option<u32>,
+
+    /// A group to hand the daemon's socket over to. Members of this
+    /// group are allowed to connect even though they are not the user
+    /// running the daemon, so they get full access to every session.
+    pub socket_group: Option<String>,
+
+    /// Tee the output of every session to log files. Individual
+    /// sessions can also turn this on with `attach --log-output`.
+    pub output_log: Option<OutputLogConfig>,
+
+    /// How shpool's own log output is formatted, and when the
+    /// `--log-file` gets rotated. Only read at startup.
+    pub log: Option<LogConfig>,
+
+    /// If true, attaching to a session that already has a client attached
+    /// mirrors the session to both clients rather than failing, as if
+    /// `--mirror` had been passed.
+    pub allow_multiple_clients: Option<bool>,
+
+    /// If true, attaching to a session that already has a client attached
+    /// takes the session over from that client, as if `-f` had been
+    /// passed. `attach --no-steal` turns this back off.
+    pub force_attach: Option<bool>,
     
     // Deprecated fields - kept for migration detection only, will cause program to exit with error
     pub session_restore_mode: Option<SessionRestoreMode>,
@@ -277,10 +697,15 @@ pub struct Config {
     /// The user supplied keybindings.
     pub keybinding: Option<Vec<Keybinding>>,
 
+    /// Settings for the keybinding engine, such as the leader key.
+    pub keybindings: Option<KeybindingsConfig>,
+
     /// A prefix to inject into the prompt of freshly spawned shells.
     /// The prefix will get included in the shell's prompt variable
-    /// verbatim except that the string '$SHPOOL_SESSION_NAME' will
-    /// get replaced with the actual name of the shpool session.
+    /// verbatim except that the placeholders `{session_name}` (or
+    /// '$SHPOOL_SESSION_NAME'), `{attach_count}` and `{daemon_version}`
+    /// get filled in. The prefix gets refreshed every time the session
+    /// is attached to.
     ///
     /// To disable the prompt prefix entirely, simply set a blank
     /// prompt prefix (`prompt_prefix = ""`). You can then optionally
@@ -288,9 +713,26 @@ pub struct Config {
     /// environment variable.
     pub prompt_prefix: Option<String>,
 
+    /// Shells (by name, e.g. "fish") which should never have the
+    /// prompt prefix injected, even when a prompt prefix is set.
+    pub prompt_prefix_skip_shells: Option<Vec<String>>,
+
+    /// Custom snippets, keyed by shell name (e.g. "nu" or "elvish"),
+    /// to inject in place of the built in prompt prefix hook. The
+    /// placeholders `{prefix_file}`, the path to a file holding the
+    /// current prefix as plain text, and `{session_dir}` get filled in.
+    pub prompt_hooks: Option<HashMap<String, String>>,
+
     /// Control when and how shpool will display the message of the day.
     pub motd: Option<MotdDisplayMode>,
 
+    /// A template for the message to show in place of the plain
+    /// message of the day. `{motd}` gets replaced with the message of
+    /// the day, and `{session_name}`, `{created_at}`, `{sessions}`,
+    /// `{detached_sessions}` and `{daemon_version}` with information
+    /// about the session and daemon.
+    pub motd_template: Option<String>,
+
     /// Override arguments to pass to pam_motd.so when resolving the
     /// message of the day. Normally, you want to leave this blank
     /// so that shpool will scrape the default arguments used in
@@ -309,7 +751,11 @@ pub struct Config {
     /// [aliases]
     /// dt = "detach"
     /// at = "attach"
-    pub aliases: Option<HashMap<String, String>>,
+    /// An alias can also expand to a whole argument list, given either
+    /// as a list or as a string that gets split like a shell would:
+    /// work = ["attach", "--ttl", "8h", "work"]
+    /// See `expand_aliases` for how arguments get filled in.
+    pub aliases: Option<HashMap<String, Alias>>,
 
     /// The default directory to start new shell sessions in.
     /// If not specified, new sessions will start in the current
@@ -317,9 +763,83 @@ pub struct Config {
     /// This can be overridden on a per-session basis using the
     /// --dir command line flag.
     pub start_directory: Option<String>,
+
+    /// Overrides for sessions with particular names, keyed by session
+    /// name. For example:
+    /// [sessions.irc]
+    /// cmd = "weechat"
+    /// session_restore = "screen"
+    /// The key can also be a glob pattern like "dev-*", which applies
+    /// to every matching session without a table of its own.
+    pub sessions: Option<HashMap<String, SessionConfig>>,
+
+    /// The sessions that `shpool up` makes sure are running, in the
+    /// order they get created. Each one is set up according to its
+    /// table in `sessions`, if it has one.
+    pub up: Option<Vec<String>>,
+
+    /// What to do with a session once its shell exits. "immediately"
+    /// removes it right away. "never" keeps it around showing the
+    /// final screen until it is explicitly killed, and a duration like
+    /// "30m" keeps it around for that long.
+    /// Default: "immediately"
+    pub reap_exited: Option<String>,
 }
 
 impl Config {
+    /// The overrides for the session with the given name, if there are any.
+    /// A table for the exact name wins over any patterns that match it.
+    /// If several patterns match, the longest one, which is usually the
+    /// most specific, wins, with ties going to the alphabetically first
+    /// so that the choice doesn't change from one run to the next.
+    pub fn session(&self, name: &str) -> Option<&SessionConfig> {
+        let sessions = self.sessions.as_ref()?;
+        if let Some(session) = sessions.get(name) {
+            return Some(session);
+        }
+        sessions
+            .iter()
+            .filter(|(pattern, _)| {
+                glob::Pattern::new(pattern).map(|p| p.matches(name)).unwrap_or(false)
+            })
+            .max_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+            .map(|(_, session)| session)
+    }
+
+    /// The resource limits for the named session, with any set in its
+    /// `[sessions.<name>.rlimits]` table taking priority over the global
+    /// ones.
+    pub fn rlimits(&self, session: &str) -> Option<Rlimits> {
+        let overrides = self.session(session).and_then(|s| s.rlimits.clone());
+        match (overrides, self.rlimits.clone()) {
+            (Some(overrides), Some(global)) => Some(overrides.merge(global)),
+            (overrides, global) => overrides.or(global),
+        }
+    }
+
+    /// The escape filter for the named session, with anything set in
+    /// its `[sessions.<name>.escape_filter]` table taking priority over
+    /// the global one.
+    pub fn escape_filter(&self, session: &str) -> EscapeFilter {
+        let overrides = self.session(session).and_then(|s| s.escape_filter.clone());
+        match (overrides, self.escape_filter.clone()) {
+            (Some(overrides), Some(global)) => overrides.merge(global),
+            (overrides, global) => overrides.or(global).unwrap_or_default(),
+        }
+    }
+
+    /// How to start a daemon when there isn't one running, if at all,
+    /// going by `autostart_daemon` and then `nodaemonize`.
+    pub fn autostart(&self) -> Option<Autostart> {
+        match self.autostart_daemon {
+            Some(AutostartDaemon::Enabled(true)) => Some(Autostart::Fork),
+            Some(AutostartDaemon::Enabled(false)) => None,
+            Some(AutostartDaemon::With(autostart)) => Some(autostart),
+            None if self.nodaemonize.unwrap_or(false) => None,
+            None => Some(Autostart::Fork),
+        }
+    }
+
     /// Merge with `another` Config instance, with `self` taking higher
     /// priority, i.e. it is not commutative.
     ///
@@ -336,22 +856,73 @@ impl Config {
             noread_etc_environment: self.noread_etc_environment.or(another.noread_etc_environment),
             nodaemonize: self.nodaemonize.or(another.nodaemonize),
             nodaemonize_timeout: self.nodaemonize_timeout.or(another.nodaemonize_timeout),
+            autostart_daemon: self.autostart_daemon.or(another.autostart_daemon),
             shell: self.shell.or(another.shell),
             env: self.env.or(another.env),
             forward_env: self.forward_env.or(another.forward_env),
+            refresh_env: self.refresh_env.or(another.refresh_env),
+            forward_sockets: self.forward_sockets.or(another.forward_sockets),
             initial_path: self.initial_path.or(another.initial_path),
             session_restore: self.session_restore.or(another.session_restore),
+            session_restore_swap_after: self
+                .session_restore_swap_after
+                .or(another.session_restore_swap_after),
+            session_restore_max_replay: self
+                .session_restore_max_replay
+                .or(another.session_restore_max_replay),
+            session_restore_replay_rate: self
+                .session_restore_replay_rate
+                .or(another.session_restore_replay_rate),
+            reattach_redraw: self.reattach_redraw.or(another.reattach_redraw),
+            auto_name: self.auto_name.or(another.auto_name),
+            term_mismatch: self.term_mismatch.or(another.term_mismatch),
+            default_title: self.default_title.or(another.default_title),
+            auto_kill_after_idle: self.auto_kill_after_idle.or(another.auto_kill_after_idle),
+            auto_lock_after_idle: self.auto_lock_after_idle.or(another.auto_lock_after_idle),
+            screen_lock_after_idle: self.screen_lock_after_idle.or(another.screen_lock_after_idle),
+            detached_output_limit: self.detached_output_limit.or(another.detached_output_limit),
+            client_output_queue: self.client_output_queue.or(another.client_output_queue),
+            heartbeat_interval: self.heartbeat_interval.or(another.heartbeat_interval),
+            heartbeat_timeout: self.heartbeat_timeout.or(another.heartbeat_timeout),
+            reconnect_summary: self.reconnect_summary.or(another.reconnect_summary),
+            escape_filter: self.escape_filter.or(another.escape_filter),
+            replay_graphics: self.replay_graphics.or(another.replay_graphics),
+            output: self.output.or(another.output),
+            watch: self.watch.or(another.watch),
+            detach_policy: self.detach_policy.or(another.detach_policy),
+            rlimits: self.rlimits.or(another.rlimits),
+            limits: self.limits.or(another.limits),
+            hooks: self.hooks.or(another.hooks),
+            notifications: self.notifications.or(another.notifications),
+            scrollback_lines: self.scrollback_lines.or(another.scrollback_lines),
+            output_log: self.output_log.or(another.output_log),
+            log: self.log.or(another.log),
+            tcp: self.tcp.or(another.tcp),
+            json_socket: self.json_socket.or(another.json_socket),
+            socket_mode: self.socket_mode.or(another.socket_mode),
+            socket_group: self.socket_group.or(another.socket_group),
+            allow_multiple_clients: self.allow_multiple_clients.or(another.allow_multiple_clients),
+            force_attach: self.force_attach.or(another.force_attach),
             
             // Deprecated fields
             session_restore_mode: self.session_restore_mode.or(another.session_restore_mode),
             output_spool_lines: self.output_spool_lines.or(another.output_spool_lines),
             vt100_output_spool_width: self.vt100_output_spool_width.or(another.vt100_output_spool_width),
             keybinding: self.keybinding.or(another.keybinding),
+            keybindings: self.keybindings.or(another.keybindings),
             prompt_prefix: self.prompt_prefix.or(another.prompt_prefix),
+            prompt_prefix_skip_shells: self
+                .prompt_prefix_skip_shells
+                .or(another.prompt_prefix_skip_shells),
+            prompt_hooks: self.prompt_hooks.or(another.prompt_hooks),
             motd: self.motd.or(another.motd),
+            motd_template: self.motd_template.or(another.motd_template),
             motd_args: self.motd_args.or(another.motd_args),
             aliases: self.aliases.or(another.aliases),
             start_directory: self.start_directory.or(another.start_directory),
+            sessions: self.sessions.or(another.sessions),
+            up: self.up.or(another.up),
+            reap_exited: self.reap_exited.or(another.reap_exited),
         }
     }
 }
@@ -365,11 +936,47 @@ impl Default for Config {
             noread_etc_environment: None,
             nodaemonize: None,
             nodaemonize_timeout: None,
+            autostart_daemon: None,
             shell: None,
             env: None,
             forward_env: None,
+            refresh_env: None,
+            forward_sockets: None,
             initial_path: None,
-            session_restore: Some("5MB".to_string()),  // Default value
+            session_restore: Some("5MiB".to_string()),  // Default value
+            session_restore_swap_after: None,
+            session_restore_max_replay: None,
+            session_restore_replay_rate: None,
+            reattach_redraw: None,
+            auto_name: None,
+            term_mismatch: None,
+            default_title: None,
+            auto_kill_after_idle: None,
+            auto_lock_after_idle: None,
+            screen_lock_after_idle: None,
+            detached_output_limit: None,
+            client_output_queue: None,
+            heartbeat_interval: None,
+            heartbeat_timeout: None,
+            reconnect_summary: None,
+            escape_filter: None,
+            replay_graphics: None,
+            output: None,
+            watch: None,
+            detach_policy: None,
+            rlimits: None,
+            limits: None,
+            hooks: None,
+            notifications: None,
+            scrollback_lines: None,
+            output_log: None,
+            log: None,
+            tcp: None,
+            json_socket: None,
+            socket_mode: None,
+            socket_group: None,
+            allow_multiple_clients: None,
+            force_attach: None,
             
             // Deprecated fields - always None in default
             session_restore_mode: None,
@@ -377,16 +984,445 @@ impl Default for Config {
             vt100_output_spool_width: None,
             
             keybinding: None,
+            keybindings: None,
             prompt_prefix: None,
+            prompt_prefix_skip_shells: None,
+            prompt_hooks: None,
             motd: None,
+            motd_template: None,
             motd_args: None,
             aliases: None,
             start_directory: None,
+            sessions: None,
+            up: None,
+            reap_exited: None,
+        }
+    }
+}
+
+/// Commands to run on session lifecycle events. Each command gets run
+/// with `/bin/sh -c` with SHPOOL_HOOK_EVENT, SHPOOL_SESSION_NAME and,
+/// where known, SHPOOL_SESSION_PID set in its environment. The exit
+/// hook also gets SHPOOL_EXIT_STATUS.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HookCmds {
+    /// Run when a new session gets created.
+    pub on_create: Option<String>,
+    /// Run whenever a client attaches to a session, including the
+    /// first attach to a freshly created session.
+    pub on_attach: Option<String>,
+    /// Run when a client detaches from a session which keeps running.
+    pub on_detach: Option<String>,
+    /// Run when a session's shell exits.
+    pub on_exit: Option<String>,
+    /// Run when the output of a detached session matches a watch
+    /// that has no `exec` of its own.
+    pub on_watch: Option<String>,
+}
+
+/// Which events in detached sessions raise a desktop notification.
+/// Everything is off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Notifications {
+    /// When a detached session's shell exits.
+    pub on_exit: Option<bool>,
+    /// When a detached session rings the bell. Only the first bell
+    /// after a detach counts.
+    pub on_bell: Option<bool>,
+    /// When a command finishes in a detached session, as marked by
+    /// the OSC 133;D sequence shells with prompt integration emit.
+    pub on_command_done: Option<bool>,
+    /// The program to run to show a notification. It gets called like
+    /// notify-send, with a summary and a body as its last two arguments.
+    /// Default: notify-send
+    pub command: Option<String>,
+}
+
+/// Settings for accepting connections over TCP.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TcpConfig {
+    /// The address to listen on, for example "127.0.0.1:7777". Without
+    /// `cert_file` and `key_file` nothing is encrypted, so this has to
+    /// be a loopback address reached through an ssh port forward or
+    /// similar.
+    pub listen: Option<String>,
+    /// A file holding the token clients must present to connect.
+    /// Required if `listen` is set.
+    pub token_file: Option<String>,
+    /// A PEM file holding the certificate chain to serve TLS with.
+    pub cert_file: Option<String>,
+    /// A PEM file holding the private key for `cert_file`.
+    pub key_file: Option<String>,
+}
+
+/// Where and how to log session output.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OutputLogConfig {
+    /// The directory to write log files to. Each session logs to
+    /// files named `<session>-<timestamp>.log` (or `.cast`) in here.
+    /// Output is only logged for every session if this is set.
+    pub dir: Option<String>,
+    /// How big a log file may get before a new one is started, using
+    /// the same format as session_restore (for example "10MB").
+    /// Default: "10MB"
+    pub max_size: Option<String>,
+    /// How many log files to keep for each session, including the
+    /// current one. Default: 5
+    pub keep: Option<usize>,
+    /// What to write to the log files. Default: "raw"
+    pub format: Option<OutputLogFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputLogFormat {
+    /// The terminal output as is, in `.log` files.
+    #[default]
+    Raw,
+    /// asciicast v2 recordings in `.cast` files, which keep the
+    /// timing of the output and can be played with `shpool replay`.
+    Asciicast,
+}
+
+/// Settings for shpool's own log output.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LogConfig {
+    /// The format of each log line. Default: "text"
+    pub format: Option<LogFormat>,
+    /// Rotate the `--log-file` once it would grow past this size,
+    /// using the same format as session_restore (for example "10MB").
+    pub max_size: Option<String>,
+    /// Rotate the `--log-file` once it has been written to for this
+    /// long, for example "1d".
+    pub rotate_every: Option<String>,
+    /// How many rotated log files to keep, not counting the current
+    /// one. Default: 5
+    pub keep: Option<usize>,
+    /// Include terminal content in trace and debug logging. It can
+    /// hold passwords typed at hidden prompts, so by default only its
+    /// length gets logged. Default: false
+    pub payloads: Option<bool>,
+    /// Regular expressions to scrub out of every log line, replacing
+    /// matches with `[REDACTED]`.
+    pub redact: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Free-form lines meant for people.
+    #[default]
+    Text,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+/// Settings that override the global config for a single named
+/// session. Like the global settings they only apply when the session
+/// is created, and flags passed to `shpool attach` take priority.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionConfig {
+    /// The shell to run, overriding the global `shell` value.
+    pub shell: Option<String>,
+    /// Environment variables to inject, layered on top of the
+    /// global `env` table.
+    pub env: Option<HashMap<String, String>>,
+    /// How much output to keep for session restore, overriding
+    /// the global `session_restore` value.
+    pub session_restore: Option<String>,
+    /// How long the session may live, like `attach --ttl`.
+    pub ttl: Option<String>,
+    /// A command to run instead of the shell, like `attach --cmd`.
+    pub cmd: Option<String>,
+    /// The directory to start the session in, like `attach --dir`.
+    /// Takes priority over the global `start_directory`. A leading `~`
+    /// stands for your home directory.
+    pub dir: Option<String>,
+    /// A command to type into the shell once it has started, for
+    /// example to activate a virtualenv or launch an editor. Unlike
+    /// `cmd` the shell keeps running once the command finishes. Not
+    /// used if the session runs a `cmd` instead of the shell.
+    pub startup: Option<String>,
+    /// Resource limits, layered on top of the global `rlimits` table.
+    pub rlimits: Option<Rlimits>,
+    /// What to do once the last client detaches, overriding the global
+    /// `detach_policy` value.
+    pub detach_policy: Option<String>,
+    /// Escape sequences to strip, layered on top of the global
+    /// `escape_filter` table.
+    pub escape_filter: Option<EscapeFilter>,
+    /// When to start `cmd` again after it exits, "on-failure" or
+    /// "always". By default it is left dead.
+    pub restart: Option<String>,
+}
+
+/// Resource limits for a session's shell. These are rlimits, so each
+/// process in the session gets held to them separately.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Rlimits {
+    /// The most memory each process may map, as a memory size like
+    /// "4GB" (RLIMIT_AS).
+    pub memory: Option<String>,
+    /// The most cpu time each process may use, as a duration like
+    /// "2h" (RLIMIT_CPU).
+    pub cpu_time: Option<String>,
+    /// The most files each process may have open (RLIMIT_NOFILE).
+    pub open_files: Option<u64>,
+    /// The most processes the user may have (RLIMIT_NPROC). Note that
+    /// this counts all of the user's processes, not just the session's.
+    pub processes: Option<u64>,
+}
+
+impl Rlimits {
+    /// Merge with `another`, with `self` taking priority for each limit.
+    pub fn merge(self, another: Rlimits) -> Rlimits {
+        Rlimits {
+            memory: self.memory.or(another.memory),
+            cpu_time: self.cpu_time.or(another.cpu_time),
+            open_files: self.open_files.or(another.open_files),
+            processes: self.processes.or(another.processes),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// How to format output meant for people. Machine readable formats
+/// like `list --format json` are left alone.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Output {
+    /// Whether to use color. Default: "auto", which uses color when
+    /// stdout is a terminal and NO_COLOR is unset or empty.
+    pub color: Option<ColorChoice>,
+    /// Whether to show times as when they were, or as how long ago.
+    /// Default: "absolute"
+    pub timestamps: Option<Timestamps>,
+    /// The columns the `list` table shows when not given --columns,
+    /// by the names --columns takes.
+    pub list_columns: Option<Vec<String>>,
+    /// The styles to use when color is on.
+    pub theme: Option<Theme>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Timestamps {
+    /// Like 2024-05-01T10:00:00+00:00.
+    #[default]
+    Absolute,
+    /// Like 2h13m ago.
+    Relative,
+}
+
+/// Styles for the parts of the output that get colored, each a space
+/// separated list of attributes and colors like "bold red", or "none".
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Theme {
+    /// Table headers and field labels. Default: "bold"
+    pub header: Option<String>,
+    /// Attached sessions. Default: "green"
+    pub attached: Option<String>,
+    /// Sessions whose shell has exited. Default: "red"
+    pub exited: Option<String>,
+    /// Sessions with something worth a look, like a bell or having
+    /// been throttled. Default: "yellow"
+    pub alert: Option<String>,
+}
+
+/// Which escape sequences to strip out of session output. Everything
+/// is allowed by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EscapeFilter {
+    /// Title changes (OSC 0, 1 and 2).
+    pub title: Option<FilterAction>,
+    /// Clipboard writes and reads (OSC 52).
+    pub clipboard: Option<FilterAction>,
+    /// Sequences the terminal answers by typing into the session, like
+    /// cursor position and device attribute requests or color queries.
+    pub queries: Option<FilterAction>,
+    /// Device control strings (DCS), which includes sixel graphics and
+    /// tmux passthrough.
+    pub dcs: Option<FilterAction>,
+    /// Inline images: sixel, the kitty graphics protocol and iTerm2's
+    /// inline files.
+    pub graphics: Option<FilterAction>,
+}
+
+impl EscapeFilter {
+    /// Merge with `another`, with `self` taking priority.
+    pub fn merge(self, another: EscapeFilter) -> EscapeFilter {
+        EscapeFilter {
+            title: self.title.or(another.title),
+            clipboard: self.clipboard.or(another.clipboard),
+            queries: self.queries.or(another.queries),
+            dcs: self.dcs.or(another.dcs),
+            graphics: self.graphics.or(another.graphics),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Pass the sequence on.
+    #[default]
+    Allow,
+    /// Drop the sequence.
+    Strip,
+}
+
+/// Ceilings on what the daemon takes on, for hosts shared by many
+/// users.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Limits {
+    /// The most sessions each user may have at once. Sessions count
+    /// against whoever created them.
+    pub max_sessions: Option<usize>,
+    /// The most memory the session restore spools of all sessions may
+    /// hold together, as a memory size like "256MB".
+    pub max_spool_memory: Option<String>,
+    /// The most memory the spool of any one session may hold, as a
+    /// memory size like "10MB". Bigger `session_restore` sizes get
+    /// cut down to this.
+    pub max_session_spool: Option<String>,
+    /// What to do once the spools together reach `max_spool_memory`.
+    /// Default: "evict"
+    pub spool_overflow: Option<SpoolOverflow>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolOverflow {
+    /// Throw away the spools of the detached sessions that have gone
+    /// the longest without output until there is room again.
+    #[default]
+    Evict,
+    /// Refuse to create new sessions until there is room again.
+    Refuse,
+}
+
+/// The `autostart_daemon` option, which is either a bool or says how
+/// to start the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AutostartDaemon {
+    Enabled(bool),
+    With(Autostart),
+}
+
+/// How to start a daemon that isn't running.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Autostart {
+    /// Fork a daemon in the background.
+    Fork,
+    /// Start the `shpool.service` systemd user unit.
+    Systemd,
+}
+
+/// What an alias expands to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Alias {
+    /// A command line, split into words the way a shell would.
+    Line(String),
+    /// A list of words.
+    Words(Vec<String>),
+}
+
+impl Alias {
+    /// The words the alias expands to, before any placeholders are
+    /// filled in.
+    pub fn words(&self) -> Result<Vec<String>> {
+        let words = match self {
+            Alias::Line(line) => shell_words::split(line).context("splitting alias")?,
+            Alias::Words(words) => words.clone(),
+        };
+        if words.is_empty() {
+            return Err(anyhow!("alias is empty"));
+        }
+        Ok(words)
+    }
+}
+
+impl From<&str> for Alias {
+    fn from(line: &str) -> Self {
+        Alias::Line(String::from(line))
+    }
+}
+
+/// Expand the alias at the front of `args`, which start at the
+/// subcommand, if there is one there. If the expansion itself starts
+/// with an alias, that gets expanded too, and an alias that ends up
+/// back at itself is an error.
+///
+/// The arguments following the alias get tacked onto the end of its
+/// expansion, unless the expansion says where they go with placeholders:
+/// `$1`, `$2` and so on for single arguments (which can be part of a
+/// word, as in "--dir=$1") and a bare `$@` word for all of them.
+pub fn expand_aliases(aliases: &HashMap<String, Alias>, args: &[String]) -> Result<Vec<String>> {
+    let mut args = args.to_vec();
+    let mut expanded: Vec<String> = vec![];
+    while let Some(alias) = args.first().and_then(|cmd| aliases.get(cmd)) {
+        let name = args.remove(0);
+        if expanded.contains(&name) {
+            expanded.push(name);
+            return Err(anyhow!("alias loop: {}", expanded.join(" -> ")));
+        }
+        let words = alias.words().with_context(|| format!("expanding alias '{name}'"))?;
+        args = fill_placeholders(&words, &args)
+            .with_context(|| format!("expanding alias '{name}'"))?;
+        expanded.push(name);
+    }
+    Ok(args)
+}
+
+fn fill_placeholders(words: &[String], args: &[String]) -> Result<Vec<String>> {
+    let mut filled = vec![];
+    let mut has_placeholders = false;
+    for word in words {
+        if word == "$@" {
+            filled.extend(args.iter().cloned());
+            has_placeholders = true;
+            continue;
+        }
+
+        let mut filled_word = String::new();
+        let mut rest = word.as_str();
+        while let Some(dollar) = rest.find('$') {
+            filled_word.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+            let ndigits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            if ndigits == 0 {
+                filled_word.push('$');
+                continue;
+            }
+            let n: usize = rest[..ndigits].parse().context("parsing placeholder")?;
+            let arg = n
+                .checked_sub(1)
+                .and_then(|i| args.get(i))
+                .ok_or_else(|| anyhow!("no argument to fill in ${}", n))?;
+            filled_word.push_str(arg);
+            rest = &rest[ndigits..];
+            has_placeholders = true;
+        }
+        filled_word.push_str(rest);
+        filled.push(filled_word);
+    }
+    if !has_placeholders {
+        filled.extend(args.iter().cloned());
+    }
+    Ok(filled)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Keybinding {
     /// The keybinding to map to an action. The syntax for these keybindings
     /// is described in src/daemon/keybindings.rs.
@@ -395,7 +1431,47 @@ pub struct Keybinding {
     pub action: keybindings::Action,
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+/// A pattern to look for in the output of detached sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Watch {
+    /// The sessions to watch, by name or by a glob pattern like
+    /// "build-*". Default: every session.
+    pub session: Option<String>,
+    /// A regular expression to match against each line of output,
+    /// with escape sequences stripped out.
+    pub pattern: String,
+    /// A shell command to run on the first match after a detach,
+    /// instead of the `on_watch` hook.
+    pub exec: Option<String>,
+}
+
+impl Watch {
+    /// Whether the watch applies to the session with the given name.
+    pub fn applies_to(&self, name: &str) -> bool {
+        match &self.session {
+            None => true,
+            Some(session) if session == name => true,
+            Some(session) => glob::Pattern::new(session).map(|p| p.matches(name)).unwrap_or(false),
+        }
+    }
+}
+
+/// The `[keybindings]` table, which sets up a tmux style leader key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeybindingsConfig {
+    /// The chord that the word `Leader` stands for in keybindings,
+    /// for example "Ctrl-a".
+    pub leader: Option<String>,
+    /// How long, in milliseconds, to wait for the next key of a
+    /// multi-key binding before giving up and passing the keys typed
+    /// so far through to the shell. By default shpool waits forever.
+    pub timeout_ms: Option<u64>,
+    /// Keys to press after the leader, mapped to the action to perform.
+    /// `d = "detach"` is shorthand for binding "Leader d" to detach.
+    pub leader_bindings: Option<HashMap<String, keybindings::Action>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum SessionRestoreMode {
     /// Just reattach to the pty and issue SIGWINCH to force apps like
@@ -414,7 +1490,50 @@ pub enum SessionRestoreMode {
     Lines(u16),
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoName {
+    /// Name the session after the directory `shpool attach` was run
+    /// in, like `api` for `~/src/api`.
+    #[default]
+    Dir,
+    /// Number sessions `1`, `2`, `3` and so on.
+    Counter,
+    /// Make up an adjective-noun name like `brave-otter`.
+    Pet,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TermMismatch {
+    /// Warn about the mismatch, and if the session expects more colors
+    /// than the terminal can show, translate its colors to ones the
+    /// terminal has.
+    #[default]
+    Downgrade,
+    /// Just warn about the mismatch.
+    Warn,
+    /// Say nothing and pass output through as is.
+    Ignore,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReattachRedraw {
+    /// Briefly make the pty one row and column bigger than the client's
+    /// terminal before setting it to the right size. Programs get a
+    /// resize notification with a real size change, so even ones that
+    /// ignore a resize to the size they already have repaint themselves.
+    #[default]
+    Bounce,
+    /// Just set the pty to the size of the client's terminal, which
+    /// only notifies programs if the size actually changed. Use this
+    /// if your shell redraws its prompt on every resize and the extra
+    /// prompts bother you.
+    Resize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum MotdDisplayMode {
     /// Never display the message of the day.
@@ -450,6 +1569,11 @@ pub enum MotdDisplayMode {
     /// There is no safe way to dump directly when reattaching,
     /// so we don't attempt it.
     Dump,
+
+    /// Print the first line of the message of the day on every attach,
+    /// before the session's output. Mostly useful with a motd_template
+    /// that fits on one line.
+    Banner,
 }
 
 #[cfg(test)]
@@ -483,7 +1607,7 @@ mod test {
                 )),
                 SessionRestoreMode::Screen => warnings.push((
                     "'session_restore_mode = \"screen\"' is deprecated".to_string(),
-                    "Use 'session_restore = \"1MB\"' instead".to_string()
+                    "Use 'session_restore = \"screen\"' instead".to_string()
                 )),
                 SessionRestoreMode::Lines(n) => {
                     let mb = std::cmp::max(1, (*n as usize * 200) / (1024 * 1024));
@@ -516,11 +1640,54 @@ mod test {
             action = "detach"
             "#,
             r#"
+            [keybindings]
+            leader = "Ctrl-a"
+            timeout_ms = 1000
+            [keybindings.leader_bindings]
+            d = "detach"
+            "#,
+            r#"
             [aliases]
             dt = "detach"
             at = "attach"
             ls = "list"
             "#,
+            r#"
+            [sessions.irc]
+            cmd = "weechat"
+            ttl = "30d"
+            [sessions.build.env]
+            CCACHE_DIR = "/tmp/ccache"
+            "#,
+            r#"
+            reattach_redraw = "resize"
+            default_title = true
+            "#,
+            r#"
+            socket_mode = 0o660
+            socket_group = "devteam"
+            "#,
+            r#"
+            [log]
+            format = "json"
+            max_size = "10MB"
+            rotate_every = "1d"
+            keep = 3
+            "#,
+            r#"
+            up = ["editor", "server"]
+            [sessions.editor]
+            cmd = "nvim"
+            "#,
+            r#"
+            reap_exited = "30m"
+            "#,
+            r#"
+            forward_sockets = ["GPG_AGENT_SOCK"]
+            "#,
+            r#"
+            detached_output_limit = "1MB"
+            "#,
         ];
 
         for case in cases.into_iter() {
@@ -618,15 +1785,15 @@ mod test {
         fn aliases_value() -> Result<()> {
             let higher = Config {
                 aliases: Some(HashMap::from([
-                    ("dt".to_string(), "detach".to_string()),
-                    ("at".to_string(), "attach".to_string()),
+                    ("dt".to_string(), "detach".into()),
+                    ("at".to_string(), "attach".into()),
                 ])),
                 ..Default::default()
             };
             let lower = Config {
                 aliases: Some(HashMap::from([
-                    ("ls".to_string(), "list".to_string()),
-                    ("sw".to_string(), "switch".to_string()),
+                    ("ls".to_string(), "list".into()),
+                    ("sw".to_string(), "switch".into()),
                 ])),
                 ..Default::default()
             };
@@ -636,8 +1803,8 @@ mod test {
             assert_eq!(
                 actual.aliases,
                 Some(HashMap::from([
-                    ("dt".to_string(), "detach".to_string()),
-                    ("at".to_string(), "attach".to_string()),
+                    ("dt".to_string(), "detach".into()),
+                    ("at".to_string(), "attach".into()),
                 ]))
             );
             Ok(())
@@ -658,15 +1825,45 @@ mod test {
         let config: Config = toml::from_str(config_str)?;
         
         let aliases = config.aliases.unwrap();
-        assert_eq!(aliases.get("dt"), Some(&"detach".to_string()));
-        assert_eq!(aliases.get("at"), Some(&"attach".to_string()));
-        assert_eq!(aliases.get("sw"), Some(&"switch".to_string()));
-        assert_eq!(aliases.get("ls"), Some(&"list".to_string()));
+        assert_eq!(aliases.get("dt"), Some(&"detach".into()));
+        assert_eq!(aliases.get("at"), Some(&"attach".into()));
+        assert_eq!(aliases.get("sw"), Some(&"switch".into()));
+        assert_eq!(aliases.get("ls"), Some(&"list".into()));
         assert_eq!(aliases.get("nonexistent"), None);
 
         Ok(())
     }
 
+    #[test]
+    fn alias_expansion() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            at = "attach"
+            work = ["at", "--ttl", "8h"]
+            dev = "attach --dir='/src/$1' dev-$1"
+            all = ["kill", "$@", "scratch"]
+            loop1 = "loop2 -f"
+            loop2 = "loop1"
+        "#,
+        )?;
+        let aliases = config.aliases.unwrap();
+        let expand = |args: &[&str]| {
+            expand_aliases(&aliases, &args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(expand(&["list"])?, vec!["list"]);
+        assert_eq!(expand(&["at", "-f", "main"])?, vec!["attach", "-f", "main"]);
+        assert_eq!(expand(&["work", "main"])?, vec!["attach", "--ttl", "8h", "main"]);
+        assert_eq!(expand(&["dev", "shpool"])?, vec!["attach", "--dir=/src/shpool", "dev-shpool"]);
+        assert_eq!(expand(&["all", "a", "b"])?, vec!["kill", "a", "b", "scratch"]);
+        assert!(expand(&["dev"]).is_err());
+        let err = expand(&["loop1"]).unwrap_err();
+        assert_eq!(format!("{err}"), "alias loop: loop1 -> loop2 -> loop1");
+
+        Ok(())
+    }
+
     #[test]
     fn test_deprecated_config_detection() -> Result<()> {
         // Test deprecated session_restore_mode detection
@@ -697,7 +1894,7 @@ mod test {
         let warnings = get_deprecated_config_warnings(&config);
         assert_eq!(warnings.len(), 1);
         assert!(warnings[0].0.contains("screen"));
-        assert!(warnings[0].1.contains("1MB"));
+        assert!(warnings[0].1.contains("session_restore = \"screen\""));
 
         // Test deprecated output_spool_lines detection
         let config_str = r#"
@@ -743,4 +1940,201 @@ mod test {
         
         Ok(())
     }
+
+    #[test]
+    #[timeout(30000)]
+    fn reload() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("config.toml");
+        fs::write(&path, "prompt_prefix = \"old\"\n")?;
+        let manager = Manager::new(Some(path.to_str().unwrap()))?;
+        assert_eq!(manager.get().prompt_prefix.as_deref(), Some("old"));
+
+        fs::write(&path, "prompt_prefix = \"new\"\n")?;
+        manager.reload()?;
+        assert_eq!(manager.get().prompt_prefix.as_deref(), Some("new"));
+
+        // a broken config leaves the last good one in place
+        fs::write(&path, "prompt_prefix = \n")?;
+        assert!(manager.reload().is_err());
+        fs::write(&path, "output_spool_lines = 100\n")?;
+        assert!(manager.reload().is_err());
+        assert_eq!(manager.get().prompt_prefix.as_deref(), Some("new"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn env_layer() -> Result<()> {
+        let vars = [
+            ("SHPOOL_NORC", "true"),
+            ("SHPOOL_SESSION_RESTORE", "10MiB"),
+            ("SHPOOL_FORWARD_ENV", "[\"TERM_PROGRAM\", \"LANG\"]"),
+            ("SHPOOL_LOG", "{ format = \"json\" }"),
+            // not config keys
+            ("SHPOOL_SESSION_NAME", "main"),
+            ("SHPOOL__INTERNAL__AUTODAEMONIZE", "true"),
+            ("HOME", "/home/me"),
+        ];
+        let EnvLayer { config, names, skipped } = Manager::env_layer(
+            vars.iter().map(|(var, value)| (String::from(*var), String::from(*value))),
+        )?;
+        assert!(skipped.is_empty());
+        assert_eq!(config.norc, Some(true));
+        assert_eq!(config.session_restore.as_deref(), Some("10MiB"));
+        assert_eq!(
+            config.forward_env,
+            Some(vec![String::from("TERM_PROGRAM"), String::from("LANG")])
+        );
+        assert_eq!(config.log.and_then(|log| log.format), Some(LogFormat::Json));
+        assert_eq!(
+            names.keys().collect::<Vec<_>>(),
+            vec!["forward_env", "log", "norc", "session_restore"]
+        );
+        assert_eq!(names["norc"], "SHPOOL_NORC");
+
+        // values that look like other types are still fine for keys
+        // that take strings
+        let vars = [
+            ("SHPOOL_PROMPT_PREFIX", "1"),
+            ("SHPOOL_SHELL", "true"),
+            ("SHPOOL_NORC", "sometimes"),
+            ("SHPOOL_NOECHO", "true"),
+        ];
+        let EnvLayer { config, names, skipped } = Manager::env_layer(
+            vars.iter().map(|(var, value)| (String::from(*var), String::from(*value))),
+        )?;
+        assert_eq!(config.prompt_prefix.as_deref(), Some("1"));
+        assert_eq!(config.shell.as_deref(), Some("true"));
+        assert_eq!(config.noecho, Some(true));
+        // a bad value gets skipped without spoiling the rest
+        assert_eq!(config.norc, None);
+        assert_eq!(names.keys().collect::<Vec<_>>(), vec!["noecho", "prompt_prefix", "shell"]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, "SHPOOL_NORC");
+
+        Ok(())
+    }
+
+    #[test]
+    fn origins() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let system = tmp_dir.path().join("system.toml");
+        let user = tmp_dir.path().join("user.toml");
+        fs::write(&system, "shell = \"/bin/zsh\"\nprompt_prefix = \"sys\"\n")?;
+        fs::write(&user, "prompt_prefix = \"user\"\n")?;
+
+        let (config, origins) = Manager::load_with_origins([&system, &user])?;
+        assert_eq!(config.shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(config.prompt_prefix.as_deref(), Some("user"));
+        assert_eq!(origins["shell"], Origin::File(system.clone()));
+        assert_eq!(origins["prompt_prefix"], Origin::File(user.clone()));
+        assert_eq!(origins["session_restore"], Origin::Default);
+        assert!(!origins.contains_key("norc"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn watch() -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("config.toml");
+        fs::write(&path, "prompt_prefix = \"old\"\n")?;
+        let manager = Manager::new(Some(path.to_str().unwrap()))?;
+        manager.watch()?;
+
+        // replace the file the way editors do
+        let tmp_path = tmp_dir.path().join("config.toml.tmp");
+        fs::write(&tmp_path, "prompt_prefix = \"new\"\n")?;
+        fs::rename(&tmp_path, &path)?;
+
+        while manager.get().prompt_prefix.as_deref() != Some("new") {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_overrides() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            shell = "/bin/bash"
+            [sessions.irc]
+            shell = "/bin/zsh"
+            session_restore = "screen"
+            "#,
+        )?;
+        let irc = config.session("irc").expect("irc overrides");
+        assert_eq!(irc.shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(irc.session_restore.as_deref(), Some("screen"));
+        assert!(config.session("build").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_patterns() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [sessions."dev-*"]
+            dir = "~/src"
+            startup = "source .venv/bin/activate"
+            [sessions."dev-shpool"]
+            dir = "~/src/shpool"
+            [sessions."*"]
+            session_restore = "1MB"
+            "#,
+        )?;
+        assert_eq!(
+            config.session("dev-shpool").and_then(|s| s.dir.as_deref()),
+            Some("~/src/shpool")
+        );
+        let dev = config.session("dev-other").expect("dev overrides");
+        assert_eq!(dev.dir.as_deref(), Some("~/src"));
+        assert_eq!(dev.startup.as_deref(), Some("source .venv/bin/activate"));
+        assert_eq!(config.session("main").and_then(|s| s.session_restore.as_deref()), Some("1MB"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn watches() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [[watch]]
+            pattern = "ERROR|panic"
+            [[watch]]
+            session = "build-*"
+            pattern = "BUILD FAILED"
+            exec = "notify-send 'build failed'"
+            "#,
+        )?;
+        let watches = config.watch.expect("watches");
+        assert!(watches[0].applies_to("main"));
+        assert!(watches[1].applies_to("build-api"));
+        assert!(!watches[1].applies_to("main"));
+        assert_eq!(watches[1].exec.as_deref(), Some("notify-send 'build failed'"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn autostart() -> Result<()> {
+        let autostart = |src: &str| -> Result<Option<Autostart>> {
+            Ok(toml::from_str::<Config>(src)?.autostart())
+        };
+        assert_eq!(autostart("")?, Some(Autostart::Fork));
+        assert_eq!(autostart("nodaemonize = true")?, None);
+        assert_eq!(autostart("autostart_daemon = false")?, None);
+        assert_eq!(
+            autostart("nodaemonize = true\nautostart_daemon = true")?,
+            Some(Autostart::Fork)
+        );
+        assert_eq!(autostart("autostart_daemon = \"systemd\"")?, Some(Autostart::Systemd));
+        assert!(autostart("autostart_daemon = \"launchd\"").is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,798 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Validating the config files.
+
+  The daemon only finds out that a config value is bad when it goes to
+  use it, which may be long after it started, and keys it does not know
+  about are silently ignored, so a typo just means a setting quietly
+  does nothing. `shpool config check` reads each config file and reports
+  everything wrong with it at once: syntax errors, unknown keys, and
+  values that won't parse, each with the line it is on.
+
+  Unknown keys are found by walking the parsed toml and comparing the
+  keys against the field names of the config structs, which we pull out
+  of their derived Deserialize impls so they can't fall out of sync.
+
+  `shpool config show` lives here too, since it shares the filling in
+  of defaults with `--print-effective`. With `--origin` it notes which
+  layer (a config file, an environment variable or the defaults) each
+  top level key came from.
+*/
+
+use std::{collections::HashMap, env, fmt::Write as _, fs, io, iter, ops::Range, path::Path};
+
+use anyhow::{anyhow, Context};
+use clap::CommandFactory as _;
+use serde::de::{self, Deserializer, Visitor};
+use shpool_protocol::TtySize;
+use toml::de::{DeTable, DeValue};
+
+use crate::{
+    config, daemon, daemon::keybindings, duration, list, logging, output, session_restore,
+    session_restore::replay, size, supervise, tcp, Args,
+};
+
+/// Something wrong with a config file.
+#[derive(Debug, PartialEq, Eq)]
+struct Problem {
+    /// The line the problem is on, counting from 1, if we know it.
+    line: Option<usize>,
+    message: String,
+    /// A suggested fix, if there is one.
+    help: Option<String>,
+}
+
+impl Problem {
+    fn new(src: &str, span: Option<Range<usize>>, message: impl Into<String>) -> Self {
+        Problem {
+            line: span.map(|span| src[..span.start.min(src.len())].matches('\n').count() + 1),
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    fn render(&self, path: &Path, src: &str) -> String {
+        let mut out = String::new();
+        match self.line {
+            Some(line) => {
+                let _ = writeln!(out, "{}:{}: {}", path.display(), line, self.message);
+                if let Some(text) = src.lines().nth(line - 1) {
+                    let _ = writeln!(out, "    | {}", text);
+                }
+            }
+            None => {
+                let _ = writeln!(out, "{}: {}", path.display(), self.message);
+            }
+        }
+        if let Some(help) = &self.help {
+            let _ = writeln!(out, "    = help: {}", help);
+        }
+        out
+    }
+}
+
+pub fn run(config_file: Option<&str>, print_effective: bool) -> anyhow::Result<()> {
+    let mut config = config::Config::default();
+    let mut checked = vec![];
+    let mut nproblems = 0;
+    for path in config::Manager::config_files(config_file)? {
+        let src = match fs::read_to_string(&path) {
+            Ok(src) => src,
+            // the default config files are all optional
+            Err(e) if e.kind() == io::ErrorKind::NotFound && config_file.is_none() => continue,
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        for problem in check(&src).iter() {
+            eprint!("{}", problem.render(&path, &src));
+            nproblems += 1;
+        }
+        if let Ok(file_config) = toml::from_str::<config::Config>(&src) {
+            config = file_config.merge(config);
+        }
+        checked.push(path);
+    }
+    match config::Manager::env_layer(env::vars()) {
+        Ok(env_layer) => {
+            for (var, problem) in env_layer.skipped.iter() {
+                eprintln!("environment: {var}: {problem}");
+                nproblems += 1;
+            }
+            config = env_layer.config.merge(config);
+        }
+        Err(e) => {
+            eprintln!("environment: {:#}", e);
+            nproblems += 1;
+        }
+    }
+
+    if print_effective {
+        print!("{}", toml::to_string(&with_defaults(config)).context("formatting config")?);
+    } else if checked.is_empty() {
+        println!("no config files found, using the defaults");
+    }
+    if nproblems > 0 {
+        return Err(anyhow!("found {} problem(s) in the config", nproblems));
+    }
+    if !print_effective {
+        for path in checked.iter() {
+            println!("{}: ok", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the effective config, noting where each key came from if
+/// `origin` is set.
+pub fn show(config_file: Option<&str>, origin: bool) -> anyhow::Result<()> {
+    let config_files = config::Manager::config_files(config_file)?;
+    let (config, origins) = config::Manager::load_with_origins(&config_files)?;
+    let config = with_defaults(config);
+    if !origin {
+        print!("{}", toml::to_string(&config).context("formatting config")?);
+        return Ok(());
+    }
+
+    let toml::Value::Table(table) = toml::Value::try_from(&config).context("formatting config")?
+    else {
+        return Err(anyhow!("config is not a table"));
+    };
+    // Plain keys have to come before all the tables, or they would end
+    // up inside the last one.
+    let (mut keys, mut tables) = (String::new(), String::new());
+    for (key, value) in table {
+        let from = origins.get(&key).cloned().unwrap_or(config::Origin::Default);
+        let entry = toml::Table::from_iter([(key, value)]);
+        let entry = toml::to_string(&entry).context("formatting config")?;
+        if entry.starts_with('[') {
+            let _ = write!(tables, "\n# from {from}\n{entry}");
+        } else {
+            let _ = write!(keys, "# from {from}\n{entry}");
+        }
+    }
+    print!("{keys}{tables}");
+
+    Ok(())
+}
+
+/// Check the source of a single config file.
+fn check(src: &str) -> Vec<Problem> {
+    let table = match DeTable::parse(src) {
+        Ok(table) => table.into_inner(),
+        Err(e) => return vec![Problem::new(src, e.span(), e.message())],
+    };
+
+    let mut problems = vec![];
+    unknown_keys(src, &table, &mut problems);
+    match toml::from_str::<config::Config>(src) {
+        Ok(config) => bad_values(src, &table, &config, &mut problems),
+        Err(e) => problems.push(Problem::new(src, e.span(), e.message())),
+    }
+    problems.sort_by_key(|p| p.line);
+    problems
+}
+
+fn unknown_keys(src: &str, table: &DeTable, problems: &mut Vec<Problem>) {
+    check_keys(src, table, fields::<config::Config>(), problems);
+    for (key, value) in table.iter() {
+        let (nested, fields) = match key.get_ref().as_ref() {
+            "hooks" => (vec![value.get_ref()], fields::<config::HookCmds>()),
+            "notifications" => (vec![value.get_ref()], fields::<config::Notifications>()),
+            "tcp" => (vec![value.get_ref()], fields::<config::TcpConfig>()),
+            "output_log" => (vec![value.get_ref()], fields::<config::OutputLogConfig>()),
+            "log" => (vec![value.get_ref()], fields::<config::LogConfig>()),
+            "rlimits" => (vec![value.get_ref()], fields::<config::Rlimits>()),
+            "limits" => (vec![value.get_ref()], fields::<config::Limits>()),
+            "escape_filter" => (vec![value.get_ref()], fields::<config::EscapeFilter>()),
+            "keybindings" => (vec![value.get_ref()], fields::<config::KeybindingsConfig>()),
+            "output" => {
+                if let DeValue::Table(output) = value.get_ref() {
+                    for (key, theme) in output.iter() {
+                        if key.get_ref() == "theme"
+                            && let DeValue::Table(theme) = theme.get_ref()
+                        {
+                            check_keys(src, theme, fields::<config::Theme>(), problems);
+                        }
+                    }
+                }
+                (vec![value.get_ref()], fields::<config::Output>())
+            }
+            "keybinding" => match value.get_ref() {
+                DeValue::Array(bindings) => {
+                    (bindings.iter().map(|b| b.get_ref()).collect(), fields::<config::Keybinding>())
+                }
+                _ => continue,
+            },
+            "watch" => match value.get_ref() {
+                DeValue::Array(watches) => {
+                    (watches.iter().map(|w| w.get_ref()).collect(), fields::<config::Watch>())
+                }
+                _ => continue,
+            },
+            "sessions" => match value.get_ref() {
+                DeValue::Table(sessions) => (
+                    sessions.values().map(|s| s.get_ref()).collect(),
+                    fields::<config::SessionConfig>(),
+                ),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        for value in nested {
+            // values of the wrong type get reported when we deserialize
+            if let DeValue::Table(nested) = value {
+                check_keys(src, nested, fields, problems);
+            }
+        }
+    }
+}
+
+fn check_keys(src: &str, table: &DeTable, known: &[&str], problems: &mut Vec<Problem>) {
+    for key in table.keys() {
+        if known.contains(&key.get_ref().as_ref()) {
+            continue;
+        }
+        let mut problem =
+            Problem::new(src, Some(key.span()), format!("unknown key '{}'", key.get_ref()));
+        if let Some(suggestion) = closest(key.get_ref(), known) {
+            problem = problem.help(format!("did you mean '{suggestion}'?"));
+        }
+        problems.push(problem);
+    }
+}
+
+/// Check all the values that only get parsed once the daemon gets
+/// around to using them.
+fn bad_values(src: &str, table: &DeTable, config: &config::Config, problems: &mut Vec<Problem>) {
+    let mut check = |path: &[&str], res: anyhow::Result<()>| {
+        if let Err(e) = res {
+            problems.push(Problem::new(
+                src,
+                span_of(table, path),
+                format!("bad value for {}: {:#}", path.join("."), e),
+            ));
+        }
+    };
+
+    if let Some(restore) = &config.session_restore {
+        check(&["session_restore"], check_session_restore(restore));
+    }
+    for (name, session) in config.sessions.iter().flatten() {
+        if let Some(restore) = &session.session_restore {
+            check(&["sessions", name, "session_restore"], check_session_restore(restore));
+        }
+        if let Some(ttl) = &session.ttl {
+            check(&["sessions", name, "ttl"], duration::parse(ttl).map(drop));
+        }
+        if let Some(rlimits) = &session.rlimits {
+            check(&["sessions", name, "rlimits"], daemon::check_rlimits(rlimits));
+        }
+        if let Some(policy) = &session.detach_policy {
+            check(
+                &["sessions", name, "detach_policy"],
+                daemon::DetachPolicy::parse(policy).map(drop),
+            );
+        }
+        if let Some(restart) = &session.restart {
+            check(&["sessions", name, "restart"], supervise::Policy::parse(restart).map(drop));
+        }
+    }
+    if let Some(swap_after) = &config.session_restore_swap_after {
+        check(&["session_restore_swap_after"], duration::parse(swap_after).map(drop));
+    }
+    if let Some(max_replay) = &config.session_restore_max_replay {
+        check(&["session_restore_max_replay"], size::parse(max_replay).map(drop));
+    }
+    if let Some(rate) = &config.session_restore_replay_rate {
+        check(&["session_restore_replay_rate"], replay::parse_rate(rate).map(drop));
+    }
+    if let Some(idle) = &config.auto_kill_after_idle {
+        check(&["auto_kill_after_idle"], duration::parse(idle).map(drop));
+    }
+    if let Some(idle) = &config.auto_lock_after_idle {
+        check(&["auto_lock_after_idle"], duration::parse(idle).map(drop));
+    }
+    if let Some(idle) = &config.screen_lock_after_idle {
+        check(&["screen_lock_after_idle"], duration::parse(idle).map(drop));
+    }
+    if let Some(limit) = &config.detached_output_limit {
+        check(&["detached_output_limit"], replay::parse_rate(limit).map(drop));
+    }
+    if let Some(limit) = &config.client_output_queue {
+        let limit = size::parse(limit).and_then(|limit| match limit {
+            0 => Err(anyhow!("the client output queue must hold at least some output")),
+            _ => Ok(()),
+        });
+        check(&["client_output_queue"], limit);
+    }
+    if let Some(interval) = &config.heartbeat_interval {
+        check(&["heartbeat_interval"], daemon::parse_heartbeat(interval).map(drop));
+    }
+    if let Some(timeout) = &config.heartbeat_timeout {
+        check(&["heartbeat_timeout"], daemon::parse_heartbeat(timeout).map(drop));
+    }
+    if let Some(policy) = &config.detach_policy {
+        check(&["detach_policy"], daemon::DetachPolicy::parse(policy).map(drop));
+    }
+    if let Some(rlimits) = &config.rlimits {
+        check(&["rlimits"], daemon::check_rlimits(rlimits));
+    }
+    if let Some(limits) = &config.limits {
+        check(&["limits"], daemon::check_limits(limits));
+    }
+    if let Some(reap_exited) = &config.reap_exited {
+        check(&["reap_exited"], daemon::ReapPolicy::parse(reap_exited).map(drop));
+    }
+    if let Some(config::MotdDisplayMode::Pager { show_every: Some(show_every), .. }) = &config.motd
+    {
+        check(&["motd", "pager", "show_every"], duration::parse(show_every).map(drop));
+    }
+    for watch in config.watch.iter().flatten() {
+        check(&["watch"], daemon::compile_watch(&watch.pattern).map(drop));
+    }
+    if let Some(output) = &config.output {
+        if let Some(columns) = &output.list_columns {
+            check(&["output", "list_columns"], list::parse_columns(columns).map(drop));
+        }
+        if let Some(theme) = &output.theme {
+            for (name, spec) in [
+                ("header", &theme.header),
+                ("attached", &theme.attached),
+                ("exited", &theme.exited),
+                ("alert", &theme.alert),
+            ] {
+                if let Some(spec) = spec {
+                    check(&["output", "theme", name], output::parse_style(spec).map(drop));
+                }
+            }
+        }
+    }
+    if let Some(max_size) = config.output_log.as_ref().and_then(|l| l.max_size.as_ref()) {
+        check(&["output_log", "max_size"], size::parse(max_size).map(drop));
+    }
+    if let Some(log) = &config.log {
+        if let Some(max_size) = &log.max_size {
+            check(&["log", "max_size"], size::parse(max_size).map(drop));
+        }
+        if let Some(every) = &log.rotate_every {
+            check(&["log", "rotate_every"], duration::parse(every).map(drop));
+        }
+        check(&["log", "redact"], logging::Redactor::from_config(log).map(drop));
+    }
+    if let Some(mode) = config.socket_mode {
+        check(&["socket_mode"], daemon::check_socket_mode(mode));
+    }
+    if let Some(group) = &config.socket_group {
+        check(&["socket_group"], daemon::resolve_socket_group(group).map(drop));
+    }
+    if let Some(tcp) = &config.tcp
+        && let Some(listen) = &tcp.listen
+    {
+        match (&tcp.cert_file, &tcp.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                check(&["tcp", "listen"], tcp::listen_addrs(listen).map(drop));
+                check(
+                    &["tcp", "cert_file"],
+                    tcp::server_tls(Path::new(cert_file), Path::new(key_file)).map(drop),
+                );
+            }
+            (None, None) => check(&["tcp", "listen"], tcp::loopback_addrs(listen).map(drop)),
+            _ => check(
+                &["tcp", "listen"],
+                Err(anyhow!("tcp.cert_file and tcp.key_file must be set together")),
+            ),
+        }
+        if tcp.token_file.is_none() {
+            check(&["tcp", "listen"], Err(anyhow!("tcp.token_file must be set to listen on tcp")));
+        }
+    }
+
+    if let Some(json_socket) = &config.json_socket
+        && json_socket.trim().is_empty()
+    {
+        check(&["json_socket"], Err(anyhow!("json_socket must be a path")));
+    }
+
+    check_keybindings(config, &mut check);
+    check_aliases(config, &mut check);
+
+    for (key, warning, suggestion) in config::Manager::deprecation_warnings(config) {
+        problems.push(Problem::new(src, span_of(table, &[key]), warning).help(suggestion));
+    }
+}
+
+fn check_keybindings<F>(config: &config::Config, check: &mut F)
+where
+    F: FnMut(&[&str], anyhow::Result<()>),
+{
+    let settings = config.keybindings.clone().unwrap_or_default();
+    let leader = settings.leader.as_deref();
+    let leader_res = keybindings::Bindings::new(iter::empty(), leader);
+    let leader_ok = leader_res.is_ok();
+    check(&["keybindings", "leader"], leader_res.map(drop));
+
+    let mut srcs = vec![];
+    for (i, binding) in config.keybinding.iter().flatten().enumerate() {
+        srcs.push((
+            vec![String::from("keybinding"), i.to_string(), String::from("binding")],
+            binding.binding.clone(),
+            binding.action.clone(),
+        ));
+    }
+    // don't blame every leader binding for a bad leader
+    for (key, action) in settings.leader_bindings.iter().flatten().filter(|_| leader_ok) {
+        srcs.push((
+            vec![String::from("keybindings"), String::from("leader_bindings"), key.clone()],
+            format!("Leader {key}"),
+            action.clone(),
+        ));
+    }
+
+    let mut ok = leader_ok;
+    for (path, binding, action) in srcs.iter() {
+        let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+        let res =
+            keybindings::Bindings::new(iter::once((binding.as_str(), action.clone())), leader);
+        ok &= res.is_ok();
+        check(&path, res.map(drop));
+    }
+    if ok {
+        // each binding is fine on its own, but they still might not
+        // fit in the tables together
+        let all = srcs.iter().map(|(_, binding, action)| (binding.as_str(), action.clone()));
+        check(&["keybinding"], keybindings::Bindings::new(all, leader).map(drop));
+    }
+}
+
+fn check_aliases<F>(config: &config::Config, check: &mut F)
+where
+    F: FnMut(&[&str], anyhow::Result<()>),
+{
+    let cmd = Args::command();
+    let subcommands = cmd
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set())
+        .map(|c| c.get_name())
+        .collect::<Vec<_>>();
+    let aliases = config.aliases.clone().unwrap_or_default();
+    for alias in aliases.keys() {
+        if subcommands.contains(&alias.as_str()) {
+            check(&["aliases", alias], Err(anyhow!("'{}' is already a subcommand", alias)));
+        }
+        let target_cmd = match alias_target(&aliases, alias) {
+            Ok(target_cmd) => target_cmd,
+            Err(e) => {
+                check(&["aliases", alias], Err(e));
+                continue;
+            }
+        };
+        let target_cmd = target_cmd.as_str();
+        if !subcommands.contains(&target_cmd) {
+            let err = match closest(target_cmd, &subcommands) {
+                Some(suggestion) => {
+                    anyhow!("'{}' is not a subcommand, did you mean '{}'?", target_cmd, suggestion)
+                }
+                None => anyhow!("'{}' is not a subcommand", target_cmd),
+            };
+            check(&["aliases", alias], Err(err));
+        }
+    }
+}
+
+/// Follow a chain of aliases to the command it ends up at.
+fn alias_target(
+    aliases: &HashMap<String, config::Alias>,
+    alias: &str,
+) -> anyhow::Result<String> {
+    let mut chain = vec![alias];
+    loop {
+        let words = aliases[*chain.last().unwrap()].words()?;
+        match aliases.get_key_value(&words[0]) {
+            Some((next, _)) if chain.contains(&next.as_str()) => {
+                chain.push(next);
+                return Err(anyhow!("alias loop: {}", chain.join(" -> ")));
+            }
+            Some((next, _)) => chain.push(next),
+            None => return Ok(words[0].clone()),
+        }
+    }
+}
+
+/// Check a session_restore value by making a spool with it.
+fn check_session_restore(src: &str) -> anyhow::Result<()> {
+    let size = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+    session_restore::new(src, &size, Path::new("")).map(|_| ())
+}
+
+/// Fill in the defaults for the settings where the default is not
+/// just "off", so that the effective config shows what actually
+/// happens.
+fn with_defaults(mut config: config::Config) -> config::Config {
+    config
+        .session_restore_max_replay
+        .get_or_insert_with(|| format!("{}MiB", replay::DEFAULT_MAX_REPLAY / (1024 * 1024)));
+    config.reattach_redraw.get_or_insert_default();
+    config.auto_name.get_or_insert_default();
+    config.term_mismatch.get_or_insert_default();
+    config.scrollback_lines.get_or_insert(daemon::DEFAULT_SCROLLBACK_LINES);
+    config.prompt_prefix.get_or_insert_with(|| String::from(daemon::DEFAULT_PROMPT_PREFIX));
+    config.motd.get_or_insert_default();
+    let has_leader_bindings = config
+        .keybindings
+        .as_ref()
+        .and_then(|k| k.leader_bindings.as_ref())
+        .is_some_and(|b| !b.is_empty());
+    if config.keybinding.as_ref().is_none_or(|b| b.is_empty()) && !has_leader_bindings {
+        config.keybinding = Some(vec![config::Keybinding {
+            binding: String::from(keybindings::DEFAULT_DETACH),
+            action: keybindings::Action::Detach,
+        }]);
+    }
+    config
+}
+
+/// Find the span of the value at the given path, where array elements
+/// are given by their index.
+fn span_of(table: &DeTable, path: &[&str]) -> Option<Range<usize>> {
+    let (first, rest) = path.split_first()?;
+    let mut value = table.get(*first)?;
+    for seg in rest {
+        value = match value.get_ref() {
+            DeValue::Table(table) => table.get(*seg)?,
+            DeValue::Array(array) => array.get(seg.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value.span())
+}
+
+/// The known name closest to the given one, if any are close enough
+/// that it is probably a typo.
+fn closest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|k| (edit_distance(name, k), *k))
+        .filter(|(d, _)| *d <= (name.len() / 3).max(2))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k)
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ac) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, bc) in b.iter().enumerate() {
+            let substitute = prev[j] + if ac == *bc { 0 } else { 1 };
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// The field names of a struct with a derived Deserialize impl. The
+/// derived impl hands the names to the deserializer when it asks for
+/// a struct, so we give it a deserializer that just writes them down.
+fn fields<T: de::DeserializeOwned>() -> &'static [&'static str] {
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("only collecting field names"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(Fields(&mut fields));
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn messages(src: &str) -> Vec<(Option<usize>, String)> {
+        check(src).into_iter().map(|p| (p.line, p.message)).collect()
+    }
+
+    #[test]
+    fn good_config() {
+        let src = r#"
+session_restore = "5MB"
+session_restore_swap_after = "30m"
+[hooks]
+on_attach = "true"
+[[keybinding]]
+binding = "Ctrl-q a"
+action = "detach"
+[aliases]
+at = "attach"
+[sessions.irc]
+ttl = "30d"
+"#;
+        assert_eq!(check(src), vec![]);
+    }
+
+    #[test]
+    fn syntax_error() {
+        assert_eq!(messages("norc = true\nnoecho = \n").len(), 1);
+        assert_eq!(messages("norc = true\nnoecho = \n")[0].0, Some(2));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let problems = check("norc = true\nsesion_restore = \"5MB\"\n[hooks]\non_atach = \"x\"\n");
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].line, Some(2));
+        assert_eq!(problems[0].help.as_deref(), Some("did you mean 'session_restore'?"));
+        assert_eq!(problems[1].line, Some(4));
+        assert_eq!(problems[1].help.as_deref(), Some("did you mean 'on_attach'?"));
+
+        let problems = check("[log]\nformat = \"json\"\nmax_sise = \"10MB\"\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(3));
+        assert_eq!(problems[0].help.as_deref(), Some("did you mean 'max_size'?"));
+
+        let problems = check("[sessions.irc]\ncommand = \"weechat\"\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].message, "unknown key 'command'");
+        assert_eq!(problems[0].help, None);
+    }
+
+    #[test]
+    fn bad_values() {
+        let src = r#"session_restore = "5 megs"
+auto_kill_after_idle = "forever"
+reap_exited = "eventually"
+[[keybinding]]
+binding = "Ctrl-q a"
+action = "detach"
+[[keybinding]]
+binding = "Ctrl-Nope"
+action = "detach"
+[sessions.irc]
+ttl = "soon"
+"#;
+        let lines = messages(src).into_iter().map(|(line, _)| line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![Some(1), Some(2), Some(3), Some(8), Some(11)]);
+    }
+
+    #[test]
+    fn bad_type() {
+        let problems = messages("norc = \"yes\"\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, Some(1));
+    }
+
+    #[test]
+    fn aliases() {
+        let problems = messages("[aliases]\nls = \"lsit\"\nkill = \"detach\"\n");
+        assert_eq!(problems.len(), 2);
+    }
+
+    #[test]
+    fn deprecated() {
+        let problems = check("norc = true\noutput_spool_lines = 500\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(2));
+        assert!(problems[0].help.is_some());
+    }
+
+    #[test]
+    fn output_table() {
+        let src = r#"[output]
+color = "sometimes"
+"#;
+        assert_eq!(messages(src).len(), 1);
+
+        let src = r#"[output]
+list_columns = ["name", "pdi"]
+[output.theme]
+header = "bold pink"
+exited = "red"
+atached = "green"
+"#;
+        let lines = messages(src).into_iter().map(|(line, _)| line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![Some(2), Some(4), Some(6)]);
+    }
+
+    #[test]
+    fn watches() {
+        let src = r#"[[watch]]
+pattern = "ERROR|panic"
+[[watch]]
+pattern = "(unclosed"
+exec = "notify-send oops"
+[[watch]]
+pattern = "FAIL"
+exce = "true"
+"#;
+        let messages = messages(src);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().any(|(line, m)| *line == Some(8) && m.contains("exce")));
+        assert!(messages.iter().any(|(_, m)| m.contains("bad value for watch")));
+    }
+
+    #[test]
+    fn tcp_needs_token() {
+        let problems = messages("[tcp]\nlisten = \"127.0.0.1:7777\"\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, Some(2));
+    }
+
+    #[test]
+    fn tcp_loopback_only() {
+        let problems = messages("[tcp]\nlisten = \"0.0.0.0:7777\"\ntoken_file = \"/tmp/token\"\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].1.contains("not a loopback address"));
+    }
+
+    #[test]
+    fn tcp_tls_needs_cert_and_key() {
+        let problems = messages(
+            "[tcp]\nlisten = \"0.0.0.0:7777\"\ntoken_file = \"/tmp/token\"\ncert_file = \"c.pem\"\n",
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].1.contains("must be set together"));
+    }
+
+    #[test]
+    fn effective() -> anyhow::Result<()> {
+        let config: config::Config = toml::from_str("norc = true\n[sessions.irc]\ncmd = \"x\"\n")?;
+        let effective = toml::to_string(&with_defaults(config))?;
+        let round_trip: config::Config = toml::from_str(&effective)?;
+        assert_eq!(round_trip.norc, Some(true));
+        assert_eq!(round_trip.scrollback_lines, Some(daemon::DEFAULT_SCROLLBACK_LINES));
+        assert_eq!(round_trip.keybinding.map(|b| b.len()), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn closest_name() {
+        assert_eq!(closest("nodeamonize", &["norc", "nodaemonize"]), Some("nodaemonize"));
+        assert_eq!(closest("xyz", &["norc", "nodaemonize"]), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn field_names() {
+        assert_eq!(fields::<config::TcpConfig>(), &["listen", "token_file"]);
+    }
+}
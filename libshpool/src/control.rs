@@ -0,0 +1,69 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The control subcommand puts a connection to the daemon into control
+//! mode and hooks it up to stdin and stdout, so that a program driving
+//! shpool (an editor extension, say) can just spawn `shpool control`
+//! and speak line-delimited JSON over its stdio. See the ControlRequest
+//! and ControlEvent types in shpool-protocol for the messages.
+
+use std::{io, net, path::PathBuf, thread};
+
+use anyhow::Context;
+use shpool_protocol::{capability, ConnectHeader, ControlHeader};
+use tracing::warn;
+
+use crate::{attach, config, protocol, protocol::ClientResult};
+
+pub fn run(config_manager: config::Manager, socket: PathBuf) -> anyhow::Result<()> {
+    let client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::CONTROL, "control mode")?;
+    client
+        .write_connect_header(ConnectHeader::Control(ControlHeader {
+            local_env: attach::local_env(&config_manager.get()),
+        }))
+        .context("writing control header")?;
+
+    let mut stream = client.into_stream();
+    let mut requests = stream.try_clone().context("cloning control stream")?;
+    // Nothing interrupts a blocking read of stdin, so this thread just
+    // gets left behind if the daemon hangs up first.
+    thread::Builder::new()
+        .name(String::from("control-stdin"))
+        .spawn(move || {
+            if let Err(e) = io::copy(&mut io::stdin().lock(), &mut requests) {
+                warn!("forwarding control requests: {:?}", e);
+            }
+            // Let the daemon know we are done so it hangs up on us.
+            let _ = requests.shutdown(net::Shutdown::Write);
+        })
+        .context("spawning stdin thread")?;
+
+    io::copy(&mut stream, &mut io::stdout().lock()).context("forwarding control events")?;
+    Ok(())
+}
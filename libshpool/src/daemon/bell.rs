@@ -0,0 +1,98 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Noticing when a detached session rings the terminal bell.
+
+  BEL shows up in terminal output for two reasons: as a bell in its
+  own right, and as the terminator for OSC sequences like the ones
+  that set the window title. Shells that update the title from their
+  prompt emit the second kind constantly, so the scanner keeps track
+  of whether it is in the middle of a string sequence to tell them
+  apart.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    // inside an OSC, DCS, APC, PM or SOS string
+    Str,
+    // just saw an ESC inside a string, which might be the start of ST
+    StrEsc,
+}
+
+/// Scans a stream of terminal output for bells.
+#[derive(Debug, Default)]
+pub struct Scanner {
+    state: State,
+}
+
+impl Scanner {
+    /// Feed a chunk of output through the scanner, returning true if
+    /// it contained a bell.
+    pub fn scan(&mut self, buf: &[u8]) -> bool {
+        let mut rang = false;
+        for byte in buf {
+            self.state = match (self.state, *byte) {
+                (State::Ground, 0x07) => {
+                    rang = true;
+                    State::Ground
+                }
+                (State::Ground, 0x1b) => State::Esc,
+                (State::Ground, _) => State::Ground,
+                (State::Esc, b']' | b'P' | b'_' | b'^' | b'X') => State::Str,
+                (State::Esc, 0x1b) => State::Esc,
+                (State::Esc, 0x07) => {
+                    rang = true;
+                    State::Ground
+                }
+                (State::Esc, _) => State::Ground,
+                (State::Str, 0x07) => State::Ground,
+                (State::Str, 0x1b) => State::StrEsc,
+                (State::Str, _) => State::Str,
+                (State::StrEsc, b'\\') => State::Ground,
+                (State::StrEsc, 0x1b) => State::StrEsc,
+                (State::StrEsc, _) => State::Str,
+            };
+        }
+        rang
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bells() {
+        let mut scanner = Scanner::default();
+        assert!(!scanner.scan(b"plain output\r\n"));
+        assert!(scanner.scan(b"done\x07\r\n"));
+        assert!(scanner.scan(b"\x1b\x07"));
+    }
+
+    #[test]
+    fn string_terminators() {
+        let mut scanner = Scanner::default();
+        assert!(!scanner.scan(b"\x1b]0;user@host: ~\x07$ "));
+        assert!(!scanner.scan(b"\x1b]2;title\x1b\\"));
+        assert!(!scanner.scan(b"\x1bPq#0\x07"));
+
+        // split across chunks
+        assert!(!scanner.scan(b"\x1b]0;ti"));
+        assert!(!scanner.scan(b"tle\x07"));
+        assert!(scanner.scan(b"\x07"));
+    }
+}
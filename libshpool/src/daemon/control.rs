@@ -0,0 +1,329 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Bookkeeping for control mode connections.
+
+  A control mode client gets told about sessions coming and going,
+  getting attached to, changing size and producing output. Rather than threading a way to
+  publish events through everything that can make those things happen,
+  the connection periodically looks over the session table and reports
+  whatever changed since it last looked.
+*/
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use shpool_protocol::{ControlEvent, TtySize};
+
+use crate::daemon::exit_notify::ExitNotifier;
+
+/// How often a control connection looks for changes to the sessions.
+pub const POLL_DUR: Duration = Duration::from_millis(100);
+
+/// What a control connection can see of a session at a glance.
+pub struct Snapshot {
+    pub name: String,
+    pub size: TtySize,
+    pub last_active: i64,
+    pub exit_status: Option<i32>,
+    pub attached: bool,
+    pub child_exit_notifier: Arc<ExitNotifier>,
+}
+
+/// What we last told the client about a session.
+struct Seen {
+    size: TtySize,
+    last_active: i64,
+    exited: bool,
+    attached: bool,
+    child_exit_notifier: Arc<ExitNotifier>,
+}
+
+/// Turns successive looks at the session table into events.
+#[derive(Default)]
+pub struct Watcher {
+    seen: HashMap<String, Seen>,
+}
+
+impl Watcher {
+    /// The events for everything that changed since the last call,
+    /// given every session in the table. Sessions for which `proxied`
+    /// is true get their output sent along in full, so they don't get
+    /// OutputAvailable events.
+    pub fn poll<F>(&mut self, sessions: Vec<Snapshot>, proxied: F) -> Vec<ControlEvent>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut events = vec![];
+        let mut gone: Vec<String> = self.seen.keys().cloned().collect();
+        for session in sessions {
+            gone.retain(|name| *name != session.name);
+            let Some(seen) = self.seen.get_mut(&session.name) else {
+                events.push(ControlEvent::Created { session: session.name.clone() });
+                if session.attached {
+                    events.push(ControlEvent::Attached { session: session.name.clone() });
+                }
+                if let Some(exit_status) = session.exit_status {
+                    events.push(ControlEvent::Exited {
+                        session: session.name.clone(),
+                        exit_status: Some(exit_status),
+                    });
+                }
+                self.seen.insert(
+                    session.name,
+                    Seen {
+                        size: session.size,
+                        last_active: session.last_active,
+                        exited: session.exit_status.is_some(),
+                        attached: session.attached,
+                        child_exit_notifier: session.child_exit_notifier,
+                    },
+                );
+                continue;
+            };
+
+            if seen.size != session.size {
+                events.push(ControlEvent::Resized {
+                    session: session.name.clone(),
+                    rows: session.size.rows,
+                    cols: session.size.cols,
+                });
+                seen.size = session.size;
+            }
+            if seen.attached != session.attached {
+                let name = session.name.clone();
+                events.push(if session.attached {
+                    ControlEvent::Attached { session: name }
+                } else {
+                    ControlEvent::Detached { session: name }
+                });
+                seen.attached = session.attached;
+            }
+            if seen.last_active != session.last_active {
+                if !proxied(&session.name) {
+                    events.push(ControlEvent::OutputAvailable { session: session.name.clone() });
+                }
+                seen.last_active = session.last_active;
+            }
+            if let (false, Some(exit_status)) = (seen.exited, session.exit_status) {
+                events.push(ControlEvent::Exited {
+                    session: session.name,
+                    exit_status: Some(exit_status),
+                });
+                seen.exited = true;
+            }
+        }
+
+        for name in gone {
+            let seen = self.seen.remove(&name).expect("gone sessions to have been seen");
+            if !seen.exited {
+                events.push(ControlEvent::Exited {
+                    session: name,
+                    exit_status: seen.child_exit_notifier.wait(Some(Duration::ZERO)),
+                });
+            }
+        }
+
+        events
+    }
+}
+
+/// A session whose output is being passed along to the client.
+pub struct Proxy {
+    pub output: crossbeam_channel::Receiver<Vec<u8>>,
+    pub decoder: Utf8Decoder,
+}
+
+/// Turns a session's raw output into strings for Output events. JSON
+/// strings can't hold arbitrary bytes, so anything that is not valid
+/// UTF-8 gets replaced, but a character that got split across two
+/// reads is held back until the rest of it shows up.
+#[derive(Default)]
+pub struct Utf8Decoder {
+    partial: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub fn decode(&mut self, buf: &[u8]) -> String {
+        self.partial.extend_from_slice(buf);
+        let complete = match std::str::from_utf8(&self.partial) {
+            Ok(_) => self.partial.len(),
+            // error_len is None when the input just ended too early
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.partial.len() - incomplete_tail(&self.partial),
+        };
+        let rest = self.partial.split_off(complete);
+        let out = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial = rest;
+        out
+    }
+}
+
+/// The length of the start of a multi-byte character at the end of
+/// `buf`, if there is one.
+fn incomplete_tail(buf: &[u8]) -> usize {
+    for len in 1..=buf.len().min(3) {
+        let b = buf[buf.len() - len];
+        if b & 0xc0 != 0x80 {
+            let want = match b {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return 0,
+            };
+            return if want > len { len } else { 0 };
+        }
+    }
+    0
+}
+
+/// Send an event to a control mode client.
+pub fn write_event<W: Write>(w: &mut W, event: &ControlEvent) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *w, event).context("encoding control event")?;
+    w.write_all(b"\n").context("writing control event")?;
+    w.flush().context("flushing control event")?;
+    Ok(())
+}
+
+/// Read the next line from a control mode client, or None once it
+/// hangs up.
+pub fn read_line<R: io::BufRead>(r: &mut R) -> anyhow::Result<Option<String>> {
+    let mut line = String::new();
+    if r.read_line(&mut line).context("reading control request")? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shpool_protocol::{ControlCommand, ControlRequest};
+
+    fn snapshot(name: &str, rows: u16, last_active: i64, exit_status: Option<i32>) -> Snapshot {
+        Snapshot {
+            name: String::from(name),
+            size: TtySize { rows, cols: 80, xpixel: 0, ypixel: 0 },
+            last_active,
+            exit_status,
+            attached: false,
+            child_exit_notifier: Arc::new(ExitNotifier::new()),
+        }
+    }
+
+    #[test]
+    fn watcher_events() {
+        let mut watcher = Watcher::default();
+        let not_proxied = |_: &str| false;
+        assert_eq!(
+            watcher.poll(vec![snapshot("a", 24, 0, None), snapshot("b", 24, 0, None)], not_proxied),
+            vec![
+                ControlEvent::Created { session: String::from("a") },
+                ControlEvent::Created { session: String::from("b") },
+            ]
+        );
+        assert_eq!(
+            watcher.poll(vec![snapshot("a", 24, 0, None), snapshot("b", 24, 0, None)], not_proxied),
+            vec![]
+        );
+        assert_eq!(
+            watcher.poll(vec![snapshot("a", 40, 5, None), snapshot("b", 24, 5, None)], |name| {
+                name == "b"
+            }),
+            vec![
+                ControlEvent::Resized { session: String::from("a"), rows: 40, cols: 80 },
+                ControlEvent::OutputAvailable { session: String::from("a") },
+            ]
+        );
+        assert_eq!(
+            watcher.poll(vec![snapshot("a", 40, 5, Some(3))], not_proxied),
+            vec![
+                ControlEvent::Exited { session: String::from("a"), exit_status: Some(3) },
+                ControlEvent::Exited { session: String::from("b"), exit_status: None },
+            ]
+        );
+        assert_eq!(watcher.poll(vec![], not_proxied), vec![]);
+    }
+
+    #[test]
+    fn watcher_attach_events() {
+        let mut watcher = Watcher::default();
+        let not_proxied = |_: &str| false;
+        let attached = |name| Snapshot { attached: true, ..snapshot(name, 24, 0, None) };
+        assert_eq!(
+            watcher.poll(vec![attached("a"), snapshot("b", 24, 0, None)], not_proxied),
+            vec![
+                ControlEvent::Created { session: String::from("a") },
+                ControlEvent::Attached { session: String::from("a") },
+                ControlEvent::Created { session: String::from("b") },
+            ]
+        );
+        assert_eq!(
+            watcher.poll(vec![snapshot("a", 24, 0, None), attached("b")], not_proxied),
+            vec![
+                ControlEvent::Detached { session: String::from("a") },
+                ControlEvent::Attached { session: String::from("b") },
+            ]
+        );
+        assert_eq!(
+            watcher.poll(vec![snapshot("a", 24, 0, None), attached("b")], not_proxied),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn utf8_decoder() {
+        let mut decoder = Utf8Decoder::default();
+        let snowman = "☃".as_bytes();
+        assert_eq!(decoder.decode(b"plain"), "plain");
+        assert_eq!(decoder.decode(&snowman[..1]), "");
+        assert_eq!(decoder.decode(&snowman[1..2]), "");
+        assert_eq!(decoder.decode(&snowman[2..]), "☃");
+        assert_eq!(decoder.decode(b"bad \xff byte"), "bad \u{fffd} byte");
+        assert_eq!(decoder.decode(&[b'\xff', snowman[0]]), "\u{fffd}");
+        assert_eq!(decoder.decode(&snowman[1..]), "☃");
+    }
+
+    #[test]
+    fn json_shape() -> anyhow::Result<()> {
+        let req: ControlRequest = serde_json::from_str(
+            r#"{"id": 7, "cmd": "send-input", "session": "main", "data": "ls\n"}"#,
+        )?;
+        assert_eq!(
+            req,
+            ControlRequest {
+                id: Some(7),
+                command: ControlCommand::SendInput {
+                    session: String::from("main"),
+                    data: String::from("ls\n"),
+                },
+            }
+        );
+        let req: ControlRequest = serde_json::from_str(r#"{"cmd": "create", "session": "x"}"#)?;
+        assert_eq!(
+            req.command,
+            ControlCommand::Create { session: String::from("x"), command: None, dir: None }
+        );
+
+        let mut out = vec![];
+        write_event(&mut out, &ControlEvent::Done { id: Some(7) })?;
+        assert_eq!(String::from_utf8(out)?, "{\"event\":\"done\",\"id\":7}\n");
+        Ok(())
+    }
+}
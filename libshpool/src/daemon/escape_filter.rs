@@ -0,0 +1,465 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Stripping unwanted escape sequences out of session output, set up
+  with the `[escape_filter]` config table.
+
+  Whatever runs in a session gets to talk straight to the terminal of
+  everyone who attaches to it. Most of that is harmless, but some
+  sequences reach past the session: titles end up in the window
+  decorations, OSC 52 writes to (or reads from) the system clipboard,
+  queries get answered by the terminal typing the answer into the
+  session, and DCS strings cover everything from sixel images to tmux
+  passthrough. The shell->client thread runs all output through a
+  Filter before it goes anywhere else, so stripped sequences never make
+  it to clients, mirrors, the spool or the output taps.
+
+  Sequences get held back until the filter can tell what they are,
+  which for CSI sequences is at the final byte and for OSC and DCS
+  strings is usually after a few bytes. Strings that need to be seen
+  in full to tell whether they are queries, like `OSC 11;? ST`, are
+  held up to a limit, past which they can't be a query anyway.
+*/
+
+use std::borrow::Cow;
+
+use crate::config::{self, FilterAction};
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+// No query comes anywhere near this long, so sequences that get this
+// long without being recognized are passed through.
+const MAX_HELD: usize = 256;
+
+// How each kind of inline image starts: sixel, the kitty graphics
+// protocol and iTerm2's OSC 1337.
+const GRAPHICS_INTRODUCERS: [&[u8]; 3] = [b"\x1bP", b"\x1b_", b"\x1b]1337"];
+
+/// Which kinds of sequences to strip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub title: bool,
+    pub clipboard: bool,
+    pub queries: bool,
+    pub dcs: bool,
+    /// Inline images: sixel, the kitty graphics protocol and iTerm2's
+    /// OSC 1337 files.
+    pub graphics: bool,
+}
+
+impl Policy {
+    pub fn new(config: &config::EscapeFilter) -> Self {
+        let strip = |action: Option<FilterAction>| action == Some(FilterAction::Strip);
+        Policy {
+            title: strip(config.title),
+            clipboard: strip(config.clipboard),
+            queries: strip(config.queries),
+            dcs: strip(config.dcs),
+            graphics: strip(config.graphics),
+        }
+    }
+
+    /// A policy that strips inline images and nothing else.
+    pub fn graphics() -> Self {
+        Policy { graphics: true, ..Default::default() }
+    }
+
+    /// Whether the policy lets everything through.
+    pub fn is_noop(&self) -> bool {
+        *self == Policy::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Osc,
+    Dcs,
+    Apc,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    // a CSI sequence, held until its final byte
+    Csi,
+    // a string we don't know what to do with yet, held
+    Held(Kind),
+    // a string being passed through as it comes
+    Pass(Kind),
+    // a string being stripped
+    Drop(Kind),
+}
+
+/// Strips sequences out of a stream of output according to a Policy.
+/// Sequences can be split across calls to filter.
+#[derive(Debug)]
+pub struct Filter {
+    policy: Policy,
+    state: State,
+    // the sequence being held back, starting with its ESC
+    held: Vec<u8>,
+    // just saw an ESC inside a string, which might be the start of ST
+    string_esc: bool,
+}
+
+impl Filter {
+    pub fn new(policy: Policy) -> Self {
+        Filter { policy, state: State::Ground, held: vec![], string_esc: false }
+    }
+
+    /// Feed a chunk of output through the filter, returning what is
+    /// left of it.
+    pub fn filter<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.passes_untouched(buf) {
+            return Cow::Borrowed(buf);
+        }
+        let mut out = Vec::with_capacity(buf.len());
+        for byte in buf {
+            self.step(*byte, &mut out);
+        }
+        Cow::Owned(out)
+    }
+
+    /// Whether a chunk would come out of the filter as it is, without
+    /// having to go through it byte by byte. The graphics policy, which
+    /// the spool runs every chunk through, only has to look for the
+    /// start of an image, including one cut off at the end of the chunk.
+    fn passes_untouched(&self, buf: &[u8]) -> bool {
+        if self.state != State::Ground {
+            return false;
+        }
+        if self.policy != Policy::graphics() {
+            return !buf.contains(&ESC);
+        }
+        buf.iter().enumerate().filter(|(_, b)| **b == ESC).all(|(i, _)| {
+            let rest = &buf[i..];
+            !GRAPHICS_INTRODUCERS
+                .iter()
+                .any(|intro| rest.starts_with(intro) || intro.starts_with(rest))
+        })
+    }
+
+    fn step(&mut self, byte: u8, out: &mut Vec<u8>) {
+        match self.state {
+            State::Ground if byte == ESC => self.start(),
+            State::Ground => out.push(byte),
+            State::Esc => match byte {
+                b'[' => {
+                    self.held.push(byte);
+                    self.state = State::Csi;
+                }
+                b']' | b'P' | b'_' => {
+                    self.held.push(byte);
+                    let kind = match byte {
+                        b']' => Kind::Osc,
+                        b'P' => Kind::Dcs,
+                        _ => Kind::Apc,
+                    };
+                    self.state = State::Held(kind);
+                    self.decide(kind, false, out);
+                }
+                ESC => {
+                    out.append(&mut self.held);
+                    self.start();
+                }
+                _ => {
+                    self.held.push(byte);
+                    out.append(&mut self.held);
+                    self.state = State::Ground;
+                }
+            },
+            State::Csi => {
+                self.held.push(byte);
+                if (0x40..=0x7e).contains(&byte) {
+                    if !(self.policy.queries && is_csi_query(&self.held[2..])) {
+                        out.extend_from_slice(&self.held);
+                    }
+                    self.held.clear();
+                    self.state = State::Ground;
+                } else if self.held.len() > MAX_HELD {
+                    out.append(&mut self.held);
+                    self.state = State::Ground;
+                }
+            }
+            State::Held(kind) | State::Pass(kind) | State::Drop(kind) => {
+                self.string_step(kind, byte, out)
+            }
+        }
+    }
+
+    fn string_step(&mut self, kind: Kind, byte: u8, out: &mut Vec<u8>) {
+        let terminator: &[u8] = if self.string_esc {
+            self.string_esc = false;
+            match byte {
+                b'\\' => b"\x1b\\",
+                // tmux passthrough doubles the ESCs of the sequences it
+                // wraps in a DCS
+                ESC if kind == Kind::Dcs => {
+                    self.string_data(kind, &[ESC, ESC], out);
+                    return;
+                }
+                _ => b"",
+            }
+        } else if byte == ESC {
+            self.string_esc = true;
+            return;
+        } else if byte == BEL && kind == Kind::Osc {
+            b"\x07"
+        } else {
+            self.string_data(kind, &[byte], out);
+            return;
+        };
+
+        // the string is over
+        if let State::Held(_) = self.state {
+            self.decide(kind, true, out);
+        }
+        if let State::Pass(_) = self.state {
+            out.extend_from_slice(terminator);
+        }
+        self.held.clear();
+        self.state = State::Ground;
+        if terminator.is_empty() {
+            // an ESC that cut the string short starts something new
+            self.start();
+            self.step(byte, out);
+        }
+    }
+
+    fn string_data(&mut self, kind: Kind, bytes: &[u8], out: &mut Vec<u8>) {
+        match self.state {
+            State::Held(_) => {
+                self.held.extend_from_slice(bytes);
+                self.decide(kind, false, out);
+            }
+            State::Pass(_) => out.extend_from_slice(bytes),
+            _ => {}
+        }
+    }
+
+    fn start(&mut self) {
+        self.held.clear();
+        self.held.push(ESC);
+        self.state = State::Esc;
+    }
+
+    /// Move a held string on to being passed or dropped once we know
+    /// which.
+    fn decide(&mut self, kind: Kind, complete: bool, out: &mut Vec<u8>) {
+        let strip = match self.strip_string(kind, &self.held[2..], complete) {
+            Some(strip) => strip,
+            None if self.held.len() > MAX_HELD => false,
+            None => return,
+        };
+        if strip {
+            self.held.clear();
+            self.state = State::Drop(kind);
+        } else {
+            out.append(&mut self.held);
+            self.state = State::Pass(kind);
+        }
+    }
+
+    /// Whether to strip a string with the given contents so far, or
+    /// None if there is no telling yet. Always Some if `complete`.
+    fn strip_string(&self, kind: Kind, contents: &[u8], complete: bool) -> Option<bool> {
+        let policy = &self.policy;
+        match kind {
+            Kind::Dcs if policy.dcs => Some(true),
+            // sixel, which is DCS P1;P2;P3 q
+            Kind::Dcs if policy.graphics && contents.first() != Some(&b'$') => {
+                match contents.iter().find(|b| !(b.is_ascii_digit() || **b == b';')) {
+                    Some(b'q') => Some(true),
+                    Some(_) if !policy.queries => Some(false),
+                    Some(_) => Some(contents.starts_with(b"+q")),
+                    None if complete => Some(false),
+                    None => None,
+                }
+            }
+            Kind::Dcs if !policy.queries => Some(false),
+            // DECRQSS and XTGETTCAP
+            Kind::Dcs if contents.len() >= 2 || complete => {
+                Some(contents.starts_with(b"$q") || contents.starts_with(b"+q"))
+            }
+            Kind::Dcs => None,
+            // the kitty graphics protocol, the only thing APC sees much
+            // use for
+            Kind::Apc => match contents.first() {
+                Some(b) => Some(policy.graphics && *b == b'G'),
+                None => complete.then_some(false),
+            },
+            Kind::Osc => {
+                let Some(semi) = contents.iter().position(|b| *b == b';') else {
+                    return complete.then_some(false);
+                };
+                match &contents[..semi] {
+                    b"0" | b"1" | b"2" => Some(policy.title),
+                    b"52" if policy.clipboard => Some(true),
+                    b"1337" if policy.graphics => {
+                        let prefixes: [&[u8]; 3] = [b"File=", b"MultipartFile=", b"FilePart="];
+                        starts_with_any(&contents[semi + 1..], &prefixes, complete)
+                    }
+                    _ if !policy.queries => Some(false),
+                    // like OSC 11;? to ask for the background color
+                    _ if complete => Some(contents.split(|b| *b == b';').next_back() == Some(b"?")),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Whether `contents` starts with one of the `prefixes`, or None if it
+/// is too short to tell and there is more to come.
+fn starts_with_any(contents: &[u8], prefixes: &[&[u8]], complete: bool) -> Option<bool> {
+    if prefixes.iter().any(|p| contents.starts_with(p)) {
+        Some(true)
+    } else if !complete && prefixes.iter().any(|p| p.starts_with(contents)) {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+/// Whether the body of a CSI sequence, everything after the `ESC [`,
+/// asks the terminal to answer.
+fn is_csi_query(body: &[u8]) -> bool {
+    let Some((&last, rest)) = body.split_last() else {
+        return false;
+    };
+    let (prefix, rest) = match rest.split_first() {
+        Some((&b, rest)) if matches!(b, b'?' | b'>' | b'=' | b'<') => (Some(b), rest),
+        _ => (None, rest),
+    };
+    let (intermediate, params) = match rest.split_last() {
+        Some((&b, params)) if (0x20..=0x2f).contains(&b) => (Some(b), params),
+        _ => (None, rest),
+    };
+    let first = params
+        .split(|b| *b == b';')
+        .next()
+        .and_then(|p| std::str::from_utf8(p).ok())
+        .and_then(|p| p.parse::<u32>().ok());
+    match (prefix, intermediate, last) {
+        // device status reports, including the cursor position
+        (None | Some(b'?'), None, b'n') => true,
+        // device attributes
+        (None | Some(b'>') | Some(b'='), None, b'c') => true,
+        // XTVERSION
+        (Some(b'>'), None, b'q') => true,
+        // DECRQM
+        (None | Some(b'?'), Some(b'$'), b'p') => true,
+        // the kitty keyboard protocol flags
+        (Some(b'?'), None, b'u') => true,
+        // window size and position reports
+        (None, None, b't') => matches!(first, Some(11 | 13 | 14 | 15 | 16 | 18 | 19 | 20 | 21)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter(policy: Policy, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut filter = Filter::new(policy);
+        chunks.iter().flat_map(|chunk| filter.filter(chunk).into_owned()).collect()
+    }
+
+    const ALL: Policy =
+        Policy { title: true, clipboard: true, queries: true, dcs: true, graphics: true };
+
+    #[test]
+    fn passes_everything_else() {
+        let out = b"plain \x1b[1;32mgreen\x1b[0m \x1b]8;;https://example.com\x1b\\link\x1b]8;;\x07 \x1b7\x1b8";
+        assert_eq!(filter(ALL, &[out]), out);
+        assert!(matches!(Filter::new(ALL).filter(b"no escapes"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn strips_titles() {
+        let policy = Policy { title: true, ..Default::default() };
+        assert_eq!(filter(policy, &[b"a\x1b]0;title\x07b\x1b]2;other\x1b\\c"]), b"abc");
+        // split up every which way
+        assert_eq!(filter(policy, &[b"a\x1b", b"]", b"2;ti", b"tle\x1b", b"\\c"]), b"ac");
+        assert_eq!(filter(Policy::default(), &[b"a\x1b]0;title\x07b"]), b"a\x1b]0;title\x07b");
+    }
+
+    #[test]
+    fn strips_clipboard() {
+        let policy = Policy { clipboard: true, ..Default::default() };
+        let long = [b"\x1b]52;c;".as_slice(), &[b'A'; 4096], b"\x07after"].concat();
+        assert_eq!(filter(policy, &[&long]), b"after");
+        assert_eq!(filter(policy, &[b"\x1b]0;t\x07"]), b"\x1b]0;t\x07");
+    }
+
+    #[test]
+    fn strips_queries() {
+        let policy = Policy { queries: true, ..Default::default() };
+        assert_eq!(filter(policy, &[b"a\x1b[6nb\x1b[cc\x1b[>0cd\x1b[?1$pe\x1b[18tf"]), b"abcdef");
+        assert_eq!(filter(policy, &[b"a\x1b]11;?\x1b\\b\x1b]52;c;?\x07c\x1bP$qm\x1b\\d"]), b"abcd");
+        // look alikes that aren't queries
+        let kept = b"\x1b[>4;1m\x1b[8;24;80t\x1b]11;#000000\x07\x1b]52;c;aGk=\x07\x1bPq#0\x1b\\";
+        assert_eq!(filter(policy, &[kept]), kept);
+    }
+
+    #[test]
+    fn strips_dcs() {
+        let policy = Policy { dcs: true, ..Default::default() };
+        let sixel = b"a\x1bPq#0;2;0;0;0#0~~@@vv\x07still sixel\x1b\\b";
+        assert_eq!(filter(policy, &[sixel]), b"ab");
+        assert_eq!(filter(policy, &[b"\x1bPtmux;\x1b\x1b]52;c;x\x07\x1b\\b"]), b"b");
+    }
+
+    #[test]
+    fn strips_graphics() {
+        let policy = Policy::graphics();
+        let sixel = b"a\x1bP0;1;0q#0;2;0;0;0#0~~@@vv\x1b\\b\x1bPq#0\x1b\\c";
+        assert_eq!(filter(policy, &[sixel]), b"abc");
+        let kitty = b"a\x1b_Gf=100,a=T;iVBORw0KGgo=\x1b\\b";
+        assert_eq!(filter(policy, &[&kitty[..5], &kitty[5..]]), b"ab");
+        let iterm = b"a\x1b]1337;File=inline=1:aGk=\x07b\x1b]1337;FilePart=aGk=\x07c";
+        assert_eq!(filter(policy, &[iterm]), b"abc");
+        // other strings that just look a bit like images
+        let kept = b"\x1bP$qm\x1b\\\x1bP+q544e\x1b\\\x1b]1337;SetMark\x07\x1b_other\x1b\\";
+        assert_eq!(filter(policy, &[kept]), kept);
+        // and nothing gets stripped without the policy asking for it
+        let all: [&[u8]; 3] = [sixel, kitty, iterm];
+        assert_eq!(filter(Policy::default(), &all), all.concat());
+    }
+
+    #[test]
+    fn graphics_fast_path() {
+        let mut graphics = Filter::new(Policy::graphics());
+        let plain = b"\x1b[1;32mgreen\x1b[0m \x1b]0;title\x07 \x1b]133;A\x07";
+        assert!(matches!(graphics.filter(plain), Cow::Borrowed(_)));
+        assert!(matches!(graphics.filter(b"\x1b[1m\x1b]13"), Cow::Owned(_)));
+        // an image cut off at the end of a chunk still gets stripped
+        let kitty = b"a\x1b_Gf=100;iVBORw0KGgo=\x1b\\b";
+        assert_eq!(filter(Policy::graphics(), &[&kitty[..2], &kitty[2..]]), b"ab");
+        let iterm = b"a\x1b]1337;File=inline=1:aGk=\x07b";
+        assert_eq!(filter(Policy::graphics(), &[&iterm[..4], &iterm[4..]]), b"ab");
+    }
+
+    #[test]
+    fn unterminated_strings() {
+        // an ESC that isn't ST cuts the string short
+        let policy = Policy { title: true, ..Default::default() };
+        assert_eq!(filter(policy, &[b"\x1b]0;title\x1b[1mbold"]), b"\x1b[1mbold");
+        assert_eq!(filter(Policy::default(), &[b"\x1b]0;t\x1b[1m"]), b"\x1b]0;t\x1b[1m");
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Watching `shpool exec` output for its `--until` marker.
+
+  The shell echoes a command back before running it, so when the
+  marker is part of the command, as in `echo done` with `--until
+  done`, the echo would match before the command even started. To
+  avoid that, the scanner first waits for each line of the input to
+  come back and skips it. Lines get compared with whitespace removed,
+  since the shell prints its prompt in front of them and line editors
+  pad out lines that wrap. If a line comes back that does not look
+  like the echo, the shell is not echoing, and everything from there
+  on gets scanned.
+*/
+
+use std::collections::VecDeque;
+
+// Extra raw bytes kept around while scanning for the marker, so that
+// escape codes split across chunks still get stripped.
+const SCAN_SLACK: usize = 64;
+
+// The most output to hold on to while waiting for the end of an echoed
+// line. An echo never gets this long without a newline, so anything
+// more is real output.
+const MAX_ECHO_LEN: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct Scanner {
+    marker: Vec<u8>,
+    /// The input lines that have yet to be echoed back, with their
+    /// whitespace removed.
+    echo: VecDeque<Vec<u8>>,
+    /// Raw output we have not finished scanning.
+    scan: Vec<u8>,
+}
+
+impl Scanner {
+    pub fn new(marker: &str, input: &[u8]) -> Self {
+        let marker = marker.as_bytes().to_vec();
+        let mut echo = VecDeque::new();
+        // The echo only gets in the way if it contains the marker.
+        if !marker.is_empty() && contains(input, &marker) {
+            let input = input.strip_suffix(b"\r").unwrap_or(input);
+            echo.extend(input.split(|b| *b == b'\r' || *b == b'\n').map(squeeze));
+        }
+        Scanner { marker, echo, scan: vec![] }
+    }
+
+    /// Feed a chunk of output through the scanner, returning true once
+    /// the marker has shown up.
+    pub fn feed(&mut self, buf: &[u8]) -> bool {
+        self.scan.extend_from_slice(buf);
+        while let Some(expected) = self.echo.front() {
+            let Some(end) = self.scan.iter().position(|b| *b == b'\n') else {
+                if self.scan.len() <= MAX_ECHO_LEN {
+                    return false;
+                }
+                self.echo.clear();
+                break;
+            };
+            let line = squeeze(&strip_ansi_escapes::strip(&self.scan[..=end]));
+            if line.ends_with(expected) {
+                self.echo.pop_front();
+                self.scan.drain(..=end);
+            } else {
+                self.echo.clear();
+            }
+        }
+
+        if contains(&strip_ansi_escapes::strip(&self.scan), &self.marker) {
+            return true;
+        }
+        let keep = self.marker.len() + SCAN_SLACK;
+        if self.scan.len() > keep {
+            self.scan.drain(..self.scan.len() - keep);
+        }
+        false
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn squeeze(line: &[u8]) -> Vec<u8> {
+    line.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skips_echo() {
+        let mut scanner = Scanner::new("DONE", b"echo DONE\r");
+        assert!(!scanner.feed(b"prompt> echo DO"));
+        assert!(!scanner.feed(b"NE\r\n"));
+        assert!(scanner.feed(b"DO\x1b[0mNE\r\nprompt> "));
+    }
+
+    #[test]
+    fn skips_multi_line_echo() {
+        let mut scanner = Scanner::new("DONE", b"for i in 1; do\rsleep 0; echo DONE\rdone\r");
+        assert!(!scanner.feed(b"prompt> for i in 1; do\r\n"));
+        assert!(!scanner.feed(b"> sleep 0; echo DONE\r\n> done\r\n"));
+        assert!(scanner.feed(b"DONE\r\n"));
+    }
+
+    #[test]
+    fn skips_wrapped_echo() {
+        let mut scanner = Scanner::new("DONE", b"echo DONE\r");
+        assert!(!scanner.feed(b"prompt> echo DO \rNE\r\n"));
+        assert!(scanner.feed(b"DONE\r\n"));
+    }
+
+    #[test]
+    fn without_echo() {
+        let mut scanner = Scanner::new("DONE", b"echo DONE\r");
+        assert!(scanner.feed(b"DONE\r\n"));
+    }
+
+    #[test]
+    fn marker_not_in_input() {
+        let mut scanner = Scanner::new("DONE-42", b"echo DONE-$((40 + 2))\r");
+        assert!(!scanner.feed(b"prompt> echo DONE-$((40 + 2))\r\nDONE-"));
+        assert!(scanner.feed(b"42\r\n"));
+    }
+}
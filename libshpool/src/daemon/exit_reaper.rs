@@ -0,0 +1,226 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The exit reaper decides what happens to sessions whose shell exits
+  while nobody is attached to them.
+
+  When the shell goes away, the shell->client thread notices that the
+  pty has hung up and sends whatever output the session was holding on
+  to over here before it exits. Depending on the `reap_exited` config
+  setting, the session then either gets removed from the table right
+  away, or it gets kept around in an exited state so that the next
+  client to attach can see how things ended, either until it gets
+  killed or for a fixed amount of time.
+
+  Sessions that have a client attached when their shell exits are
+  handled by the attach logic instead, since the user already got to
+  see the final output.
+*/
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use tracing::{info, span, warn, Level};
+
+use super::{notify, session_table::SessionTable, shell};
+use crate::{config, duration};
+
+// How long to wait for the child watcher to report the exit status
+// after the pty has hung up.
+const EXIT_STATUS_WAIT: Duration = Duration::from_secs(1);
+
+/// What to do with sessions whose shell has exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Remove them from the session table right away.
+    #[default]
+    Immediately,
+    /// Keep them until they get killed.
+    Never,
+    /// Keep them for the given amount of time.
+    After(Duration),
+}
+
+impl Policy {
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        match src {
+            "immediately" => Ok(Policy::Immediately),
+            "never" => Ok(Policy::Never),
+            _ => duration::parse(src).map(Policy::After).map_err(|e| {
+                anyhow!("expected 'immediately', 'never' or a duration like '30m': {:?}", e)
+            }),
+        }
+    }
+
+    fn from_config(config: &config::Config) -> Self {
+        match config.reap_exited.as_deref().map(Policy::parse) {
+            None => Policy::default(),
+            Some(Ok(policy)) => policy,
+            Some(Err(e)) => {
+                warn!("bad reap_exited value, reaping immediately: {:?}", e);
+                Policy::default()
+            }
+        }
+    }
+}
+
+/// A notification from a shell->client thread that the pty hung up
+/// while no client was attached.
+pub struct Exited {
+    /// Shared with Session::name.
+    pub session_name: Arc<Mutex<String>>,
+    pub child_pid: libc::pid_t,
+    /// The restore buffer at the time the shell exited.
+    pub restore: Vec<u8>,
+    /// The scrollback at the time the shell exited.
+    pub scrollback: Vec<u8>,
+}
+
+/// A kept session that gets removed once its time is up.
+struct Expiring {
+    at: Instant,
+    session_name: Arc<Mutex<String>>,
+    child_pid: libc::pid_t,
+}
+
+/// Run the exit reaper thread loop. Should be invoked in a dedicated
+/// thread.
+pub fn run(
+    exited: crossbeam_channel::Receiver<Exited>,
+    shells: Arc<SessionTable>,
+    config: config::Manager,
+    notifier: notify::Notifier,
+) -> anyhow::Result<()> {
+    let _s = span!(Level::INFO, "exit_reaper").entered();
+
+    let mut expiring: Vec<Expiring> = vec![];
+    loop {
+        let wake_at = expiring.iter().map(|e| e.at).min();
+        let timer = match wake_at {
+            Some(at) => crossbeam_channel::at(at),
+            None => crossbeam_channel::never(),
+        };
+        crossbeam_channel::select! {
+            recv(exited) -> msg => {
+                let Ok(msg) = msg else {
+                    info!("bailing due to RecvError");
+                    return Ok(());
+                };
+                let policy = Policy::from_config(&config.get());
+                if let Some(e) = handle_exit(&shells, policy, &notifier, msg) {
+                    expiring.push(e);
+                }
+            }
+            recv(timer) -> _ => {
+                let now = Instant::now();
+                let (due, rest) = expiring.into_iter().partition(|e| e.at <= now);
+                expiring = rest;
+                for e in due.into_iter() {
+                    expire(&shells, e);
+                }
+            }
+        }
+    }
+}
+
+fn handle_exit(
+    shells: &SessionTable,
+    policy: Policy,
+    notifier: &notify::Notifier,
+    msg: Exited,
+) -> Option<Expiring> {
+    let name = msg.session_name.lock().unwrap().clone();
+    let child_exit_notifier = {
+        let shard = shells.shard(&name);
+        let session = shard.get(&name).filter(|s| s.child_pid == msg.child_pid)?;
+        Arc::clone(&session.child_exit_notifier)
+    };
+    let Some(exit_status) = child_exit_notifier.wait(Some(EXIT_STATUS_WAIT)) else {
+        warn!("pty for '{}' hung up, but the shell is still running", name);
+        return None;
+    };
+
+    // the session may have been renamed while we waited
+    let name = msg.session_name.lock().unwrap().clone();
+    let mut shard = shells.shard(&name);
+    let session = shard.get_mut(&name).filter(|s| s.child_pid == msg.child_pid)?;
+    if session.inner.try_lock().is_err() {
+        info!("'{}' has a client attached, leaving it to the attach logic", name);
+        return None;
+    }
+    notifier.send(&name, notify::Event::Exited(exit_status));
+
+    match policy {
+        Policy::Immediately => {
+            info!("'{}' exited with status {}, removing it", name, exit_status);
+            shard.remove(&name);
+            None
+        }
+        Policy::Never | Policy::After(_) => {
+            info!("'{}' exited with status {}, keeping it ({:?})", name, exit_status, policy);
+            session.exited = Some(shell::ExitedShell {
+                exit_status,
+                restore: msg.restore,
+                scrollback: msg.scrollback,
+            });
+            match policy {
+                Policy::After(keep) => Some(Expiring {
+                    at: Instant::now() + keep,
+                    session_name: msg.session_name,
+                    child_pid: msg.child_pid,
+                }),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn expire(shells: &SessionTable, e: Expiring) {
+    let name = e.session_name.lock().unwrap().clone();
+    let mut shard = shells.shard(&name);
+    // The session might have been killed, and maybe replaced by a new
+    // one with the same name, in the meantime.
+    if shard.get(&name).is_some_and(|s| s.child_pid == e.child_pid && s.exited.is_some()) {
+        info!("'{}' has been kept long enough, removing it", name);
+        shard.remove(&name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        assert_eq!(Policy::parse("immediately")?, Policy::Immediately);
+        assert_eq!(Policy::parse("never")?, Policy::Never);
+        assert_eq!(Policy::parse("30m")?, Policy::After(Duration::from_secs(30 * 60)));
+        assert!(Policy::parse("sometimes").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_config() {
+        let config = config::Config::default();
+        assert_eq!(Policy::from_config(&config), Policy::Immediately);
+        let config =
+            config::Config { reap_exited: Some(String::from("bogus")), ..Default::default() };
+        assert_eq!(Policy::from_config(&config), Policy::Immediately);
+        let config = config::Config { reap_exited: Some(String::from("1h")), ..Default::default() };
+        assert_eq!(Policy::from_config(&config), Policy::After(Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Stable paths for forwarded sockets.
+
+  Agents like ssh-agent and gpg-agent get forwarded over an ssh
+  connection to a socket whose path changes every time the user
+  reconnects, so a shell that was handed the path directly ends up
+  pointing at a dead socket after a reattach. Instead, for
+  SSH_AUTH_SOCK and any variable listed in the `forward_sockets`
+  config option, the shell gets the path of a symlink in the session
+  dir, and the daemon re-points that symlink at the latest client's
+  socket on every attach.
+*/
+
+use std::{fs, os, path::Path};
+
+use anyhow::Context;
+
+use crate::config;
+
+/// The variables whose sockets get proxied through a symlink.
+pub fn vars(config: &config::Config) -> Vec<String> {
+    let mut vars = vec![String::from("SSH_AUTH_SOCK")];
+    for var in config.forward_sockets.iter().flatten() {
+        if !vars.contains(var) {
+            vars.push(var.clone());
+        }
+    }
+    vars
+}
+
+/// The name of the symlink for the given variable within the
+/// session dir.
+pub fn link_name(var: &str) -> String {
+    format!("{}.socket", var.to_lowercase().replace('_', "-"))
+}
+
+/// Point the symlink at the given socket, replacing the old link.
+pub fn link(symlink: &Path, target: &str) -> anyhow::Result<()> {
+    let _ = fs::remove_file(symlink); // clean up the link if it exists already
+    os::unix::fs::symlink(target, symlink)
+        .with_context(|| format!("could not symlink '{symlink:?}' to point to '{target:?}'"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(link_name("SSH_AUTH_SOCK"), "ssh-auth-sock.socket");
+        assert_eq!(link_name("GPG_AGENT_SOCK"), "gpg-agent-sock.socket");
+    }
+
+    #[test]
+    fn always_has_ssh_auth_sock() {
+        let config = config::Config {
+            forward_sockets: Some(vec![
+                String::from("GPG_AGENT_SOCK"),
+                String::from("SSH_AUTH_SOCK"),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(vars(&config), vec!["SSH_AUTH_SOCK", "GPG_AGENT_SOCK"]);
+        assert_eq!(vars(&config::Config::default()), vec!["SSH_AUTH_SOCK"]);
+    }
+
+    #[test]
+    fn relinks() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let symlink = tmp_dir.path().join("gpg-agent-sock.socket");
+        link(&symlink, "/run/old.sock")?;
+        link(&symlink, "/run/new.sock")?;
+        assert_eq!(fs::read_link(&symlink)?, Path::new("/run/new.sock"));
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Lifecycle hook commands.
+
+  Users can configure shell commands in the `[hooks]` table of their
+  config which get run whenever a session is created, attached to,
+  detached from, or exits, or when a detached session's output matches
+  a watch (see the watch module). This is the config file equivalent of the
+  `Hooks` trait that wrapping binaries can implement.
+
+  The daemon fires hook events from the middle of its attach logic,
+  often with locks held, so the commands themselves get run one at a
+  time on a dedicated worker thread. The worker looks the command up
+  in the config when the event is processed rather than when it is
+  fired, so edits to the config take effect without a restart.
+*/
+
+use std::{
+    fmt,
+    process::{self, Command, Stdio},
+    thread,
+};
+
+use anyhow::Context;
+use tracing::{info, span, warn, Level};
+
+use super::watch;
+use crate::config;
+
+/// The lifecycle events a hook command can be attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Create,
+    Attach,
+    Detach,
+    Exit,
+    Watch,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Create => write!(f, "create"),
+            Event::Attach => write!(f, "attach"),
+            Event::Detach => write!(f, "detach"),
+            Event::Exit => write!(f, "exit"),
+            Event::Watch => write!(f, "watch"),
+        }
+    }
+}
+
+impl Event {
+    /// The configured command for this event, if any.
+    fn cmd(&self, hooks: &config::HookCmds) -> Option<String> {
+        match self {
+            Event::Create => hooks.on_create.clone(),
+            Event::Attach => hooks.on_attach.clone(),
+            Event::Detach => hooks.on_detach.clone(),
+            Event::Exit => hooks.on_exit.clone(),
+            Event::Watch => hooks.on_watch.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Fired {
+    event: Event,
+    session: String,
+    pid: Option<i32>,
+    exit_status: Option<i32>,
+    hit: Option<watch::Hit>,
+}
+
+/// A handle for firing hook events. Cheap to clone.
+#[derive(Clone)]
+pub struct Runner {
+    tx: crossbeam_channel::Sender<Fired>,
+}
+
+impl Runner {
+    /// Spawn the worker thread that runs hook commands.
+    pub fn new(config: config::Manager) -> anyhow::Result<Self> {
+        let (tx, rx) = crossbeam_channel::unbounded::<Fired>();
+        thread::Builder::new()
+            .name(String::from("hooks"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "hook_cmds").entered();
+                for fired in rx.iter() {
+                    let hooks = config.get().hooks.clone().unwrap_or_default();
+                    let exec = fired.hit.as_ref().and_then(|hit| hit.exec.clone());
+                    let Some(cmd) = exec.or_else(|| fired.event.cmd(&hooks)) else {
+                        continue;
+                    };
+                    if let Err(e) = run_cmd(&cmd, &fired) {
+                        warn!("running {} hook for '{}': {:?}", fired.event, fired.session, e);
+                    }
+                }
+            })
+            .context("spawning hook thread")?;
+
+        Ok(Runner { tx })
+    }
+
+    /// Queue up the hook command for the given event, if there is one.
+    pub fn fire(&self, event: Event, session: &str, pid: Option<i32>) {
+        self.send(Fired {
+            event,
+            session: String::from(session),
+            pid,
+            exit_status: None,
+            hit: None,
+        });
+    }
+
+    /// Queue up the exit hook command, if there is one.
+    pub fn fire_exit(&self, session: &str, pid: i32, exit_status: i32) {
+        self.send(Fired {
+            event: Event::Exit,
+            session: String::from(session),
+            pid: Some(pid),
+            exit_status: Some(exit_status),
+            hit: None,
+        });
+    }
+
+    /// Queue up the command for a watch that matched, which is the
+    /// watch's own `exec` if it has one and the watch hook otherwise.
+    pub fn fire_watch(&self, session: &str, pid: i32, hit: watch::Hit) {
+        self.send(Fired {
+            event: Event::Watch,
+            session: String::from(session),
+            pid: Some(pid),
+            exit_status: None,
+            hit: Some(hit),
+        });
+    }
+
+    fn send(&self, fired: Fired) {
+        if let Err(e) = self.tx.send(fired) {
+            warn!("queueing hook: {:?}", e);
+        }
+    }
+}
+
+fn run_cmd(cmd: &str, fired: &Fired) -> anyhow::Result<()> {
+    info!("running {} hook for '{}'", fired.event, fired.session);
+    let output = build_cmd(cmd, fired).output().context("spawning hook")?;
+    if !output.status.success() {
+        warn!(
+            "{} hook exited with {}, stderr: {}",
+            fired.event,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+fn build_cmd(cmd: &str, fired: &Fired) -> process::Command {
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .env("SHPOOL_HOOK_EVENT", fired.event.to_string())
+        .env("SHPOOL_SESSION_NAME", &fired.session);
+    if let Some(pid) = fired.pid {
+        command.env("SHPOOL_SESSION_PID", pid.to_string());
+    }
+    if let Some(exit_status) = fired.exit_status {
+        command.env("SHPOOL_EXIT_STATUS", exit_status.to_string());
+    }
+    if let Some(hit) = &fired.hit {
+        command.env("SHPOOL_WATCH_PATTERN", &hit.pattern).env("SHPOOL_WATCH_LINE", &hit.line);
+    }
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn env_vars() -> anyhow::Result<()> {
+        let fired = Fired {
+            event: Event::Exit,
+            session: String::from("main"),
+            pid: Some(42),
+            exit_status: Some(3),
+            hit: None,
+        };
+        let output = build_cmd(
+            "echo $SHPOOL_HOOK_EVENT $SHPOOL_SESSION_NAME $SHPOOL_SESSION_PID $SHPOOL_EXIT_STATUS",
+            &fired,
+        )
+        .output()?;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "exit main 42 3\n");
+
+        let fired = Fired {
+            event: Event::Watch,
+            session: String::from("build"),
+            pid: Some(42),
+            exit_status: None,
+            hit: Some(watch::Hit {
+                pattern: String::from("FAIL"),
+                exec: None,
+                line: String::from("test foo ... FAIL"),
+                first: true,
+            }),
+        };
+        let output =
+            build_cmd("echo $SHPOOL_HOOK_EVENT $SHPOOL_WATCH_PATTERN: $SHPOOL_WATCH_LINE", &fired)
+                .output()?;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "watch FAIL: test foo ... FAIL\n");
+        Ok(())
+    }
+
+    #[test]
+    fn picks_cmd() {
+        let hooks = config::HookCmds {
+            on_attach: Some(String::from("notify-send attached")),
+            ..Default::default()
+        };
+        assert_eq!(Event::Attach.cmd(&hooks), Some(String::from("notify-send attached")));
+        assert_eq!(Event::Detach.cmd(&hooks), None);
+    }
+}
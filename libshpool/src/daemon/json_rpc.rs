@@ -0,0 +1,325 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A JSON-RPC endpoint for tooling that does not speak the binary protocol.
+
+  When `json_socket` is configured, the daemon listens on a second unix
+  socket for newline-delimited JSON-RPC 2.0 requests like
+
+      {"jsonrpc": "2.0", "id": 1, "method": "kill", "params": {"sessions": ["main"]}}
+
+  and answers each one with a single line holding either a `result` or
+  an `error`. Notifications, requests without an `id`, get no answer.
+  Only a few operations are offered: `list` and `stats` to look
+  around, and `kill` and `detach` to act on sessions, so anyone who can
+  reach the socket can end sessions. The params and results are the
+  same structs the binary protocol uses, just encoded as JSON.
+
+  Much like the TCP bridge, requests are not handled directly. Each one
+  becomes a connection to the regular server over a socketpair, so the
+  JSON endpoint can never drift from what the subcommands see.
+*/
+
+use std::{
+    fs,
+    io::{BufRead as _, BufReader, Read as _, Write as _},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{anyhow, Context};
+use serde_derive::Deserialize;
+use serde_json::json;
+use shpool_protocol::{
+    ConnectHeader, DetachReply, DetachRequest, KillReply, KillRequest, ListReply, StatsReply,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config,
+    daemon::{server, socket_perms},
+    protocol,
+};
+
+// Error codes from the JSON-RPC 2.0 spec.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+// The longest request line we are willing to buffer. Real requests are
+// tiny, so this is only there to keep a peer from eating our memory.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Start listening on `socket`, handing requests off to `server`.
+/// A stale socket left behind by a daemon that is no longer running
+/// gets cleaned up first.
+pub fn listen(
+    socket: &Path,
+    config: &config::Config,
+    server: Arc<server::Server>,
+) -> anyhow::Result<PathBuf> {
+    if socket.exists() && UnixStream::connect(socket).is_err() {
+        info!("removing stale json socket {:?}", socket);
+        fs::remove_file(socket).context("removing stale json socket")?;
+    }
+    let listener = UnixListener::bind(socket).context("binding json socket")?;
+    socket_perms::apply(socket, config).context("setting json socket permissions")?;
+    info!("listening for json-rpc connections on {:?}", socket);
+
+    let shared_group = config.socket_group.clone();
+    thread::Builder::new()
+        .name(String::from("json-rpc"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("accepting json-rpc conn: {:?}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = server::check_peer(&stream, shared_group.as_deref()) {
+                    warn!("rejecting json-rpc conn: {:?}", e);
+                    continue;
+                }
+                let server = Arc::clone(&server);
+                let spawn_res =
+                    thread::Builder::new().name(String::from("json-rpc-conn")).spawn(move || {
+                        if let Err(e) = serve(&server, stream) {
+                            info!("json-rpc conn: {:?}", e);
+                        }
+                    });
+                if let Err(e) = spawn_res {
+                    error!("spawning json-rpc conn thread: {:?}", e);
+                }
+            }
+        })
+        .context("spawning json-rpc thread")?;
+
+    Ok(PathBuf::from(socket))
+}
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+/// A request that made it through parsing, ready to forward.
+#[derive(Debug)]
+enum Call {
+    List,
+    Kill(KillRequest),
+    Detach(DetachRequest),
+    Stats,
+}
+
+fn serve(server: &Arc<server::Server>, stream: UnixStream) -> anyhow::Result<()> {
+    let mut out = stream.try_clone().context("cloning json-rpc stream")?;
+    let mut write = |reply: serde_json::Value| -> anyhow::Result<()> {
+        let mut reply = serde_json::to_vec(&reply).context("encoding reply")?;
+        reply.push(b'\n');
+        out.write_all(&reply).context("writing reply")
+    };
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = vec![];
+        let len = (&mut reader)
+            .take(MAX_LINE as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .context("reading request")?;
+        if len == 0 {
+            return Ok(());
+        }
+        if line.len() > MAX_LINE {
+            // there is no telling where the next request starts
+            let message = format!("requests may be at most {MAX_LINE} bytes");
+            write(error_reply(serde_json::Value::Null, INVALID_REQUEST, message))?;
+            return Err(anyhow!("request too long"));
+        }
+        let Ok(line) = String::from_utf8(line) else {
+            write(error_reply(serde_json::Value::Null, PARSE_ERROR, "invalid utf-8"))?;
+            continue;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, call) = parse(&line);
+        let reply = match call {
+            Ok(call) => match forward(server, call) {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Err(e) => {
+                    error_reply(id.clone().unwrap_or_default(), INTERNAL_ERROR, format!("{e:#}"))
+                }
+            },
+            Err(reply) => reply,
+        };
+        if id.is_some() {
+            write(reply)?;
+        }
+    }
+}
+
+/// Make sense of one line, returning the id to answer with, or None
+/// for a notification, which gets no answer, along with the call or
+/// the error reply for it. Requests too broken to tell whether they
+/// are notifications get answered with a null id.
+fn parse(line: &str) -> (Option<serde_json::Value>, Result<Call, serde_json::Value>) {
+    let value: serde_json::Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => {
+            let null = serde_json::Value::Null;
+            return (Some(null.clone()), Err(error_reply(null, PARSE_ERROR, e)));
+        }
+    };
+    let id = value.get("id").cloned();
+    let request: Request = match serde_json::from_value(value) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(request) => {
+            let message = format!("unsupported jsonrpc version '{}'", request.jsonrpc);
+            let id = id.unwrap_or_default();
+            return (Some(id.clone()), Err(error_reply(id, INVALID_REQUEST, message)));
+        }
+        Err(e) => {
+            let id = id.unwrap_or_default();
+            return (Some(id.clone()), Err(error_reply(id, INVALID_REQUEST, e)));
+        }
+    };
+    (id.clone(), call(request, id.unwrap_or_default()))
+}
+
+/// Work out which call a well formed request is for.
+fn call(request: Request, id: serde_json::Value) -> Result<Call, serde_json::Value> {
+    // Missing params are fine, every request struct defaults its fields.
+    let params = match request.params {
+        serde_json::Value::Null => json!({}),
+        params => params,
+    };
+    let bad_params = |e: serde_json::Error| error_reply(id.clone(), INVALID_PARAMS, e);
+    let call = match request.method.as_str() {
+        "list" => Call::List,
+        "kill" => Call::Kill(serde_json::from_value(params).map_err(bad_params)?),
+        "detach" => Call::Detach(serde_json::from_value(params).map_err(bad_params)?),
+        "stats" => Call::Stats,
+        method => {
+            return Err(error_reply(id, METHOD_NOT_FOUND, format!("no such method '{method}'")));
+        }
+    };
+    Ok(call)
+}
+
+fn error_reply<M: ToString>(id: serde_json::Value, code: i64, message: M) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message.to_string()},
+    })
+}
+
+/// Run the call as an ordinary connection to the server.
+fn forward(server: &Arc<server::Server>, call: Call) -> anyhow::Result<serde_json::Value> {
+    let (ours, theirs) = UnixStream::pair().context("creating socketpair")?;
+    server::Server::accept(server, theirs);
+    let mut client = match protocol::Client::handshake(ours)? {
+        protocol::ClientResult::JustClient(client) => client,
+        protocol::ClientResult::VersionMismatch { warning, .. } => {
+            // We are talking to ourselves, so this can't really happen.
+            return Err(anyhow!("{}", warning));
+        }
+    };
+    let result = match call {
+        Call::List => {
+            client.write_connect_header(ConnectHeader::List)?;
+            serde_json::to_value(client.read_reply::<ListReply>()?)
+        }
+        Call::Kill(request) => {
+            client.write_connect_header(ConnectHeader::Kill(request))?;
+            serde_json::to_value(client.read_reply::<KillReply>()?)
+        }
+        Call::Detach(request) => {
+            client.write_connect_header(ConnectHeader::Detach(request))?;
+            serde_json::to_value(client.read_reply::<DetachReply>()?)
+        }
+        Call::Stats => {
+            client.write_connect_header(ConnectHeader::Stats)?;
+            serde_json::to_value(client.read_reply::<StatsReply>()?)
+        }
+    };
+    result.context("encoding result")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error_code(reply: serde_json::Value) -> i64 {
+        reply["error"]["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn parse_requests() {
+        let (id, call) = parse(r#"{"jsonrpc": "2.0", "id": 7, "method": "list"}"#);
+        assert_eq!(id, Some(json!(7)));
+        assert!(matches!(call, Ok(Call::List)));
+
+        let (id, call) = parse(
+            r#"{"jsonrpc": "2.0", "id": "a", "method": "kill", "params": {"sessions": ["main"]}}"#,
+        );
+        assert_eq!(id, Some(json!("a")));
+        match call {
+            Ok(Call::Kill(r)) => assert_eq!(r.sessions, vec![String::from("main")]),
+            call => panic!("unexpected call {call:?}"),
+        }
+
+        // a notification
+        let (id, call) = parse(r#"{"jsonrpc": "2.0", "method": "detach"}"#);
+        assert_eq!(id, None);
+        match call {
+            Ok(Call::Detach(r)) => assert!(r.sessions.is_empty() && r.tags.is_empty()),
+            call => panic!("unexpected call {call:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error_code(parse("{").1.unwrap_err()), PARSE_ERROR);
+        assert_eq!(
+            error_code(parse(r#"{"jsonrpc": "2.0", "id": 1}"#).1.unwrap_err()),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(parse(r#"{"id": 1, "method": "list"}"#).1.unwrap_err()),
+            INVALID_REQUEST
+        );
+        let (id, reply) = parse(r#"{"jsonrpc": "1.0", "method": "list"}"#);
+        assert_eq!(id, Some(serde_json::Value::Null));
+        assert_eq!(error_code(reply.unwrap_err()), INVALID_REQUEST);
+        assert_eq!(
+            error_code(parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "attach"}"#).1.unwrap_err()),
+            METHOD_NOT_FOUND
+        );
+        let reply =
+            parse(r#"{"jsonrpc": "2.0", "id": 3, "method": "kill", "params": {"sessions": 5}}"#)
+                .1
+                .unwrap_err();
+        assert_eq!(reply["id"], json!(3));
+        assert_eq!(error_code(reply), INVALID_PARAMS);
+    }
+}
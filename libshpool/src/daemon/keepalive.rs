@@ -0,0 +1,159 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Noticing attached clients that have vanished.
+
+  A client that hangs up shows up as a write error on its socket the
+  next time the daemon sends it a heartbeat, but one that vanishes
+  without hanging up, like an `shpool attach` stuck writing to an ssh
+  connection whose network went away, never does. Its socket just stops
+  getting read, and the session keeps looking attached until the kernel
+  gives up on the ssh connection, which can take many minutes.
+
+  So along with each heartbeat, the shell->client thread checks how
+  much of what has been sent to the client is still sitting unread in
+  its socket. If nothing is, or less is than last time, the client is
+  keeping up. Once it has gone `heartbeat_timeout` without reading
+  anything, the client gets detached just like one that hung up.
+*/
+
+use std::{os::unix::net::UnixStream, time};
+
+use anyhow::anyhow;
+use tracing::warn;
+
+use crate::{config, consts, duration};
+
+/// How long a client may go without reading anything by default.
+pub const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// Parse a heartbeat_interval or heartbeat_timeout value.
+pub fn parse(src: &str) -> anyhow::Result<time::Duration> {
+    let d = duration::parse(src)?;
+    if d.is_zero() {
+        return Err(anyhow!("must be more than zero"));
+    }
+    Ok(d)
+}
+
+/// How often to send heartbeats, from the config.
+pub fn interval(config: &config::Config) -> time::Duration {
+    from_config(&config.heartbeat_interval, "heartbeat_interval", consts::HEARTBEAT_DURATION)
+}
+
+/// How long a client may go without reading, from the config.
+pub fn timeout(config: &config::Config) -> time::Duration {
+    from_config(&config.heartbeat_timeout, "heartbeat_timeout", DEFAULT_TIMEOUT)
+}
+
+fn from_config(src: &Option<String>, key: &str, default: time::Duration) -> time::Duration {
+    match src.as_deref().map(parse) {
+        None => default,
+        Some(Ok(d)) => d,
+        Some(Err(e)) => {
+            warn!("bad {} {:?}, using the default: {:?}", key, src, e);
+            default
+        }
+    }
+}
+
+/// How many bytes sent over the stream the peer has not read yet, if
+/// the platform can tell us.
+#[cfg(target_os = "linux")]
+pub fn unread_bytes(stream: &UnixStream) -> Option<usize> {
+    use std::os::fd::AsRawFd as _;
+
+    let mut unread: libc::c_int = 0;
+    // Safety: TIOCOUTQ (aka SIOCOUTQ) just writes an int through the
+    // pointer, which points at a live c_int.
+    let res = unsafe { libc::ioctl(stream.as_raw_fd(), libc::TIOCOUTQ, &mut unread) };
+    if res < 0 {
+        return None;
+    }
+    usize::try_from(unread).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn unread_bytes(_stream: &UnixStream) -> Option<usize> {
+    None
+}
+
+/// Keeps track of when a client last read some of its output.
+#[derive(Debug)]
+pub struct Liveness {
+    /// How many bytes the client had read as of the last check.
+    consumed: u64,
+    last_read: time::Instant,
+}
+
+impl Liveness {
+    pub fn new(now: time::Instant) -> Self {
+        Liveness { consumed: 0, last_read: now }
+    }
+
+    /// Take note of how many bytes have been `written` to the client's
+    /// socket so far and how many of those are still `unread`, and
+    /// return when the client last read anything. If we can't tell
+    /// what is unread, the client gets the benefit of the doubt.
+    pub fn observe(
+        &mut self,
+        written: u64,
+        unread: Option<usize>,
+        now: time::Instant,
+    ) -> time::Instant {
+        let Some(unread) = unread else {
+            self.last_read = now;
+            return now;
+        };
+        let consumed = written.saturating_sub(unread as u64);
+        if unread == 0 || consumed > self.consumed {
+            self.last_read = now;
+        }
+        self.consumed = self.consumed.max(consumed);
+        self.last_read
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn liveness() {
+        let start = time::Instant::now();
+        let at = |secs| start + time::Duration::from_secs(secs);
+        let mut liveness = Liveness::new(start);
+
+        // nothing left unread
+        assert_eq!(liveness.observe(100, Some(0), at(1)), at(1));
+        // something unread, but less than before
+        assert_eq!(liveness.observe(200, Some(50), at(2)), at(2));
+        // not reading any more
+        assert_eq!(liveness.observe(300, Some(150), at(3)), at(2));
+        assert_eq!(liveness.observe(400, Some(250), at(4)), at(2));
+        // and back again
+        assert_eq!(liveness.observe(400, Some(200), at(5)), at(5));
+        // no idea
+        assert_eq!(liveness.observe(500, None, at(6)), at(6));
+    }
+
+    #[test]
+    fn parses() -> anyhow::Result<()> {
+        assert_eq!(parse("500ms")?, time::Duration::from_millis(500));
+        assert_eq!(parse("2m")?, time::Duration::from_secs(120));
+        assert!(parse("0s").is_err());
+        assert!(parse("soon").is_err());
+        Ok(())
+    }
+}
@@ -38,7 +38,7 @@
 //!
 //! mod ::= 'Ctrl'
 //!
-//! sym ::= 'Space' | <lowercase letters> | <numbers>
+//! sym ::= 'Space' | 'Leader' | <lowercase letters> | <numbers>
 //! ```
 //!
 //! chords bind tighter than sequnces. A chord must be pressed all at once
@@ -47,22 +47,25 @@
 //! For now, only fairly limited chords are supported. Chords must either
 //! be singletons besides 'Ctrl' or of the form 'Ctrl-x' where
 //! x is some non-'Ctrl' key.
+//!
+//! 'Leader' stands in for the leader key configured in the `[keybindings]`
+//! table, so with a leader of 'Ctrl-a' the binding 'Leader d' is the same
+//! as 'Ctrl-a d'. It may only appear as a chord on its own.
 
 use std::{collections::HashMap, fmt};
 
 use anyhow::{anyhow, Context};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
 use super::trie::{Trie, TrieCursor, TrieTab};
 
+/// The binding for detaching when the user has not configured any.
+pub const DEFAULT_DETACH: &str = "Ctrl-Space Ctrl-q";
+
 //
 // Keybindings table
 //
 
-// TODO(ethan): should I have some notion of a cooldown time
-//              where sequences don't count if they are pressed
-//              too slowly?
-
 /// Bindings represents an engine for scanning through user input
 /// and occasionally emitting actions that should be acted upon.
 pub struct Bindings {
@@ -115,8 +118,11 @@ impl TrieTab<ChordAtom> for Vec<Option<usize>> {
 impl Bindings {
     /// new builds a bindings matching engine, parsing the given binding->action
     /// mapping and compiling it into the pair of tries that we use to perform
-    /// online keybinding matching.
-    pub fn new<'a, B: IntoIterator<Item = (&'a str, Action)>>(bindings: B) -> anyhow::Result<Self> {
+    /// online keybinding matching. Any 'Leader' keys in the bindings get expanded to the given leader.
+    pub fn new<'a, B: IntoIterator<Item = (&'a str, Action)>>(
+        bindings: B,
+        leader: Option<&str>,
+    ) -> anyhow::Result<Self> {
         let mut chords = Trie::new();
         let mut sequences = Trie::new();
 
@@ -124,10 +130,22 @@ impl Bindings {
         let mut chord_atom_tab = HashMap::new();
 
         let tokenizer = Lexer::new();
+        let leader = match leader {
+            Some(src) => {
+                let tokens = tokenizer.tokenize(src.chars()).context("tokenizing leader")?;
+                let leader = parse(tokens).context("parsing leader")?;
+                if leader.0.is_empty() || leader.0.iter().any(Chord::is_leader) {
+                    return Err(anyhow!("invalid leader: '{}'", src));
+                }
+                Some(leader)
+            }
+            None => None,
+        };
         for (binding_src, action) in bindings.into_iter() {
             let tokens =
                 tokenizer.tokenize(binding_src.chars()).context("tokenizing keybinding")?;
             let sequence = parse(tokens).context("parsing keybinding")?;
+            let sequence = sequence.expand_leader(leader.as_ref())?;
             for chord in sequence.0.iter() {
                 // resolving the key code will also check the validity
                 let code = chord.key_code()?;
@@ -158,6 +176,12 @@ impl Bindings {
         })
     }
 
+    /// reset abandons any partially matched keybinding.
+    pub fn reset(&mut self) {
+        self.chords_cursor = TrieCursor::Start;
+        self.sequences_cursor = TrieCursor::Start;
+    }
+
     /// transition takes the next byte in an input stream and mutates the
     /// bindings engine while possibly emitting an action that the caller
     /// should perform in response to a keybinding that has just been completed.
@@ -173,7 +197,7 @@ impl Bindings {
                     let cursor = self.sequences_cursor;
                     self.sequences_cursor = TrieCursor::Start;
                     if let Some(action) = self.sequences.get(cursor) {
-                        BindingResult::Match(*action)
+                        BindingResult::Match(action.clone())
                     } else {
                         BindingResult::NoMatch
                     }
@@ -197,11 +221,24 @@ impl Bindings {
     }
 }
 
-#[derive(Eq, PartialEq, Debug, Deserialize, Copy, Clone)]
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     /// detaches the current shpool session
     Detach,
+    /// enters scroll mode to page back through the session's scrollback
+    Scroll,
+    /// shows a list of all the sessions, paged like scroll mode
+    List,
+    /// kills the current shpool session
+    Kill,
+    /// stops (or resumes) forwarding keypresses other than keybindings
+    /// to the shell
+    #[serde(rename = "toggle-read-only")]
+    ToggleReadOnly,
+    /// detaches and attaches to the given session instead, written as
+    /// `action = { switch = "name" }` in the config
+    Switch(String),
     /// does nothing, useful for testing the keybinding engine and not much else
     NoOp,
 }
@@ -214,11 +251,36 @@ pub enum Action {
 #[derive(Eq, PartialEq, Debug)]
 pub struct Sequence(Vec<Chord>);
 
+impl Sequence {
+    /// Replace any 'Leader' chords with the chords of the given leader.
+    fn expand_leader(self, leader: Option<&Sequence>) -> anyhow::Result<Self> {
+        if !self.0.iter().any(Chord::is_leader) {
+            return Ok(self);
+        }
+        let leader =
+            leader.ok_or(anyhow!("keybinding uses Leader, but no leader key is configured"))?;
+
+        let mut chords = vec![];
+        for chord in self.0.into_iter() {
+            if chord.is_leader() {
+                chords.extend(leader.0.iter().cloned());
+            } else {
+                chords.push(chord);
+            }
+        }
+        Ok(Sequence(chords))
+    }
+}
+
 /// a list of keys that need to be held down all together
 #[derive(Eq, PartialEq, Debug, Hash, Clone)]
 pub struct Chord(Vec<String>);
 
 impl Chord {
+    fn is_leader(&self) -> bool {
+        self.0.len() == 1 && self.0[0] == "Leader"
+    }
+
     /// Make sure the chord is valid.
     ///
     /// Valid forms are:
@@ -358,7 +420,7 @@ enum Token {
 
 impl Lexer {
     fn new() -> Self {
-        let words = vec!["Ctrl", "Space"];
+        let words = vec!["Ctrl", "Space", "Leader"];
         let mut words_trie = Trie::new();
         for word in words {
             words_trie.insert(word.chars(), ());
@@ -517,7 +579,7 @@ mod test {
         ];
 
         for (bindings_mapping, keypresses, final_output) in cases.into_iter() {
-            let mut bindings = Bindings::new(bindings_mapping)?;
+            let mut bindings = Bindings::new(bindings_mapping, None)?;
 
             let mut actual_final_output = BindingResult::NoMatch;
             for byte in keypresses.into_iter() {
@@ -529,6 +591,29 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_leader() -> anyhow::Result<()> {
+        let mut bindings = Bindings::new(
+            vec![("Leader d", Action::Detach), ("Leader Leader", Action::NoOp)],
+            Some("Ctrl-a"),
+        )?;
+        assert_eq!(bindings.transition(1), BindingResult::Partial);
+        assert_eq!(bindings.transition(b'd'), BindingResult::Match(Action::Detach));
+        assert_eq!(bindings.transition(1), BindingResult::Partial);
+        assert_eq!(bindings.transition(1), BindingResult::Match(Action::NoOp));
+
+        // reset drops the pending leader
+        assert_eq!(bindings.transition(1), BindingResult::Partial);
+        bindings.reset();
+        assert_eq!(bindings.transition(b'd'), BindingResult::NoMatch);
+
+        let err = Bindings::new(vec![("Leader d", Action::Detach)], None).err().expect("an error");
+        assert!(format!("{err:?}").contains("no leader key"));
+        assert!(Bindings::new(vec![], Some("Leader")).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_cord_validity() -> anyhow::Result<()> {
         let cases = vec![
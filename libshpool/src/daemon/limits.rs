@@ -0,0 +1,314 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Ceilings from the `[limits]` config table, for hosts where lots of
+  users share one daemon. They cap how many sessions each user may
+  have and how much memory the session restore spools may hold, both
+  for each session and for all of them together.
+
+  Once the spools together reach their ceiling, the daemon either
+  refuses new sessions until there is room again or throws away the
+  spools of the detached sessions that have gone the longest without
+  output. Losing a spool only costs a session its restore buffer the
+  next time someone attaches, so evicting is the default. Eviction
+  happens when a new session needs room, and otherwise every few
+  seconds from a background thread.
+*/
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, span, warn, Level};
+
+use super::session_table::SessionTable;
+use crate::{config, config::SpoolOverflow, session_restore, size, status::format_bytes};
+
+// How often the background thread checks the spools against the
+// ceiling.
+const ENFORCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The `[limits]` config table, parsed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_sessions: Option<usize>,
+    pub max_spool_memory: Option<usize>,
+    pub max_session_spool: Option<usize>,
+    pub spool_overflow: SpoolOverflow,
+}
+
+impl Limits {
+    pub fn new(config: &config::Limits) -> anyhow::Result<Self> {
+        if config.max_sessions == Some(0) {
+            return Err(anyhow!("max_sessions must allow at least one session"));
+        }
+        let size = |src: &Option<String>, key: &str| -> anyhow::Result<Option<usize>> {
+            match src.as_deref().map(size::parse).transpose() {
+                Ok(Some(0)) => Err(anyhow!("{key} must be more than zero")),
+                res => res.with_context(|| format!("parsing {key}")),
+            }
+        };
+        Ok(Limits {
+            max_sessions: config.max_sessions,
+            max_spool_memory: size(&config.max_spool_memory, "max_spool_memory")?,
+            max_session_spool: size(&config.max_session_spool, "max_session_spool")?,
+            spool_overflow: config.spool_overflow.unwrap_or_default(),
+        })
+    }
+
+    /// The limits from the config. A bad `[limits]` table gets
+    /// ignored, since refusing every new session over a typo would be
+    /// worse.
+    pub fn from_config(config: &config::Config) -> Self {
+        match config.limits.as_ref().map(Limits::new) {
+            None => Limits::default(),
+            Some(Ok(limits)) => limits,
+            Some(Err(e)) => {
+                warn!("bad limits config, not enforcing any: {:?}", e);
+                Limits::default()
+            }
+        }
+    }
+
+    /// Make sure there is room for a new session called `name` created
+    /// by the user `owner`, returning why not if there isn't. The answer
+    /// only holds until someone else creates a session, so the caller
+    /// has to serialize this with inserting the new session.
+    pub fn admit(&self, shells: &SessionTable, name: &str, owner: u32) -> Result<(), String> {
+        let mut owned = 0;
+        let mut spools = vec![];
+        for shard in shells.shards() {
+            for (session_name, session) in shard.iter() {
+                // an exited session of the same name gets replaced, and
+                // other exited sessions no longer have a shell or spool
+                if session_name == name || session.exited.is_some() {
+                    continue;
+                }
+                if session.owner == owner {
+                    owned += 1;
+                }
+                spools.extend(Spool::of(session_name, session));
+            }
+        }
+
+        if let Some(max) = self.max_sessions
+            && owned >= max
+        {
+            return Err(format!(
+                "session limit reached: you already have {owned} sessions, and the daemon allows {max}"
+            ));
+        }
+
+        if let Some(max) = self.max_spool_memory {
+            let total: usize = spools.iter().map(|s| s.bytes).sum();
+            if total >= max {
+                match self.spool_overflow {
+                    SpoolOverflow::Refuse => {
+                        return Err(format!(
+                            "spool memory limit reached: sessions are holding on to {} of output, and the daemon allows {}",
+                            format_bytes(total as u64),
+                            format_bytes(max as u64)
+                        ));
+                    }
+                    SpoolOverflow::Evict => evict(&spools, max),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The session_restore value to use for a new session, cut down to
+    /// `max_session_spool`.
+    pub fn cap_restore(&self, restore_config: String) -> String {
+        match self.max_session_spool {
+            Some(max) => session_restore::cap(&restore_config, max),
+            None => restore_config,
+        }
+    }
+}
+
+/// Check the `[limits]` table for `shpool config check`.
+pub fn check(config: &config::Limits) -> anyhow::Result<()> {
+    Limits::new(config).map(drop)
+}
+
+/// Keep the spools under `max_spool_memory` when evicting. Should be
+/// invoked in a dedicated thread.
+pub fn run(
+    shells: Arc<SessionTable>,
+    config: config::Manager,
+    stopping: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let _s = span!(Level::INFO, "limits").entered();
+
+    loop {
+        thread::sleep(ENFORCE_INTERVAL);
+        if stopping.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let limits = Limits::from_config(&config.get());
+        let (Some(max), SpoolOverflow::Evict) = (limits.max_spool_memory, limits.spool_overflow)
+        else {
+            continue;
+        };
+        let mut spools = vec![];
+        for shard in shells.shards() {
+            for (name, session) in shard.iter() {
+                spools.extend(Spool::of(name, session));
+            }
+        }
+        if spools.iter().map(|s| s.bytes).sum::<usize>() >= max {
+            evict(&spools, max);
+        }
+    }
+}
+
+/// A session's output spool, as far as eviction is concerned.
+struct Spool {
+    name: String,
+    bytes: usize,
+    /// When the session last produced output, in milliseconds since
+    /// the epoch.
+    last_active: i64,
+    /// Whether a client is attached, in which case the spool is kept.
+    attached: bool,
+    evict: Arc<AtomicBool>,
+}
+
+impl Spool {
+    fn of(name: &str, session: &super::shell::Session) -> Option<Self> {
+        // the shell->client thread of an exited session is gone, so
+        // there is nobody left to evict its spool
+        if session.exited.is_some() {
+            return None;
+        }
+        Some(Spool {
+            name: String::from(name),
+            bytes: session.spool_bytes.load(Ordering::Relaxed),
+            last_active: session.last_active.load(Ordering::Relaxed),
+            attached: session.inner.try_lock().is_err(),
+            evict: Arc::clone(&session.evict_spool),
+        })
+    }
+}
+
+/// Ask the shell->client threads of enough spools to throw them away
+/// to get back under `max` bytes.
+fn evict(spools: &[Spool], max: usize) {
+    for i in pick(spools, max) {
+        let spool = &spools[i];
+        info!("evicting the {} byte spool of '{}'", spool.bytes, spool.name);
+        spool.evict.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The indices of the spools to evict to get under `max` bytes, the
+/// ones that have gone the longest without output first. If that isn't
+/// possible without touching attached sessions, this evicts what it
+/// can.
+fn pick(spools: &[Spool], max: usize) -> Vec<usize> {
+    let mut total: usize = spools.iter().map(|s| s.bytes).sum();
+    let mut candidates: Vec<usize> =
+        (0..spools.len()).filter(|i| !spools[*i].attached && spools[*i].bytes > 0).collect();
+    candidates.sort_by_key(|i| spools[*i].last_active);
+
+    let mut picked = vec![];
+    for i in candidates {
+        if total < max {
+            break;
+        }
+        total -= spools[i].bytes;
+        picked.push(i);
+    }
+    picked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spool(name: &str, bytes: usize, last_active: i64, attached: bool) -> Spool {
+        Spool {
+            name: String::from(name),
+            bytes,
+            last_active,
+            attached,
+            evict: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn new() -> anyhow::Result<()> {
+        let limits = Limits::new(&config::Limits {
+            max_sessions: Some(20),
+            max_spool_memory: Some(String::from("256MiB")),
+            max_session_spool: None,
+            spool_overflow: Some(SpoolOverflow::Refuse),
+        })?;
+        assert_eq!(
+            limits,
+            Limits {
+                max_sessions: Some(20),
+                max_spool_memory: Some(256 * 1024 * 1024),
+                max_session_spool: None,
+                spool_overflow: SpoolOverflow::Refuse,
+            }
+        );
+
+        assert!(
+            Limits::new(&config::Limits { max_sessions: Some(0), ..Default::default() }).is_err()
+        );
+        assert!(Limits::new(&config::Limits {
+            max_session_spool: Some(String::from("0")),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Limits::new(&config::Limits {
+            max_spool_memory: Some(String::from("lots")),
+            ..Default::default()
+        })
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn picks_oldest_detached() {
+        let spools = vec![
+            spool("new", 40, 300, false),
+            spool("old", 40, 100, false),
+            spool("attached", 40, 0, true),
+            spool("middle", 40, 200, false),
+            spool("empty", 0, 50, false),
+        ];
+        // 160 bytes down to under 100 takes two spools
+        let picked: Vec<&str> =
+            pick(&spools, 100).iter().map(|i| spools[*i].name.as_str()).collect();
+        assert_eq!(picked, vec!["old", "middle"]);
+
+        // nothing to do when already under
+        assert!(pick(&spools, 200).is_empty());
+
+        // the attached spool is kept even if that leaves us over
+        let picked: Vec<&str> =
+            pick(&spools, 10).iter().map(|i| spools[*i].name.as_str()).collect();
+        assert_eq!(picked, vec!["old", "middle", "new"]);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! What happens to a session once its last client detaches. By default
+  it just keeps running, but it can also be paused until someone
+  attaches again, or killed once it has gone without a client for a
+  while, for sessions that shouldn't use up resources nobody is
+  looking at.
+
+  Pausing sends SIGSTOP to every process in the session, since the
+  SIGTSTP a terminal would send gets ignored by interactive shells.
+*/
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use nix::{sys::signal, unistd::Pid};
+use tracing::{info, warn};
+
+use super::proc_tree;
+use crate::{config, duration};
+
+/// What to do with a session once its last client detaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Leave it running.
+    #[default]
+    Keep,
+    /// Stop all of its processes until a client attaches again.
+    Stop,
+    /// Kill it once it has gone without a client for this long.
+    Kill(Duration),
+}
+
+impl Policy {
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        match src {
+            "keep" => Ok(Policy::Keep),
+            "stop" => Ok(Policy::Stop),
+            "kill" => Ok(Policy::Kill(Duration::ZERO)),
+            _ => duration::parse(src).map(Policy::Kill).map_err(|e| {
+                anyhow!("expected 'keep', 'stop', 'kill' or a duration like '30m': {:?}", e)
+            }),
+        }
+    }
+
+    /// The policy for the given session, which its `[sessions.<name>]`
+    /// table can override.
+    pub fn from_config(config: &config::Config, session: &str) -> Self {
+        let src = config
+            .session(session)
+            .and_then(|s| s.detach_policy.as_deref())
+            .or(config.detach_policy.as_deref());
+        match src.map(Policy::parse) {
+            None => Policy::default(),
+            Some(Ok(policy)) => policy,
+            Some(Err(e)) => {
+                warn!("bad detach_policy value, keeping the session: {:?}", e);
+                Policy::default()
+            }
+        }
+    }
+}
+
+/// Stop every process in the session led by `leader`. The shell goes
+/// first so that it doesn't get to react to its jobs stopping.
+pub fn stop(leader: libc::pid_t) {
+    info!("stopping the processes of session led by {}", leader);
+    for pid in std::iter::once(leader).chain(others(leader)) {
+        send(pid, signal::Signal::SIGSTOP);
+    }
+}
+
+/// Undo `stop`, with the shell going last.
+pub fn resume(leader: libc::pid_t) {
+    info!("resuming the processes of session led by {}", leader);
+    for pid in others(leader).into_iter().chain(std::iter::once(leader)) {
+        send(pid, signal::Signal::SIGCONT);
+    }
+}
+
+/// The processes in the session other than its shell. Where we can't
+/// look them up, there is only the shell to go on.
+fn others(leader: libc::pid_t) -> Vec<libc::pid_t> {
+    match proc_tree::Snapshot::take() {
+        Some(snapshot) => snapshot.members(leader).into_iter().filter(|p| *p != leader).collect(),
+        None => vec![],
+    }
+}
+
+fn send(pid: libc::pid_t, sig: signal::Signal) {
+    if let Err(e) = signal::kill(Pid::from_raw(pid), sig) {
+        // it may well have exited in the meantime
+        info!("sending {} to {}: {:?}", sig, pid, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        assert_eq!(Policy::parse("keep")?, Policy::Keep);
+        assert_eq!(Policy::parse("stop")?, Policy::Stop);
+        assert_eq!(Policy::parse("kill")?, Policy::Kill(Duration::ZERO));
+        assert_eq!(Policy::parse("10m")?, Policy::Kill(Duration::from_secs(600)));
+        assert!(Policy::parse("pause").is_err());
+        Ok(())
+    }
+}
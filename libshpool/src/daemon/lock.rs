@@ -0,0 +1,204 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Session locks. A locked session takes a passphrase to attach to,
+  which the daemon checks against a salted argon2 hash so that the
+  passphrase itself never gets kept around.
+
+  Every wrong passphrase doubles how long the session refuses to check
+  another one, so guessing gets slow even though each check only takes
+  a fraction of a second. Only one check runs at a time, so guessing
+  from many connections at once is no faster.
+*/
+
+use std::{fs, io::Read, time};
+
+use anyhow::{anyhow, Context};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde_derive::{Deserialize, Serialize};
+
+const SALT_LEN: usize = 16;
+
+// How long a session refuses to check passphrases after the first
+// wrong one, and the most it ever backs off for.
+const BACKOFF_BASE: time::Duration = time::Duration::from_secs(1);
+const BACKOFF_MAX: time::Duration = time::Duration::from_secs(60);
+
+// How long a check holds off other ones if its verdict never gets
+// recorded, say because the session went away in the meantime.
+const CHECK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lock {
+    /// The passphrase hash in PHC string format.
+    hash: String,
+    /// Set by `shpool lock`, cleared by attaching with the passphrase.
+    locked: bool,
+    /// Wrong passphrases since the last right one.
+    #[serde(skip)]
+    failures: u32,
+    /// When the session starts checking passphrases again.
+    #[serde(skip)]
+    backoff_until: Option<time::Instant>,
+}
+
+impl Lock {
+    /// A new lock for the given passphrase, already locked.
+    pub fn new(passphrase: &str) -> anyhow::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        fs::File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut salt))
+            .context("reading salt")?;
+        let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow!("encoding salt: {}", e))?;
+        let hash = Argon2::default()
+            .hash_password(passphrase.as_bytes(), &salt)
+            .map_err(|e| anyhow!("hashing passphrase: {}", e))?
+            .to_string();
+        Ok(Lock { hash, locked: true, failures: 0, backoff_until: None })
+    }
+
+    pub fn verify(&self, passphrase: &str) -> bool {
+        match PasswordHash::new(&self.hash) {
+            Ok(hash) => Argon2::default().verify_password(passphrase.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `other` is the same lock, or at least one with the same
+    /// passphrase, for checking that a verdict from `verify` on a copy
+    /// still applies.
+    pub fn same_passphrase(&self, other: &Lock) -> bool {
+        self.hash == other.hash
+    }
+
+    /// Whether the lock is refusing to check passphrases for now.
+    pub fn backing_off(&self) -> bool {
+        self.backoff_until.is_some_and(|until| time::Instant::now() < until)
+    }
+
+    /// Claim the next passphrase check, returning false if the lock is
+    /// backing off or another check is still underway. The claim holds
+    /// off other checks until `record` notes down the verdict.
+    pub fn start_check(&mut self) -> bool {
+        if self.backing_off() {
+            return false;
+        }
+        self.backoff_until = Some(time::Instant::now() + CHECK_TIMEOUT);
+        true
+    }
+
+    /// Note down the verdict on a passphrase, backing off further
+    /// after each wrong one.
+    pub fn record(&mut self, verified: bool) {
+        if verified {
+            self.failures = 0;
+            self.backoff_until = None;
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        let backoff = BACKOFF_BASE.saturating_mul(1u32 << (self.failures - 1).min(16));
+        self.backoff_until = Some(time::Instant::now() + backoff.min(BACKOFF_MAX));
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    /// Whether attaching takes the passphrase right now. Besides being
+    /// locked explicitly, the lock closes by itself once the session
+    /// has gone `auto_lock_after` without a client, counting from
+    /// `detached_at` (None while a client is attached).
+    pub fn is_locked(
+        &self,
+        detached_at: Option<time::SystemTime>,
+        auto_lock_after: Option<time::Duration>,
+    ) -> bool {
+        if self.locked {
+            return true;
+        }
+        match (detached_at, auto_lock_after) {
+            (Some(at), Some(after)) => at.elapsed().is_ok_and(|idle| idle >= after),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify() -> anyhow::Result<()> {
+        let lock = Lock::new("hunter2")?;
+        assert!(!lock.hash.contains("hunter2"));
+        assert!(lock.verify("hunter2"));
+        assert!(!lock.verify("hunter3"));
+        assert!(!lock.verify(""));
+        Ok(())
+    }
+
+    #[test]
+    fn backoff() -> anyhow::Result<()> {
+        let mut lock = Lock::new("pass")?;
+        assert!(!lock.backing_off());
+        lock.record(false);
+        assert!(lock.backing_off());
+        lock.record(false);
+        assert!(lock.backoff_until.unwrap() > time::Instant::now() + BACKOFF_BASE);
+        for _ in 0..40 {
+            lock.record(false);
+        }
+        assert!(lock.backoff_until.unwrap() <= time::Instant::now() + BACKOFF_MAX);
+
+        lock.record(true);
+        assert!(!lock.backing_off());
+        assert_eq!(lock.failures, 0);
+
+        // one check at a time
+        assert!(lock.start_check());
+        assert!(!lock.start_check());
+        lock.record(true);
+        assert!(lock.start_check());
+
+        assert!(lock.same_passphrase(&lock.clone()));
+        assert!(!lock.same_passphrase(&Lock::new("pass")?));
+        Ok(())
+    }
+
+    #[test]
+    fn auto_lock() -> anyhow::Result<()> {
+        let mut lock = Lock::new("pass")?;
+        assert!(lock.is_locked(None, None));
+        lock.unlock();
+        assert!(!lock.is_locked(None, None));
+
+        let hour = time::Duration::from_secs(60 * 60);
+        let two_hours_ago = time::SystemTime::now() - 2 * hour;
+        assert!(!lock.is_locked(None, Some(hour)));
+        assert!(!lock.is_locked(Some(two_hours_ago), None));
+        assert!(lock.is_locked(Some(two_hours_ago), Some(hour)));
+        assert!(!lock.is_locked(Some(time::SystemTime::now()), Some(hour)));
+
+        lock.lock();
+        assert!(lock.is_locked(None, None));
+        Ok(())
+    }
+}
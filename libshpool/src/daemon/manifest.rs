@@ -0,0 +1,99 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping the resurrect manifest up to date.
+
+  Every so often, the manifest thread takes a snapshot of the sessions
+  and writes it out to the state dir if anything changed, so that the
+  sessions can be brought back with `shpool resurrect` after a reboot.
+  Polling rather than writing on every change means the manifest also
+  picks up on shells moving to a different directory, which the daemon
+  has no other way to find out about.
+*/
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use tracing::{info, span, warn, Level};
+
+use super::session_table::SessionTable;
+use crate::resurrect;
+
+// How stale the manifest is allowed to get. The manifest is small, but
+// there is no point in waking up any more often than this since the
+// only thing that needs it is a reboot.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Run the manifest thread loop. Should be invoked in a dedicated
+/// thread. Once `stopping` is set, the daemon has written the final
+/// manifest itself and is killing off the sessions, so we leave the
+/// manifest alone.
+pub fn run(
+    shells: Arc<SessionTable>,
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let _s = span!(Level::INFO, "manifest").entered();
+
+    // Starting out with an empty manifest means we don't write anything
+    // until the first session shows up, leaving the manifest from the
+    // last daemon alone in case it didn't get set aside.
+    let mut last = resurrect::Manifest::default();
+    loop {
+        thread::sleep(SNAPSHOT_INTERVAL);
+        if stopping.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let manifest = snapshot(&shells);
+        if manifest == last {
+            continue;
+        }
+        if let Err(e) = resurrect::save(&path, &manifest) {
+            warn!("writing manifest: {:?}", e);
+            continue;
+        }
+        info!("wrote manifest with {} sessions", manifest.sessions.len());
+        last = manifest;
+    }
+}
+
+pub fn snapshot(shells: &SessionTable) -> resurrect::Manifest {
+    let mut sessions = vec![];
+    for shard in shells.shards() {
+        for (name, session) in shard.iter() {
+            // there is no shell left to bring back
+            if session.exited.is_some() {
+                continue;
+            }
+            let mut recipe = session.recipe.clone();
+            if let Some(cwd) = session.cwd() {
+                recipe.dir = Some(cwd);
+            }
+            sessions.push(resurrect::Entry {
+                name: name.clone(),
+                tags: session.tags.clone(),
+                recipe,
+            });
+        }
+    }
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+    resurrect::Manifest { sessions }
+}
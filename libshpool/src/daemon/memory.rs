@@ -0,0 +1,182 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Memory statistics for the daemon.
+
+  By default shpool uses the system allocator, which cannot tell us
+  much about where memory is going. The shpool binary's `jemalloc`
+  feature swaps in jemalloc, which can report exact allocation
+  totals and dump heap profiles (when run with
+  `MALLOC_CONF=prof:true`). Its `mimalloc` feature swaps in mimalloc,
+  which is often leaner but only gets process level numbers. The
+  binary installs the global allocator, the matching features here
+  just tell us which one it picked and how to query it.
+
+  The per-subsystem numbers are estimates built by adding up the
+  sizes of the buffers we know about, so they will never quite sum
+  to the totals the allocator reports.
+*/
+
+use std::{
+    mem,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use anyhow::anyhow;
+use shpool_protocol::MemoryReport;
+
+use crate::{
+    consts,
+    daemon::{session_table::SessionTable, shell},
+};
+
+// std::io::BufWriter::new allocates this much, but does not export the
+// constant.
+const BUF_WRITER_CAPACITY: usize = 8 * 1024;
+
+/// Build a memory report covering the whole daemon.
+pub fn report(shells: &SessionTable) -> MemoryReport {
+    let mut report = MemoryReport {
+        allocator: String::from(allocator_name()),
+        allocated_bytes: allocated_bytes(),
+        resident_bytes: resident_bytes(),
+        ..MemoryReport::default()
+    };
+
+    // Every session has a shell->client thread with its own read buffer
+    // whether or not anyone is attached.
+    let per_session =
+        mem::size_of::<shell::Session>() + mem::size_of::<shell::SessionInner>() + consts::BUF_SIZE;
+    // An attached client adds a client->shell read buffer and the write
+    // buffer in front of the client stream.
+    let per_connection = consts::BUF_SIZE + BUF_WRITER_CAPACITY;
+
+    for shard in shells.shards() {
+        for session in shard.values() {
+            report.sessions.count += 1;
+            report.sessions.bytes += per_session as u64;
+
+            let spool_bytes = session.spool_bytes.load(Ordering::Relaxed);
+            if spool_bytes > 0 {
+                report.spools.count += 1;
+                report.spools.bytes += spool_bytes as u64;
+            }
+
+            if session.inner.try_lock().is_err() {
+                report.connections.count += 1;
+                report.connections.bytes += per_connection as u64;
+            }
+        }
+    }
+
+    report
+}
+
+/// The name of the global allocator this binary was built with.
+pub fn allocator_name() -> &'static str {
+    if cfg!(feature = "jemalloc") {
+        "jemalloc"
+    } else if cfg!(feature = "mimalloc") {
+        "mimalloc"
+    } else {
+        "system"
+    }
+}
+
+/// The number of bytes currently allocated by the application, if
+/// the allocator is able to tell us.
+#[cfg(feature = "jemalloc")]
+pub fn allocated_bytes() -> Option<u64> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its stats, so we need to bump the epoch to get
+    // a fresh reading.
+    epoch::advance().ok()?;
+    stats::allocated::read().ok().map(|b| b as u64)
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn allocated_bytes() -> Option<u64> {
+    None
+}
+
+/// The resident set size of the daemon process, if known.
+#[cfg(feature = "jemalloc")]
+pub fn resident_bytes() -> Option<u64> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    epoch::advance().ok()?;
+    stats::resident::read().ok().map(|b| b as u64)
+}
+
+#[cfg(all(not(feature = "jemalloc"), target_os = "linux"))]
+pub fn resident_bytes() -> Option<u64> {
+    // The second field of statm is the resident set size in pages.
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE).ok()??;
+    Some(pages * page_size as u64)
+}
+
+#[cfg(all(not(feature = "jemalloc"), not(target_os = "linux")))]
+pub fn resident_bytes() -> Option<u64> {
+    None
+}
+
+/// Write a heap profile into the runtime dir, returning the path
+/// it ended up at.
+#[cfg(feature = "jemalloc")]
+pub fn dump_heap_profile(runtime_dir: &Path) -> anyhow::Result<PathBuf> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    use anyhow::Context;
+
+    let path = runtime_dir.join("heap.prof");
+    let c_path =
+        CString::new(path.as_os_str().as_bytes()).context("converting heap profile path")?;
+    // Safety: prof.dump takes a `const char *` naming the output file,
+    // and jemalloc does not hold on to the pointer after the call.
+    unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", c_path.as_ptr()) }.map_err(|e| {
+        anyhow!("dumping heap profile (is the daemon running with MALLOC_CONF=prof:true?): {e}")
+    })?;
+    Ok(path)
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn dump_heap_profile(_runtime_dir: &Path) -> anyhow::Result<PathBuf> {
+    Err(anyhow!(
+        "heap profiling is not supported by the {} allocator, rebuild shpool with the jemalloc feature",
+        allocator_name()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocator_name_matches_features() {
+        if cfg!(feature = "jemalloc") {
+            assert_eq!(allocator_name(), "jemalloc");
+            assert!(allocated_bytes().is_some());
+        } else if cfg!(feature = "mimalloc") {
+            assert_eq!(allocator_name(), "mimalloc");
+        } else {
+            assert_eq!(allocator_name(), "system");
+            assert!(allocated_bytes().is_none());
+            assert!(dump_heap_profile(Path::new("/nonexistent")).is_err());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Moving a single session from one daemon to another with `shpool
+  migrate`, say from a daemon started by hand to the one systemd runs.
+
+  This is a takeover of one session rather than all of them. The daemon
+  the session is leaving detaches any attached client, snapshots the
+  session's restore buffer and dials the other daemon's socket with an
+  Adopt header. It then sends the session's state followed by its pty
+  master, passed as SCM_RIGHTS ancillary data, and waits for the other
+  daemon to confirm that it has adopted the session before dropping the
+  session from its own table without touching the shell.
+
+  The shell stays a child of the daemon it started under, which reaps it
+  once it exits, so the adopting daemon can tell when the shell exits
+  but not what its exit status was. As with a takeover, output printed
+  while the session is in flight can get lost, and session ttls and
+  output logs don't carry over.
+*/
+
+use std::{
+    io::Write as _,
+    os::{
+        fd::{OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader};
+use tracing::warn;
+
+use super::takeover;
+use crate::{protocol, protocol::ClientResult};
+
+// How long to give the other daemon to answer us at each step of the
+// handover, up to and including adopting the session.
+const ADOPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hand a session over to the daemon listening on the given socket,
+/// returning once it has adopted it. The caller should then forget the
+/// session without killing its shell.
+pub fn send(socket: &Path, session: takeover::SessionState, pty_fd: RawFd) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(socket).context("dialing the other daemon")?;
+    stream.set_read_timeout(Some(ADOPT_TIMEOUT)).context("setting adopt timeout")?;
+    stream.set_write_timeout(Some(ADOPT_TIMEOUT)).context("setting adopt timeout")?;
+    let client = match protocol::Client::handshake(stream).context("greeting the other daemon")? {
+        ClientResult::JustClient(c) => c,
+        ClientResult::VersionMismatch { warning, client } => {
+            warn!("migrating to a daemon of a different version: {}", warning);
+            client
+        }
+    };
+    client.require(capability::MIGRATE, "adopting sessions")?;
+    client.write_connect_header(ConnectHeader::Adopt).context("requesting adoption")?;
+    let mut stream = client.into_stream();
+
+    let state = takeover::State { sessions: vec![session] };
+    takeover::hand_over(&mut stream, &state, &[pty_fd])
+        .context("handing the session over, the other daemon's log may say why it failed")
+}
+
+/// The adopting daemon's side of a migration. Reads the session and its
+/// pty master. Once the session has been adopted, the caller should
+/// call `ack`.
+pub fn receive(stream: &UnixStream) -> anyhow::Result<(takeover::SessionState, OwnedFd)> {
+    takeover::check_peer(stream)?;
+    let state: takeover::State =
+        protocol::decode_from(stream).context("reading migrated session")?;
+    let mut sessions = state.sessions.into_iter();
+    let (Some(session), None) = (sessions.next(), sessions.next()) else {
+        return Err(anyhow!("expected exactly one migrated session"));
+    };
+    let pty =
+        takeover::recv_fds(stream, 1)?.into_iter().next().ok_or(anyhow!("no pty handed over"))?;
+    Ok((session, pty))
+}
+
+/// Let the daemon the session came from know that it can let go.
+pub fn ack(mut stream: &UnixStream) -> anyhow::Result<()> {
+    stream.write_all(&[1]).context("acking migration")?;
+    Ok(())
+}
@@ -12,72 +12,285 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{env, os::unix::net::UnixListener, path::PathBuf};
+use std::{
+    env, fs,
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
 
-use anyhow::Context;
-use tracing::{info, instrument};
+use anyhow::{anyhow, Context};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+use tracing::{info, instrument, warn};
 
-use crate::{config, consts, hooks};
+use crate::{config, consts, hooks, resurrect, session_restore, tcp};
 
+mod bell;
+mod control;
+mod escape_filter;
 mod etc_environment;
+mod exec_marker;
 mod exit_notify;
+mod exit_reaper;
+mod forward_sockets;
+mod hook_cmds;
+mod json_rpc;
+mod keepalive;
 pub mod keybindings;
+mod limits;
+mod linger;
+mod lock;
+mod manifest;
+mod migrate;
+mod memory;
+mod notify;
+mod out_queue;
+mod output_log;
 mod pager;
+mod proc_tree;
 mod prompt;
+mod refresh_env;
+mod rlimits;
+mod screen_lock;
+mod scrollback;
 mod server;
+mod session_table;
 mod shell;
 mod show_motd;
 mod signals;
+mod socket_perms;
+mod state_dump;
 mod systemd;
+mod takeover;
+mod threads;
+mod throttle;
+mod title;
 mod trie;
 mod ttl_reaper;
+mod utf8;
+mod watch;
+
+pub use exit_reaper::Policy as ReapPolicy;
+pub use keepalive::parse as parse_heartbeat;
+pub use limits::check as check_limits;
+pub use linger::Policy as DetachPolicy;
+pub use scrollback::DEFAULT_LINES as DEFAULT_SCROLLBACK_LINES;
+pub use server::DEFAULT_PROMPT_PREFIX;
+pub use rlimits::check as check_rlimits;
+pub use socket_perms::{check_mode as check_socket_mode, resolve_group as resolve_socket_group};
+pub use watch::compile as compile_watch;
+
+/// How to go into the background for `shpool daemon --daemonize`.
+#[derive(Debug, Default)]
+pub struct Background {
+    /// Defaults to daemonized-shpool.pid next to the socket.
+    pub pid_file: Option<PathBuf>,
+    /// Send stderr, where logs go when there is no --log-file, to
+    /// daemonized-shpool.log next to the socket.
+    pub redirect_logs: bool,
+}
 
 #[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     config_manager: config::Manager,
     runtime_dir: PathBuf,
+    state_dir: PathBuf,
     hooks: Box<dyn hooks::Hooks + Send + Sync>,
     log_level_handle: tracing_subscriber::reload::Handle<
-        tracing_subscriber::filter::LevelFilter,
+        tracing_subscriber::filter::Targets,
         tracing_subscriber::registry::Registry,
     >,
     socket: PathBuf,
+    namespace: Option<String>,
+    takeover: bool,
+    background: Option<Background>,
 ) -> anyhow::Result<()> {
+    let mut background = background;
     if let Ok(daemonize) = env::var(consts::AUTODAEMONIZE_VAR)
         && daemonize == "true" {
             // TODO: Audit that the environment access only happens in single-threaded code.
             unsafe { env::remove_var(consts::AUTODAEMONIZE_VAR) }; // avoid looping
-
-            let pid_file = socket.with_file_name("daemonized-shpool.pid");
-
-            info!("daemonizing with pid_file={:?}", pid_file);
-            daemonize::Daemonize::new().pid_file(pid_file).start().context("daemonizing")?;
+            background.get_or_insert_default();
         }
+    let pid_file = match background {
+        Some(background) => Some(daemonize(&socket, background)?),
+        None => None,
+    };
 
     info!("\n\n======================== STARTING DAEMON ============================\n\n");
 
-    let server = server::Server::new(config_manager, hooks, runtime_dir, log_level_handle)?;
+    if let Err(e) = config_manager.watch() {
+        // not fatal, SIGHUP still works
+        warn!("could not watch config for changes: {:?}", e);
+    }
 
-    let (cleanup_socket, listener) = match systemd::activation_socket() {
-        Ok(l) => {
-            info!("using systemd activation socket");
-            (None, l)
+    // A daemon taking over keeps the sessions going, so there is
+    // nothing to resurrect.
+    let checkpointed = if takeover {
+        vec![]
+    } else {
+        let sessions_dir = runtime_dir.join("sessions");
+        resurrect::set_aside(&state_dir);
+        session_restore::disk::sweep(&sessions_dir, &resurrect::pending(&state_dir));
+        resurrect::take_checkpointed(&state_dir, &sessions_dir)
+    };
+
+    let json_socket = config_manager.get().json_socket.clone().map(|path| runtime_dir.join(path));
+    let server = server::Server::new(
+        config_manager.clone(),
+        hooks,
+        runtime_dir,
+        state_dir,
+        log_level_handle,
+        namespace,
+    )?;
+
+    let (cleanup_socket, listener) = if takeover {
+        let handover = takeover::request(&socket).context("taking over from running daemon")?;
+        let (listener, sessions) = handover.finish()?;
+        info!("took over {} sessions", sessions.len());
+        for (state, pty) in sessions {
+            let name = state.name.clone();
+            if let Err(e) = server.adopt(state, pty) {
+                warn!("adopting session '{}': {:?}", name, e);
+            }
         }
-        Err(e) => {
-            info!("no systemd activation socket: {:?}", e);
-            (Some(socket.clone()), UnixListener::bind(&socket).context("binding to socket")?)
+        (Some(socket.clone()), listener)
+    } else {
+        match systemd::activation_socket() {
+            Ok(l) => {
+                info!("using systemd activation socket");
+                (None, l)
+            }
+            Err(e) => {
+                info!("no systemd activation socket: {:?}", e);
+                let listener = UnixListener::bind(&socket).context("binding to socket")?;
+                socket_perms::apply(&socket, &config_manager.get())
+                    .context("setting socket permissions")?;
+                (Some(socket.clone()), listener)
+            }
         }
     };
+    if let Some(tcp_config) = config_manager.get().tcp.clone()
+        && let Some(addr) = tcp_config.listen
+    {
+        let token_file = tcp_config
+            .token_file
+            .ok_or(anyhow!("tcp.listen is set, but there is no tcp.token_file"))?;
+        let token = tcp::read_token(Path::new(&token_file))?;
+        let tls = match (tcp_config.cert_file, tcp_config.key_file) {
+            (Some(cert_file), Some(key_file)) => Some(
+                tcp::server_tls(Path::new(&cert_file), Path::new(&key_file))
+                    .context("loading tcp tls config")?,
+            ),
+            (None, None) => None,
+            _ => return Err(anyhow!("tcp.cert_file and tcp.key_file must be set together")),
+        };
+        let tcp_server = Arc::clone(&server);
+        let keepalive = tcp::Keepalive {
+            interval: keepalive::interval(&config_manager.get()),
+            timeout: keepalive::timeout(&config_manager.get()),
+        };
+        tcp::listen(&addr, token, tls, keepalive, move |stream| {
+            server::Server::accept(&tcp_server, stream)
+        })?;
+    }
+    // The JSON socket is an extra, so failing to set it up should not
+    // take the whole daemon down with it.
+    let json_socket = json_socket.and_then(|path| {
+        json_rpc::listen(&path, &config_manager.get(), Arc::clone(&server))
+            .inspect_err(|e| warn!("not serving json-rpc: {:?}", e))
+            .ok()
+    });
+
+    // The sessions the last daemon took down with it come back as soon
+    // as we start serving, since the listener is already bound.
+    if !checkpointed.is_empty() {
+        info!("recreating {} sessions with spool checkpoints", checkpointed.len());
+        let config = config_manager.get().clone();
+        let socket = socket.clone();
+        thread::Builder::new()
+            .name(String::from("resurrect"))
+            .spawn(move || resurrect::recreate(&config, checkpointed, &socket))?;
+    }
+
     // spawn the signal handler thread in the background
-    signals::Handler::new(cleanup_socket.clone()).spawn()?;
+    signals::Handler::new(
+        cleanup_socket.clone(),
+        pid_file.clone(),
+        config_manager,
+        Arc::clone(&server),
+    )
+    .spawn()?;
+
+    // We are accepting connections as soon as we start serving, since
+    // the listener is already bound.
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!("notifying systemd that we are ready: {:?}", e);
+    }
+    systemd::spawn_watchdog()?;
 
     server::Server::serve(server, listener)?;
 
+    if let Err(e) = systemd::notify("STOPPING=1") {
+        warn!("notifying systemd that we are stopping: {:?}", e);
+    }
+
     if let Some(sock) = cleanup_socket {
         std::fs::remove_file(sock).context("cleaning up socket on exit")?;
     } else {
         info!("systemd manages the socket, so not cleaning it up");
     }
+    if let Some(json_socket) = json_socket
+        && let Err(e) = fs::remove_file(json_socket)
+    {
+        warn!("cleaning up json socket: {:?}", e);
+    }
+    if let Some(pid_file) = pid_file {
+        fs::remove_file(pid_file).context("cleaning up pid file on exit")?;
+    }
 
     Ok(())
 }
+
+/// Double fork into the background, detaching from the controlling
+/// terminal and locking the pid file. Only the daemon process returns,
+/// with the path of the pid file it holds.
+fn daemonize(socket: &Path, background: Background) -> anyhow::Result<PathBuf> {
+    let pid_file =
+        background.pid_file.unwrap_or_else(|| socket.with_file_name("daemonized-shpool.pid"));
+    // The daemon changes directory to / before it writes the pid file,
+    // so a relative path has to be resolved while we still know what
+    // it was relative to.
+    let pid_file = std::path::absolute(&pid_file)
+        .with_context(|| format!("resolving pid file {pid_file:?}"))?;
+    info!("daemonizing with pid_file={:?}", pid_file);
+
+    // The lock is only taken after forking, when it is too late to fail
+    // with a useful exit status, so check that it is free up front.
+    if let Ok(file) = fs::File::open(&pid_file)
+        && let Err((_, Errno::EWOULDBLOCK)) = Flock::lock(file, FlockArg::LockExclusiveNonblock)
+    {
+        let pid = fs::read_to_string(&pid_file).unwrap_or_default();
+        return Err(anyhow!("daemon already running with pid {} (per {:?})", pid.trim(), pid_file));
+    }
+
+    let mut daemon = daemonize::Daemonize::new().pid_file(&pid_file);
+    if background.redirect_logs {
+        let log_file = socket.with_file_name("daemonized-shpool.log");
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file)
+            .with_context(|| format!("opening {log_file:?}"))?;
+        daemon = daemon.stderr(log);
+    }
+    daemon.start().with_context(|| format!("daemonizing with pid file {pid_file:?}"))?;
+
+    Ok(pid_file)
+}
@@ -0,0 +1,230 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Desktop notifications for detached sessions.
+
+  When the `[notifications]` config table asks for them, the daemon
+  raises a freedesktop notification when something happens in a session
+  nobody is looking at: the shell exits, it rings the bell, or a command
+  finishes. Rather than speaking D-Bus itself, the daemon hands the
+  summary and body to `notify-send` (or whatever `command` is set to),
+  which already knows how to find the session bus and the notification
+  server.
+
+  Like lifecycle hooks, notifications get sent one at a time from a
+  worker thread so that a slow notification server never holds up a
+  session.
+*/
+
+use std::{
+    fmt,
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::Context;
+use tracing::{info, span, warn, Level};
+
+use crate::config;
+
+const DEFAULT_COMMAND: &str = "notify-send";
+
+/// The start of the OSC 133 sequence shells emit when a command is done.
+const COMMAND_DONE: &[u8] = b"\x1b]133;D";
+/// How far past COMMAND_DONE we look for the terminator before giving up.
+const MAX_PARAMS: usize = 32;
+
+/// Something worth telling the user about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The shell exited with the given status.
+    Exited(i32),
+    /// The session rang the bell.
+    Bell,
+    /// A command finished, with its exit status if the shell said.
+    CommandDone(Option<i32>),
+}
+
+impl Event {
+    fn wanted(&self, notifications: &config::Notifications) -> bool {
+        match self {
+            Event::Exited(_) => notifications.on_exit,
+            Event::Bell => notifications.on_bell,
+            Event::CommandDone(_) => notifications.on_command_done,
+        }
+        .unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Exited(status) => write!(f, "exited with status {status}"),
+            Event::Bell => write!(f, "rang the bell"),
+            Event::CommandDone(Some(status)) => write!(f, "command finished with status {status}"),
+            Event::CommandDone(None) => write!(f, "command finished"),
+        }
+    }
+}
+
+struct Sent {
+    session: String,
+    event: Event,
+}
+
+/// A handle for sending notifications. Cheap to clone.
+#[derive(Clone)]
+pub struct Notifier {
+    tx: crossbeam_channel::Sender<Sent>,
+}
+
+impl Notifier {
+    /// Spawn the worker thread that shows notifications.
+    pub fn new(config: config::Manager) -> anyhow::Result<Self> {
+        let (tx, rx) = crossbeam_channel::unbounded::<Sent>();
+        thread::Builder::new()
+            .name(String::from("notify"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "notify").entered();
+                for sent in rx.iter() {
+                    let notifications = config.get().notifications.clone().unwrap_or_default();
+                    if !sent.event.wanted(&notifications) {
+                        continue;
+                    }
+                    let cmd = notifications.command.as_deref().unwrap_or(DEFAULT_COMMAND);
+                    if let Err(e) = show(cmd, &sent) {
+                        warn!("notifying about '{}': {:?}", sent.session, e);
+                    }
+                }
+            })
+            .context("spawning notify thread")?;
+
+        Ok(Notifier { tx })
+    }
+
+    /// Queue up a notification, which gets dropped if the config does
+    /// not ask for this kind of event.
+    pub fn send(&self, session: &str, event: Event) {
+        if let Err(e) = self.tx.send(Sent { session: String::from(session), event }) {
+            warn!("queueing notification: {:?}", e);
+        }
+    }
+}
+
+fn show(cmd: &str, sent: &Sent) -> anyhow::Result<()> {
+    info!("'{}' {}, notifying", sent.session, sent.event);
+    // Going through the shell lets `command` carry its own flags,
+    // like "notify-send --urgency=low".
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{cmd} \"$@\""))
+        .arg("shpool")
+        .arg(format!("shpool: {}", sent.session))
+        .arg(format!("{} {}", sent.session, sent.event))
+        .stdin(Stdio::null())
+        .output()
+        .context("spawning notification command")?;
+    if !output.status.success() {
+        warn!(
+            "notification command exited with {}, stderr: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Scans a stream of terminal output for the OSC 133;D marks shells
+/// with prompt integration emit when a command finishes.
+#[derive(Debug, Default)]
+pub struct CommandScanner {
+    // the tail of the last chunk, in case a mark got split across chunks
+    pending: Vec<u8>,
+}
+
+impl CommandScanner {
+    /// Feed a chunk of output through the scanner, returning an event
+    /// for each command that finished.
+    pub fn scan(&mut self, buf: &[u8]) -> Vec<Event> {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(buf);
+
+        let mut events = vec![];
+        let mut pos = 0;
+        while let Some(start) = find(&data[pos..], COMMAND_DONE).map(|i| pos + i) {
+            let params_start = start + COMMAND_DONE.len();
+            let rest = &data[params_start..];
+            let Some(end) = rest.iter().position(|b| *b == 0x07 || *b == 0x1b) else {
+                if rest.len() < MAX_PARAMS {
+                    // wait for the rest of the sequence
+                    self.pending = data[start..].to_vec();
+                    return events;
+                }
+                pos = params_start;
+                continue;
+            };
+            // The params look like ";<exit status>" followed by
+            // anything else the shell wants to add.
+            let status = std::str::from_utf8(&rest[..end])
+                .ok()
+                .and_then(|p| p.strip_prefix(';'))
+                .and_then(|p| p.split(';').next())
+                .and_then(|s| s.parse().ok());
+            events.push(Event::CommandDone(status));
+            pos = params_start + end;
+        }
+
+        // hang on to anything that could be the start of a mark
+        let keep = (1..COMMAND_DONE.len())
+            .rev()
+            .find(|n| data.len() >= *n && data.ends_with(&COMMAND_DONE[..*n]))
+            .unwrap_or(0);
+        self.pending = data[data.len() - keep..].to_vec();
+        events
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scan_command_done() {
+        let mut scanner = CommandScanner::default();
+        assert_eq!(
+            scanner.scan(b"$ make\r\nok\r\n\x1b]133;D;0\x07\x1b]133;A\x07$ "),
+            vec![Event::CommandDone(Some(0))]
+        );
+        assert_eq!(scanner.scan(b"\x1b]133;D\x1b\\"), vec![Event::CommandDone(None)]);
+        assert_eq!(scanner.scan(b"\x1b]133;A\x07\x1b]133;B\x07"), vec![]);
+
+        // split across chunks, both in the prefix and in the params
+        assert_eq!(scanner.scan(b"done\x1b]13"), vec![]);
+        assert_eq!(scanner.scan(b"3;D;1"), vec![]);
+        assert_eq!(scanner.scan(b"27;aid=1\x07"), vec![Event::CommandDone(Some(127))]);
+    }
+
+    #[test]
+    fn wanted() {
+        let notifications =
+            config::Notifications { on_bell: Some(true), ..config::Notifications::default() };
+        assert!(Event::Bell.wanted(&notifications));
+        assert!(!Event::Exited(0).wanted(&notifications));
+        assert!(!Event::CommandDone(None).wanted(&notifications));
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping slow clients from holding up their session.
+
+  Writing session output straight to a client socket means a client on
+  a laggy link either stalls the shell->client thread, and with it the
+  shell, or has the kernel buffer pile up without bound. Instead, each
+  client connection gets a [`Sink`] that queues the output frames
+  handed to it and has a writer thread of its own feed them to the
+  socket as fast as the client will take them.
+
+  The queue is capped at `client_output_queue` bytes. Once it is full,
+  the oldest data frames get dropped to make room. Control frames like
+  heartbeats and exit statuses are never dropped, since the client
+  needs all of them to make sense of the stream. A client that has
+  missed some output is showing a garbled screen, so once its writer
+  catches up the shell->client thread clears it and sends it a fresh
+  restore buffer, the same way it would for a reattach.
+
+  Restore buffers that are supposed to be replayed at a limited rate
+  get paced by the writer thread too, so a slow replay never holds up
+  the shell.
+*/
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write as _},
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread, time,
+};
+
+use shpool_protocol::ChunkKind;
+use tracing::{info, warn};
+
+use crate::{config, daemon::threads, session_restore::replay, size};
+
+/// The default cap on how much output may be queued up for a client.
+pub const DEFAULT_LIMIT: usize = 1024 * 1024;
+
+/// What to send a client ahead of the restore buffer when resyncing
+/// it: home the cursor and clear the screen.
+pub const RESYNC_PREFIX: &[u8] = b"\x1b[H\x1b[2J";
+
+/// The queue cap from the config, falling back to the default if it
+/// is unset or bad.
+pub fn limit(config: &config::Config) -> usize {
+    match &config.client_output_queue {
+        Some(src) => match size::parse(src) {
+            Ok(0) | Err(_) => {
+                warn!("bad client_output_queue {:?}, using the default", src);
+                DEFAULT_LIMIT
+            }
+            Ok(limit) => limit,
+        },
+        None => DEFAULT_LIMIT,
+    }
+}
+
+/// Running totals for the queues of all the clients of a session.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// How many bytes are currently waiting to be written.
+    pub queued_bytes: AtomicUsize,
+    /// How many bytes of output got dropped because a client was
+    /// too slow to read them.
+    pub dropped_bytes: AtomicU64,
+    /// How many times a client got sent a fresh restore buffer after
+    /// output was dropped.
+    pub resyncs: AtomicU64,
+}
+
+struct Frame {
+    buf: Vec<u8>,
+    /// The least amount of time the writer should spend on the frame,
+    /// for pacing replays. Paced frames never get dropped, since
+    /// dropping part of a replay would only set off another one.
+    pace: Option<time::Duration>,
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Frame>,
+    /// The total size of `frames`.
+    bytes: usize,
+    /// Set while the writer has a frame popped off the queue that it
+    /// has not finished writing yet.
+    writing: bool,
+    /// Set once some output has been dropped, until the client gets
+    /// resynced.
+    dropped: bool,
+    /// Set once the sink goes away, telling the writer to stop once
+    /// it has written whatever is left.
+    closed: bool,
+    /// The error the writer ran into, if it did.
+    error: Option<io::ErrorKind>,
+}
+
+impl State {
+    /// Queue up a frame, first making room for it by dropping the
+    /// oldest output the client has not gotten to yet. The new frame
+    /// always goes in, even if it is bigger than the limit on its own.
+    fn push(&mut self, frame: Frame, limit: usize, counters: &Counters) {
+        let mut i = 0;
+        while self.bytes + frame.buf.len() > limit && i < self.frames.len() {
+            if self.frames[i].pace.is_none() && is_data(&self.frames[i].buf) {
+                let dropped = self.frames.remove(i).unwrap();
+                self.bytes -= dropped.buf.len();
+                self.dropped = true;
+                counters.queued_bytes.fetch_sub(dropped.buf.len(), Ordering::Relaxed);
+                counters.dropped_bytes.fetch_add(dropped.buf.len() as u64, Ordering::Relaxed);
+            } else {
+                i += 1;
+            }
+        }
+
+        self.bytes += frame.buf.len();
+        counters.queued_bytes.fetch_add(frame.buf.len(), Ordering::Relaxed);
+        self.frames.push_back(frame);
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signaled whenever a frame gets queued or finishes writing.
+    cond: Condvar,
+    counters: Arc<Counters>,
+    /// How many bytes the writer has written to the socket.
+    written: AtomicU64,
+}
+
+/// An output stream to a client that never blocks. Everything written
+/// between two flushes makes up one frame, so callers must flush after
+/// writing each batch of whole chunks.
+pub struct Sink {
+    /// The frame being built up by writes.
+    frame: Vec<u8>,
+    limit: usize,
+    shared: Arc<Shared>,
+}
+
+impl Sink {
+    /// Create a sink writing to the given stream, spawning its writer
+    /// thread.
+    pub fn new(
+        stream: UnixStream,
+        session: &str,
+        limit: usize,
+        counters: Arc<Counters>,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            counters,
+            written: AtomicU64::new(0),
+        });
+        let writer_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name(threads::name("out", session))
+            .spawn(move || write_frames(stream, &writer_shared))?;
+        Ok(Sink { frame: vec![], limit, shared })
+    }
+
+    /// Returns true once, when output has been dropped and the client
+    /// has since caught up on everything queued for it, meaning it is
+    /// time to send it a fresh restore buffer.
+    pub fn resync_due(&self) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.dropped && state.frames.is_empty() && !state.writing && state.error.is_none() {
+            state.dropped = false;
+            self.shared.counters.resyncs.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Like flush, but the writer waits until `interval` has passed
+    /// since it started on this frame before moving on to the next.
+    pub fn flush_paced(&mut self, interval: time::Duration) -> io::Result<()> {
+        self.push_frame(Some(interval))
+    }
+
+    fn push_frame(&mut self, pace: Option<time::Duration>) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(kind) = state.error {
+            self.frame.clear();
+            return Err(io::Error::from(kind));
+        }
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+
+        let frame = Frame { buf: std::mem::take(&mut self.frame), pace };
+        state.push(frame, self.limit, &self.shared.counters);
+        self.shared.cond.notify_all();
+        Ok(())
+    }
+
+    /// How many bytes have made it into the client's socket so far.
+    pub fn written(&self) -> u64 {
+        self.shared.written.load(Ordering::Relaxed)
+    }
+
+    /// Wait for up to the given timeout for the queue to drain, so
+    /// that a final exit chunk has a chance to make it out before the
+    /// connection gets shut down.
+    pub fn drain(&self, timeout: time::Duration) {
+        let state = self.shared.state.lock().unwrap();
+        let (state, res) = self
+            .shared
+            .cond
+            .wait_timeout_while(state, timeout, |state| {
+                (!state.frames.is_empty() || state.writing) && state.error.is_none()
+            })
+            .unwrap();
+        if res.timed_out() {
+            info!("gave up draining client output with {} bytes queued", state.bytes);
+        }
+    }
+}
+
+impl io::Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.frame.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.push_frame(None)
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+/// Whether a frame holds nothing but session output, and so is safe
+/// to drop. Frames holding several chunks only ever hold data chunks.
+fn is_data(frame: &[u8]) -> bool {
+    frame.first() == Some(&(ChunkKind::Data as u8))
+}
+
+/// The body of the writer thread.
+fn write_frames(mut stream: UnixStream, shared: &Shared) {
+    loop {
+        let frame = {
+            let mut state = shared.state.lock().unwrap();
+            while state.frames.is_empty() && !state.closed {
+                state = shared.cond.wait(state).unwrap();
+            }
+            let Some(frame) = state.frames.pop_front() else {
+                return;
+            };
+            state.bytes -= frame.buf.len();
+            state.writing = true;
+            frame
+        };
+
+        let started = time::Instant::now();
+        let res = stream.write_all(&frame.buf).and_then(|_| stream.flush());
+        shared.counters.queued_bytes.fetch_sub(frame.buf.len(), Ordering::Relaxed);
+
+        let mut state = shared.state.lock().unwrap();
+        state.writing = false;
+        if let Err(e) = res {
+            info!("client write err, assuming hangup: {:?}", e);
+            state.error = Some(e.kind());
+            let bytes = state.bytes;
+            state.frames.clear();
+            state.bytes = 0;
+            shared.counters.queued_bytes.fetch_sub(bytes, Ordering::Relaxed);
+            shared.cond.notify_all();
+            return;
+        }
+        shared.written.fetch_add(frame.buf.len() as u64, Ordering::Relaxed);
+        shared.cond.notify_all();
+        drop(state);
+
+        if let Some(interval) = frame.pace {
+            replay::pace(started, interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(kind: ChunkKind, len: usize) -> Frame {
+        let mut buf = vec![kind as u8];
+        buf.resize(len, b'x');
+        Frame { buf, pace: None }
+    }
+
+    #[test]
+    fn push() {
+        let counters = Counters::default();
+        let mut state = State::default();
+
+        state.push(frame(ChunkKind::Data, 40), 100, &counters);
+        state.push(frame(ChunkKind::Heartbeat, 10), 100, &counters);
+        state.push(frame(ChunkKind::Data, 40), 100, &counters);
+        assert_eq!(state.bytes, 90);
+        assert!(!state.dropped);
+
+        // the oldest data frame makes way, but not the heartbeat
+        state.push(frame(ChunkKind::Data, 40), 100, &counters);
+        assert_eq!(state.bytes, 90);
+        assert!(state.dropped);
+        assert_eq!(state.frames[0].buf[0], ChunkKind::Heartbeat as u8);
+        assert_eq!(counters.dropped_bytes.load(Ordering::Relaxed), 40);
+
+        // a frame too big to ever fit still goes in
+        state.push(frame(ChunkKind::Data, 200), 100, &counters);
+        assert_eq!(state.bytes, 210);
+        assert_eq!(state.frames.len(), 2);
+        assert_eq!(counters.queued_bytes.load(Ordering::Relaxed), 210);
+        assert_eq!(counters.dropped_bytes.load(Ordering::Relaxed), 120);
+    }
+
+    #[test]
+    fn keeps_paced_frames() {
+        let counters = Counters::default();
+        let mut state = State::default();
+
+        let paced =
+            Frame { pace: Some(time::Duration::from_millis(10)), ..frame(ChunkKind::Data, 60) };
+        state.push(paced, 100, &counters);
+        state.push(frame(ChunkKind::Data, 60), 100, &counters);
+        assert!(!state.dropped);
+        state.push(frame(ChunkKind::Data, 30), 100, &counters);
+        assert!(state.dropped);
+        assert_eq!(state.frames.len(), 2);
+        assert!(state.frames[0].pace.is_some());
+    }
+
+    #[test]
+    fn paces_writes() -> anyhow::Result<()> {
+        let (client, mut server) = UnixStream::pair()?;
+        let mut sink = Sink::new(client, "test", DEFAULT_LIMIT, Arc::new(Counters::default()))?;
+        let started = time::Instant::now();
+        for _ in 0..3 {
+            sink.write_all(&frame(ChunkKind::Data, 10).buf)?;
+            sink.flush_paced(time::Duration::from_millis(50))?;
+        }
+        sink.write_all(&frame(ChunkKind::Data, 10).buf)?;
+        sink.flush()?;
+        drop(sink);
+
+        let mut buf = vec![];
+        std::io::Read::read_to_end(&mut server, &mut buf)?;
+        assert_eq!(buf.len(), 40);
+        assert!(started.elapsed() >= time::Duration::from_millis(150));
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Teeing session output to log files.
+
+  When output logging is turned on for a session, a dedicated thread
+  subscribes to the session's output taps and appends everything the
+  shell prints to a log file, so output that has scrolled out of the
+  restore buffer is not lost. Each log file is named after the session
+  and the time it was opened. Once a file grows past the size limit,
+  a fresh one gets opened and the oldest files beyond the number we
+  are supposed to keep get removed.
+
+  Logs either hold the raw output, or are asciicast v2 recordings that
+  `shpool replay` can play back. A recording starts with a header line
+  giving the terminal size, followed by one JSON line per chunk of
+  output with the time since the file was opened. Notes set with
+  `shpool note` show up in recordings as markers.
+*/
+
+use std::{fs, io::Write as _, os::unix::fs::OpenOptionsExt as _, path::PathBuf, thread, time};
+
+use anyhow::Context;
+use shpool_protocol::TtySize;
+use tracing::{info, span, warn, Level};
+
+use crate::{
+    config::OutputLogFormat,
+    daemon::{shell, threads},
+};
+
+/// How big a log file may get before we move on to a new one.
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How many log files to keep for each session.
+pub const DEFAULT_KEEP: usize = 5;
+
+// Sorts in time order, and has millisecond resolution so that a
+// burst of output rotating through several files in the same second
+// does not reuse a name.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S%.3f";
+
+pub struct OutputLog {
+    dir: PathBuf,
+    session: String,
+    max_size: u64,
+    keep: usize,
+    format: OutputLogFormat,
+    /// The size recordings say the terminal is.
+    size: TtySize,
+    file: Option<fs::File>,
+    written: u64,
+    /// When the current file was opened, which recording event times
+    /// count from.
+    opened: time::Instant,
+}
+
+impl OutputLog {
+    pub fn new(
+        dir: PathBuf,
+        session: String,
+        max_size: u64,
+        keep: usize,
+        format: OutputLogFormat,
+        size: TtySize,
+    ) -> Self {
+        OutputLog {
+            dir,
+            session,
+            max_size,
+            keep: keep.max(1),
+            format,
+            size,
+            file: None,
+            written: 0,
+            opened: time::Instant::now(),
+        }
+    }
+
+    /// Log some output, rotating first if the current file is full.
+    pub fn write(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        match self.format {
+            OutputLogFormat::Raw => self.append(buf),
+            OutputLogFormat::Asciicast => self.event("o", &String::from_utf8_lossy(buf)),
+        }
+    }
+
+    /// Drop a marker with the given label into the log. Raw logs have
+    /// nowhere to put one, so they are left alone.
+    pub fn mark(&mut self, label: &str) -> anyhow::Result<()> {
+        match self.format {
+            OutputLogFormat::Raw => Ok(()),
+            OutputLogFormat::Asciicast => self.event("m", label),
+        }
+    }
+
+    fn event(&mut self, kind: &str, data: &str) -> anyhow::Result<()> {
+        // rotate before taking the time so that it counts from the
+        // start of the file the event ends up in
+        self.maybe_rotate()?;
+        let secs = (self.opened.elapsed().as_secs_f64() * 1e6).round() / 1e6;
+        let mut line = serde_json::to_vec(&(secs, kind, data)).context("formatting event")?;
+        line.push(b'\n');
+        self.append(&line)
+    }
+
+    fn maybe_rotate(&mut self) -> anyhow::Result<()> {
+        if self.file.is_none() || self.written >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.maybe_rotate()?;
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(buf).context("writing output log")?;
+            self.written += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir).context("creating output log dir")?;
+        let stamp = chrono::Local::now().format(TIMESTAMP_FORMAT);
+        let path = self.dir.join(format!("{}-{}.{}", self.session, stamp, self.extension()));
+        info!("logging output to {:?}", path);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&path)
+            .context("opening output log")?;
+        self.written = 0;
+        self.opened = time::Instant::now();
+        if self.format == OutputLogFormat::Asciicast {
+            let mut header = serde_json::to_vec(&serde_json::json!({
+                "version": 2,
+                "width": self.size.cols,
+                "height": self.size.rows,
+                "timestamp": chrono::Utc::now().timestamp(),
+            }))
+            .context("formatting recording header")?;
+            header.push(b'\n');
+            file.write_all(&header).context("writing recording header")?;
+            self.written += header.len() as u64;
+        }
+        self.file = Some(file);
+        self.prune()
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            OutputLogFormat::Raw => "log",
+            OutputLogFormat::Asciicast => "cast",
+        }
+    }
+
+    /// Remove all but the newest `keep` log files for this session.
+    fn prune(&self) -> anyhow::Result<()> {
+        let mut logs = vec![];
+        for entry in fs::read_dir(&self.dir).context("listing output log dir")? {
+            let name = entry.context("reading output log dir entry")?.file_name();
+            if self.is_log(&name.to_string_lossy()) {
+                logs.push(name);
+            }
+        }
+        logs.sort();
+        let excess = logs.len().saturating_sub(self.keep);
+        for name in logs.into_iter().take(excess) {
+            let path = self.dir.join(name);
+            info!("removing old output log {:?}", path);
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("removing old output log {:?}: {:?}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// True if the given file name belongs to this session. We check
+    /// that the rest of the name is a timestamp so that the logs of a
+    /// session called 'build' don't get mixed up with those of one
+    /// called 'build-release'.
+    fn is_log(&self, name: &str) -> bool {
+        name.strip_prefix(self.session.as_str())
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|rest| rest.strip_suffix(self.extension()))
+            .and_then(|rest| rest.strip_suffix('.'))
+            .map(|stamp| chrono::NaiveDateTime::parse_from_str(stamp, TIMESTAMP_FORMAT).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+/// Spawn a thread that logs everything the session prints from now
+/// on. The thread exits once the session goes away. If the disk can't
+/// keep up with the shell, the log skips some output and notes how
+/// much rather than stopping altogether. Labels sent down the returned
+/// channel get dropped into the log as markers.
+pub fn spawn(
+    taps: &shell::OutputTaps,
+    mut log: OutputLog,
+) -> anyhow::Result<crossbeam_channel::Sender<String>> {
+    let output = taps.add_lossy();
+    let (markers_tx, mut markers) = crossbeam_channel::unbounded::<String>();
+    thread::Builder::new()
+        .name(threads::name("log", &log.session))
+        .spawn(move || {
+            let _s = span!(Level::INFO, "output_log", s = log.session).entered();
+            loop {
+                let res = crossbeam_channel::select! {
+                    recv(output) -> buf => match buf {
+                        Ok(buf) => log.write(&buf),
+                        Err(_) => break,
+                    },
+                    recv(markers) -> label => match label {
+                        Ok(label) => log.mark(&label),
+                        Err(_) => {
+                            // nobody can send markers anymore, but the
+                            // output can still have some way to go
+                            markers = crossbeam_channel::never();
+                            Ok(())
+                        }
+                    },
+                };
+                if let Err(e) = res {
+                    warn!("giving up on output log: {:?}", e);
+                    return;
+                }
+            }
+            info!("session done, closing output log");
+        })
+        .context("spawning output log thread")?;
+    Ok(markers_tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn logs(dir: &std::path::Path) -> anyhow::Result<Vec<String>> {
+        let mut names = fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn rotates_and_prunes() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let dir = tmp_dir.path().join("logs");
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("build-release-2024-01-01_00-00-00.000.log"), "other session")?;
+
+        let mut log = OutputLog::new(
+            dir.clone(),
+            String::from("build"),
+            10,
+            2,
+            OutputLogFormat::Raw,
+            TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        );
+        for _ in 0..4 {
+            log.write(b"0123456789")?;
+            // make sure every file gets a distinct timestamp
+            thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        let mut names = logs(&dir)?;
+        // the other session's log is left alone
+        assert!(names.contains(&String::from("build-release-2024-01-01_00-00-00.000.log")));
+        names.retain(|n| n != "build-release-2024-01-01_00-00-00.000.log");
+        assert_eq!(names.len(), 2);
+        for name in &names {
+            assert!(log.is_log(name));
+            assert_eq!(fs::read(dir.join(name))?, b"0123456789");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_asciicast() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let dir = tmp_dir.path().join("logs");
+
+        let mut log = OutputLog::new(
+            dir.clone(),
+            String::from("demo"),
+            1024,
+            2,
+            OutputLogFormat::Asciicast,
+            TtySize { rows: 30, cols: 100, xpixel: 0, ypixel: 0 },
+        );
+        log.write(b"hello\r\n")?;
+        log.mark("half way")?;
+        log.write("caf\u{e9}".as_bytes())?;
+
+        let names = logs(&dir)?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].ends_with(".cast"));
+        assert!(log.is_log(&names[0]));
+
+        let contents = fs::read_to_string(dir.join(&names[0]))?;
+        let lines = contents
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 100);
+        assert_eq!(lines[0]["height"], 30);
+        let events = lines[1..].iter().map(|l| (l[1].clone(), l[2].clone())).collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                (serde_json::json!("o"), serde_json::json!("hello\r\n")),
+                (serde_json::json!("m"), serde_json::json!("half way")),
+                (serde_json::json!("o"), serde_json::json!("caf\u{e9}")),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -50,7 +50,7 @@ use nix::{poll, sys::signal, unistd};
 use shpool_protocol::{Chunk, ChunkKind, TtySize};
 use tracing::{error, info, instrument, span, trace, warn, Level};
 
-use crate::{consts, protocol::ChunkExt as _, tty::TtySizeExt as _};
+use crate::{consts, logging, protocol::ChunkExt as _, tty::TtySizeExt as _};
 
 // poll relatively quickly to pick up pager exits reasonably fast,
 // but still slow enough to spend most of the time parked.
@@ -152,24 +152,27 @@ impl Pager {
 
         let pager_exited_ref = Arc::clone(&pager_exited);
         let waitable_child = fork.clone();
-        thread::spawn(move || {
-            let _s = span!(Level::INFO, "pager_exit_monitor").entered();
-            match waitable_child.wait_for_exit() {
-                Ok((_, Some(exit_status))) => {
-                    info!("child pager exited with status {}", exit_status);
-                    pager_exited_ref.store(true, Ordering::Relaxed);
-                }
-                Ok((_, None)) => {
-                    info!("child pager exited without status");
-                    pager_exited_ref.store(true, Ordering::Relaxed);
-                }
-                Err(e) => {
-                    info!("error waiting on pager child: {:?}", e);
-                    pager_exited_ref.store(true, Ordering::Relaxed);
+        thread::Builder::new()
+            .name(String::from("pager-wait"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "pager_exit_monitor").entered();
+                match waitable_child.wait_for_exit() {
+                    Ok((_, Some(exit_status))) => {
+                        info!("child pager exited with status {}", exit_status);
+                        pager_exited_ref.store(true, Ordering::Relaxed);
+                    }
+                    Ok((_, None)) => {
+                        info!("child pager exited without status");
+                        pager_exited_ref.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        info!("error waiting on pager child: {:?}", e);
+                        pager_exited_ref.store(true, Ordering::Relaxed);
+                    }
                 }
-            }
-            info!("reaped child pager: {:?}", waitable_child);
-        });
+                info!("reaped child pager: {:?}", waitable_child);
+            })
+            .context("spawning pager exit monitor thread")?;
 
         let mut pty_master = fork.is_parent().context("getting pty_master handle")?;
 
@@ -181,31 +184,34 @@ impl Pager {
         let tty_size = Arc::new(Mutex::new(init_tty_size.clone()));
         let tty_size_ref = Arc::clone(&tty_size);
         info!("spawning pager size change listener");
-        thread::spawn(move || {
-            let _s = span!(Level::INFO, "pager_size_change").entered();
-
-            // We could also set things up to handle detach commands, but
-            // since pagers don't stick around when the client hangs up
-            // it is not really that importaint. Let's KISS.
-            while let Ok(size) = tty_size_change_rx.recv() {
-                info!("recvd new size: {:?}", size);
-                if let Err(e) = size.set_fd(pty_master_fd) {
-                    warn!("setting pager size: {:?}", e);
-                }
+        thread::Builder::new()
+            .name(String::from("pager-size"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "pager_size_change").entered();
+
+                // We could also set things up to handle detach commands, but
+                // since pagers don't stick around when the client hangs up
+                // it is not really that importaint. Let's KISS.
+                while let Ok(size) = tty_size_change_rx.recv() {
+                    info!("recvd new size: {:?}", size);
+                    if let Err(e) = size.set_fd(pty_master_fd) {
+                        warn!("setting pager size: {:?}", e);
+                    }
 
-                {
-                    // register the new size so it will get returned
-                    let mut tty_size = tty_size_ref.lock().unwrap();
-                    *tty_size = size;
-                }
+                    {
+                        // register the new size so it will get returned
+                        let mut tty_size = tty_size_ref.lock().unwrap();
+                        *tty_size = size;
+                    }
 
-                if let Err(e) = tty_size_change_ack_tx.send(()) {
-                    error!("could not send size change ack: {:?}", e);
-                    break;
+                    if let Err(e) = tty_size_change_ack_tx.send(()) {
+                        error!("could not send size change ack: {:?}", e);
+                        break;
+                    }
                 }
-            }
-            info!("pager size change loop done");
-        });
+                info!("pager size change loop done");
+            })
+            .context("spawning pager size change thread")?;
 
         let mut last_heartbeat_at = Instant::now();
         let mut buf = vec![0; consts::BUF_SIZE];
@@ -281,7 +287,7 @@ impl Pager {
                         continue;
                     }
 
-                    trace!("user input: {}", String::from_utf8_lossy(&buf[..len]));
+                    trace!("user input: {}", logging::payload(&buf[..len]));
 
                     if let Err(e) = pty_master.write_all(&buf[0..len]) {
                         info!("Error writing to pager pty, nbd though: {:?}", e);
@@ -0,0 +1,158 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! What the processes running in a session are using. A session's
+  processes are the descendants of its shell along with anything else
+  in the shell's tty session, which catches programs that got
+  reparented to init when whatever started them exited.
+
+  Only supported on Linux, where we can read it all out of /proc.
+*/
+
+use std::collections::{HashMap, HashSet};
+
+use shpool_protocol::ProcUsage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Proc {
+    pid: i32,
+    ppid: i32,
+    sid: i32,
+    cpu_ticks: u64,
+    rss_pages: u64,
+}
+
+/// Every process on the system at one point in time, so that looking
+/// up a bunch of sessions only reads /proc once.
+pub struct Snapshot {
+    procs: Vec<Proc>,
+    tick_ns: u64,
+    page_size: u64,
+}
+
+impl Snapshot {
+    #[cfg(target_os = "linux")]
+    pub fn take() -> Option<Self> {
+        use nix::unistd::{sysconf, SysconfVar};
+
+        let ticks_per_sec = sysconf(SysconfVar::CLK_TCK).ok()?? as u64;
+        let page_size = sysconf(SysconfVar::PAGE_SIZE).ok()?? as u64;
+        let procs = std::fs::read_dir("/proc")
+            .ok()?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                entry.file_name().to_str()?.parse::<i32>().ok()?;
+                // the process may well have exited since we listed it
+                parse_stat(&std::fs::read_to_string(entry.path().join("stat")).ok()?)
+            })
+            .collect();
+        Some(Snapshot { procs, tick_ns: 1_000_000_000 / ticks_per_sec.max(1), page_size })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn take() -> Option<Self> {
+        None
+    }
+
+    /// The usage of the processes in the session led by `leader`.
+    pub fn usage(&self, leader: i32) -> ProcUsage {
+        let members = self.members(leader);
+        let mut usage = ProcUsage::default();
+        for proc in self.procs.iter().filter(|p| members.contains(&p.pid)) {
+            usage.count += 1;
+            usage.cpu_ns += proc.cpu_ticks * self.tick_ns;
+            usage.rss_bytes += proc.rss_pages * self.page_size;
+        }
+        usage
+    }
+
+    /// The pids of the processes in the session led by `leader`,
+    /// including the leader itself if it is still around.
+    pub fn members(&self, leader: i32) -> HashSet<i32> {
+        let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+        for proc in self.procs.iter() {
+            children.entry(proc.ppid).or_default().push(proc.pid);
+        }
+        let mut todo: Vec<i32> =
+            self.procs.iter().filter(|p| p.sid == leader).map(|p| p.pid).collect();
+        todo.push(leader);
+        let mut members = HashSet::new();
+        while let Some(pid) = todo.pop() {
+            if members.insert(pid) {
+                todo.extend(children.get(&pid).into_iter().flatten());
+            }
+        }
+        self.procs.iter().map(|p| p.pid).filter(|pid| members.contains(pid)).collect()
+    }
+}
+
+/// Parse a /proc/<pid>/stat file. See proc_pid_stat(5) for the fields.
+fn parse_stat(stat: &str) -> Option<Proc> {
+    let (pid, rest) = stat.split_once(" (")?;
+    // The command name may itself contain spaces and parens, but it
+    // is always followed by the last paren in the file.
+    let (_, rest) = rest.rsplit_once(") ")?;
+    // fields from the state (the third field) onwards
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+    Some(Proc {
+        pid: pid.parse().ok()?,
+        ppid: field(4)? as i32,
+        sid: field(6)? as i32,
+        // utime, stime, cutime and cstime
+        cpu_ticks: field(14)? + field(15)? + field(16)? + field(17)?,
+        rss_pages: field(24)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stat() {
+        let stat = "4242 (my (weird) cmd) S 4200 4242 4200 34817 4242 4194304 120 0 0 0 \
+                    7 3 2 1 20 0 1 0 1234 9000000 512 18446744073709551615 1 1 0 0 0 0";
+        assert_eq!(
+            parse_stat(stat),
+            Some(Proc { pid: 4242, ppid: 4200, sid: 4200, cpu_ticks: 13, rss_pages: 512 })
+        );
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn usage() {
+        let proc = |pid, ppid, sid| Proc { pid, ppid, sid, cpu_ticks: 1, rss_pages: 1 };
+        let snapshot = Snapshot {
+            procs: vec![
+                proc(1, 0, 1),
+                // the session's shell, a child and a grandchild
+                proc(10, 5, 10),
+                proc(11, 10, 10),
+                proc(12, 11, 11),
+                // a daemon that got reparented to init
+                proc(13, 1, 10),
+                proc(14, 13, 14),
+                // some other session
+                proc(20, 5, 20),
+                proc(21, 20, 20),
+            ],
+            tick_ns: 10,
+            page_size: 4096,
+        };
+        assert_eq!(snapshot.usage(10), ProcUsage { count: 5, cpu_ns: 50, rss_bytes: 5 * 4096 });
+        assert_eq!(snapshot.usage(20), ProcUsage { count: 2, cpu_ns: 20, rss_bytes: 2 * 4096 });
+        assert_eq!(snapshot.usage(99), ProcUsage::default());
+    }
+}
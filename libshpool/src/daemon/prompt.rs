@@ -12,10 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-// This file contains the logic for injecting the `prompt_annotation`
+// This file contains the logic for injecting the `prompt_prefix`
 // config option into a user's prompt for known shells.
+//
+// Rather than baking the prefix into the prompt once, the injected
+// hooks source a little script from the session's runtime dir before
+// drawing each prompt. The daemon rewrites that script on every attach,
+// so template values like the attach count stay up to date.
+//
+// Nushell and xonsh can't source a script picked at runtime, so their
+// hooks read the finished prefix out of a plain text file instead. The
+// `prompt_hooks` config option can swap in a custom snippet for any
+// shell, including ones we don't know about.
 
-use std::io::{Read, Write};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
 
 use anyhow::{anyhow, Context};
 use tracing::{debug, info, instrument, warn};
@@ -23,61 +38,185 @@ use tracing::{debug, info, instrument, warn};
 use crate::{
     consts::{SENTINEL_FLAG_VAR, STARTUP_SENTINEL},
     daemon::trie::{Trie, TrieCursor},
+    logging,
 };
 
+/// The script bash and zsh source to pick up the current prefix.
+const SH_PREFIX_FILE: &str = "prompt_prefix.sh";
+/// The script fish sources to pick up the current prefix.
+const FISH_PREFIX_FILE: &str = "prompt_prefix.fish";
+/// The prefix as plain text, for shells which read it rather than
+/// sourcing a script.
+const TEXT_PREFIX_FILE: &str = "prompt_prefix.txt";
+
 #[derive(Debug, Clone)]
 enum KnownShell {
     Bash,
     Zsh,
     Fish,
+    Nushell,
+    Xonsh,
+}
+
+impl KnownShell {
+    /// The name used to refer to the shell in the config.
+    fn name(&self) -> &'static str {
+        match self {
+            KnownShell::Bash => "bash",
+            KnownShell::Zsh => "zsh",
+            KnownShell::Fish => "fish",
+            KnownShell::Nushell => "nu",
+            KnownShell::Xonsh => "xonsh",
+        }
+    }
+
+    /// Work out which shell a process name belongs to, if any.
+    fn from_name(name: &str) -> Option<Self> {
+        if name.ends_with("bash") {
+            Some(KnownShell::Bash)
+        } else if name.ends_with("zsh") {
+            Some(KnownShell::Zsh)
+        } else if name.ends_with("fish") {
+            Some(KnownShell::Fish)
+        } else if name == "nu" {
+            Some(KnownShell::Nushell)
+        } else if name.ends_with("xonsh") {
+            Some(KnownShell::Xonsh)
+        } else {
+            None
+        }
+    }
 }
 
+/// The values that can be filled into a prompt prefix template.
+pub struct PrefixVars<'a> {
+    pub session_name: &'a str,
+    pub attach_count: usize,
+}
+
+/// Fill in the placeholders in a prompt prefix template. The result
+/// ends up inside a double quoted string in the shell, so the
+/// substituted values get escaped while the rest of the template is
+/// left alone, so that things like `$HOST` still get expanded by the
+/// shell.
+fn render(template: &str, vars: &PrefixVars) -> String {
+    fill(template, vars, |s| {
+        let mut quoted = String::with_capacity(s.len());
+        for c in s.chars() {
+            if matches!(c, '\\' | '"' | '$' | '`') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted
+    })
+}
+
+/// Fill in the placeholders in a prompt prefix template, passing the
+/// substituted values through `escape`.
+fn fill(template: &str, vars: &PrefixVars, escape: fn(&str) -> String) -> String {
+    let session_name = escape(vars.session_name);
+    template
+        .replace("$SHPOOL_SESSION_NAME", &session_name)
+        .replace("{session_name}", &session_name)
+        .replace("{attach_count}", &vars.attach_count.to_string())
+        .replace("{daemon_version}", &escape(shpool_protocol::VERSION))
+}
 
-/// Inject the given prefix into the given shell subprocess, using
-/// the shell path in `shell` to decide the right way to go about
-/// injecting the prefix.
+/// Write out the scripts the injected prompt hooks source to get the
+/// current prefix.
+pub fn write_prefix_scripts(
+    session_dir: &Path,
+    template: &str,
+    vars: &PrefixVars,
+) -> anyhow::Result<()> {
+    let prefix = render(template, vars);
+    fs::create_dir_all(session_dir).context("creating session dir")?;
+    for (file, script) in [
+        (SH_PREFIX_FILE, format!("SHPOOL__PREFIX=\"{prefix}\"\n")),
+        (FISH_PREFIX_FILE, format!("set -g SHPOOL__PREFIX \"{prefix}\"\n")),
+        (TEXT_PREFIX_FILE, fill(template, vars, |s| String::from(s))),
+    ] {
+        // write then rename so a prompt never sources half a script
+        let path = session_dir.join(file);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, script).context("writing prompt prefix script")?;
+        fs::rename(&tmp_path, &path).context("moving prompt prefix script into place")?;
+    }
+    Ok(())
+}
+
+/// Inject the prompt prefix hooks into the given shell subprocess,
+/// sniffing the shell to decide the right way to go about it. The
+/// hooks source the scripts written by `write_prefix_scripts` in
+/// `session_dir`.
 ///
-/// If the prefix is blank, this is a noop.
+/// Shells named in `skip_shells` are left alone, and shells with an
+/// entry in `hooks` get that snippet in place of the built in one.
 #[instrument(skip_all)]
 pub fn maybe_inject_prefix(
     pty_master: &mut shpool_pty::fork::Fork,
-    prompt_prefix: &str,
-    session_name: &str,
+    session_dir: &Path,
+    skip_shells: &[String],
+    hooks: &HashMap<String, String>,
 ) -> anyhow::Result<()> {
-    if prompt_prefix.is_empty() {
-        return Ok(());
-    }
-
     let shell_pid = pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
     // scan for the startup sentinel so we know it is safe to sniff the shell
     let mut pty_master = pty_master.is_parent().context("expected parent")?;
     wait_for_startup(&mut pty_master)?;
 
-    let shell_type = sniff_shell(shell_pid);
-    debug!("sniffed shell type: {:?}", shell_type);
+    let shell_name = sniff_shell(shell_pid);
+    debug!("sniffed shell: {:?}", shell_name);
+    let shell_name = shell_name.map(|name| match KnownShell::from_name(&name) {
+        Some(shell) => (String::from(shell.name()), Some(shell)),
+        None => (name, None),
+    });
 
-    // now actually inject the prompt
-    let prompt_prefix = prompt_prefix.replace("$SHPOOL_SESSION_NAME", session_name);
+    let sh_script =
+        shell_words::quote(&session_dir.join(SH_PREFIX_FILE).to_string_lossy()).into_owned();
+    let fish_script =
+        shell_words::quote(&session_dir.join(FISH_PREFIX_FILE).to_string_lossy()).into_owned();
+    // a JSON string is also a valid string literal in nushell and python
+    let text_file = serde_json::to_string(&session_dir.join(TEXT_PREFIX_FILE).to_string_lossy())
+        .context("quoting prefix file")?;
 
-    let mut script = match (prompt_prefix.as_str(), shell_type) {
-        (_, Ok(KnownShell::Bash)) => format!(
+    // now actually inject the prompt
+    let mut script = match shell_name {
+        Ok((name, _)) if skip_shells.contains(&name) => {
+            info!("prompt prefix disabled for {}", name);
+            String::new()
+        }
+        Ok((name, _)) if hooks.contains_key(&name) => {
+            info!("using custom prompt hook for {}", name);
+            custom_hook(&hooks[&name], session_dir)
+        }
+        Ok((name, None)) => {
+            warn!("unknown shell: {:?}", name);
+            String::new()
+        }
+        // PROMPT_COMMAND names a function that only this shell has, so
+        // keep it out of the environment of anything the shell runs, even
+        // if it came in exported.
+        Ok((_, Some(KnownShell::Bash))) => format!(
             "if [[ -z \"${{PROMPT_COMMAND+x}}\" ]]; then\n\
-               PS1=\"{prompt_prefix}${{PS1}}\"\n\
+               SHPOOL__OLD_PROMPT_COMMAND=()\n\
             else\n\
                SHPOOL__OLD_PROMPT_COMMAND=(\"${{PROMPT_COMMAND[@]}}\")\n\
-               SHPOOL__OLD_PS1=\"${{PS1}}\"\n\
-               function __shpool__prompt_command() {{\n\
-                  PS1=\"${{SHPOOL__OLD_PS1}}\"\n\
-                  for prompt_hook in \"${{SHPOOL__OLD_PROMPT_COMMAND[@]}}\"\n\
-                  do\n\
-                    eval \"${{prompt_hook}}\"\n\
-                  done\n\
-                  PS1=\"{prompt_prefix}${{PS1}}\"\n\
-               }}\n\
-               PROMPT_COMMAND=__shpool__prompt_command\n\
-            fi\n"
+            fi\n\
+            SHPOOL__OLD_PS1=\"${{PS1}}\"\n\
+            function __shpool__prompt_command() {{\n\
+               PS1=\"${{SHPOOL__OLD_PS1}}\"\n\
+               for prompt_hook in \"${{SHPOOL__OLD_PROMPT_COMMAND[@]}}\"\n\
+               do\n\
+                 eval \"${{prompt_hook}}\"\n\
+               done\n\
+               [[ -r {sh_script} ]] && . {sh_script}\n\
+               PS1=\"${{SHPOOL__PREFIX}}${{PS1}}\"\n\
+            }}\n\
+            PROMPT_COMMAND=__shpool__prompt_command\n\
+            export -n PROMPT_COMMAND\n"
         ),
-        (_, Ok(KnownShell::Zsh)) => format!(
+        Ok((_, Some(KnownShell::Zsh))) => format!(
             "typeset -a precmd_functions\n\
             SHPOOL__OLD_PROMPT=\"${{PROMPT}}\"\n\
             function __shpool__reset_rprompt() {{\n\
@@ -85,15 +224,36 @@ pub fn maybe_inject_prefix(
             }}\n\
             precmd_functions[1,0]=(__shpool__reset_rprompt)\n\
             function __shpool__prompt_command() {{\n\
-               PROMPT=\"{prompt_prefix}${{PROMPT}}\"\n\
+               [[ -r {sh_script} ]] && . {sh_script}\n\
+               PROMPT=\"${{SHPOOL__PREFIX}}${{PROMPT}}\"\n\
             }}\n\
             precmd_functions+=(__shpool__prompt_command)\n"
         ),
-        (_, Ok(KnownShell::Fish)) => format!(
+        Ok((_, Some(KnownShell::Fish))) => format!(
             "functions --copy fish_prompt shpool__old_prompt\n\
-            function fish_prompt; echo -n \"{prompt_prefix}\"; shpool__old_prompt; end\n"
+            function fish_prompt; test -r {fish_script}; and source {fish_script}; \
+            echo -n \"$SHPOOL__PREFIX\"; shpool__old_prompt; end\n"
+        ),
+        // every statement goes on its own line, since both shells run
+        // each line as soon as it is complete
+        Ok((_, Some(KnownShell::Nushell))) => format!(
+            "$env.SHPOOL__OLD_PROMPT_COMMAND = ($env.PROMPT_COMMAND? | default '')\n\
+            $env.PROMPT_COMMAND = {{|| \
+            let old = $env.SHPOOL__OLD_PROMPT_COMMAND; \
+            let rest = if ($old | describe | str starts-with closure) {{ do $old }} else {{ $old }}; \
+            let prefix = try {{ open --raw {text_file} }} catch {{ '' }}; \
+            $\"($prefix)($rest)\" }}\n"
         ),
-        (_, Err(e)) => {
+        // xonsh runs the prompt through its formatter, so braces from
+        // the prefix need doubling to come out as they are
+        Ok((_, Some(KnownShell::Xonsh))) => format!(
+            "__shpool__old_prompt = $PROMPT\n\
+            __shpool__prefix = lambda p=__import__('pathlib').Path({text_file}): \
+            p.read_text().replace('{{', '{{{{').replace('}}', '}}}}') if p.is_file() else ''\n\
+            $PROMPT = lambda: __shpool__prefix() + \
+            (__shpool__old_prompt() if callable(__shpool__old_prompt) else __shpool__old_prompt)\n"
+        ),
+        Err(e) => {
             warn!("could not sniff shell: {}", e);
 
             // not the end of the world, we will just not inject a prompt prefix
@@ -106,13 +266,7 @@ pub fn maybe_inject_prefix(
     // this rather than `echo $PROMPT_SENTINEL` because different
     // shells have subtly different echo behavior which makes it
     // hard to make the scanner work right.
-    // Use current executable path instead of /proc/{pid}/exe for cross-platform compatibility
-    let current_exe = std::env::current_exe()
-        .context("failed to get current executable path")?
-        .to_string_lossy()
-        .to_string();
-    let sentinel_cmd =
-        format!("\n {SENTINEL_FLAG_VAR}=prompt {current_exe} daemon\n");
+    let sentinel_cmd = sentinel_cmd("prompt")?;
     script.push_str(sentinel_cmd.as_str());
 
     debug!("injecting prefix script '{}'", script);
@@ -124,13 +278,7 @@ pub fn maybe_inject_prefix(
 #[instrument(skip_all)]
 fn wait_for_startup(pty_master: &mut shpool_pty::fork::Master) -> anyhow::Result<()> {
     let mut startup_sentinel_scanner = SentinelScanner::new(STARTUP_SENTINEL);
-    // Use current executable path instead of /proc/{pid}/exe for cross-platform compatibility
-    let current_exe = std::env::current_exe()
-        .context("failed to get current executable path")?
-        .to_string_lossy()
-        .to_string();
-    let startup_sentinel_cmd =
-        format!("\n {SENTINEL_FLAG_VAR}=startup {current_exe} daemon\n");
+    let startup_sentinel_cmd = sentinel_cmd("startup")?;
 
     pty_master
         .write_all(startup_sentinel_cmd.as_bytes())
@@ -143,7 +291,7 @@ fn wait_for_startup(pty_master: &mut shpool_pty::fork::Master) -> anyhow::Result
             continue;
         }
         let buf = &buf[..len];
-        debug!("buf='{}'", String::from_utf8_lossy(buf));
+        debug!("buf='{}'", logging::payload(buf));
         for byte in buf.iter() {
             if startup_sentinel_scanner.transition(*byte) {
                 // This might drop trailing data from the chunk we just read, but
@@ -156,23 +304,47 @@ fn wait_for_startup(pty_master: &mut shpool_pty::fork::Master) -> anyhow::Result
     }
 }
 
-/// Determine the shell process running under the given pid by examining
-/// `/proc/<pid>/exe`.
+/// The command line which gets `shpool daemon` to print the given
+/// sentinel. It goes through `env` rather than the `VAR=value cmd`
+/// syntax, since it has to run before we know what the shell is and
+/// not every shell understands that syntax. The leading space keeps
+/// it out of the history of shells which ignore lines starting with one.
+fn sentinel_cmd(sentinel: &str) -> anyhow::Result<String> {
+    // Use current executable path instead of /proc/{pid}/exe for cross-platform compatibility
+    let current_exe = std::env::current_exe()
+        .context("failed to get current executable path")?
+        .to_string_lossy()
+        .to_string();
+    Ok(format!("\n /usr/bin/env {SENTINEL_FLAG_VAR}={sentinel} {current_exe} daemon\n"))
+}
+
+/// Fill in the placeholders in a custom `prompt_hooks` snippet.
+fn custom_hook(snippet: &str, session_dir: &Path) -> String {
+    let mut script = snippet
+        .replace("{session_dir}", &session_dir.to_string_lossy())
+        .replace("{prefix_file}", &session_dir.join(TEXT_PREFIX_FILE).to_string_lossy());
+    if !script.ends_with('\n') {
+        script.push('\n');
+    }
+    script
+}
+
+/// Determine the name of the shell process running under the given pid.
+/// Shells like xonsh which run under an interpreter get found by their
+/// command line.
 #[instrument(skip_all)]
-fn sniff_shell(pid: libc::pid_t) -> anyhow::Result<KnownShell> {
+fn sniff_shell(pid: libc::pid_t) -> anyhow::Result<String> {
     let shell_proc_name =
         libproc::proc_pid::name(pid).map_err(|e| anyhow!("determining subproc name: {:?}", e))?;
     info!("shell_proc_name: {}", shell_proc_name);
 
-    if shell_proc_name.ends_with("bash") {
-        Ok(KnownShell::Bash)
-    } else if shell_proc_name.ends_with("zsh") {
-        Ok(KnownShell::Zsh)
-    } else if shell_proc_name.ends_with("fish") {
-        Ok(KnownShell::Fish)
-    } else {
-        Err(anyhow!("unknown shell: {:?}", shell_proc_name))
+    if shell_proc_name.starts_with("python")
+        && let Ok(cmdline) = fs::read(format!("/proc/{pid}/cmdline"))
+        && cmdline.split(|b| *b == 0).any(|arg| arg.ends_with(b"xonsh"))
+    {
+        return Ok(String::from("xonsh"));
     }
+    Ok(shell_proc_name)
 }
 
 /// A trie for scanning through shell output to look for the sentinel.
@@ -211,3 +383,64 @@ impl SentinelScanner {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let vars = PrefixVars { session_name: "main", attach_count: 3 };
+        assert_eq!(render("[{session_name}:{attach_count}] ", &vars), "[main:3] ");
+        assert_eq!(render("$SHPOOL_SESSION_NAME@$HOST ", &vars), "main@$HOST ");
+        assert_eq!(render("{daemon_version}", &vars), shpool_protocol::VERSION);
+
+        // session names get escaped, but the template itself does not
+        let vars = PrefixVars { session_name: "a\"$(b)`", attach_count: 1 };
+        assert_eq!(render("$USER {session_name}", &vars), "$USER a\\\"\\$(b)\\`");
+    }
+
+    #[test]
+    fn prefix_scripts() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let session_dir = tmp_dir.path().join("sessions").join("main");
+        let vars = PrefixVars { session_name: "main", attach_count: 2 };
+        write_prefix_scripts(&session_dir, "{session_name}#{attach_count} ", &vars)?;
+
+        assert_eq!(
+            fs::read_to_string(session_dir.join(SH_PREFIX_FILE))?,
+            "SHPOOL__PREFIX=\"main#2 \"\n"
+        );
+        assert_eq!(
+            fs::read_to_string(session_dir.join(FISH_PREFIX_FILE))?,
+            "set -g SHPOOL__PREFIX \"main#2 \"\n"
+        );
+        assert_eq!(fs::read_to_string(session_dir.join(TEXT_PREFIX_FILE))?, "main#2 ");
+
+        // the plain text version is not escaped
+        let vars = PrefixVars { session_name: "a\"b", attach_count: 1 };
+        write_prefix_scripts(&session_dir, "$HOST {session_name}", &vars)?;
+        assert_eq!(fs::read_to_string(session_dir.join(TEXT_PREFIX_FILE))?, "$HOST a\"b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn shell_names() {
+        assert!(matches!(KnownShell::from_name("bash"), Some(KnownShell::Bash)));
+        assert!(matches!(KnownShell::from_name("-zsh"), Some(KnownShell::Zsh)));
+        assert!(matches!(KnownShell::from_name("nu"), Some(KnownShell::Nushell)));
+        assert!(matches!(KnownShell::from_name("xonsh"), Some(KnownShell::Xonsh)));
+        assert!(KnownShell::from_name("menu").is_none());
+        assert!(KnownShell::from_name("elvish").is_none());
+    }
+
+    #[test]
+    fn custom_hooks() {
+        let dir = Path::new("/run/shpool/sessions/main");
+        assert_eq!(
+            custom_hook("set-prefix (slurp {prefix_file})", dir),
+            "set-prefix (slurp /run/shpool/sessions/main/prompt_prefix.txt)\n"
+        );
+        assert_eq!(custom_hook("cd {session_dir}\n", dir), "cd /run/shpool/sessions/main\n");
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Refreshing environment variables on reattach.
+
+  A shell's environment is baked in when it starts, so variables like
+  DISPLAY that point at something belonging to the ssh connection go
+  stale once the user reconnects. SSH_AUTH_SOCK gets handled with a
+  symlink in the session dir that is re-pointed on every attach, but
+  that trick only works for paths. For everything else listed in the
+  `refresh_env` config option, the daemon writes a script to the
+  session dir on every attach which the shell can source (say from a
+  prompt hook) to pick up the values from the latest client. The
+  variables pointing at the graphical session are always included,
+  since reattaching from a different desktop login is the most
+  common way for them to go stale, as are TERM, COLORTERM and the
+  terminal size.
+
+  Each script starts with a comment holding a generation number that
+  changes on every attach, which lets the prompt hooks printed by
+  `shpool shell-hook` skip sourcing a script they have already seen.
+  Nushell and xonsh can't easily source a script picked at runtime, so
+  their hooks read the same values out of a JSON file instead, which
+  carries the generation number as a field.
+*/
+
+use crate::config;
+
+/// The name of the POSIX shell script within the session dir.
+pub const SCRIPT_NAME: &str = "refresh.env";
+
+/// The name of the fish script within the session dir.
+pub const FISH_SCRIPT_NAME: &str = "refresh.fish";
+
+/// The name of the JSON file the nushell and xonsh hooks read.
+pub const JSON_NAME: &str = "refresh.json";
+
+/// The X11 and Wayland variables, which always get refreshed so that
+/// things like `xdg-open` and clipboard tools keep working.
+pub const DISPLAY_VARS: [&str; 3] = ["DISPLAY", "WAYLAND_DISPLAY", "XAUTHORITY"];
+
+/// Shell variables for the terminal size. Shells keep these up to date
+/// themselves without exporting them, so the scripts follow suit.
+pub const SIZE_VARS: [&str; 2] = ["COLUMNS", "LINES"];
+
+/// The variables that go in the scripts for the given session: the
+/// display variables, TERM and COLORTERM unless the config pins them,
+/// SSH_AUTH_SOCK if it is not being handled with a symlink, the
+/// terminal size and then the ones from the `refresh_env` config
+/// option.
+pub fn vars(config: &config::Config, session: &str) -> Vec<String> {
+    let mut vars: Vec<String> = DISPLAY_VARS.iter().map(|v| String::from(*v)).collect();
+    let session_env = config.session(session).and_then(|s| s.env.as_ref());
+    for var in ["TERM", "COLORTERM"] {
+        let pinned = config.env.iter().chain(session_env).any(|env| env.contains_key(var));
+        if !pinned {
+            vars.push(String::from(var));
+        }
+    }
+    if config.nosymlink_ssh_auth_sock.unwrap_or(false) {
+        vars.push(String::from("SSH_AUTH_SOCK"));
+    }
+    vars.extend(SIZE_VARS.iter().map(|v| String::from(*v)));
+    for var in config.refresh_env.iter().flatten() {
+        if !vars.contains(var) {
+            vars.push(var.clone());
+        }
+    }
+    vars
+}
+
+/// The first line of both scripts.
+pub fn header(generation: i64) -> String {
+    format!("# shpool refresh {generation}\n")
+}
+
+/// Build a POSIX shell script which exports the given variables with
+/// the values they have in `local_env`, unsetting any that the client
+/// does not have set. The size variables get set without being
+/// exported.
+pub fn script(vars: &[String], local_env: &[(String, String)]) -> String {
+    let mut out = String::new();
+    for var in vars.iter() {
+        match local_env.iter().find(|(k, _)| k == var) {
+            Some((_, val)) if SIZE_VARS.contains(&var.as_str()) => {
+                out.push_str(&format!("{}={}\n", var, shell_words::quote(val)));
+            }
+            Some((_, val)) => {
+                out.push_str(&format!("export {}={}\n", var, shell_words::quote(val)));
+            }
+            None => out.push_str(&format!("unset {var}\n")),
+        }
+    }
+    out
+}
+
+/// Like `script`, but for fish. Fish tracks the terminal size on its
+/// own and does not let you set it, so the size variables are left
+/// out.
+pub fn fish_script(vars: &[String], local_env: &[(String, String)]) -> String {
+    let mut out = String::new();
+    for var in vars.iter().filter(|v| !SIZE_VARS.contains(&v.as_str())) {
+        match local_env.iter().find(|(k, _)| k == var) {
+            Some((_, val)) => {
+                out.push_str(&format!("set -gx {} {}\n", var, shell_words::quote(val)));
+            }
+            None => out.push_str(&format!("set -e {var}\n")),
+        }
+    }
+    out
+}
+
+/// Like `script`, but as JSON for the hooks of shells which can't
+/// source a script chosen at runtime. The values to set go in `set` and
+/// the variables to unset in `unset`. Like fish, neither nushell nor
+/// xonsh need the size variables.
+pub fn json(generation: i64, vars: &[String], local_env: &[(String, String)]) -> String {
+    let mut set = serde_json::Map::new();
+    let mut unset = vec![];
+    for var in vars.iter().filter(|v| !SIZE_VARS.contains(&v.as_str())) {
+        match local_env.iter().find(|(k, _)| k == var) {
+            Some((_, val)) => {
+                set.insert(var.clone(), serde_json::Value::from(val.as_str()));
+            }
+            None => unset.push(var.as_str()),
+        }
+    }
+    let doc = serde_json::json!({ "generation": generation, "set": set, "unset": unset });
+    format!("{doc}\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exports_and_unsets() {
+        let local_env = vec![
+            (String::from("DISPLAY"), String::from("localhost:10.0")),
+            (String::from("WEIRD"), String::from("it's got spaces")),
+        ];
+        let vars = vec![String::from("DISPLAY"), String::from("WEIRD"), String::from("KRB5CCNAME")];
+        assert_eq!(
+            script(&vars, &local_env),
+            "export DISPLAY=localhost:10.0\nexport WEIRD='it'\\''s got spaces'\nunset KRB5CCNAME\n"
+        );
+    }
+
+    #[test]
+    fn always_refreshes_display() {
+        let config = config::Config {
+            refresh_env: Some(vec![String::from("KRB5CCNAME"), String::from("DISPLAY")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            vars(&config, "main"),
+            vec![
+                "DISPLAY",
+                "WAYLAND_DISPLAY",
+                "XAUTHORITY",
+                "TERM",
+                "COLORTERM",
+                "COLUMNS",
+                "LINES",
+                "KRB5CCNAME"
+            ]
+        );
+    }
+
+    #[test]
+    fn pinned_term_and_unlinked_auth_sock() {
+        let config = config::Config {
+            env: Some([(String::from("TERM"), String::new())].into_iter().collect()),
+            nosymlink_ssh_auth_sock: Some(true),
+            ..Default::default()
+        };
+        let vars = vars(&config, "main");
+        assert!(!vars.contains(&String::from("TERM")));
+        assert!(vars.contains(&String::from("SSH_AUTH_SOCK")));
+    }
+
+    #[test]
+    fn fish() {
+        let local_env = vec![
+            (String::from("DISPLAY"), String::from(":1")),
+            (String::from("COLUMNS"), String::from("80")),
+        ];
+        let vars = vec![String::from("DISPLAY"), String::from("COLUMNS"), String::from("TERM")];
+        assert_eq!(fish_script(&vars, &local_env), "set -gx DISPLAY :1\nset -e TERM\n");
+    }
+
+    #[test]
+    fn json_doc() {
+        let local_env = vec![
+            (String::from("DISPLAY"), String::from(":1")),
+            (String::from("COLUMNS"), String::from("80")),
+        ];
+        let vars = vec![String::from("DISPLAY"), String::from("COLUMNS"), String::from("TERM")];
+        assert_eq!(
+            json(7, &vars, &local_env),
+            "{\"generation\":7,\"set\":{\"DISPLAY\":\":1\"},\"unset\":[\"TERM\"]}\n"
+        );
+    }
+}
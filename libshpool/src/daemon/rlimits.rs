@@ -0,0 +1,98 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resource limits for the shells of new sessions. They get set in the
+//! forked child just before the shell is exec'd, so they are inherited
+//! by everything started from the session, but each process gets its
+//! own allowance rather than the session sharing one.
+
+use anyhow::Context;
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+use crate::{config, duration, size};
+
+/// Limits resolved from the config ahead of the fork, since parsing
+/// them allocates.
+#[derive(Debug, Clone, Default)]
+pub struct Limits(Vec<(Resource, u64)>);
+
+impl Limits {
+    pub fn new(config: &config::Rlimits) -> anyhow::Result<Self> {
+        let mut limits = vec![];
+        if let Some(memory) = &config.memory {
+            let bytes = size::parse(memory).context("parsing memory")?;
+            limits.push((Resource::RLIMIT_AS, bytes as u64));
+        }
+        if let Some(cpu_time) = &config.cpu_time {
+            let secs = duration::parse(cpu_time).context("parsing cpu_time")?.as_secs();
+            limits.push((Resource::RLIMIT_CPU, secs));
+        }
+        if let Some(open_files) = config.open_files {
+            limits.push((Resource::RLIMIT_NOFILE, open_files));
+        }
+        if let Some(processes) = config.processes {
+            limits.push((Resource::RLIMIT_NPROC, processes));
+        }
+        Ok(Limits(limits))
+    }
+
+    /// Apply the limits to the current process. Both the soft and the
+    /// hard limit get lowered so that a runaway program can't just raise
+    /// them again. Limits above the existing hard limit are clamped to
+    /// it, since we are not allowed to raise it. This gets called after
+    /// forking, so it must not allocate.
+    pub fn apply(&self) -> nix::Result<()> {
+        for (resource, limit) in self.0.iter() {
+            let (_, hard) = getrlimit(*resource)?;
+            let limit = (*limit).min(hard);
+            setrlimit(*resource, limit, limit)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that the limits in the config parse.
+pub fn check(config: &config::Rlimits) -> anyhow::Result<()> {
+    Limits::new(config).map(drop)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new() -> anyhow::Result<()> {
+        let limits = Limits::new(&config::Rlimits {
+            memory: Some(String::from("2GiB")),
+            cpu_time: Some(String::from("1h")),
+            open_files: Some(256),
+            processes: None,
+        })?;
+        assert_eq!(
+            limits.0,
+            vec![
+                (Resource::RLIMIT_AS, 2 * 1024 * 1024 * 1024),
+                (Resource::RLIMIT_CPU, 3600),
+                (Resource::RLIMIT_NOFILE, 256),
+            ]
+        );
+
+        assert!(Limits::new(&config::Rlimits {
+            memory: Some(String::from("lots")),
+            ..Default::default()
+        })
+        .is_err());
+        Ok(())
+    }
+}
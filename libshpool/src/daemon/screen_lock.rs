@@ -0,0 +1,204 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Locking the screen of an attached client that has gone without
+  typing anything for a while, set up with the `screen_lock_after_idle`
+  config option.
+
+  This works a lot like scroll mode. The client->shell thread notices
+  that the client has gone idle, stops passing its input on to the
+  shell, and sends a Cmd to the shell->client thread, which blanks the
+  client's screen and holds back live output until the screen gets
+  unlocked. Keypresses then go to an Unlocker, which wants a keypress
+  and then a `y` to confirm, or the session's passphrase if it has one
+  from `shpool lock`. Unlocking redraws the screen from the spool, just
+  like a reattach does.
+
+  Whether the screen is locked is part of the session rather than the
+  connection, so detaching and attaching again lands on the locked
+  screen.
+*/
+
+use std::{io::Write as _, mem};
+
+use shpool_protocol::TtySize;
+
+/// Clears the locked screen away before the session gets redrawn.
+pub const CLEAR: &[u8] = b"\x1b[H\x1b[2J";
+
+/// What the locked screen asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Prompt {
+    /// Any keypress, to get started on unlocking.
+    #[default]
+    Locked,
+    /// A `y` to confirm unlocking.
+    Confirm,
+    /// The session's passphrase, followed by Enter.
+    Passphrase { wrong: bool },
+}
+
+/// Commands for the shell->client thread, from the client->shell
+/// thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmd {
+    /// Blank the screen and hold back live output.
+    Lock,
+    /// Show a different prompt on the locked screen.
+    Show(Prompt),
+    /// Redraw the screen and go back to streaming live output.
+    Unlock,
+}
+
+/// Paint the locked screen.
+pub fn render(prompt: Prompt, size: &TtySize) -> Vec<u8> {
+    let (headline, hint) = match prompt {
+        Prompt::Locked => ("This session is locked.", "Press any key to unlock it."),
+        Prompt::Confirm => {
+            ("This session is locked.", "Press y to unlock it, or any other key to keep it locked.")
+        }
+        Prompt::Passphrase { wrong: false } => {
+            ("This session is locked.", "Type its passphrase and press Enter to unlock it.")
+        }
+        Prompt::Passphrase { wrong: true } => {
+            ("Wrong passphrase.", "Type its passphrase and press Enter to unlock it.")
+        }
+    };
+
+    let mut out = b"\x1b[0m".to_vec();
+    out.extend(CLEAR);
+    let top = usize::from(size.rows / 2).max(1);
+    for (i, line) in [headline, hint].into_iter().enumerate() {
+        let col = usize::from(size.cols).saturating_sub(line.len()) / 2 + 1;
+        let _ = write!(out, "\x1b[{};{}H{}", top + i, col, line);
+    }
+    out
+}
+
+/// What to do about some keypresses at the locked screen.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Show(Prompt),
+    Unlock,
+}
+
+/// Keeps track of what a client has typed at the locked screen.
+#[derive(Default)]
+pub struct Unlocker {
+    prompt: Prompt,
+    typed: Vec<u8>,
+}
+
+impl Unlocker {
+    /// Feed in keypresses. `verify` checks a passphrase, and should
+    /// be None if the session doesn't have one.
+    pub fn feed<F>(&mut self, keys: &[u8], verify: Option<F>) -> Option<Outcome>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut outcome = None;
+        for &key in keys {
+            match self.prompt {
+                Prompt::Locked => {
+                    self.prompt = match verify {
+                        Some(_) => Prompt::Passphrase { wrong: false },
+                        None => Prompt::Confirm,
+                    };
+                    self.typed.clear();
+                    outcome = Some(Outcome::Show(self.prompt));
+                }
+                Prompt::Confirm if key == b'y' || key == b'Y' => return Some(Outcome::Unlock),
+                Prompt::Confirm => {
+                    self.prompt = Prompt::Locked;
+                    outcome = Some(Outcome::Show(self.prompt));
+                }
+                Prompt::Passphrase { .. } => match key {
+                    b'\r' | b'\n' => {
+                        let typed = mem::take(&mut self.typed);
+                        let passphrase = String::from_utf8_lossy(&typed);
+                        if verify.as_ref().is_some_and(|verify| verify(&passphrase)) {
+                            return Some(Outcome::Unlock);
+                        }
+                        self.prompt = Prompt::Passphrase { wrong: true };
+                        outcome = Some(Outcome::Show(self.prompt));
+                    }
+                    // backspace
+                    0x7f | 0x08 => {
+                        self.typed.pop();
+                    }
+                    // ^U
+                    0x15 => self.typed.clear(),
+                    key if key >= 0x20 => self.typed.push(key),
+                    _ => {}
+                },
+            }
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NO_PASSPHRASE: Option<fn(&str) -> bool> = None;
+
+    #[test]
+    fn confirm() {
+        let mut unlocker = Unlocker::default();
+        assert_eq!(unlocker.feed(b"y", NO_PASSPHRASE), Some(Outcome::Show(Prompt::Confirm)));
+        assert_eq!(unlocker.feed(b"n", NO_PASSPHRASE), Some(Outcome::Show(Prompt::Locked)));
+        assert_eq!(unlocker.feed(b" ", NO_PASSPHRASE), Some(Outcome::Show(Prompt::Confirm)));
+        assert_eq!(unlocker.feed(b"y", NO_PASSPHRASE), Some(Outcome::Unlock));
+
+        // a keypress and the confirmation can come in together
+        let mut unlocker = Unlocker::default();
+        assert_eq!(unlocker.feed(b"\ry", NO_PASSPHRASE), Some(Outcome::Unlock));
+    }
+
+    #[test]
+    fn passphrase() {
+        let verify = Some(|typed: &str| typed == "hunter2");
+        let mut unlocker = Unlocker::default();
+        assert_eq!(
+            unlocker.feed(b"\r", verify),
+            Some(Outcome::Show(Prompt::Passphrase { wrong: false }))
+        );
+        assert_eq!(unlocker.feed(b"hunter", verify), None);
+        assert_eq!(
+            unlocker.feed(b"3\r", verify),
+            Some(Outcome::Show(Prompt::Passphrase { wrong: true }))
+        );
+        // y is just part of the passphrase
+        assert_eq!(unlocker.feed(b"y\x7fhunter3\x7f2", verify), None);
+        assert_eq!(unlocker.feed(b"\r", verify), Some(Outcome::Unlock));
+
+        let mut unlocker = Unlocker::default();
+        assert_eq!(unlocker.feed(b" junk\x15hunter2\r", verify), Some(Outcome::Unlock));
+    }
+
+    #[test]
+    fn renders_centered() {
+        let size = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+        let out = String::from_utf8(render(Prompt::Locked, &size)).unwrap();
+        assert!(out.contains("\x1b[H\x1b[2J"));
+        assert!(out.contains("\x1b[12;29HThis session is locked."));
+        assert!(out.contains("\x1b[13;27HPress any key to unlock it."));
+
+        // too narrow to center
+        let size = TtySize { rows: 1, cols: 10, xpixel: 0, ypixel: 0 };
+        let out = String::from_utf8(render(Prompt::Locked, &size)).unwrap();
+        assert!(out.contains("\x1b[1;1HThis session is locked."));
+    }
+}
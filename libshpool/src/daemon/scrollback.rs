@@ -0,0 +1,464 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Scrollback and scroll mode.
+
+  The shell->client thread feeds all shell output into a Scrollback,
+  which strips out escape sequences and keeps a ring of the most
+  recent lines of plain text. When the user fires the `scroll`
+  keybinding, the client->shell thread stops forwarding input to the
+  shell and instead translates keypresses into ScrollCmds, which the
+  shell->client thread uses to drive a ScrollMode. While in scroll
+  mode, the client gets switched to the alternate screen and shown a
+  page of the scrollback, and live output is held back until the user
+  quits scroll mode, at which point we flip back to the main screen
+  and flush the held output.
+
+  This is a lot simpler than tmux's copy mode. There is no selection
+  or search, you just use your terminal's native selection to copy
+  whatever is on screen.
+*/
+
+use std::{collections::VecDeque, io::Write as _};
+
+use shpool_protocol::TtySize;
+
+/// How many lines of scrollback to keep if the user does not say.
+pub const DEFAULT_LINES: usize = 1000;
+
+// If the shell prints more than this while the user is in scroll mode,
+// we kick them out of scroll mode rather than holding an unbounded
+// amount of output.
+const MAX_HELD_BYTES: usize = 1024 * 1024;
+
+const TAB_WIDTH: usize = 8;
+
+/// Commands that drive scroll mode, decoded from the user's keypresses.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ScrollCmd {
+    Enter,
+    /// Enter scroll mode looking at the given text rather than the
+    /// session's scrollback.
+    Show {
+        title: String,
+        text: String,
+    },
+    Exit,
+    Up(usize),
+    Down(usize),
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ParseState {
+    Ground,
+    Esc,
+    Csi,
+    Osc,
+    OscEsc,
+}
+
+/// A ring of the most recent lines of shell output, with all escape
+/// sequences stripped out.
+pub struct Scrollback {
+    lines: VecDeque<String>,
+    max_lines: usize,
+    /// The line currently being written.
+    line: Vec<u8>,
+    state: ParseState,
+    /// We have seen a carriage return, but don't yet know if it
+    /// is part of a line ending or the start of an overwrite.
+    pending_cr: bool,
+}
+
+impl Scrollback {
+    pub fn new(max_lines: usize) -> Self {
+        Scrollback {
+            lines: VecDeque::new(),
+            max_lines,
+            line: vec![],
+            state: ParseState::Ground,
+            pending_cr: false,
+        }
+    }
+
+    /// Process bytes from the pty master.
+    pub fn process(&mut self, bytes: &[u8]) {
+        if self.max_lines == 0 {
+            return;
+        }
+
+        for &byte in bytes {
+            match self.state {
+                ParseState::Ground => self.ground(byte),
+                ParseState::Esc => {
+                    self.state = match byte {
+                        b'[' => ParseState::Csi,
+                        b']' => ParseState::Osc,
+                        _ => ParseState::Ground,
+                    }
+                }
+                ParseState::Csi => {
+                    // a final byte ends the sequence
+                    if (0x40..=0x7e).contains(&byte) {
+                        self.state = ParseState::Ground;
+                    }
+                }
+                ParseState::Osc => match byte {
+                    0x07 => self.state = ParseState::Ground,
+                    0x1b => self.state = ParseState::OscEsc,
+                    _ => {}
+                },
+                ParseState::OscEsc => {
+                    self.state = if byte == b'\\' { ParseState::Ground } else { ParseState::Osc }
+                }
+            }
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if self.pending_cr && byte != b'\n' {
+            // A bare carriage return means the program is about to
+            // overwrite the current line (think progress bars), so only
+            // keep the final version.
+            self.line.clear();
+        }
+        self.pending_cr = false;
+
+        match byte {
+            0x1b => self.state = ParseState::Esc,
+            b'\r' => self.pending_cr = true,
+            b'\n' => self.commit_line(),
+            b'\t' => {
+                let width = TAB_WIDTH - (self.line.len() % TAB_WIDTH);
+                self.line.extend(std::iter::repeat_n(b' ', width));
+            }
+            0x08 => {
+                // pop a whole utf8 char
+                while let Some(b) = self.line.pop() {
+                    if b & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            }
+            b if b < 0x20 || b == 0x7f => {}
+            b => self.line.push(b),
+        }
+    }
+
+    fn commit_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        self.lines.push_back(line);
+        while self.lines.len() > self.max_lines {
+            self.lines.pop_front();
+        }
+    }
+
+    /// The number of lines available, including the one in progress.
+    pub fn len(&self) -> usize {
+        self.lines.len() + if self.line.is_empty() { 0 } else { 1 }
+    }
+
+    /// All the lines we have, oldest first, each terminated by a newline.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for i in 0..self.len() {
+            if let Some(line) = self.get(i) {
+                text.push_str(&line);
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Take all the finished lines, oldest first, leaving the one in
+    /// progress.
+    pub fn drain(&mut self) -> impl Iterator<Item = String> + '_ {
+        self.lines.drain(..)
+    }
+
+    /// Get a line by index, oldest first.
+    fn get(&self, i: usize) -> Option<String> {
+        if i < self.lines.len() {
+            self.lines.get(i).cloned()
+        } else if i == self.lines.len() && !self.line.is_empty() {
+            Some(String::from_utf8_lossy(&self.line).into_owned())
+        } else {
+            None
+        }
+    }
+}
+
+/// The state of a client that is scrolled back through the scrollback.
+pub struct ScrollMode {
+    /// How many lines up from the bottom the view is.
+    offset: usize,
+    /// Live output which the client has not seen yet.
+    held: Vec<u8>,
+    /// A title and some text to look at instead of the scrollback.
+    page: Option<(String, Scrollback)>,
+}
+
+impl ScrollMode {
+    /// Enter scroll mode, returning the bytes to send to the client.
+    pub fn enter(scrollback: &Scrollback, size: &TtySize) -> (Self, Vec<u8>) {
+        let mode = ScrollMode { offset: 0, held: vec![], page: None };
+        let mut out = b"\x1b[?1049h".to_vec();
+        out.extend(mode.render(scrollback, size));
+        (mode, out)
+    }
+
+    /// Enter scroll mode looking at the given text, returning the bytes
+    /// to send to the client.
+    pub fn show(title: String, text: &str, size: &TtySize) -> (Self, Vec<u8>) {
+        let mut page = Scrollback::new(usize::MAX);
+        page.process(text.as_bytes());
+        let mode = ScrollMode { offset: 0, held: vec![], page: Some((title, page)) };
+        let mut out = b"\x1b[?1049h".to_vec();
+        out.extend(mode.render(&Scrollback::new(0), size));
+        (mode, out)
+    }
+
+    /// The lines being looked at, along with what to call them.
+    fn source<'a>(&'a self, scrollback: &'a Scrollback) -> (&'a str, &'a Scrollback) {
+        match &self.page {
+            Some((title, page)) => (title, page),
+            None => ("scroll", scrollback),
+        }
+    }
+
+    /// Move the view, returning the bytes needed to repaint it.
+    pub fn handle(&mut self, cmd: ScrollCmd, scrollback: &Scrollback, size: &TtySize) -> Vec<u8> {
+        let page = body_rows(size);
+        let max_offset = self.source(scrollback).1.len().saturating_sub(page);
+        self.offset = match cmd {
+            ScrollCmd::Up(n) => self.offset.saturating_add(n),
+            ScrollCmd::Down(n) => self.offset.saturating_sub(n),
+            ScrollCmd::PageUp => self.offset.saturating_add(page),
+            ScrollCmd::PageDown => self.offset.saturating_sub(page),
+            ScrollCmd::Top => max_offset,
+            ScrollCmd::Bottom | ScrollCmd::Enter | ScrollCmd::Show { .. } | ScrollCmd::Exit => 0,
+        }
+        .min(max_offset);
+        self.render(scrollback, size)
+    }
+
+    /// Paint the current page of scrollback along with a status line.
+    pub fn render(&self, scrollback: &Scrollback, size: &TtySize) -> Vec<u8> {
+        let (title, scrollback) = self.source(scrollback);
+        let body = body_rows(size);
+        let cols = size.cols as usize;
+        let total = scrollback.len();
+        let end = total.saturating_sub(self.offset.min(total.saturating_sub(body)));
+        let start = end.saturating_sub(body);
+
+        let mut out = b"\x1b[H\x1b[2J".to_vec();
+        for (row, i) in (start..end).enumerate() {
+            let line = scrollback.get(i).unwrap_or_default();
+            let _ = write!(out, "\x1b[{};1H{}", row + 1, truncate(&line, cols));
+        }
+        let status = format!("[{}] lines {}-{} of {}, q to quit", title, start + 1, end, total);
+        let _ = write!(out, "\x1b[{};1H\x1b[7m{}\x1b[0m", body + 1, truncate(&status, cols));
+        out
+    }
+
+    /// Hold on to some live output until scroll mode ends. Returns
+    /// false if we are holding too much and the caller should exit
+    /// scroll mode.
+    pub fn hold(&mut self, bytes: &[u8]) -> bool {
+        self.held.extend_from_slice(bytes);
+        self.held.len() <= MAX_HELD_BYTES
+    }
+
+    /// Leave scroll mode, returning the bytes to send to the client to
+    /// get it back to where it was and caught up on any output it missed.
+    pub fn exit(self) -> Vec<u8> {
+        let mut out = b"\x1b[?1049l".to_vec();
+        out.extend(self.held);
+        out
+    }
+}
+
+/// Decode keypresses made while in scroll mode.
+pub fn parse_keys(mut bytes: &[u8]) -> Vec<ScrollCmd> {
+    let mut cmds = vec![];
+    while let Some(&byte) = bytes.first() {
+        let (cmd, len) = match bytes {
+            [0x1b, b'[', b'A', ..] => (Some(ScrollCmd::Up(1)), 3),
+            [0x1b, b'[', b'B', ..] => (Some(ScrollCmd::Down(1)), 3),
+            [0x1b, b'[', b'H', ..] => (Some(ScrollCmd::Top), 3),
+            [0x1b, b'[', b'F', ..] => (Some(ScrollCmd::Bottom), 3),
+            [0x1b, b'[', b'5', b'~', ..] => (Some(ScrollCmd::PageUp), 4),
+            [0x1b, b'[', b'6', b'~', ..] => (Some(ScrollCmd::PageDown), 4),
+            // some other escape sequence, skip over it
+            [0x1b, b'[', rest @ ..] => {
+                let len = rest.iter().position(|b| (0x40..=0x7e).contains(b)).map(|p| p + 3);
+                (None, len.unwrap_or(bytes.len()))
+            }
+            [0x1b, b'O', _, ..] => (None, 3),
+            _ => {
+                let cmd = match byte {
+                    0x1b | b'q' | 0x03 => Some(ScrollCmd::Exit),
+                    b'k' | 0x10 => Some(ScrollCmd::Up(1)),
+                    b'j' | 0x0e | b'\r' => Some(ScrollCmd::Down(1)),
+                    b'b' | b'u' | 0x02 | 0x15 => Some(ScrollCmd::PageUp),
+                    b'f' | b'd' | b' ' | 0x06 | 0x04 => Some(ScrollCmd::PageDown),
+                    b'g' => Some(ScrollCmd::Top),
+                    b'G' => Some(ScrollCmd::Bottom),
+                    _ => None,
+                };
+                (cmd, 1)
+            }
+        };
+        cmds.extend(cmd);
+        bytes = &bytes[len..];
+    }
+    cmds
+}
+
+/// The number of rows used to show scrollback, leaving one for the
+/// status line.
+fn body_rows(size: &TtySize) -> usize {
+    std::cmp::max(size.rows as usize, 2) - 1
+}
+
+fn truncate(s: &str, cols: usize) -> &str {
+    match s.char_indices().nth(cols) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(sb: &Scrollback) -> Vec<String> {
+        (0..sb.len()).filter_map(|i| sb.get(i)).collect()
+    }
+
+    #[test]
+    fn strips_escapes() {
+        let mut sb = Scrollback::new(10);
+        sb.process(b"\x1b[1;31mred\x1b[0m text\r\n\x1b]0;title\x07prompt$ ");
+        assert_eq!(lines(&sb), vec!["red text", "prompt$ "]);
+
+        sb.process(b"\x1b]2;other title\x1b\\ls\r\n");
+        assert_eq!(lines(&sb), vec!["red text", "prompt$ ls"]);
+    }
+
+    #[test]
+    fn carriage_return_overwrites() {
+        let mut sb = Scrollback::new(10);
+        sb.process(b"10%\r50%\r100%\r\ndone\n");
+        assert_eq!(lines(&sb), vec!["100%", "done"]);
+    }
+
+    #[test]
+    fn text() {
+        let mut sb = Scrollback::new(2);
+        sb.process(b"one\r\ntwo\r\nthree\r\n$ ");
+        assert_eq!(sb.text(), "two\nthree\n$ \n");
+    }
+
+    #[test]
+    fn backspace_and_tabs() {
+        let mut sb = Scrollback::new(10);
+        sb.process("lsé\x08\x08 -l\ta\n".as_bytes());
+        assert_eq!(lines(&sb), vec!["l -l    a"]);
+    }
+
+    #[test]
+    fn ring_is_bounded() {
+        let mut sb = Scrollback::new(3);
+        for i in 0..10 {
+            sb.process(format!("line {i}\n").as_bytes());
+        }
+        assert_eq!(lines(&sb), vec!["line 7", "line 8", "line 9"]);
+
+        let mut disabled = Scrollback::new(0);
+        disabled.process(b"line\n");
+        assert_eq!(disabled.len(), 0);
+    }
+
+    #[test]
+    fn scrolling_clamps() {
+        let mut sb = Scrollback::new(100);
+        for i in 0..20 {
+            sb.process(format!("line {i}\n").as_bytes());
+        }
+        let size = TtySize { rows: 6, cols: 80, xpixel: 0, ypixel: 0 };
+
+        let (mut mode, out) = ScrollMode::enter(&sb, &size);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[?1049h"));
+        assert!(out.contains("line 19"));
+        assert!(out.contains("lines 16-20 of 20"));
+
+        let out = String::from_utf8(mode.handle(ScrollCmd::PageUp, &sb, &size)).unwrap();
+        assert!(out.contains("lines 11-15 of 20"));
+        let out = String::from_utf8(mode.handle(ScrollCmd::Up(100), &sb, &size)).unwrap();
+        assert!(out.contains("lines 1-5 of 20"));
+        let out = String::from_utf8(mode.handle(ScrollCmd::Down(1), &sb, &size)).unwrap();
+        assert!(out.contains("lines 2-6 of 20"));
+        let out = String::from_utf8(mode.handle(ScrollCmd::Bottom, &sb, &size)).unwrap();
+        assert!(out.contains("lines 16-20 of 20"));
+
+        assert!(mode.hold(b"live"));
+        assert_eq!(mode.exit(), b"\x1b[?1049llive");
+    }
+
+    #[test]
+    fn show_text() {
+        let sb = Scrollback::new(100);
+        let size = TtySize { rows: 3, cols: 80, xpixel: 0, ypixel: 0 };
+
+        let (mut mode, out) =
+            ScrollMode::show(String::from("sessions"), "one\ntwo\nthree\n", &size);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("three"));
+        assert!(out.contains("[sessions] lines 2-3 of 3"));
+
+        // the session's own scrollback gets ignored
+        let out = String::from_utf8(mode.handle(ScrollCmd::Top, &sb, &size)).unwrap();
+        assert!(out.contains("one"));
+        assert!(out.contains("[sessions] lines 1-2 of 3"));
+    }
+
+    #[test]
+    fn hold_overflows() {
+        let sb = Scrollback::new(10);
+        let size = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+        let (mut mode, _) = ScrollMode::enter(&sb, &size);
+        assert!(mode.hold(&vec![b'x'; MAX_HELD_BYTES]));
+        assert!(!mode.hold(b"x"));
+    }
+
+    #[test]
+    fn keys() {
+        use ScrollCmd::*;
+        assert_eq!(parse_keys(b"kj"), vec![Up(1), Down(1)]);
+        assert_eq!(
+            parse_keys(b"\x1b[A\x1b[B\x1b[5~\x1b[6~"),
+            vec![Up(1), Down(1), PageUp, PageDown]
+        );
+        assert_eq!(parse_keys(b"gG q"), vec![Top, Bottom, PageDown, Exit]);
+        assert_eq!(parse_keys(b"\x1b"), vec![Exit]);
+        assert_eq!(parse_keys(b"\x1b[1;5Cx"), vec![]);
+    }
+}
@@ -15,10 +15,13 @@
 use std::{
     collections::HashMap,
     env,
-    ffi::OsString,
-    fs, io, net,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Read as _, Write},
+    net,
     ops::Add,
     os,
+    os::fd::{AsRawFd as _, BorrowedFd, OwnedFd, RawFd},
     os::unix::{
         fs::PermissionsExt as _,
         net::{UnixListener, UnixStream},
@@ -26,7 +29,10 @@ use std::{
     },
     path::{Path, PathBuf},
     process,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread, time,
     time::{Duration, Instant},
 };
@@ -34,27 +40,41 @@ use std::{
 use anyhow::{anyhow, Context};
 #[cfg(target_os = "linux")]
 use nix::unistd;
+use nix::{sys::signal, unistd::Pid};
 use shpool_protocol::{
-    AttachHeader, AttachReplyHeader, AttachStatus, ConnectHeader, DetachReply, DetachRequest,
-    KillReply, KillRequest, ListReply, LogLevel, ResizeReply, Session, SessionMessageDetachReply,
-    SessionMessageReply, SessionMessageRequest, SessionMessageRequestPayload, SessionStatus,
-    SetLogLevelReply, SetLogLevelRequest, VersionHeader,
+    AttachHeader, AttachReplyHeader, AttachStatus, CaptureReply, CaptureRequest, Chunk, ChunkKind,
+    ConnectHeader, ControlCommand, ControlEvent, ControlHeader, ControlRequest, DetachReason,
+    DetachReply, DetachRequest, DumpStateReply, ExecReply, ExecRequest, HeapProfileReply,
+    KillReply, KillRequest, ListReply, LockReply, LockRequest, MigrateReply, MigrateRequest,
+    NoteReply, NoteRequest, PingReply, PingRequest, PipeReply, PipeRequest, RenameReply,
+    RenameRequest, ResizeReply, Session, SessionMessageDetachReply, SessionMessageReply,
+    SessionMessageRequest, SessionMessageRequestPayload, SessionPing, SessionStats, SessionStatus,
+    SessionTerm, SetLogLevelReply, SetLogLevelRequest, StatsReply, StatusReply, StatusRequest,
+    StopReply, StopRequest, TailReply, TailRequest, TtySize, VersionHeader, WaitFor, WaitReply,
+    WaitRequest, Watch, WatchReply, WatchRequest,
 };
 use tracing::{error, info, instrument, span, warn, Level};
 
 use crate::{
-    config,
+    attach, config,
     config::MotdDisplayMode,
     consts,
     daemon::{
-        etc_environment, exit_notify::ExitNotifier, hooks, pager::PagerError, prompt, shell,
-        show_motd, ttl_reaper,
+        control, escape_filter, etc_environment, exec_marker, exit_notify::ExitNotifier,
+        exit_reaper, forward_sockets, hook_cmds, hooks, limits, lock, manifest, memory, migrate,
+        notify, out_queue, output_log, pager::PagerError, proc_tree, prompt, refresh_env, rlimits,
+        scrollback, session_table::SessionTable, shell, show_motd, state_dump, takeover, threads,
+        ttl_reaper, watch,
     },
-    protocol, test_hooks, tty, user,
+    duration, namespace, protocol,
+    protocol::ChunkExt as _,
+    resurrect, session_restore,
+    session_restore::replay,
+    set_log_level, size, supervise, test_hooks, tty, user,
 };
 
 const DEFAULT_INITIAL_SHELL_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
-const DEFAULT_PROMPT_PREFIX: &str = "shpool:$SHPOOL_SESSION_NAME ";
+pub const DEFAULT_PROMPT_PREFIX: &str = "shpool:$SHPOOL_SESSION_NAME ";
 
 // Half a second should be more than enough time to handle any resize or
 // or detach. If things are taking longer, we can't afford to keep waiting
@@ -62,23 +82,69 @@ const DEFAULT_PROMPT_PREFIX: &str = "shpool:$SHPOOL_SESSION_NAME ";
 // global session table lock held.
 const SESSION_MSG_TIMEOUT: time::Duration = time::Duration::from_millis(500);
 
+// How long `shpool exec --until` waits for its marker if the user did
+// not say.
+const EXEC_DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// How often to check whether the shell of an adopted session is still
+// around. Adopted shells are not our children, so we can't wait on them.
+const ADOPTED_CHILD_POLL_DUR: time::Duration = time::Duration::from_millis(500);
+
+// How often `shpool wait` checks back on the session it is watching.
+const WAIT_POLL_DUR: time::Duration = time::Duration::from_millis(100);
+
+// How often `shpool pipe` checks whether the session it is feeding
+// has exited while waiting on more input.
+const PIPE_POLL_DUR: time::Duration = time::Duration::from_millis(100);
+
+// How long `shpool ping` waits on each leg of a trip through a session.
+// Generous, since the point is to measure lag.
+const PING_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// What `shpool ping --echo` types into the session: a space and then a
+// backspace, which a shell prompt echoes and then erases again.
+const PING_ECHO_INPUT: &[u8] = b" \x7f";
+
 pub struct Server {
     config: config::Manager,
     /// A map from shell session names to session descriptors.
-    /// We wrap this in Arc<Mutex<_>> so that we can get at the
-    /// table from different threads such as the SIGWINCH thread
-    /// that is spawned during the attach process, and so that
-    /// handle_conn can delegate to worker threads and quickly allow
-    /// the main thread to become available to accept new connections.
-    shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    /// We wrap this in an Arc so that we can get at the table from
+    /// different threads such as the SIGWINCH thread that is spawned
+    /// during the attach process, and so that handle_conn can delegate
+    /// to worker threads and quickly allow the main thread to become
+    /// available to accept new connections. The table is internally
+    /// sharded so that traffic for one session does not contend with
+    /// traffic for unrelated sessions.
+    shells: Arc<SessionTable>,
     runtime_dir: PathBuf,
-    register_new_reapable_session: crossbeam_channel::Sender<(String, Instant)>,
+    register_new_reapable_session: crossbeam_channel::Sender<ttl_reaper::Msg>,
+    /// The exit reaper's mailbox.
+    exited: crossbeam_channel::Sender<exit_reaper::Exited>,
     hooks: Box<dyn hooks::Hooks + Send + Sync>,
+    hook_cmds: hook_cmds::Runner,
+    notifier: notify::Notifier,
     daily_messenger: Arc<show_motd::DailyMessenger>,
     log_level_handle: tracing_subscriber::reload::Handle<
-        tracing_subscriber::filter::LevelFilter,
+        tracing_subscriber::filter::Targets,
         tracing_subscriber::registry::Registry,
     >,
+    /// The id of the last connection we accepted, shared between the
+    /// unix socket and the tcp listener.
+    conn_counter: AtomicUsize,
+    /// The namespace this daemon serves, if it was given one.
+    namespace: Option<String>,
+    /// The socket we are listening on, so that we can hand it over
+    /// to a new daemon taking over from us.
+    listener_fd: Mutex<Option<RawFd>>,
+    /// Where the resurrect manifest lives.
+    manifest_path: PathBuf,
+    /// Set once `shpool daemon stop` has started shutting us down.
+    stopping: Arc<AtomicBool>,
+    /// When the daemon started, for `shpool ping`.
+    started_at: Instant,
+    /// Held from checking the `[limits]` until a new session is in the
+    /// table.
+    admission: Mutex<()>,
 }
 
 impl Server {
@@ -87,51 +153,100 @@ impl Server {
         config: config::Manager,
         hooks: Box<dyn hooks::Hooks + Send + Sync>,
         runtime_dir: PathBuf,
+        state_dir: PathBuf,
         log_level_handle: tracing_subscriber::reload::Handle<
-            tracing_subscriber::filter::LevelFilter,
+            tracing_subscriber::filter::Targets,
             tracing_subscriber::registry::Registry,
         >,
+        namespace: Option<String>,
     ) -> anyhow::Result<Arc<Self>> {
-        let shells = Arc::new(Mutex::new(HashMap::new()));
+        let shells = Arc::new(SessionTable::new());
         // buffered so that we are unlikely to block when setting up a
         // new session
         let (new_sess_tx, new_sess_rx) = crossbeam_channel::bounded(10);
         let shells_tab = Arc::clone(&shells);
-        thread::spawn(move || {
-            if let Err(e) = ttl_reaper::run(new_sess_rx, shells_tab) {
-                warn!("ttl reaper exited with error: {:?}", e);
-            }
-        });
+        thread::Builder::new()
+            .name(String::from("ttl-reaper"))
+            .spawn(move || {
+                if let Err(e) = ttl_reaper::run(new_sess_rx, shells_tab) {
+                    warn!("ttl reaper exited with error: {:?}", e);
+                }
+            })
+            .context("spawning ttl reaper thread")?;
+
+        let notifier = notify::Notifier::new(config.clone())?;
+        let (exited_tx, exited_rx) = crossbeam_channel::unbounded();
+        let shells_tab = Arc::clone(&shells);
+        let exit_reaper_config = config.clone();
+        let exit_notifier = notifier.clone();
+        thread::Builder::new()
+            .name(String::from("exit-reaper"))
+            .spawn(move || {
+                if let Err(e) =
+                    exit_reaper::run(exited_rx, shells_tab, exit_reaper_config, exit_notifier)
+                {
+                    warn!("exit reaper exited with error: {:?}", e);
+                }
+            })
+            .context("spawning exit reaper thread")?;
+
+        let shells_tab = Arc::clone(&shells);
+        let manifest_path = state_dir.join(resurrect::MANIFEST_FILE);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let manifest_thread_path = manifest_path.clone();
+        let manifest_stopping = Arc::clone(&stopping);
+        thread::Builder::new()
+            .name(String::from("manifest"))
+            .spawn(move || {
+                if let Err(e) = manifest::run(shells_tab, manifest_thread_path, manifest_stopping) {
+                    warn!("manifest thread exited with error: {:?}", e);
+                }
+            })
+            .context("spawning manifest thread")?;
+
+        let shells_tab = Arc::clone(&shells);
+        let limits_config = config.clone();
+        let limits_stopping = Arc::clone(&stopping);
+        thread::Builder::new()
+            .name(String::from("limits"))
+            .spawn(move || {
+                if let Err(e) = limits::run(shells_tab, limits_config, limits_stopping) {
+                    warn!("limits thread exited with error: {:?}", e);
+                }
+            })
+            .context("spawning limits thread")?;
 
+        let hook_cmds = hook_cmds::Runner::new(config.clone())?;
         let daily_messenger = Arc::new(show_motd::DailyMessenger::new(config.clone())?);
         Ok(Arc::new(Server {
             config,
             shells,
             runtime_dir,
             register_new_reapable_session: new_sess_tx,
+            exited: exited_tx,
             hooks,
+            hook_cmds,
+            notifier,
             daily_messenger,
             log_level_handle,
+            conn_counter: AtomicUsize::new(0),
+            namespace,
+            listener_fd: Mutex::new(None),
+            manifest_path,
+            stopping,
+            started_at: Instant::now(),
+            admission: Mutex::new(()),
         }))
     }
 
     #[instrument(skip_all)]
     pub fn serve(server: Arc<Self>, listener: UnixListener) -> anyhow::Result<()> {
+        *server.listener_fd.lock().unwrap() = Some(listener.as_raw_fd());
         test_hooks::emit("daemon-about-to-listen");
-        let mut conn_counter = 0;
         for stream in listener.incoming() {
             info!("socket got a new connection");
             match stream {
-                Ok(stream) => {
-                    conn_counter += 1;
-                    let conn_id = conn_counter;
-                    let server = Arc::clone(&server);
-                    thread::spawn(move || {
-                        if let Err(err) = server.handle_conn(stream, conn_id) {
-                            error!("handling new connection: {:?}", err)
-                        }
-                    });
-                }
+                Ok(stream) => Self::accept(&server, stream),
                 Err(err) => {
                     error!("accepting stream: {:?}", err);
                 }
@@ -141,8 +256,28 @@ impl Server {
         Ok(())
     }
 
+    /// Handle a new connection on a thread of its own.
+    pub fn accept(server: &Arc<Self>, stream: UnixStream) {
+        let conn_id = server.conn_counter.fetch_add(1, Ordering::Relaxed) + 1;
+        let server = Arc::clone(server);
+        let spawn_res = thread::Builder::new().name(format!("conn:{conn_id}")).spawn(move || {
+            if let Err(err) = server.handle_conn(stream, conn_id) {
+                error!("handling new connection: {:?}", err)
+            }
+        });
+        if let Err(err) = spawn_res {
+            error!("spawning connection handler thread: {:?}", err);
+        }
+    }
+
     #[instrument(skip_all, fields(cid = conn_id))]
     fn handle_conn(&self, mut stream: UnixStream, conn_id: usize) -> anyhow::Result<()> {
+        // Nothing new gets started while we are shutting down.
+        if self.stopping.load(Ordering::Relaxed) {
+            info!("stopping, turning the connection away");
+            return Ok(());
+        }
+
         // We want to avoid timing out while blocking the main thread.
         // On macOS, Unix domain sockets may not support timeouts
         #[cfg(not(target_os = "macos"))]
@@ -161,6 +296,8 @@ impl Server {
                     Ok(fake_version) => fake_version,
                     Err(_) => String::from(shpool_protocol::VERSION),
                 },
+                protocol: shpool_protocol::PROTOCOL_VERSION,
+                capabilities: shpool_protocol::capability::ALL,
             },
             &mut stream,
         ) {
@@ -179,11 +316,15 @@ impl Server {
 
         let header = parse_connect_header(&mut stream).context("parsing connect header")?;
 
-        if let Err(err) = check_peer(&stream) {
+        if let Err(err) = check_peer(&stream, self.config.get().socket_group.as_deref()) {
             if let ConnectHeader::Attach(_) = header {
                 write_reply(
                     &mut stream,
-                    AttachReplyHeader { status: AttachStatus::Forbidden(format!("{err:?}")) },
+                    AttachReplyHeader {
+                        status: AttachStatus::Forbidden(format!("{err:?}")),
+                        banner: None,
+                        term: None,
+                    },
                 )?;
             }
             stream.shutdown(net::Shutdown::Both).context("closing stream")?;
@@ -204,29 +345,153 @@ impl Server {
             ConnectHeader::List => self.handle_list(stream),
             ConnectHeader::SessionMessage(header) => self.handle_session_message(stream, header),
             ConnectHeader::SetLogLevel(r) => self.handle_set_log_level(stream, r),
+            ConnectHeader::Status(r) => self.handle_status(stream, r),
+            ConnectHeader::Stats => self.handle_stats(stream),
+            ConnectHeader::Rename(r) => self.handle_rename(stream, r),
+            ConnectHeader::Exec(r) => self.handle_exec(stream, r),
+            ConnectHeader::Capture(r) => self.handle_capture(stream, r),
+            ConnectHeader::Takeover => self.handle_takeover(stream),
+            ConnectHeader::Wait(r) => self.handle_wait(stream, r),
+            ConnectHeader::Control(h) => self.handle_control(stream, conn_id, h),
+            ConnectHeader::Lock(r) => self.handle_lock(stream, r),
+            ConnectHeader::Stop(r) => self.handle_stop(stream, r),
+            ConnectHeader::Migrate(r) => self.handle_migrate(stream, r),
+            ConnectHeader::Adopt => self.handle_adopt(stream),
+            ConnectHeader::Ping(r) => self.handle_ping(stream, r),
+            ConnectHeader::Pipe(r) => self.handle_pipe(stream, r),
+            ConnectHeader::Tail(r) => self.handle_tail(stream, r),
+            ConnectHeader::Note(r) => self.handle_note(stream, r),
+            ConnectHeader::DumpState => self.handle_dump_state(stream),
+            ConnectHeader::Watch(r) => self.handle_watch(stream, r),
         }
     }
 
     #[instrument(skip_all)]
     fn handle_attach(
         &self,
-        mut stream: UnixStream,
+        stream: UnixStream,
         conn_id: usize,
-        header: AttachHeader,
+        mut header: AttachHeader,
     ) -> anyhow::Result<()> {
+        let owner = peer_uid(&stream);
+        // Keep following the client around for as long as it keeps
+        // switching sessions in place.
+        loop {
+            let conn = stream.try_clone().context("cloning client stream")?;
+            match self.attach(conn, conn_id, owner, header.clone())? {
+                Some(next) => header = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Attach a client to a session, returning the header to attach
+    /// with next if it switched to another session in place rather
+    /// than detaching. `owner` is the uid of the user on the other end,
+    /// who a new session counts against.
+    fn attach(
+        &self,
+        mut stream: UnixStream,
+        conn_id: usize,
+        owner: u32,
+        mut header: AttachHeader,
+    ) -> anyhow::Result<Option<AttachHeader>> {
+        self.apply_session_config(&mut header);
+
         // We don't currently populate any warnings, but we used to and we might
         // want to in the future, so it is not worth breaking the protocol over.
         let warnings = vec![];
 
         let user_info = user::info().context("resolving user info")?;
         let shell_env = self.build_shell_env(&user_info, &header).context("building shell env")?;
+        // Counting the sessions needs every shard, so this has to
+        // happen before we take the lock on ours.
+        let motd_vars = self.motd_vars(&header);
+        // As does making sure there is room for a new session. We hang
+        // on to the admission lock until the new session is in the table
+        // so that two attaches can't both take the last free slot.
+        let (admission_lock, admission) =
+            if self.shells.shard(&header.name).contains_key(&header.name) {
+                (None, Ok(()))
+            } else {
+                let admission_lock = self.admission.lock().unwrap();
+                let limits = limits::Limits::from_config(&self.config.get());
+                (Some(admission_lock), limits.admit(&self.shells, &header.name, owner))
+            };
 
-        let (child_exit_notifier, inner_to_stream, pager_ctl_slot, status) = {
+        // Likewise checking a passphrase, which is slow on purpose.
+        let checked_passphrase = match header.passphrase.as_deref() {
+            Some(passphrase) => self.check_passphrase(&header.name, passphrase, true),
+            None => None,
+        };
+
+        let allow_mirror = header.mirror
+            || header.read_only
+            || self.config.get().allow_multiple_clients.unwrap_or(false);
+        let mut mirror_args = None;
+        let mut session_term = None;
+        let mut reconnect_summary = None;
+
+        let (
+            child_exit_notifier,
+            inner_to_stream,
+            pager_ctl_slot,
+            session_name,
+            attach_count,
+            status,
+        ) = {
             // we unwrap to propagate the poison as an unwind
             let _s = span!(Level::INFO, "1_lock(shells)").entered();
-            let mut shells = self.shells.lock().unwrap();
+            let mut shells = self.shells.shard(&header.name);
 
             let mut status = AttachStatus::Attached { warnings: warnings.clone() };
+            let running = shells.get(&header.name).is_some_and(|session| {
+                session.child_exit_notifier.wait(Some(time::Duration::from_millis(0))).is_none()
+            });
+            if header.detached && running {
+                info!("'{}' is already running, leaving it be", header.name);
+                write_reply(&mut stream, AttachReplyHeader { status, banner: None, term: None })?;
+                return Ok(None);
+            }
+
+            if let Some(session) = shells.get_mut(&header.name)
+                && session.is_locked(self.configured_auto_lock())
+            {
+                match (session.lock.as_mut(), &checked_passphrase) {
+                    (Some(lock), Some((checked, true))) if lock.same_passphrase(checked) => {
+                        info!("'{}' unlocked", header.name);
+                        lock.unlock();
+                    }
+                    _ => {
+                        info!("'{}' is locked", header.name);
+                        let status =
+                            AttachStatus::Locked { wrong_passphrase: header.passphrase.is_some() };
+                        write_reply(
+                            &mut stream,
+                            AttachReplyHeader { status, banner: None, term: None },
+                        )?;
+                        return Ok(None);
+                    }
+                }
+            }
+
+            if let Some(exited) = shells.get(&header.name).and_then(|s| s.exited.as_ref()) {
+                info!("'{}' has exited, showing its final output", header.name);
+                if header.detached {
+                    let msg = format!("'{}' has exited, kill it to start a new shell", header.name);
+                    write_reply(&mut stream, AttachReplyHeader {
+                        status: AttachStatus::UnexpectedError(msg),
+                        banner: None,
+                        term: None,
+                    })?;
+                    return Ok(None);
+                }
+                write_reply(&mut stream, AttachReplyHeader { status, banner: None, term: None })?;
+                exited.replay_to(&mut stream).context("showing final output")?;
+                stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                return Ok(None);
+            }
+
             if let Some(session) = shells.get(&header.name) {
                 info!("found entry for '{}'", header.name);
                 match session.inner.try_lock() {
@@ -293,68 +558,197 @@ impl Server {
 
                         // fallthrough to bidi streaming
                     }
+                    _ if allow_mirror => {
+                        info!("busy shell session, attaching as a mirror");
+                        mirror_args = Some(shell::MirrorArgs {
+                            name: header.name.clone(),
+                            conn_id,
+                            client_pid: peer_pid(&stream),
+                            stream: stream.try_clone().context("cloning mirror stream")?,
+                            size: header.local_tty_size.clone(),
+                            shell_to_client_ctl: Arc::clone(&session.shell_to_client_ctl),
+                            pty_master: session.pty_master,
+                            pump_cpu_ns: Arc::clone(&session.pump_cpu_ns),
+                            bytes_in: Arc::clone(&session.bytes_in),
+                            output_queue: Arc::clone(&session.output_queue),
+                            config: self.config.clone(),
+                            replay: replay_override(&header),
+                            read_only: header.read_only,
+                        });
+                    }
                     _ => {
                         info!("busy shell session, doing nothing");
                         // The stream is busy, so we just inform the client and close the stream.
-                        write_reply(&mut stream, AttachReplyHeader { status: AttachStatus::Busy })?;
+                        write_reply(
+                            &mut stream,
+                            AttachReplyHeader {
+                                status: AttachStatus::Busy,
+                                banner: None,
+                                term: None,
+                            },
+                        )?;
                         stream.shutdown(net::Shutdown::Both).context("closing stream")?;
                         if let Err(err) = self.hooks.on_busy(&header.name) {
                             warn!("busy hook: {:?}", err);
                         }
-                        return Ok(());
+                        return Ok(None);
                     }
                 }
+            } else if let Err(reason) = admission {
+                info!("refusing to create '{}': {}", header.name, reason);
+                let status = AttachStatus::LimitReached(reason);
+                write_reply(&mut stream, AttachReplyHeader { status, banner: None, term: None })?;
+                return Ok(None);
             } else {
                 info!("no existing '{}' session, creating new one", &header.name);
                 status = AttachStatus::Created { warnings };
             }
 
             if matches!(status, AttachStatus::Created { .. }) {
-                use config::MotdDisplayMode;
-
                 info!("creating new subshell");
                 if let Err(err) = self.hooks.on_new_session(&header.name) {
                     warn!("new_session hook: {:?}", err);
                 }
                 let motd = self.config.get().motd.clone().unwrap_or_default();
-                let session = self.spawn_subshell(
+                let motd_dump = motd_vars.clone().filter(|_| matches!(motd, MotdDisplayMode::Dump));
+                // a detached session has nobody to stream its output to yet
+                let client_stream = if header.detached {
+                    None
+                } else {
+                    Some(stream.try_clone().context("cloning client stream")?)
+                };
+                let mut session = self.spawn_subshell(
                     conn_id,
-                    stream,
+                    client_stream,
                     &header,
                     &user_info,
                     &shell_env,
-                    matches!(motd, MotdDisplayMode::Dump),
+                    motd_dump,
                 )?;
+                session.owner = owner;
+                if header.detached {
+                    session.attach_count = 0;
+                } else {
+                    session.client_tty = header.client_tty.clone();
+                    session.last_attached_at = Some(time::SystemTime::now());
+                }
 
+                self.hook_cmds.fire(
+                    hook_cmds::Event::Create,
+                    &header.name,
+                    Some(session.child_pid),
+                );
                 shells.insert(header.name.clone(), Box::new(session));
                 // fallthrough to bidi streaming
-            } else if let Err(err) = self.hooks.on_reattach(&header.name) {
-                warn!("reattach hook: {:?}", err);
+            } else {
+                if let Err(err) = self.hooks.on_reattach(&header.name) {
+                    warn!("reattach hook: {:?}", err);
+                }
+                if let Some(session) = shells.get_mut(&header.name) {
+                    session.attach_count += 1;
+                    if !header.detached
+                        && mirror_args.is_none()
+                        && self.config.get().reconnect_summary.unwrap_or(false)
+                    {
+                        reconnect_summary = away_summary(session);
+                    }
+                    if !header.detached {
+                        if let (Some(DetachReason::HungUp), Some(at)) =
+                            (session.last_detach_reason.take(), session.last_detached_at)
+                            && let AttachStatus::Attached { warnings } = &mut status
+                        {
+                            warnings.push(hangup_banner(at));
+                        }
+                        session.client_tty = header.client_tty.clone();
+                        session.last_attached_at = Some(time::SystemTime::now());
+                    }
+                    for tag in header.tags.iter() {
+                        if !session.tags.contains(tag) {
+                            session.tags.push(tag.clone());
+                        }
+                    }
+                }
             }
 
             // return a reference to the inner session so that
             // we can work with it without the global session
             // table lock held
-            if let Some(session) = shells.get(&header.name) {
+            if let Some(session) = shells.get(&header.name)
+                && !header.detached
+            {
+                if matches!(status, AttachStatus::Attached { .. }) {
+                    session_term = Some(session.term.clone());
+                }
+                self.hook_cmds.fire(
+                    hook_cmds::Event::Attach,
+                    &header.name,
+                    Some(session.child_pid),
+                );
                 (
                     Some(Arc::clone(&session.child_exit_notifier)),
                     Some(Arc::clone(&session.inner)),
                     Some(Arc::clone(&session.pager_ctl)),
+                    Arc::clone(&session.name),
+                    session.attach_count,
                     status,
                 )
             } else {
-                (None, None, None, status)
+                (None, None, None, Arc::new(Mutex::new(header.name.clone())), 0, status)
             }
         };
         info!("released lock on shells table");
+        drop(admission_lock);
+
+        let motd_mode = self.config.get().motd.clone().unwrap_or_default();
+        let banner = match (&motd_vars, &motd_mode) {
+            (Some(vars), MotdDisplayMode::Banner) => {
+                self.daily_messenger.banner(vars).unwrap_or_else(|e| {
+                    warn!("rendering motd banner: {:?}", e);
+                    None
+                })
+            }
+            _ => None,
+        };
+        let banner = match (banner, reconnect_summary) {
+            (Some(banner), Some(summary)) => Some(format!("{banner}\n{summary}")),
+            (banner, summary) => banner.or(summary),
+        };
+
+        if let Some(mut mirror_args) = mirror_args {
+            write_reply(
+                &mut mirror_args.stream,
+                AttachReplyHeader { status, banner, term: session_term },
+            )
+            .context("writing mirror attach reply")?;
+            shell::attach_mirror(mirror_args)?;
+            return Ok(None);
+        }
+
+        if matches!(status, AttachStatus::Attached { .. })
+            && let Err(e) = self.write_prompt_prefix(&header.name, attach_count)
+        {
+            warn!("refreshing prompt prefix: {:?}", e);
+        }
 
-        self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
+        self.link_forwarded_sockets(&header).context("linking forwarded sockets")?;
         self.populate_session_env_file(&header).context("populating session env file")?;
 
+        if header.detached {
+            info!("created '{}' detached", header.name);
+            write_reply(&mut stream, AttachReplyHeader { status, banner: None, term: None })
+                .context("writing detached attach reply")?;
+            return Ok(None);
+        }
+
         match (child_exit_notifier, inner_to_stream, pager_ctl_slot) {
             (Some(child_exit_notifier), Some(inner), Some(pager_ctl_slot)) => {
                 let mut child_done = false;
                 let mut inner = inner.lock().unwrap();
+                {
+                    let mut switch = inner.switch.lock().unwrap();
+                    switch.in_place = header.switch_in_place;
+                    switch.previous = header.previous.clone();
+                }
                 let client_stream = match inner.client_stream.as_mut() {
                     Some(s) => s,
                     None => {
@@ -362,8 +756,14 @@ impl Server {
                     }
                 };
 
-                let reply_status =
-                    write_reply(client_stream, AttachReplyHeader { status: status.clone() });
+                let reply_status = write_reply(
+                    client_stream,
+                    AttachReplyHeader {
+                        status: status.clone(),
+                        banner: banner.clone(),
+                        term: session_term.clone(),
+                    },
+                );
                 if let Err(e) = reply_status {
                     error!("error writing reply status: {:?}", e);
                 }
@@ -371,13 +771,15 @@ impl Server {
                 // If in pager motd mode, launch the pager and block until it is
                 // done, picking up any tty size change that happened while the
                 // user was examining the motd.
-                let motd_mode = self.config.get().motd.clone().unwrap_or_default();
-                let init_tty_size = if matches!(motd_mode, MotdDisplayMode::Pager { .. }) {
+                let init_tty_size = if let (Some(vars), MotdDisplayMode::Pager { .. }) =
+                    (&motd_vars, &motd_mode)
+                {
                     match self.daily_messenger.display_in_pager(
                         client_stream,
                         pager_ctl_slot,
                         header.local_tty_size.clone(),
                         &shell_env,
+                        vars,
                     ) {
                         Ok(Some(new_size)) => {
                             info!("motd pager finished, reporting new tty size: {:?}", new_size);
@@ -390,7 +792,7 @@ impl Server {
                         Err(e) => match e.downcast::<PagerError>() {
                             Ok(PagerError::ClientHangup) => {
                                 info!("client hung up while talking to pager, bailing");
-                                return Ok(());
+                                return Ok(None);
                             }
                             Err(e) => {
                                 return Err(e).context("showing motd in pager")?;
@@ -402,7 +804,14 @@ impl Server {
                 };
 
                 info!("starting bidi stream loop");
-                match inner.bidi_stream(conn_id, init_tty_size, child_exit_notifier) {
+                match inner.bidi_stream(
+                    conn_id,
+                    init_tty_size,
+                    replay_override(&header),
+                    header.read_only,
+                    header.evicted_notice,
+                    child_exit_notifier,
+                ) {
                     Ok(done) => {
                         child_done = done;
                     }
@@ -411,15 +820,19 @@ impl Server {
                     }
                 }
                 info!("bidi stream loop finished child_done={}", child_done);
+                // take it so a stream that bails early next time does not
+                // see a stale value
+                let hung_up = std::mem::take(&mut inner.hung_up);
 
+                // The session might have been renamed while we were attached.
+                let name = session_name.lock().unwrap().clone();
                 if child_done {
-                    info!("'{}' exited, removing from session table", header.name);
-                    if let Err(err) = self.hooks.on_shell_disconnect(&header.name) {
+                    info!("'{}' exited, removing from session table", name);
+                    if let Err(err) = self.hooks.on_shell_disconnect(&name) {
                         warn!("shell_disconnect hook: {:?}", err);
                     }
                     let _s = span!(Level::INFO, "2_lock(shells)").entered();
-                    let mut shells = self.shells.lock().unwrap();
-                    shells.remove(&header.name);
+                    self.shells.remove(&name);
 
                     // The child shell has exited, so the shell->client thread should
                     // attempt to read from its stdout and get an error, causing
@@ -433,30 +846,125 @@ impl Server {
                             })?
                             .context("within shell->client thread after child exit")?;
                     }
-                } else if let Err(err) = self.hooks.on_client_disconnect(&header.name) {
-                    warn!("client_disconnect hook: {:?}", err);
+                } else {
+                    if hung_up {
+                        info!("client hung up, leaving '{}' detached", name);
+                    }
+                    if let Some(session) = self.shells.shard(&name).get_mut(&name) {
+                        session.last_detached_at = Some(time::SystemTime::now());
+                        session.output_at_detach = (
+                            session.bytes_out.load(Ordering::Relaxed),
+                            session.lines_out.load(Ordering::Relaxed),
+                        );
+                        session.last_detach_reason = Some(if hung_up {
+                            DetachReason::HungUp
+                        } else {
+                            DetachReason::Detached
+                        });
+                    }
+                    if let Err(err) = self.hooks.on_client_disconnect(&name) {
+                        warn!("client_disconnect hook: {:?}", err);
+                    }
+                    self.hook_cmds.fire(hook_cmds::Event::Detach, &name, None);
                 }
 
                 info!("finished attach streaming section");
+                if !child_done
+                    && let Some((target, size)) = inner.switch.lock().unwrap().switched_to.take()
+                {
+                    return Ok(Some(self.switched_header(&header, name, target, size)));
+                }
             }
             _ => {
                 error!("internal error: failed to fetch just inserted session");
             }
         }
 
-        Ok(())
+        Ok(None)
     }
 
-    #[instrument(skip_all)]
-    fn link_ssh_auth_sock(&self, header: &AttachHeader) -> anyhow::Result<()> {
-        if self.config.get().nosymlink_ssh_auth_sock.unwrap_or(false) {
-            return Ok(());
+    /// What the motd template can refer to for this attach, or None if
+    /// it should not show the motd at all.
+    fn motd_vars(&self, header: &AttachHeader) -> Option<show_motd::Vars> {
+        let mode = self.config.get().motd.clone().unwrap_or_default();
+        if header.no_motd || header.detached || matches!(mode, MotdDisplayMode::Never) {
+            return None;
+        }
+
+        let mut vars = show_motd::Vars {
+            session_name: header.name.clone(),
+            created_at: time::SystemTime::now(),
+            sessions: 1,
+            detached_sessions: 0,
+        };
+        for shard in self.shells.shards() {
+            for (name, session) in shard.iter() {
+                if *name == header.name {
+                    vars.created_at = session.started_at;
+                    continue;
+                }
+                vars.sessions += 1;
+                if session.exited.is_none() && session.inner.try_lock().is_ok() {
+                    vars.detached_sessions += 1;
+                }
+            }
+        }
+        Some(vars)
+    }
+
+    /// The header to attach to `target` with after the client switched
+    /// over to it in place from `previous`. Like when the client dials
+    /// back in to switch, the options it attached with were about the
+    /// session it left, so they get dropped.
+    fn switched_header(
+        &self,
+        header: &AttachHeader,
+        previous: String,
+        target: String,
+        size: TtySize,
+    ) -> AttachHeader {
+        let config = self.config.get();
+        let dir = config
+            .session(&target)
+            .and_then(|s| s.dir.as_deref())
+            .or(config.start_directory.as_deref());
+        let working_directory = match dir {
+            Some(dir) => match attach::resolve_working_directory(None, Some(dir)) {
+                Ok(dir) => Some(dir.to_string_lossy().into_owned()),
+                Err(e) => {
+                    warn!("resolving working directory for '{}': {:?}", target, e);
+                    header.working_directory.clone()
+                }
+            },
+            None => header.working_directory.clone(),
+        };
+        AttachHeader {
+            name: target,
+            local_tty_size: size,
+            local_env: header.local_env.clone(),
+            working_directory,
+            client_tty: header.client_tty.clone(),
+            switch_in_place: true,
+            previous: Some(previous),
+            ..Default::default()
         }
+    }
+
+    #[instrument(skip_all)]
+    fn link_forwarded_sockets(&self, header: &AttachHeader) -> anyhow::Result<()> {
+        let config = self.config.get();
+        for var in forward_sockets::vars(&config) {
+            if var == "SSH_AUTH_SOCK" && config.nosymlink_ssh_auth_sock.unwrap_or(false) {
+                continue;
+            }
+            let Some(target) = header.local_env_get(&var) else {
+                info!("no {} in client env, leaving it unlinked", var);
+                continue;
+            };
 
-        if let Some(ssh_auth_sock) = header.local_env_get("SSH_AUTH_SOCK") {
-            let symlink = self.ssh_auth_sock_symlink(PathBuf::from(&header.name));
+            let symlink = self.forwarded_socket_symlink(&header.name, &var);
             fs::create_dir_all(symlink.parent().ok_or(anyhow!("no symlink parent dir"))?)
-                .context("could not create directory for SSH_AUTH_SOCK symlink")?;
+                .with_context(|| format!("could not create directory for {var} symlink"))?;
 
             let sessions_dir =
                 symlink.parent().and_then(|d| d.parent()).ok_or(anyhow!("no sessions dir"))?;
@@ -470,17 +978,29 @@ impl Server {
                     .context("locking down permissions for sessions dir")?;
             }
 
-            let _ = fs::remove_file(&symlink); // clean up the link if it exists already
-            os::unix::fs::symlink(ssh_auth_sock, &symlink).context(format!(
-                "could not symlink '{symlink:?}' to point to '{ssh_auth_sock:?}'"
-            ))?;
-        } else {
-            info!("no SSH_AUTH_SOCK in client env, leaving it unlinked");
+            forward_sockets::link(&symlink, target)?;
         }
 
         Ok(())
     }
 
+    /// Write out the current prompt prefix for the session, returning
+    /// false if the prompt prefix has been disabled.
+    fn write_prompt_prefix(&self, session: &str, attach_count: usize) -> anyhow::Result<bool> {
+        let template =
+            self.config.get().prompt_prefix.clone().unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
+        if template.is_empty() {
+            return Ok(false);
+        }
+        prompt::write_prefix_scripts(
+            &self.session_dir(session),
+            &template,
+            &prompt::PrefixVars { session_name: session, attach_count },
+        )
+        .context("writing prompt prefix scripts")?;
+        Ok(true)
+    }
+
     #[instrument(skip_all)]
     fn populate_session_env_file(&self, header: &AttachHeader) -> anyhow::Result<()> {
         let session_name = PathBuf::from(&header.name);
@@ -495,6 +1015,31 @@ impl Server {
         )
         .context("writing session env")?;
 
+        let vars = refresh_env::vars(&self.config.get(), &header.name);
+        let mut values = header.local_env.clone();
+        if header.local_tty_size.cols > 0 {
+            values.push((String::from("COLUMNS"), header.local_tty_size.cols.to_string()));
+            values.push((String::from("LINES"), header.local_tty_size.rows.to_string()));
+        }
+        let generation = shell::unix_ms(time::SystemTime::now());
+        let script_header = refresh_env::header(generation);
+        let session_dir = self.session_dir(&header.name);
+        fs::write(
+            session_dir.join(refresh_env::SCRIPT_NAME),
+            script_header.clone() + &refresh_env::script(&vars, &values),
+        )
+        .context("writing refresh env script")?;
+        fs::write(
+            session_dir.join(refresh_env::FISH_SCRIPT_NAME),
+            script_header + &refresh_env::fish_script(&vars, &values),
+        )
+        .context("writing refresh fish script")?;
+        fs::write(
+            session_dir.join(refresh_env::JSON_NAME),
+            refresh_env::json(generation, &vars, &values),
+        )
+        .context("writing refresh json")?;
+
         Ok(())
     }
 
@@ -503,25 +1048,42 @@ impl Server {
         let mut not_found_sessions = vec![];
         let mut not_attached_sessions = vec![];
         {
+            // Sessions named explicitly must exist and be attached, but
+            // sessions picked out by tag are just skipped if not.
+            let mut targets: Vec<(String, bool)> =
+                request.sessions.into_iter().map(|s| (s, true)).collect();
+            if !request.tags.is_empty() {
+                targets.extend(self.shells.tagged(&request.tags).into_iter().map(|s| (s, false)));
+            }
+
             let _s = span!(Level::INFO, "lock(shells)").entered();
-            let shells = self.shells.lock().unwrap();
-            for session in request.sessions.into_iter() {
+            for (session, explicit) in targets.into_iter() {
+                let shells = self.shells.shard(&session);
                 if let Some(s) = shells.get(&session) {
+                    let msg = match &request.taken_over_by {
+                        Some(by) => shell::ClientConnectionMsg::Evict(format!(
+                            "session '{}' taken over by {} at {}",
+                            session,
+                            by,
+                            chrono::Local::now().format("%H:%M:%S")
+                        )),
+                        None => shell::ClientConnectionMsg::Disconnect,
+                    };
                     let _s = span!(Level::INFO, "lock(shell_to_client_ctl)", s = session).entered();
                     let shell_to_client_ctl = s.shell_to_client_ctl.lock().unwrap();
                     shell_to_client_ctl
                         .client_connection
-                        .send(shell::ClientConnectionMsg::Disconnect)
+                        .send(msg)
                         .context("sending client detach to shell->client")?;
                     let status = shell_to_client_ctl
                         .client_connection_ack
                         .recv()
                         .context("getting client conn ack")?;
                     info!("detached session({}), status = {:?}", session, status);
-                    if let shell::ClientConnectionStatus::DetachNone = status {
+                    if let (shell::ClientConnectionStatus::DetachNone, true) = (status, explicit) {
                         not_attached_sessions.push(session);
                     }
-                } else {
+                } else if explicit {
                     not_found_sessions.push(session);
                 }
             }
@@ -539,119 +1101,1384 @@ impl Server {
         mut stream: UnixStream,
         request: SetLogLevelRequest,
     ) -> anyhow::Result<()> {
-        let level_filter = match request.level {
-            LogLevel::Off => tracing_subscriber::filter::LevelFilter::OFF,
-            LogLevel::Error => tracing_subscriber::filter::LevelFilter::ERROR,
-            LogLevel::Warn => tracing_subscriber::filter::LevelFilter::WARN,
-            LogLevel::Info => tracing_subscriber::filter::LevelFilter::INFO,
-            LogLevel::Debug => tracing_subscriber::filter::LevelFilter::DEBUG,
-            LogLevel::Trace => tracing_subscriber::filter::LevelFilter::TRACE,
+        let error = match set_log_level::targets(&request) {
+            Ok(targets) => {
+                info!("setting log filter to '{}'", targets);
+                self.log_level_handle
+                    .modify(|filter| *filter = targets)
+                    .err()
+                    .map(|e| format!("modifying log filter: {e}"))
+            }
+            Err(e) => Some(format!("{e:#}")),
+        };
+        if let Some(e) = &error {
+            error!("{}", e);
+        }
+
+        write_reply(&mut stream, SetLogLevelReply { error })
+            .context("writing set log level reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_status(&self, mut stream: UnixStream, request: StatusRequest) -> anyhow::Result<()> {
+        let session_count = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            self.shells.shards().map(|shard| shard.len() as u64).sum()
+        };
+
+        let memory = if request.memory {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            Some(memory::report(&self.shells))
+        } else {
+            None
+        };
+
+        let heap_profile = request.heap_profile.then(|| {
+            info!("dumping heap profile");
+            match memory::dump_heap_profile(&self.runtime_dir) {
+                Ok(path) => HeapProfileReply::Dumped(path.to_string_lossy().into_owned()),
+                Err(e) => {
+                    warn!("dumping heap profile: {:?}", e);
+                    HeapProfileReply::Failed(format!("{e:#}"))
+                }
+            }
+        });
+
+        write_reply(
+            &mut stream,
+            StatusReply {
+                version: String::from(env!("CARGO_PKG_VERSION")),
+                session_count,
+                memory,
+                heap_profile,
+            },
+        )
+        .context("writing status reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_ping(&self, mut stream: UnixStream, request: PingRequest) -> anyhow::Result<()> {
+        let session = request.session.as_deref().map(|name| self.ping_session(name, request.echo));
+        write_reply(
+            &mut stream,
+            PingReply {
+                sent_at_unix_us: request.sent_at_unix_us,
+                version: String::from(env!("CARGO_PKG_VERSION")),
+                uptime_ms: self.started_at.elapsed().as_millis() as u64,
+                session,
+            },
+        )
+        .context("writing ping reply")?;
+        Ok(())
+    }
+
+    /// Route a ping through a session, past the shell->client thread
+    /// and then, if asked, through the shell by way of its echo.
+    #[instrument(skip_all, fields(s = name))]
+    fn ping_session(&self, name: &str, echo: bool) -> SessionPing {
+        let (shell_to_client_ctl, mut pty_master, output) = {
+            let shells = self.shells.shard(name);
+            match shells.get(name) {
+                None => return SessionPing::NotFound,
+                Some(session) if session.exited.is_some() => return SessionPing::Exited,
+                Some(session) if echo && session.is_locked(self.configured_auto_lock()) => {
+                    return SessionPing::Locked;
+                }
+                // Subscribe before typing anything so we can't miss
+                // the echo.
+                Some(session) => (
+                    Arc::clone(&session.shell_to_client_ctl),
+                    session.pty_master,
+                    echo.then(|| session.output_taps.add()),
+                ),
+            }
         };
-        if let Err(e) = self.log_level_handle.modify(|filter| *filter = level_filter) {
-            error!("modifying log level: {}", e);
+
+        // A heartbeat is the cheapest thing the shell->client thread
+        // answers, and attached clients just ignore the extra one.
+        let start = Instant::now();
+        let answered = {
+            let _s = span!(Level::INFO, "ping_lock(shell_to_client_ctl)").entered();
+            let shell_to_client_ctl = shell_to_client_ctl.lock().unwrap();
+            shell_to_client_ctl.heartbeat.send_timeout((), PING_TIMEOUT).is_ok()
+                && shell_to_client_ctl.heartbeat_ack.recv_timeout(PING_TIMEOUT).is_ok()
+        };
+        if !answered {
+            warn!("shell->client thread did not answer the ping");
+            return SessionPing::Unresponsive;
+        }
+        let reader_us = start.elapsed().as_micros() as u64;
+
+        let echo_us = output.and_then(|output| {
+            let start = Instant::now();
+            if let Err(e) = pty_master.write_all(PING_ECHO_INPUT).and_then(|_| pty_master.flush()) {
+                warn!("typing ping echo input: {:?}", e);
+                return None;
+            }
+            output.recv_timeout(PING_TIMEOUT).ok().map(|_| start.elapsed().as_micros() as u64)
+        });
+
+        SessionPing::Answered { reader_us, echo_us }
+    }
+
+    #[instrument(skip_all)]
+    fn handle_stats(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        // Read /proc before taking any locks, it can take a little while.
+        let snapshot = proc_tree::Snapshot::take();
+
+        let _s = span!(Level::INFO, "lock(shells)").entered();
+
+        let mut sessions = vec![];
+        for shard in self.shells.shards() {
+            for (name, session) in shard.iter() {
+                sessions.push(SessionStats {
+                    name: name.to_string(),
+                    pump_cpu_ns: session.pump_cpu_ns.load(Ordering::Relaxed),
+                    bytes_in: session.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: session.bytes_out.load(Ordering::Relaxed),
+                    spool_bytes: session.spool_bytes.load(Ordering::Relaxed) as u64,
+                    attach_count: session.attach_count as u64,
+                    queued_bytes: session.output_queue.queued_bytes.load(Ordering::Relaxed) as u64,
+                    dropped_bytes: session.output_queue.dropped_bytes.load(Ordering::Relaxed),
+                    resyncs: session.output_queue.resyncs.load(Ordering::Relaxed),
+                    procs: snapshot.as_ref().map(|s| s.usage(session.child_pid)),
+                    last_heartbeat_unix_ms: match session.last_heartbeat.load(Ordering::Relaxed) {
+                        0 => None,
+                        ms => Some(ms),
+                    },
+                });
+            }
         }
 
-        write_reply(&mut stream, SetLogLevelReply {}).context("writing set log level reply")?;
+        write_reply(&mut stream, StatsReply { sessions }).context("writing stats reply")?;
         Ok(())
     }
 
     #[instrument(skip_all)]
     fn handle_kill(&self, mut stream: UnixStream, request: KillRequest) -> anyhow::Result<()> {
         let mut not_found_sessions = vec![];
+        let mut killed = vec![];
+        let mut signaled = vec![];
+        let mut unmatched_patterns = vec![];
+        let signal = match &request.signal {
+            Some(name) => match name.parse::<nix::sys::signal::Signal>() {
+                Ok(signal) => Some(signal),
+                Err(_) => {
+                    info!("refusing to kill with unknown signal '{}'", name);
+                    let error = Some(format!("unknown signal '{name}'"));
+                    write_reply(&mut stream, KillReply { error, ..Default::default() })
+                        .context("writing kill reply")?;
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
         {
+            // Expand --all and any patterns into concrete session names.
+            // Sessions named explicitly must exist, but matched ones might
+            // have gone away on their own by the time we get to them.
+            let mut targets: Vec<(String, bool)> =
+                request.sessions.into_iter().map(|s| (s, true)).collect();
+            if !request.tags.is_empty() {
+                targets.extend(self.shells.tagged(&request.tags).into_iter().map(|n| (n, false)));
+            }
+            if request.all || !request.patterns.is_empty() {
+                let names = self.shells.names();
+                if request.all {
+                    targets.extend(names.iter().map(|n| (n.clone(), false)));
+                }
+                for pattern in request.patterns.into_iter() {
+                    let matched: Vec<&String> = match glob::Pattern::new(&pattern) {
+                        Ok(p) => names.iter().filter(|n| p.matches(n)).collect(),
+                        Err(e) => {
+                            warn!("bad kill pattern '{}': {:?}", pattern, e);
+                            vec![]
+                        }
+                    };
+                    if matched.is_empty() {
+                        unmatched_patterns.push(pattern);
+                    }
+                    targets.extend(matched.into_iter().map(|n| (n.clone(), false)));
+                }
+            }
+
             let _s = span!(Level::INFO, "lock(shells)").entered();
-            let mut shells = self.shells.lock().unwrap();
 
-            let mut to_remove = Vec::with_capacity(request.sessions.len());
-            for session in request.sessions.into_iter() {
-                if let Some(s) = shells.get(&session) {
+            for (session, explicit) in targets.into_iter() {
+                if killed.contains(&session) || signaled.contains(&session) {
+                    continue;
+                }
+                let mut shells = self.shells.shard(&session);
+                if let (Some(s), Some(signal)) = (shells.get(&session), signal) {
+                    info!("sending {} to '{}' ({:?})", signal, session, request.signal_target);
+                    match s.signal(signal, request.signal_target) {
+                        Ok(()) => signaled.push(session),
+                        Err(e) => warn!("signaling '{}': {:?}", session, e),
+                    }
+                } else if let Some(s) = shells.get(&session) {
                     s.kill().context("killing shell proc")?;
 
                     // we don't need to wait since the dedicated reaping thread is active
                     // even when a tty is not attached
-                    to_remove.push(session);
-                } else {
+                    shells.remove(&session);
+                    killed.push(session);
+                } else if explicit {
                     not_found_sessions.push(session);
                 }
             }
 
-            for session in to_remove.iter() {
-                shells.remove(session);
-            }
-            if !to_remove.is_empty() {
+            if !killed.is_empty() {
                 test_hooks::emit("daemon-handle-kill-removed-shells");
             }
         }
 
-        write_reply(&mut stream, KillReply { not_found_sessions }).context("writing kill reply")?;
+        killed.sort();
+        signaled.sort();
+        write_reply(
+            &mut stream,
+            KillReply { not_found_sessions, killed, signaled, unmatched_patterns, error: None },
+        )
+        .context("writing kill reply")?;
 
         Ok(())
     }
 
     #[instrument(skip_all)]
-    fn handle_list(&self, mut stream: UnixStream) -> anyhow::Result<()> {
-        let _s = span!(Level::INFO, "lock(shells)").entered();
-        let shells = self.shells.lock().unwrap();
-
-        let sessions: anyhow::Result<Vec<Session>> = shells
-            .iter()
-            .map(|(k, v)| {
-                let status = match v.inner.try_lock() {
-                    Ok(_) => SessionStatus::Disconnected,
-                    Err(_) => SessionStatus::Attached,
-                };
+    fn handle_rename(&self, mut stream: UnixStream, request: RenameRequest) -> anyhow::Result<()> {
+        // The new name becomes a directory under the runtime dir, so don't
+        // trust the client to have checked it.
+        if let Err(e) = validate_session_name(&request.to) {
+            warn!("refusing rename of '{}': {:?}", request.from, e);
+            write_reply(&mut stream, RenameReply::InvalidName).context("writing rename reply")?;
+            return Ok(());
+        }
 
-                Ok(Session {
-                    name: k.to_string(),
-                    started_at_unix_ms: v.started_at.duration_since(time::UNIX_EPOCH)?.as_millis()
-                        as i64,
-                    status,
-                })
-            })
-            .collect();
-        let sessions = sessions.context("collecting running session metadata")?;
+        let reply = self.shells.rename(&request.from, &request.to, |session| {
+            info!("renaming '{}' to '{}'", request.from, request.to);
+            *session.name.lock().unwrap() = request.to.clone();
+            if let Err(e) = self.move_session_dir(&request.from, &request.to) {
+                warn!("moving session dir: {:?}", e);
+            }
+            let shell_to_client_ctl = session.shell_to_client_ctl.lock().unwrap();
+            if let Err(e) = shell_to_client_ctl.rename.send(request.to.clone()) {
+                warn!("telling shell->client about rename: {:?}", e);
+            }
+        });
+        if reply == RenameReply::Renamed {
+            self.register_new_reapable_session
+                .send(ttl_reaper::Msg::Rename { from: request.from, to: request.to })
+                .context("telling the reaper about a rename")?;
+        }
 
-        write_reply(&mut stream, ListReply { sessions })?;
+        write_reply(&mut stream, reply).context("writing rename reply")?;
 
         Ok(())
     }
 
-    #[instrument(skip_all, fields(s = &header.session_name))]
-    fn handle_session_message(
-        &self,
-        mut stream: UnixStream,
-        header: SessionMessageRequest,
-    ) -> anyhow::Result<()> {
-        // create a slot to store our reply so we can do
-        // our IO without the lock held.
-        let reply = {
-            let _s = span!(Level::INFO, "lock(shells)").entered();
-            let shells = self.shells.lock().unwrap();
-            if let Some(session) = shells.get(&header.session_name) {
-                match header.payload {
-                    SessionMessageRequestPayload::Resize(resize_request) => {
-                        let _s = span!(Level::INFO, "lock(pager_ctl)").entered();
-                        let pager_ctl = session.pager_ctl.lock().unwrap();
-                        if let Some(pager_ctl) = pager_ctl.as_ref() {
-                            info!("resizing pager");
-                            pager_ctl
-                                .tty_size_change
-                                .send_timeout(resize_request.tty_size.clone(), SESSION_MSG_TIMEOUT)
-                                .context("sending tty size change to pager")?;
-                            pager_ctl
-                                .tty_size_change_ack
-                                .recv_timeout(SESSION_MSG_TIMEOUT)
-                                .context("recving tty size change ack from pager")?;
-                        } else {
-                            let _s =
-                                span!(Level::INFO, "resize_lock(shell_to_client_ctl)").entered();
-                            let shell_to_client_ctl = session.shell_to_client_ctl.lock().unwrap();
-                            shell_to_client_ctl
-                                .tty_size_change
-                                .send_timeout(resize_request.tty_size, SESSION_MSG_TIMEOUT)
-                                .context("sending tty size change to shell->client")?;
-                            shell_to_client_ctl
-                                .tty_size_change_ack
+    /// Take a snapshot of the daemon's internal state without waiting
+    /// on any locks, see the state_dump module.
+    pub fn dump_state(&self) -> state_dump::State {
+        let mut locked_shards = vec![];
+        let mut sessions = vec![];
+        for (i, shard) in self.shells.try_shards() {
+            match shard {
+                Some(shard) => sessions.extend(
+                    shard.iter().map(|(name, session)| state_dump::Session::of(name, session)),
+                ),
+                None => locked_shards.push(i),
+            }
+        }
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        state_dump::State {
+            pid: std::process::id(),
+            version: shpool_protocol::VERSION,
+            taken_at_unix_ms: shell::unix_ms(time::SystemTime::now()),
+            uptime_ms: self.started_at.elapsed().as_millis() as u64,
+            connections_accepted: self.conn_counter.load(Ordering::Relaxed),
+            stopping: self.stopping.load(Ordering::Relaxed),
+            locked_shards,
+            sessions,
+            threads: state_dump::threads(),
+        }
+    }
+
+    /// Log a snapshot of the daemon's internal state and write it to
+    /// the runtime dir, for SIGUSR1.
+    pub fn dump_state_to_file(&self) -> anyhow::Result<()> {
+        let state =
+            serde_json::to_string_pretty(&self.dump_state()).context("serializing state dump")?;
+        info!("state dump: {}", state);
+        let path = state_dump::write(&self.runtime_dir, &state)?;
+        info!("wrote state dump to {:?}", path);
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_dump_state(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let state =
+            serde_json::to_string_pretty(&self.dump_state()).context("serializing state dump")?;
+        write_reply(&mut stream, DumpStateReply { state }).context("writing dump state reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_watch(&self, mut stream: UnixStream, request: WatchRequest) -> anyhow::Result<()> {
+        if let Some(pattern) = &request.pattern
+            && let Err(e) = watch::compile(pattern)
+        {
+            write_reply(&mut stream, WatchReply::BadPattern(format!("{e:#}")))
+                .context("writing watch reply")?;
+            return Ok(());
+        }
+
+        let reply = match self.shells.shard(&request.session).get(&request.session) {
+            // a watch's exec runs as the session's owner
+            Some(session) if session.is_locked(self.configured_auto_lock()) => WatchReply::Locked,
+            Some(session) => {
+                let added = session.watches.edit(|added| {
+                    if request.clear {
+                        info!("clearing watches");
+                        added.clear();
+                    }
+                    if let Some(pattern) = request.pattern {
+                        info!("watching for '{}'", pattern);
+                        added.push(config::Watch { session: None, pattern, exec: request.exec });
+                    }
+                    added.clone()
+                });
+                let added = added.into_iter().map(|w| Watch {
+                    pattern: w.pattern,
+                    exec: w.exec,
+                    from_config: false,
+                });
+                let config = self.config.get();
+                let configured =
+                    config.watch.iter().flatten().filter(|w| w.applies_to(&request.session));
+                let watches = added
+                    .chain(configured.map(|w| Watch {
+                        pattern: w.pattern.clone(),
+                        exec: w.exec.clone(),
+                        from_config: true,
+                    }))
+                    .collect();
+                WatchReply::Watches(watches)
+            }
+            None => WatchReply::NotFound,
+        };
+        write_reply(&mut stream, reply).context("writing watch reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_note(&self, mut stream: UnixStream, request: NoteRequest) -> anyhow::Result<()> {
+        let reply = match self.shells.shard(&request.session).get_mut(&request.session) {
+            Some(session) => {
+                info!("updating note");
+                if let (Some(markers), Some(note)) = (&session.log_markers, &request.note) {
+                    // the logger only goes away along with the session
+                    let _ = markers.send(note.clone());
+                }
+                session.note = request.note;
+                NoteReply::Noted
+            }
+            None => NoteReply::NotFound,
+        };
+        write_reply(&mut stream, reply).context("writing note reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_lock(&self, mut stream: UnixStream, request: LockRequest) -> anyhow::Result<()> {
+        // Hashing is slow on purpose, so get it done before taking
+        // the shells lock.
+        let new_lock = match &request.passphrase {
+            Some(passphrase) if !request.remove => Some(lock::Lock::new(passphrase)?),
+            _ => None,
+        };
+
+        let checked_passphrase = match &request.passphrase {
+            Some(passphrase) if request.remove => {
+                self.check_passphrase(&request.session, passphrase, false)
+            }
+            _ => None,
+        };
+
+        let reply = {
+            let mut shells = self.shells.shard(&request.session);
+            match shells.get_mut(&request.session) {
+                None => LockReply::NotFound,
+                Some(session) if request.remove => match (&mut session.lock, &checked_passphrase) {
+                    (None, _) => LockReply::Removed,
+                    (Some(lock), Some((checked, true))) if lock.same_passphrase(checked) => {
+                        info!("removing lock");
+                        session.lock = None;
+                        LockReply::Removed
+                    }
+                    (Some(_), _) => LockReply::WrongPassphrase,
+                },
+                Some(session) => match (&mut session.lock, new_lock) {
+                    (Some(lock), _) => {
+                        info!("locking");
+                        lock.lock();
+                        LockReply::Locked
+                    }
+                    (None, Some(new_lock)) => {
+                        info!("locking with a new passphrase");
+                        session.lock = Some(new_lock);
+                        LockReply::Locked
+                    }
+                    (None, None) => LockReply::NeedPassphrase,
+                },
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing lock reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_exec(&self, mut stream: UnixStream, request: ExecRequest) -> anyhow::Result<()> {
+        let wants_output = request.until.is_some() || request.timeout_ms.is_some();
+        let (mut pty_master, output) = {
+            let shells = self.shells.shard(&request.session);
+            let Some(session) = shells.get(&request.session) else {
+                write_reply(&mut stream, ExecReply::NotFound).context("writing exec reply")?;
+                return Ok(());
+            };
+            if session.is_locked(self.configured_auto_lock()) {
+                write_reply(&mut stream, ExecReply::Locked).context("writing exec reply")?;
+                return Ok(());
+            }
+            // Subscribe before sending the input so we can't miss
+            // any of the output it produces.
+            (session.pty_master, if wants_output { Some(session.output_taps.add()) } else { None })
+        };
+
+        info!("sending {} bytes of input", request.input.len());
+        pty_master.write_all(&request.input).context("writing exec input")?;
+        pty_master.flush().context("flushing exec input")?;
+        write_reply(&mut stream, ExecReply::Sent).context("writing exec reply")?;
+
+        let Some(output) = output else {
+            return Ok(());
+        };
+        let timeout = request.timeout_ms.map(Duration::from_millis).unwrap_or(EXEC_DEFAULT_TIMEOUT);
+        let deadline = Instant::now() + timeout;
+        let mut scanner =
+            request.until.as_deref().map(|until| exec_marker::Scanner::new(until, &request.input));
+        let mut status: i32 = if scanner.is_some() { 1 } else { 0 };
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let buf = match output.recv_timeout(remaining) {
+                Ok(buf) => buf,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    info!("exec timed out");
+                    break;
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    info!("session went away during exec");
+                    break;
+                }
+            };
+            Chunk { kind: ChunkKind::Data, buf: &buf }
+                .write_to(&mut stream)
+                .context("writing exec output")?;
+
+            if let Some(scanner) = scanner.as_mut()
+                && scanner.feed(&buf)
+            {
+                status = 0;
+                break;
+            }
+        }
+
+        Chunk { kind: ChunkKind::ExitStatus, buf: &status.to_le_bytes() }
+            .write_to(&mut stream)
+            .context("writing exec status")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_pipe(&self, mut stream: UnixStream, request: PipeRequest) -> anyhow::Result<()> {
+        // The pty gets duplicated while we still hold the shard so that
+        // the fd can't get closed and reused out from under us if the
+        // session goes away part way through.
+        let (mut pty_master, child_exit_notifier) = {
+            let shells = self.shells.shard(&request.session);
+            let status = match shells.get(&request.session) {
+                None => Err(PipeReply::NotFound),
+                Some(session) if session.exited.is_some() => Err(PipeReply::Exited),
+                Some(session) if session.is_locked(self.configured_auto_lock()) => {
+                    Err(PipeReply::Locked)
+                }
+                Some(session) => match Self::dup_pty_master(session) {
+                    Ok(pty) => Ok((fs::File::from(pty), Arc::clone(&session.child_exit_notifier))),
+                    Err(e) => Err(PipeReply::Failed(format!("{e:#}"))),
+                },
+            };
+            match status {
+                Ok(pipe_to) => pipe_to,
+                Err(reply) => {
+                    write_reply(&mut stream, reply).context("writing pipe reply")?;
+                    return Ok(());
+                }
+            }
+        };
+        write_reply(&mut stream, PipeReply::Ready).context("writing pipe reply")?;
+
+        // Writes to the pty block once the shell falls behind on reading
+        // its input, which pushes back on the client rather than having
+        // us buffer up everything it sends.
+        let mut buf = vec![0; consts::BUF_SIZE];
+        let mut bytes = 0;
+        stream.set_read_timeout(Some(PIPE_POLL_DUR)).context("setting pipe read timeout")?;
+        let reply = loop {
+            if child_exit_notifier.wait(Some(Duration::ZERO)).is_some() {
+                info!("session exited after {} bytes of piped input", bytes);
+                break PipeReply::Exited;
+            }
+            let len = match stream.read(&mut buf) {
+                Ok(0) => break PipeReply::Done { bytes },
+                Ok(len) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e).context("reading pipe input"),
+            };
+            if let Err(e) = pty_master.write_all(&buf[..len]).and_then(|_| pty_master.flush()) {
+                warn!("writing pipe input after {} bytes: {:?}", bytes, e);
+                break PipeReply::Failed(format!("writing to the session: {e}"));
+            }
+            bytes += len as u64;
+        };
+        info!("piped {} bytes of input", bytes);
+        write_reply(&mut stream, reply).context("writing pipe reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_capture(
+        &self,
+        mut stream: UnixStream,
+        request: CaptureRequest,
+    ) -> anyhow::Result<()> {
+        let shell_to_client_ctl = {
+            let shells = self.shells.shard(&request.session);
+            match shells.get(&request.session) {
+                Some(session) if session.is_locked(self.configured_auto_lock()) => {
+                    write_reply(&mut stream, CaptureReply::Locked)
+                        .context("writing capture reply")?;
+                    return Ok(());
+                }
+                // the shell->client thread is gone along with the shell
+                Some(session) if let Some(exited) = &session.exited => {
+                    let data =
+                        if request.scrollback { &exited.scrollback } else { &exited.restore };
+                    write_reply(&mut stream, CaptureReply::Captured { data: data.clone() })
+                        .context("writing capture reply")?;
+                    return Ok(());
+                }
+                Some(session) => Arc::clone(&session.shell_to_client_ctl),
+                None => {
+                    write_reply(&mut stream, CaptureReply::NotFound)
+                        .context("writing capture reply")?;
+                    return Ok(());
+                }
+            }
+        };
+
+        let kind = if request.scrollback {
+            shell::CaptureKind::Scrollback
+        } else {
+            shell::CaptureKind::Restore
+        };
+        let data = capture(&shell_to_client_ctl, kind)?;
+
+        write_reply(&mut stream, CaptureReply::Captured { data })
+            .context("writing capture reply")?;
+
+        Ok(())
+    }
+
+    /// Stream a session's restore buffer back, followed by its live
+    /// output if the client wants to follow along.
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_tail(&self, mut stream: UnixStream, request: TailRequest) -> anyhow::Result<()> {
+        let (exited, shell_to_client_ctl, output, child_exit_notifier) = {
+            let shells = self.shells.shard(&request.session);
+            match shells.get(&request.session) {
+                Some(session) if session.is_locked(self.configured_auto_lock()) => {
+                    write_reply(&mut stream, TailReply::Locked).context("writing tail reply")?;
+                    return Ok(());
+                }
+                Some(session) => (
+                    session.exited.as_ref().map(|e| (e.restore.clone(), e.exit_status)),
+                    Arc::clone(&session.shell_to_client_ctl),
+                    // Subscribe before capturing so nothing falls in
+                    // between.
+                    request.follow.then(|| session.output_taps.add()),
+                    Arc::clone(&session.child_exit_notifier),
+                ),
+                None => {
+                    write_reply(&mut stream, TailReply::NotFound).context("writing tail reply")?;
+                    return Ok(());
+                }
+            }
+        };
+        write_reply(&mut stream, TailReply::Streaming).context("writing tail reply")?;
+
+        // the shell->client thread is gone along with the shell
+        let (restore, exit_status) = match exited {
+            Some((restore, exit_status)) => (restore, Some(exit_status)),
+            None => (capture(&shell_to_client_ctl, shell::CaptureKind::Restore)?, None),
+        };
+        for buf in restore.chunks(consts::BUF_SIZE) {
+            Chunk { kind: ChunkKind::Data, buf }
+                .write_to(&mut stream)
+                .context("writing restore buffer")?;
+        }
+
+        let status = match (output, exit_status) {
+            (None, _) => 0,
+            (Some(_), Some(exit_status)) => exit_status,
+            (Some(output), None) => {
+                match follow_output(&mut stream, &output, &child_exit_notifier)? {
+                    Some(status) => status,
+                    None => {
+                        info!("client stopped following");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        Chunk { kind: ChunkKind::ExitStatus, buf: &status.to_le_bytes() }
+            .write_to(&mut stream)
+            .context("writing tail status")?;
+
+        Ok(())
+    }
+
+    /// Hold on to the connection until the thing the client is waiting
+    /// for happens, then reply. We don't keep the session table locked
+    /// while waiting, so everything we need gets cloned out up front.
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_wait(&self, mut stream: UnixStream, request: WaitRequest) -> anyhow::Result<()> {
+        let (child_exit_notifier, activity) = {
+            let shells = self.shells.shard(&request.session);
+            match shells.get(&request.session) {
+                Some(session) if let Some(exited) = &session.exited => {
+                    let exit_status = exited.exit_status;
+                    write_reply(&mut stream, WaitReply::Exited { exit_status })
+                        .context("writing wait reply")?;
+                    return Ok(());
+                }
+                Some(session) => {
+                    (Arc::clone(&session.child_exit_notifier), Arc::clone(&session.activity))
+                }
+                None => {
+                    write_reply(&mut stream, WaitReply::NotFound).context("writing wait reply")?;
+                    return Ok(());
+                }
+            }
+        };
+        info!("waiting for {:?}", request.until);
+
+        let reply = loop {
+            if let Some(exit_status) = child_exit_notifier.wait(Some(WAIT_POLL_DUR)) {
+                break WaitReply::Exited { exit_status };
+            }
+            match request.until {
+                WaitFor::Activity if activity.load(Ordering::Relaxed) => break WaitReply::Activity,
+                WaitFor::Activity | WaitFor::Exit => {}
+            }
+            // The client never sends anything after the header, so the
+            // stream only becomes readable once it hangs up.
+            if shell::input_pending(&stream, 0) {
+                info!("client gave up waiting");
+                return Ok(());
+            }
+        };
+
+        info!("done waiting: {:?}", reply);
+        write_reply(&mut stream, reply).context("writing wait reply")?;
+        Ok(())
+    }
+
+    /// Speak line-delimited JSON with a control mode client until it
+    /// hangs up. Requests get read on their own thread and handed over
+    /// to this one, which does all the writing.
+    #[instrument(skip_all)]
+    fn handle_control(
+        &self,
+        stream: UnixStream,
+        conn_id: usize,
+        header: ControlHeader,
+    ) -> anyhow::Result<()> {
+        let mut reader = io::BufReader::new(stream.try_clone().context("cloning control stream")?);
+        let mut writer = io::BufWriter::new(stream.try_clone().context("cloning control stream")?);
+        let (requests_tx, requests_rx) = crossbeam_channel::unbounded();
+
+        thread::scope(|s| -> anyhow::Result<()> {
+            thread::Builder::new()
+                .name(format!("control:{conn_id}"))
+                .spawn_scoped(s, move || {
+                    while let Ok(Some(line)) = control::read_line(&mut reader) {
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        let request = serde_json::from_str::<ControlRequest>(&line)
+                            .map_err(|e| format!("parsing request: {e}"));
+                        if requests_tx.send(request).is_err() {
+                            return;
+                        }
+                    }
+                })
+                .context("spawning control reader")?;
+
+            let owner = peer_uid(&stream);
+            let res = self.control_loop(&mut writer, conn_id, owner, &header, requests_rx);
+            // Unblock the reader thread if it is still waiting on the client.
+            let _ = stream.shutdown(net::Shutdown::Both);
+            res
+        })
+    }
+
+    fn control_loop<W: Write>(
+        &self,
+        writer: &mut W,
+        conn_id: usize,
+        owner: u32,
+        header: &ControlHeader,
+        requests: crossbeam_channel::Receiver<Result<ControlRequest, String>>,
+    ) -> anyhow::Result<()> {
+        let mut watcher = control::Watcher::default();
+        let mut proxies: HashMap<String, control::Proxy> = HashMap::new();
+        let mut last_poll: Option<Instant> = None;
+        loop {
+            if last_poll.is_none_or(|t| t.elapsed() >= control::POLL_DUR) {
+                let sessions = self.control_snapshot();
+                for event in watcher.poll(sessions, |name| proxies.contains_key(name)) {
+                    control::write_event(writer, &event)?;
+                }
+                last_poll = Some(Instant::now());
+            }
+
+            // Sleep until there is a request or some output to pass
+            // along, or it is time to look over the sessions again.
+            let mut sel = crossbeam_channel::Select::new();
+            sel.recv(&requests);
+            for proxy in proxies.values() {
+                sel.recv(&proxy.output);
+            }
+            let _ = sel.ready_timeout(control::POLL_DUR);
+
+            match requests.try_recv() {
+                Ok(Ok(request)) => {
+                    info!("control request: {:?}", request);
+                    let id = request.id;
+                    let event = match self.control_command(
+                        request.command,
+                        conn_id,
+                        owner,
+                        header,
+                        &mut proxies,
+                    ) {
+                        Ok(output) => {
+                            if let Some(output) = output {
+                                control::write_event(writer, &output)?;
+                            }
+                            ControlEvent::Done { id }
+                        }
+                        Err(e) => ControlEvent::Error { id, message: format!("{e:#}") },
+                    };
+                    control::write_event(writer, &event)?;
+                }
+                Ok(Err(message)) => {
+                    control::write_event(writer, &ControlEvent::Error { id: None, message })?;
+                }
+                Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                    info!("control client hung up");
+                    return Ok(());
+                }
+                Err(crossbeam_channel::TryRecvError::Empty) => {}
+            }
+
+            let mut ended = vec![];
+            for (name, proxy) in proxies.iter_mut() {
+                loop {
+                    match proxy.output.try_recv() {
+                        Ok(buf) => {
+                            let data = proxy.decoder.decode(&buf);
+                            if !data.is_empty() {
+                                let session = name.clone();
+                                control::write_event(
+                                    writer,
+                                    &ControlEvent::Output { session, data },
+                                )?;
+                            }
+                        }
+                        Err(crossbeam_channel::TryRecvError::Empty) => break,
+                        Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                            ended.push(name.clone());
+                            break;
+                        }
+                    }
+                }
+            }
+            for name in ended {
+                proxies.remove(&name);
+                // If the session is gone, the client hears about it
+                // from the watcher, otherwise the tap fell behind.
+                if self.shells.shard(&name).get(&name).is_some() {
+                    let message =
+                        format!("fell behind on output from '{name}', no longer proxying it");
+                    control::write_event(writer, &ControlEvent::Error { id: None, message })?;
+                }
+            }
+        }
+    }
+
+    /// Carry out a control mode command, returning an event to send
+    /// before the one saying it is done, if there is one.
+    fn control_command(
+        &self,
+        command: ControlCommand,
+        conn_id: usize,
+        owner: u32,
+        header: &ControlHeader,
+        proxies: &mut HashMap<String, control::Proxy>,
+    ) -> anyhow::Result<Option<ControlEvent>> {
+        match command {
+            ControlCommand::Create { session, command, dir } => {
+                // Go through the same path as `shpool attach --detached`,
+                // answering ourselves.
+                let (mut ours, theirs) = UnixStream::pair().context("creating socket pair")?;
+                self.attach(
+                    theirs,
+                    conn_id,
+                    owner,
+                    AttachHeader {
+                        name: session.clone(),
+                        local_env: header.local_env.clone(),
+                        cmd: command,
+                        working_directory: dir,
+                        detached: true,
+                        ..Default::default()
+                    },
+                )?;
+                let reply: AttachReplyHeader =
+                    protocol::decode_from(&mut ours).context("reading create reply")?;
+                match reply.status {
+                    AttachStatus::Created { .. } => Ok(None),
+                    AttachStatus::Attached { .. } => Err(anyhow!("'{}' already exists", session)),
+                    AttachStatus::LimitReached(reason) => {
+                        Err(anyhow!("creating '{}': {}", session, reason))
+                    }
+                    status => Err(anyhow!("creating '{}': {:?}", session, status)),
+                }
+            }
+            ControlCommand::AttachProxy { session } => {
+                if proxies.contains_key(&session) {
+                    return Err(anyhow!("already proxying '{}'", session));
+                }
+                let (output, shell_to_client_ctl) = {
+                    let shells = self.shells.shard(&session);
+                    match shells.get(&session) {
+                        Some(s) if s.exited.is_some() => {
+                            return Err(anyhow!("'{}' has exited", session));
+                        }
+                        Some(s) if s.is_locked(self.configured_auto_lock()) => {
+                            return Err(anyhow!("'{}' is locked", session));
+                        }
+                        // Subscribe before capturing so nothing falls
+                        // in between.
+                        Some(s) => (s.output_taps.add(), Arc::clone(&s.shell_to_client_ctl)),
+                        None => return Err(anyhow!("no session named '{}'", session)),
+                    }
+                };
+                let restore = capture(&shell_to_client_ctl, shell::CaptureKind::Restore)?;
+                let mut proxy = control::Proxy { output, decoder: Default::default() };
+                let data = proxy.decoder.decode(&restore);
+                proxies.insert(session.clone(), proxy);
+                Ok(Some(ControlEvent::Output { session, data }))
+            }
+            ControlCommand::DetachProxy { session } => match proxies.remove(&session) {
+                Some(_) => Ok(None),
+                None => Err(anyhow!("not proxying '{}'", session)),
+            },
+            ControlCommand::SendInput { session, data } => {
+                let mut pty_master = {
+                    let shells = self.shells.shard(&session);
+                    match shells.get(&session) {
+                        Some(s) if s.exited.is_some() => {
+                            return Err(anyhow!("'{}' has exited", session));
+                        }
+                        Some(s) if s.is_locked(self.configured_auto_lock()) => {
+                            return Err(anyhow!("'{}' is locked", session));
+                        }
+                        Some(s) => s.pty_master,
+                        None => return Err(anyhow!("no session named '{}'", session)),
+                    }
+                };
+                pty_master.write_all(data.as_bytes()).context("writing input")?;
+                pty_master.flush().context("flushing input")?;
+                Ok(None)
+            }
+            ControlCommand::Resize { session, rows, cols } => {
+                let shell_to_client_ctl = {
+                    let shells = self.shells.shard(&session);
+                    match shells.get(&session) {
+                        Some(s) if s.exited.is_some() => {
+                            return Err(anyhow!("'{}' has exited", session));
+                        }
+                        Some(s) => Arc::clone(&s.shell_to_client_ctl),
+                        None => return Err(anyhow!("no session named '{}'", session)),
+                    }
+                };
+                let shell_to_client_ctl = shell_to_client_ctl.lock().unwrap();
+                shell_to_client_ctl
+                    .tty_size_change
+                    .send_timeout(
+                        shell::SizeChange {
+                            size: TtySize { rows, cols, xpixel: 0, ypixel: 0 },
+                            client_pid: None,
+                        },
+                        SESSION_MSG_TIMEOUT,
+                    )
+                    .context("sending tty size change to shell->client")?;
+                shell_to_client_ctl
+                    .tty_size_change_ack
+                    .recv_timeout(SESSION_MSG_TIMEOUT)
+                    .context("recving tty size ack")?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// What a control connection needs to know about every session.
+    fn control_snapshot(&self) -> Vec<control::Snapshot> {
+        let mut sessions = vec![];
+        for shard in self.shells.shards() {
+            for (name, session) in shard.iter() {
+                sessions.push(control::Snapshot {
+                    name: name.to_string(),
+                    size: session.pty_size.lock().unwrap().clone(),
+                    last_active: session.last_active.load(Ordering::Relaxed),
+                    exit_status: session.exited.as_ref().map(|e| e.exit_status),
+                    // an attached client holds the lock on inner
+                    attached: session.inner.try_lock().is_err(),
+                    child_exit_notifier: Arc::clone(&session.child_exit_notifier),
+                });
+            }
+        }
+        sessions
+    }
+
+    /// Hand our listening socket and all our sessions over to a new
+    /// daemon, then exit. We hold every shard of the session table
+    /// for the whole handover so that no sessions get created or
+    /// killed out from under us. If anything goes wrong we just
+    /// keep running, since the shells are still ours.
+    #[instrument(skip_all)]
+    fn handle_takeover(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        takeover::check_peer(&stream)?;
+        let listener_fd =
+            self.listener_fd.lock().unwrap().ok_or(anyhow!("not listening on a socket"))?;
+
+        let shards: Vec<_> = self.shells.shards().collect();
+        let mut state = takeover::State::default();
+        let mut fds = vec![listener_fd];
+        for shard in shards.iter() {
+            for (name, session) in shard.iter() {
+                if session.child_exit_notifier.wait(Some(Duration::ZERO)).is_some() {
+                    continue;
+                }
+                let pty_fd =
+                    session.pty_master.raw_fd().ok_or(anyhow!("no pty fd for '{}'", name))?;
+                state.sessions.push(Self::hand_over_state(name, session)?);
+                fds.push(pty_fd);
+            }
+        }
+
+        info!("handing {} sessions over to a new daemon", state.sessions.len());
+        takeover::hand_over(&mut stream, &state, &fds)?;
+        info!("handover done, exiting");
+        process::exit(0);
+    }
+
+    /// Detach any client from a session that is about to be handed
+    /// over to another daemon and snapshot everything the other daemon
+    /// needs to know about it.
+    fn hand_over_state(
+        name: &str,
+        session: &shell::Session,
+    ) -> anyhow::Result<takeover::SessionState> {
+        let restore_buffer = {
+            let _s = span!(Level::INFO, "takeover_lock(shell_to_client_ctl)").entered();
+            let shell_to_client_ctl = session.shell_to_client_ctl.lock().unwrap();
+            shell_to_client_ctl
+                .client_connection
+                .send_timeout(shell::ClientConnectionMsg::Disconnect, SESSION_MSG_TIMEOUT)
+                .context("sending client detach to shell->client")?;
+            shell_to_client_ctl
+                .client_connection_ack
+                .recv_timeout(SESSION_MSG_TIMEOUT)
+                .context("getting client conn ack")?;
+            shell_to_client_ctl
+                .capture
+                .send_timeout(shell::CaptureKind::Contents, SESSION_MSG_TIMEOUT)
+                .context("sending capture request to shell->client")?;
+            shell_to_client_ctl
+                .capture_ack
+                .recv_timeout(SESSION_MSG_TIMEOUT)
+                .context("recving capture")?
+        };
+
+        let started_at_unix_ms = session
+            .started_at
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        Ok(takeover::SessionState {
+            name: String::from(name),
+            tags: session.tags.clone(),
+            started_at_unix_ms,
+            child_pid: session.child_pid,
+            tty_size: session.pty_size.lock().unwrap().clone(),
+            attach_count: session.attach_count,
+            restore_buffer,
+            recipe: session.recipe.clone(),
+            lock: session.lock.clone(),
+            note: session.note.clone(),
+            watches: session.watches.added.lock().unwrap().clone(),
+        })
+    }
+
+    /// Move a session over to the daemon listening on another socket.
+    /// The session gets snapshotted with its shard held, but the shard
+    /// is let go before dialing the other daemon so that a slow or
+    /// wedged one can't hold up the rest of the sessions in the shard.
+    /// If the other daemon doesn't adopt it, the session stays here.
+    #[instrument(skip_all, fields(s = &request.session))]
+    fn handle_migrate(
+        &self,
+        mut stream: UnixStream,
+        request: MigrateRequest,
+    ) -> anyhow::Result<()> {
+        takeover::check_peer(&stream)?;
+        let snapshot = {
+            let shells = self.shells.shard(&request.session);
+            match shells.get(&request.session) {
+                None => Err(MigrateReply::NotFound),
+                Some(session)
+                    if session.exited.is_some()
+                        || session.child_exit_notifier.wait(Some(Duration::ZERO)).is_some() =>
+                {
+                    Err(MigrateReply::Exited)
+                }
+                Some(session) if session.is_locked(self.configured_auto_lock()) => {
+                    Err(MigrateReply::Locked)
+                }
+                Some(session) => Self::migration_snapshot(&request.session, session)
+                    .map(|(state, pty)| (state, pty, Arc::clone(&session.handed_off)))
+                    .map_err(|e| {
+                        warn!("snapshotting session: {:?}", e);
+                        MigrateReply::Failed(format!("{e:#}"))
+                    }),
+            }
+        };
+
+        let reply = match snapshot {
+            Err(reply) => reply,
+            Ok((state, pty, handed_off)) => {
+                let child_pid = state.child_pid;
+                match migrate::send(Path::new(&request.to_socket), state, pty.as_raw_fd()) {
+                    Ok(()) => {
+                        info!("migrated to {}", request.to_socket);
+                        // The other daemon runs the exit hooks from now on.
+                        handed_off.store(true, Ordering::Release);
+                        // Dropping the session stops its threads, and
+                        // closes our copy of the pty without touching
+                        // the shell. It might have been renamed or
+                        // replaced while we were busy, so make sure we
+                        // drop the right one.
+                        let mut shells = self.shells.shard(&request.session);
+                        if shells.get(&request.session).is_some_and(|s| s.child_pid == child_pid) {
+                            shells.remove(&request.session);
+                        }
+                        MigrateReply::Migrated
+                    }
+                    Err(e) => {
+                        warn!("migrating to {}: {:?}", request.to_socket, e);
+                        MigrateReply::Failed(format!("{e:#}"))
+                    }
+                }
+            }
+        };
+
+        write_reply(&mut stream, reply).context("writing migrate reply")?;
+        Ok(())
+    }
+
+    /// Everything the daemon adopting a session needs, along with our
+    /// own copy of its pty master, which stays good even if the session
+    /// goes away while the handover is in flight.
+    fn migration_snapshot(
+        name: &str,
+        session: &shell::Session,
+    ) -> anyhow::Result<(takeover::SessionState, OwnedFd)> {
+        let pty = Self::dup_pty_master(session)?;
+        Ok((Self::hand_over_state(name, session)?, pty))
+    }
+
+    /// A copy of the session's pty master that stays open even if the
+    /// session goes away, for use once the shard is unlocked.
+    fn dup_pty_master(session: &shell::Session) -> anyhow::Result<OwnedFd> {
+        let pty_fd = session.pty_master.raw_fd().ok_or(anyhow!("no pty fd"))?;
+        // Safety: the session, and so its pty master, is alive for as
+        // long as we have a reference to it.
+        unsafe { BorrowedFd::borrow_raw(pty_fd) }.try_clone_to_owned().context("duplicating pty fd")
+    }
+
+    /// Take on a session that another daemon is moving over to us with
+    /// `shpool migrate`.
+    #[instrument(skip_all)]
+    fn handle_adopt(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let (state, pty) = migrate::receive(&stream)?;
+        validate_session_name(&state.name)?;
+        // Like attach, hang on to the admission lock until the session
+        // is in the table so it can't take a slot someone else got.
+        let _admission_lock = self.admission.lock().unwrap();
+        limits::Limits::from_config(&self.config.get())
+            .admit(&self.shells, &state.name, peer_uid(&stream))
+            .map_err(|reason| anyhow!("refusing to adopt '{}': {}", state.name, reason))?;
+        info!("adopting '{}' from another daemon", state.name);
+        self.adopt(state, pty)?;
+        migrate::ack(&stream)
+    }
+
+    /// Hang up on every shell, give them until the grace period is up
+    /// to exit, kill the stragglers and then exit. The sessions get
+    /// written to the manifest first so that they can be resurrected.
+    #[instrument(skip_all)]
+    fn handle_stop(&self, mut stream: UnixStream, request: StopRequest) -> anyhow::Result<()> {
+        let grace = Duration::from_millis(request.grace_ms);
+        if self.stopping.swap(true, Ordering::Relaxed) {
+            info!("already stopping");
+            let reply = StopReply { exited: vec![], killed: vec![], already_stopping: true };
+            return write_reply(&mut stream, reply);
+        }
+        info!("stopping, giving shells {:?} to exit", grace);
+
+        let manifest = manifest::snapshot(&self.shells);
+        if let Err(e) = resurrect::save(&self.manifest_path, &manifest) {
+            warn!("writing final manifest: {:?}", e);
+        }
+
+        let mut shells = vec![];
+        for shard in self.shells.shards() {
+            for (name, session) in shard.iter() {
+                if session.exited.is_none() {
+                    shells.push((
+                        name.clone(),
+                        session.child_pid,
+                        Arc::clone(&session.child_exit_notifier),
+                    ));
+                }
+            }
+        }
+        for (name, child_pid, _) in shells.iter() {
+            // SIGHUP is how shells get told to shut down, see kill_shell.
+            // The SIGCONT is for shells stopped by their detach policy.
+            for sig in [signal::Signal::SIGHUP, signal::Signal::SIGCONT] {
+                if let Err(e) = signal::kill(Pid::from_raw(*child_pid), Some(sig)) {
+                    warn!("sending {} to '{}': {:?}", sig, name, e);
+                }
+            }
+        }
+
+        let deadline = time::Instant::now() + grace;
+        let mut reply = StopReply { exited: vec![], killed: vec![], already_stopping: false };
+        for (name, child_pid, child_exit_notifier) in shells.into_iter() {
+            let left = deadline.saturating_duration_since(time::Instant::now());
+            if child_exit_notifier.wait(Some(left)).is_some() {
+                reply.exited.push(name);
+                continue;
+            }
+            info!("'{}' did not exit within the grace period, killing it", name);
+            if let Err(e) = signal::kill(Pid::from_raw(child_pid), Some(signal::Signal::SIGKILL)) {
+                warn!("killing '{}': {:?}", name, e);
+            }
+            reply.killed.push(name);
+        }
+
+        write_reply(&mut stream, reply)?;
+        let _ = stream.shutdown(net::Shutdown::Both);
+
+        // The term signal handler cleans up the socket and pid file on
+        // its way out, same as if someone had run kill on us.
+        info!("stop done, exiting");
+        signal::raise(signal::Signal::SIGTERM).context("raising SIGTERM")?;
+        Ok(())
+    }
+
+    /// The output log for a new session, if it should have one.
+    fn output_log(&self, header: &AttachHeader) -> anyhow::Result<Option<output_log::OutputLog>> {
+        let config = self.config.get().output_log.clone().unwrap_or_default();
+        let Some(dir) = header.log_output.clone().or(config.dir) else {
+            return Ok(None);
+        };
+        let max_size = match &config.max_size {
+            Some(src) => size::parse(src).context("parsing output_log.max_size")? as u64,
+            None => output_log::DEFAULT_MAX_SIZE,
+        };
+        // recordings need some size, so guess one for clients that
+        // don't have a terminal
+        let size = if header.local_tty_size.rows == 0 || header.local_tty_size.cols == 0 {
+            TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 }
+        } else {
+            header.local_tty_size.clone()
+        };
+        Ok(Some(output_log::OutputLog::new(
+            PathBuf::from(dir),
+            header.name.clone(),
+            max_size,
+            config.keep.unwrap_or(output_log::DEFAULT_KEEP),
+            config.format.unwrap_or_default(),
+            size,
+        )))
+    }
+
+    /// Move a session's runtime dir over to its new name. The shell
+    /// has the old path baked into its environment (through the
+    /// forwarded socket symlinks and SHPOOL_SESSION_DIR), so we leave
+    /// a symlink behind to keep those working.
+    fn move_session_dir(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let from_dir = self.session_dir(from);
+        let to_dir = self.session_dir(to);
+        if !from_dir.exists() {
+            return Ok(());
+        }
+        // No live session has the new name, so anything there is stale.
+        if let Ok(meta) = fs::symlink_metadata(&to_dir) {
+            if meta.is_symlink() {
+                fs::remove_file(&to_dir).context("removing stale session symlink")?;
+            } else {
+                fs::remove_dir_all(&to_dir).context("removing stale session dir")?;
+            }
+        }
+        fs::rename(&from_dir, &to_dir).context("renaming session dir")?;
+        os::unix::fs::symlink(to, &from_dir).context("linking old session dir")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_list(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let _s = span!(Level::INFO, "lock(shells)").entered();
+
+        let auto_lock_after = self.configured_auto_lock();
+        let mut sessions = vec![];
+        for shard in self.shells.shards() {
+            for (k, v) in shard.iter() {
+                let (status, client_tty) = match v.inner.try_lock() {
+                    Ok(_) => (SessionStatus::Disconnected, None),
+                    Err(_) => (SessionStatus::Attached, v.client_tty.clone()),
+                };
+
+                sessions.push(Session {
+                    name: k.to_string(),
+                    started_at_unix_ms: v
+                        .started_at
+                        .duration_since(time::UNIX_EPOCH)
+                        .context("collecting running session metadata")?
+                        .as_millis() as i64,
+                    status,
+                    pid: v.child_pid,
+                    tags: v.tags.clone(),
+                    tty_size: v.pty_size.lock().unwrap().clone(),
+                    exit_status: v.exited.as_ref().map(|e| e.exit_status),
+                    throttled: v.throttled.load(Ordering::Relaxed),
+                    activity: v.activity.load(Ordering::Relaxed),
+                    bell: v.bell.load(Ordering::Relaxed),
+                    client_tty,
+                    last_active_unix_ms: v.last_active.load(Ordering::Relaxed),
+                    spool_bytes: v.spool_bytes.load(Ordering::Relaxed) as u64,
+                    bytes_in: v.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: v.bytes_out.load(Ordering::Relaxed),
+                    attach_count: v.attach_count as u64,
+                    last_attached_unix_ms: v.last_attached_at.map(shell::unix_ms),
+                    last_detached_unix_ms: v.last_detached_at.map(shell::unix_ms),
+                    last_detach_reason: v.last_detach_reason,
+                    locked: v.is_locked(auto_lock_after),
+                    cwd: v.cwd(),
+                    note: v.note.clone(),
+                    watch_matches: v.watches.matches.load(Ordering::Relaxed),
+                    last_watch_match: v.watches.last_match.lock().unwrap().clone(),
+                });
+            }
+        }
+
+        write_reply(&mut stream, ListReply { sessions })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = &header.session_name))]
+    fn handle_session_message(
+        &self,
+        mut stream: UnixStream,
+        header: SessionMessageRequest,
+    ) -> anyhow::Result<()> {
+        // create a slot to store our reply so we can do
+        // our IO without the lock held.
+        let reply = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            let shells = self.shells.shard(&header.session_name);
+            if let Some(session) = shells.get(&header.session_name) {
+                match header.payload {
+                    SessionMessageRequestPayload::Resize(resize_request) => {
+                        let _s = span!(Level::INFO, "lock(pager_ctl)").entered();
+                        let pager_ctl = session.pager_ctl.lock().unwrap();
+                        if let Some(pager_ctl) = pager_ctl.as_ref() {
+                            info!("resizing pager");
+                            pager_ctl
+                                .tty_size_change
+                                .send_timeout(resize_request.tty_size.clone(), SESSION_MSG_TIMEOUT)
+                                .context("sending tty size change to pager")?;
+                            pager_ctl
+                                .tty_size_change_ack
+                                .recv_timeout(SESSION_MSG_TIMEOUT)
+                                .context("recving tty size change ack from pager")?;
+                        } else {
+                            let _s =
+                                span!(Level::INFO, "resize_lock(shell_to_client_ctl)").entered();
+                            let shell_to_client_ctl = session.shell_to_client_ctl.lock().unwrap();
+                            shell_to_client_ctl
+                                .tty_size_change
+                                .send_timeout(
+                                    shell::SizeChange {
+                                        size: resize_request.tty_size,
+                                        client_pid: peer_pid(&stream),
+                                    },
+                                    SESSION_MSG_TIMEOUT,
+                                )
+                                .context("sending tty size change to shell->client")?;
+                            shell_to_client_ctl
+                                .tty_size_change_ack
                                 .recv_timeout(SESSION_MSG_TIMEOUT)
                                 .context("recving tty size ack")?;
                         }
@@ -675,6 +2502,18 @@ impl Server {
                         info!("detached session({}), status = {:?}", header.session_name, status);
                         SessionMessageReply::Detach(SessionMessageDetachReply::Ok)
                     }
+                    SessionMessageRequestPayload::Switch(switch_request) => {
+                        let _s = span!(Level::INFO, "switch_lock(shell_to_client_ctl)").entered();
+                        let current = session.name.lock().unwrap().clone();
+                        let reply = shell::switch_client(
+                            &session.shell_to_client_ctl,
+                            &session.switch,
+                            &current,
+                            &switch_request.target,
+                        )?;
+                        info!("switch '{}' to '{}': {:?}", current, switch_request.target, reply);
+                        reply
+                    }
                 }
             } else {
                 SessionMessageReply::NotFound
@@ -686,6 +2525,46 @@ impl Server {
         Ok(())
     }
 
+    /// The shell a new session should run. The header has already had
+    /// the session's own config applied, so after that it is just the
+    /// global setting and then the user's login shell.
+    fn session_shell(&self, header: &AttachHeader, user_info: &user::Info) -> String {
+        header
+            .shell
+            .clone()
+            .or_else(|| self.config.get().shell.clone())
+            .unwrap_or_else(|| user_info.default_shell.clone())
+    }
+
+    /// Fill in any per-session settings from the `[sessions.<name>]`
+    /// config table that the client did not explicitly ask for.
+    fn apply_session_config(&self, header: &mut AttachHeader) {
+        let config = self.config.get();
+        let Some(session_config) = config.session(&header.name) else {
+            return;
+        };
+        if header.cmd.is_none() {
+            header.cmd = session_config.cmd.clone();
+        }
+        if header.startup.is_none() {
+            header.startup = session_config.startup.clone();
+        }
+        if header.shell.is_none() {
+            header.shell = session_config.shell.clone();
+        }
+        if header.restart.is_none() {
+            header.restart = session_config.restart.clone();
+        }
+        if header.ttl_secs.is_none()
+            && let Some(src) = &session_config.ttl
+        {
+            match duration::parse(src) {
+                Ok(d) => header.ttl_secs = Some(d.as_secs()),
+                Err(e) => warn!("bad ttl for session '{}', ignoring: {:?}", header.name, e),
+            }
+        }
+    }
+
     /// Spawn a subshell and return the sessession descriptor for it. The
     /// session is wrapped in an Arc so the inner session can hold a Weak
     /// back-reference to the session.
@@ -693,17 +2572,22 @@ impl Server {
     fn spawn_subshell(
         &self,
         conn_id: usize,
-        client_stream: UnixStream,
+        client_stream: Option<UnixStream>,
         header: &AttachHeader,
         user_info: &user::Info,
         shell_env: &[(OsString, OsString)],
-        dump_motd_on_new_session: bool,
+        motd_dump: Option<show_motd::Vars>,
     ) -> anyhow::Result<shell::Session> {
-        let shell = if let Some(s) = &self.config.get().shell {
-            s.clone()
-        } else {
-            user_info.default_shell.clone()
-        };
+        // A renamed session leaves a symlink behind at its old session
+        // dir. Now that the name is being reused, the new session needs
+        // a dir of its own.
+        let session_dir = self.session_dir(&header.name);
+        if fs::symlink_metadata(&session_dir).map(|m| m.is_symlink()).unwrap_or(false) {
+            info!("removing leftover rename symlink {:?}", session_dir);
+            fs::remove_file(&session_dir).context("removing rename symlink")?;
+        }
+
+        let shell = self.session_shell(header, user_info);
         info!("user_info={:?}", user_info);
 
         // Build up the command we will exec while allocation is still chill.
@@ -716,9 +2600,30 @@ impl Server {
             if cmd_parts.is_empty() {
                 return Err(anyhow!("no command to run"));
             }
-            let mut cmd = process::Command::new(&cmd_parts[0]);
-            cmd.args(&cmd_parts[1..]);
-            cmd
+            let restart = match header.restart.as_deref().map(supervise::Policy::parse) {
+                Some(Ok(policy)) => Some(policy),
+                Some(Err(e)) => {
+                    warn!("not restarting the command of '{}': {:?}", header.name, e);
+                    None
+                }
+                None => None,
+            };
+            match restart {
+                Some(policy) => {
+                    // Run the command under `shpool supervise`, which
+                    // sticks around to restart it.
+                    let exe = env::current_exe().context("getting current executable path")?;
+                    let mut cmd = process::Command::new(exe);
+                    cmd.arg("supervise").arg("--restart").arg(policy.as_str()).arg("--");
+                    cmd.args(&cmd_parts);
+                    cmd
+                }
+                None => {
+                    let mut cmd = process::Command::new(&cmd_parts[0]);
+                    cmd.args(&cmd_parts[1..]);
+                    cmd
+                }
+            }
         } else {
             let mut cmd = process::Command::new(&shell);
             if self.config.get().norc.unwrap_or(false) {
@@ -749,35 +2654,13 @@ impl Server {
             .env_clear();
 
         let term = shell_env.iter().filter(|(k, _)| k == "TERM").map(|(_, v)| v).next();
-        cmd.envs(shell_env.to_vec());
-        let fallback_terminfo = || match termini::TermInfo::from_name("xterm") {
-            Ok(db) => Ok(db),
-            Err(err) => {
-                warn!("could not get xterm terminfo: {:?}", err);
-                let empty_db = io::Cursor::new(vec![]);
-                termini::TermInfo::parse(empty_db).context("getting terminfo db")
-            }
+        let colorterm = shell_env.iter().filter(|(k, _)| k == "COLORTERM").map(|(_, v)| v).next();
+        let session_term = SessionTerm {
+            term: term.map(|t| t.to_string_lossy().into_owned()),
+            colorterm: colorterm.map(|t| t.to_string_lossy().into_owned()),
         };
-        let term_db = Arc::new(if let Some(term) = &term {
-            match termini::TermInfo::from_name(term.to_string_lossy().as_ref())
-                .context("resolving terminfo")
-            {
-                Ok(ti) => ti,
-                Err(err) => {
-                    warn!("could not get terminfo for '{:?}': {:?}", term, err);
-                    fallback_terminfo()?
-                }
-            }
-        } else {
-            warn!("no $TERM, using default terminfo");
-            match termini::TermInfo::from_env() {
-                Ok(db) => db,
-                Err(err) => {
-                    warn!("could not get terminfo from env: {:?}", err);
-                    fallback_terminfo()?
-                }
-            }
-        });
+        cmd.envs(shell_env.to_vec());
+        let term_db = Arc::new(resolve_term_db(term.map(|t| t.as_os_str()))?);
 
         if header.cmd.is_none() {
             // spawn the shell as a login shell by setting
@@ -793,14 +2676,22 @@ impl Server {
             cmd.arg0(format!("-{shell_basename}"));
         };
 
+        let limits = match self.config.get().rlimits(&header.name) {
+            Some(config) => rlimits::Limits::new(&config).context("parsing rlimits")?,
+            None => rlimits::Limits::default(),
+        };
+
         let noecho = self.config.get().noecho.unwrap_or(false);
-        info!("about to fork subshell noecho={}", noecho);
+        info!("about to fork subshell noecho={} limits={:?}", noecho, limits);
         let mut fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
         if let Ok(slave) = fork.is_child() {
             if noecho
                 && let Some(fd) = slave.borrow_fd() {
                     tty::disable_echo(fd).context("disabling echo on pty")?;
                 }
+            if let Err(e) = limits.apply() {
+                eprintln!("shpool: could not apply rlimits: {e}");
+            }
             for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
                 let _ = nix::unistd::close(fd);
             }
@@ -826,7 +2717,11 @@ impl Server {
         let waitable_child_pid = fork.child_pid().ok_or(anyhow!("missing child pid"))?;
         let session_name = header.name.clone();
         let notifiable_child_exit_notifier = Arc::clone(&child_exit_notifier);
-        thread::spawn(move || {
+        let handed_off = Arc::new(AtomicBool::new(false));
+        let watcher_handed_off = Arc::clone(&handed_off);
+        let exit_hook_cmds = self.hook_cmds.clone();
+        let child_watcher_name = threads::name("wait", &session_name);
+        thread::Builder::new().name(child_watcher_name).spawn(move || {
             let _s = span!(Level::INFO, "child_watcher", s = session_name, cid = conn_id).entered();
 
             let mut err = None;
@@ -844,41 +2739,184 @@ impl Server {
                         _ => {
                             if libc::WIFEXITED(status) {
                                 unpacked_status = Some(libc::WEXITSTATUS(status));
+                            } else if libc::WIFSIGNALED(status) {
+                                // the same status a shell would report
+                                unpacked_status = Some(128 + libc::WTERMSIG(status));
                             }
                             break;
                         }
                     }
                 }
             }
-            if let Some(status) = unpacked_status {
+            let status = if let Some(status) = unpacked_status {
                 info!("child exited with status {}", status);
-                notifiable_child_exit_notifier.notify_exit(status);
+                status
             } else {
                 if let Some(e) = err {
                     info!("child exited without status, using 1: {:?}", e);
                 } else {
                     info!("child exited without status, using 1");
                 }
-                notifiable_child_exit_notifier.notify_exit(1);
+                1
+            };
+            notifiable_child_exit_notifier.notify_exit(status);
+            if !watcher_handed_off.load(Ordering::Acquire) {
+                exit_hook_cmds.fire_exit(&session_name, waitable_child_pid, status);
             }
-        });
+        }).context("spawning child watcher thread")?;
 
         // Inject the prompt prefix, if any. For custom commands, avoid doing this
         // since we have no idea what the command is so the shell code probably won't
         // work.
-        if header.cmd.is_none() {
+        let has_prompt_prefix = self.write_prompt_prefix(&header.name, 1).unwrap_or_else(|e| {
+            // still inject the hooks, since the shell->client thread will
+            // be waiting on the prompt sentinel
+            warn!("writing prompt prefix: {:?}", e);
+            true
+        });
+        if header.cmd.is_none() && has_prompt_prefix {
             info!("injecting prompt prefix");
-            let prompt_prefix = self
-                .config
-                .get()
-                .prompt_prefix
-                .clone()
-                .unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
-            if let Err(err) = prompt::maybe_inject_prefix(&mut fork, &prompt_prefix, &header.name) {
+            let config = self.config.get();
+            let skip_shells = config.prompt_prefix_skip_shells.clone().unwrap_or_default();
+            let hooks = config.prompt_hooks.clone().unwrap_or_default();
+            if let Err(err) = prompt::maybe_inject_prefix(
+                &mut fork,
+                &self.session_dir(&header.name),
+                &skip_shells,
+                &hooks,
+            ) {
                 warn!("issue injecting prefix: {:?}", err);
             }
         }
 
+        // Type in the session's startup command, if it has one. The shell
+        // picks it up once it is ready to read input, so there is no need
+        // to wait for it.
+        if header.cmd.is_none()
+            && let Some(startup) = &header.startup
+        {
+            info!("typing startup command");
+            let mut pty_master = fork.is_parent().context("expected parent")?;
+            pty_master
+                .write_all(format!("{startup}\n").as_bytes())
+                .context("writing startup command")?;
+        }
+
+        let restore_config = header
+            .restore_override
+            .clone()
+            .unwrap_or_else(|| self.session_restore_config(&header.name));
+        let idle_ttl =
+            header.idle_ttl_secs.map(Duration::from_secs).or_else(|| self.configured_idle_ttl());
+
+        let mut session = self.start_session(SessionParts {
+            name: header.name.clone(),
+            conn_id,
+            fork,
+            child_exit_notifier,
+            handed_off,
+            client_stream,
+            term_db,
+            initial_motd: motd_dump,
+            custom_cmd: header.cmd.is_some(),
+            tty_size: header.local_tty_size.clone(),
+            restore_config,
+            idle_ttl,
+            tags: header.tags.clone(),
+            recipe: resurrect::Recipe::from_header(header),
+            adopted: None,
+        })?;
+        session.term = session_term;
+
+        if let Some(log) = self.output_log(header)? {
+            session.log_markers = Some(output_log::spawn(&session.output_taps, log)?);
+        }
+
+        if let Some(ttl_secs) = header.ttl_secs {
+            info!("registering session with ttl with the reaper");
+            self.register_new_reapable_session
+                .send(ttl_reaper::Msg::Reap(
+                    header.name.clone(),
+                    Instant::now().add(Duration::from_secs(ttl_secs)),
+                ))
+                .context("sending reapable session registration msg")?;
+        }
+
+        Ok(session)
+    }
+
+    /// Take on a session that a previous daemon handed over to us,
+    /// along with the pty master for its shell.
+    #[instrument(skip_all, fields(s = state.name))]
+    pub fn adopt(&self, state: takeover::SessionState, pty: OwnedFd) -> anyhow::Result<()> {
+        // Hold our shard from the check until the insert, so that an
+        // attach can't create a session of the same name in between
+        // and then get clobbered.
+        let mut shells = self.shells.shard(&state.name);
+        if shells.contains_key(&state.name) {
+            return Err(anyhow!("there is already a session called '{}'", state.name));
+        }
+        let master = takeover::master_from_fd(pty)?;
+        let fork = shpool_pty::fork::Fork::Parent(state.child_pid, master);
+
+        // The shell is not our child, so we can't wait on it. It got
+        // reparented when the old daemon exited, or still belongs to the
+        // daemon it got migrated from, so all we can do is notice when
+        // it goes away. Its exit status is lost.
+        let child_exit_notifier = Arc::new(ExitNotifier::new());
+        let notifiable_child_exit_notifier = Arc::clone(&child_exit_notifier);
+        let handed_off = Arc::new(AtomicBool::new(false));
+        let watcher_handed_off = Arc::clone(&handed_off);
+        let exit_hook_cmds = self.hook_cmds.clone();
+        let session_name = state.name.clone();
+        let child_pid = state.child_pid;
+        thread::Builder::new()
+            .name(threads::name("wait", &session_name))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "adopted_child_watcher", s = session_name).entered();
+                while signal::kill(Pid::from_raw(child_pid), None).is_ok() {
+                    thread::sleep(ADOPTED_CHILD_POLL_DUR);
+                }
+                info!("adopted child exited");
+                notifiable_child_exit_notifier.notify_exit(0);
+                if !watcher_handed_off.load(Ordering::Acquire) {
+                    exit_hook_cmds.fire_exit(&session_name, child_pid, 0);
+                }
+            })
+            .context("spawning adopted child watcher thread")?;
+
+        let mut session = self.start_session(SessionParts {
+            name: state.name.clone(),
+            conn_id: 0,
+            fork,
+            child_exit_notifier,
+            handed_off,
+            client_stream: None,
+            term_db: Arc::new(resolve_term_db(None)?),
+            initial_motd: None,
+            custom_cmd: false,
+            tty_size: state.tty_size.clone(),
+            restore_config: self.session_restore_config(&state.name),
+            idle_ttl: self.configured_idle_ttl(),
+            tags: state.tags.clone(),
+            recipe: state.recipe.clone(),
+            adopted: Some(state.restore_buffer),
+        })?;
+        session.attach_count = state.attach_count;
+        session.lock = state.lock;
+        session.note = state.note;
+        session.watches.edit(|added| *added = state.watches);
+        session.started_at =
+            time::UNIX_EPOCH + Duration::from_millis(state.started_at_unix_ms as u64);
+
+        info!("adopted session with pid {}", state.child_pid);
+        shells.insert(state.name, Box::new(session));
+        Ok(())
+    }
+
+    /// Wire up the threads and channels for a session whose shell is
+    /// already running.
+    fn start_session(&self, parts: SessionParts) -> anyhow::Result<shell::Session> {
         let (client_connection_tx, client_connection_rx) = crossbeam_channel::bounded(0);
         let (client_connection_ack_tx, client_connection_ack_rx) = crossbeam_channel::bounded(0);
         let (tty_size_change_tx, tty_size_change_rx) = crossbeam_channel::bounded(0);
@@ -886,6 +2924,23 @@ impl Server {
 
         let (heartbeat_tx, heartbeat_rx) = crossbeam_channel::bounded(0);
         let (heartbeat_ack_tx, heartbeat_ack_rx) = crossbeam_channel::bounded(0);
+        let (scroll_tx, scroll_rx) = crossbeam_channel::unbounded();
+        let (screen_lock_tx, screen_lock_rx) = crossbeam_channel::unbounded();
+        let (rename_tx, rename_rx) = crossbeam_channel::unbounded();
+        let (capture_tx, capture_rx) = crossbeam_channel::bounded(0);
+        let (capture_ack_tx, capture_ack_rx) = crossbeam_channel::bounded(0);
+        let session_name = Arc::new(Mutex::new(parts.name.clone()));
+        let scrolling = Arc::new(AtomicBool::new(false));
+        let screen_locked = Arc::new(AtomicBool::new(false));
+        let spool_bytes = Arc::new(AtomicUsize::new(0));
+        let evict_spool = Arc::new(AtomicBool::new(false));
+        let pump_cpu_ns = Arc::new(AtomicU64::new(0));
+        let bytes_in = Arc::new(AtomicU64::new(0));
+        let bytes_out = Arc::new(AtomicU64::new(0));
+        let lines_out = Arc::new(AtomicU64::new(0));
+        let output_queue = Arc::new(out_queue::Counters::default());
+        let pty_size = Arc::new(Mutex::new(parts.tty_size.clone()));
+        let output_taps = shell::OutputTaps::default();
 
         let shell_to_client_ctl = Arc::new(Mutex::new(shell::ReaderCtl {
             client_connection: client_connection_tx,
@@ -894,29 +2949,70 @@ impl Server {
             tty_size_change_ack: tty_size_change_ack_rx,
             heartbeat: heartbeat_tx,
             heartbeat_ack: heartbeat_ack_rx,
+            scroll: scroll_tx,
+            scrolling: Arc::clone(&scrolling),
+            screen_lock: screen_lock_tx,
+            screen_locked: Arc::clone(&screen_locked),
+            rename: rename_tx,
+            capture: capture_tx,
+            capture_ack: capture_ack_rx,
         }));
+        let switch = Arc::new(Mutex::new(shell::SwitchState::default()));
         let mut session_inner = shell::SessionInner {
-            name: header.name.clone(),
+            name: parts.name.clone(),
             shell_to_client_ctl: Arc::clone(&shell_to_client_ctl),
-            pty_master: fork,
-            client_stream: Some(client_stream),
+            pty_master: parts.fork,
+            client_stream: parts.client_stream,
             config: self.config.clone(),
             shell_to_client_join_h: None,
-            term_db,
+            term_db: parts.term_db,
             daily_messenger: Arc::clone(&self.daily_messenger),
-            needs_initial_motd_dump: dump_motd_on_new_session,
-            custom_cmd: header.cmd.is_some(),
+            initial_motd: parts.initial_motd,
+            custom_cmd: parts.custom_cmd,
+            pump_cpu_ns: Arc::clone(&pump_cpu_ns),
+            bytes_in: Arc::clone(&bytes_in),
+            output_queue: Arc::clone(&output_queue),
+            shells: Arc::downgrade(&self.shells),
+            switch: Arc::clone(&switch),
+            hung_up: false,
         };
         let child_pid = session_inner.pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
-        let restore_config = header.restore_override
-            .clone()
-            .or_else(|| self.config.get().session_restore.clone())
-            .unwrap_or_else(|| "5MB".to_string());
-        
+        let pty_master =
+            session_inner.pty_master.is_parent().context("internal error: executing in child fork")?;
+        let detached = session_inner.client_stream.is_none();
+        let spool_swap_after = match &self.config.get().session_restore_swap_after {
+            Some(src) => match duration::parse(src) {
+                Ok(d) => Some(d),
+                Err(e) => {
+                    warn!("bad session_restore_swap_after, never swapping: {:?}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let detached_output_limit = match &self.config.get().detached_output_limit {
+            Some(src) => replay::parse_rate(src).unwrap_or_else(|e| {
+                warn!("bad detached_output_limit, not throttling: {:?}", e);
+                None
+            }),
+            None => None,
+        };
+        let throttled = Arc::new(AtomicBool::new(false));
+        let activity = Arc::new(AtomicBool::new(false));
+        let bell = Arc::new(AtomicBool::new(false));
+        let watches = Arc::new(watch::Watches::default());
+        let started_at = time::SystemTime::now();
+        let last_active = Arc::new(AtomicI64::new(shell::unix_ms(started_at)));
+        let reported_cwd = Arc::new(Mutex::new(None));
+        let last_heartbeat = Arc::new(AtomicI64::new(0));
+        let restore_config =
+            limits::Limits::from_config(&self.config.get()).cap_restore(parts.restore_config);
+        let restore_capacity = session_restore::Capacity::of(&restore_config);
+
         session_inner.shell_to_client_join_h =
             Some(session_inner.spawn_shell_to_client(shell::ReaderArgs {
-                conn_id,
-                tty_size: header.local_tty_size.clone(),
+                conn_id: parts.conn_id,
+                tty_size: parts.tty_size,
                 session_restore_config: restore_config,
                 client_connection: client_connection_rx,
                 client_connection_ack: client_connection_ack_tx,
@@ -924,25 +3020,165 @@ impl Server {
                 tty_size_change_ack: tty_size_change_ack_tx,
                 heartbeat: heartbeat_rx,
                 heartbeat_ack: heartbeat_ack_tx,
+                spool_bytes: Arc::clone(&spool_bytes),
+                evict_spool: Arc::clone(&evict_spool),
+                spool_swap_after,
+                spool_swap_path: self.session_dir(&parts.name).join("spool.zst"),
+                spool_checkpoint_path: self.session_dir(&parts.name).join(session_restore::disk::CHECKPOINT_FILE),
+                scrollback_lines:
+                    self.config.get().scrollback_lines.unwrap_or(scrollback::DEFAULT_LINES),
+                scroll: scroll_rx,
+                scrolling,
+                screen_lock: screen_lock_rx,
+                screen_locked,
+                pty_size: Arc::clone(&pty_size),
+                idle_ttl: parts.idle_ttl,
+                reap: self.register_new_reapable_session.clone(),
+                session_name: Arc::clone(&session_name),
+                rename: rename_rx,
+                output_taps: output_taps.clone(),
+                capture: capture_rx,
+                capture_ack: capture_ack_tx,
+                adopted: parts.adopted,
+                detached,
+                child_pid,
+                exited: self.exited.clone(),
+                detached_output_limit,
+                throttled: Arc::clone(&throttled),
+                activity: Arc::clone(&activity),
+                bell: Arc::clone(&bell),
+                notifier: self.notifier.clone(),
+                watches: Arc::clone(&watches),
+                hook_cmds: self.hook_cmds.clone(),
+                stopping: Arc::clone(&self.stopping),
+                last_active: Arc::clone(&last_active),
+                bytes_out: Arc::clone(&bytes_out),
+                lines_out: Arc::clone(&lines_out),
+                reported_cwd: Arc::clone(&reported_cwd),
+                last_heartbeat: Arc::clone(&last_heartbeat),
+                escape_filter: escape_filter::Policy::new(
+                    &self.config.get().escape_filter(&parts.name),
+                ),
+                replay_graphics: self.config.get().replay_graphics.unwrap_or(false),
             })?);
 
-        if let Some(ttl_secs) = header.ttl_secs {
-            info!("registering session with ttl with the reaper");
-            self.register_new_reapable_session
-                .send((header.name.clone(), Instant::now().add(Duration::from_secs(ttl_secs))))
-                .context("sending reapable session registration msg")?;
-        }
-
         Ok(shell::Session {
+            name: session_name,
+            tags: parts.tags,
+            recipe: parts.recipe,
             shell_to_client_ctl,
+            pty_master,
             pager_ctl: Arc::new(Mutex::new(None)),
+            spool_bytes,
+            evict_spool,
+            owner: unistd::Uid::current().as_raw(),
+            pump_cpu_ns,
+            bytes_in,
+            bytes_out,
+            lines_out,
+            output_at_detach: (0, 0),
+            restore_capacity,
+            output_queue,
+            pty_size,
+            output_taps,
+            attach_count: 1,
             child_pid,
-            child_exit_notifier,
-            started_at: time::SystemTime::now(),
+            child_exit_notifier: parts.child_exit_notifier,
+            handed_off: parts.handed_off,
+            started_at,
             inner: Arc::new(Mutex::new(session_inner)),
+            exited: None,
+            throttled,
+            activity,
+            bell,
+            watches,
+            client_tty: None,
+            last_active,
+            last_attached_at: None,
+            last_detached_at: None,
+            last_detach_reason: None,
+            lock: None,
+            note: None,
+            log_markers: None,
+            switch,
+            term: SessionTerm::default(),
+            reported_cwd,
+            last_heartbeat,
         })
     }
 
+    /// The session_restore setting for the named session, from the config.
+    fn session_restore_config(&self, name: &str) -> String {
+        let config = self.config.get();
+        config
+            .session(name)
+            .and_then(|s| s.session_restore.clone())
+            .or_else(|| config.session_restore.clone())
+            .unwrap_or_else(|| "5MiB".to_string())
+    }
+
+    /// Check a passphrase against a copy of the lock on the given
+    /// session, so that argon2 does not hold up the rest of the shard.
+    /// Returns the copy and whether the passphrase matched, or None if
+    /// there is nothing to check (just locked ones if `only_locked`) or
+    /// the session is backing off after wrong passphrases or already
+    /// checking one. The verdict gets recorded on the session's lock,
+    /// but the caller has to make sure the lock did not change in the
+    /// meantime before acting on it.
+    fn check_passphrase(
+        &self,
+        name: &str,
+        passphrase: &str,
+        only_locked: bool,
+    ) -> Option<(lock::Lock, bool)> {
+        let lock = {
+            let mut shells = self.shells.shard(name);
+            let session = shells.get_mut(name)?;
+            if only_locked && !session.is_locked(self.configured_auto_lock()) {
+                return None;
+            }
+            // Claim the check while we hold the shard, so that a pile
+            // of connections can't all get past the backoff at once.
+            let lock = session.lock.as_mut()?;
+            if !lock.start_check() {
+                info!("'{}' is backing off or already checking a passphrase", name);
+                return None;
+            }
+            lock.clone()
+        };
+        let verified = lock.verify(passphrase);
+        if let Some(current) = self.shells.shard(name).get_mut(name).and_then(|s| s.lock.as_mut())
+            && current.same_passphrase(&lock)
+        {
+            current.record(verified);
+        }
+        Some((lock, verified))
+    }
+
+    /// How long sessions with a passphrase stay unlocked without a
+    /// client, from the auto_lock_after_idle config option.
+    fn configured_auto_lock(&self) -> Option<Duration> {
+        let src = self.config.get().auto_lock_after_idle.clone()?;
+        match duration::parse(&src) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                warn!("bad auto_lock_after_idle, never locking idle sessions: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// The idle ttl from the auto_kill_after_idle config option, if any.
+    fn configured_idle_ttl(&self) -> Option<Duration> {
+        let src = self.config.get().auto_kill_after_idle.clone()?;
+        match duration::parse(&src) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                warn!("bad auto_kill_after_idle, never killing idle sessions: {:?}", e);
+                None
+            }
+        }
+    }
     /// Set up the environment for the shell, returning the right TERM value.
     #[instrument(skip_all)]
     fn build_shell_env(
@@ -952,7 +3188,7 @@ impl Server {
     ) -> anyhow::Result<Vec<(OsString, OsString)>> {
         let s = OsString::from;
         let config = self.config.get();
-        let auth_sock = self.ssh_auth_sock_symlink(PathBuf::from(&header.name));
+        let forwarded_sockets = forward_sockets::vars(&config);
         let mut env = vec![
             (s("HOME"), s(&user_info.home_dir)),
             (
@@ -968,17 +3204,20 @@ impl Server {
                 s("SHPOOL_SESSION_DIR"),
                 self.session_dir(PathBuf::from(&header.name)).into_os_string(),
             ),
-            (s("SHELL"), s(&user_info.default_shell)),
+            (s("SHELL"), self.session_shell(header, user_info).into()),
             (s("USER"), s(&user_info.user)),
-            (
-                s("SSH_AUTH_SOCK"),
-                s(auth_sock.to_str().ok_or(anyhow!("failed to convert auth sock symlink"))?),
-            ),
         ];
+        for var in forwarded_sockets.iter() {
+            let symlink = self.forwarded_socket_symlink(&header.name, var);
+            env.push((s(var), symlink.into_os_string()));
+        }
 
         if let Some(xdg_runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
             env.push((s("XDG_RUNTIME_DIR"), xdg_runtime_dir));
         }
+        if let Some(namespace) = &self.namespace {
+            env.push((s(namespace::NAMESPACE_VAR), s(namespace)));
+        }
 
         // Most of the time, use the TERM that the user sent along in
         // the attach header. If they have an explicit TERM value set
@@ -989,8 +3228,16 @@ impl Server {
         if let Some(t) = header.local_env_get("TERM") {
             term = Some(String::from(t));
         }
+        // per-session env vars get layered on top of the global ones
+        let mut session_env = config.env.clone();
+        if let Some(overrides) = config.session(&header.name).and_then(|s| s.env.as_ref()) {
+            session_env.get_or_insert_default().extend(overrides.clone());
+        }
+        if !header.env.is_empty() {
+            session_env.get_or_insert_default().extend(header.env.iter().cloned());
+        }
         let filtered_env_pin;
-        if let Some(extra_env) = config.env.as_ref() {
+        if let Some(extra_env) = session_env.as_ref() {
             term = match extra_env.get("TERM") {
                 None => term,
                 Some(t) if t.is_empty() => None,
@@ -1023,7 +3270,7 @@ impl Server {
 
         // inject all other local variables
         for (var, val) in &header.local_env {
-            if var == "TERM" || var == "SSH_AUTH_SOCK" {
+            if var == "TERM" || forwarded_sockets.contains(var) {
                 continue;
             }
             env.push((s(var), s(val)));
@@ -1053,8 +3300,8 @@ impl Server {
         self.session_dir(session_name).join("forward.env")
     }
 
-    fn ssh_auth_sock_symlink<P: AsRef<Path>>(&self, session_name: P) -> PathBuf {
-        self.session_dir(session_name).join("ssh-auth-sock.socket")
+    fn forwarded_socket_symlink<P: AsRef<Path>>(&self, session_name: P, var: &str) -> PathBuf {
+        self.session_dir(session_name).join(forward_sockets::link_name(var))
     }
 
     fn session_dir<P: AsRef<Path>>(&self, session_name: P) -> PathBuf {
@@ -1062,12 +3309,83 @@ impl Server {
     }
 }
 
+/// Check that a session name is safe to use as a directory name under
+/// the runtime dir.
+fn validate_session_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("blank session names are not allowed"));
+    }
+    if name == "."
+        || name == ".."
+        || name.contains('/')
+        || name.contains(|c: char| c.is_whitespace() || c.is_control())
+    {
+        return Err(anyhow!("invalid session name '{}'", name.escape_debug()));
+    }
+    Ok(())
+}
+
 #[instrument(skip_all)]
 fn parse_connect_header(stream: &mut UnixStream) -> anyhow::Result<ConnectHeader> {
     let header: ConnectHeader = protocol::decode_from(stream).context("parsing header")?;
     Ok(header)
 }
 
+/// Ask a session's shell->client thread for a copy of its output.
+fn capture(
+    shell_to_client_ctl: &Mutex<shell::ReaderCtl>,
+    kind: shell::CaptureKind,
+) -> anyhow::Result<Vec<u8>> {
+    let _s = span!(Level::INFO, "capture_lock(shell_to_client_ctl)").entered();
+    let shell_to_client_ctl = shell_to_client_ctl.lock().unwrap();
+    shell_to_client_ctl
+        .capture
+        .send_timeout(kind, SESSION_MSG_TIMEOUT)
+        .context("sending capture request to shell->client")?;
+    shell_to_client_ctl.capture_ack.recv_timeout(SESSION_MSG_TIMEOUT).context("recving capture")
+}
+
+/// Pass a session's live output on to a tail client until the shell
+/// exits, returning its exit status, or the client hangs up, in
+/// which case this returns None.
+fn follow_output(
+    stream: &mut UnixStream,
+    output: &crossbeam_channel::Receiver<Vec<u8>>,
+    child_exit_notifier: &ExitNotifier,
+) -> anyhow::Result<Option<i32>> {
+    loop {
+        match output.recv_timeout(WAIT_POLL_DUR) {
+            Ok(buf) => {
+                let res = Chunk { kind: ChunkKind::Data, buf: &buf }.write_to(stream);
+                if let Err(e) = res {
+                    info!("writing tail output: {:?}", e);
+                    return Ok(None);
+                }
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                if let Some(exit_status) = child_exit_notifier.wait(Some(Duration::ZERO)) {
+                    // pick up whatever the shell printed on its way out
+                    for buf in output.try_iter() {
+                        Chunk { kind: ChunkKind::Data, buf: &buf }
+                            .write_to(stream)
+                            .context("writing tail output")?;
+                    }
+                    return Ok(Some(exit_status));
+                }
+                // The client never sends anything after the header,
+                // so the stream only becomes readable once it hangs up.
+                if shell::input_pending(stream, 0) {
+                    return Ok(None);
+                }
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                warn!("tail client fell too far behind, giving up on it");
+                return Ok(Some(1));
+            }
+        }
+    }
+}
+
 #[instrument(skip_all)]
 fn write_reply<H>(stream: &mut UnixStream, header: H) -> anyhow::Result<()>
 where
@@ -1084,21 +3402,161 @@ where
     Ok(())
 }
 
+/// Everything that differs between a freshly spawned session and one
+/// that was adopted from a previous daemon.
+struct SessionParts {
+    name: String,
+    conn_id: usize,
+    fork: shpool_pty::fork::Fork,
+    child_exit_notifier: Arc<ExitNotifier>,
+    handed_off: Arc<AtomicBool>,
+    /// The client to hook the session up to right away, if any.
+    client_stream: Option<UnixStream>,
+    term_db: Arc<termini::TermInfo>,
+    initial_motd: Option<show_motd::Vars>,
+    custom_cmd: bool,
+    tty_size: TtySize,
+    restore_config: String,
+    idle_ttl: Option<Duration>,
+    tags: Vec<String>,
+    recipe: resurrect::Recipe,
+    /// The restore buffer handed over by the previous daemon.
+    adopted: Option<Vec<u8>>,
+}
+
+/// The replay override the client asked for with `attach --restore`.
+fn replay_override(header: &AttachHeader) -> Option<replay::Override> {
+    let src = header.restore_override.as_deref()?;
+    match replay::Override::parse(src) {
+        Ok(o) => Some(o),
+        Err(e) => {
+            warn!("bad restore override '{}', ignoring: {:?}", src, e);
+            None
+        }
+    }
+}
+
+/// The warning shown on the first attach after the previous client
+/// hung up rather than detaching.
+fn hangup_banner(at: time::SystemTime) -> String {
+    let away = time::SystemTime::now().duration_since(at).unwrap_or_default();
+    format!(
+        "previous client lost connection at {}, you were away {}",
+        chrono::DateTime::<chrono::Local>::from(at).format("%Y-%m-%d %H:%M:%S"),
+        duration::format(away),
+    )
+}
+
+/// The `reconnect_summary` banner line about what a session got up to
+/// since a client last detached, if one ever did.
+fn away_summary(session: &shell::Session) -> Option<String> {
+    let detached_at = session.last_detached_at?;
+    if session.last_attached_at.is_some_and(|at| at > detached_at) {
+        return None;
+    }
+    let away = time::SystemTime::now().duration_since(detached_at).unwrap_or_default();
+    let bytes =
+        session.bytes_out.load(Ordering::Relaxed).saturating_sub(session.output_at_detach.0);
+    let lines =
+        session.lines_out.load(Ordering::Relaxed).saturating_sub(session.output_at_detach.1);
+    let rows = session.pty_size.lock().unwrap().rows;
+    let holds = if session.spool_bytes.load(Ordering::Relaxed) == 0 && bytes > 0 {
+        // evicted, or never kept
+        Some(false)
+    } else {
+        session.restore_capacity.holds(bytes, lines, rows)
+    };
+    Some(format_away_summary(away, bytes, lines, holds))
+}
+
+fn format_away_summary(away: Duration, bytes: u64, lines: u64, holds: Option<bool>) -> String {
+    let mut summary = format!("shpool: you were away {}", duration::format(away));
+    if bytes == 0 {
+        summary.push_str(", no output since you left");
+        return summary;
+    }
+    summary.push_str(&format!(
+        ", {} ({} {}) of output since you left",
+        crate::status::format_bytes(bytes),
+        lines,
+        if lines == 1 { "line" } else { "lines" }
+    ));
+    match holds {
+        Some(true) => summary.push_str(", all of it replayed below"),
+        Some(false) => summary.push_str(", more than the restore buffer holds"),
+        None => {}
+    }
+    summary
+}
+
+/// Look up the terminfo for the given TERM value, falling back to the
+/// daemon's own TERM and then to xterm.
+fn resolve_term_db(term: Option<&OsStr>) -> anyhow::Result<termini::TermInfo> {
+    let fallback_terminfo = || match termini::TermInfo::from_name("xterm") {
+        Ok(db) => Ok(db),
+        Err(err) => {
+            warn!("could not get xterm terminfo: {:?}", err);
+            let empty_db = io::Cursor::new(vec![]);
+            termini::TermInfo::parse(empty_db).context("getting terminfo db")
+        }
+    };
+    Ok(if let Some(term) = term {
+        match termini::TermInfo::from_name(term.to_string_lossy().as_ref())
+            .context("resolving terminfo")
+        {
+            Ok(ti) => ti,
+            Err(err) => {
+                warn!("could not get terminfo for '{:?}': {:?}", term, err);
+                fallback_terminfo()?
+            }
+        }
+    } else {
+        warn!("no $TERM, using default terminfo");
+        match termini::TermInfo::from_env() {
+            Ok(db) => db,
+            Err(err) => {
+                warn!("could not get terminfo from env: {:?}", err);
+                fallback_terminfo()?
+            }
+        }
+    })
+}
+
 /// check_peer makes sure that a process dialing in on the shpool
 /// control socket has the same UID as the current user and that
 /// both have the same executable path.
-fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
+pub fn check_peer(sock: &UnixStream, shared_group: Option<&str>) -> anyhow::Result<()> {
     #[cfg(target_os = "linux")]
     {
         use nix::sys::socket;
         use nix::unistd;
 
+        use crate::daemon::socket_perms;
+
         let peer_creds = socket::getsockopt(sock, socket::sockopt::PeerCredentials)
             .context("could not get peer creds from socket")?;
+        info!(
+            "peer creds: pid={} uid={} gid={}",
+            peer_creds.pid(),
+            peer_creds.uid(),
+            peer_creds.gid()
+        );
         let peer_uid = unistd::Uid::from_raw(peer_creds.uid());
         let self_uid = unistd::Uid::current();
         if peer_uid != self_uid {
-            return Err(anyhow!("shpool prohibits connections across users"));
+            // members of the configured socket group are trusted
+            // just like we are
+            let shared = match shared_group {
+                Some(group) => {
+                    let gid = socket_perms::resolve_group(group)?;
+                    socket_perms::in_group(peer_creds.pid(), peer_creds.gid(), gid)
+                }
+                None => false,
+            };
+            if !shared {
+                return Err(anyhow!("shpool prohibits connections across users"));
+            }
+            info!("allowing uid={} as a member of the socket group", peer_creds.uid());
         }
 
         let peer_pid = unistd::Pid::from_raw(peer_creds.pid());
@@ -1112,7 +3570,7 @@ fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
 
     #[cfg(target_os = "macos")]
     {
-        let _ = sock; // suppress unused variable warning
+        let _ = (sock, shared_group); // suppress unused variable warning
                       // On macOS, Unix domain socket connections are already restricted by file permissions
                       // Additional peer verification is not available through socket options
         info!("Peer credential verification not available on macOS - relying on file permissions");
@@ -1121,6 +3579,43 @@ fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The uid of the user on the other end of the socket. Where the
+/// platform doesn't let us find out, only our own user can connect.
+fn peer_uid(sock: &UnixStream) -> u32 {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::sys::socket;
+
+        if let Ok(creds) = socket::getsockopt(sock, socket::sockopt::PeerCredentials) {
+            return creds.uid();
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = sock;
+    }
+
+    unistd::Uid::current().as_raw()
+}
+
+/// The pid of the process on the other end of the socket, if the
+/// platform lets us find out.
+fn peer_pid(sock: &UnixStream) -> Option<i32> {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::sys::socket;
+
+        socket::getsockopt(sock, socket::sockopt::PeerCredentials).ok().map(|creds| creds.pid())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = sock;
+        None
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn exe_for_pid(pid: unistd::Pid) -> anyhow::Result<PathBuf> {
     let path = std::fs::read_link(format!("/proc/{pid}/exe"))?;
@@ -0,0 +1,187 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! The session table maps session names to session descriptors.
+
+  Rather than guarding the whole table with a single mutex, the
+  table is split into a fixed number of shards, each with its own
+  lock. A session always lives in the shard picked by hashing its
+  name, so operations on one session (attach, detach, resize...)
+  only contend with operations on sessions that happen to hash to
+  the same shard. Operations that need to see every session, like
+  `shpool list`, walk the shards one at a time and never hold more
+  than one shard lock at once. The exceptions are renames, which lock
+  the two shards involved, and a takeover, which holds every shard
+  while it hands the sessions over. Both take shard locks in index
+  order so they can't deadlock with each other.
+*/
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
+
+use shpool_protocol::RenameReply;
+
+use super::shell;
+
+const NUM_SHARDS: usize = 16;
+
+pub type Shard = HashMap<String, Box<shell::Session>>;
+
+pub struct SessionTable {
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        SessionTable { shards: (0..NUM_SHARDS).map(|_| Mutex::new(HashMap::new())).collect() }
+    }
+
+    /// Lock and return the shard that owns the given session name.
+    ///
+    /// We unwrap to propagate lock poisoning as an unwind, just like
+    /// we did with the single global lock.
+    pub fn shard(&self, name: &str) -> MutexGuard<'_, Shard> {
+        self.shards[Self::shard_index(name)].lock().unwrap()
+    }
+
+    /// Iterate over all the shards, locking each one only as the
+    /// iterator reaches it. Callers must drop each guard before
+    /// pulling the next one off the iterator if they want to avoid
+    /// holding multiple shard locks at once.
+    pub fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, Shard>> {
+        self.shards.iter().map(|s| s.lock().unwrap())
+    }
+
+    /// Like `shards`, but skipping over any shard whose lock is held
+    /// (or poisoned), which comes back as None along with its index,
+    /// rather than waiting on it.
+    pub fn try_shards(&self) -> impl Iterator<Item = (usize, Option<MutexGuard<'_, Shard>>)> {
+        self.shards.iter().enumerate().map(|(i, s)| (i, s.try_lock().ok()))
+    }
+
+    /// The names of all the sessions in the table. Shards are locked
+    /// one at a time, so sessions may come and go while this runs.
+    pub fn names(&self) -> Vec<String> {
+        self.shards().flat_map(|shard| shard.keys().cloned().collect::<Vec<_>>()).collect()
+    }
+
+    /// The names of all the sessions which have any of the given tags.
+    pub fn tagged(&self, tags: &[String]) -> Vec<String> {
+        self.shards()
+            .flat_map(|shard| {
+                shard
+                    .iter()
+                    .filter(|(_, s)| s.tags.iter().any(|t| tags.contains(t)))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Remove the given session from the table, returning it if
+    /// it was present.
+    pub fn remove(&self, name: &str) -> Option<Box<shell::Session>> {
+        self.shard(name).remove(name)
+    }
+
+    /// Atomically move a session over to a new name. `on_rename` gets
+    /// called with the session while the locks for both names are
+    /// still held, so nobody can observe a half renamed session.
+    pub fn rename<F>(&self, from: &str, to: &str, on_rename: F) -> RenameReply
+    where
+        F: FnOnce(&shell::Session),
+    {
+        let (from_idx, to_idx) = (Self::shard_index(from), Self::shard_index(to));
+        if from_idx == to_idx {
+            let mut shard = self.shards[from_idx].lock().unwrap();
+            return Self::rename_within(&mut shard, None, from, to, on_rename);
+        }
+
+        // Always lock the lower shard first so that two concurrent
+        // renames can't deadlock.
+        let (mut from_shard, mut to_shard) = if from_idx < to_idx {
+            let from_shard = self.shards[from_idx].lock().unwrap();
+            (from_shard, self.shards[to_idx].lock().unwrap())
+        } else {
+            let to_shard = self.shards[to_idx].lock().unwrap();
+            (self.shards[from_idx].lock().unwrap(), to_shard)
+        };
+        Self::rename_within(&mut from_shard, Some(&mut to_shard), from, to, on_rename)
+    }
+
+    fn rename_within<F>(
+        from_shard: &mut Shard,
+        to_shard: Option<&mut Shard>,
+        from: &str,
+        to: &str,
+        on_rename: F,
+    ) -> RenameReply
+    where
+        F: FnOnce(&shell::Session),
+    {
+        let exists = match &to_shard {
+            Some(shard) => shard.contains_key(to),
+            None => from_shard.contains_key(to),
+        };
+        if exists {
+            return RenameReply::AlreadyExists;
+        }
+        let Some(session) = from_shard.remove(from) else {
+            return RenameReply::NotFound;
+        };
+        on_rename(&session);
+        match to_shard {
+            Some(shard) => shard.insert(String::from(to), session),
+            None => from_shard.insert(String::from(to), session),
+        };
+        RenameReply::Renamed
+    }
+
+    fn shard_index(name: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        (hasher.finish() as usize) % NUM_SHARDS
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shard_index_stable() {
+        for name in ["a", "main", "some-long-session-name", ""] {
+            let idx = SessionTable::shard_index(name);
+            assert!(idx < NUM_SHARDS);
+            assert_eq!(idx, SessionTable::shard_index(name));
+        }
+    }
+
+    #[test]
+    fn empty_table() {
+        let table = SessionTable::new();
+        assert!(table.remove("foo").is_none());
+        assert!(table.names().is_empty());
+        assert!(table.tagged(&[String::from("prod")]).is_empty());
+        assert_eq!(
+            table.rename("foo", "bar", |_| panic!("renamed nothing")),
+            RenameReply::NotFound
+        );
+        assert!(table.shards().all(|s| s.is_empty()));
+        assert_eq!(table.shards().count(), NUM_SHARDS);
+    }
+}
@@ -13,29 +13,46 @@
 // limitations under the License.
 
 use std::{
+    borrow::Cow,
     io,
     io::{Read, Write},
     net,
     ops::Add,
-    os::unix::net::UnixStream,
+    os::{fd::BorrowedFd, unix::net::UnixStream},
+    path::PathBuf,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
     },
     thread, time,
     time::Duration,
 };
 
 use anyhow::{anyhow, Context};
-use nix::{sys::signal, unistd::Pid};
-use shpool_protocol::{Chunk, ChunkKind, TtySize};
+use nix::{
+    sys::signal,
+    unistd::{self, Pid},
+};
+use shpool_protocol::{
+    Chunk, ChunkKind, DetachReason, SessionMessageReply, SessionTerm, SignalTarget, SwitchReply,
+    TtySize,
+};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
 use crate::{
     consts,
-    daemon::{config, exit_notify::ExitNotifier, keybindings, pager::PagerCtl, prompt, show_motd},
+    daemon::{
+        bell, config, escape_filter, exit_notify::ExitNotifier, exit_reaper, hook_cmds, keepalive,
+        keybindings, linger, lock, notify, out_queue, pager::PagerCtl, proc_tree, prompt,
+        screen_lock, scrollback, session_table::SessionTable, show_motd, threads, throttle, title,
+        ttl_reaper, utf8, watch,
+    },
+    duration, logging, protocol,
     protocol::ChunkExt as _,
-    session_restore, test_hooks,
+    resurrect, session_restore,
+    session_restore::replay,
+    switch::PREVIOUS_SESSION,
+    test_hooks,
     tty::TtySizeExt as _,
 };
 
@@ -54,43 +71,337 @@ const REATTACH_RESIZE_DELAY: time::Duration = time::Duration::from_millis(50);
 // the inner loop.
 const SHELL_TO_CLIENT_POLL_MS: u16 = 100;
 
+// After a reattach, the shell->client thread holds on to the restore buffer
+// for this long waiting for live output so that it can send both in a
+// single vectored write. Most apps redraw in response to the reattach
+// resize well within this window, so the client sees one atomic repaint
+// rather than a restore followed by a separate redraw.
+const RESTORE_STITCH_WINDOW_MS: u16 = 20;
+
+// While the client is in scroll mode, the shell->client thread polls more
+// often so that it picks up scroll keypresses without a noticeable lag.
+const SCROLL_MODE_POLL_MS: u16 = 10;
+
 // How long to wait before giving up while trying to talk to the
 // shell->client thread.
 const SHELL_TO_CLIENT_CTL_TIMEOUT: time::Duration = time::Duration::from_millis(300);
 
+// How long to give the output queued up for a client to go out before
+// shutting its connection down. Needs to stay well under
+// SHELL_TO_CLIENT_CTL_TIMEOUT, since whoever asked for the shutdown is
+// waiting on an ack.
+const CLIENT_DRAIN_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+// After reading a chunk of client input, the client->shell thread keeps
+// reading for as long as more input shows up within this window so that
+// pastes and key-repeat bursts get coalesced into a single pty write. Small
+// enough that a human typing will never notice it, large enough that an
+// escape sequence fragmented across socket reads gets stitched back together.
+const CLIENT_INPUT_BATCH_WINDOW_MS: u8 = 2;
+
+// How long the client->shell thread waits for input before checking
+// whether it has been told to stop.
+const CLIENT_INPUT_POLL_MS: u8 = 100;
+
+// How many chunks of output an output tap may have queued up before
+// we give up on it, or start skipping output for a lossy one.
+const OUTPUT_TAP_DEPTH: usize = 1024;
+
 /// Session represent a shell session
 #[derive(Debug)]
 pub struct Session {
+    /// The current name of the session, which changes if the
+    /// session gets renamed.
+    pub name: Arc<Mutex<String>>,
+    /// Tags for grouping sessions, given with `attach --tag`.
+    pub tags: Vec<String>,
+    /// How the session was started, for the resurrect manifest.
+    pub recipe: resurrect::Recipe,
     pub started_at: time::SystemTime,
     pub child_pid: libc::pid_t,
     pub child_exit_notifier: Arc<ExitNotifier>,
+    /// Set once the session has been migrated to another daemon, which
+    /// looks after it from then on, so that our child watcher still
+    /// reaps the shell but leaves the exit hooks to that daemon.
+    pub handed_off: Arc<AtomicBool>,
     pub shell_to_client_ctl: Arc<Mutex<ReaderCtl>>,
+    /// The pty master, so that mirror clients can type into the
+    /// session without going through the inner lock.
+    pub pty_master: shpool_pty::fork::Master,
     pub pager_ctl: Arc<Mutex<Option<PagerCtl>>>,
+    /// How many bytes the session's output spool currently holds on to.
+    /// Published by the shell->client thread, which owns the spool.
+    pub spool_bytes: Arc<AtomicUsize>,
+    /// Set to get the shell->client thread to throw the spool away,
+    /// to keep the daemon under `limits.max_spool_memory`.
+    pub evict_spool: Arc<AtomicBool>,
+    /// The uid of the user who created the session, for
+    /// `limits.max_sessions`.
+    pub owner: u32,
+    /// Total CPU time, in nanoseconds, that the daemon has spent pumping
+    /// data between this session's shell and its clients.
+    pub pump_cpu_ns: Arc<AtomicU64>,
+    /// Total bytes of client input written to the shell, counting
+    /// mirrors.
+    pub bytes_in: Arc<AtomicU64>,
+    /// Total bytes of output read from the shell.
+    pub bytes_out: Arc<AtomicU64>,
+    /// Total lines of output read from the shell.
+    pub lines_out: Arc<AtomicU64>,
+    /// bytes_out and lines_out as of when a client last detached, for
+    /// the `reconnect_summary`.
+    pub output_at_detach: (u64, u64),
+    /// How much output the session's restore spool can replay.
+    pub restore_capacity: session_restore::Capacity,
+    /// How the output queues of the session's clients are doing.
+    pub output_queue: Arc<out_queue::Counters>,
+    /// The size the pty was last set to. Published by the shell->client
+    /// thread, which is in charge of resizing the pty.
+    pub pty_size: Arc<Mutex<TtySize>>,
+    /// Subscribers to the session's output.
+    pub output_taps: OutputTaps,
+    /// How many times a client has attached to the session, counting
+    /// the attach that created it but not mirrors.
+    pub attach_count: usize,
     /// Mutable state with the lock held by the servicing handle_attach thread
     /// while a tty is attached to the session. Probing the mutex can be used
     /// to determine if someone is currently attached to the session.
     pub inner: Arc<Mutex<SessionInner>>,
+    /// Set once the shell has exited if the session is being kept
+    /// around anyway, see the exit_reaper module.
+    pub exited: Option<ExitedShell>,
+    /// Set by the shell->client thread if it has had to hold back the
+    /// session's output since a client was last attached.
+    pub throttled: Arc<AtomicBool>,
+    /// Set by the shell->client thread if the session has produced
+    /// output since a client was last attached.
+    pub activity: Arc<AtomicBool>,
+    /// Set by the shell->client thread if the session has rung the
+    /// bell since a client was last attached.
+    pub bell: Arc<AtomicBool>,
+    /// Patterns to look for in the output while detached, and the
+    /// matches so far.
+    pub watches: Arc<watch::Watches>,
+    /// The tty of the client that last attached, if it said.
+    pub client_tty: Option<String>,
+    /// When the shell last produced output, in milliseconds since the
+    /// epoch. Published by the shell->client thread.
+    pub last_active: Arc<AtomicI64>,
+    /// When a client last attached, for `attach --last` and
+    /// `list --sort recent`.
+    pub last_attached_at: Option<time::SystemTime>,
+    /// When a client last detached.
+    pub last_detached_at: Option<time::SystemTime>,
+    /// Why the last client went away.
+    pub last_detach_reason: Option<DetachReason>,
+    /// Set once the session has been given a passphrase with
+    /// `shpool lock`.
+    pub lock: Option<lock::Lock>,
+    /// What the session is for, set with `shpool note`.
+    pub note: Option<String>,
+    /// Where to send notes so that they show up as markers in the
+    /// session's output log, if it has one.
+    pub log_markers: Option<crossbeam_channel::Sender<String>>,
+    /// Shared with SessionInner::switch, so that `shpool switch` can
+    /// move the attached client.
+    pub switch: Arc<Mutex<SwitchState>>,
+    /// The TERM and COLORTERM the shell was started with, unknown for
+    /// sessions adopted from a previous daemon.
+    pub term: SessionTerm,
+    /// The working directory the shell last reported with OSC 7, if it
+    /// does that. Published by the shell->client thread.
+    pub reported_cwd: Arc<Mutex<Option<String>>>,
+    /// When the attached client last took a heartbeat, in milliseconds
+    /// since the epoch, or 0 if no client ever has. Published by the
+    /// shell->client thread.
+    pub last_heartbeat: Arc<AtomicI64>,
+}
+
+/// What is left of a session whose shell has exited.
+#[derive(Debug)]
+pub struct ExitedShell {
+    pub exit_status: i32,
+    /// The restore buffer at the time the shell exited.
+    pub restore: Vec<u8>,
+    /// The scrollback at the time the shell exited.
+    pub scrollback: Vec<u8>,
+}
+
+impl ExitedShell {
+    /// Show a client how the session ended: the final output, a note
+    /// about the shell having exited, and then the exit status, which
+    /// makes the client hang up.
+    pub fn replay_to<W: io::Write>(&self, sink: &mut W) -> io::Result<()> {
+        let note = format!(
+            "\r\n[shpool: the shell exited with status {}, kill the session to start a new one]\r\n",
+            self.exit_status
+        );
+        SessionInner::write_data(sink, &self.restore, note.as_bytes())?;
+        SessionInner::write_exit_chunk(sink, self.exit_status);
+        Ok(())
+    }
 }
 
 impl Session {
+    /// The shell's current working directory, from /proc where there
+    /// is one, or else the last one the shell reported with OSC 7.
+    pub fn cwd(&self) -> Option<String> {
+        match std::fs::read_link(format!("/proc/{}/cwd", self.child_pid)) {
+            Ok(cwd) => Some(cwd.to_string_lossy().into_owned()),
+            Err(_) => self.reported_cwd.lock().unwrap().clone(),
+        }
+    }
+
     /// Kill the session, first sending a SIGHUP and then resorting to a
     /// SIGKILL if that doesn't work (SIGTERM doesn't really work on shells).
     #[instrument(skip_all)]
     pub fn kill(&self) -> anyhow::Result<()> {
-        // SIGHUP is a signal to indicate that the terminal has disconnected
-        // from a process. We can't use the normal SIGTERM graceful-shutdown
-        // signal since shells just forward those to their child process,
-        // but for shells SIGHUP serves as the graceful shutdown signal.
-        signal::kill(Pid::from_raw(self.child_pid), Some(signal::Signal::SIGHUP))
-            .context("sending SIGHUP to child proc")?;
-
-        if self.child_exit_notifier.wait(Some(SHELL_KILL_TIMEOUT)).is_none() {
-            info!("child failed to exit within kill timeout, no longer being polite");
-            signal::kill(Pid::from_raw(self.child_pid), Some(signal::Signal::SIGKILL))
-                .context("sending SIGKILL to child proc")?;
+        if self.exited.is_some() {
+            // nothing left to kill, and the pid might belong to
+            // someone else by now
+            return Ok(());
         }
+        kill_shell(self.child_pid, &self.child_exit_notifier)
+    }
 
-        Ok(())
+    /// Send some of the session's processes a signal, leaving the
+    /// session itself alone.
+    #[instrument(skip_all)]
+    pub fn signal(&self, sig: signal::Signal, target: SignalTarget) -> anyhow::Result<()> {
+        if self.exited.is_some() {
+            return Err(anyhow!("the shell has already exited"));
+        }
+        match target {
+            SignalTarget::Shell => {
+                signal::kill(Pid::from_raw(self.child_pid), sig).context("signaling shell")
+            }
+            SignalTarget::Foreground => {
+                let fd = self.pty_master.raw_fd().ok_or(anyhow!("no pty master fd"))?;
+                // Safety: the session owns the pty master, which outlives this call
+                let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                let pgrp = unistd::tcgetpgrp(fd).context("getting foreground process group")?;
+                signal::killpg(pgrp, sig).context("signaling foreground process group")
+            }
+            SignalTarget::Tree => {
+                let snapshot = proc_tree::Snapshot::take()
+                    .ok_or(anyhow!("signaling process trees is only supported on linux"))?;
+                for pid in snapshot.members(self.child_pid) {
+                    // processes may exit out from under us, which is fine
+                    if let Err(e) = signal::kill(Pid::from_raw(pid), sig)
+                        && e != nix::errno::Errno::ESRCH
+                    {
+                        return Err(e).context(format!("signaling pid {pid}"));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether attaching to the session takes its passphrase right now.
+    pub fn is_locked(&self, auto_lock_after: Option<Duration>) -> bool {
+        let Some(lock) = &self.lock else {
+            return false;
+        };
+        let detached_at = match (self.last_attached_at, self.last_detached_at) {
+            (Some(attached), Some(detached)) if attached > detached => None,
+            (_, detached) => detached,
+        };
+        lock.is_locked(detached_at, auto_lock_after)
+    }
+}
+
+/// Kill a shell, first sending a SIGHUP and then resorting to a SIGKILL
+/// if that doesn't work.
+fn kill_shell(child_pid: libc::pid_t, child_exit_notifier: &ExitNotifier) -> anyhow::Result<()> {
+    // SIGHUP is a signal to indicate that the terminal has disconnected
+    // from a process. We can't use the normal SIGTERM graceful-shutdown
+    // signal since shells just forward those to their child process,
+    // but for shells SIGHUP serves as the graceful shutdown signal.
+    signal::kill(Pid::from_raw(child_pid), Some(signal::Signal::SIGHUP))
+        .context("sending SIGHUP to child proc")?;
+    // The shell can't act on the SIGHUP if its detach policy stopped it.
+    signal::kill(Pid::from_raw(child_pid), Some(signal::Signal::SIGCONT))
+        .context("sending SIGCONT to child proc")?;
+
+    if child_exit_notifier.wait(Some(SHELL_KILL_TIMEOUT)).is_none() {
+        info!("child failed to exit within kill timeout, no longer being polite");
+        signal::kill(Pid::from_raw(child_pid), Some(signal::Signal::SIGKILL))
+            .context("sending SIGKILL to child proc")?;
+    }
+
+    Ok(())
+}
+
+/// Subscribers to a session's shell output, which the shell->client
+/// thread feeds whether or not a client is attached. A tap that falls
+/// too far behind gets dropped, unless it is lossy, as does one whose
+/// receiver has gone away.
+#[derive(Clone, Debug, Default)]
+pub struct OutputTaps(Arc<Mutex<Vec<OutputTap>>>);
+
+#[derive(Debug)]
+struct OutputTap {
+    tx: crossbeam_channel::Sender<Vec<u8>>,
+    /// Whether to skip the output there is no room for rather than
+    /// dropping the tap when it falls behind.
+    lossy: bool,
+    /// How many bytes got skipped since there was last room.
+    skipped: usize,
+}
+
+impl OutputTaps {
+    /// Start getting a copy of everything the shell prints from now on.
+    pub fn add(&self) -> crossbeam_channel::Receiver<Vec<u8>> {
+        self.add_tap(false)
+    }
+
+    /// Like `add`, but for subscribers that would rather miss some
+    /// output than all of it if they can't keep up. Once there is room
+    /// again, they get a note saying how much they missed.
+    pub fn add_lossy(&self) -> crossbeam_channel::Receiver<Vec<u8>> {
+        self.add_tap(true)
+    }
+
+    fn add_tap(&self, lossy: bool) -> crossbeam_channel::Receiver<Vec<u8>> {
+        let (tx, rx) = crossbeam_channel::bounded(OUTPUT_TAP_DEPTH);
+        self.0.lock().unwrap().push(OutputTap { tx, lossy, skipped: 0 });
+        rx
+    }
+
+    fn feed(&self, buf: &[u8]) {
+        let mut taps = self.0.lock().unwrap();
+        if taps.is_empty() || buf.is_empty() {
+            return;
+        }
+        taps.retain_mut(|tap| {
+            if tap.skipped > 0 {
+                let note = format!("\r\n[shpool: skipped {} bytes of output]\r\n", tap.skipped);
+                match tap.tx.try_send(note.into_bytes()) {
+                    Ok(()) => tap.skipped = 0,
+                    Err(crossbeam_channel::TrySendError::Full(_)) => {
+                        tap.skipped += buf.len();
+                        return true;
+                    }
+                    Err(crossbeam_channel::TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            match tap.tx.try_send(buf.to_vec()) {
+                Ok(()) => true,
+                Err(crossbeam_channel::TrySendError::Full(_)) if tap.lossy => {
+                    if tap.skipped == 0 {
+                        warn!("output tap fell too far behind, skipping output");
+                    }
+                    tap.skipped += buf.len();
+                    true
+                }
+                Err(crossbeam_channel::TrySendError::Full(_)) => {
+                    warn!("output tap fell too far behind, dropping it");
+                    false
+                }
+                Err(crossbeam_channel::TrySendError::Disconnected(_)) => false,
+            }
+        });
     }
 }
 
@@ -105,8 +416,22 @@ pub struct SessionInner {
     pub config: config::Manager,
     pub term_db: Arc<termini::TermInfo>,
     pub daily_messenger: Arc<show_motd::DailyMessenger>,
-    pub needs_initial_motd_dump: bool,
+    /// Set if the motd should get dumped once the shell has started.
+    pub initial_motd: Option<show_motd::Vars>,
     pub custom_cmd: bool,
+    /// Shared with the owning Session, see Session::pump_cpu_ns.
+    pub pump_cpu_ns: Arc<AtomicU64>,
+    /// Shared with Session::bytes_in.
+    pub bytes_in: Arc<AtomicU64>,
+    /// Shared with Session::output_queue.
+    pub output_queue: Arc<out_queue::Counters>,
+    /// The daemon's session table, for the `list` keybinding action.
+    pub shells: Weak<SessionTable>,
+    /// Where the attached client has come from and is going to.
+    pub switch: Arc<Mutex<SwitchState>>,
+    /// Set by bidi_stream if the client connection went away without
+    /// anyone asking for it to be detached.
+    pub hung_up: bool,
 
     /// The join handle for the always-on background shell->client thread.
     /// Only wrapped in an option so we can spawn the thread after
@@ -114,18 +439,116 @@ pub struct SessionInner {
     pub shell_to_client_join_h: Option<thread::JoinHandle<anyhow::Result<()>>>,
 }
 
+/// What a session knows about its attached client's trips between
+/// sessions.
+#[derive(Debug, Default)]
+pub struct SwitchState {
+    /// Whether the attached client can follow a switch to another
+    /// session over its existing connection.
+    pub in_place: bool,
+    /// The session the attached client was on before this one, which
+    /// is where a switch to `-` goes.
+    pub previous: Option<String>,
+    /// Set when the client gets switched in place, to the session it
+    /// is moving to and the size of its tty.
+    pub switched_to: Option<(String, TtySize)>,
+}
+
+/// Move the client attached to the session `current` over to `target`,
+/// the session it was on before if `target` is `-`. Used by both the
+/// switch keybinding and `shpool switch`.
+pub fn switch_client(
+    ctl: &Mutex<ReaderCtl>,
+    switch: &Mutex<SwitchState>,
+    current: &str,
+    target: &str,
+) -> anyhow::Result<SessionMessageReply> {
+    // Held throughout so that the attach routine can't see the client
+    // go without also seeing where it went.
+    let mut switch = switch.lock().unwrap();
+    let target = if target == PREVIOUS_SESSION {
+        match &switch.previous {
+            Some(previous) => previous.clone(),
+            None => return Ok(SessionMessageReply::Switch(SwitchReply::NoPrevious)),
+        }
+    } else {
+        String::from(target)
+    };
+    if target == current {
+        info!("already attached to '{}', not switching", target);
+        return Ok(SessionMessageReply::Switch(SwitchReply::Ok));
+    }
+
+    let msg = if switch.in_place {
+        ClientConnectionMsg::SwitchInPlace(target.clone())
+    } else {
+        ClientConnectionMsg::Switch(target.clone())
+    };
+    match send_client_connection_msg(ctl, msg)? {
+        ClientConnectionStatus::DetachNone => Ok(SessionMessageReply::NotAttached),
+        ClientConnectionStatus::Switched(size) => {
+            switch.switched_to = Some((target, size));
+            Ok(SessionMessageReply::Switch(SwitchReply::Ok))
+        }
+        _ => Ok(SessionMessageReply::Switch(SwitchReply::Ok)),
+    }
+}
+
+fn send_client_connection_msg(
+    ctl: &Mutex<ReaderCtl>,
+    msg: ClientConnectionMsg,
+) -> anyhow::Result<ClientConnectionStatus> {
+    let shell_to_client_ctl = ctl.lock().unwrap();
+    shell_to_client_ctl
+        .client_connection
+        .send_timeout(msg, SHELL_TO_CLIENT_CTL_TIMEOUT)
+        .context("signaling client detach to shell->client thread")?;
+    let status = shell_to_client_ctl
+        .client_connection_ack
+        .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
+        .context("waiting for client connection ack (3)")?;
+
+    info!("action detach, status={:?}", status);
+    Ok(status)
+}
+
 /// A notification that a new client has connected, sent to the
 /// shell->client thread.
 pub struct ClientConnection {
     /// All output data should be written to this sink rather than
     /// directly to the unix stream.
-    sink: io::BufWriter<UnixStream>,
+    sink: out_queue::Sink,
     /// The size of the client tty.
     size: TtySize,
     /// The raw unix socket stream. The shell->client thread should
     /// never write to this directly, just use it for control
     /// operations like shutdown.
     stream: UnixStream,
+    /// How much output to replay to this client, if it asked for
+    /// something other than the configured limits.
+    replay: Option<replay::Override>,
+    /// Whether the client understands ChunkKind::Evicted.
+    evicted_notice: bool,
+    /// Whether the client is still reading what gets sent to it.
+    liveness: keepalive::Liveness,
+}
+
+impl ClientConnection {
+    /// Shut the connection down once whatever is queued up for the
+    /// client has gone out, or it has had a moment to.
+    fn shutdown(&self) -> io::Result<()> {
+        self.sink.drain(CLIENT_DRAIN_TIMEOUT);
+        self.stream.shutdown(net::Shutdown::Both)
+    }
+
+    /// The replay limits for this client.
+    fn replay_limits(&self, config: &config::Config) -> replay::Limits {
+        let mut limits = replay::Limits::from_config(config);
+        if let Some(over) = &self.replay {
+            limits.apply(over);
+        }
+        limits
+    }
 }
 
 #[derive(Debug)]
@@ -139,6 +562,9 @@ pub enum ClientConnectionStatus {
     /// An instruction to detach had no effect, since there was already
     /// no client attached.
     DetachNone,
+    /// We let go of a client that is switching to another session in
+    /// place. Holds the size of its tty.
+    Switched(TtySize),
 }
 
 struct ResizeCmd {
@@ -150,6 +576,11 @@ struct ResizeCmd {
     when: time::Instant,
 }
 
+/// Milliseconds since the epoch, the way times go over the wire.
+pub fn unix_ms(t: time::SystemTime) -> i64 {
+    t.duration_since(time::UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
 fn log_if_error<T, E>(ctx: &str, res: Result<T, E>) -> Result<T, E>
 where
     E: std::fmt::Debug,
@@ -170,6 +601,69 @@ pub enum ClientConnectionMsg {
     /// Disconnect the client, but stay around and be ready for
     /// reconnects.
     Disconnect,
+    /// Disconnect the client like Disconnect, but first tell it to
+    /// attach to the given session instead.
+    Switch(String),
+    /// Disconnect the client like Disconnect, but first tell it that
+    /// another client is taking the session over, with the given
+    /// message.
+    Evict(String),
+    /// Let go of the client without closing its connection, after
+    /// telling it that it is being moved over to the given session.
+    SwitchInPlace(String),
+    /// Attach an additional client alongside the current one.
+    AddMirror(MirrorConnection),
+    /// Disconnect the mirror client with the given connection id.
+    RemoveMirror(usize),
+}
+
+/// A client attached alongside the main client of a session.
+pub struct MirrorConnection {
+    conn_id: usize,
+    /// The pid of the attach process, used to figure out which client
+    /// a resize came from.
+    client_pid: Option<i32>,
+    conn: ClientConnection,
+}
+
+/// The kinds of output the shell->client thread can hand over for
+/// `shpool capture`, or to another daemon.
+#[derive(Debug, Clone, Copy)]
+pub enum CaptureKind {
+    /// The same thing a reattaching client would get.
+    Restore,
+    /// The plain text scrollback.
+    Scrollback,
+    /// The raw output the spool is holding on to, which unlike the
+    /// restore buffer still has whatever a full screen program drew.
+    Contents,
+}
+
+/// A notification that a client's tty has changed size.
+pub struct SizeChange {
+    pub size: TtySize,
+    /// The pid of the attach process that got resized, if known.
+    pub client_pid: Option<i32>,
+}
+
+/// Everything needed to attach a mirror client to a session without
+/// taking the session's inner lock, which the main client is holding.
+pub struct MirrorArgs {
+    pub name: String,
+    pub conn_id: usize,
+    pub client_pid: Option<i32>,
+    pub stream: UnixStream,
+    pub size: TtySize,
+    pub shell_to_client_ctl: Arc<Mutex<ReaderCtl>>,
+    pub pty_master: shpool_pty::fork::Master,
+    pub pump_cpu_ns: Arc<AtomicU64>,
+    pub bytes_in: Arc<AtomicU64>,
+    pub output_queue: Arc<out_queue::Counters>,
+    pub config: config::Manager,
+    /// From `attach --restore`.
+    pub replay: Option<replay::Override>,
+    /// From `attach --read-only`.
+    pub read_only: bool,
 }
 
 pub struct ReaderArgs {
@@ -178,11 +672,94 @@ pub struct ReaderArgs {
     pub session_restore_config: String,
     pub client_connection: crossbeam_channel::Receiver<ClientConnectionMsg>,
     pub client_connection_ack: crossbeam_channel::Sender<ClientConnectionStatus>,
-    pub tty_size_change: crossbeam_channel::Receiver<TtySize>,
+    pub tty_size_change: crossbeam_channel::Receiver<SizeChange>,
     pub tty_size_change_ack: crossbeam_channel::Sender<()>,
     pub heartbeat: crossbeam_channel::Receiver<()>,
     // true if the client is still live, false if it has hung up on us
     pub heartbeat_ack: crossbeam_channel::Sender<bool>,
+    /// Where to publish the memory usage of the output spool.
+    pub spool_bytes: Arc<AtomicUsize>,
+    /// Shared with Session::evict_spool.
+    pub evict_spool: Arc<AtomicBool>,
+    /// How long the session must sit detached with no output before
+    /// the spool gets swapped out to disk, if ever.
+    pub spool_swap_after: Option<time::Duration>,
+    /// Where to put the spool when it gets swapped out.
+    pub spool_swap_path: PathBuf,
+    /// Where disk backed spools checkpoint their buffer.
+    pub spool_checkpoint_path: PathBuf,
+    /// How many lines of scrollback to keep for scroll mode.
+    pub scrollback_lines: usize,
+    /// Scroll mode commands decoded by the client->shell thread.
+    pub scroll: crossbeam_channel::Receiver<scrollback::ScrollCmd>,
+    /// Shared with ReaderCtl::scrolling.
+    pub scrolling: Arc<AtomicBool>,
+    /// Screen lock commands from the client->shell thread.
+    pub screen_lock: crossbeam_channel::Receiver<screen_lock::Cmd>,
+    /// Shared with ReaderCtl::screen_locked.
+    pub screen_locked: Arc<AtomicBool>,
+    /// Where to publish the size of the pty.
+    pub pty_size: Arc<Mutex<TtySize>>,
+    /// How long the session may sit detached with no output before
+    /// it gets killed, if ever.
+    pub idle_ttl: Option<time::Duration>,
+    /// The ttl reaper's mailbox, used to get the session killed once
+    /// it has been idle for too long.
+    pub reap: crossbeam_channel::Sender<ttl_reaper::Msg>,
+    /// Shared with Session::name.
+    pub session_name: Arc<Mutex<String>>,
+    /// Renames to pass on to clients.
+    pub rename: crossbeam_channel::Receiver<String>,
+    /// Shared with Session::output_taps.
+    pub output_taps: OutputTaps,
+    /// Requests for a copy of the session's output.
+    pub capture: crossbeam_channel::Receiver<CaptureKind>,
+    /// Replies to capture requests.
+    pub capture_ack: crossbeam_channel::Sender<Vec<u8>>,
+    /// For sessions taken over from a previous daemon, the restore
+    /// buffer it handed over. Such sessions start out detached, and
+    /// their shell is long past the prompt setup.
+    pub adopted: Option<Vec<u8>>,
+    /// True if the session was created without a client attached, so
+    /// there is no initial connection to wait for.
+    pub detached: bool,
+    /// The pid of the session's shell.
+    pub child_pid: libc::pid_t,
+    /// The exit reaper's mailbox, used to hand over the session's
+    /// final output if the shell exits while nobody is attached.
+    pub exited: crossbeam_channel::Sender<exit_reaper::Exited>,
+    /// The most output per second to read from the pty while no client
+    /// is attached, if there is a limit.
+    pub detached_output_limit: Option<usize>,
+    /// Shared with Session::throttled.
+    pub throttled: Arc<AtomicBool>,
+    /// Shared with Session::activity.
+    pub activity: Arc<AtomicBool>,
+    /// Shared with Session::bell.
+    pub bell: Arc<AtomicBool>,
+    /// For telling the user about bells and finished commands while
+    /// nobody is attached.
+    pub notifier: notify::Notifier,
+    /// Shared with Session::watches.
+    pub watches: Arc<watch::Watches>,
+    /// For running commands when a watch matches.
+    pub hook_cmds: hook_cmds::Runner,
+    /// Shared with Server::stopping.
+    pub stopping: Arc<AtomicBool>,
+    /// Shared with Session::last_active.
+    pub last_active: Arc<AtomicI64>,
+    /// Shared with Session::bytes_out.
+    pub bytes_out: Arc<AtomicU64>,
+    /// Shared with Session::lines_out.
+    pub lines_out: Arc<AtomicU64>,
+    /// Shared with Session::reported_cwd.
+    pub reported_cwd: Arc<Mutex<Option<String>>>,
+    /// Shared with Session::last_heartbeat.
+    pub last_heartbeat: Arc<AtomicI64>,
+    /// Which escape sequences to strip out of the output.
+    pub escape_filter: escape_filter::Policy,
+    /// Whether to keep inline images in the spool.
+    pub replay_graphics: bool,
 }
 
 impl SessionInner {
@@ -192,7 +769,7 @@ impl SessionInner {
     #[instrument(skip_all, fields(s = self.name))]
     pub fn spawn_shell_to_client(
         &self,
-        args: ReaderArgs,
+        mut args: ReaderArgs,
     ) -> anyhow::Result<thread::JoinHandle<anyhow::Result<()>>> {
         use nix::poll;
 
@@ -203,36 +780,116 @@ impl SessionInner {
         // custom command or blanked out the prompt_prefix config option.
         let prompt_prefix_is_blank =
             self.config.get().prompt_prefix.as_ref().map(|p| p.is_empty()).unwrap_or(false);
-        let mut has_seen_prompt_sentinel = self.custom_cmd || prompt_prefix_is_blank;
+        let mut has_seen_prompt_sentinel =
+            self.custom_cmd || prompt_prefix_is_blank || args.adopted.is_some();
 
         let daily_messenger = Arc::clone(&self.daily_messenger);
-        let mut needs_initial_motd_dump = self.needs_initial_motd_dump;
+        let mut initial_motd = self.initial_motd.clone();
 
         let mut pty_master = self.pty_master.is_parent()?;
         let watchable_master = pty_master;
         let name = self.name.clone();
-        let mut closure = move || {
+        let pump_cpu_ns = Arc::clone(&self.pump_cpu_ns);
+        let checkpoint_path = args.spool_checkpoint_path.clone();
+        let stopping = Arc::clone(&args.stopping);
+        let config = self.config.clone();
+        let closure = move || {
             let _s = span!(Level::INFO, "shell->client", s = name, cid = args.conn_id).entered();
+            let mut cpu_meter = threads::ThreadCpuMeter::new(pump_cpu_ns);
 
             let mut output_spool = session_restore::new(
                 &args.session_restore_config,
                 &args.tty_size,
+                &args.spool_checkpoint_path,
             )?;
+            args.spool_bytes.store(output_spool.memory_usage(), Ordering::Relaxed);
+            let mut escape_filter = if args.escape_filter.is_noop() {
+                None
+            } else {
+                Some(escape_filter::Filter::new(args.escape_filter))
+            };
+            let mut utf8_boundary = utf8::Boundary::default();
+            // Images are kept out of the spool unless asked for, but still
+            // go out to clients live.
+            let mut spool_graphics = if args.replay_graphics {
+                None
+            } else {
+                Some(escape_filter::Filter::new(escape_filter::Policy::graphics()))
+            };
+            // The size the spool was last told about, needed to build a fresh
+            // spool when swapping the current one out.
+            let mut spool_tty_size = args.tty_size.clone();
+            let mut swap_file = session_restore::swap::SwapFile::new(args.spool_swap_path.clone());
+            let mut last_output_at = time::Instant::now();
+            // When the last client went away, for idle ttl and swapping purposes.
+            let mut detached_at = time::Instant::now();
+            let mut idle_reap_requested = false;
+            // What to do with the session now that the last client is gone.
+            let mut linger = linger::Policy::Keep;
+            let mut linger_reap_requested = false;
+            let mut throttle = args.detached_output_limit.map(throttle::Throttle::new);
+            // While throttled, when to start reading from the pty again.
+            let mut paused_until: Option<time::Instant> = None;
+            let mut scrollback = scrollback::Scrollback::new(args.scrollback_lines);
+            let mut title = title::Tracker::default();
+            let mut bell_scanner = bell::Scanner::default();
+            let mut command_scanner = notify::CommandScanner::default();
+            let mut watch_scanner = watch::Scanner::new();
+            let mut scroll_mode: Option<scrollback::ScrollMode> = None;
+            // What the locked screen is showing, if it is locked.
+            let mut lock_prompt = screen_lock::Prompt::default();
+            // Extra clients attached alongside the main one, and the size
+            // of the main client's tty, which together determine the pty size.
+            let mut mirrors: Vec<MirrorConnection> = vec![];
+            let mut primary_size = args.tty_size.clone();
             let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
             let mut poll_fds = [poll::PollFd::new(
                 watchable_master.borrow_fd().ok_or(anyhow!("no master fd"))?,
                 poll::PollFlags::POLLIN,
             )];
 
-            // block until we get the first connection attached so that we don't drop
-            // the initial prompt on the floor
-            info!("waiting for initial client connection");
-            let mut client_conn: ClientConnectionMsg =
-                args.client_connection.recv().context("waiting for initial client connection")?;
-            args.client_connection_ack
-                .send(ClientConnectionStatus::New)
-                .context("sending initial client connection ack")?;
-            info!("got initial client connection");
+            let adopted = args.adopted.take();
+            let mut client_conn = if let Some(adopted_buf) = &adopted {
+                // There is no prompt to protect, the shell has been running
+                // for a while already, so just pick up its old output.
+                info!("adopting {} bytes of output from the previous daemon", adopted_buf.len());
+                output_spool.process(adopted_buf);
+                scrollback.process(adopted_buf);
+                title.process(adopted_buf);
+                if let Some(cwd) = title.take_cwd() {
+                    *args.reported_cwd.lock().unwrap() = Some(cwd);
+                }
+                args.spool_bytes.store(output_spool.memory_usage(), Ordering::Relaxed);
+                ClientConnectionMsg::Disconnect
+            } else if args.detached {
+                // The initial prompt will make it into the spool, so whoever
+                // attaches first gets to see it in the restore buffer.
+                info!("created detached, not waiting for a client connection");
+                ClientConnectionMsg::Disconnect
+            } else {
+                // block until we get the first connection attached so that we don't drop
+                // the initial prompt on the floor
+                info!("waiting for initial client connection");
+                let mut client_conn = args
+                    .client_connection
+                    .recv()
+                    .context("waiting for initial client connection")?;
+                args.client_connection_ack
+                    .send(ClientConnectionStatus::New)
+                    .context("sending initial client connection ack")?;
+                info!("got initial client connection");
+                if let ClientConnectionMsg::New(conn) = &mut client_conn
+                    && config.get().default_title.unwrap_or(false)
+                {
+                    let name = args.session_name.lock().unwrap().clone();
+                    if let Err(e) =
+                        Self::write_data(&mut conn.sink, &title.reemit(&name, true), &[])
+                    {
+                        warn!("writing default title: {:?}", e);
+                    }
+                }
+                client_conn
+            };
 
             let mut resize_cmd = if let ClientConnectionMsg::New(conn) = &client_conn {
                 Some(ResizeCmd { size: conn.size.clone(), when: time::Instant::now() })
@@ -240,6 +897,22 @@ impl SessionInner {
                 None
             };
 
+            // A restore buffer that has been generated for a newly attached
+            // client, but not yet sent because we are waiting to stitch it
+            // together with the first chunk of live output.
+            let mut pending_restore: Option<Vec<u8>> = None;
+            // The limits the pending restore buffer was cut down with,
+            // which also say how fast to send it.
+            let mut replay_limits = replay::Limits::default();
+
+            // Output left over from a session of the same name that was
+            // running when the last daemon went away. We hold off on showing
+            // it until the prompt setup has been dropped so it lands right
+            // above the new prompt.
+            // An adopted session's output already covers the checkpoint.
+            let mut recovered = if adopted.is_some() { None } else { output_spool.recover() };
+            args.spool_bytes.store(output_spool.memory_usage(), Ordering::Relaxed);
+
             loop {
                 let mut do_reattach = false;
                 crossbeam_channel::select! {
@@ -250,49 +923,117 @@ impl SessionInner {
                                 do_reattach = true;
                                 let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
                                     Self::write_exit_chunk(&mut old_conn.sink, 0);
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
+                                    old_conn.shutdown()?;
                                     ClientConnectionStatus::Replaced
                                 } else {
                                     ClientConnectionStatus::New
                                 };
 
+                                // A session stopped by a previous daemon we took
+                                // over from needs resuming too, so go by the config
+                                // rather than remembering whether we stopped it.
+                                let policy = linger::Policy::from_config(
+                                    &config.get(),
+                                    &args.session_name.lock().unwrap(),
+                                );
+                                if linger == linger::Policy::Stop || policy == linger::Policy::Stop {
+                                    linger::resume(args.child_pid);
+                                }
+                                linger = linger::Policy::Keep;
+
+                                // The new client is not looking at the scrollback.
+                                scroll_mode = None;
+                                args.scrolling.store(false, Ordering::Relaxed);
+
+                                // Someone is watching again, so there is no need
+                                // to hold the output back.
+                                paused_until = None;
+                                args.throttled.store(false, Ordering::Relaxed);
+                                args.activity.store(false, Ordering::Relaxed);
+                                args.bell.store(false, Ordering::Relaxed);
+                                watch_scanner.reset();
+                                args.watches.reset();
+
                                 // Always instantly resize the spool, since we don't
                                 // need to inject a delay into that.
-                                output_spool.resize(conn.size.clone());
-
-                                // First resize the pty to be bigger than it needs to be,
-                                // we do this immediately so that the extra size
-                                // can "bake" for a little bit, which emacs seems
-                                // to require in order to pick up the jiggle.
-                                let oversize = TtySize {
-                                    rows: conn.size.rows + 1,
-                                    cols: conn.size.cols + 1,
-                                    xpixel: conn.size.xpixel,
-                                    ypixel: conn.size.ypixel,
+                                primary_size = conn.size.clone();
+                                let size = Self::fit_size(&primary_size, &mirrors);
+                                output_spool.resize(size.clone());
+                                spool_tty_size = size.clone();
+
+                                let redraw = config.get().reattach_redraw.unwrap_or_default();
+                                let when = if redraw == config::ReattachRedraw::Bounce {
+                                    // First resize the pty to be bigger than it needs to be,
+                                    // we do this immediately so that the extra size
+                                    // can "bake" for a little bit, which emacs seems
+                                    // to require in order to pick up the jiggle.
+                                    let oversize = TtySize {
+                                        rows: size.rows + 1,
+                                        cols: size.cols + 1,
+                                        xpixel: size.xpixel,
+                                        ypixel: size.ypixel,
+                                    };
+                                    oversize.set_fd(pty_master.raw_fd().ok_or(anyhow!("no master fd"))?)?;
+                                    time::Instant::now().add(REATTACH_RESIZE_DELAY)
+                                } else {
+                                    time::Instant::now()
                                 };
-                                oversize.set_fd(pty_master.raw_fd().ok_or(anyhow!("no master fd"))?)?;
 
                                 // Prepare a resize command for pty to execute later.
-                                resize_cmd = Some(ResizeCmd {
-                                    size: conn.size.clone(),
-                                    when: time::Instant::now().add(REATTACH_RESIZE_DELAY),
-                                });
+                                resize_cmd = Some(ResizeCmd { size, when });
                                 client_conn = ClientConnectionMsg::New(conn);
 
                                 args.client_connection_ack.send(ack)
                                     .context("sending client connection ack")?;
                             }
-                            Ok(ClientConnectionMsg::Disconnect) => {
+                            Ok(msg @ (ClientConnectionMsg::Disconnect
+                                | ClientConnectionMsg::Switch(_)
+                                | ClientConnectionMsg::Evict(_)
+                                | ClientConnectionMsg::SwitchInPlace(_))) => {
                                 let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
-                                    info!("disconnect, shutting down client stream");
-                                    Self::write_exit_chunk(&mut old_conn.sink, 0);
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
-                                    ClientConnectionStatus::Detached
+                                    if let ClientConnectionMsg::SwitchInPlace(target) = &msg {
+                                        // The connection lives on, so whoever picks it up
+                                        // next is responsible for closing it.
+                                        info!("switching client to '{}' in place", target);
+                                        Self::write_switch_chunk(&mut old_conn.sink, ChunkKind::Switched, target);
+                                        ClientConnectionStatus::Switched(primary_size.clone())
+                                    } else {
+                                        info!("disconnect, shutting down client stream");
+                                        match &msg {
+                                            ClientConnectionMsg::Switch(target) => {
+                                                Self::write_switch_chunk(&mut old_conn.sink, ChunkKind::Switch, target);
+                                            }
+                                            ClientConnectionMsg::Evict(notice) if old_conn.evicted_notice => {
+                                                info!("evicting client: {}", notice);
+                                                Self::write_evicted_chunk(&mut old_conn.sink, notice);
+                                            }
+                                            _ => {}
+                                        }
+                                        Self::write_exit_chunk(&mut old_conn.sink, 0);
+                                        old_conn.shutdown()?;
+                                        ClientConnectionStatus::Detached
+                                    }
                                 } else {
                                     info!("disconnect, no client stream to shut down");
                                     ClientConnectionStatus::DetachNone
                                 };
                                 client_conn = ClientConnectionMsg::Disconnect;
+                                detached_at = time::Instant::now();
+                                linger = linger::Policy::from_config(
+                                    &config.get(),
+                                    &args.session_name.lock().unwrap(),
+                                );
+                                linger_reap_requested = false;
+                                if linger == linger::Policy::Stop {
+                                    linger::stop(args.child_pid);
+                                }
+                                scroll_mode = None;
+                                args.scrolling.store(false, Ordering::Relaxed);
+                                // Mirrors go along with the client they are mirroring.
+                                for mut mirror in mirrors.drain(..) {
+                                    Self::write_exit_chunk(&mut mirror.conn.sink, 0);
+                                    let _ = mirror.conn.shutdown();
+                                }
 
                                 args.client_connection_ack.send(ack)
                                     .context("sending client disconnect ack")?;
@@ -305,7 +1046,7 @@ impl SessionInner {
                                     // write an exit status frame so the attach process
                                     // can exit with the same exit code as the child shell
                                     Self::write_exit_chunk(&mut old_conn.sink, exit_status);
-                                    old_conn.stream.shutdown(net::Shutdown::Both)?;
+                                    old_conn.shutdown()?;
 
                                     ClientConnectionStatus::Detached
                                 } else {
@@ -314,12 +1055,56 @@ impl SessionInner {
                                           exit_status);
                                     ClientConnectionStatus::DetachNone
                                 };
+                                for mut mirror in mirrors.drain(..) {
+                                    Self::write_exit_chunk(&mut mirror.conn.sink, exit_status);
+                                    let _ = mirror.conn.shutdown();
+                                }
                                 args.client_connection_ack.send(ack)
                                     .context("sending client disconnect exit ack")?;
 
                                 return Ok(());
                             }
 
+                            Ok(ClientConnectionMsg::AddMirror(mut mirror)) => {
+                                info!("adding mirror cid={} (rows={}, cols={})",
+                                      mirror.conn_id, mirror.conn.size.rows, mirror.conn.size.cols);
+                                Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                                let replay_limits = mirror.conn.replay_limits(&config.get());
+                                let mut restore_buf = replay_limits.trim(output_spool.restore_buffer(), &spool_tty_size);
+                                let name = args.session_name.lock().unwrap().clone();
+                                restore_buf.extend(title.reemit(&name, config.get().default_title.unwrap_or(false)));
+                                if let Err(e) = Self::write_restore(&mut mirror.conn.sink, &restore_buf, &[], &replay_limits) {
+                                    warn!("writing restore buf to mirror: {:?}", e);
+                                }
+                                mirrors.push(mirror);
+                                if let Some(size) = Self::refit_size(&primary_size, &mirrors, &spool_tty_size) {
+                                    output_spool.resize(size.clone());
+                                    spool_tty_size = size.clone();
+                                    resize_cmd = Some(ResizeCmd { size, when: time::Instant::now() });
+                                }
+                                args.client_connection_ack.send(ClientConnectionStatus::New)
+                                    .context("sending add mirror ack")?;
+                            }
+                            Ok(ClientConnectionMsg::RemoveMirror(conn_id)) => {
+                                let ack = match mirrors.iter().position(|m| m.conn_id == conn_id) {
+                                    Some(i) => {
+                                        info!("removing mirror cid={}", conn_id);
+                                        let mut mirror = mirrors.remove(i);
+                                        Self::write_exit_chunk(&mut mirror.conn.sink, 0);
+                                        let _ = mirror.conn.shutdown();
+                                        ClientConnectionStatus::Detached
+                                    }
+                                    None => ClientConnectionStatus::DetachNone,
+                                };
+                                if let Some(size) = Self::refit_size(&primary_size, &mirrors, &spool_tty_size) {
+                                    output_spool.resize(size.clone());
+                                    spool_tty_size = size.clone();
+                                    resize_cmd = Some(ResizeCmd { size, when: time::Instant::now() });
+                                }
+                                args.client_connection_ack.send(ack)
+                                    .context("sending remove mirror ack")?;
+                            }
+
                             // SessionInner getting dropped, so this thread should go away.
                             Err(crossbeam_channel::RecvError) => {
                                 info!("client conn: bailing due to RecvError");
@@ -329,9 +1114,31 @@ impl SessionInner {
                     }
                     recv(args.tty_size_change) -> new_size => {
                         match new_size {
-                            Ok(size) => {
-                                info!("resize size={:?}", size);
+                            Ok(change) => {
+                                info!("resize size={:?} pid={:?}", change.size, change.client_pid);
+                                match mirrors.iter_mut().find(|m| {
+                                    m.client_pid.is_some() && m.client_pid == change.client_pid
+                                }) {
+                                    Some(mirror) => mirror.conn.size = change.size,
+                                    None => primary_size = change.size,
+                                }
+                                let size = Self::fit_size(&primary_size, &mirrors);
                                 output_spool.resize(size.clone());
+                                spool_tty_size = size.clone();
+                                if let (Some(mode), ClientConnectionMsg::New(conn)) =
+                                    (&scroll_mode, &mut client_conn)
+                                    && let Err(e) = Self::write_data(
+                                        &mut conn.sink, &mode.render(&scrollback, &size), &[])
+                                {
+                                    warn!("repainting scroll mode after resize: {:?}", e);
+                                }
+                                if let (true, ClientConnectionMsg::New(conn)) =
+                                    (args.screen_locked.load(Ordering::Relaxed), &mut client_conn)
+                                    && let Err(e) = Self::write_data(
+                                        &mut conn.sink, &screen_lock::render(lock_prompt, &size), &[])
+                                {
+                                    warn!("repainting locked screen after resize: {:?}", e);
+                                }
                                 resize_cmd = Some(ResizeCmd {
                                     size,
                                     // No delay needed for ordinary resizes, just
@@ -353,7 +1160,26 @@ impl SessionInner {
                             match chunk.write_to(&mut conn.sink).and_then(|_| conn.sink.flush()) {
                                 Ok(_) => {
                                     trace!("wrote heartbeat");
-                                    true
+                                    let now = time::Instant::now();
+                                    let last_read = conn.liveness.observe(
+                                        conn.sink.written(),
+                                        keepalive::unread_bytes(&conn.stream),
+                                        now,
+                                    );
+                                    let timeout = keepalive::timeout(&config.get());
+                                    if now.duration_since(last_read) > timeout {
+                                        info!(
+                                            "client has not read anything in {:?}, assuming it vanished",
+                                            timeout
+                                        );
+                                        false
+                                    } else {
+                                        args.last_heartbeat.store(
+                                            unix_ms(time::SystemTime::now()),
+                                            Ordering::Relaxed,
+                                        );
+                                        true
+                                    }
                                 }
                                 Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
                                     trace!("client hangup: {:?}", e);
@@ -372,6 +1198,123 @@ impl SessionInner {
                             .context("sending heartbeat ack")?;
                     }
 
+                    recv(args.scroll) -> cmd => {
+                        let cmd = match cmd {
+                            Ok(cmd) => cmd,
+                            Err(err) => {
+                                warn!("scroll: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        };
+                        debug!("scroll cmd={:?}", cmd);
+                        let out = match (cmd, scroll_mode.as_mut()) {
+                            (scrollback::ScrollCmd::Enter, None) => {
+                                let (mode, out) =
+                                    scrollback::ScrollMode::enter(&scrollback, &spool_tty_size);
+                                scroll_mode = Some(mode);
+                                out
+                            }
+                            (scrollback::ScrollCmd::Show { title, text }, None) => {
+                                let (mode, out) =
+                                    scrollback::ScrollMode::show(title, &text, &spool_tty_size);
+                                scroll_mode = Some(mode);
+                                out
+                            }
+                            (scrollback::ScrollCmd::Enter | scrollback::ScrollCmd::Show { .. }, Some(_)) => vec![],
+                            (scrollback::ScrollCmd::Exit, Some(_)) => {
+                                args.scrolling.store(false, Ordering::Relaxed);
+                                scroll_mode.take().map(|mode| mode.exit()).unwrap_or_default()
+                            }
+                            (cmd, Some(mode)) => mode.handle(cmd, &scrollback, &spool_tty_size),
+                            (_, None) => {
+                                args.scrolling.store(false, Ordering::Relaxed);
+                                vec![]
+                            }
+                        };
+                        if let (false, ClientConnectionMsg::New(conn)) = (out.is_empty(), &mut client_conn)
+                            && let Err(e) = Self::write_data(&mut conn.sink, &out, &[])
+                        {
+                            warn!("writing scroll mode output: {:?}", e);
+                        }
+                    }
+                    recv(args.screen_lock) -> cmd => {
+                        let cmd = match cmd {
+                            Ok(cmd) => cmd,
+                            Err(err) => {
+                                warn!("screen lock: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        };
+                        info!("screen lock cmd={:?}", cmd);
+                        let out = match cmd {
+                            screen_lock::Cmd::Lock => {
+                                // There is nothing to scroll through on a locked screen.
+                                scroll_mode = None;
+                                args.scrolling.store(false, Ordering::Relaxed);
+                                lock_prompt = screen_lock::Prompt::default();
+                                screen_lock::render(lock_prompt, &spool_tty_size)
+                            }
+                            screen_lock::Cmd::Show(prompt) => {
+                                lock_prompt = prompt;
+                                screen_lock::render(lock_prompt, &spool_tty_size)
+                            }
+                            screen_lock::Cmd::Unlock => {
+                                // Bring the client back up to date the same
+                                // way we would if it had just attached.
+                                do_reattach = true;
+                                screen_lock::CLEAR.to_vec()
+                            }
+                        };
+                        if let ClientConnectionMsg::New(conn) = &mut client_conn
+                            && let Err(e) = Self::write_data(&mut conn.sink, &out, &[])
+                        {
+                            warn!("writing locked screen: {:?}", e);
+                        }
+                    }
+                    recv(args.rename) -> new_name => {
+                        let new_name = match new_name {
+                            Ok(n) => n,
+                            Err(err) => {
+                                warn!("rename: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        };
+                        info!("telling clients about rename to '{}'", new_name);
+                        // different [[watch]] tables might apply now
+                        watch_scanner.invalidate();
+                        if let ClientConnectionMsg::New(conn) = &mut client_conn {
+                            Self::write_rename_chunk(&mut conn.sink, &new_name);
+                        }
+                        for mirror in mirrors.iter_mut() {
+                            Self::write_rename_chunk(&mut mirror.conn.sink, &new_name);
+                        }
+                    }
+
+                    recv(args.capture) -> kind => {
+                        let kind = match kind {
+                            Ok(k) => k,
+                            Err(err) => {
+                                warn!("capture: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        };
+                        info!("capturing {:?}", kind);
+                        let captured = match kind {
+                            CaptureKind::Restore => {
+                                Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                                output_spool.restore_buffer()
+                            }
+                            CaptureKind::Scrollback => scrollback.text().into_bytes(),
+                            CaptureKind::Contents => {
+                                Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                                output_spool.contents()
+                            }
+                        };
+                        if let Err(err) = args.capture_ack.send_timeout(captured, SHELL_TO_CLIENT_CTL_TIMEOUT) {
+                            warn!("sending capture: {:?}", err);
+                        }
+                    }
+
                     // make this select non-blocking so we spend most of our time parked
                     // in poll
                     default => {}
@@ -390,6 +1333,7 @@ impl SessionInner {
                             warn!("error resizing pty: {}", e);
                         }
                         executed_resize = true;
+                        *args.pty_size.lock().unwrap() = resize_cmd.size.clone();
                         info!(
                             "resized fd (rows={}, cols={})",
                             resize_cmd.size.rows, resize_cmd.size.cols
@@ -399,62 +1343,258 @@ impl SessionInner {
                     resize_cmd = None;
                 }
 
+                if has_seen_prompt_sentinel && let Some(recovered_buf) = recovered.take() {
+                    info!("restoring {} bytes recovered from a previous daemon", recovered_buf.len());
+                    replay_limits = match &client_conn {
+                        ClientConnectionMsg::New(conn) => conn.replay_limits(&config.get()),
+                        _ => replay::Limits::from_config(&config.get()),
+                    };
+                    pending_restore = Some(replay_limits.trim(recovered_buf, &spool_tty_size));
+                }
+
+                // A client that fell far enough behind to have output dropped
+                // is showing a garbled screen, so once it has caught up it gets
+                // the whole thing again.
+                let mut resyncing = false;
+                if let ClientConnectionMsg::New(conn) = &client_conn
+                    && conn.sink.resync_due()
+                {
+                    info!("client caught up after dropping output, resyncing");
+                    do_reattach = true;
+                    resyncing = true;
+                }
+                for mirror in mirrors.iter_mut().filter(|mirror| mirror.conn.sink.resync_due()) {
+                    info!(
+                        "mirror cid={} caught up after dropping output, resyncing",
+                        mirror.conn_id
+                    );
+                    Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                    let replay_limits = mirror.conn.replay_limits(&config.get());
+                    let mut restore_buf =
+                        replay_limits.trim(output_spool.restore_buffer(), &spool_tty_size);
+                    if !restore_buf.is_empty() {
+                        restore_buf.splice(0..0, out_queue::RESYNC_PREFIX.iter().copied());
+                    }
+                    if let Err(e) = Self::write_restore(
+                        &mut mirror.conn.sink,
+                        &restore_buf,
+                        &[],
+                        &replay_limits,
+                    ) {
+                        warn!("writing resync restore buf to mirror: {:?}", e);
+                    }
+                }
+
                 if do_reattach {
                     info!("executing reattach protocol (config={})", &args.session_restore_config);
-                    let restore_buf = output_spool.restore_buffer();
+                    Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                    replay_limits = match &client_conn {
+                        ClientConnectionMsg::New(conn) => conn.replay_limits(&config.get()),
+                        _ => replay::Limits::from_config(&config.get()),
+                    };
+                    let mut restore_buf =
+                        replay_limits.trim(output_spool.restore_buffer(), &spool_tty_size);
+                    if resyncing && !restore_buf.is_empty() {
+                        restore_buf.splice(0..0, out_queue::RESYNC_PREFIX.iter().copied());
+                    }
+                    if let ClientConnectionMsg::New(conn) = &client_conn {
+                        restore_buf = replay_limits.fix_cursor(
+                            restore_buf,
+                            &output_spool.cursor_fixup(),
+                            &spool_tty_size,
+                            &conn.size,
+                        );
+                        // The client is probably showing the title of whatever
+                        // it was attached to last.
+                        let name = args.session_name.lock().unwrap().clone();
+                        restore_buf.extend(
+                            title.reemit(&name, config.get().default_title.unwrap_or(false)),
+                        );
+                    }
                     info!("restore buffer length: {} bytes", restore_buf.len());
-                    if let (true, ClientConnectionMsg::New(conn)) =
-                        (!restore_buf.is_empty(), &mut client_conn)
-                    {
-                        trace!("restore chunk='{}'", String::from_utf8_lossy(&restore_buf[..]));
-                        // send the restore buffer, broken up into chunks so that we don't make
-                        // the client allocate too much
-                        for block in restore_buf.as_slice().chunks(consts::BUF_SIZE) {
-                            let chunk = Chunk { kind: ChunkKind::Data, buf: block };
-
-                            if let Err(err) = chunk.write_to(&mut conn.sink) {
-                                warn!("err writing session-restore buf: {:?}", err);
-                            }
-                        }
-                        if let Err(err) = conn.sink.flush() {
-                            warn!("err flushing session-restore: {:?}", err);
+                    pending_restore = if restore_buf.is_empty() {
+                        None
+                    } else {
+                        trace!("restore chunk='{}'", logging::payload(&restore_buf[..]));
+                        Some(restore_buf)
+                    };
+
+                    // A client attaching to a locked screen gets the locked
+                    // screen, and the restore buffer waits for the unlock.
+                    if args.screen_locked.load(Ordering::Relaxed) {
+                        pending_restore = None;
+                        lock_prompt = screen_lock::Prompt::default();
+                        if let ClientConnectionMsg::New(conn) = &mut client_conn
+                            && let Err(e) = Self::write_data(
+                                &mut conn.sink,
+                                &screen_lock::render(lock_prompt, &spool_tty_size),
+                                &[],
+                            )
+                        {
+                            warn!("writing locked screen: {:?}", e);
                         }
                     }
                 }
 
-                // TODO(ethan): what if poll times out on a tick when we have just
-                // set up a restore chunk? It looks like we will just drop the
-                // data as things are now.
+                // The daemon is short on spool memory and picked ours to go.
+                if args.evict_spool.swap(false, Ordering::Relaxed) {
+                    info!("evicting spool holding {} bytes", output_spool.memory_usage());
+                    output_spool = session_restore::new(
+                        &args.session_restore_config,
+                        &spool_tty_size,
+                        &args.spool_checkpoint_path,
+                    )?;
+                    args.spool_bytes.store(output_spool.memory_usage(), Ordering::Relaxed);
+                }
+
+                // Some sessions are not meant to outlive their last client
+                // for long.
+                if let (linger::Policy::Kill(grace), ClientConnectionMsg::Disconnect, false) =
+                    (linger, &client_conn, linger_reap_requested)
+                    && detached_at.elapsed() >= grace
+                {
+                    info!("no client for {:?}, asking the reaper to kill the session", grace);
+                    linger_reap_requested = true;
+                    let name = args.session_name.lock().unwrap().clone();
+                    if let Err(e) = args.reap.send(ttl_reaper::Msg::Reap(name, time::Instant::now())) {
+                        warn!("sending detached session to the reaper: {:?}", e);
+                    }
+                }
+
+                // Leave the output of a throttled session sitting in the pty
+                // until the pause is over, but keep waking up as usual so that
+                // a client attaching does not have to wait.
+                if let (Some(until), ClientConnectionMsg::Disconnect) = (paused_until, &client_conn)
+                {
+                    let now = time::Instant::now();
+                    if now < until {
+                        let poll_dur = Duration::from_millis(SHELL_TO_CLIENT_POLL_MS as u64);
+                        thread::sleep(std::cmp::min(until - now, poll_dur));
+                        continue;
+                    }
+                    paused_until = None;
+                }
 
                 // Block until the shell has some data for us so we can be sure our reads
                 // always succeed. We don't want to end up blocked forever on a read while
-                // a client is trying to attach.
-                let nready = match poll::poll(&mut poll_fds, SHELL_TO_CLIENT_POLL_MS) {
+                // a client is trying to attach. If we are sitting on a restore buffer, only
+                // wait a little while for live output to stitch it to.
+                let poll_ms = if pending_restore.is_some() {
+                    RESTORE_STITCH_WINDOW_MS
+                } else if scroll_mode.is_some() {
+                    SCROLL_MODE_POLL_MS
+                } else {
+                    SHELL_TO_CLIENT_POLL_MS
+                };
+                let nready = match poll::poll(&mut poll_fds, poll_ms) {
                     Ok(n) => n,
                     Err(e) => {
                         error!("polling pty master: {:?}", e);
                         return Err(e)?;
                     }
                 };
-                if nready == 0 {
-                    // if timeout
+                // A character cut off by the last read that still hasn't been
+                // finished never will be, so send out what there is of it.
+                let stale = if nready == 0 { utf8_boundary.flush() } else { vec![] };
+                if nready == 0 && stale.is_empty() {
+                    // if timeout, there is no live output to go with the restore
+                    // buffer, so just send it on its own.
+                    if let (Some(restore_buf), ClientConnectionMsg::New(conn)) =
+                        (pending_restore.take(), &mut client_conn)
+                        && let Err(err) =
+                            Self::write_restore(&mut conn.sink, &restore_buf, &[], &replay_limits)
+                    {
+                        warn!("err writing session-restore buf: {:?}", err);
+                    }
+                    output_spool.tick();
+
+                    // A detached session that has gone quiet for long enough
+                    // doesn't need its spool in memory.
+                    if let (Some(swap_after), ClientConnectionMsg::Disconnect, false) =
+                        (args.spool_swap_after, &client_conn, swap_file.is_swapped())
+                        && last_output_at.elapsed() >= swap_after
+                        && detached_at.elapsed() >= swap_after
+                        && output_spool.memory_usage() > 0
+                    {
+                        let contents = output_spool.contents();
+                        match swap_file.store(&contents) {
+                            Ok(()) => {
+                                output_spool = session_restore::new(
+                                    &args.session_restore_config,
+                                    &spool_tty_size,
+                                    &args.spool_checkpoint_path,
+                                )?;
+                                args.spool_bytes
+                                    .store(output_spool.memory_usage(), Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                warn!("swapping out spool, keeping it in memory: {:?}", e);
+                                // don't keep retrying every tick
+                                last_output_at = time::Instant::now();
+                            }
+                        }
+                    }
+
+                    // A session nobody has looked at or heard from in a long
+                    // time is probably forgotten, so hand it off to the reaper.
+                    if let (Some(idle_ttl), ClientConnectionMsg::Disconnect, false) =
+                        (args.idle_ttl, &client_conn, idle_reap_requested)
+                        && last_output_at.elapsed() >= idle_ttl
+                        && detached_at.elapsed() >= idle_ttl
+                    {
+                        info!("session idle for {:?}, asking the reaper to kill it", idle_ttl);
+                        idle_reap_requested = true;
+                        let name = args.session_name.lock().unwrap().clone();
+                        if let Err(e) = args.reap.send(ttl_reaper::Msg::Reap(name, time::Instant::now())) {
+                            warn!("sending idle session to the reaper: {:?}", e);
+                        }
+                    }
                     continue;
                 }
-                if nready != 1 {
-                    return Err(anyhow!("shell->client thread: expected exactly 1 ready fd"));
-                }
-                let len = match pty_master.read(&mut buf) {
-                    Ok(l) => l,
-                    Err(e) => {
-                        error!("reading chunk from pty master: {:?}", e);
-                        return Err(e).context("reading pty master chunk")?;
+                let (whole, len) = if !stale.is_empty() {
+                    (Cow::Owned(stale), 0)
+                } else {
+                    if nready != 1 {
+                        return Err(anyhow!("shell->client thread: expected exactly 1 ready fd"));
+                    }
+                    let len = match pty_master.read(&mut buf) {
+                        Ok(l) => l,
+                        Err(e) => {
+                            error!("reading chunk from pty master: {:?}", e);
+                            if let ClientConnectionMsg::Disconnect = client_conn {
+                                // Nobody saw how things ended, so pass what we
+                                // have on in case the session gets kept around.
+                                Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                                let exited = exit_reaper::Exited {
+                                    session_name: Arc::clone(&args.session_name),
+                                    child_pid: args.child_pid,
+                                    restore: output_spool.restore_buffer(),
+                                    scrollback: scrollback.text().into_bytes(),
+                                };
+                                if let Err(e) = args.exited.send(exited) {
+                                    warn!("sending exit to the exit reaper: {:?}", e);
+                                }
+                            }
+                            return Err(e).context("reading pty master chunk")?;
+                        }
+                    };
+                    if len == 0 {
+                        continue;
                     }
+                    args.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+                    let buf = &buf[..len];
+                    let lines = buf.iter().filter(|b| **b == b'\n').count();
+                    args.lines_out.fetch_add(lines as u64, Ordering::Relaxed);
+                    trace!("read pty master len={} '{}'", len, logging::payload(buf));
+
+                    // Everything downstream gets whole characters only, so a
+                    // character the read cut in half waits for the next one.
+                    (utf8_boundary.split(buf), len)
                 };
-                if len == 0 {
+                let mut buf: &[u8] = &whole;
+                if buf.is_empty() {
                     continue;
                 }
-                let mut buf = &buf[..len];
-                trace!("read pty master len={} '{}'", len, String::from_utf8_lossy(buf));
 
                 // scan for control codes we need to handle
                 if !has_seen_prompt_sentinel {
@@ -471,28 +1611,110 @@ impl SessionInner {
                     }
                 }
 
+                let filtered;
+                if has_seen_prompt_sentinel && let Some(escape_filter) = escape_filter.as_mut() {
+                    filtered = escape_filter.filter(buf);
+                    buf = &filtered;
+                }
+
+                last_output_at = time::Instant::now();
+                args.last_active.store(unix_ms(time::SystemTime::now()), Ordering::Relaxed);
                 if has_seen_prompt_sentinel {
-                    output_spool.process(buf);
+                    Self::swap_in_spool(&mut swap_file, &mut output_spool, &args.spool_bytes);
+                    let spooled = match spool_graphics.as_mut() {
+                        Some(spool_graphics) => spool_graphics.filter(buf),
+                        None => Cow::Borrowed(buf),
+                    };
+                    output_spool.process(&spooled);
+                    args.spool_bytes.store(output_spool.memory_usage(), Ordering::Relaxed);
+                    scrollback.process(&spooled);
+                    title.process(buf);
+                    if let Some(cwd) = title.take_cwd() {
+                        *args.reported_cwd.lock().unwrap() = Some(cwd);
+                    }
+                    args.output_taps.feed(buf);
+
+                    let rang = bell_scanner.scan(buf);
+                    let commands_done = command_scanner.scan(buf);
+                    if matches!(client_conn, ClientConnectionMsg::Disconnect) && !buf.is_empty() {
+                        args.activity.store(true, Ordering::Relaxed);
+                        let mut events = commands_done;
+                        if rang && !args.bell.swap(true, Ordering::Relaxed) {
+                            info!("bell rang while detached");
+                            events.push(notify::Event::Bell);
+                        }
+                        watch_scanner.update(args.watches.version(), config.generation(), || {
+                            args.watches.all(&args.session_name.lock().unwrap(), &config.get())
+                        });
+                        let hits = watch_scanner.scan(buf);
+                        if !events.is_empty() || !hits.is_empty() {
+                            let name = args.session_name.lock().unwrap().clone();
+                            for event in events {
+                                args.notifier.send(&name, event);
+                            }
+                            for hit in hits {
+                                args.watches.record(&hit);
+                                if hit.first {
+                                    info!("output matched watch '{}' while detached", hit.pattern);
+                                    args.hook_cmds.fire_watch(&name, args.child_pid, hit);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if let (Some(throttle), ClientConnectionMsg::Disconnect) =
+                    (&mut throttle, &client_conn)
+                    && let Some(until) = throttle.record(len, last_output_at)
+                {
+                    if !args.throttled.swap(true, Ordering::Relaxed) {
+                        info!("detached output went over the limit, throttling");
+                    }
+                    paused_until = Some(until);
                 }
 
                 let mut reset_client_conn = false;
-                if let (ClientConnectionMsg::New(conn), true) =
-                    (&mut client_conn, has_seen_prompt_sentinel)
+                // A locked screen stays blank, the output is all in the
+                // spool for when it gets unlocked.
+                let screen_locked = args.screen_locked.load(Ordering::Relaxed);
+                if let (ClientConnectionMsg::New(conn), true, false) =
+                    (&mut client_conn, has_seen_prompt_sentinel, screen_locked)
                 {
                     let chunk = Chunk { kind: ChunkKind::Data, buf };
 
                     // If we still need to do an initial motd dump, it means we have just finished
                     // dropping all the prompt setup stuff, we should dump the motd now before we
                     // write the first chunk.
-                    if needs_initial_motd_dump {
-                        needs_initial_motd_dump = false;
-                        if let Err(e) = daily_messenger.dump(&mut conn.sink, &term_db) {
-                            warn!("Error handling clear: {:?}", e);
-                        }
+                    if let Some(vars) = initial_motd.take()
+                        && let Err(e) = daily_messenger.dump(&mut conn.sink, &term_db, &vars)
+                    {
+                        warn!("Error handling clear: {:?}", e);
                     }
 
-                    let write_result =
-                        chunk.write_to(&mut conn.sink).and_then(|_| conn.sink.flush());
+                    // Send any pending restore buffer along with this first chunk of
+                    // live output, flipping the client straight into streaming mode.
+                    // While the user is looking at the scrollback, hold live output
+                    // back until they are done, unless there is so much of it that
+                    // we need to kick them out of scroll mode.
+                    let write_result = match (pending_restore.take(), scroll_mode.as_mut()) {
+                        (Some(restore_buf), _) => {
+                            Self::write_restore(&mut conn.sink, &restore_buf, buf, &replay_limits)
+                        }
+                        (None, Some(mode)) => {
+                            if mode.hold(buf) {
+                                Ok(())
+                            } else {
+                                info!("too much output held in scroll mode, exiting scroll mode");
+                                args.scrolling.store(false, Ordering::Relaxed);
+                                let out =
+                                    scroll_mode.take().map(|mode| mode.exit()).unwrap_or_default();
+                                Self::write_data(&mut conn.sink, &out, &[])
+                            }
+                        }
+                        (None, None) => {
+                            chunk.write_to(&mut conn.sink).and_then(|_| conn.sink.flush())
+                        }
+                    };
                     if let Err(err) = write_result {
                         info!("client_stream write err, assuming hangup: {:?}", err);
                         reset_client_conn = true;
@@ -502,13 +1724,156 @@ impl SessionInner {
                 }
                 if reset_client_conn {
                     client_conn = ClientConnectionMsg::Disconnect;
+                    detached_at = time::Instant::now();
+                }
+
+                if has_seen_prompt_sentinel && !mirrors.is_empty() {
+                    let nmirrors = mirrors.len();
+                    mirrors.retain_mut(|mirror| {
+                        let chunk = Chunk { kind: ChunkKind::Data, buf };
+                        match chunk.write_to(&mut mirror.conn.sink).and_then(|_| mirror.conn.sink.flush()) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                info!("mirror cid={} write err, assuming hangup: {:?}", mirror.conn_id, e);
+                                let _ = mirror.conn.shutdown();
+                                false
+                            }
+                        }
+                    });
+                    if mirrors.len() != nmirrors
+                        && let Some(size) = Self::refit_size(&primary_size, &mirrors, &spool_tty_size)
+                    {
+                        output_spool.resize(size.clone());
+                        spool_tty_size = size.clone();
+                        resize_cmd = Some(ResizeCmd { size, when: time::Instant::now() });
+                    }
                 }
+                cpu_meter.tick();
             }
         };
 
-        Ok(thread::Builder::new()
-            .name(format!("shell->client({})", self.name))
-            .spawn(move || log_if_error("error in shell->client", closure()))?)
+        Ok(thread::Builder::new().name(threads::name("s2c", &self.name)).spawn(move || {
+            let res = log_if_error("error in shell->client", closure());
+            // The shell is gone, so there is nothing left for a future
+            // daemon to recover, unless it is only gone because the
+            // daemon is being stopped.
+            if !stopping.load(Ordering::Relaxed) {
+                session_restore::disk::discard(&checkpoint_path);
+            }
+            res
+        })?)
+    }
+
+    /// The size to make the pty so that it fits on every attached client.
+    fn fit_size(primary: &TtySize, mirrors: &[MirrorConnection]) -> TtySize {
+        let size = mirrors.iter().fold(primary.clone(), |size, mirror| TtySize {
+            rows: std::cmp::min(size.rows, mirror.conn.size.rows),
+            cols: std::cmp::min(size.cols, mirror.conn.size.cols),
+            ..size
+        });
+        // Graphics programs work out the size of a cell from the pixel
+        // size, so that has to shrink along with the cells.
+        let scale = |pixels: u16, cells: u16, of: u16| {
+            (pixels as u32 * cells as u32).checked_div(of as u32).unwrap_or(0) as u16
+        };
+        TtySize {
+            xpixel: scale(primary.xpixel, size.cols, primary.cols),
+            ypixel: scale(primary.ypixel, size.rows, primary.rows),
+            ..size
+        }
+    }
+
+    /// The new pty size after the set of attached clients has changed,
+    /// or None if the size should stay the same.
+    fn refit_size(
+        primary: &TtySize,
+        mirrors: &[MirrorConnection],
+        current: &TtySize,
+    ) -> Option<TtySize> {
+        let size = Self::fit_size(primary, mirrors);
+        if size == *current {
+            None
+        } else {
+            Some(size)
+        }
+    }
+
+    /// If the spool has been swapped out to disk, replay the swapped out
+    /// data into it so it is back to its state from before the swap.
+    fn swap_in_spool(
+        swap_file: &mut session_restore::swap::SwapFile,
+        spool: &mut Box<dyn session_restore::SessionSpool>,
+        spool_bytes: &AtomicUsize,
+    ) {
+        if !swap_file.is_swapped() {
+            return;
+        }
+        match swap_file.load() {
+            Ok(data) => spool.process(&data),
+            Err(e) => warn!("swapping spool back in, restore buffer lost: {:?}", e),
+        }
+        spool_bytes.store(spool.memory_usage(), Ordering::Relaxed);
+    }
+
+    /// Write the restore buffer followed by a chunk of live output
+    /// to the client as a single vectored write, then flush. The restore
+    /// buffer is broken up into chunks so that we don't make the client
+    /// allocate too much.
+    fn write_data<W: io::Write>(sink: &mut W, restore_buf: &[u8], live: &[u8]) -> io::Result<()> {
+        let mut chunks: Vec<Chunk> = restore_buf
+            .chunks(consts::BUF_SIZE)
+            .map(|block| Chunk { kind: ChunkKind::Data, buf: block })
+            .collect();
+        if !live.is_empty() {
+            chunks.push(Chunk { kind: ChunkKind::Data, buf: live });
+        }
+        protocol::write_chunks_vectored(sink, &chunks)?;
+        sink.flush()
+    }
+
+    /// Write out a restore buffer, followed by any live output that
+    /// goes with it, pacing the restore buffer if the limits ask for it.
+    /// The sink's writer thread does the pacing, so we don't hold up the
+    /// shell's output in the meantime.
+    fn write_restore(
+        sink: &mut out_queue::Sink,
+        restore_buf: &[u8],
+        live: &[u8],
+        limits: &replay::Limits,
+    ) -> io::Result<()> {
+        let Some((per_batch, interval)) = limits.batch() else {
+            return Self::write_data(sink, restore_buf, live);
+        };
+        for batch in restore_buf.chunks(per_batch) {
+            let chunks: Vec<Chunk> = batch
+                .chunks(consts::BUF_SIZE)
+                .map(|block| Chunk { kind: ChunkKind::Data, buf: block })
+                .collect();
+            protocol::write_chunks_vectored(sink, &chunks)?;
+            sink.flush_paced(interval)?;
+        }
+        Self::write_data(sink, &[], live)
+    }
+
+    fn write_rename_chunk<W: io::Write>(mut sink: W, new_name: &str) {
+        let chunk = Chunk { kind: ChunkKind::Rename, buf: new_name.as_bytes() };
+        if let Err(e) = chunk.write_to(&mut sink).and_then(|_| sink.flush()) {
+            warn!("writing rename chunk: {:?}", e);
+        }
+    }
+
+    fn write_switch_chunk<W: io::Write>(mut sink: W, kind: ChunkKind, target: &str) {
+        let chunk = Chunk { kind, buf: target.as_bytes() };
+        if let Err(e) = chunk.write_to(&mut sink).and_then(|_| sink.flush()) {
+            warn!("writing switch chunk: {:?}", e);
+        }
+    }
+
+    fn write_evicted_chunk<W: io::Write>(mut sink: W, notice: &str) {
+        let chunk = Chunk { kind: ChunkKind::Evicted, buf: notice.as_bytes() };
+        if let Err(e) = chunk.write_to(&mut sink).and_then(|_| sink.flush()) {
+            warn!("writing evicted chunk: {:?}", e);
+        }
     }
 
     fn write_exit_chunk<W: io::Write>(mut sink: W, status: i32) {
@@ -535,6 +1900,9 @@ impl SessionInner {
         &mut self,
         conn_id: usize,
         init_tty_size: TtySize,
+        replay: Option<replay::Override>,
+        read_only: bool,
+        evicted_notice: bool,
         child_exit_notifier: Arc<ExitNotifier>,
     ) -> anyhow::Result<bool> {
         test_hooks::emit("daemon-bidi-stream-enter");
@@ -552,8 +1920,13 @@ impl SessionInner {
             client_stream.try_clone().context("creating client->shell client stream")?;
         let shell_to_client_client_stream =
             client_stream.try_clone().context("creating shell->client client stream handle")?;
-        let output_sink =
-            io::BufWriter::new(client_stream.try_clone().context("wrapping stream in bufwriter")?);
+        let output_sink = out_queue::Sink::new(
+            client_stream.try_clone().context("creating output queue stream handle")?,
+            &self.name,
+            out_queue::limit(&self.config.get()),
+            Arc::clone(&self.output_queue),
+        )
+        .context("spawning output queue writer")?;
 
         {
             let _s = span!(Level::INFO, "initial_attach_lock(shell_to_client_ctl)").entered();
@@ -565,6 +1938,9 @@ impl SessionInner {
                         sink: output_sink,
                         size: init_tty_size,
                         stream: shell_to_client_client_stream,
+                        replay,
+                        evicted_notice,
+                        liveness: keepalive::Liveness::new(time::Instant::now()),
                     }),
                     SHELL_TO_CLIENT_CTL_TIMEOUT,
                 )
@@ -583,11 +1959,13 @@ impl SessionInner {
         let stop = AtomicBool::new(false);
         // A flag to indicate if the child shell has exited
         let child_done = AtomicBool::new(false);
+        let mut hung_up = false;
 
         thread::scope(|s| -> anyhow::Result<()> {
             // Spawn the main data transport threads
             let client_to_shell_h = self.spawn_client_to_shell(
-                s, conn_id, &stop, &pty_master, &mut client_to_shell_client_stream)?;
+                s, conn_id, &stop, &pty_master, &mut client_to_shell_client_stream,
+                read_only, &child_exit_notifier)?;
 
             // Send a steady stream of heartbeats to the client
             // so that if the connection unexpectedly goes
@@ -637,6 +2015,7 @@ impl SessionInner {
 
                 if let Err(send_timeout_err) = send_res {
                     info!("failed to tell shell->client to disconnect: {:?}", send_timeout_err);
+                    hung_up = !c_done;
 
                     // the shell->client didn't close the client stream for us, so we'll need
                     // to handle that ourselves
@@ -645,6 +2024,10 @@ impl SessionInner {
                     let status = shell_to_client_ctl.client_connection_ack.recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
                         .context("waiting for client connection ack (2)")?;
                     info!("detached from shell->client, status = {:?}", status);
+                    // Anyone detaching the client on purpose does so through the
+                    // shell->client thread, so if it still had the client, the
+                    // connection must have just gone away.
+                    hung_up = !c_done && matches!(status, ClientConnectionStatus::Detached);
                 }
             }
 
@@ -670,6 +2053,7 @@ impl SessionInner {
 
             Ok(())
         }).context("outer thread scope")?;
+        self.hung_up = hung_up;
 
         let c_done = child_done.load(Ordering::Acquire);
         if c_done {
@@ -683,7 +2067,7 @@ impl SessionInner {
         
         // If child_done is false but we're exiting, check if the child has actually exited
         // This handles the race condition where client disconnects before child exit is detected
-        if !c_done {
+        if !c_done && self.switch.lock().unwrap().switched_to.is_none() {
             // macOS may need more time for process cleanup and signal propagation
             #[cfg(target_os = "macos")]
             let wait_time = Duration::from_millis(500);
@@ -720,6 +2104,7 @@ impl SessionInner {
     }
 
     #[instrument(skip_all)]
+    #[allow(clippy::too_many_arguments)]
     fn spawn_client_to_shell<'scope>(
         &'scope self,
         scope: &'scope thread::Scope<'scope, '_>,
@@ -727,40 +2112,66 @@ impl SessionInner {
         stop: &'scope AtomicBool,
         pty_master: &'scope shpool_pty::fork::Master,
         shell_to_client_client_stream: &'scope mut UnixStream,
+        locked_read_only: bool,
+        child_exit_notifier: &'scope ExitNotifier,
     ) -> anyhow::Result<thread::ScopedJoinHandle<'scope, anyhow::Result<()>>> {
-        let empty_bindings = vec![config::Keybinding {
-            binding: String::from("Ctrl-Space Ctrl-q"),
-            action: keybindings::Action::Detach,
-        }];
-        let bindings = keybindings::Bindings::new(
-            self.config
-                .get()
-                .keybinding
-                .as_ref()
-                .unwrap_or(&empty_bindings)
-                .iter()
-                .map(|binding| (binding.binding.as_str(), binding.action)),
-        );
+        let input_filter = InputFilter::new(&self.config);
 
         thread::Builder::new()
-            .name(format!("client->shell({})", self.name))
+            .name(threads::name("c2s", &self.name))
             .spawn_scoped(scope, move || -> anyhow::Result<()> {
                 let _s =
                     span!(Level::INFO, "client->shell", s = self.name, cid = conn_id).entered();
-                let mut bindings = bindings.context("compiling keybindings engine")?;
+                let mut cpu_meter = threads::ThreadCpuMeter::new(Arc::clone(&self.pump_cpu_ns));
+                let mut input_filter = input_filter.context("compiling keybindings engine")?;
+                let (scroll, scrolling, screen_lock, screen_locked) = {
+                    let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+                    (
+                        shell_to_client_ctl.scroll.clone(),
+                        Arc::clone(&shell_to_client_ctl.scrolling),
+                        shell_to_client_ctl.screen_lock.clone(),
+                        Arc::clone(&shell_to_client_ctl.screen_locked),
+                    )
+                };
+                let lock_after = self.screen_lock_after();
+                let mut last_input = time::Instant::now();
+                let mut unlocker = screen_lock::Unlocker::default();
 
                 let mut master_writer = *pty_master;
+                // Toggled by the toggle-read-only keybinding, unless the
+                // client attached with --read-only, in which case it is
+                // stuck on.
+                let mut read_only = locked_read_only;
 
-                let mut snip_sections = vec![]; // (<len>, <end offset>)
-                let mut keep_sections = vec![]; // (<start offset>, <end offset>)
                 let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
-                let mut partial_keybinding = vec![];
 
                 loop {
                     if stop.load(Ordering::Relaxed) {
                         info!("recvd stop msg (1)");
                         return Ok(());
                     }
+                    if self.switch.lock().unwrap().switched_to.is_some() {
+                        // Anything else the client sends is for the
+                        // session it switched to.
+                        info!("client switched away in place");
+                        return Ok(());
+                    }
+                    // A client attached with --read-only can't type into
+                    // the shell, so there is nothing to lock it out of.
+                    if !locked_read_only
+                        && let Some(after) = lock_after
+                        && last_input.elapsed() >= after
+                        && !screen_locked.swap(true, Ordering::Relaxed)
+                    {
+                        info!("no input for {:?}, locking the screen", after);
+                        unlocker = screen_lock::Unlocker::default();
+                        screen_lock.send(screen_lock::Cmd::Lock).context("sending screen lock")?;
+                    }
+                    // A switch from `shpool switch` leaves the stream
+                    // open, so we can't just block on it.
+                    if !input_pending(shell_to_client_client_stream, CLIENT_INPUT_POLL_MS) {
+                        continue;
+                    }
 
                     // N.B. we don't need to muck about with chunking or anything
                     // in this direction, because there is only one input stream
@@ -769,96 +2180,107 @@ impl SessionInner {
                     //
                     // Also, note that we don't access through the mutex because reads
                     // don't need to be excluded from trampling on writes.
-                    let mut len = shell_to_client_client_stream
-                        .read(&mut buf)
-                        .context("reading client chunk")?;
+                    let len = read_client_input(shell_to_client_client_stream, &mut buf)?;
                     if len == 0 {
                         continue;
                     }
-                    test_hooks::emit("daemon-read-c2s-chunk");
-                    trace!("read client len={}: '{}'", len, String::from_utf8_lossy(&buf[..len]),);
-
-                    // We might be able to gain some perf by doing this scanning in
-                    // a background thread (though maybe not given the need to copy
-                    // the data), but just doing it inline doesn't seem have have
-                    // a major perf impact, and this way is simpler.
-                    snip_sections.clear();
-                    for (i, byte) in buf[0..len].iter().enumerate() {
-                        use keybindings::BindingResult::*;
-                        match bindings.transition(*byte) {
-                            NoMatch
-                                if !partial_keybinding.is_empty()
-                                    && i < partial_keybinding.len() =>
-                            {
-                                // it turned out the partial keybinding match was not
-                                // a real match, so flush it to the output stream
-                                debug!(
-                                    "flushing partial keybinding_len={} i={}",
-                                    partial_keybinding.len(),
-                                    i
-                                );
-                                master_writer
-                                    .write_all(&partial_keybinding)
-                                    .context("writing partial keybinding")?;
-                                if i > 0 {
-                                    // snip the leading part of the input chunk that
-                                    // was part of this keybinding
-                                    snip_sections.push((i, i - 1));
-                                }
-                                partial_keybinding.clear()
-                            }
-                            NoMatch => {
-                                partial_keybinding.clear();
+                    last_input = time::Instant::now();
+
+                    // On a locked screen, keypresses go to unlocking it.
+                    if screen_locked.load(Ordering::Relaxed) {
+                        let lock = self.passphrase_lock();
+                        let verify = lock.as_ref().map(|lock| |typed: &str| lock.verify(typed));
+                        let cmd = match unlocker.feed(&buf[..len], verify) {
+                            Some(screen_lock::Outcome::Show(prompt)) => {
+                                Some(screen_lock::Cmd::Show(prompt))
                             }
-                            Partial => {
-                                partial_keybinding.push(*byte);
+                            Some(screen_lock::Outcome::Unlock) => {
+                                info!("unlocking the screen");
+                                screen_locked.store(false, Ordering::Relaxed);
+                                unlocker = screen_lock::Unlocker::default();
+                                Some(screen_lock::Cmd::Unlock)
                             }
-                            Match(action) => {
-                                info!("{:?} keybinding action fired", action);
-                                let keybinding_len = partial_keybinding.len() + 1;
-                                if keybinding_len < i {
-                                    // this keybinding is wholly contained in buf
-                                    debug!("snipping keybinding_len={} i={}", keybinding_len, i);
-                                    snip_sections.push((keybinding_len, i));
-                                } else {
-                                    // this keybinding was split across multiple
-                                    // input buffers, just snip the last bit
-                                    debug!("snipping split keybinding i={}", i);
-                                    snip_sections.push((i + 1, i));
-                                }
-                                partial_keybinding.clear();
+                            None => None,
+                        };
+                        if let Some(cmd) = cmd {
+                            screen_lock.send(cmd).context("sending screen lock cmd")?;
+                        }
+                        cpu_meter.tick();
+                        continue;
+                    }
 
-                                use keybindings::Action::*;
-                                match action {
-                                    Detach => self.action_detach()?,
-                                    NoOp => {}
-                                }
+                    // In scroll mode, keypresses drive the scrollback view rather
+                    // than going to the shell, though keybindings still get
+                    // first crack at them so that detaching keeps working.
+                    // In read-only mode, keypresses that don't turn out to
+                    // be part of a keybinding get dropped.
+                    let scroll_input_mode = scrolling.load(Ordering::Relaxed);
+                    let mut scroll_input = vec![];
+                    let mut dropped_input = io::sink();
+                    let mut writer: &mut dyn Write = if scroll_input_mode {
+                        &mut scroll_input
+                    } else if read_only {
+                        &mut dropped_input
+                    } else {
+                        &mut master_writer
+                    };
+                    let len = input_filter.filter(&mut buf, len, &mut writer, |action| {
+                        use keybindings::Action::*;
+                        if locked_read_only && action != Detach {
+                            info!("ignoring {:?} from read-only client", action);
+                            return Ok(());
+                        }
+                        match action {
+                            Detach => self.action_detach()?,
+                            Scroll => {
+                                scrolling.store(true, Ordering::Relaxed);
+                                scroll
+                                    .send(scrollback::ScrollCmd::Enter)
+                                    .context("sending scroll enter cmd")?;
+                            }
+                            List => {
+                                scrolling.store(true, Ordering::Relaxed);
+                                scroll
+                                    .send(scrollback::ScrollCmd::Show {
+                                        title: String::from("sessions"),
+                                        text: self.session_list(),
+                                    })
+                                    .context("sending session list")?;
                             }
+                            Kill => self.action_kill(child_exit_notifier)?,
+                            ToggleReadOnly => {
+                                read_only = !read_only;
+                                info!("read_only={}", read_only);
+                            }
+                            Switch(target) => self.action_switch(target)?,
+                            NoOp => {}
                         }
+                        Ok(())
+                    })?;
+                    if self.switch.lock().unwrap().switched_to.is_some() {
+                        info!("client switched away in place");
+                        return Ok(());
                     }
-                    if !partial_keybinding.is_empty() {
-                        // we have a partial keybinding pending, so don't write
-                        // it to the output stream immediately
-                        let snip_chunk_len = if partial_keybinding.len() > len {
-                            len
-                        } else {
-                            partial_keybinding.len()
-                        };
-                        debug!(
-                            "end of buf w/ partial keybinding_len={} snip_chunk_len={} buf_len={}",
-                            partial_keybinding.len(),
-                            snip_chunk_len,
-                            len
-                        );
-                        snip_sections.push((snip_chunk_len, len - 1));
+                    if scroll_input_mode {
+                        scroll_input.extend_from_slice(&buf[..len]);
+                        for cmd in scrollback::parse_keys(&scroll_input) {
+                            scroll.send(cmd).context("sending scroll cmd")?;
+                        }
+                        cpu_meter.tick();
+                        continue;
+                    }
+                    if read_only {
+                        cpu_meter.tick();
+                        continue;
                     }
-                    len = snip_buf(&mut buf[..], len, &snip_sections[..], &mut keep_sections);
 
                     master_writer.write_all(&buf[0..len]).context("writing client chunk")?;
 
                     master_writer.flush().context("flushing input from client to shell")?;
+                    self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
 
                     debug!("flushed chunk of len {}", len);
+                    cpu_meter.tick();
                 }
             })
             .map_err(|e| anyhow!("{:?}", e))
@@ -872,7 +2294,7 @@ impl SessionInner {
         stop: &'scope AtomicBool,
     ) -> anyhow::Result<thread::ScopedJoinHandle<'scope, anyhow::Result<()>>> {
         thread::Builder::new()
-            .name(format!("heartbeat({})", self.name))
+            .name(threads::name("hb", &self.name))
             .spawn_scoped(scope, move || -> anyhow::Result<()> {
                 let _s1 = span!(Level::INFO, "heartbeat", s = self.name, cid = conn_id).entered();
 
@@ -883,7 +2305,7 @@ impl SessionInner {
                         return Ok(());
                     }
 
-                    thread::sleep(consts::HEARTBEAT_DURATION);
+                    thread::sleep(keepalive::interval(&self.config.get()));
                     {
                         let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
                         match shell_to_client_ctl
@@ -939,7 +2361,7 @@ impl SessionInner {
         child_exit_notifier: Arc<ExitNotifier>,
     ) -> anyhow::Result<thread::ScopedJoinHandle<'scope, anyhow::Result<()>>> {
         thread::Builder::new()
-            .name(format!("supervisor({})", self.name))
+            .name(threads::name("sup", &self.name))
             .spawn_scoped(scope, move || -> anyhow::Result<()> {
                 let _s1 = span!(Level::INFO, "supervisor", s = self.name, cid = conn_id).entered();
 
@@ -978,21 +2400,375 @@ impl SessionInner {
 
     #[instrument(skip_all)]
     fn action_detach(&self) -> anyhow::Result<()> {
-        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        self.disconnect(ClientConnectionMsg::Disconnect)?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn action_switch(&self, target: String) -> anyhow::Result<()> {
+        let reply = switch_client(&self.shell_to_client_ctl, &self.switch, &self.name, &target)?;
+        info!("switch to '{}': {:?}", target, reply);
+        Ok(())
+    }
+
+    fn disconnect(&self, msg: ClientConnectionMsg) -> anyhow::Result<ClientConnectionStatus> {
+        send_client_connection_msg(&self.shell_to_client_ctl, msg)
+    }
+
+    #[instrument(skip_all)]
+    fn action_kill(&self, child_exit_notifier: &ExitNotifier) -> anyhow::Result<()> {
+        let child_pid = self.pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
+        info!("action kill, pid={}", child_pid);
+        // The supervisor thread notices the shell exiting and takes
+        // care of the rest, just like when the user types `exit`.
+        kill_shell(child_pid, child_exit_notifier)
+    }
+
+    /// How long the client may go without typing before its screen
+    /// gets locked, if ever.
+    fn screen_lock_after(&self) -> Option<Duration> {
+        let src = self.config.get().screen_lock_after_idle.clone()?;
+        match duration::parse(&src) {
+            Ok(after) => Some(after),
+            Err(e) => {
+                warn!("bad screen_lock_after_idle, never locking screens: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// The session's passphrase lock from `shpool lock`, if it has one.
+    fn passphrase_lock(&self) -> Option<lock::Lock> {
+        let shells = self.shells.upgrade()?;
+        let shard = shells.shard(&self.name);
+        shard.get(&self.name).and_then(|session| session.lock.clone())
+    }
+
+    /// Render a table of all the sessions for the `list` action.
+    fn session_list(&self) -> String {
+        let Some(shells) = self.shells.upgrade() else {
+            return String::from("no session table\n");
+        };
+        let mut sessions = vec![];
+        for shard in shells.shards() {
+            for (name, session) in shard.iter() {
+                // our own inner is locked since we are attached to it
+                let status =
+                    if session.inner.try_lock().is_ok() { "disconnected" } else { "attached" };
+                let cwd = session.cwd().unwrap_or_default();
+                sessions.push((name.clone(), status, cwd, session.tags.join(",")));
+            }
+        }
+        sessions.sort();
+
+        let width = sessions.iter().map(|(name, ..)| name.len()).max().unwrap_or(0);
+        let cwd_width = sessions.iter().map(|(_, _, cwd, _)| cwd.len()).max().unwrap_or(0);
+        let mut text = String::new();
+        for (name, status, cwd, tags) in sessions {
+            let marker = if name == self.name { '*' } else { ' ' };
+            text.push_str(&format!(
+                "{marker} {name:width$}  {status:12}  {cwd:cwd_width$}  {tags}\n"
+            ));
+        }
+        text
+    }
+}
+
+/// Attach a client to a session that already has a client attached,
+/// streaming the session's output to both and forwarding input from the
+/// mirror to the shell until the mirror detaches or hangs up.
+#[instrument(skip_all, fields(s = args.name, cid = args.conn_id))]
+pub fn attach_mirror(args: MirrorArgs) -> anyhow::Result<()> {
+    let MirrorArgs {
+        name,
+        conn_id,
+        client_pid,
+        mut stream,
+        size,
+        shell_to_client_ctl,
+        mut pty_master,
+        pump_cpu_ns,
+        bytes_in,
+        output_queue,
+        config,
+        replay,
+        read_only,
+    } = args;
+
+    {
+        let shell_to_client_ctl = shell_to_client_ctl.lock().unwrap();
         shell_to_client_ctl
             .client_connection
-            .send_timeout(ClientConnectionMsg::Disconnect, SHELL_TO_CLIENT_CTL_TIMEOUT)
-            .context("signaling client detach to shell->client thread")?;
+            .send_timeout(
+                ClientConnectionMsg::AddMirror(MirrorConnection {
+                    conn_id,
+                    client_pid,
+                    conn: ClientConnection {
+                        sink: out_queue::Sink::new(
+                            stream.try_clone().context("creating output queue stream handle")?,
+                            &name,
+                            out_queue::limit(&config.get()),
+                            output_queue,
+                        )
+                        .context("spawning output queue writer")?,
+                        size,
+                        stream: stream.try_clone().context("creating mirror stream handle")?,
+                        replay,
+                        evicted_notice: false,
+                        liveness: keepalive::Liveness::new(time::Instant::now()),
+                    },
+                }),
+                SHELL_TO_CLIENT_CTL_TIMEOUT,
+            )
+            .context("attaching mirror to shell->client thread")?;
         let status = shell_to_client_ctl
             .client_connection_ack
             .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
-            .context("waiting for client connection ack (3)")?;
+            .context("waiting for add mirror ack")?;
+        info!("mirror connection status={:?}", status);
+    }
 
-        info!("action detach, status={:?}", status);
-        Ok(())
+    let pump_input = || -> anyhow::Result<()> {
+        let mut cpu_meter = threads::ThreadCpuMeter::new(pump_cpu_ns);
+        let mut input_filter = InputFilter::new(&config).context("compiling keybindings engine")?;
+        let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
+        loop {
+            let len = read_client_input(&mut stream, &mut buf)?;
+            if len == 0 {
+                info!("mirror hung up");
+                return Ok(());
+            }
+
+            // Mirrors can detach themselves, but other actions only make
+            // sense for the main client.
+            let mut detach = false;
+            let mut dropped_input = io::sink();
+            let mut writer: &mut dyn Write =
+                if read_only { &mut dropped_input } else { &mut pty_master };
+            let len = input_filter.filter(&mut buf, len, &mut writer, |action| {
+                detach |= action == keybindings::Action::Detach;
+                Ok(())
+            })?;
+            if !read_only {
+                pty_master.write_all(&buf[..len]).context("writing mirror chunk")?;
+                pty_master.flush().context("flushing input from mirror to shell")?;
+                bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            cpu_meter.tick();
+
+            if detach {
+                info!("mirror detached");
+                return Ok(());
+            }
+        }
+    };
+    let res = pump_input();
+
+    // The shell->client thread may already have dropped the mirror, or be
+    // gone entirely if the shell exited, so there is nothing to do if this
+    // fails.
+    let shell_to_client_ctl = shell_to_client_ctl.lock().unwrap();
+    if shell_to_client_ctl
+        .client_connection
+        .send_timeout(ClientConnectionMsg::RemoveMirror(conn_id), SHELL_TO_CLIENT_CTL_TIMEOUT)
+        .is_ok()
+    {
+        let status = shell_to_client_ctl.client_connection_ack.recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT);
+        info!("mirror disconnect status={:?}", status);
+    }
+
+    res
+}
+
+/// Read a burst of input from a client, returning how many bytes were read.
+fn read_client_input(stream: &mut UnixStream, buf: &mut [u8]) -> anyhow::Result<usize> {
+    let mut len = stream.read(buf).context("reading client chunk")?;
+    if len == 0 {
+        return Ok(0);
+    }
+    test_hooks::emit("daemon-read-c2s-chunk");
+
+    // Coalesce any input that arrives right behind this chunk
+    // so that we issue one pty write per burst rather than one
+    // per socket read.
+    while len < buf.len() && input_pending(stream, CLIENT_INPUT_BATCH_WINDOW_MS) {
+        let nread = stream.read(&mut buf[len..]).context("reading batched client chunk")?;
+        if nread == 0 {
+            break;
+        }
+        len += nread;
+    }
+    trace!("read client len={}: '{}'", len, logging::payload(&buf[..len]));
+
+    Ok(len)
+}
+
+/// Scans client input for keybindings, snipping them out of the
+/// input before it gets forwarded to the shell.
+struct InputFilter {
+    bindings: keybindings::Bindings,
+    partial_keybinding: Vec<u8>,
+    /// How long a partial keybinding may sit waiting for its next key.
+    timeout: Option<time::Duration>,
+    /// When the last chunk of input came through.
+    last_input: time::Instant,
+    snip_sections: Vec<(usize, usize)>, // (<len>, <end offset>)
+    keep_sections: Vec<(usize, usize)>, // (<start offset>, <end offset>)
+}
+
+impl InputFilter {
+    fn new(config: &config::Manager) -> anyhow::Result<Self> {
+        let config = config.get();
+        let settings = config.keybindings.clone().unwrap_or_default();
+
+        let mut binding_srcs = config
+            .keybinding
+            .iter()
+            .flatten()
+            .map(|binding| (binding.binding.clone(), binding.action.clone()))
+            .collect::<Vec<_>>();
+        binding_srcs.extend(
+            settings
+                .leader_bindings
+                .iter()
+                .flatten()
+                .map(|(key, action)| (format!("Leader {key}"), action.clone())),
+        );
+        if binding_srcs.is_empty() {
+            binding_srcs
+                .push((String::from(keybindings::DEFAULT_DETACH), keybindings::Action::Detach));
+        }
+
+        let bindings = keybindings::Bindings::new(
+            binding_srcs.iter().map(|(src, action)| (src.as_str(), action.clone())),
+            settings.leader.as_deref(),
+        )?;
+        Ok(InputFilter {
+            bindings,
+            partial_keybinding: vec![],
+            timeout: settings.timeout_ms.map(time::Duration::from_millis),
+            last_input: time::Instant::now(),
+            snip_sections: vec![],
+            keep_sections: vec![],
+        })
+    }
+
+    /// Filter buf[..len] in place, calling on_action for every keybinding
+    /// that fires, and return the length of the input that should be
+    /// forwarded to the shell. Bytes held back as a possible keybinding
+    /// that turn out not to be one get written straight to master_writer.
+    fn filter<W, F>(
+        &mut self,
+        buf: &mut [u8],
+        len: usize,
+        master_writer: &mut W,
+        mut on_action: F,
+    ) -> anyhow::Result<usize>
+    where
+        W: Write,
+        F: FnMut(keybindings::Action) -> anyhow::Result<()>,
+    {
+        // We might be able to gain some perf by doing this scanning in
+        // a background thread (though maybe not given the need to copy
+        // the data), but just doing it inline doesn't seem have have
+        // a major perf impact, and this way is simpler.
+        self.snip_sections.clear();
+
+        // If the user took too long to finish a keybinding, it was
+        // not a keybinding after all.
+        if let Some(timeout) = self.timeout
+            && !self.partial_keybinding.is_empty()
+            && self.last_input.elapsed() > timeout
+        {
+            debug!("partial keybinding timed out len={}", self.partial_keybinding.len());
+            master_writer
+                .write_all(&self.partial_keybinding)
+                .context("writing timed out partial keybinding")?;
+            self.partial_keybinding.clear();
+            self.bindings.reset();
+        }
+        self.last_input = time::Instant::now();
+
+        for (i, byte) in buf[0..len].iter().enumerate() {
+            use keybindings::BindingResult::*;
+            match self.bindings.transition(*byte) {
+                NoMatch
+                    if !self.partial_keybinding.is_empty()
+                        && i < self.partial_keybinding.len() =>
+                {
+                    // it turned out the partial keybinding match was not
+                    // a real match, so flush it to the output stream
+                    debug!(
+                        "flushing partial keybinding_len={} i={}",
+                        self.partial_keybinding.len(),
+                        i
+                    );
+                    master_writer
+                        .write_all(&self.partial_keybinding)
+                        .context("writing partial keybinding")?;
+                    if i > 0 {
+                        // snip the leading part of the input chunk that
+                        // was part of this keybinding
+                        self.snip_sections.push((i, i - 1));
+                    }
+                    self.partial_keybinding.clear()
+                }
+                NoMatch => {
+                    self.partial_keybinding.clear();
+                }
+                Partial => {
+                    self.partial_keybinding.push(*byte);
+                }
+                Match(action) => {
+                    info!("{:?} keybinding action fired", action);
+                    let keybinding_len = self.partial_keybinding.len() + 1;
+                    if keybinding_len < i {
+                        // this keybinding is wholly contained in buf
+                        debug!("snipping keybinding_len={} i={}", keybinding_len, i);
+                        self.snip_sections.push((keybinding_len, i));
+                    } else {
+                        // this keybinding was split across multiple
+                        // input buffers, just snip the last bit
+                        debug!("snipping split keybinding i={}", i);
+                        self.snip_sections.push((i + 1, i));
+                    }
+                    self.partial_keybinding.clear();
+
+                    on_action(action)?;
+                }
+            }
+        }
+        if !self.partial_keybinding.is_empty() {
+            // we have a partial keybinding pending, so don't write
+            // it to the output stream immediately
+            let snip_chunk_len = if self.partial_keybinding.len() > len {
+                len
+            } else {
+                self.partial_keybinding.len()
+            };
+            debug!(
+                "end of buf w/ partial keybinding_len={} snip_chunk_len={} buf_len={}",
+                self.partial_keybinding.len(),
+                snip_chunk_len,
+                len
+            );
+            self.snip_sections.push((snip_chunk_len, len - 1));
+        }
+        Ok(snip_buf(buf, len, &self.snip_sections[..], &mut self.keep_sections))
     }
 }
 
+/// Returns true if the client stream has data ready to read within the
+/// given timeout. Errors are treated as no data being ready so that the
+/// following blocking read gets a chance to report them properly.
+pub fn input_pending(stream: &UnixStream, timeout_ms: u8) -> bool {
+    use nix::poll;
+    use std::os::fd::AsFd as _;
+
+    let mut poll_fds = [poll::PollFd::new(stream.as_fd(), poll::PollFlags::POLLIN)];
+    matches!(poll::poll(&mut poll_fds, timeout_ms), Ok(n) if n > 0)
+}
+
 /// A handle for poking at the always-running shell->client thread.
 /// Shared between the session struct (for calls originating with the cli)
 /// and the session inner struct (for calls resulting from keybindings).
@@ -1011,7 +2787,7 @@ pub struct ReaderCtl {
     /// A control channel for the shell->client thread. Used to signal size
     /// changes so that the output spool will correctly reflect the size of
     /// the user's tty.
-    pub tty_size_change: crossbeam_channel::Sender<TtySize>,
+    pub tty_size_change: crossbeam_channel::Sender<SizeChange>,
     /// A control channel for the shell->client thread. Acks the completion of a
     /// spool resize.
     pub tty_size_change_ack: crossbeam_channel::Receiver<()>,
@@ -1022,6 +2798,33 @@ pub struct ReaderCtl {
     // True if the client is still listening, false if it has hung up
     // on us.
     pub heartbeat_ack: crossbeam_channel::Receiver<bool>,
+
+    /// A control channel for driving scroll mode in the shell->client thread.
+    pub scroll: crossbeam_channel::Sender<scrollback::ScrollCmd>,
+    /// True while the client is in scroll mode, so the client->shell
+    /// thread knows to route input to the scrollback view rather than
+    /// the shell. Cleared by the shell->client thread when scroll mode
+    /// ends.
+    pub scrolling: Arc<AtomicBool>,
+
+    /// A control channel for locking and unlocking the screen of the
+    /// attached client.
+    pub screen_lock: crossbeam_channel::Sender<screen_lock::Cmd>,
+    /// True while the screen is locked, so the client->shell thread
+    /// knows to route input to unlocking. Outlives connections, so a
+    /// client that attaches while it is set gets the locked screen.
+    pub screen_locked: Arc<AtomicBool>,
+
+    /// A control channel telling the shell->client thread to let its
+    /// clients know that the session has a new name.
+    pub rename: crossbeam_channel::Sender<String>,
+
+    /// A control channel asking the shell->client thread for a copy of
+    /// the session's output.
+    pub capture: crossbeam_channel::Sender<CaptureKind>,
+    /// A control channel for the shell->client thread. Returns the
+    /// captured output.
+    pub capture_ack: crossbeam_channel::Receiver<Vec<u8>>,
 }
 
 /// Given a buffer, a length after which the data is not valid, a list of
@@ -1083,6 +2886,24 @@ fn snip_buf(
 mod test {
     use super::*;
 
+    #[test]
+    fn slow_output_taps() {
+        let taps = OutputTaps::default();
+        let strict = taps.add();
+        let lossy = taps.add_lossy();
+        for _ in 0..OUTPUT_TAP_DEPTH + 2 {
+            taps.feed(b"abc");
+        }
+        // the strict tap got dropped, but the lossy one hangs on
+        assert_eq!(taps.0.lock().unwrap().len(), 1);
+        assert_eq!(strict.try_iter().count(), OUTPUT_TAP_DEPTH);
+        assert_eq!(lossy.try_iter().count(), OUTPUT_TAP_DEPTH);
+
+        taps.feed(b"def");
+        assert_eq!(lossy.try_recv().unwrap(), b"\r\n[shpool: skipped 6 bytes of output]\r\n");
+        assert_eq!(lossy.try_recv().unwrap(), b"def");
+    }
+
     #[test]
     fn test_snip_buf() {
         let cases = vec![
@@ -1100,4 +2921,130 @@ mod test {
             assert_eq!(&buf[..got_len], &want_buf[..]);
         }
     }
+
+    #[test]
+    fn test_input_filter_timeout() -> anyhow::Result<()> {
+        let mut filter = InputFilter {
+            bindings: keybindings::Bindings::new(
+                vec![("Leader d", keybindings::Action::Detach)],
+                Some("Ctrl-a"),
+            )?,
+            partial_keybinding: vec![],
+            timeout: Some(Duration::from_millis(10)),
+            last_input: time::Instant::now(),
+            snip_sections: vec![],
+            keep_sections: vec![],
+        };
+        let mut actions = vec![];
+        let mut shell_input = vec![];
+        let mut feed = |filter: &mut InputFilter, byte: u8| -> anyhow::Result<Vec<u8>> {
+            let mut buf = vec![byte];
+            let len = filter.filter(&mut buf, 1, &mut shell_input, |action| {
+                actions.push(action);
+                Ok(())
+            })?;
+            buf.truncate(len);
+            Ok(buf)
+        };
+
+        // a leader followed quickly by 'd' fires
+        assert!(feed(&mut filter, 1)?.is_empty());
+        assert!(feed(&mut filter, b'd')?.is_empty());
+
+        // but a slow one gets passed through to the shell
+        assert!(feed(&mut filter, 1)?.is_empty());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(feed(&mut filter, b'd')?, vec![b'd']);
+
+        assert_eq!(actions, vec![keybindings::Action::Detach]);
+        assert_eq!(shell_input, vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_client_input_batches() -> anyhow::Result<()> {
+        use std::io::Write as _;
+
+        let (mut client, mut daemon) = UnixStream::pair()?;
+        // a paste that shows up in several writes, each well within the
+        // batch window of the last
+        let writer = thread::spawn(move || -> anyhow::Result<UnixStream> {
+            for piece in [&b"echo "[..], b"\x1b[200~pasted", b"\x1b[201~\r"] {
+                client.write_all(piece)?;
+                thread::sleep(Duration::from_micros(200));
+            }
+            Ok(client)
+        });
+
+        let mut buf = vec![0; consts::BUF_SIZE];
+        let len = read_client_input(&mut daemon, &mut buf)?;
+        let _client = writer.join().unwrap()?;
+        assert_eq!(&buf[..len], b"echo \x1b[200~pasted\x1b[201~\r");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_client_input_single_key() -> anyhow::Result<()> {
+        use std::io::Write as _;
+
+        let (mut client, mut daemon) = UnixStream::pair()?;
+        client.write_all(b"a")?;
+
+        // the client stays connected but sends nothing more, so the key
+        // goes out once the batch window passes
+        let mut buf = vec![0; consts::BUF_SIZE];
+        let start = time::Instant::now();
+        let len = read_client_input(&mut daemon, &mut buf)?;
+        assert_eq!(&buf[..len], b"a");
+        assert!(start.elapsed() < Duration::from_millis(100), "held for {:?}", start.elapsed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fit_size() -> anyhow::Result<()> {
+        let mirror = |rows, cols| -> anyhow::Result<MirrorConnection> {
+            let (stream, _) = UnixStream::pair()?;
+            Ok(MirrorConnection {
+                conn_id: 0,
+                client_pid: None,
+                conn: ClientConnection {
+                    sink: out_queue::Sink::new(
+                        stream.try_clone()?,
+                        "test",
+                        out_queue::DEFAULT_LIMIT,
+                        Default::default(),
+                    )?,
+                    size: TtySize { rows, cols, xpixel: 0, ypixel: 0 },
+                    stream,
+                    replay: None,
+                    evicted_notice: false,
+                    liveness: keepalive::Liveness::new(time::Instant::now()),
+                },
+            })
+        };
+        let primary = TtySize { rows: 40, cols: 100, xpixel: 0, ypixel: 0 };
+
+        let size = SessionInner::fit_size(&primary, &[]);
+        assert_eq!((size.rows, size.cols), (40, 100));
+
+        let mirrors = vec![mirror(50, 80)?, mirror(30, 120)?];
+        let size = SessionInner::fit_size(&primary, &mirrors);
+        assert_eq!((size.rows, size.cols), (30, 80));
+
+        assert!(SessionInner::refit_size(&primary, &mirrors, &size).is_none());
+        assert!(SessionInner::refit_size(&primary, &mirrors[..1], &size).is_some());
+
+        // cells keep the primary's pixel size
+        let primary = TtySize { rows: 40, cols: 100, xpixel: 1000, ypixel: 800 };
+        let size = SessionInner::fit_size(&primary, &mirrors);
+        assert_eq!(size, TtySize { rows: 30, cols: 80, xpixel: 800, ypixel: 600 });
+        // and a change in pixel size alone is worth a resize
+        let zoomed = TtySize { xpixel: 2000, ypixel: 1600, ..primary.clone() };
+        assert!(SessionInner::refit_size(&zoomed, &mirrors, &size).is_some());
+
+        Ok(())
+    }
 }
@@ -31,6 +31,17 @@ use crate::{
     protocol::ChunkExt as _,
 };
 
+/// What a motd template can refer to besides the motd itself.
+#[derive(Debug, Clone)]
+pub struct Vars {
+    pub session_name: String,
+    pub created_at: time::SystemTime,
+    /// How many sessions the daemon has, counting this one.
+    pub sessions: usize,
+    /// How many other sessions have no client attached.
+    pub detached_sessions: usize,
+}
+
 /// Showers know how to show the message of the day.
 #[derive(Debug, Clone)]
 pub struct DailyMessenger {
@@ -64,13 +75,14 @@ impl DailyMessenger {
         &self,
         mut stream: W,
         term_db: &termini::TermInfo,
+        vars: &Vars,
     ) -> anyhow::Result<()> {
         assert!(matches!(
             self.config.get().motd.clone().unwrap_or_default(),
             config::MotdDisplayMode::Dump
         ));
 
-        let raw_motd_value = self.raw_motd_value(term_db)?;
+        let raw_motd_value = Self::convert_to_raw(term_db, &self.render(vars)?)?;
 
         let chunk = Chunk { kind: ChunkKind::Data, buf: raw_motd_value.as_slice() };
 
@@ -99,6 +111,7 @@ impl DailyMessenger {
         // the same env for the pager program (mostly because we want
         // to pass TERM along correctly).
         shell_env: &[(OsString, OsString)],
+        vars: &Vars,
     ) -> anyhow::Result<Option<TtySize>> {
         if let Some(debouncer) = &self.debouncer
             && !debouncer.should_fire()? {
@@ -114,7 +127,7 @@ impl DailyMessenger {
 
         info!("displaying motd in pager '{}'", pager_bin);
 
-        let motd_value = self.motd_value()?;
+        let motd_value = self.render(vars)?;
 
         let pager = Pager::new(pager_bin.to_string());
 
@@ -128,6 +141,26 @@ impl DailyMessenger {
         Ok(Some(final_size))
     }
 
+    /// The one line banner to show on attach in banner mode, if the
+    /// message has anything in it.
+    pub fn banner(&self, vars: &Vars) -> anyhow::Result<Option<String>> {
+        let motd_value = self.render(vars)?;
+        Ok(motd_value.lines().map(str::trim).find(|l| !l.is_empty()).map(String::from))
+    }
+
+    /// The message to show, which is the motd_template config option
+    /// filled in, or just the system motd if there is no template.
+    fn render(&self, vars: &Vars) -> anyhow::Result<String> {
+        let template = self.config.get().motd_template.clone();
+        let Some(template) = template else {
+            return self.motd_value();
+        };
+        // Only go looking for the system motd if the template wants it.
+        let motd_value =
+            if template.contains("{motd}") { self.motd_value()? } else { String::new() };
+        Ok(render(&template, &motd_value, vars))
+    }
+
     fn motd_value(&self) -> anyhow::Result<String> {
         self.motd_resolver
             .value(match &self.config.get().motd_args {
@@ -145,11 +178,6 @@ impl DailyMessenger {
             .context("resolving motd")
     }
 
-    fn raw_motd_value(&self, term_db: &termini::TermInfo) -> anyhow::Result<Vec<u8>> {
-        let motd_value = self.motd_value()?;
-        Self::convert_to_raw(term_db, &motd_value)
-    }
-
     /// Convert the given motd into a byte buffer suitable to be written to the
     /// terminal. The only real transformation we perform is injecting carrage
     /// returns after newlines.
@@ -171,6 +199,21 @@ impl DailyMessenger {
     }
 }
 
+/// Fill in the placeholders in a motd template.
+fn render(template: &str, motd_value: &str, vars: &Vars) -> String {
+    let created_at = chrono::DateTime::<chrono::Local>::from(vars.created_at)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    template
+        .replace("{session_name}", &vars.session_name)
+        .replace("{created_at}", &created_at)
+        .replace("{sessions}", &vars.sessions.to_string())
+        .replace("{detached_sessions}", &vars.detached_sessions.to_string())
+        .replace("{daemon_version}", shpool_protocol::VERSION)
+        // last, so that placeholders in the motd itself stay put
+        .replace("{motd}", motd_value)
+}
+
 #[derive(Debug, Clone)]
 struct Debouncer {
     last_fired: Arc<Mutex<time::SystemTime>>,
@@ -198,3 +241,27 @@ impl Debouncer {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let vars = Vars {
+            session_name: String::from("main"),
+            created_at: time::SystemTime::now(),
+            sessions: 3,
+            detached_sessions: 2,
+        };
+        assert_eq!(
+            render("{session_name}: {detached_sessions} of {sessions} waiting", "", &vars),
+            "main: 2 of 3 waiting"
+        );
+        assert_eq!(
+            render("{motd}\n-- {session_name}", "hi {sessions}", &vars),
+            "hi {sessions}\n-- main"
+        );
+        assert!(!render("since {created_at}", "", &vars).contains('{'));
+    }
+}
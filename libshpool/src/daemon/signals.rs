@@ -19,15 +19,30 @@ use std::{
 };
 
 use anyhow::Context;
-use signal_hook::{consts::TERM_SIGNALS, flag, iterator::Signals};
-use tracing::{error, info};
+use signal_hook::{
+    consts::{SIGHUP, SIGUSR1, TERM_SIGNALS},
+    flag,
+    iterator::Signals,
+};
+use tracing::{error, info, warn};
+
+use super::server;
+use crate::config;
 
 pub struct Handler {
     sock: Option<PathBuf>,
+    pid_file: Option<PathBuf>,
+    config: config::Manager,
+    server: Arc<server::Server>,
 }
 impl Handler {
-    pub fn new(sock: Option<PathBuf>) -> Self {
-        Handler { sock }
+    pub fn new(
+        sock: Option<PathBuf>,
+        pid_file: Option<PathBuf>,
+        config: config::Manager,
+        server: Arc<server::Server>,
+    ) -> Self {
+        Handler { sock, pid_file, config, server }
     }
 
     pub fn spawn(self) -> anyhow::Result<()> {
@@ -47,26 +62,45 @@ impl Handler {
             flag::register(*sig, Arc::clone(&term_now))?;
         }
 
-        let mut signals = Signals::new(TERM_SIGNALS).context("creating signal iterator")?;
-        thread::spawn(move || {
-            // Signals are exposed via an iterator so this loop is just to consume
-            // that by blocking until the first value is emitted. Clippy thinks we
-            // are looping over a collection and is confused about why we always
-            // exit in the loop body.
-            #[allow(clippy::never_loop)]
+        let mut signals = Signals::new(TERM_SIGNALS.iter().chain(&[SIGHUP, SIGUSR1]))
+            .context("creating signal iterator")?;
+        thread::Builder::new().name(String::from("signals")).spawn(move || {
             for signal in &mut signals {
+                if signal == SIGHUP {
+                    info!("hup sig handler: reloading config");
+                    if let Err(e) = self.config.reload() {
+                        warn!("reloading config: {:?}", e);
+                    }
+                    continue;
+                }
+                if signal == SIGUSR1 {
+                    info!("usr1 sig handler: dumping state");
+                    if let Err(e) = self.server.dump_state_to_file() {
+                        warn!("dumping state: {:?}", e);
+                    }
+                    continue;
+                }
                 assert!(TERM_SIGNALS.contains(&signal));
 
+                if let Err(e) = super::systemd::notify("STOPPING=1") {
+                    warn!("notifying systemd that we are stopping: {:?}", e);
+                }
+
                 info!("term sig handler: cleaning up socket");
                 if let Some(sock) = self.sock
                     && let Err(e) = std::fs::remove_file(sock).context("cleaning up socket") {
                         error!("error cleaning up socket file: {}", e);
                     }
+                if let Some(pid_file) = self.pid_file
+                    && let Err(e) = std::fs::remove_file(pid_file)
+                {
+                    error!("error cleaning up pid file: {}", e);
+                }
 
                 info!("term sig handler: exiting");
                 std::process::exit(0);
             }
-        });
+        }).context("spawning signal handler thread")?;
 
         Ok(())
     }
@@ -0,0 +1,117 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Sharing the daemon socket with other users.
+
+  By default the socket is only usable by the user running the daemon,
+  and the daemon turns away connections from anyone else. Setting
+  `socket_mode` and `socket_group` in the config loosens both of these
+  so that members of a trusted group can attach to the daemon's
+  sessions, for example to pair on a debugging session.
+*/
+
+use std::{fs, os::unix::fs::PermissionsExt as _, path::Path};
+
+use anyhow::{anyhow, Context};
+use nix::unistd;
+use tracing::info;
+
+use crate::config;
+
+/// Apply the configured mode and owning group to the freshly bound
+/// socket.
+pub fn apply(socket: &Path, config: &config::Config) -> anyhow::Result<()> {
+    if let Some(group) = &config.socket_group {
+        let gid = resolve_group(group)?;
+        unistd::chown(socket, None, Some(gid)).context("changing socket group")?;
+        info!("socket group set to '{}' ({})", group, gid);
+    }
+    if let Some(mode) = config.socket_mode {
+        check_mode(mode)?;
+        fs::set_permissions(socket, fs::Permissions::from_mode(mode))
+            .context("changing socket mode")?;
+        info!("socket mode set to {:o}", mode);
+    }
+    Ok(())
+}
+
+/// Look up the gid for the given group name.
+pub fn resolve_group(name: &str) -> anyhow::Result<unistd::Gid> {
+    match unistd::Group::from_name(name).context("looking up socket group")? {
+        Some(group) => Ok(group.gid),
+        None => Err(anyhow!("no such group '{}'", name)),
+    }
+}
+
+/// Make sure the mode only contains permission bits.
+pub fn check_mode(mode: u32) -> anyhow::Result<()> {
+    if mode & !0o777 != 0 {
+        return Err(anyhow!(
+            "{:o} is not a valid socket mode, it should be something like 0o660",
+            mode
+        ));
+    }
+    Ok(())
+}
+
+/// True if the process with the given pid and primary gid is a member
+/// of the given group, either directly or through its supplementary
+/// groups.
+#[cfg(target_os = "linux")]
+pub fn in_group(pid: i32, peer_gid: u32, gid: unistd::Gid) -> bool {
+    if peer_gid == gid.as_raw() {
+        return true;
+    }
+    match fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => supplementary_groups(&status).contains(&gid.as_raw()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn supplementary_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| groups.split_whitespace().filter_map(|g| g.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        assert!(check_mode(0o660).is_ok());
+        assert!(check_mode(0o600).is_ok());
+        assert!(check_mode(0o4770).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn parses_groups() {
+        let status = "Name:\tbash\nGid:\t1000\t1000\t1000\t1000\nGroups:\t4 27 1000 1001 \n";
+        assert_eq!(supplementary_groups(status), vec![4, 27, 1000, 1001]);
+        assert_eq!(supplementary_groups("Name:\tbash\n"), Vec::<u32>::new());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn own_groups() {
+        let pid = std::process::id() as i32;
+        let gid = unistd::Gid::current();
+        assert!(in_group(pid, gid.as_raw(), gid));
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Snapshots of the daemon's internal state, for debugging.
+
+  `shpool daemon dump-state` asks the daemon for one over the socket,
+  and sending the daemon a SIGUSR1 gets it to write one to the log and
+  to daemon-state.json in the runtime dir, which still works when
+  whatever is wrong has the socket wedged too. The snapshot is meant
+  for a person working out why an attach is stuck, so it never waits
+  on a lock: anything behind a lock that is held shows up as unknown
+  instead, which is often the interesting part.
+
+  The layout is not a stable interface and may change between versions.
+*/
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, TryLockError},
+};
+
+use anyhow::Context;
+use serde_derive::Serialize;
+
+use super::shell;
+
+/// The name of the file SIGUSR1 writes the snapshot to, within the
+/// runtime dir.
+pub const FILE_NAME: &str = "daemon-state.json";
+
+#[derive(Serialize, Debug)]
+pub struct State {
+    pub pid: u32,
+    pub version: &'static str,
+    pub taken_at_unix_ms: i64,
+    pub uptime_ms: u64,
+    /// How many connections the daemon has accepted since it started.
+    pub connections_accepted: usize,
+    /// Set once `shpool daemon stop` has started shutting down.
+    pub stopping: bool,
+    /// Shards of the session table that were locked, whose sessions
+    /// are missing from `sessions`.
+    pub locked_shards: Vec<usize>,
+    pub sessions: Vec<Session>,
+    /// Every thread in the daemon, which tells you which connections
+    /// are open and which session threads are still around.
+    pub threads: Vec<Thread>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Session {
+    pub name: String,
+    pub child_pid: libc::pid_t,
+    pub started_at_unix_ms: i64,
+    pub attach_count: usize,
+    /// Whether the session's inner lock is held, which is the case
+    /// while a client is attached.
+    pub attached: bool,
+    /// Whether the session has a client stream, unknown while attached.
+    pub client_stream: Option<bool>,
+    /// Whether the shell->client thread has exited, unknown while
+    /// attached.
+    pub reader_finished: Option<bool>,
+    /// Messages waiting in the shell->client thread's control channels,
+    /// unknown if the control lock is held.
+    pub pending: Option<Pending>,
+    /// The pty size as rows and cols, unknown if its lock is held.
+    pub pty_size: Option<(u16, u16)>,
+    pub spool_bytes: usize,
+    pub evict_spool: bool,
+    pub queued_bytes: usize,
+    pub dropped_bytes: u64,
+    pub resyncs: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub pump_cpu_ns: u64,
+    pub throttled: bool,
+    pub last_active_unix_ms: i64,
+    pub last_heartbeat_unix_ms: i64,
+    pub locked: bool,
+    pub exit_status: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Pending {
+    pub client_connection: usize,
+    pub client_connection_ack: usize,
+    pub tty_size_change: usize,
+    pub tty_size_change_ack: usize,
+    pub heartbeat: usize,
+    pub heartbeat_ack: usize,
+    pub scroll: usize,
+    pub scrolling: bool,
+    pub screen_lock: usize,
+    pub screen_locked: bool,
+    pub rename: usize,
+    pub capture: usize,
+    pub capture_ack: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Thread {
+    pub tid: u32,
+    pub name: String,
+    /// The state letter from /proc, like R for running, S for sleeping
+    /// or D for stuck in the kernel.
+    pub state: String,
+}
+
+impl Session {
+    /// Snapshot a session without waiting on any of its locks.
+    pub fn of(name: &str, session: &shell::Session) -> Self {
+        let (attached, client_stream, reader_finished) = match session.inner.try_lock() {
+            Ok(inner) => (
+                false,
+                Some(inner.client_stream.is_some()),
+                inner.shell_to_client_join_h.as_ref().map(|h| h.is_finished()),
+            ),
+            Err(TryLockError::WouldBlock) => (true, None, None),
+            Err(TryLockError::Poisoned(_)) => (false, None, None),
+        };
+        let pending = session.shell_to_client_ctl.try_lock().ok().map(|ctl| Pending {
+            client_connection: ctl.client_connection.len(),
+            client_connection_ack: ctl.client_connection_ack.len(),
+            tty_size_change: ctl.tty_size_change.len(),
+            tty_size_change_ack: ctl.tty_size_change_ack.len(),
+            heartbeat: ctl.heartbeat.len(),
+            heartbeat_ack: ctl.heartbeat_ack.len(),
+            scroll: ctl.scroll.len(),
+            scrolling: ctl.scrolling.load(Ordering::Relaxed),
+            screen_lock: ctl.screen_lock.len(),
+            screen_locked: ctl.screen_locked.load(Ordering::Relaxed),
+            rename: ctl.rename.len(),
+            capture: ctl.capture.len(),
+            capture_ack: ctl.capture_ack.len(),
+        });
+
+        Session {
+            name: String::from(name),
+            child_pid: session.child_pid,
+            started_at_unix_ms: shell::unix_ms(session.started_at),
+            attach_count: session.attach_count,
+            attached,
+            client_stream,
+            reader_finished,
+            pending,
+            pty_size: session.pty_size.try_lock().ok().map(|size| (size.rows, size.cols)),
+            spool_bytes: session.spool_bytes.load(Ordering::Relaxed),
+            evict_spool: session.evict_spool.load(Ordering::Relaxed),
+            queued_bytes: session.output_queue.queued_bytes.load(Ordering::Relaxed),
+            dropped_bytes: session.output_queue.dropped_bytes.load(Ordering::Relaxed),
+            resyncs: session.output_queue.resyncs.load(Ordering::Relaxed),
+            bytes_in: session.bytes_in.load(Ordering::Relaxed),
+            bytes_out: session.bytes_out.load(Ordering::Relaxed),
+            pump_cpu_ns: session.pump_cpu_ns.load(Ordering::Relaxed),
+            throttled: session.throttled.load(Ordering::Relaxed),
+            last_active_unix_ms: session.last_active.load(Ordering::Relaxed),
+            last_heartbeat_unix_ms: session.last_heartbeat.load(Ordering::Relaxed),
+            locked: session.lock.is_some(),
+            exit_status: session.exited.as_ref().map(|e| e.exit_status),
+        }
+    }
+}
+
+/// The daemon's threads, from /proc. Empty where there is no /proc.
+pub fn threads() -> Vec<Thread> {
+    let Ok(tasks) = fs::read_dir("/proc/self/task") else {
+        return vec![];
+    };
+    let mut threads: Vec<Thread> = tasks
+        .filter_map(|task| {
+            let task = task.ok()?;
+            let tid = task.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(task.path().join("stat")).ok()?;
+            let (name, state) = parse_stat(&stat)?;
+            Some(Thread { tid, name, state })
+        })
+        .collect();
+    threads.sort_by_key(|t| t.tid);
+    threads
+}
+
+/// Pull the name and state out of a /proc/<pid>/stat line. The name is
+/// in parens and may have parens and spaces of its own, so the state is
+/// the first field after the last paren.
+fn parse_stat(stat: &str) -> Option<(String, String)> {
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let name = stat.get(open + 1..close)?;
+    let state = stat.get(close + 1..)?.split_whitespace().next()?;
+    Some((String::from(name), String::from(state)))
+}
+
+/// Write the snapshot out to the runtime dir, returning where it went.
+pub fn write(runtime_dir: &Path, json: &str) -> anyhow::Result<PathBuf> {
+    let path = runtime_dir.join(FILE_NAME);
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, json).context("writing state dump")?;
+    fs::rename(&tmp_path, &path).context("moving state dump into place")?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stat_lines() {
+        assert_eq!(
+            parse_stat("1234 (s2c:main) S 1 1234 1234 0 -1"),
+            Some((String::from("s2c:main"), String::from("S")))
+        );
+        assert_eq!(
+            parse_stat("1234 (c2s:a) b (c)) D 1 1234"),
+            Some((String::from("c2s:a) b (c)"), String::from("D")))
+        );
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn own_threads() {
+        if !Path::new("/proc/self/task").exists() {
+            return;
+        }
+        // at the very least, the thread doing the looking is running
+        assert!(threads().iter().any(|t| t.state == "R"));
+    }
+}
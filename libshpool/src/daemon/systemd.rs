@@ -16,12 +16,18 @@ use std::{
     env,
     os::{
         fd::{OwnedFd, RawFd},
-        unix::{io::FromRawFd, net::UnixListener},
+        unix::{
+            io::FromRawFd,
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
     },
+    process, thread,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
 use nix::sys::stat;
+use tracing::{info, warn};
 
 // the fd that systemd uses for the first activation socket
 // (0 through 2 are for the std streams)
@@ -50,3 +56,56 @@ pub fn activation_socket() -> anyhow::Result<UnixListener> {
 
     Ok(fd.into())
 }
+
+/// Send a state update such as READY=1 to the service manager. This is
+/// a noop unless we are running as a Type=notify unit.
+/// Reference https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+pub fn notify(state: &str) -> anyhow::Result<()> {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let addr = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(anyhow!("abstract NOTIFY_SOCKET {} is not supported", path)),
+        None => SocketAddr::from_pathname(&path),
+    }
+    .with_context(|| format!("parsing NOTIFY_SOCKET {path}"))?;
+
+    let sock = UnixDatagram::unbound().context("creating notify socket")?;
+    sock.send_to_addr(state.as_bytes(), &addr)
+        .with_context(|| format!("sending {state:?} to {path}"))?;
+    Ok(())
+}
+
+/// If the unit sets WatchdogSec, spawn a thread that pings the service
+/// manager twice per watchdog interval so that it does not kill us.
+pub fn spawn_watchdog() -> anyhow::Result<()> {
+    let Ok(usec) = env::var("WATCHDOG_USEC") else {
+        return Ok(());
+    };
+    let usec: u64 = usec.parse().context("parsing WATCHDOG_USEC as int")?;
+    // The watchdog may be meant for some other process in the unit.
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse::<u32>().ok() != Some(process::id())
+    {
+        return Ok(());
+    }
+
+    let interval = Duration::from_micros(usec) / 2;
+    info!("pinging the systemd watchdog every {:?}", interval);
+    thread::Builder::new()
+        .name(String::from("watchdog"))
+        .spawn(move || loop {
+            if let Err(e) = notify("WATCHDOG=1") {
+                warn!("pinging watchdog: {:?}", e);
+            }
+            thread::sleep(interval);
+        })
+        .context("spawning watchdog thread")?;
+    Ok(())
+}
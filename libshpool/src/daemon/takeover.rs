@@ -0,0 +1,315 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Handing a running daemon's sessions over to a new daemon.
+
+  Upgrading shpool normally means restarting the daemon, which takes
+  every shell down with it. `shpool daemon --takeover` avoids that. The
+  new daemon dials the old one's control socket and asks for a takeover.
+  The old daemon detaches any attached clients, snapshots the restore
+  buffer of each session, and sends that state over followed by the
+  listening socket and the pty master of every session, passed as
+  SCM_RIGHTS ancillary data. Once the new daemon confirms it has
+  everything, the old one exits without touching the shells, and the
+  new one adopts the sessions and starts serving the socket it was
+  handed.
+
+  The shells are not children of the new daemon, so it can tell when
+  they exit but not what their exit status was. Output printed in the
+  brief window between the snapshot and the old daemon exiting is lost,
+  and session ttls and output logs don't carry over.
+*/
+
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read as _, Write as _},
+    os::{
+        fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+use serde_derive::{Deserialize, Serialize};
+use shpool_protocol::{ConnectHeader, TtySize};
+use tracing::info;
+
+use super::lock;
+use crate::{config, protocol, protocol::ClientResult, resurrect};
+
+// The most fds we pass in a single message, comfortably under the
+// kernel's limit of 253 (SCM_MAX_FD).
+const FDS_PER_MSG: usize = 200;
+
+/// Everything the old daemon hands over besides file descriptors.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    #[serde(default)]
+    pub sessions: Vec<SessionState>,
+}
+
+/// A session being handed over. The pty master for the session is
+/// passed separately, in the same order as the sessions.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionState {
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub started_at_unix_ms: i64,
+    pub child_pid: libc::pid_t,
+    pub tty_size: TtySize,
+    #[serde(default)]
+    pub attach_count: usize,
+    /// The raw contents of the session's spool, which get replayed
+    /// into the new daemon's spool.
+    #[serde(default)]
+    pub restore_buffer: Vec<u8>,
+    #[serde(default)]
+    pub recipe: resurrect::Recipe,
+    /// The session's lock, so that a takeover does not unlock it.
+    #[serde(default)]
+    pub lock: Option<lock::Lock>,
+    /// The session's note, see `shpool note`.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// The watches added with `shpool watch`.
+    #[serde(default)]
+    pub watches: Vec<config::Watch>,
+}
+
+/// Make sure a request to hand over ptys (a takeover, a migration or
+/// an adoption) came from another process running as our own user and
+/// dialing the socket directly. Members of the socket group may use
+/// sessions, but handing them every pty fd would get them past session
+/// locks. Connections relayed from the tcp listener show up as coming
+/// from the daemon itself, and we never want to hand our ptys over to
+/// the network.
+pub fn check_peer(stream: &UnixStream) -> anyhow::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let peer_creds = socket::getsockopt(stream, socket::sockopt::PeerCredentials)
+            .context("getting peer creds")?;
+        check_creds(peer_creds.pid(), peer_creds.uid())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = stream;
+        Err(anyhow!("takeover is only supported on linux"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_creds(pid: libc::pid_t, uid: libc::uid_t) -> anyhow::Result<()> {
+    if pid == std::process::id() as libc::pid_t {
+        return Err(anyhow!("refusing takeover over a relayed connection"));
+    }
+    if uid != nix::unistd::Uid::current().as_raw() {
+        return Err(anyhow!("refusing to hand sessions over to uid {}", uid));
+    }
+    Ok(())
+}
+
+/// The old daemon's side of a takeover. Sends the state and the fds
+/// (the listener first, then one pty master per session) and waits for
+/// the new daemon to confirm that it got them. The caller should exit
+/// once this returns successfully.
+pub fn hand_over(stream: &mut UnixStream, state: &State, fds: &[RawFd]) -> anyhow::Result<()> {
+    protocol::encode_to(state, &*stream).context("sending takeover state")?;
+    send_fds(stream, fds)?;
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack).context("waiting for takeover ack")?;
+    Ok(())
+}
+
+/// The new daemon's side of a takeover, in progress.
+pub struct Handover {
+    stream: UnixStream,
+    listener: UnixListener,
+    sessions: Vec<(SessionState, OwnedFd)>,
+}
+
+/// Ask the daemon listening on the given socket to hand everything over.
+pub fn request(socket: &Path) -> anyhow::Result<Handover> {
+    let client = match protocol::Client::new(socket).context("dialing the running daemon")? {
+        ClientResult::JustClient(c) => c,
+        ClientResult::VersionMismatch { warning, client } => {
+            // expected, since the point is to upgrade
+            info!("taking over across versions: {}", warning);
+            client
+        }
+    };
+    client.write_connect_header(ConnectHeader::Takeover).context("requesting takeover")?;
+    let stream = client.into_stream();
+
+    let state: State = protocol::decode_from(&stream).context("reading takeover state")?;
+    let mut fds = recv_fds(&stream, state.sessions.len() + 1)?.into_iter();
+    let listener = UnixListener::from(fds.next().ok_or(anyhow!("no listener handed over"))?);
+    let sessions = state.sessions.into_iter().zip(fds).collect();
+
+    Ok(Handover { stream, listener, sessions })
+}
+
+impl Handover {
+    /// Tell the old daemon we have everything, then wait for it to exit
+    /// so that it is no longer reading from the ptys when we start to.
+    pub fn finish(mut self) -> anyhow::Result<(UnixListener, Vec<(SessionState, OwnedFd)>)> {
+        self.stream.write_all(&[1]).context("acking takeover")?;
+        // The old daemon never writes anything else, so this returns
+        // once its end of the connection is closed by it exiting.
+        let mut buf = [0; 1];
+        let _ = self.stream.read(&mut buf);
+        Ok((self.listener, self.sessions))
+    }
+}
+
+/// Wrap a pty master that was handed to us in the Master type the rest
+/// of the daemon works with. shpool_pty only builds Masters for ptys it
+/// opened itself, so we have it open a placeholder and then swap the
+/// real pty in underneath.
+pub fn master_from_fd(fd: OwnedFd) -> anyhow::Result<shpool_pty::fork::Master> {
+    let master = shpool_pty::fork::Master::new(c"/dev/null".as_ptr())
+        .map_err(|e| anyhow!("opening placeholder fd: {:?}", e))?;
+    let placeholder = master.raw_fd().ok_or(anyhow!("no placeholder fd"))?;
+    // Safety: both fds are open, and dup2 atomically closes the
+    // placeholder before making it refer to the pty.
+    if unsafe { libc::dup2(fd.as_raw_fd(), placeholder) } < 0 {
+        return Err(io::Error::last_os_error()).context("swapping in pty master");
+    }
+    Ok(master)
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> anyhow::Result<()> {
+    for batch in fds.chunks(FDS_PER_MSG) {
+        // ancillary data has to ride along with at least one real byte
+        let iov = [IoSlice::new(&[0])];
+        let cmsgs = [ControlMessage::ScmRights(batch)];
+        socket::sendmsg::<UnixAddr>(stream.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)
+            .context("sending fds")?;
+    }
+    Ok(())
+}
+
+pub fn recv_fds(stream: &UnixStream, count: usize) -> anyhow::Result<Vec<OwnedFd>> {
+    let mut fds = vec![];
+    while fds.len() < count {
+        let mut byte = [0; 1];
+        let mut iov = [IoSliceMut::new(&mut byte)];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; FDS_PER_MSG]);
+        let msg = socket::recvmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::empty(),
+        )
+        .context("receiving fds")?;
+        if msg.bytes == 0 {
+            return Err(anyhow!("connection closed after {} of {} fds", fds.len(), count));
+        }
+        if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+            return Err(anyhow!("fds got truncated"));
+        }
+        for cmsg in msg.cmsgs().context("parsing control messages")? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // Safety: the kernel just gave us these, nothing else owns them.
+                fds.extend(received.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+    }
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Seek as _};
+
+    use super::*;
+
+    #[test]
+    fn passes_fds() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let (left, right) = UnixStream::pair()?;
+
+        // more files than fit in one message
+        let mut files = vec![];
+        for i in 0..(FDS_PER_MSG + 5) {
+            let path = tmp_dir.path().join(format!("f{i}"));
+            fs::write(&path, format!("{i}"))?;
+            files.push(fs::File::open(path)?);
+        }
+        let raw_fds: Vec<RawFd> = files.iter().map(|f| f.as_raw_fd()).collect();
+        send_fds(&left, &raw_fds)?;
+
+        let received = recv_fds(&right, files.len())?;
+        assert_eq!(received.len(), files.len());
+        let mut last = fs::File::from(received.into_iter().last().unwrap());
+        last.rewind()?;
+        let mut contents = String::new();
+        last.read_to_string(&mut contents)?;
+        assert_eq!(contents, format!("{}", FDS_PER_MSG + 4));
+        Ok(())
+    }
+
+    #[test]
+    fn state_round_trip() -> anyhow::Result<()> {
+        let state = State {
+            sessions: vec![SessionState {
+                name: String::from("main"),
+                tags: vec![String::from("work")],
+                started_at_unix_ms: 42,
+                child_pid: 1234,
+                tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+                attach_count: 3,
+                restore_buffer: b"some output".to_vec(),
+                recipe: resurrect::Recipe { cmd: Some(String::from("htop")), ..Default::default() },
+                lock: None,
+                note: Some(String::from("bisecting")),
+                watches: vec![config::Watch {
+                    pattern: String::from("FAIL"),
+                    ..Default::default()
+                }],
+            }],
+        };
+        let mut buf = vec![];
+        protocol::encode_to(&state, &mut buf)?;
+        let decoded: State = protocol::decode_from(&buf[..])?;
+        assert_eq!(decoded.sessions[0].name, "main");
+        assert_eq!(decoded.sessions[0].attach_count, 3);
+        assert_eq!(decoded.sessions[0].restore_buffer, b"some output");
+        assert_eq!(decoded.sessions[0].recipe.cmd.as_deref(), Some("htop"));
+        assert_eq!(decoded.sessions[0].note.as_deref(), Some("bisecting"));
+        assert_eq!(decoded.sessions[0].watches[0].pattern, "FAIL");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn rejects_own_connections() -> anyhow::Result<()> {
+        let (left, _right) = UnixStream::pair()?;
+        assert!(check_peer(&left).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn rejects_other_users() {
+        let uid = nix::unistd::Uid::current().as_raw();
+        let other_pid = std::process::id() as libc::pid_t + 1;
+        assert!(check_creds(other_pid, uid).is_ok());
+        // a socket group member gets to attach, but must not be able
+        // to grab the ptys of locked sessions this way
+        assert!(check_creds(other_pid, uid.wrapping_add(1)).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Helpers for keeping track of the threads the daemon spawns.
+
+  Every daemon thread is named after its role and, where it has one,
+  the session it serves. Linux truncates thread names to 15 bytes, so
+  roles use short tags (`s2c`, `c2s`, `hb`...) to leave as much room
+  as possible for the session name, which is what you are usually
+  looking for in `top -H` or `gdb`.
+*/
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use nix::time::{clock_gettime, ClockId};
+
+/// The name to give a thread playing the given role for the given session.
+///
+/// Session names come straight from clients, so control characters are
+/// swapped out. In particular `thread::Builder::name` panics on an
+/// interior NUL, which would take down the spawning thread while it
+/// holds the shard lock.
+pub fn name(role: &str, session: &str) -> String {
+    let session: String = session.chars().map(|c| if c.is_control() { '?' } else { c }).collect();
+    format!("{role}:{session}")
+}
+
+/// Accumulates the CPU time burned by the current thread into a
+/// counter that can be shared with other threads.
+///
+/// A meter must only be used on the thread that created it, since it
+/// reads the per-thread CPU clock.
+pub struct ThreadCpuMeter {
+    total_ns: Arc<AtomicU64>,
+    last_ns: u64,
+}
+
+impl ThreadCpuMeter {
+    pub fn new(total_ns: Arc<AtomicU64>) -> Self {
+        ThreadCpuMeter { total_ns, last_ns: thread_cpu_ns() }
+    }
+
+    /// Add the CPU time used since the last tick to the shared counter.
+    pub fn tick(&mut self) {
+        let now = thread_cpu_ns();
+        self.total_ns.fetch_add(now.saturating_sub(self.last_ns), Ordering::Relaxed);
+        self.last_ns = now;
+    }
+}
+
+impl Drop for ThreadCpuMeter {
+    fn drop(&mut self) {
+        self.tick();
+    }
+}
+
+fn thread_cpu_ns() -> u64 {
+    match clock_gettime(ClockId::CLOCK_THREAD_CPUTIME_ID) {
+        Ok(ts) => ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64,
+        // Should never happen, and if it does it just means our
+        // accounting is off, which is not worth blowing up over.
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn meter_accumulates() {
+        let total = Arc::new(AtomicU64::new(0));
+        {
+            let mut meter = ThreadCpuMeter::new(Arc::clone(&total));
+            let mut x: u64 = 0;
+            for i in 0..1_000_000 {
+                x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(i));
+            }
+            meter.tick();
+            assert!(total.load(Ordering::Relaxed) > 0);
+        }
+        // dropping the meter should not lose or double count time
+        let after_drop = total.load(Ordering::Relaxed);
+        assert!(after_drop > 0);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(name("s2c", "main"), "s2c:main");
+    }
+
+    #[test]
+    fn names_without_control_chars() {
+        assert_eq!(name("s2c", "a\0b\n"), "s2c:a?b?");
+        std::thread::Builder::new()
+            .name(name("c2s", "a\0b"))
+            .spawn(|| {})
+            .expect("spawn with sanitized name")
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Holding back sessions that print too much while detached.
+
+  A program spewing output in a session nobody is looking at keeps the
+  shell->client thread busy feeding the spool for no one's benefit. If
+  `detached_output_limit` is set, the shell->client thread counts the
+  output it reads while detached over one second windows, and once a
+  window goes over the limit it stops reading from the pty until the
+  next window starts. The pty fills up in the meantime, so the program
+  blocks on its writes rather than the daemon spinning to keep up.
+*/
+
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+pub struct Throttle {
+    /// The most bytes to read per window.
+    limit: usize,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+impl Throttle {
+    pub fn new(limit: usize) -> Self {
+        Throttle { limit, window_start: Instant::now(), window_bytes: 0 }
+    }
+
+    /// Count some output read at the given time. If that puts the
+    /// current window over the limit, returns when reading can resume.
+    pub fn record(&mut self, nbytes: usize, now: Instant) -> Option<Instant> {
+        if now.saturating_duration_since(self.window_start) >= WINDOW {
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        self.window_bytes += nbytes;
+        if self.window_bytes >= self.limit {
+            Some(self.window_start + WINDOW)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(100);
+        throttle.window_start = start;
+
+        assert_eq!(throttle.record(60, start), None);
+        assert_eq!(throttle.record(60, start + Duration::from_millis(200)), Some(start + WINDOW));
+
+        // a new window starts with a clean slate
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(throttle.record(60, later), None);
+        assert_eq!(throttle.record(40, later), Some(later + WINDOW));
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping track of the terminal title.
+
+  Shells and editors set the title of the terminal (which most
+  terminal emulators show in the tab or window decoration) with OSC 0
+  or OSC 2 sequences. These only get sent when the title changes, so
+  a client that attaches later never sees them and is left showing
+  whatever title the last session it was attached to set. To fix that
+  up, the shell->client thread watches the output for title changes
+  and sends the most recent one again whenever a client attaches.
+
+  Many shells also report their working directory with OSC 7 on every
+  prompt, which looks just like a title as far as parsing goes, so the
+  tracker picks those up too for `shpool list` to show.
+*/
+
+// Titles longer than this are almost certainly garbage, so they get
+// dropped rather than held on to.
+const MAX_TITLE_LEN: usize = 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    // the numeric parameter at the start of an OSC sequence
+    OscKind,
+    // the text of a title setting OSC, or of an OSC 7
+    Title,
+    TitleEsc,
+    // some other OSC, or one with a title that is too long
+    Skip,
+    SkipEsc,
+}
+
+/// Tracks the most recent title the output has set. Sequences can be
+/// split across calls to process.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    state: State,
+    kind: Vec<u8>,
+    buf: Vec<u8>,
+    title: Option<String>,
+    // A working directory reported since the last call to take_cwd.
+    cwd: Option<String>,
+}
+
+impl Tracker {
+    pub fn process(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state = match (self.state, *byte) {
+                (State::Ground, 0x1b) => State::Esc,
+                (State::Ground, _) => State::Ground,
+                (State::Esc, b']') => {
+                    self.kind.clear();
+                    State::OscKind
+                }
+                (State::Esc, 0x1b) => State::Esc,
+                (State::Esc, _) => State::Ground,
+                (State::OscKind, b'0'..=b'9') if self.kind.len() < 4 => {
+                    self.kind.push(*byte);
+                    State::OscKind
+                }
+                (State::OscKind, b';') if matches!(&self.kind[..], b"0" | b"2" | b"7") => {
+                    self.buf.clear();
+                    State::Title
+                }
+                (State::OscKind | State::Skip, 0x07) => State::Ground,
+                (State::OscKind | State::Skip, 0x1b) => State::SkipEsc,
+                (State::OscKind | State::Skip, _) => State::Skip,
+                (State::Title, 0x07) => {
+                    self.finish();
+                    State::Ground
+                }
+                (State::Title, 0x1b) => State::TitleEsc,
+                (State::Title, _) if self.buf.len() < MAX_TITLE_LEN => {
+                    self.buf.push(*byte);
+                    State::Title
+                }
+                (State::Title, _) => State::Skip,
+                // ESC \ terminates the string, anything else after an ESC
+                // starts a new sequence and abandons the string
+                (State::TitleEsc, b'\\') => {
+                    self.finish();
+                    State::Ground
+                }
+                (State::SkipEsc, b'\\') => State::Ground,
+                (State::TitleEsc | State::SkipEsc, b']') => {
+                    self.kind.clear();
+                    State::OscKind
+                }
+                (State::TitleEsc | State::SkipEsc, 0x1b) => State::Esc,
+                (State::TitleEsc | State::SkipEsc, _) => State::Ground,
+            };
+        }
+    }
+
+    fn finish(&mut self) {
+        let text = String::from_utf8_lossy(&self.buf);
+        if self.kind == b"7" {
+            if let Some(cwd) = osc7_path(&text) {
+                self.cwd = Some(cwd);
+            }
+        } else {
+            self.title = Some(text.into_owned());
+        }
+    }
+
+    /// The working directory the output has reported with OSC 7 since
+    /// this was last called, if any.
+    pub fn take_cwd(&mut self) -> Option<String> {
+        self.cwd.take()
+    }
+
+    /// The sequence to send to a newly attached client to get its title
+    /// right. If the output has not set a title, this gives the session
+    /// the default `shpool: <session>` title if asked to, and is empty
+    /// otherwise.
+    pub fn reemit(&self, session_name: &str, default_title: bool) -> Vec<u8> {
+        match &self.title {
+            Some(title) => set_title(title),
+            None if default_title => set_title(&format!("shpool: {session_name}")),
+            None => vec![],
+        }
+    }
+}
+
+/// The path from an OSC 7 `file://host/path` URI, with any percent
+/// escapes decoded.
+fn osc7_path(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("file://")?;
+    let path = &rest.as_bytes()[rest.find('/')?..];
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let digit = |at: usize| path.get(at).and_then(|b| hex(*b));
+        match (path[i], digit(i + 1), digit(i + 2)) {
+            (b'%', Some(high), Some(low)) => {
+                decoded.push(high << 4 | low);
+                i += 3;
+            }
+            (b, _, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// The OSC 2 sequence to set the title, with any control characters
+/// that could break out of it dropped.
+fn set_title(title: &str) -> Vec<u8> {
+    let title = title.chars().filter(|c| !c.is_control()).collect::<String>();
+    format!("\x1b]2;{title}\x07").into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tracks_title() {
+        let mut tracker = Tracker::default();
+        assert_eq!(tracker.title.as_deref(), None);
+        tracker.process(b"\x1b]0;user@host: ~\x07$ ");
+        assert_eq!(tracker.title.as_deref(), Some("user@host: ~"));
+        tracker.process(b"vim\r\n\x1b]2;notes.txt - VIM\x1b\\");
+        assert_eq!(tracker.title.as_deref(), Some("notes.txt - VIM"));
+
+        // other OSC sequences, like the icon name or hyperlinks, don't count
+        tracker.process(b"\x1b]1;icon\x07\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\");
+        assert_eq!(tracker.title.as_deref(), Some("notes.txt - VIM"));
+    }
+
+    #[test]
+    fn split_sequences() {
+        let mut tracker = Tracker::default();
+        for chunk in [&b"\x1b"[..], b"]", b"2", b";some ", b"title\x1b", b"\\"] {
+            tracker.process(chunk);
+        }
+        assert_eq!(tracker.title.as_deref(), Some("some title"));
+    }
+
+    #[test]
+    fn abandoned_title() {
+        let mut tracker = Tracker::default();
+        tracker.process(b"\x1b]2;half a title\x1b[H");
+        assert_eq!(tracker.title.as_deref(), None);
+
+        let mut long = b"\x1b]2;".to_vec();
+        long.extend(vec![b'x'; MAX_TITLE_LEN + 1]);
+        long.push(0x07);
+        tracker.process(&long);
+        assert_eq!(tracker.title.as_deref(), None);
+    }
+
+    #[test]
+    fn tracks_cwd() {
+        let mut tracker = Tracker::default();
+        assert_eq!(tracker.take_cwd(), None);
+        tracker.process(b"\x1b]7;file://host/home/me/My%20Stuff\x1b\\$ ");
+        assert_eq!(tracker.take_cwd().as_deref(), Some("/home/me/My Stuff"));
+        assert_eq!(tracker.take_cwd(), None);
+        assert_eq!(tracker.title.as_deref(), None);
+
+        tracker.process(b"\x1b]7;file:///tmp\x07\x1b]0;title\x07");
+        assert_eq!(tracker.take_cwd().as_deref(), Some("/tmp"));
+        assert_eq!(tracker.title.as_deref(), Some("title"));
+
+        // not a file URI
+        tracker.process(b"\x1b]7;https://example.com/\x07");
+        assert_eq!(tracker.take_cwd(), None);
+    }
+
+    #[test]
+    fn reemit() {
+        let mut tracker = Tracker::default();
+        assert_eq!(tracker.reemit("main", false), b"");
+        assert_eq!(tracker.reemit("main", true), b"\x1b]2;shpool: main\x07");
+        tracker.process(b"\x1b]0;vim\x07");
+        assert_eq!(tracker.reemit("main", true), b"\x1b]2;vim\x07");
+    }
+}
@@ -18,24 +18,41 @@
   names to avoid clobbering fresh session with the same
   session name as a previous session, and uses a min heap
   to schedule wakeups in order to reap threads on time.
+
+  Sessions with an idle ttl also end up here. Their shell->client
+  thread keeps track of how long the session has been idle and
+  sends it to the reaper with a reap time of right now once it has
+  been idle for too long.
+
+  When a session gets renamed, the reaper gets told about it so that
+  any pending reap follows the session to its new name.
 */
 
 use std::{
     cmp,
     collections::{BinaryHeap, HashMap},
-    sync::{Arc, Mutex},
+    sync::Arc,
     time::Instant,
 };
 
 use tracing::{info, span, warn, Level};
 
-use super::shell;
+use super::session_table::SessionTable;
+
+/// A message to the reaper's mailbox.
+#[derive(Debug)]
+pub enum Msg {
+    /// Kill the given session at the given time.
+    Reap(String, Instant),
+    /// The session has been renamed.
+    Rename { from: String, to: String },
+}
 
 /// Run the reaper thread loop. Should be invoked in a dedicated
 /// thread.
 pub fn run(
-    new_sess: crossbeam_channel::Receiver<(String, Instant)>,
-    shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    new_sess: crossbeam_channel::Receiver<Msg>,
+    shells: Arc<SessionTable>,
 ) -> anyhow::Result<()> {
     let _s = span!(Level::INFO, "ttl_reaper").entered();
 
@@ -46,7 +63,7 @@ pub fn run(
         // empty heap loop, just waiting for new sessions to watch
         while heap.is_empty() {
             match new_sess.recv() {
-                Ok((session_name, reap_at)) => {
+                Ok(Msg::Reap(session_name, reap_at)) => {
                     let gen_id = gen_ids.entry(session_name.clone()).or_insert(0);
                     *gen_id += 1;
                     info!(
@@ -55,6 +72,9 @@ pub fn run(
                     );
                     heap.push(Reapable { session_name, gen_id: *gen_id, reap_at });
                 }
+                Ok(Msg::Rename { .. }) => {
+                    // nothing scheduled, so nothing to follow the rename
+                }
                 Err(crossbeam_channel::RecvError) => {
                     info!("bailing due to RecvError in empty heap loop");
                     return Ok(());
@@ -73,7 +93,7 @@ pub fn run(
             crossbeam_channel::select! {
                 recv(new_sess) -> new_sess_msg => {
                     match new_sess_msg {
-                        Ok((session_name, reap_at)) => {
+                        Ok(Msg::Reap(session_name, reap_at)) => {
                             let gen_id = gen_ids.entry(session_name.clone()).or_insert(0);
                             *gen_id += 1;
                             info!("scheduling {}:{} to be reaped at {:?}",
@@ -84,6 +104,9 @@ pub fn run(
                                 reap_at,
                             });
                         }
+                        Ok(Msg::Rename { from, to }) => {
+                            heap = rename(heap, &mut gen_ids, &from, &to);
+                        }
                         Err(crossbeam_channel::RecvError) => {
                             info!("bailing due to RecvError");
                             return Ok(())
@@ -103,7 +126,7 @@ pub fn run(
                     }
 
                     let _s = span!(Level::INFO, "lock(shells)").entered();
-                    let mut shells = shells.lock().unwrap();
+                    let mut shells = shells.shard(&reapable.session_name);
                     if let Some(sess) = shells.get(&reapable.session_name) {
                         if let Err(e) = sess.kill() {
                             warn!("error trying to kill '{}': {:?}",
@@ -121,6 +144,32 @@ pub fn run(
     }
 }
 
+/// Move any live reap entries for `from` over to `to`, bumping the
+/// generation of `to` so stale entries from an older session with
+/// that name stay dead.
+fn rename(
+    heap: BinaryHeap<Reapable>,
+    gen_ids: &mut HashMap<String, usize>,
+    from: &str,
+    to: &str,
+) -> BinaryHeap<Reapable> {
+    let from_gen = gen_ids.get(from).copied().unwrap_or(0);
+    let to_gen = gen_ids.entry(String::from(to)).or_insert(0);
+    *to_gen += 1;
+    let to_gen = *to_gen;
+    info!("moving reaps for {}:{} to {}:{}", from, from_gen, to, to_gen);
+
+    heap.into_iter()
+        .map(|r| {
+            if r.session_name == from && r.gen_id == from_gen {
+                Reapable { session_name: String::from(to), gen_id: to_gen, reap_at: r.reap_at }
+            } else {
+                r
+            }
+        })
+        .collect()
+}
+
 /// A record in the min heap that we use to track the
 /// sessions that need to be cleaned up.
 #[derive(Debug)]
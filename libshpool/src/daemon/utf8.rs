@@ -0,0 +1,147 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Keeping multi-byte UTF-8 characters in one piece as session output
+  moves through the daemon.
+
+  A read from the pty can end partway through a character. Passed
+  straight on, the first half lands in the spool and gets sent to the
+  client on its own, so anything the daemon sends in between, like a
+  restore buffer getting followed by a cursor fixup on reattach, splits
+  the character and the terminal draws replacement characters. The
+  shell->client thread runs its reads through a Boundary, which holds
+  the start of a cut off character back until the rest of it shows up.
+  If the shell goes quiet instead, the thread flushes the held bytes
+  out as they are, since the rest is not coming.
+*/
+
+use std::borrow::Cow;
+
+/// Holds back a character cut off at the end of one read so that it
+/// can go out whole at the start of the next.
+#[derive(Debug, Default)]
+pub struct Boundary {
+    held: Vec<u8>,
+}
+
+impl Boundary {
+    /// The part of `buf`, with whatever was held back last time stuck
+    /// on the front, that ends on a character boundary. Anything after
+    /// that gets held back. Bytes that can't be part of a valid
+    /// character are passed along as they are, so this never holds on
+    /// to more than 3 bytes.
+    pub fn split<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.held.is_empty() {
+            let end = buf.len() - incomplete_tail(buf);
+            self.held.extend_from_slice(&buf[end..]);
+            return Cow::Borrowed(&buf[..end]);
+        }
+
+        let mut joined = std::mem::take(&mut self.held);
+        joined.extend_from_slice(buf);
+        let end = joined.len() - incomplete_tail(&joined);
+        self.held = joined.split_off(end);
+        Cow::Owned(joined)
+    }
+
+    /// Whatever has been held back, which is then forgotten.
+    pub fn flush(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// How many bytes at the end of `buf` make up the start of a character
+/// that is missing the rest of its bytes.
+fn incomplete_tail(buf: &[u8]) -> usize {
+    // a character is at most 4 bytes, so its lead byte is within the
+    // last 3 if it is incomplete
+    for back in 1..=buf.len().min(3) {
+        let b = buf[buf.len() - back];
+        if b & 0xc0 == 0x80 {
+            // a continuation byte, keep looking for the lead
+            continue;
+        }
+        let want = match b {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            // ascii, or something that can't start a character
+            _ => return 0,
+        };
+        return if back < want { back } else { 0 };
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tails() {
+        assert_eq!(incomplete_tail(b""), 0);
+        assert_eq!(incomplete_tail(b"abc"), 0);
+        assert_eq!(incomplete_tail("é".as_bytes()), 0);
+        assert_eq!(incomplete_tail(&"é".as_bytes()[..1]), 1);
+        assert_eq!(incomplete_tail(&"€".as_bytes()[..2]), 2);
+        assert_eq!(incomplete_tail(&"🦀".as_bytes()[..3]), 3);
+        assert_eq!(incomplete_tail("a🦀".as_bytes()), 0);
+        // stray continuation bytes and invalid leads are not held
+        assert_eq!(incomplete_tail(&[b'a', 0x80, 0x80]), 0);
+        assert_eq!(incomplete_tail(&[b'a', 0xff]), 0);
+        assert_eq!(incomplete_tail(&[0x80, 0x80, 0x80, 0x80]), 0);
+    }
+
+    #[test]
+    fn splits() {
+        let text = "héllo wörld, ça coûte 5€ 🦀".as_bytes();
+        // every way of cutting the text in two comes out whole
+        for cut in 0..=text.len() {
+            let mut boundary = Boundary::default();
+            let mut out = boundary.split(&text[..cut]).into_owned();
+            assert!(std::str::from_utf8(&out).is_ok(), "first half of cut at {cut}");
+            out.extend_from_slice(&boundary.split(&text[cut..]));
+            assert_eq!(out, text);
+        }
+
+        // one byte at a time
+        let mut boundary = Boundary::default();
+        let mut out = vec![];
+        for b in text {
+            let piece = boundary.split(std::slice::from_ref(b));
+            assert!(std::str::from_utf8(&piece).is_ok());
+            out.extend_from_slice(&piece);
+        }
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn flushes() {
+        let mut boundary = Boundary::default();
+        assert!(boundary.flush().is_empty());
+        assert_eq!(&*boundary.split(&"a€".as_bytes()[..3]), b"a");
+        assert_eq!(boundary.flush(), &"€".as_bytes()[..2]);
+        assert!(boundary.flush().is_empty());
+        assert_eq!(&*boundary.split(b"b"), b"b");
+    }
+
+    #[test]
+    fn invalid() {
+        let mut boundary = Boundary::default();
+        assert_eq!(&boundary.split(&[b'a', 0xe2])[..], b"a");
+        // the rest never shows up, so the lead byte goes out as it was
+        assert_eq!(&boundary.split(b"bc")[..], &[0xe2, b'b', b'c']);
+        assert_eq!(&boundary.split(&[0xff, 0xfe])[..], &[0xff, 0xfe]);
+    }
+}
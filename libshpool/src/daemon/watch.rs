@@ -0,0 +1,295 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Watching the output of detached sessions for patterns.
+
+  Watches come from `[[watch]]` tables in the config and from `shpool
+  watch`, which adds them to a single session. While nobody is
+  attached, the shell->client thread runs the session's output through
+  a Scanner, which strips out escape sequences, splits what is left
+  into lines and checks each line against every watch. Every match
+  gets counted so that `shpool list` can point it out, but only the
+  first match for each watch after a detach runs the watch's `exec`
+  command or the `on_watch` hook, so that a burst of errors does not
+  turn into a burst of notifications.
+
+  Only whole lines get checked, so a prompt sitting there waiting for
+  input without a newline never matches.
+*/
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Context;
+use regex::Regex;
+use tracing::warn;
+
+use super::scrollback::Scrollback;
+use crate::{config, consts};
+
+/// The most of a matching line to hang on to for `shpool list`.
+const MAX_LINE_CHARS: usize = 200;
+
+/// A line of output that matched a watch.
+#[derive(Debug, PartialEq)]
+pub struct Hit {
+    pub pattern: String,
+    pub exec: Option<String>,
+    pub line: String,
+    /// True for the watch's first match since the last attach, which
+    /// is the one that runs a command.
+    pub first: bool,
+}
+
+/// A session's watches and what they have turned up, shared between
+/// the session and its shell->client thread.
+#[derive(Debug, Default)]
+pub struct Watches {
+    /// The watches added with `shpool watch`, see `Watches::edit`.
+    pub added: Mutex<Vec<config::Watch>>,
+    /// Bumped every time `added` changes, so that the shell->client
+    /// thread knows to rebuild its Scanner.
+    version: AtomicU64,
+    /// How many lines have matched since a client was last attached.
+    pub matches: AtomicU64,
+    /// The last line to match since a client was last attached.
+    pub last_match: Mutex<Option<String>>,
+}
+
+impl Watches {
+    /// Everything to watch for in the named session, the ones added
+    /// with `shpool watch` followed by the ones from the config.
+    pub fn all(&self, name: &str, config: &config::Config) -> Vec<config::Watch> {
+        let mut watches = self.added.lock().unwrap().clone();
+        if let Some(configured) = &config.watch {
+            watches.extend(configured.iter().filter(|w| w.applies_to(name)).cloned());
+        }
+        watches
+    }
+
+    /// Change the watches added with `shpool watch`.
+    pub fn edit<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Vec<config::Watch>) -> R,
+    {
+        let mut added = self.added.lock().unwrap();
+        let r = f(&mut added);
+        self.version.fetch_add(1, Ordering::Relaxed);
+        r
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, hit: &Hit) {
+        self.matches.fetch_add(1, Ordering::Relaxed);
+        *self.last_match.lock().unwrap() = Some(hit.line.clone());
+    }
+
+    /// Forget the matches once a client attaches and sees the output
+    /// for itself.
+    pub fn reset(&self) {
+        self.matches.store(0, Ordering::Relaxed);
+        *self.last_match.lock().unwrap() = None;
+    }
+}
+
+/// Checks a stream of terminal output against a set of watches.
+pub struct Scanner {
+    lines: Scrollback,
+    /// The watches to check, with None for patterns that don't compile.
+    watches: Vec<(config::Watch, Option<Regex>)>,
+    /// The Watches version and config generation that `watches` was
+    /// built from, or None if it needs building.
+    built_from: Option<(u64, u64)>,
+    /// The watches that have matched since the last attach, by pattern
+    /// and command.
+    fired: HashSet<(String, Option<String>)>,
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Scanner {
+            // the lines get drained after every chunk, so this just
+            // needs to be enough for a chunk full of newlines
+            lines: Scrollback::new(consts::BUF_SIZE),
+            watches: vec![],
+            built_from: None,
+            fired: HashSet::new(),
+        }
+    }
+
+    /// Rebuild the set of watches with `build` if the session's watches
+    /// or the config have changed since it was last built, rather than
+    /// for every chunk of output.
+    pub fn update<F>(&mut self, watches_version: u64, config_generation: u64, build: F)
+    where
+        F: FnOnce() -> Vec<config::Watch>,
+    {
+        let stamp = (watches_version, config_generation);
+        if self.built_from != Some(stamp) {
+            self.set_watches(build());
+            self.built_from = Some(stamp);
+        }
+    }
+
+    /// Make the next `update` rebuild the watches, for when the session
+    /// gets renamed and different `[[watch]]` tables might apply.
+    pub fn invalidate(&mut self) {
+        self.built_from = None;
+    }
+
+    fn set_watches(&mut self, watches: Vec<config::Watch>) {
+        let mut compiled: HashMap<String, Option<Regex>> = HashMap::new();
+        self.watches = watches
+            .into_iter()
+            .map(|watch| {
+                let regex = compiled
+                    .entry(watch.pattern.clone())
+                    .or_insert_with(|| {
+                        compile(&watch.pattern)
+                            .map_err(|e| warn!("ignoring watch '{}': {:?}", watch.pattern, e))
+                            .ok()
+                    })
+                    .clone();
+                (watch, regex)
+            })
+            .collect();
+    }
+
+    /// Feed a chunk of output through the scanner, returning a hit for
+    /// each watch that each finished line matches.
+    pub fn scan(&mut self, buf: &[u8]) -> Vec<Hit> {
+        if self.watches.is_empty() {
+            return vec![];
+        }
+        self.lines.process(buf);
+
+        let mut hits = vec![];
+        for line in self.lines.drain() {
+            for (watch, regex) in self.watches.iter() {
+                if !regex.as_ref().is_some_and(|r| r.is_match(&line)) {
+                    continue;
+                }
+                hits.push(Hit {
+                    pattern: watch.pattern.clone(),
+                    exec: watch.exec.clone(),
+                    line: truncate(&line),
+                    first: self.fired.insert((watch.pattern.clone(), watch.exec.clone())),
+                });
+            }
+        }
+        hits
+    }
+
+    /// Start over once a client attaches, forgetting which watches have
+    /// fired along with any half finished line.
+    pub fn reset(&mut self) {
+        self.lines = Scrollback::new(consts::BUF_SIZE);
+        self.fired.clear();
+    }
+}
+
+/// Compile a watch pattern.
+pub fn compile(pattern: &str) -> anyhow::Result<Regex> {
+    Regex::new(pattern).context("parsing pattern")
+}
+
+fn truncate(line: &str) -> String {
+    let line = line.trim_end();
+    match line.char_indices().nth(MAX_LINE_CHARS) {
+        Some((i, _)) => format!("{}...", &line[..i]),
+        None => String::from(line),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn watch(pattern: &str, exec: Option<&str>) -> config::Watch {
+        config::Watch {
+            session: None,
+            pattern: String::from(pattern),
+            exec: exec.map(String::from),
+        }
+    }
+
+    #[test]
+    fn matches_lines() {
+        let watches = vec![watch("ERROR|panic", None), watch("^done$", Some("notify-send done"))];
+        let mut scanner = Scanner::new();
+        scanner.set_watches(watches);
+        assert_eq!(scanner.scan(b"building\r\n\x1b[31mERR"), vec![]);
+        // the line only gets checked once it is finished, and with the
+        // color codes stripped out
+        let hits = scanner.scan(b"OR\x1b[0m: bad\r\ndone\r\nERROR: worse\r\n");
+        let summary: Vec<_> = hits.iter().map(|h| (h.line.as_str(), h.first)).collect();
+        assert_eq!(summary, vec![("ERROR: bad", true), ("done", true), ("ERROR: worse", false)]);
+        assert_eq!(hits[1].exec.as_deref(), Some("notify-send done"));
+
+        // a new attach starts things over
+        scanner.reset();
+        let hits = scanner.scan(b"thread 'main' panicked\n");
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].first);
+    }
+
+    #[test]
+    fn bad_patterns() {
+        let mut scanner = Scanner::new();
+        scanner.set_watches(vec![watch("(unclosed", None), watch("ok", None)]);
+        let hits = scanner.scan(b"(unclosed ok\n");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].pattern, "ok");
+        assert!(compile("(unclosed").is_err());
+    }
+
+    #[test]
+    fn rebuilds_on_change() {
+        let mut scanner = Scanner::new();
+        let mut builds = 0;
+        for _ in 0..3 {
+            scanner.update(0, 0, || {
+                builds += 1;
+                vec![watch("old", None)]
+            });
+        }
+        assert_eq!(builds, 1);
+
+        scanner.update(1, 0, || vec![watch("new", None)]);
+        assert_eq!(scanner.scan(b"old\n").len(), 0);
+        assert_eq!(scanner.scan(b"new\n").len(), 1);
+
+        scanner.update(1, 1, || vec![]);
+        assert_eq!(scanner.scan(b"new\n").len(), 0);
+
+        scanner.invalidate();
+        scanner.update(1, 1, || vec![watch("new", None)]);
+        assert_eq!(scanner.scan(b"new\n").len(), 1);
+    }
+
+    #[test]
+    fn long_lines() {
+        let line = "x".repeat(MAX_LINE_CHARS + 10);
+        assert_eq!(truncate(&line).len(), MAX_LINE_CHARS + 3);
+        assert_eq!(truncate("short \r"), "short");
+    }
+}
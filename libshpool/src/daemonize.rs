@@ -19,11 +19,16 @@ use crate::{config, consts, Args};
 use anyhow::{anyhow, Context};
 use tracing::info;
 
+// The systemd user unit that `autostart_daemon = "systemd"` starts.
+const SYSTEMD_UNIT: &str = "shpool.service";
+
 /// Check if we can connect to the control socket, and if we
-/// can't, fork the daemon in the background.
-pub fn maybe_fork_daemon<B, P>(
+/// can't, start the daemon in the background.
+pub fn maybe_start_daemon<B, P>(
     config_manager: &config::Manager,
     args: &Args,
+    autostart: config::Autostart,
+    namespace: Option<&str>,
     shpool_bin: B,
     control_sock: P,
 ) -> anyhow::Result<()>
@@ -39,25 +44,14 @@ where
         // don't need to do anything.
         return Ok(());
     }
-    info!("no daemon running on {:?}, autodaemonizing", control_sock);
 
-    let log_file = control_sock.with_file_name("daemonized-shpool.log");
-
-    let mut cmd = process::Command::new(shpool_bin);
-    if let Some(config_file) = &args.config_file {
-        cmd.arg("--config-file").arg(config_file);
+    match autostart {
+        // The unit only ever serves the default socket.
+        config::Autostart::Systemd if namespace.is_none() && args.socket.is_none() => {
+            start_systemd_unit()?
+        }
+        _ => fork_daemon(args, namespace, shpool_bin, control_sock)?,
     }
-    cmd.arg("--log-file")
-        .arg(log_file)
-        .arg("--socket")
-        .arg(control_sock.as_os_str())
-        .arg("daemon")
-        .env(consts::AUTODAEMONIZE_VAR, "true")
-        .stdout(process::Stdio::null())
-        .stderr(process::Stdio::null())
-        .spawn()
-        .context("launching background daemon")?;
-    info!("launched background daemon");
 
     // Now poll with exponential backoff until we can dial the control socket.
     if config_manager.get().nodaemonize_timeout.unwrap_or(false) {
@@ -93,3 +87,53 @@ where
 
     Err(anyhow!("daemonizing: launched daemon, but control socket never came up"))
 }
+
+/// Fork `shpool daemon` in the background.
+fn fork_daemon<B>(
+    args: &Args,
+    namespace: Option<&str>,
+    shpool_bin: B,
+    control_sock: &Path,
+) -> anyhow::Result<()>
+where
+    B: AsRef<OsStr>,
+{
+    info!("no daemon running on {:?}, autodaemonizing", control_sock);
+
+    let log_file = control_sock.with_file_name("daemonized-shpool.log");
+
+    let mut cmd = process::Command::new(shpool_bin);
+    if let Some(config_file) = &args.config_file {
+        cmd.arg("--config-file").arg(config_file);
+    }
+    cmd.arg("--log-file").arg(log_file);
+    // The daemon needs to know its namespace rather than just the
+    // socket so that it puts its runtime data in the same place.
+    match namespace {
+        Some(namespace) => cmd.arg("--namespace").arg(namespace),
+        None => cmd.arg("--socket").arg(control_sock.as_os_str()),
+    };
+    cmd.arg("daemon")
+        .env(consts::AUTODAEMONIZE_VAR, "true")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .context("launching background daemon")?;
+    info!("launched background daemon");
+    Ok(())
+}
+
+/// Start the daemon's systemd user unit.
+fn start_systemd_unit() -> anyhow::Result<()> {
+    info!("no daemon running, starting the {} user unit", SYSTEMD_UNIT);
+    let status = process::Command::new("systemctl")
+        .args(["--user", "start", SYSTEMD_UNIT])
+        .stdout(process::Stdio::null())
+        .status()
+        .context("running systemctl")?;
+    if !status.success() {
+        return Err(anyhow!("systemctl --user start {} failed with {}", SYSTEMD_UNIT, status));
+    }
+    info!("started {}", SYSTEMD_UNIT);
+    Ok(())
+}
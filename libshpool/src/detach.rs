@@ -19,7 +19,7 @@ use shpool_protocol::{ConnectHeader, DetachReply, DetachRequest};
 
 use crate::{common, protocol, protocol::ClientResult};
 
-pub fn run<P>(mut sessions: Vec<String>, socket: P) -> anyhow::Result<()>
+pub fn run<P>(mut sessions: Vec<String>, tags: Vec<String>, socket: P) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
@@ -38,10 +38,16 @@ where
         }
     };
 
-    common::resolve_sessions(&mut sessions, "detach")?;
+    if tags.is_empty() {
+        common::resolve_sessions(&mut sessions, "detach")?;
+    }
 
     client
-        .write_connect_header(ConnectHeader::Detach(DetachRequest { sessions }))
+        .write_connect_header(ConnectHeader::Detach(DetachRequest {
+            sessions,
+            tags,
+            taken_over_by: None,
+        }))
         .context("writing detach request header")?;
 
     let reply: DetachReply = client.read_reply().context("reading reply")?;
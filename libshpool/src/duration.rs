@@ -13,7 +13,8 @@
 // limitations under the License.
 
 /*! A parser for the duration format supported by the
-  attach --ttl flag.
+  attach --ttl flag, and a formatter for showing durations to people
+  in the same style.
 */
 
 use anyhow::{anyhow, bail, Context};
@@ -60,14 +61,39 @@ fn parse_colon_duration(src: &str) -> anyhow::Result<time::Duration> {
     Ok(time::Duration::from_secs(secs))
 }
 
-/// Parses 20d, 3h, 14m ect
+/// Parses 20d, 3h, 14m, 500ms ect
 fn parse_suffix_duration(src: &str) -> anyhow::Result<time::Duration> {
     let num: String = src.chars().take_while(|c| c.is_numeric()).collect();
+    if src.ends_with("ms") && src.len() == num.len() + 2 {
+        return Ok(time::Duration::from_millis(
+            num.parse::<u64>().context("parsing num part of duration")?,
+        ));
+    }
     let c = src.chars().last().ok_or(anyhow!("internal error: no suffix"))?;
     make_suffix_duration(num.parse::<u64>().context("parsing num part of duration")?, c)
         .ok_or(anyhow!("unknown time unit '{}'", c))
 }
 
+/// Formats a duration like 2h13m, with just the two largest units
+/// since nobody cares about the seconds when it has been days.
+pub fn format(d: time::Duration) -> String {
+    let secs = d.as_secs();
+    let parts = [(secs / (60 * 60 * 24), 'd'), (secs / (60 * 60) % 24, 'h'), (secs / 60 % 60, 'm')];
+    let mut out = String::new();
+    let mut units = 0;
+    for (n, unit) in parts {
+        if units == 2 || (n == 0 && units == 0) {
+            continue;
+        }
+        out.push_str(&format!("{n}{unit}"));
+        units += 1;
+    }
+    if units < 2 && secs < 60 * 60 {
+        out.push_str(&format!("{}s", secs % 60));
+    }
+    out
+}
+
 fn make_suffix_duration(n: u64, c: char) -> Option<time::Duration> {
     match c {
         's' => Some(time::Duration::from_secs(n)),
@@ -92,6 +118,7 @@ mod test {
             ("5m", time::Duration::from_secs(5 * 60)),
             ("5h", time::Duration::from_secs(5 * 60 * 60)),
             ("5d", time::Duration::from_secs(5 * 60 * 60 * 24)),
+            ("500ms", time::Duration::from_millis(500)),
         ];
 
         for (src, dur) in cases.into_iter() {
@@ -106,6 +133,21 @@ mod test {
         }
     }
 
+    #[test]
+    fn formats() {
+        let cases = vec![
+            (0, "0s"),
+            (45, "45s"),
+            (5 * 60 + 3, "5m3s"),
+            (2 * 60 * 60 + 13 * 60 + 59, "2h13m"),
+            (2 * 60 * 60, "2h0m"),
+            (3 * 60 * 60 * 24 + 4 * 60 * 60 + 5, "3d4h"),
+        ];
+        for (secs, want) in cases.into_iter() {
+            assert_eq!(format(time::Duration::from_secs(secs)), want);
+        }
+    }
+
     #[test]
     fn errors() {
         let cases = vec![
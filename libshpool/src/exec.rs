@@ -0,0 +1,90 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf, process};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, ExecReply, ExecRequest};
+
+use crate::{duration, protocol, protocol::ClientResult};
+
+pub fn run(
+    session: String,
+    command: String,
+    until: Option<String>,
+    timeout: Option<String>,
+    no_newline: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let timeout_ms = match timeout {
+        Some(src) => Some(duration::parse(&src).context("parsing timeout")?.as_millis() as u64),
+        None => None,
+    };
+    let wants_output = until.is_some() || timeout_ms.is_some();
+
+    let mut input = command.into_bytes();
+    if !no_newline {
+        // A pty in raw mode wants a carriage return, same as a
+        // terminal would send for the enter key.
+        input.push(b'\r');
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::EXEC, "running commands")?;
+    client
+        .write_connect_header(ConnectHeader::Exec(ExecRequest {
+            session: session.clone(),
+            input,
+            until,
+            timeout_ms,
+        }))
+        .context("writing exec request header")?;
+
+    let reply: ExecReply = client.read_reply().context("reading reply")?;
+    match reply {
+        ExecReply::Sent => {}
+        ExecReply::NotFound => {
+            eprintln!("not found: {session}");
+            return Err(anyhow!("not found: {}", session));
+        }
+        ExecReply::Locked => {
+            eprintln!("session '{session}' is locked");
+            return Err(anyhow!("session '{}' is locked", session));
+        }
+    }
+    if !wants_output {
+        return Ok(());
+    }
+
+    let status = client.read_output(&mut io::stdout().lock()).context("streaming output")?;
+    if status != 0 {
+        process::exit(status);
+    }
+
+    Ok(())
+}
@@ -12,17 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, path::Path};
+use std::{
+    io::{self, BufRead as _, IsTerminal as _, Write as _},
+    path::Path,
+};
 
 use anyhow::{anyhow, Context};
-use shpool_protocol::{ConnectHeader, KillReply, KillRequest};
+use nix::sys::signal::Signal;
+use shpool_protocol::{ConnectHeader, KillReply, KillRequest, SignalTarget};
 
 use crate::{common, protocol, protocol::ClientResult};
 
-pub fn run<P>(mut sessions: Vec<String>, socket: P) -> anyhow::Result<()>
+pub fn run<P>(
+    mut sessions: Vec<String>,
+    all: bool,
+    tags: Vec<String>,
+    yes: bool,
+    signal: Option<String>,
+    signal_target: SignalTarget,
+    socket: P,
+) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
+    let signal = match signal {
+        Some(name) => Some(parse_signal(&name)?),
+        None => None,
+    };
+    if !all && tags.is_empty() {
+        let current = sessions.is_empty();
+        common::resolve_sessions(&mut sessions, "kill")?;
+        // signals leave the session around, so there is nothing to confirm
+        if current && !yes && signal.is_none() && !confirm_kill_current(&sessions[0])? {
+            eprintln!("not killing {}", sessions[0]);
+            return Ok(());
+        }
+    }
+
     let mut client = match protocol::Client::new(socket) {
         Ok(ClientResult::JustClient(c)) => c,
         Ok(ClientResult::VersionMismatch { warning, client }) => {
@@ -38,18 +64,129 @@ where
         }
     };
 
-    common::resolve_sessions(&mut sessions, "kill")?;
+    let (patterns, sessions): (Vec<String>, Vec<String>) =
+        sessions.into_iter().partition(|s| is_pattern(s));
+    for pattern in patterns.iter() {
+        if let Err(e) = glob::Pattern::new(pattern) {
+            eprintln!("bad pattern '{pattern}': {e}");
+            return Err(anyhow!("bad pattern '{}': {}", pattern, e));
+        }
+    }
+    let bulk = all || !patterns.is_empty() || !tags.is_empty();
+    let named = sessions.clone();
 
     client
-        .write_connect_header(ConnectHeader::Kill(KillRequest { sessions }))
+        .write_connect_header(ConnectHeader::Kill(KillRequest {
+            sessions,
+            patterns,
+            all,
+            tags,
+            signal: signal.map(|s| String::from(s.as_str())),
+            signal_target,
+        }))
         .context("writing detach request header")?;
 
     let reply: KillReply = client.read_reply().context("reading reply")?;
+    if let Some(error) = reply.error {
+        eprintln!("{error}");
+        return Err(anyhow!("{}", error));
+    }
+
+    if bulk && !reply.killed.is_empty() {
+        println!("killed: {}", reply.killed.join(" "));
+    }
+    if bulk && !reply.signaled.is_empty() {
+        println!("signaled: {}", reply.signaled.join(" "));
+    }
+    if !reply.unmatched_patterns.is_empty() {
+        eprintln!("no sessions matched: {}", reply.unmatched_patterns.join(" "));
+    }
 
     if !reply.not_found_sessions.is_empty() {
         eprintln!("not found: {}", reply.not_found_sessions.join(" "));
         return Err(anyhow!("not found: {}", reply.not_found_sessions.join(" ")));
     }
+    // The daemon logs why, usually because the shell has exited.
+    let unsignaled: Vec<String> =
+        named.into_iter().filter(|s| signal.is_some() && !reply.signaled.contains(s)).collect();
+    if !unsignaled.is_empty() {
+        eprintln!("could not signal: {}", unsignaled.join(" "));
+        return Err(anyhow!("could not signal: {}", unsignaled.join(" ")));
+    }
+    if reply.killed.is_empty() && reply.signaled.is_empty() && !reply.unmatched_patterns.is_empty()
+    {
+        return Err(anyhow!("no sessions matched: {}", reply.unmatched_patterns.join(" ")));
+    }
 
     Ok(())
 }
+
+/// Killing the session we are running inside of takes the shell we
+/// were typed into down with it, so check that the user really meant
+/// to when they didn't name the session. There is nobody to ask if
+/// stdin is not a terminal, in which case we go ahead.
+fn confirm_kill_current(session: &str) -> anyhow::Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Ok(true);
+    }
+    eprint!("kill the current session ({session})? [y/N] ");
+    io::stderr().flush().context("flushing prompt")?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer).context("reading answer")?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Parse a signal given by name, with or without the SIG prefix, or by
+/// number.
+fn parse_signal(name: &str) -> anyhow::Result<Signal> {
+    if let Ok(n) = name.parse::<i32>() {
+        return Signal::try_from(n).map_err(|_| anyhow!("unknown signal {}", n));
+    }
+    let name = name.to_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{name}") };
+    name.parse().map_err(|_| anyhow!("unknown signal '{}'", name))
+}
+
+/// True if the given argument should be treated as a glob pattern
+/// rather than a session name.
+fn is_pattern(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(is_pattern("ci-*"));
+        assert!(is_pattern("sh?"));
+        assert!(is_pattern("sh[12]"));
+        assert!(!is_pattern("main"));
+        assert!(!is_pattern("ci-1234"));
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(parse_signal("HUP").unwrap(), Signal::SIGHUP);
+        assert_eq!(parse_signal("sigterm").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("usr1").unwrap(), Signal::SIGUSR1);
+        assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert!(parse_signal("NOPE").is_err());
+        assert!(parse_signal("0").is_err());
+    }
+
+    #[test]
+    fn answers() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes("YES\n"));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("n\n"));
+        assert!(!is_yes("yep\n"));
+    }
+}
@@ -17,7 +17,7 @@ use std::{
     env, fs,
     hash::{Hash, Hasher},
     io,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Mutex, MutexGuard},
 };
 
@@ -28,22 +28,64 @@ use tracing::error;
 use tracing_subscriber::{fmt::format::FmtSpan, prelude::*};
 
 mod attach;
+mod auto_name;
+mod capture;
+pub mod client;
 mod common;
+mod completion;
 pub mod config;
+mod config_check;
 mod consts;
+mod control;
 mod daemon;
 mod daemonize;
 mod detach;
+mod dump_state;
 mod duration;
+mod exec;
 mod hooks;
 mod kill;
 mod list;
+mod lock;
+mod logging;
+mod migrate;
+mod namespace;
+mod note;
+mod output;
+mod ping;
+mod pipe;
 mod protocol;
+mod rename;
+mod replay;
+mod resurrect;
 mod session_restore;
 mod set_log_level;
+mod shell_hook;
+mod size;
+mod ssh_attach;
+mod stats;
+mod status;
+mod stop;
+mod supervise;
+mod switch;
+mod tail;
+mod tcp;
+mod term_compat;
 mod test_hooks;
 mod tty;
+mod up;
 mod user;
+mod wait;
+mod watch;
+
+/// Internals for the benchmarks under benches/, not a stable API.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::{
+        protocol::ChunkExt,
+        session_restore::{new as new_spool, SessionSpool},
+    };
+}
 
 /// The command line arguments that shpool expects.
 /// These can be directly parsed with clap or manually
@@ -89,9 +131,50 @@ the daemon is launched by systemd."
     )]
     pub socket: Option<String>,
 
+    #[clap(
+        long,
+        action,
+        conflicts_with = "socket",
+        long_help = "Use a separate pool of sessions with its own daemon
+
+Each namespace gets its own socket, runtime directory and daemon log file,
+so sessions in one namespace are invisible to commands run against another.
+Defaults to $SHPOOL_NAMESPACE, which is set inside sessions to the namespace
+they belong to, or else the 'default' namespace."
+    )]
+    pub namespace: Option<String>,
+
     #[clap(short, long, action, help = "a toml file containing configuration")]
     pub config_file: Option<String>,
 
+    #[clap(
+        long,
+        action,
+        value_name = "HOST:PORT",
+        long_help = "Talk to a daemon listening for TCP connections rather than the local socket
+
+The daemon must have the tcp.listen config option set. The token it expects
+is read from the file given by --token-file, or else from $SHPOOL_TOKEN.
+If the daemon serves TLS, pass --tls-ca as well. Without it traffic is not
+encrypted, so this is meant to be used over an ssh port forward or similar.
+Implies --no-daemonize."
+    )]
+    pub connect: Option<String>,
+
+    #[clap(long, action, help = "a file holding the token to present with --connect")]
+    pub token_file: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        value_name = "FILE",
+        long_help = "Talk TLS to the daemon given with --connect
+
+The daemon's certificate must be signed by one of the PEM certificates in FILE,
+or be one of them, and must be for the host part of the --connect address."
+    )]
+    pub tls_ca: Option<String>,
+
     #[clap(short, long, action, help = "automatically launch a daemon if one is not running")]
     pub daemonize: bool,
 
@@ -120,13 +203,80 @@ pub enum Commands {
     Version,
 
     #[clap(about = "Starts running a daemon that holds a pool of shells")]
-    Daemon,
+    Daemon {
+        #[clap(
+            long,
+            long_help = "Take the sessions over from a daemon that is already running
+
+The running daemon hands over its socket and the ptys of all its sessions
+and then exits, leaving the shells running. Use this to upgrade shpool
+without losing your sessions. Any attached terminals get detached, so
+reattach (or use attach --auto-reconnect) once the takeover is done."
+        )]
+        takeover: bool,
+        #[clap(
+            long,
+            long_help = "Fork into the background and detach from the terminal
+
+For systems without a service manager to run the daemon under. Unless
+--log-file is given, logs go to daemonized-shpool.log next to the socket."
+        )]
+        daemonize: bool,
+        #[clap(
+            long,
+            requires = "daemonize",
+            long_help = "The pid file to write and lock once in the background
+
+Defaults to daemonized-shpool.pid next to the socket. Starting a second
+daemon with the same pid file fails while the first one is running."
+        )]
+        pid_file: Option<String>,
+        #[clap(subcommand)]
+        command: Option<DaemonCommands>,
+    },
 
     #[clap(about = "Creates or attaches to an existing shell session")]
     #[non_exhaustive]
     Attach {
         #[clap(short, long, help = "If a tty is already attached to the session, detach it first")]
         force: bool,
+        #[clap(
+            long,
+            conflicts_with = "force",
+            help = "If a tty is already attached to the session, give up even if force_attach is set"
+        )]
+        no_steal: bool,
+        #[clap(
+            short,
+            long,
+            conflicts_with = "force",
+            long_help = "If a tty is already attached to the session, attach alongside it
+
+All attached terminals see the same output and can type into the
+session. The session's pty is sized to fit the smallest terminal.
+Set allow_multiple_clients in the config to make this the default."
+        )]
+        mirror: bool,
+        #[clap(
+            long,
+            conflicts_with = "force",
+            long_help = "Watch the session without being able to type into it
+
+All keyboard input other than the detach keybinding gets dropped by
+the daemon. If a tty is already attached to the session, attach
+alongside it as with --mirror."
+        )]
+        read_only: bool,
+        #[clap(
+            long,
+            conflicts_with = "mirror",
+            long_help = "Keep the terminal and reconnect if the connection to the daemon drops
+
+Rather than exiting when the daemon goes away (for example because it got
+restarted), keep retrying with exponential backoff and pick the session back
+up once the daemon is reachable again. Hit ^C while reconnecting to give up."
+        )]
+        auto_reconnect: bool,
         #[clap(
             long,
             long_help = "Automatically kill the session after the given time
@@ -141,6 +291,16 @@ using a number with a trailing letter to indicate time unit
 (i.e. '3d', '19h', or '5s')."
         )]
         ttl: Option<String>,
+        #[clap(
+            long,
+            long_help = "Automatically kill the session once it has sat idle for the given time
+
+A session is idle when no terminal is attached to it and its shell has not
+produced any output. This overrides the auto_kill_after_idle config option,
+and like --ttl it only applies when first creating a session. The duration
+uses the same format as --ttl."
+        )]
+        idle_ttl: Option<String>,
         #[clap(
             short,
             long,
@@ -150,9 +310,23 @@ The command is broken up into a binary to invoke and a list of arguments to
 pass to the binary using the shell-words crate."
         )]
         cmd: Option<String>,
+        #[clap(
+            long,
+            value_enum,
+            long_help = "Start the command again whenever it exits
+
+With on-failure, the command gets restarted if it exits with a non-zero
+status or gets killed, and with always, whenever it exits. Restarts
+back off from 1s up to a minute if the command keeps dying right away.
+The session stays around in between, so you can stay attached. Hitting
+^C stops the command for good. Only applies to a command given with
+--cmd or the session's cmd config, when first creating a session."
+        )]
+        restart: Option<supervise::Policy>,
         #[clap(
             short = 'd',
             long = "dir",
+            visible_alias = "cwd",
             long_help = "The working directory to start the new shell session in
 
 This option only applies when first creating a session, it is ignored on
@@ -160,18 +334,100 @@ reattach. If not specified, the session will start in the current working
 directory of this command (or the directory specified in the config file)."
         )]
         dir: Option<String>,
+        #[clap(
+            long,
+            long_help = "The shell to run in the new session
+
+Overrides the shell setting in the config file (including the session's
+[sessions.NAME] table) and your login shell. Like --dir, this only applies
+when first creating a session."
+        )]
+        shell: Option<String>,
         #[clap(
             long = "restore",
-            help = "Override session restore cache size for this attachment",
+            help = "Override session restore behavior for this attachment",
             long_help = "Override the configured session_restore value for this specific attachment.
-Useful when you know the session contains TUI applications that benefit from different caching.
-Examples: '0' (no cache, SIGWINCH only), '1MB', '10MB'"
+
+When creating a session, this sets how much output the session caches.
+When reattaching, it sets how much of the cached output gets replayed,
+so '0' skips the replay entirely.
+Examples: '0' (no cache, SIGWINCH only), '1MB', '10MB', 'screen' (repaint the last screen),
+'lines:100' (keep just the last 100 lines),
+'prompts:3' (replay from the start of the third most recent OSC 133 prompt mark),
+'disk:10MB' (also checkpoint to disk to survive daemon restarts),
+'zstd:100MB' (keep the cache compressed in memory)"
         )]
         restore: Option<String>,
-        #[clap(help = "The name of the shell session to create or attach to")]
-        name: String,
+        #[clap(
+            long = "tag",
+            long_help = "Tag the session, may be given more than once
+
+Tags group sessions together so that list, detach and kill can operate
+on all the sessions with a given tag. Tags given when reattaching get
+added to the session's existing tags."
+        )]
+        tags: Vec<String>,
+        #[clap(
+            long,
+            value_name = "DIR",
+            long_help = "Log all of the session's output to files in the given directory
+
+Log files are named after the session and the time they were started, and
+get rotated by size as described for the output_log config option. This
+overrides output_log.dir in the config, and like --ttl it only applies when
+first creating a session."
+        )]
+        log_output: Option<String>,
+        #[clap(long, help = "don't show the message of the day for this attach")]
+        no_motd: bool,
+        #[clap(
+            long,
+            conflicts_with = "name",
+            long_help = "Attach to the session you most recently detached from
+
+Passing - as the session name does the same thing."
+        )]
+        last: bool,
+        #[clap(
+            long,
+            conflicts_with_all = ["name", "last"],
+            long_help = "Start a new session with a generated name
+
+This is what happens when no name is given. The name comes from the
+current directory, a counter, or a made up adjective-noun pair,
+depending on the auto_name config option, and gets printed."
+        )]
+        auto_name: bool,
+        #[clap(
+            help = "The name of the shell session to create or attach to, or - for the last one. \
+Generated if left out."
+        )]
+        name: Option<String>,
     },
 
+    #[clap(about = "Make sure a declared set of sessions is running
+
+Creates each session listed in the 'up' key of the config that is not
+already running, detached and set up according to its [sessions.NAME]
+table. Pass a project file to use its sessions instead. A project file
+uses the same format as the config, but only the up, start_directory
+and sessions keys, and if it has no 'up' key all of its sessions get
+created. Relative directories in it are relative to the file.")]
+    #[non_exhaustive]
+    Up {
+        #[clap(help = "a project file listing the sessions to bring up")]
+        file: Option<PathBuf>,
+    },
+
+    #[clap(about = "Recreate the sessions from before the daemon last went away
+
+The daemon keeps a record of its sessions under $XDG_STATE_HOME/shpool
+(or ~/.local/state/shpool), which survives a reboot. This creates each of
+those sessions again, detached, as a fresh shell in the directory the old
+shell was last in, with the same command, startup command and tags.")]
+    #[non_exhaustive]
+    Resurrect,
+
     #[clap(about = "Make the given session detach from shpool
 
 This does not close the shell. If no session name is provided
@@ -179,35 +435,560 @@ $SHPOOL_SESSION_NAME will be used if it is present in the
 environment.")]
     #[non_exhaustive]
     Detach {
+        #[clap(long = "tag", help = "detach every attached session with this tag")]
+        tags: Vec<String>,
         #[clap(help = "sessions to detach")]
         sessions: Vec<String>,
     },
 
+    #[clap(about = "Move the terminal attached to a session over to another session
+
+This works like the switch keybinding action. `shpool switch -` goes
+back to the session the terminal was on before, so running it again
+bounces back and forth between two sessions. If no --session is
+provided $SHPOOL_SESSION_NAME will be used if it is present in the
+environment.")]
+    #[non_exhaustive]
+    Switch {
+        #[clap(
+            short,
+            long,
+            conflicts_with = "target",
+            help = "switch back to the previous session, same as a target of -"
+        )]
+        previous: bool,
+        #[clap(short, long, help = "the session whose terminal to move")]
+        session: Option<String>,
+        #[clap(
+            required_unless_present = "previous",
+            help = "the session to switch to, or - for the previous one"
+        )]
+        target: Option<String>,
+    },
+
     #[clap(about = "Kill the given sessions
 
 This detaches the session if it is attached and kills the underlying
 shell with a SIGHUP followed by a SIGKILL if the shell fails to exit
 quickly enough. If no session name is provided $SHPOOL_SESSION_NAME
-will be used if it is present in the environment.")]
+will be used if it is present in the environment, after asking for
+confirmation if stdin is a terminal.
+
+Any argument containing one of the glob characters '*', '?' or '['
+is treated as a pattern, so `shpool kill 'ci-*'` kills every session
+whose name starts with 'ci-'.
+
+With --signal, the sessions are sent that signal instead and kept
+around, so `shpool kill --signal HUP --foreground server` asks
+whatever is running in the foreground of the 'server' session to
+reload its config. The signal goes to the shell unless --foreground
+or --tree say otherwise.")]
     #[non_exhaustive]
     Kill {
-        #[clap(help = "sessions to kill")]
+        #[clap(long, conflicts_with = "sessions", help = "kill every session")]
+        all: bool,
+        #[clap(long = "tag", help = "kill every session with this tag")]
+        tags: Vec<String>,
+        #[clap(short, long, help = "don't ask before killing the current session")]
+        yes: bool,
+        #[clap(
+            short,
+            long,
+            value_name = "SIGNAL",
+            help = "send this signal, like TERM, HUP or USR1, rather than killing the session"
+        )]
+        signal: Option<String>,
+        #[clap(
+            long,
+            requires = "signal",
+            help = "send the signal to the foreground process group of the session's terminal"
+        )]
+        foreground: bool,
+        #[clap(
+            long,
+            requires = "signal",
+            conflicts_with = "foreground",
+            help = "send the signal to the shell and every process started from it"
+        )]
+        tree: bool,
+        #[clap(help = "sessions or glob patterns to kill")]
         sessions: Vec<String>,
     },
 
+    #[clap(about = "Dump a session's output without attaching to it
+
+By default this writes out the session's restore buffer, which is what
+you would see on reattach, so what it contains depends on the
+session_restore_mode setting. Pass --scrollback to get the plain text
+scrollback that scroll mode shows instead.")]
+    #[non_exhaustive]
+    Capture {
+        #[clap(short, long, help = "write to this file rather than stdout")]
+        output: Option<PathBuf>,
+        #[clap(long, help = "capture the plain text scrollback rather than the restore buffer")]
+        scrollback: bool,
+        #[clap(long, help = "remove terminal escape codes from the output")]
+        strip_ansi: bool,
+        #[clap(help = "the session to capture")]
+        session: String,
+    },
+
+    #[clap(about = "Print a session's output without attaching to it
+
+Prints the session's restore buffer, the same thing a reattaching
+terminal would get. With -f, keeps going with the session's live output
+until its shell exits, and then exits with the shell's exit status.
+Nothing about the calling terminal gets changed and no input is taken,
+so tail is safe to run from scripts or alongside an attached client.")]
+    #[non_exhaustive]
+    Tail {
+        #[clap(short, long, help = "keep printing output as the session produces it")]
+        follow: bool,
+        #[clap(long, help = "remove terminal escape codes from the output")]
+        strip_ansi: bool,
+        #[clap(help = "the session to print the output of")]
+        session: String,
+    },
+
+    #[clap(about = "Type a command into a session without attaching to it
+
+The command is sent to the session's shell followed by a newline, just
+as if it had been typed into an attached terminal. By default exec
+returns as soon as the input has been sent. Pass --until to stream the
+session's output back until a marker string shows up in it, or --timeout
+to stream it back for a fixed amount of time. With --until, exec exits
+with status 1 if the marker did not show up in time.")]
+    #[non_exhaustive]
+    Exec {
+        #[clap(
+            long,
+            help = "stream output until this string shows up in it (escape codes are ignored)"
+        )]
+        until: Option<String>,
+        #[clap(
+            long,
+            help = "how long to stream output for, for example '30s' or '2m' (default 10s with --until)"
+        )]
+        timeout: Option<String>,
+        #[clap(long, help = "don't send a newline after the command")]
+        no_newline: bool,
+        #[clap(help = "the session to run the command in")]
+        session: String,
+        #[clap(help = "the command to type into the session")]
+        command: String,
+    },
+
+    #[clap(about = "Stream stdin into a session without attaching to it
+
+Everything read from stdin gets written to the session's terminal as if
+it had been typed, so `generate-commands | shpool pipe worker` runs each
+command in the worker session. The input goes over as is unless
+--newline is passed, which ends each line with the carriage return the
+enter key sends. --eof sends a marker once stdin runs out, by default
+the ^D that tells a program reading from the terminal that its input is
+over.")]
+    #[non_exhaustive]
+    Pipe {
+        #[clap(long, help = "end each line of input with a carriage return, like the enter key")]
+        newline: bool,
+        #[clap(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "\x04",
+            value_name = "MARKER",
+            help = "send this once stdin runs out (^D if no marker is given)"
+        )]
+        eof: Option<String>,
+        #[clap(help = "the session to send the input to")]
+        session: String,
+    },
+
+    #[clap(about = "Block until a session's shell exits
+
+Exits with the same status as the shell, so scripts can tell whether a
+job running in a session succeeded. With --activity, wait until the
+session produces output while no terminal is attached instead,
+returning right away if it already has since a terminal was last
+attached, and exit with status 1 if the shell exits first.")]
+    #[non_exhaustive]
+    Wait {
+        #[clap(
+            long,
+            help = "wait for output from the detached session rather than for it to exit"
+        )]
+        activity: bool,
+        #[clap(help = "the session to wait on")]
+        session: String,
+    },
+
+    #[clap(about = "Put an ssh login into a shpool session
+
+Meant to be run by sshd rather than by hand, as the RemoteCommand in
+your ssh config or as a ForceCommand on the server. With no name, the
+session is named after the machine you are connecting from, with a
+number tacked on if a terminal is already attached to that session.
+Logins without a tty, and ones that asked to run a command when this
+is a ForceCommand, get a plain shell instead.")]
+    #[non_exhaustive]
+    SshAttach {
+        #[clap(short, long, help = "If a tty is already attached to the session, detach it first")]
+        force: bool,
+        #[clap(help = "the session to attach to")]
+        name: Option<String>,
+    },
+
+    #[clap(about = "Drive the daemon over line-delimited JSON
+
+Meant for editor and IDE integrations rather than people. Each line
+written to stdin is a JSON request like
+{\"id\": 1, \"cmd\": \"send-input\", \"session\": \"main\", \"data\": \"ls\\n\"}
+and each line written to stdout is a JSON event like
+{\"event\": \"created\", \"session\": \"main\"}. The README lists
+them all. Exits once stdin is closed.")]
+    #[non_exhaustive]
+    Control,
+
     #[clap(about = "lists all the running shell sessions")]
     #[non_exhaustive]
-    List,
+    List {
+        #[clap(
+            long,
+            value_enum,
+            default_value_t,
+            long_help = "The output format
+
+'table' is meant for humans, 'json' emits an array of session objects
+and 'tsv' emits one tab separated line per session with no header, with
+the columns name, started_at, status, rows, cols, pid and tags (comma
+separated) unless --columns says otherwise."
+        )]
+        format: list::Format,
+        #[clap(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            help = "comma separated columns to show in the table or tsv output"
+        )]
+        columns: Vec<list::Column>,
+        #[clap(long, value_enum, help = "how to order the sessions")]
+        sort: Option<list::Sort>,
+        #[clap(long = "tag", help = "only list sessions with this tag")]
+        tags: Vec<String>,
+        #[clap(long, help = "list the sessions of every namespace with a running daemon")]
+        all_namespaces: bool,
+        #[clap(
+            short,
+            long,
+            long_help = "Also show what the processes in each session are using
+
+Adds the PROCS, CPU and RSS columns: how many processes are running in
+the session, the CPU time they have used and their combined resident
+memory. Getting these takes the daemon a walk through /proc, so they
+are only looked up when asked for. Only supported on Linux.
+
+Also adds a CWD column with the shell's current working directory,
+which comes from /proc or, elsewhere, from the shell reporting it with
+OSC 7 like many shells do for the terminal's benefit."
+        )]
+        verbose: bool,
+        #[clap(
+            short,
+            long,
+            conflicts_with = "all_namespaces",
+            long_help = "Keep the list on screen and update it as sessions change
+
+The list gets redrawn whenever the daemon reports a session being
+created, attached to, detached from, resized or exiting, and sessions
+that just printed something are highlighted for a few seconds. Only
+works with the table format. Exit with ^C."
+        )]
+        watch: bool,
+    },
+
+    #[clap(about = "Rename a session
+
+The session keeps running, and any attached terminals stay attached.
+Commands like `shpool detach` run from inside the session will pick
+up the new name, but the session's shell keeps the old name in its
+$SHPOOL_SESSION_NAME environment variable and prompt prefix.")]
+    #[non_exhaustive]
+    Rename {
+        #[clap(help = "the current name of the session")]
+        from: String,
+        #[clap(help = "the new name for the session")]
+        to: String,
+    },
+
+    #[clap(about = "Describe what a session is for
+
+The note shows up in `shpool list -v` and the json list format. Run
+with just a session name to print its note.")]
+    #[non_exhaustive]
+    Note {
+        #[clap(long, conflicts_with = "note", help = "remove the session's note")]
+        clear: bool,
+        #[clap(help = "the session to describe")]
+        session: String,
+        #[clap(help = "what the session is for, like \"bisecting the flaky test\"")]
+        note: Option<String>,
+    },
+
+    #[clap(about = "Watch a detached session's output for a pattern
+
+While nobody is attached, each line the session prints gets checked
+against the pattern. The first match after a detach runs the --exec
+command, or the on_watch hook if there isn't one, and matches show up
+in `shpool list`. Run with just a session name to print what the
+session is being watched for.")]
+    #[non_exhaustive]
+    Watch {
+        #[clap(long, help = "a regular expression to look for, like 'ERROR|panic'")]
+        pattern: Option<String>,
+        #[clap(
+            long,
+            requires = "pattern",
+            help = "a shell command to run on a match, which gets the line in $SHPOOL_WATCH_LINE"
+        )]
+        exec: Option<String>,
+        #[clap(long, help = "stop watching for the patterns added with shpool watch")]
+        clear: bool,
+        #[clap(help = "the session to watch")]
+        session: String,
+    },
+
+    #[clap(about = "Move a session over to another daemon without killing its shell
+
+The daemon detaches the session if it is attached and hands the
+session's pty and restore buffer over to the daemon listening on the
+given socket, for example to move sessions from a daemon started by
+hand over to the one systemd manages. The shell stays a child of the
+daemon it started under, so the other daemon can't tell what its exit
+status was once it exits.")]
+    #[non_exhaustive]
+    Migrate {
+        #[clap(long, help = "the socket of the daemon to move the session to")]
+        to_socket: PathBuf,
+        #[clap(help = "the session to move")]
+        session: String,
+    },
+
+    #[clap(about = "Lock a session so that attaching to it takes a passphrase
+
+The first time a session gets locked you are asked for the passphrase
+to give it, after that it just locks with the same one. Attaching with
+the passphrase unlocks the session until it gets locked again, either
+by `shpool lock` or by the auto_lock_after_idle config option. If no
+session name is provided $SHPOOL_SESSION_NAME will be used if it is
+present in the environment.")]
+    #[non_exhaustive]
+    Lock {
+        #[clap(long, help = "forget the session's passphrase, which needs the passphrase")]
+        remove: bool,
+        #[clap(help = "the session to lock")]
+        session: Option<String>,
+    },
 
     #[clap(about = "Dynamically change daemon log level
 
 This command changes the log level of the shpool daemon without
 restarting. It may to useful if the daemon gets into a state that
-needs debugging, but would be clobbered by a restart.")]
+needs debugging, but would be clobbered by a restart.
+
+The filter is either a plain level (off, error, warn, info, debug
+or trace) or a comma separated list of target=level directives
+with an optional default level, for example
+`libshpool::daemon::shell=trace,info` to get trace logs from just
+the shell module.")]
     #[non_exhaustive]
     SetLogLevel {
-        #[clap(help = "new log level")]
-        level: shpool_protocol::LogLevel,
+        #[clap(help = "new log level or filter")]
+        filter: String,
+    },
+
+    #[clap(about = "Show information about the running daemon
+
+With --memory, include a breakdown of where the daemon's memory is
+going. Per-subsystem numbers are estimates. Exact allocation totals
+and heap profiles require shpool to be built with the jemalloc
+feature (and heap profiles additionally require the daemon to be
+started with MALLOC_CONF=prof:true). Heap profiles get written to
+the daemon's runtime directory.")]
+    #[non_exhaustive]
+    Status {
+        #[clap(long, help = "report memory usage")]
+        memory: bool,
+        #[clap(long, help = "ask the daemon to dump a heap profile")]
+        heap_profile: bool,
+    },
+
+    #[clap(about = "Check that the daemon is alive and how quickly it answers
+
+Sends the daemon a timestamped ping and reports how long the round trip
+took, along with the daemon's version and uptime. Given a session, the
+ping also goes through the thread that pumps the session's output, and
+with --echo the daemon types a space and a backspace into the session
+and times how long the shell takes to echo them, which is only harmless
+at a shell prompt. Exits with an error if anything fails to answer
+within the timeout, so it works as a liveness probe.")]
+    #[non_exhaustive]
+    Ping {
+        #[clap(long, help = "time how long the session's shell takes to echo input")]
+        echo: bool,
+        #[clap(long, default_value = "10s", help = "how long to wait for an answer")]
+        timeout: String,
+        #[clap(help = "a session to route the ping through")]
+        session: Option<String>,
+    },
+
+    #[clap(about = "Show per-session resource usage
+
+Reports how much CPU time the daemon has spent moving data between
+each session's shell and its clients, busiest sessions first, along
+with how much data has gone in and out of each session, how big its
+restore spool is and how many times it has been attached to. Daemon
+threads are named after the session they serve, so `top -H` can also
+be used to watch this live.")]
+    #[non_exhaustive]
+    Stats,
+
+    #[clap(about = "Show resource usage for a single session
+
+Reports the same numbers as `shpool stats`, but for just the given
+session and with exact byte counts.")]
+    #[non_exhaustive]
+    Stat {
+        #[clap(help = "the session to report on")]
+        session: String,
+    },
+
+    #[clap(about = "Play back a terminal recording
+
+Plays an asciicast v2 recording, like the ones asciinema makes, in
+the current terminal without needing the daemon or asciinema. While
+it plays, space pauses and resumes, the left and right arrow keys
+seek back and forward 5 seconds, ] jumps to the next marker and q
+quits.")]
+    #[non_exhaustive]
+    Replay {
+        #[clap(long, default_value = "1", help = "how many times faster than real time to play")]
+        speed: f64,
+        #[clap(long, help = "pause whenever playback reaches a marker")]
+        pause_on_marker: bool,
+        #[clap(help = "the recording to play")]
+        file: PathBuf,
+    },
+
+    #[clap(about = "Print a completion script for the given shell
+
+The script completes subcommands and flags, and asks the daemon for
+the names of the running sessions when completing a session argument.
+For example, add `source <(shpool completion bash)` to your .bashrc,
+or write the output of `shpool completion fish` to
+~/.config/fish/completions/shpool.fish.")]
+    #[non_exhaustive]
+    Completion {
+        #[clap(value_enum, help = "the shell to generate completions for")]
+        shell: completion::Shell,
+    },
+
+    #[clap(about = "Print shell integration for the given shell
+
+The snippet installs a prompt hook which, inside a shpool session,
+picks up the environment of the latest `shpool attach` whenever you
+reattach, so variables like DISPLAY, TERM and SSH_AUTH_SOCK stay
+current in the running shell and not just in new ones. For example,
+add `eval \"$(shpool shell-hook bash)\"` to your .bashrc or
+`shpool shell-hook fish | source` to your config.fish. Nushell can't
+source a command's output, so save the output of `shpool shell-hook nu`
+to a file and `source` that from your config.nu. For xonsh, add
+`execx($(shpool shell-hook xonsh))` to your .xonshrc.")]
+    #[non_exhaustive]
+    ShellHook {
+        #[clap(value_enum, help = "the shell to print the integration for")]
+        shell: shell_hook::Shell,
+    },
+
+    #[clap(about = "Inspect the config")]
+    #[non_exhaustive]
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommands,
+    },
+
+    #[clap(name = "complete-sessions", hide = true)]
+    #[non_exhaustive]
+    CompleteSessions,
+
+    #[clap(name = "supervise", hide = true)]
+    #[non_exhaustive]
+    Supervise {
+        #[clap(long, value_enum)]
+        restart: supervise::Policy,
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        cmd: Vec<String>,
+    },
+}
+
+/// The subcommands of `shpool daemon`.
+#[derive(Subcommand, Debug)]
+#[non_exhaustive]
+pub enum DaemonCommands {
+    #[clap(about = "Shut down the running daemon along with its sessions
+
+Every session's shell gets hung up on, the same as when its terminal
+goes away, and has until the grace period is up to exit before it gets
+killed. Then the daemon exits, cleaning up its socket. The sessions get
+recorded for `shpool resurrect` first, and disk backed restore buffers
+(session_restore = \"disk:...\") are kept so they can be replayed when
+sessions with the same names get created again.")]
+    #[non_exhaustive]
+    Stop {
+        #[clap(
+            long,
+            default_value = "10s",
+            help = "how long to give shells to exit before killing them, like 30s"
+        )]
+        grace: String,
+    },
+
+    #[clap(about = "Print a snapshot of the daemon's internal state as JSON
+
+The snapshot covers the sessions, their locks and control channels,
+spool and output queue sizes, and the daemon's threads, which helps
+with working out why something like an attach is stuck. The daemon
+never waits on a lock to take it, so anything behind a held lock shows
+up as null. If the daemon does not answer, send it a SIGUSR1 instead,
+which writes the snapshot to its log and to daemon-state.json in the
+runtime dir. The layout may change between versions.")]
+    #[non_exhaustive]
+    DumpState,
+}
+
+/// The subcommands of `shpool config`.
+#[derive(Subcommand, Debug)]
+#[non_exhaustive]
+pub enum ConfigCommands {
+    #[clap(about = "Check the config files for mistakes
+
+Reports syntax errors, unknown keys (usually typos, which would
+otherwise just be ignored) and values that won't parse, along with
+the line they are on. Exits non-zero if there were any problems.")]
+    #[non_exhaustive]
+    Check {
+        #[clap(long, help = "print the merged config, with defaults filled in")]
+        print_effective: bool,
+    },
+
+    #[clap(about = "Print the effective config
+
+The config is merged from /etc/shpool/config.toml, then the user's
+config file, then SHPOOL_<KEY> environment variables, later layers
+overriding earlier ones key by key. Defaults are filled in for
+anything left unset.")]
+    #[non_exhaustive]
+    Show {
+        #[clap(long, help = "note where each value came from")]
+        origin: bool,
     },
 }
 
@@ -256,20 +1037,26 @@ where
 }
 
 struct LogWriterBuilder {
-    log_file: Option<Mutex<fs::File>>,
+    log_file: Option<Mutex<logging::LogFile>>,
     is_daemon: bool,
+    redactor: logging::Redactor,
 }
 
 impl<'writer> tracing_subscriber::fmt::MakeWriter<'writer> for LogWriterBuilder {
     type Writer = Box<dyn io::Write + 'writer>;
 
     fn make_writer(&'writer self) -> Self::Writer {
-        if let Some(log_file) = &self.log_file {
+        let writer: Self::Writer = if let Some(log_file) = &self.log_file {
             Box::new(MutexGuardWriter(log_file.lock().expect("poisoned")))
         } else if self.is_daemon {
             Box::new(io::stderr())
         } else {
             Box::new(io::empty())
+        };
+        if self.redactor.is_empty() {
+            writer
+        } else {
+            Box::new(logging::Redacting { inner: writer, redactor: &self.redactor })
         }
     }
 }
@@ -278,12 +1065,25 @@ impl<'writer> tracing_subscriber::fmt::MakeWriter<'writer> for LogWriterBuilder
 /// Run the shpool tool with the given arguments. If hooks is provided,
 /// inject the callbacks into the daemon.
 pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> anyhow::Result<()> {
+    run_with_config(args, None, hooks)
+}
+
+/// Like `run`, but if `config_manager` is provided it is used as-is rather
+/// than loading the config files again. This lets wrapper binaries that
+/// already needed the config (for example to expand aliases) avoid paying
+/// for a second load at startup. The manager must have been built from
+/// `args.config_file`.
+pub fn run_with_config(
+    args: Args,
+    config_manager: Option<config::Manager>,
+    hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>,
+) -> anyhow::Result<()> {
     match (&args.command, env::var(consts::SENTINEL_FLAG_VAR).as_deref()) {
-        (Commands::Daemon, Ok("prompt")) => {
+        (Commands::Daemon { .. }, Ok("prompt")) => {
             println!("{}", consts::PROMPT_SENTINEL);
             std::process::exit(0);
         }
-        (Commands::Daemon, Ok("startup")) => {
+        (Commands::Daemon { .. }, Ok("startup")) => {
             println!("{}", consts::STARTUP_SENTINEL);
             std::process::exit(0);
         }
@@ -297,40 +1097,70 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
     } else {
         tracing_subscriber::filter::LevelFilter::TRACE
     };
-    let (log_level_layer, log_level_handle) =
-        tracing_subscriber::reload::Layer::new(log_level_filter);
+    let (log_level_layer, log_level_handle) = tracing_subscriber::reload::Layer::new(
+        tracing_subscriber::filter::Targets::new().with_default(log_level_filter),
+    );
 
+    let log_config = match &config_manager {
+        Some(config_manager) => config_manager.get().log.clone().unwrap_or_default(),
+        None => config::Manager::log_config(args.config_file.as_deref()),
+    };
     let log_writer_builder = LogWriterBuilder {
         log_file: if let Some(lf) = &args.log_file {
-            Some(Mutex::new(fs::File::create(lf).context("unable to create log file")?))
+            let rotation = logging::Rotation::from_config(&log_config)?;
+            Some(Mutex::new(
+                logging::LogFile::open(Path::new(lf), rotation)
+                    .context("unable to create log file")?,
+            ))
         } else {
             None
         },
-        is_daemon: matches!(args.command, Commands::Daemon),
+        is_daemon: matches!(args.command, Commands::Daemon { command: None, .. }),
+        redactor: logging::Redactor::from_config(&log_config)?,
     };
-    tracing_subscriber::registry::Registry::default()
-        .with(log_level_layer)
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_ansi(false)
-                .with_thread_ids(true)
-                .with_target(false)
-                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-                .with_writer(log_writer_builder),
-        )
-        .init();
-
-    let mut runtime_dir = match env::var("XDG_RUNTIME_DIR") {
-        Ok(runtime_dir) => PathBuf::from(runtime_dir),
-        Err(_) => PathBuf::from(env::var("HOME").context("no XDG_RUNTIME_DIR or HOME")?)
-            .join(".local")
-            .join("run"),
+    logging::set_payloads(log_config.payloads.unwrap_or(false));
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_thread_ids(true)
+        .with_target(false)
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE);
+    // The two formats make for different layer types, hence the boxing.
+    let fmt_layer = match log_config.format.unwrap_or_default() {
+        config::LogFormat::Text => fmt_layer.with_writer(log_writer_builder).boxed(),
+        config::LogFormat::Json => fmt_layer
+            .fmt_fields(logging::JsonFields)
+            .event_format(logging::Json)
+            .with_writer(log_writer_builder)
+            .boxed(),
+    };
+    tracing_subscriber::registry::Registry::default().with(log_level_layer).with(fmt_layer).init();
+
+    // The supervisor runs inside a session, where it has no business
+    // loading the config or starting a daemon.
+    if let Commands::Supervise { restart, cmd } = args.command {
+        return supervise::run(restart, cmd);
+    }
+
+    // Checking the config has to work even if it would fail to load.
+    if let Commands::Config { command: ConfigCommands::Check { print_effective } } = args.command {
+        return config_check::run(args.config_file.as_deref(), print_effective);
     }
-    .join("shpool");
+    if let Commands::Config { command: ConfigCommands::Show { origin } } = args.command {
+        return config_check::show(args.config_file.as_deref(), origin);
+    }
+
+    let mut runtime_dir = namespace::base_runtime_dir()?;
     fs::create_dir_all(&runtime_dir).context("ensuring runtime dir exists")?;
+    let base_runtime_dir = runtime_dir.clone();
 
-    let socket = match &args.socket {
-        Some(s) => {
+    let namespace = match &args.namespace {
+        Some(ns) => Some(ns.clone()),
+        None if args.socket.is_none() => namespace::from_env(),
+        None => None,
+    };
+
+    let socket = match (&args.socket, &namespace) {
+        (Some(s), _) => {
             // The user can reasonably expect that if they provide seperate
             // sockets for differnt shpool instances to run on, they won't
             // stomp on one another. To respect this expectation we need to
@@ -343,15 +1173,74 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
 
             PathBuf::from(s)
         }
-        None => runtime_dir.join("shpool.socket"),
+        (None, Some(ns)) => {
+            namespace::validate(ns)?;
+            runtime_dir = namespace::runtime_dir(&runtime_dir, ns);
+            fs::create_dir_all(&runtime_dir).context("ensuring namespace runtime dir exists")?;
+            namespace::socket(&base_runtime_dir, ns)
+        }
+        (None, None) => runtime_dir.join("shpool.socket"),
     };
+    // Persistent state is kept apart the same way runtime data is.
+    let state_dir = resurrect::base_state_dir()?
+        .join(runtime_dir.strip_prefix(&base_runtime_dir).unwrap_or(Path::new("")));
 
-    let config_manager = config::Manager::new(args.config_file.as_deref())?;
+    let config_manager = match config_manager {
+        Some(config_manager) => config_manager,
+        None => config::Manager::new(args.config_file.as_deref())?,
+    };
 
-    if !config_manager.get().nodaemonize.unwrap_or(false) || args.daemonize {
+    // When talking to a remote daemon, every subcommand just dials a
+    // local relay socket instead. The relay has to outlive the command.
+    let relay = match &args.connect {
+        Some(addr) => {
+            let token = match &args.token_file {
+                Some(path) => tcp::read_token(Path::new(path))?,
+                None => env::var(tcp::TOKEN_VAR)
+                    .context("no --token-file given and $SHPOOL_TOKEN is not set")?,
+            };
+            let tls = match &args.tls_ca {
+                Some(ca_file) => Some(tcp::ClientTls::new(Path::new(ca_file), addr)?),
+                None => None,
+            };
+            Some(tcp::Relay::spawn(addr.clone(), token, tls)?)
+        }
+        None => None,
+    };
+    let socket = match &relay {
+        Some(relay) => relay.socket(),
+        None => socket,
+    };
+
+    let autostart = if args.daemonize {
+        Some(config::Autostart::Fork)
+    } else {
+        config_manager.get().autostart()
+    };
+    if let Some(autostart) = autostart {
         let arg0 = env::args().next().ok_or(anyhow!("arg0 missing"))?;
-        if !args.no_daemonize && !matches!(args.command, Commands::Daemon) {
-            daemonize::maybe_fork_daemon(&config_manager, &args, arg0, &socket)?;
+        if !args.no_daemonize
+            && relay.is_none()
+            && !matches!(
+                args.command,
+                Commands::Daemon { .. }
+                    | Commands::Completion { .. }
+                    | Commands::CompleteSessions
+                    // a ping should find out that the daemon is down,
+                    // not bring it up
+                    | Commands::Ping { .. }
+                    | Commands::ShellHook { .. }
+                    | Commands::Replay { .. }
+            )
+        {
+            daemonize::maybe_start_daemon(
+                &config_manager,
+                &args,
+                autostart,
+                namespace.as_deref(),
+                arg0,
+                &socket,
+            )?;
         }
     }
 
@@ -368,22 +1257,151 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
 
     let res: anyhow::Result<()> = match args.command {
         Commands::Version => return Err(anyhow!("wrapper binary must handle version")),
-        Commands::Daemon => daemon::run(
+        Commands::Daemon { command: Some(DaemonCommands::Stop { grace }), .. } => {
+            stop::run(grace, socket)
+        }
+        Commands::Daemon { command: Some(DaemonCommands::DumpState), .. } => {
+            dump_state::run(socket)
+        }
+        Commands::Daemon { takeover, daemonize, pid_file, command: None } => daemon::run(
             config_manager,
             runtime_dir,
+            state_dir,
             hooks.unwrap_or(Box::new(NoopHooks {})),
             log_level_handle,
             socket,
+            namespace,
+            takeover,
+            daemonize.then(|| daemon::Background {
+                pid_file: pid_file.map(PathBuf::from),
+                redirect_logs: args.log_file.is_none(),
+            }),
         ),
-        Commands::Attach { force, ttl, cmd, dir, restore, name } => {
-            attach::run(config_manager, attach::AttachOptions {
-                name, force, ttl, cmd, dir, restore
-            }, socket)
+        Commands::Attach {
+            force,
+            no_steal,
+            mirror,
+            read_only,
+            auto_reconnect,
+            ttl,
+            idle_ttl,
+            cmd,
+            restart,
+            dir,
+            shell,
+            restore,
+            tags,
+            log_output,
+            no_motd,
+            last,
+            auto_name,
+            name,
+        } => attach::run(
+            config_manager,
+            attach::AttachOptions {
+                auto_name: auto_name || (!last && name.is_none()),
+                name: if last {
+                    String::from(attach::LAST_SESSION)
+                } else {
+                    name.unwrap_or_default()
+                },
+                force,
+                no_steal,
+                mirror,
+                read_only,
+                auto_reconnect,
+                ttl,
+                idle_ttl,
+                cmd,
+                restart,
+                dir,
+                shell,
+                restore,
+                tags,
+                log_output,
+                no_motd,
+                previous: None,
+                passphrase: None,
+            },
+            socket,
+        ),
+        Commands::Up { file } => up::run(config_manager, file, socket),
+        Commands::Resurrect => resurrect::run(config_manager, state_dir, socket),
+        Commands::Detach { tags, sessions } => detach::run(sessions, tags, socket),
+        Commands::Switch { previous, session, target } => {
+            switch::run(target, previous, session, socket)
+        }
+        Commands::Capture { output, scrollback, strip_ansi, session } => {
+            capture::run(session, output, scrollback, strip_ansi, socket)
+        }
+        Commands::Exec { until, timeout, no_newline, session, command } => {
+            exec::run(session, command, until, timeout, no_newline, socket)
+        }
+        Commands::Pipe { newline, eof, session } => pipe::run(session, newline, eof, socket),
+        Commands::Tail { follow, strip_ansi, session } => {
+            tail::run(session, follow, strip_ansi, socket)
+        }
+        Commands::Control => control::run(config_manager, socket),
+        Commands::SshAttach { force, name } => ssh_attach::run(config_manager, name, force, socket),
+        Commands::Wait { activity, session } => {
+            let until = if activity {
+                shpool_protocol::WaitFor::Activity
+            } else {
+                shpool_protocol::WaitFor::Exit
+            };
+            wait::run(session, until, socket)
+        }
+        Commands::Kill { all, tags, yes, signal, foreground, tree, sessions } => {
+            let target = match (foreground, tree) {
+                (true, _) => shpool_protocol::SignalTarget::Foreground,
+                (_, true) => shpool_protocol::SignalTarget::Tree,
+                _ => shpool_protocol::SignalTarget::Shell,
+            };
+            kill::run(sessions, all, tags, yes, signal, target, socket)
+        }
+        Commands::List { format, columns, sort, tags, all_namespaces, verbose, watch } => {
+            let config = config_manager.get();
+            let columns = match &config.output {
+                Some(config::Output { list_columns: Some(names), .. })
+                    if columns.is_empty() && format == list::Format::Table =>
+                {
+                    list::parse_columns(names).context("parsing output.list_columns")?
+                }
+                _ => columns,
+            };
+            let style = output::Style::from_config(&config);
+            let layout = list::Layout { format, columns, sort, verbose, style };
+            if watch {
+                list::watch(layout, tags, socket)
+            } else if all_namespaces {
+                namespace::all(&base_runtime_dir)
+                    .and_then(|sockets| list::run_all(layout, tags, sockets))
+            } else {
+                list::run(layout, tags, socket)
+            }
+        }
+        Commands::Rename { from, to } => rename::run(from, to, socket),
+        Commands::Note { clear, session, note } => note::run(session, note, clear, socket),
+        Commands::Watch { pattern, exec, clear, session } => {
+            watch::run(session, pattern, exec, clear, socket)
+        }
+        Commands::Migrate { to_socket, session } => migrate::run(session, to_socket, socket),
+        Commands::Lock { remove, session } => lock::run(session, remove, socket),
+        Commands::SetLogLevel { filter } => set_log_level::run(filter, socket),
+        Commands::Status { memory, heap_profile } => status::run(memory, heap_profile, socket),
+        Commands::Ping { echo, timeout, session } => ping::run(session, echo, timeout, socket),
+        Commands::Stats => stats::run(socket),
+        Commands::Stat { session } => {
+            stats::run_session(session, output::Style::from_config(&config_manager.get()), socket)
+        }
+        Commands::Replay { speed, pause_on_marker, file } => {
+            replay::run(&file, speed, pause_on_marker)
         }
-        Commands::Detach { sessions } => detach::run(sessions, socket),
-        Commands::Kill { sessions } => kill::run(sessions, socket),
-        Commands::List => list::run(socket),
-        Commands::SetLogLevel { level } => set_log_level::run(level, socket),
+        Commands::Completion { shell } => completion::run(shell),
+        Commands::ShellHook { shell } => shell_hook::run(shell),
+        Commands::CompleteSessions => completion::list_sessions(socket),
+        Commands::Config { .. } => unreachable!("config commands run before the config loads"),
+        Commands::Supervise { .. } => unreachable!("supervise runs before the config loads"),
     };
 
     if let Err(err) = res {
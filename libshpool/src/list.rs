@@ -12,14 +12,472 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, path::PathBuf, time};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write as _},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    thread, time,
+};
 
-use anyhow::Context;
-use shpool_protocol::{ConnectHeader, ListReply};
+use anyhow::{anyhow, Context};
+use serde_derive::Serialize;
+use shpool_protocol::{
+    ConnectHeader, ControlEvent, DetachReason, ListReply, ProcUsage, Session, SessionStatus,
+};
 
-use crate::{protocol, protocol::ClientResult};
+use crate::{
+    client, output,
+    output::{Role, Style},
+    protocol,
+    protocol::ClientResult,
+    stats,
+    status::format_bytes,
+};
 
-pub fn run(socket: PathBuf) -> anyhow::Result<()> {
+/// How long a session stays highlighted in `list --watch` after it
+/// last printed something.
+const WATCH_HIGHLIGHT: time::Duration = time::Duration::from_secs(3);
+/// The least time between two redraws in `list --watch`, so a session
+/// spewing output doesn't have us redrawing constantly.
+const WATCH_REDRAW_INTERVAL: time::Duration = time::Duration::from_millis(250);
+
+/// A session to list, along with the namespace it came from and what
+/// its processes are using, if we asked.
+type Entry<'a> = (Option<&'a str>, &'a Session, Option<&'a ProcUsage>);
+
+/// How to print the session list.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Table,
+    Json,
+    Tsv,
+}
+
+/// A column for the table and tsv formats.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Name,
+    StartedAt,
+    Status,
+    /// The tty of the attached client.
+    ClientTty,
+    /// Whether the session has produced output or rung the bell since
+    /// a client was last attached.
+    Activity,
+    /// The size of the session's pty, as ROWSxCOLS.
+    Size,
+    LastActive,
+    /// The memory used by the restore spool.
+    Memory,
+    Pid,
+    Tags,
+    /// How many processes are running in the session.
+    Procs,
+    /// The CPU time used by the session's processes.
+    Cpu,
+    /// The combined resident memory of the session's processes.
+    Rss,
+    /// The shell's current working directory.
+    Cwd,
+    /// What the session is for, as set with `shpool note`.
+    Note,
+    /// The last line of output to match a watch since a client was
+    /// last attached, see `shpool watch`.
+    Matched,
+}
+
+impl Column {
+    fn header(&self) -> &'static str {
+        match self {
+            Column::Name => "NAME",
+            Column::StartedAt => "STARTED_AT",
+            Column::Status => "STATUS",
+            Column::ClientTty => "CLIENT_TTY",
+            Column::Activity => "ACTIVITY",
+            Column::Size => "SIZE",
+            Column::LastActive => "LAST_ACTIVE",
+            Column::Memory => "MEMORY",
+            Column::Pid => "PID",
+            Column::Tags => "TAGS",
+            Column::Procs => "PROCS",
+            Column::Cpu => "CPU",
+            Column::Rss => "RSS",
+            Column::Cwd => "CWD",
+            Column::Note => "NOTE",
+            Column::Matched => "MATCHED",
+        }
+    }
+
+    /// The column's value for a session. The table is meant for people,
+    /// so it gets friendlier values than tsv.
+    fn value(&self, session: &Session, procs: Option<&ProcUsage>, table: bool) -> String {
+        match self {
+            Column::Name => session.name.clone(),
+            Column::StartedAt => started_at(session),
+            Column::Status if table => table_status(session),
+            Column::Status => status(session),
+            Column::ClientTty => session.client_tty.clone().unwrap_or_else(|| String::from("-")),
+            Column::Activity => activity(session).unwrap_or("-").to_string(),
+            Column::Size => format!("{}x{}", session.tty_size.rows, session.tty_size.cols),
+            Column::LastActive => last_active_at(session),
+            Column::Memory if table => format_bytes(session.spool_bytes),
+            Column::Memory => session.spool_bytes.to_string(),
+            Column::Pid => session.pid.to_string(),
+            Column::Tags => session.tags.join(","),
+            Column::Procs => procs.map(|p| p.count.to_string()).unwrap_or_else(dash),
+            Column::Cpu if table => procs.map(|p| stats::format_cpu(p.cpu_ns)).unwrap_or_else(dash),
+            Column::Cpu => procs.map(|p| p.cpu_ns.to_string()).unwrap_or_else(dash),
+            Column::Rss if table => procs.map(|p| format_bytes(p.rss_bytes)).unwrap_or_else(dash),
+            Column::Rss => procs.map(|p| p.rss_bytes.to_string()).unwrap_or_else(dash),
+            Column::Cwd if table => session.cwd.as_deref().map(tilde).unwrap_or_else(dash),
+            Column::Cwd => session.cwd.clone().unwrap_or_else(dash),
+            Column::Note => session.note.clone().unwrap_or_else(dash),
+            Column::Matched => session.last_watch_match.clone().unwrap_or_else(dash),
+        }
+    }
+
+    /// The column's value for a session in the table, styled and with
+    /// times shown the way the config asks.
+    fn cell(&self, session: &Session, procs: Option<&ProcUsage>, style: &Style) -> String {
+        match self {
+            Column::StartedAt => style.timestamp(session.started_at_unix_ms),
+            Column::LastActive => style.timestamp(session.last_active_unix_ms),
+            Column::Status => {
+                let status = table_status(session);
+                match status_role(session) {
+                    Some(role) => style.paint(role, &status),
+                    None => status,
+                }
+            }
+            _ => self.value(session, procs, true),
+        }
+    }
+
+    fn is_proc_column(&self) -> bool {
+        PROC_COLUMNS.contains(self)
+    }
+}
+
+/// Parse column names like the ones --columns takes, for the
+/// `output.list_columns` config option.
+pub fn parse_columns(names: &[String]) -> anyhow::Result<Vec<Column>> {
+    names
+        .iter()
+        .map(|name| {
+            <Column as clap::ValueEnum>::from_str(name, false)
+                .map_err(|_| anyhow!("unknown column '{}'", name))
+        })
+        .collect()
+}
+
+fn dash() -> String {
+    String::from("-")
+}
+
+/// Abbreviate the home directory at the start of a path to ~.
+fn tilde(path: &str) -> String {
+    let home = std::env::var("HOME").unwrap_or_default();
+    match path.strip_prefix(home.as_str()) {
+        Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+            format!("~{rest}")
+        }
+        _ => String::from(path),
+    }
+}
+
+// What the table shows without --columns.
+const DEFAULT_TABLE_COLUMNS: [Column; 3] = [Column::Name, Column::StartedAt, Column::Status];
+
+// The columns that need the daemon to look at the sessions' processes.
+const PROC_COLUMNS: [Column; 3] = [Column::Procs, Column::Cpu, Column::Rss];
+
+// What --verbose adds on the end.
+const VERBOSE_COLUMNS: [Column; 5] =
+    [Column::Procs, Column::Cpu, Column::Rss, Column::Cwd, Column::Note];
+
+/// How to order the session list.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sort {
+    /// Alphabetically by name.
+    Name,
+    /// Oldest first.
+    Started,
+    /// Most recently active first.
+    Active,
+    /// Biggest restore spool first.
+    Memory,
+    /// Most recently used first, counting attached sessions as in use
+    /// right now.
+    Recent,
+}
+
+impl Sort {
+    fn apply(&self, entries: &mut [Entry]) {
+        match self {
+            Sort::Name => entries.sort_by(|(_, a, _), (_, b, _)| a.name.cmp(&b.name)),
+            Sort::Started => entries.sort_by_key(|(_, s, _)| s.started_at_unix_ms),
+            Sort::Active => {
+                entries.sort_by_key(|(_, s, _)| std::cmp::Reverse(s.last_active_unix_ms))
+            }
+            Sort::Memory => entries.sort_by_key(|(_, s, _)| std::cmp::Reverse(s.spool_bytes)),
+            Sort::Recent => entries.sort_by_key(|(_, s, _)| std::cmp::Reverse(last_used(s))),
+        }
+    }
+}
+
+/// When the session was last used, for sorting by recency.
+fn last_used(session: &Session) -> i64 {
+    if let SessionStatus::Attached = session.status {
+        return i64::MAX;
+    }
+    session.last_detached_unix_ms.or(session.last_attached_unix_ms).unwrap_or(0)
+}
+
+/// The session a client most recently detached from, for `shpool attach
+/// --last`. Sessions that are attached right now or whose shell has
+/// exited don't count.
+pub fn last_detached(sessions: &[Session]) -> Option<&Session> {
+    sessions
+        .iter()
+        .filter(|s| matches!(s.status, SessionStatus::Disconnected) && s.exit_status.is_none())
+        .filter(|s| s.last_detached_unix_ms.is_some())
+        .max_by_key(|s| s.last_detached_unix_ms)
+}
+
+/// How to lay the session list out.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    pub format: Format,
+    /// The columns to show, or empty for the format's usual ones.
+    /// Ignored by json, which always has everything.
+    pub columns: Vec<Column>,
+    pub sort: Option<Sort>,
+    /// Also show what each session's processes are using.
+    pub verbose: bool,
+    /// How to style the table.
+    pub style: Style,
+}
+
+impl Layout {
+    /// Whether we need to ask the daemon what the sessions' processes
+    /// are using, which takes it a walk through /proc.
+    fn shows_procs(&self) -> bool {
+        self.verbose || self.columns.iter().any(Column::is_proc_column)
+    }
+}
+
+/// The machine readable form of a session.
+#[derive(Serialize, Debug)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'a str>,
+    name: &'a str,
+    started_at: String,
+    started_at_unix_ms: i64,
+    status: String,
+    rows: u16,
+    cols: u16,
+    pid: i32,
+    tags: &'a [String],
+    throttled: bool,
+    locked: bool,
+    activity: bool,
+    bell: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_tty: Option<&'a str>,
+    last_active_at: String,
+    last_active_unix_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_attached_unix_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_detached_unix_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_detach_reason: Option<String>,
+    spool_bytes: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    attach_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    procs: Option<&'a ProcUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+    watch_matches: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_watch_match: Option<&'a str>,
+}
+
+impl<'a> Record<'a> {
+    fn new(namespace: Option<&'a str>, session: &'a Session, procs: Option<&'a ProcUsage>) -> Self {
+        Record {
+            namespace,
+            name: &session.name,
+            started_at: started_at(session),
+            started_at_unix_ms: session.started_at_unix_ms,
+            status: status(session),
+            rows: session.tty_size.rows,
+            cols: session.tty_size.cols,
+            pid: session.pid,
+            tags: &session.tags,
+            throttled: session.throttled,
+            locked: session.locked,
+            activity: session.activity,
+            bell: session.bell,
+            client_tty: session.client_tty.as_deref(),
+            last_active_at: last_active_at(session),
+            last_active_unix_ms: session.last_active_unix_ms,
+            last_attached_unix_ms: session.last_attached_unix_ms,
+            last_detached_unix_ms: session.last_detached_unix_ms,
+            last_detach_reason: session.last_detach_reason.map(|r| r.to_string()),
+            spool_bytes: session.spool_bytes,
+            bytes_in: session.bytes_in,
+            bytes_out: session.bytes_out,
+            attach_count: session.attach_count,
+            procs,
+            cwd: session.cwd.as_deref(),
+            note: session.note.as_deref(),
+            watch_matches: session.watch_matches,
+            last_watch_match: session.last_watch_match.as_deref(),
+        }
+    }
+}
+
+pub fn run(layout: Layout, tags: Vec<String>, socket: PathBuf) -> anyhow::Result<()> {
+    let sessions = fetch(socket.clone(), &tags)?;
+    let procs = if layout.shows_procs() { fetch_procs(socket)? } else { HashMap::new() };
+    let entries = sessions.iter().map(|s| (None, s, procs.get(&s.name))).collect();
+    print!("{}", format_entries(&layout, false, entries)?);
+
+    Ok(())
+}
+
+/// Keep the list on screen, redrawing it whenever the daemon says that
+/// something happened to a session. Sessions that just printed something
+/// get highlighted for a little while.
+pub fn watch(layout: Layout, tags: Vec<String>, socket: PathBuf) -> anyhow::Result<()> {
+    if layout.format != Format::Table {
+        return Err(anyhow!("--watch only works with the table format"));
+    }
+
+    let events = client::Client::new(socket.clone())
+        .subscribe_events()
+        .context("subscribing to session events")?;
+    let (events_tx, events_rx) = crossbeam_channel::unbounded();
+    thread::Builder::new()
+        .name(String::from("list-events"))
+        .spawn(move || {
+            for event in events {
+                if events_tx.send(event).is_err() {
+                    return;
+                }
+            }
+        })
+        .context("spawning event thread")?;
+
+    let mut active: HashMap<String, time::Instant> = HashMap::new();
+    loop {
+        active.retain(|_, at| at.elapsed() < WATCH_HIGHLIGHT);
+        let sessions = fetch(socket.clone(), &tags)?;
+        let procs =
+            if layout.shows_procs() { fetch_procs(socket.clone())? } else { HashMap::new() };
+        let entries = sessions.iter().map(|s| (None, s, procs.get(&s.name))).collect();
+        let highlighted = active.keys().map(String::as_str).collect();
+        let mut out = String::from("\x1b[H\x1b[2J");
+        out.push_str(&format!(
+            "shpool sessions at {}\n\n",
+            chrono::Local::now().format("%H:%M:%S")
+        ));
+        out.push_str(&format_watch(&layout, entries, &highlighted)?);
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(out.as_bytes()).context("drawing session list")?;
+        stdout.flush().context("flushing stdout")?;
+        let drawn_at = time::Instant::now();
+
+        // Sleep until the daemon tells us about something, or until a
+        // highlight needs to come off.
+        let unhighlight_in =
+            active.values().map(|at| WATCH_HIGHLIGHT.saturating_sub(at.elapsed())).min();
+        let first = match unhighlight_in {
+            Some(timeout) => match events_rx.recv_timeout(timeout) {
+                Ok(event) => Some(event),
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => None,
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("the daemon hung up"));
+                }
+            },
+            None => Some(events_rx.recv().map_err(|_| anyhow!("the daemon hung up"))?),
+        };
+        if first.is_some() {
+            thread::sleep(WATCH_REDRAW_INTERVAL.saturating_sub(drawn_at.elapsed()));
+        }
+        for event in first.into_iter().chain(events_rx.try_iter()) {
+            if let ControlEvent::OutputAvailable { session } = event? {
+                active.insert(session, time::Instant::now());
+            }
+        }
+    }
+}
+
+/// Format the table for `list --watch`, with the rows of the
+/// `highlighted` sessions in reverse video.
+fn format_watch(
+    layout: &Layout,
+    mut entries: Vec<Entry>,
+    highlighted: &HashSet<&str>,
+) -> anyhow::Result<String> {
+    // Sort up front so that we know which row is which.
+    if let Some(sort) = layout.sort {
+        sort.apply(&mut entries);
+    }
+    let names: Vec<&str> = entries.iter().map(|(_, s, _)| s.name.as_str()).collect();
+    let table = format_entries(&Layout { sort: None, ..layout.clone() }, false, entries)?;
+
+    let mut out = String::new();
+    for (i, line) in table.lines().enumerate() {
+        let name = i.checked_sub(1).and_then(|i| names.get(i));
+        if name.is_some_and(|name| highlighted.contains(name)) {
+            out.push_str(&format!("\x1b[7m{line}\x1b[0m\n"));
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+/// List the sessions of every namespace. Namespaces whose daemon is
+/// not running are skipped.
+pub fn run_all(
+    layout: Layout,
+    tags: Vec<String>,
+    sockets: Vec<(String, PathBuf)>,
+) -> anyhow::Result<()> {
+    let mut listings = vec![];
+    for (namespace, socket) in sockets {
+        if UnixStream::connect(&socket).is_err() {
+            continue;
+        }
+        let sessions = fetch(socket.clone(), &tags)
+            .with_context(|| format!("listing namespace '{namespace}'"))?;
+        let procs = if layout.shows_procs() { fetch_procs(socket)? } else { HashMap::new() };
+        listings.push((namespace, sessions, procs));
+    }
+
+    let entries: Vec<Entry> = listings
+        .iter()
+        .flat_map(|(namespace, sessions, procs)| {
+            sessions.iter().map(|s| (Some(namespace.as_str()), s, procs.get(&s.name)))
+        })
+        .collect();
+    print!("{}", format_entries(&layout, true, entries)?);
+
+    Ok(())
+}
+
+pub fn fetch(socket: PathBuf, tags: &[String]) -> anyhow::Result<Vec<Session>> {
     let mut client = match protocol::Client::new(socket) {
         Ok(ClientResult::JustClient(c)) => c,
         Ok(ClientResult::VersionMismatch { warning, client }) => {
@@ -36,15 +494,504 @@ pub fn run(socket: PathBuf) -> anyhow::Result<()> {
     };
 
     client.write_connect_header(ConnectHeader::List).context("sending list connect header")?;
-    let reply: ListReply = client.read_reply().context("reading reply")?;
+    let mut reply: ListReply = client.read_reply().context("reading reply")?;
+    if !tags.is_empty() {
+        reply.sessions.retain(|s| s.tags.iter().any(|t| tags.contains(t)));
+    }
+
+    Ok(reply.sessions)
+}
+
+/// What the processes of each session are using, by session name.
+fn fetch_procs(socket: PathBuf) -> anyhow::Result<HashMap<String, ProcUsage>> {
+    let stats = stats::fetch(socket).context("fetching session stats")?;
+    Ok(stats.into_iter().filter_map(|s| Some((s.name, s.procs?))).collect())
+}
+
+#[cfg(test)]
+fn format_sessions(layout: &Layout, sessions: &[Session]) -> anyhow::Result<String> {
+    let entries: Vec<Entry> = sessions.iter().map(|s| (None, s, None)).collect();
+    format_entries(layout, false, entries)
+}
 
-    println!("NAME\tSTARTED_AT\tSTATUS");
-    for session in reply.sessions.iter() {
-        let started_at =
-            time::UNIX_EPOCH + time::Duration::from_millis(session.started_at_unix_ms as u64);
-        let started_at = chrono::DateTime::<chrono::Utc>::from(started_at);
-        println!("{}\t{}\t{}", session.name, started_at.to_rfc3339(), session.status);
+/// Format sessions along with the namespace each one came from. If
+/// `namespaced` is set, the table gets a leading NAMESPACE column and
+/// tsv lines get a trailing namespace column.
+fn format_entries(
+    layout: &Layout,
+    namespaced: bool,
+    mut entries: Vec<Entry>,
+) -> anyhow::Result<String> {
+    if let Some(sort) = layout.sort {
+        sort.apply(&mut entries);
+    }
+    let mut out = String::new();
+    match layout.format {
+        Format::Table => {
+            let mut columns = if layout.columns.is_empty() {
+                DEFAULT_TABLE_COLUMNS.to_vec()
+            } else {
+                layout.columns.clone()
+            };
+            if layout.verbose {
+                columns.extend(VERBOSE_COLUMNS);
+            }
+            let mut headers: Vec<&str> = columns.iter().map(|c| c.header()).collect();
+            if namespaced {
+                headers.insert(0, "NAMESPACE");
+            }
+            out.push_str(&layout.style.paint(Role::Header, &headers.join("\t")));
+            out.push('\n');
+            for (namespace, session, procs) in entries.iter() {
+                let mut values: Vec<String> =
+                    columns.iter().map(|c| c.cell(session, *procs, &layout.style)).collect();
+                if namespaced {
+                    values.insert(0, String::from(namespace.unwrap_or_default()));
+                }
+                out.push_str(&values.join("\t"));
+                out.push('\n');
+            }
+        }
+        Format::Json => {
+            let records: Vec<Record> =
+                entries.iter().map(|(ns, s, procs)| Record::new(*ns, s, *procs)).collect();
+            out.push_str(&serde_json::to_string_pretty(&records).context("formatting json")?);
+            out.push('\n');
+        }
+        Format::Tsv => {
+            for (namespace, session, procs) in entries.iter() {
+                let mut values: Vec<String> = if layout.columns.is_empty() {
+                    tsv_default(session)
+                } else {
+                    layout.columns.iter().map(|c| c.value(session, *procs, false)).collect()
+                };
+                if layout.verbose {
+                    values.extend(VERBOSE_COLUMNS.iter().map(|c| c.value(session, *procs, false)));
+                }
+                out.push_str(&values.join("\t"));
+                if namespaced {
+                    out.push_str(&format!("\t{}", namespace.unwrap_or_default()));
+                }
+                out.push('\n');
+            }
+        }
     }
+    Ok(out)
+}
 
-    Ok(())
+/// Sessions whose shell has exited but which are being kept around
+/// show the exit status in place of attached/disconnected.
+fn status(session: &Session) -> String {
+    match session.exit_status {
+        Some(exit_status) => format!("exited({exit_status})"),
+        None => session.status.to_string(),
+    }
+}
+
+/// The table is meant for people, so it also points out sessions that
+/// have had something happen since they were last attached, that have
+/// been throttled, whose last client hung up rather than detaching, or
+/// that are locked.
+fn table_status(session: &Session) -> String {
+    if session.exit_status.is_some() {
+        return status(session);
+    }
+    let mut notes = vec![];
+    if session.locked {
+        notes.push("locked");
+    }
+    if let (SessionStatus::Disconnected, Some(DetachReason::HungUp)) =
+        (&session.status, session.last_detach_reason)
+    {
+        notes.push("hung up");
+    }
+    notes.extend(activity(session));
+    if session.watch_matches > 0 {
+        notes.push("matched");
+    }
+    if session.throttled {
+        notes.push("throttled");
+    }
+    if notes.is_empty() {
+        status(session)
+    } else {
+        format!("{} ({})", status(session), notes.join(", "))
+    }
+}
+
+/// How to color the session's status in the table.
+fn status_role(session: &Session) -> Option<Role> {
+    if session.exit_status.is_some() {
+        Some(Role::Exited)
+    } else if table_status(session) != status(session) {
+        // something worth pointing out, see table_status
+        Some(Role::Alert)
+    } else if let SessionStatus::Attached = session.status {
+        Some(Role::Attached)
+    } else {
+        None
+    }
+}
+
+/// What has happened in the session since a client was last attached,
+/// with a bell being the more interesting of the two.
+fn activity(session: &Session) -> Option<&'static str> {
+    if session.bell {
+        Some("bell")
+    } else if session.activity {
+        Some("activity")
+    } else {
+        None
+    }
+}
+
+/// What tsv shows without --columns. This is what it has always
+/// shown, so scripts that split the lines up keep working.
+fn tsv_default(session: &Session) -> Vec<String> {
+    vec![
+        session.name.clone(),
+        started_at(session),
+        status(session),
+        session.tty_size.rows.to_string(),
+        session.tty_size.cols.to_string(),
+        session.pid.to_string(),
+        session.tags.join(","),
+    ]
+}
+
+fn started_at(session: &Session) -> String {
+    output::rfc3339(session.started_at_unix_ms)
+}
+
+fn last_active_at(session: &Session) -> String {
+    output::rfc3339(session.last_active_unix_ms)
+}
+
+#[cfg(test)]
+mod test {
+    use shpool_protocol::TtySize;
+
+    use super::*;
+
+    fn sessions() -> Vec<Session> {
+        vec![Session {
+            name: String::from("main"),
+            started_at_unix_ms: 0,
+            status: SessionStatus::Attached,
+            pid: 1234,
+            tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+            tags: vec![String::from("work"), String::from("ci")],
+            exit_status: None,
+            throttled: false,
+            activity: false,
+            bell: false,
+            client_tty: Some(String::from("/dev/pts/3")),
+            last_active_unix_ms: 60_000,
+            spool_bytes: 2048,
+            bytes_in: 12,
+            bytes_out: 345,
+            attach_count: 2,
+            last_attached_unix_ms: Some(30_000),
+            last_detached_unix_ms: None,
+            last_detach_reason: None,
+            locked: false,
+            cwd: None,
+            note: None,
+            watch_matches: 0,
+            last_watch_match: None,
+        }]
+    }
+
+    fn layout(format: Format) -> Layout {
+        Layout { format, ..Default::default() }
+    }
+
+    #[test]
+    fn table() -> anyhow::Result<()> {
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions())?,
+            "NAME\tSTARTED_AT\tSTATUS\nmain\t1970-01-01T00:00:00+00:00\tattached\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn watch_highlights() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        let mut quiet = sessions.remove(0);
+        quiet.name = String::from("quiet");
+        sessions.insert(0, quiet);
+        sessions.extend(self::sessions());
+        let entries = sessions.iter().map(|s| (None, s, None)).collect();
+        let layout =
+            Layout { columns: vec![Column::Name], sort: Some(Sort::Name), ..layout(Format::Table) };
+        assert_eq!(
+            format_watch(&layout, entries, &HashSet::from(["main"]))?,
+            "NAME\n\x1b[7mmain\x1b[0m\nquiet\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tsv() -> anyhow::Result<()> {
+        assert_eq!(
+            format_sessions(&layout(Format::Tsv), &sessions())?,
+            "main\t1970-01-01T00:00:00+00:00\tattached\t24\t80\t1234\twork,ci\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> anyhow::Result<()> {
+        let out = format_sessions(&layout(Format::Json), &sessions())?;
+        let parsed: serde_json::Value = serde_json::from_str(&out)?;
+        assert_eq!(parsed[0]["name"], "main");
+        assert_eq!(parsed[0]["status"], "attached");
+        assert_eq!(parsed[0]["rows"], 24);
+        assert_eq!(parsed[0]["pid"], 1234);
+        assert_eq!(parsed[0]["tags"][1], "ci");
+        assert!(parsed[0].get("namespace").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn exited() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        sessions[0].status = SessionStatus::Disconnected;
+        sessions[0].exit_status = Some(2);
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions)?,
+            "NAME\tSTARTED_AT\tSTATUS\nmain\t1970-01-01T00:00:00+00:00\texited(2)\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn throttled() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        sessions[0].status = SessionStatus::Disconnected;
+        sessions[0].throttled = true;
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions)?,
+            "NAME\tSTARTED_AT\tSTATUS\nmain\t1970-01-01T00:00:00+00:00\tdisconnected (throttled)\n"
+        );
+        assert_eq!(
+            format_sessions(&layout(Format::Tsv), &sessions)?,
+            "main\t1970-01-01T00:00:00+00:00\tdisconnected\t24\t80\t1234\twork,ci\n"
+        );
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_sessions(&layout(Format::Json), &sessions)?)?;
+        assert_eq!(parsed[0]["throttled"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn locked() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        sessions[0].locked = true;
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions)?,
+            "NAME	STARTED_AT	STATUS
+main	1970-01-01T00:00:00+00:00	attached (locked)
+"
+        );
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_sessions(&layout(Format::Json), &sessions)?)?;
+        assert_eq!(parsed[0]["locked"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn activity() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        sessions[0].status = SessionStatus::Disconnected;
+        sessions[0].activity = true;
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions)?,
+            "NAME\tSTARTED_AT\tSTATUS\nmain\t1970-01-01T00:00:00+00:00\tdisconnected (activity)\n"
+        );
+
+        sessions[0].bell = true;
+        sessions[0].throttled = true;
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions)?,
+            "NAME\tSTARTED_AT\tSTATUS\n\
+             main\t1970-01-01T00:00:00+00:00\tdisconnected (bell, throttled)\n"
+        );
+        let tsv = Layout {
+            format: Format::Tsv,
+            columns: vec![Column::Name, Column::Activity],
+            ..Default::default()
+        };
+        assert_eq!(format_sessions(&tsv, &sessions)?, "main\tbell\n");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_sessions(&layout(Format::Json), &sessions)?)?;
+        assert_eq!(parsed[0]["activity"], true);
+        assert_eq!(parsed[0]["bell"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn watch_matches() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        sessions[0].status = SessionStatus::Disconnected;
+        sessions[0].activity = true;
+        sessions[0].watch_matches = 2;
+        sessions[0].last_watch_match = Some(String::from("error: linking failed"));
+        assert_eq!(
+            format_sessions(&layout(Format::Table), &sessions)?,
+            "NAME\tSTARTED_AT\tSTATUS\n\
+             main\t1970-01-01T00:00:00+00:00\tdisconnected (activity, matched)\n"
+        );
+        let tsv = Layout {
+            format: Format::Tsv,
+            columns: vec![Column::Name, Column::Matched],
+            ..Default::default()
+        };
+        assert_eq!(format_sessions(&tsv, &sessions)?, "main\terror: linking failed\n");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_sessions(&layout(Format::Json), &sessions)?)?;
+        assert_eq!(parsed[0]["watch_matches"], 2);
+        assert_eq!(parsed[0]["last_watch_match"], "error: linking failed");
+        Ok(())
+    }
+
+    #[test]
+    fn columns() -> anyhow::Result<()> {
+        let columns = vec![
+            Column::Name,
+            Column::ClientTty,
+            Column::Size,
+            Column::LastActive,
+            Column::Memory,
+            Column::Pid,
+        ];
+        let table =
+            Layout { format: Format::Table, columns: columns.clone(), ..Default::default() };
+        assert_eq!(
+            format_sessions(&table, &sessions())?,
+            "NAME\tCLIENT_TTY\tSIZE\tLAST_ACTIVE\tMEMORY\tPID\n\
+             main\t/dev/pts/3\t24x80\t1970-01-01T00:01:00+00:00\t2.0 KiB\t1234\n"
+        );
+        let tsv = Layout { format: Format::Tsv, columns, ..Default::default() };
+        assert_eq!(
+            format_sessions(&tsv, &sessions())?,
+            "main\t/dev/pts/3\t24x80\t1970-01-01T00:01:00+00:00\t2048\t1234\n"
+        );
+
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_sessions(&layout(Format::Json), &sessions())?)?;
+        assert_eq!(parsed[0]["client_tty"], "/dev/pts/3");
+        assert_eq!(parsed[0]["last_active_unix_ms"], 60_000);
+        assert_eq!(parsed[0]["spool_bytes"], 2048);
+        assert_eq!(parsed[0]["bytes_in"], 12);
+        assert_eq!(parsed[0]["bytes_out"], 345);
+        assert_eq!(parsed[0]["attach_count"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn sort() -> anyhow::Result<()> {
+        let mut other = sessions().remove(0);
+        let mut sessions = sessions();
+        other.name = String::from("build");
+        other.started_at_unix_ms = 1000;
+        other.last_active_unix_ms = 1000;
+        other.spool_bytes = 4096;
+        other.status = SessionStatus::Disconnected;
+        other.last_detached_unix_ms = Some(2000);
+        sessions.push(other);
+
+        let names = |sort| -> anyhow::Result<String> {
+            let layout = Layout {
+                format: Format::Tsv,
+                columns: vec![Column::Name],
+                sort,
+                ..Default::default()
+            };
+            Ok(format_sessions(&layout, &sessions)?.replace('\n', " "))
+        };
+        assert_eq!(names(None)?, "main build ");
+        assert_eq!(names(Some(Sort::Name))?, "build main ");
+        assert_eq!(names(Some(Sort::Started))?, "main build ");
+        assert_eq!(names(Some(Sort::Active))?, "main build ");
+        assert_eq!(names(Some(Sort::Memory))?, "build main ");
+        assert_eq!(names(Some(Sort::Recent))?, "main build ");
+        Ok(())
+    }
+
+    #[test]
+    fn last_detached_session() {
+        let detached = |name: &str, at| Session {
+            name: String::from(name),
+            status: SessionStatus::Disconnected,
+            last_detached_unix_ms: Some(at),
+            ..sessions().remove(0)
+        };
+        let mut sessions = sessions();
+        assert!(last_detached(&sessions).is_none());
+
+        sessions.push(detached("old", 1000));
+        sessions.push(detached("new", 2000));
+        sessions.push(Session { exit_status: Some(0), ..detached("exited", 3000) });
+        assert_eq!(last_detached(&sessions).map(|s| s.name.as_str()), Some("new"));
+
+        // an attached session is not a candidate, however recently it
+        // was detached from before
+        sessions[0].last_detached_unix_ms = Some(4000);
+        assert_eq!(last_detached(&sessions).map(|s| s.name.as_str()), Some("new"));
+    }
+
+    #[test]
+    fn verbose() -> anyhow::Result<()> {
+        let mut sessions = sessions();
+        sessions[0].cwd = Some(String::from("/src/api"));
+        sessions[0].note = Some(String::from("bisecting"));
+        let procs = ProcUsage { count: 3, cpu_ns: 2_500_000_000, rss_bytes: 4096 };
+        let entries = vec![(None, &sessions[0], Some(&procs))];
+        let verbose = |format| Layout { format, verbose: true, ..Default::default() };
+        assert_eq!(
+            format_entries(&verbose(Format::Table), false, entries.clone())?,
+            "NAME\tSTARTED_AT\tSTATUS\tPROCS\tCPU\tRSS\tCWD\tNOTE\n\
+             main\t1970-01-01T00:00:00+00:00\tattached\t3\t2.500s\t4.0 KiB\t/src/api\tbisecting\n"
+        );
+        assert_eq!(
+            format_entries(&verbose(Format::Tsv), false, entries.clone())?,
+            "main\t1970-01-01T00:00:00+00:00\tattached\t24\t80\t1234\twork,ci\t3\t2500000000\t4096\t\
+             /src/api\tbisecting\n"
+        );
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_entries(&verbose(Format::Json), false, entries)?)?;
+        assert_eq!(parsed[0]["procs"]["count"], 3);
+        assert_eq!(parsed[0]["procs"]["rss_bytes"], 4096);
+        assert_eq!(parsed[0]["cwd"], "/src/api");
+        assert_eq!(parsed[0]["note"], "bisecting");
+
+        // a daemon that can't look the processes or the cwd up, and a
+        // session without a note
+        sessions[0].cwd = None;
+        sessions[0].note = None;
+        let entries = vec![(None, &sessions[0], None)];
+        let table = format_entries(&verbose(Format::Table), false, entries)?;
+        assert!(table.ends_with("\t-\t-\t-\t-\t-\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn namespaced() -> anyhow::Result<()> {
+        let sessions = sessions();
+        let entries = vec![(Some("work"), &sessions[0], None)];
+        assert_eq!(
+            format_entries(&layout(Format::Table), true, entries.clone())?,
+            "NAMESPACE\tNAME\tSTARTED_AT\tSTATUS\nwork\tmain\t1970-01-01T00:00:00+00:00\tattached\n"
+        );
+        assert_eq!(
+            format_entries(&layout(Format::Tsv), true, entries.clone())?,
+            "main\t1970-01-01T00:00:00+00:00\tattached\t24\t80\t1234\twork,ci\twork\n"
+        );
+        let parsed: serde_json::Value =
+            serde_json::from_str(&format_entries(&layout(Format::Json), true, entries.clone())?)?;
+        assert_eq!(parsed[0]["namespace"], "work");
+        Ok(())
+    }
 }
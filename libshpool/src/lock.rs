@@ -0,0 +1,103 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The lock subcommand guards a session with a passphrase, which the
+//! daemon then asks for before letting anyone attach to it.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, LockReply, LockRequest};
+
+use crate::{common, protocol, protocol::ClientResult, tty};
+
+pub fn run(session: Option<String>, remove: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut sessions: Vec<String> = session.into_iter().collect();
+    common::resolve_sessions(&mut sessions, "lock")?;
+    let session = sessions.remove(0);
+
+    let passphrase = if remove {
+        Some(tty::read_passphrase(&format!("passphrase for '{session}': "))?)
+    } else {
+        None
+    };
+    let mut reply = request(&socket, &session, passphrase, remove)?;
+    if reply == LockReply::NeedPassphrase {
+        let passphrase = new_passphrase(&session)?;
+        reply = request(&socket, &session, Some(passphrase), remove)?;
+    }
+
+    match reply {
+        LockReply::Locked | LockReply::Removed => Ok(()),
+        LockReply::NotFound => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+        LockReply::WrongPassphrase => {
+            eprintln!("wrong passphrase");
+            Err(anyhow!("wrong passphrase for '{}'", session))
+        }
+        LockReply::NeedPassphrase => Err(anyhow!("daemon ignored the new passphrase")),
+    }
+}
+
+/// Ask for the passphrase to give a session that does not have one yet.
+fn new_passphrase(session: &str) -> anyhow::Result<String> {
+    let passphrase = tty::read_passphrase(&format!("new passphrase for '{session}': "))?;
+    if passphrase.is_empty() {
+        eprintln!("blank passphrases are not allowed");
+        return Err(anyhow!("blank passphrases are not allowed"));
+    }
+    if tty::read_passphrase("again: ")? != passphrase {
+        eprintln!("passphrases do not match");
+        return Err(anyhow!("passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn request(
+    socket: &Path,
+    session: &str,
+    passphrase: Option<String>,
+    remove: bool,
+) -> anyhow::Result<LockReply> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::LOCK, "locking sessions")?;
+    client
+        .write_connect_header(ConnectHeader::Lock(LockRequest {
+            session: String::from(session),
+            passphrase,
+            remove,
+        }))
+        .context("writing lock request header")?;
+
+    client.read_reply().context("reading reply")
+}
@@ -0,0 +1,75 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, MigrateReply, MigrateRequest};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(session: String, to_socket: PathBuf, socket: PathBuf) -> anyhow::Result<()> {
+    // The daemon has its own working directory, so it can't make sense
+    // of a relative path.
+    let to_socket = std::path::absolute(&to_socket)
+        .with_context(|| format!("resolving {}", to_socket.display()))?;
+    if std::path::absolute(&socket).ok().as_ref() == Some(&to_socket) {
+        eprintln!("the session is already on that daemon");
+        return Err(anyhow!("the session is already on that daemon"));
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::MIGRATE, "migrating sessions")?;
+    client
+        .write_connect_header(ConnectHeader::Migrate(MigrateRequest {
+            session: session.clone(),
+            to_socket: to_socket.to_string_lossy().into_owned(),
+        }))
+        .context("writing migrate request header")?;
+
+    let reply: MigrateReply = client.read_reply().context("reading reply")?;
+    match reply {
+        MigrateReply::Migrated => Ok(()),
+        MigrateReply::NotFound => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+        MigrateReply::Exited => {
+            eprintln!("the shell in '{session}' has already exited");
+            Err(anyhow!("the shell in '{}' has already exited", session))
+        }
+        MigrateReply::Locked => {
+            eprintln!("session '{session}' is locked");
+            Err(anyhow!("session '{}' is locked", session))
+        }
+        MigrateReply::Failed(reason) => {
+            eprintln!("could not migrate '{session}': {reason}");
+            Err(anyhow!("could not migrate '{}': {}", session, reason))
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Running more than one daemon side by side.
+
+  Each namespace gets its own daemon with its own socket, runtime
+  directory and log file, all living under `ns/<name>` in the normal
+  runtime directory. The default namespace is the plain runtime
+  directory, so users that never pass `--namespace` see no change.
+
+  Sessions record the namespace they were created in in the
+  SHPOOL_NAMESPACE environment variable, and shpool commands run from
+  inside a session use it when no namespace or socket is given on the
+  command line, so `shpool detach` and friends talk to the right daemon.
+*/
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+/// The environment variable holding the namespace to use when
+/// `--namespace` is not given.
+pub const NAMESPACE_VAR: &str = "SHPOOL_NAMESPACE";
+
+/// The name of the namespace you get without asking for one.
+pub const DEFAULT: &str = "default";
+
+const SOCKET_NAME: &str = "shpool.socket";
+
+/// The runtime directory of the default namespace, which every other
+/// namespace lives under. This does not create it.
+pub fn base_runtime_dir() -> anyhow::Result<PathBuf> {
+    let runtime_dir = match env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime_dir) => PathBuf::from(runtime_dir),
+        Err(_) => PathBuf::from(env::var("HOME").context("no XDG_RUNTIME_DIR or HOME")?)
+            .join(".local")
+            .join("run"),
+    };
+    Ok(runtime_dir.join("shpool"))
+}
+
+/// The namespace named by $SHPOOL_NAMESPACE, if it is set.
+pub fn from_env() -> Option<String> {
+    env::var(NAMESPACE_VAR).ok().filter(|ns| !ns.is_empty())
+}
+
+/// Check that a namespace name is safe to use as a directory name.
+pub fn validate(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("blank namespace names are not allowed"));
+    }
+    if name == "." || name == ".." || name.contains('/') || name.contains(char::is_whitespace) {
+        return Err(anyhow!("invalid namespace name '{}'", name));
+    }
+    Ok(())
+}
+
+/// The runtime directory for the given namespace, under the base
+/// runtime directory.
+pub fn runtime_dir(base: &Path, name: &str) -> PathBuf {
+    if name == DEFAULT {
+        base.to_path_buf()
+    } else {
+        base.join("ns").join(name)
+    }
+}
+
+/// The control socket for the given namespace.
+pub fn socket(base: &Path, name: &str) -> PathBuf {
+    runtime_dir(base, name).join(SOCKET_NAME)
+}
+
+/// All the namespaces that have a socket, along with the socket path,
+/// default namespace first and the rest sorted by name. This says
+/// nothing about whether there is a daemon listening on the sockets.
+pub fn all(base: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut namespaces = vec![];
+    let ns_dir = base.join("ns");
+    match fs::read_dir(&ns_dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let entry = entry.context("reading namespace dir entry")?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if validate(&name).is_ok() && socket(base, &name).exists() {
+                    namespaces.push(name);
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("reading namespace dir"),
+    }
+    namespaces.sort();
+
+    let mut sockets = vec![];
+    if socket(base, DEFAULT).exists() {
+        sockets.push((String::from(DEFAULT), socket(base, DEFAULT)));
+    }
+    sockets.extend(namespaces.into_iter().map(|name| {
+        let sock = socket(base, &name);
+        (name, sock)
+    }));
+    Ok(sockets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(validate("work").is_ok());
+        assert!(validate("proj-1.2").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("..").is_err());
+        assert!(validate("a/b").is_err());
+        assert!(validate("a b").is_err());
+    }
+
+    #[test]
+    fn dirs() {
+        let base = Path::new("/run/user/1000/shpool");
+        assert_eq!(runtime_dir(base, DEFAULT), base);
+        assert_eq!(socket(base, "work"), base.join("ns").join("work").join("shpool.socket"));
+    }
+
+    #[test]
+    fn finds_all() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let base = tmp_dir.path();
+        assert!(all(base)?.is_empty());
+
+        for name in [DEFAULT, "work", "home"] {
+            fs::create_dir_all(runtime_dir(base, name))?;
+            fs::write(socket(base, name), "")?;
+        }
+        // a namespace with no socket is skipped
+        fs::create_dir_all(runtime_dir(base, "stale"))?;
+
+        let names: Vec<String> = all(base)?.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["default", "home", "work"]);
+        Ok(())
+    }
+}
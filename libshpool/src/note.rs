@@ -0,0 +1,92 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, NoteReply, NoteRequest};
+
+use crate::{list, protocol, protocol::ClientResult};
+
+pub fn run(
+    session: String,
+    note: Option<String>,
+    clear: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    if note.is_none() && !clear {
+        let sessions = list::fetch(socket, &[])?;
+        let Some(found) = sessions.into_iter().find(|s| s.name == session) else {
+            eprintln!("not found: {session}");
+            return Err(anyhow!("not found: {}", session));
+        };
+        if let Some(note) = found.note {
+            println!("{note}");
+        }
+        return Ok(());
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::NOTE, "noting sessions")?;
+    client
+        .write_connect_header(ConnectHeader::Note(NoteRequest {
+            session: session.clone(),
+            note: note.map(|n| one_line(&n)).filter(|n| !n.is_empty()),
+        }))
+        .context("writing note request header")?;
+
+    let reply: NoteReply = client.read_reply().context("reading reply")?;
+    match reply {
+        NoteReply::Noted => Ok(()),
+        NoteReply::NotFound => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+    }
+}
+
+/// Squash a note onto one line so that it fits in a table cell, with
+/// runs of whitespace and control characters turned into single spaces.
+fn one_line(note: &str) -> String {
+    note.split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn squashes() {
+        assert_eq!(one_line("bisecting the flaky test"), "bisecting the flaky test");
+        assert_eq!(one_line("  two\tlines\nof\x1b[1mnote "), "two lines of [1mnote");
+        assert_eq!(one_line(" \n "), "");
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Formatting output meant for people.
+
+  Commands like `list` and `stat` run what they print through a Style
+  built from the `[output]` config table, which decides whether things
+  get colored and how times get shown. Color follows the no-color.org
+  convention: "auto" turns it off when NO_COLOR is set to a non-empty
+  value, when TERM is dumb or when stdout is not a terminal, while an
+  explicit "always" or "never" in the config wins over NO_COLOR.
+  Machine readable output never goes through here.
+*/
+
+use std::{env, ffi::OsString, io, io::IsTerminal as _, time};
+
+use anyhow::anyhow;
+use tracing::warn;
+
+use crate::{config, duration};
+
+/// The parts of the output that can be styled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Header,
+    Attached,
+    Exited,
+    Alert,
+}
+
+/// How to render output for people.
+#[derive(Debug, Clone, Default)]
+pub struct Style {
+    /// The SGR parameters for each role, or None for plain output.
+    colors: Option<Colors>,
+    timestamps: config::Timestamps,
+}
+
+#[derive(Debug, Clone)]
+struct Colors {
+    header: String,
+    attached: String,
+    exited: String,
+    alert: String,
+}
+
+impl Style {
+    /// The style the config asks for, given the environment we are
+    /// printing to.
+    pub fn from_config(config: &config::Config) -> Self {
+        let output = config.output.clone().unwrap_or_default();
+        let color = use_color(
+            output.color.unwrap_or_default(),
+            env::var_os("NO_COLOR"),
+            env::var("TERM").ok(),
+            io::stdout().is_terminal(),
+        );
+        Style::new(&output, color)
+    }
+
+    fn new(output: &config::Output, color: bool) -> Self {
+        let theme = output.theme.clone().unwrap_or_default();
+        let sgr = |spec: Option<String>, default: &str| {
+            let spec = spec.unwrap_or_else(|| String::from(default));
+            parse_style(&spec).unwrap_or_else(|e| {
+                warn!("bad output style '{}', leaving it plain: {:?}", spec, e);
+                String::new()
+            })
+        };
+        Style {
+            colors: color.then(|| Colors {
+                header: sgr(theme.header, "bold"),
+                attached: sgr(theme.attached, "green"),
+                exited: sgr(theme.exited, "red"),
+                alert: sgr(theme.alert, "yellow"),
+            }),
+            timestamps: output.timestamps.unwrap_or_default(),
+        }
+    }
+
+    /// Style some text for the given role, if color is on.
+    pub fn paint(&self, role: Role, text: &str) -> String {
+        let Some(colors) = &self.colors else {
+            return String::from(text);
+        };
+        let sgr = match role {
+            Role::Header => &colors.header,
+            Role::Attached => &colors.attached,
+            Role::Exited => &colors.exited,
+            Role::Alert => &colors.alert,
+        };
+        if sgr.is_empty() || text.is_empty() {
+            String::from(text)
+        } else {
+            format!("\x1b[{sgr}m{text}\x1b[0m")
+        }
+    }
+
+    /// Show a time given in milliseconds since the epoch.
+    pub fn timestamp(&self, unix_ms: i64) -> String {
+        match self.timestamps {
+            config::Timestamps::Absolute => rfc3339(unix_ms),
+            config::Timestamps::Relative => relative(unix_ms, time::SystemTime::now()),
+        }
+    }
+}
+
+/// Lay out labeled values one per line, like `name:\tmain`.
+pub fn fields(style: &Style, fields: &[(&str, String)]) -> String {
+    let mut out = String::new();
+    for (label, value) in fields {
+        out.push_str(&format!("{}\t{}\n", style.paint(Role::Header, &format!("{label}:")), value));
+    }
+    out
+}
+
+/// Whether to use color, going by the config, the NO_COLOR and TERM
+/// environment variables and whether stdout is a terminal.
+fn use_color(
+    choice: config::ColorChoice,
+    no_color: Option<OsString>,
+    term: Option<String>,
+    is_tty: bool,
+) -> bool {
+    match choice {
+        config::ColorChoice::Always => true,
+        config::ColorChoice::Never => false,
+        config::ColorChoice::Auto => {
+            no_color.is_none_or(|v| v.is_empty()) && term.as_deref() != Some("dumb") && is_tty
+        }
+    }
+}
+
+/// Turn a style like "bold red" into SGR parameters like "1;31". An
+/// empty result means no styling at all.
+pub fn parse_style(spec: &str) -> anyhow::Result<String> {
+    const COLORS: [&str; 8] =
+        ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+    let mut params = vec![];
+    for word in spec.split_whitespace() {
+        let param = match word {
+            "none" => continue,
+            "bold" => 1,
+            "dim" => 2,
+            "italic" => 3,
+            "underline" => 4,
+            "reverse" => 7,
+            _ => {
+                let (base, name) = match word.strip_prefix("bright-") {
+                    Some(name) => (90, name),
+                    None => (30, word),
+                };
+                let i = COLORS
+                    .iter()
+                    .position(|c| *c == name)
+                    .ok_or(anyhow!("unknown style '{}'", word))?;
+                base + i
+            }
+        };
+        params.push(param.to_string());
+    }
+    Ok(params.join(";"))
+}
+
+pub fn rfc3339(unix_ms: i64) -> String {
+    match chrono::DateTime::<chrono::Utc>::from_timestamp_millis(unix_ms) {
+        Some(at) => at.to_rfc3339(),
+        None => unix_ms.to_string(),
+    }
+}
+
+/// How long ago a time was, like 2h13m ago.
+fn relative(unix_ms: i64, now: time::SystemTime) -> String {
+    let at = time::UNIX_EPOCH + time::Duration::from_millis(unix_ms.max(0) as u64);
+    match now.duration_since(at) {
+        Ok(ago) if ago >= time::Duration::from_secs(1) => format!("{} ago", duration::format(ago)),
+        _ => String::from("just now"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn styles() -> anyhow::Result<()> {
+        assert_eq!(parse_style("bold")?, "1");
+        assert_eq!(parse_style("bold red")?, "1;31");
+        assert_eq!(parse_style("  underline bright-cyan ")?, "4;96");
+        assert_eq!(parse_style("none")?, "");
+        assert_eq!(parse_style("")?, "");
+        assert!(parse_style("bold pink").is_err());
+        assert!(parse_style("bright-bold").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn color_choice() {
+        use config::ColorChoice::*;
+        let tty = |choice, no_color: Option<&str>, term: &str| {
+            use_color(choice, no_color.map(OsString::from), Some(String::from(term)), true)
+        };
+        assert!(tty(Auto, None, "xterm"));
+        assert!(tty(Auto, Some(""), "xterm"));
+        assert!(!tty(Auto, Some("1"), "xterm"));
+        assert!(!tty(Auto, None, "dumb"));
+        assert!(!use_color(Auto, None, None, false));
+        // an explicit choice wins over NO_COLOR
+        assert!(tty(Always, Some("1"), "dumb"));
+        assert!(!tty(Never, None, "xterm"));
+    }
+
+    #[test]
+    fn paint() {
+        let plain = Style::new(&config::Output::default(), false);
+        assert_eq!(plain.paint(Role::Header, "NAME"), "NAME");
+
+        let output = config::Output {
+            theme: Some(config::Theme { exited: Some(String::from("none")), ..Default::default() }),
+            ..Default::default()
+        };
+        let colored = Style::new(&output, true);
+        assert_eq!(colored.paint(Role::Header, "NAME"), "\x1b[1mNAME\x1b[0m");
+        assert_eq!(colored.paint(Role::Attached, "attached"), "\x1b[32mattached\x1b[0m");
+        assert_eq!(colored.paint(Role::Exited, "exited(1)"), "exited(1)");
+        assert_eq!(colored.paint(Role::Alert, ""), "");
+
+        let fields = [("name", String::from("main")), ("pid", String::from("12"))];
+        assert_eq!(super::fields(&plain, &fields), "name:\tmain\npid:\t12\n");
+        assert_eq!(super::fields(&colored, &fields[..1]), "\x1b[1mname:\x1b[0m\tmain\n");
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(100_000);
+        assert_eq!(relative(100_000_000, now), "just now");
+        assert_eq!(relative(99_990_000, now), "10s ago");
+        assert_eq!(relative(100_000_000 - 8_000_000, now), "2h13m ago");
+        assert_eq!(relative(200_000_000, now), "just now");
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00+00:00");
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, PingReply, PingRequest, SessionPing};
+
+use crate::{duration, protocol, protocol::ClientResult};
+
+pub fn run(
+    session: Option<String>,
+    echo: bool,
+    timeout: String,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    if echo && session.is_none() {
+        eprintln!("--echo needs a session to type into");
+        return Err(anyhow!("--echo needs a session to type into"));
+    }
+    let timeout = duration::parse(&timeout).context("parsing timeout")?;
+
+    // Dial by hand rather than with Client::new so that a daemon that
+    // accepts the connection but never says anything can't hang us.
+    let sent_at = SystemTime::now();
+    let stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(err).context("connecting to daemon");
+        }
+    };
+    stream.set_read_timeout(Some(timeout)).context("setting ping timeout")?;
+    let mut client = match protocol::Client::handshake(stream)? {
+        ClientResult::JustClient(c) => c,
+        ClientResult::VersionMismatch { warning, client } => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+    };
+
+    client.require(capability::PING, "ping")?;
+    client
+        .write_connect_header(ConnectHeader::Ping(PingRequest {
+            sent_at_unix_us: unix_us(sent_at),
+            session: session.clone(),
+            echo,
+        }))
+        .context("writing ping request header")?;
+    let reply: PingReply = match client.read_reply() {
+        Ok(reply) => reply,
+        Err(err) => {
+            eprintln!("no answer from the daemon");
+            return Err(err).context("reading reply");
+        }
+    };
+    let round_trip = Duration::from_micros(
+        unix_us(SystemTime::now()).saturating_sub(reply.sent_at_unix_us).max(0) as u64,
+    );
+
+    println!(
+        "pong from shpool v{}, up {}: {}",
+        reply.version,
+        duration::format(Duration::from_millis(reply.uptime_ms)),
+        format_latency(round_trip)
+    );
+
+    let Some(session) = session else {
+        return Ok(());
+    };
+    match reply.session {
+        Some(SessionPing::Answered { reader_us, echo_us }) => {
+            let mut line = format!(
+                "session {}: {} through the daemon",
+                session,
+                format_latency(Duration::from_micros(reader_us))
+            );
+            match echo_us {
+                Some(echo_us) => line.push_str(&format!(
+                    ", {} for the shell to echo",
+                    format_latency(Duration::from_micros(echo_us))
+                )),
+                None if echo => {
+                    println!("{line}");
+                    eprintln!("the shell in '{session}' never echoed anything back");
+                    return Err(anyhow!("the shell in '{}' never echoed anything back", session));
+                }
+                None => {}
+            }
+            println!("{line}");
+            Ok(())
+        }
+        Some(SessionPing::NotFound) => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+        Some(SessionPing::Exited) => {
+            eprintln!("the shell in '{session}' has exited");
+            Err(anyhow!("the shell in '{}' has exited", session))
+        }
+        Some(SessionPing::Locked) => {
+            eprintln!("session '{session}' is locked, not typing into it");
+            Err(anyhow!("session '{}' is locked", session))
+        }
+        Some(SessionPing::Unresponsive) | None => {
+            eprintln!("session '{session}' did not answer");
+            Err(anyhow!("session '{}' did not answer", session))
+        }
+    }
+}
+
+fn unix_us(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_micros() as i64).unwrap_or(0)
+}
+
+/// Format a latency in milliseconds, with enough precision to tell
+/// fast trips apart.
+fn format_latency(d: Duration) -> String {
+    format!("{:.2}ms", d.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency() {
+        assert_eq!(format_latency(Duration::from_micros(0)), "0.00ms");
+        assert_eq!(format_latency(Duration::from_micros(1234)), "1.23ms");
+        assert_eq!(format_latency(Duration::from_secs(2)), "2000.00ms");
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, net, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, PipeReply, PipeRequest};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(
+    session: String,
+    newline: bool,
+    eof: Option<String>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::PIPE, "piping input")?;
+    client
+        .write_connect_header(ConnectHeader::Pipe(PipeRequest { session: session.clone() }))
+        .context("writing pipe request header")?;
+    let reply: PipeReply = client.read_reply().context("reading reply")?;
+    check(&session, reply)?;
+
+    let mut stream = client.into_stream();
+    // If the daemon gives up part way through, it still says why, so
+    // hang on to the error until we have heard from it.
+    let sent =
+        send(&mut stream, &mut io::stdin().lock(), newline, eof.as_deref().map(str::as_bytes));
+    stream.shutdown(net::Shutdown::Write).context("finishing input")?;
+    let reply: PipeReply = protocol::decode_from(&stream).context("reading final reply")?;
+    check(&session, reply)?;
+    sent.context("sending input")?;
+
+    Ok(())
+}
+
+/// Copy the input over, with each line ending in a carriage return if
+/// `newline` is set, followed by `eof` if there is one.
+fn send<W, R>(w: &mut W, input: &mut R, newline: bool, eof: Option<&[u8]>) -> io::Result<()>
+where
+    W: io::Write,
+    R: io::BufRead,
+{
+    if newline {
+        let mut line = vec![];
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let text = line.strip_suffix(b"\n").unwrap_or(&line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            // A pty in raw mode wants a carriage return, same as a
+            // terminal would send for the enter key.
+            w.write_all(text)?;
+            w.write_all(b"\r")?;
+        }
+    } else {
+        io::copy(input, w)?;
+    }
+    if let Some(eof) = eof {
+        w.write_all(eof)?;
+    }
+    w.flush()
+}
+
+fn check(session: &str, reply: PipeReply) -> anyhow::Result<()> {
+    let err = match reply {
+        PipeReply::Ready | PipeReply::Done { .. } => return Ok(()),
+        PipeReply::NotFound => format!("not found: {session}"),
+        PipeReply::Exited => format!("session '{session}' has exited"),
+        PipeReply::Locked => format!("session '{session}' is locked"),
+        PipeReply::Failed(reason) => format!("piping into '{session}': {reason}"),
+    };
+    eprintln!("{err}");
+    Err(anyhow!(err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn framing() -> anyhow::Result<()> {
+        let mut out = vec![];
+        send(&mut out, &mut &b"ls\npwd\r\nexit"[..], true, None)?;
+        assert_eq!(out, b"ls\rpwd\rexit\r");
+
+        let mut out = vec![];
+        send(&mut out, &mut &b"ls\npwd\n"[..], false, Some(b"\x04"))?;
+        assert_eq!(out, b"ls\npwd\n\x04");
+
+        let mut out = vec![];
+        send(&mut out, &mut &b""[..], true, Some(b"EOF\r"))?;
+        assert_eq!(out, b"EOF\r");
+        Ok(())
+    }
+}
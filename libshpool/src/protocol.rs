@@ -14,23 +14,33 @@
 
 use std::{
     cmp,
-    io::{self, Read, Write},
+    io::{self, IoSlice, Read, Write},
     os::unix::net::UnixStream,
     path::Path,
-    sync::atomic::{AtomicI32, Ordering},
-    thread, time,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
 };
 
 use anyhow::{anyhow, Context};
-use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+use byteorder::{LittleEndian, ReadBytesExt as _};
 use serde::{Deserialize, Serialize};
-use shpool_protocol::{Chunk, ChunkKind, ConnectHeader, VersionHeader};
-use tracing::{debug, error, info, instrument, span, trace, warn, Level};
+use shpool_protocol::{
+    AttachReplyHeader, AttachStatus, Chunk, ChunkKind, ConnectHeader, VersionHeader,
+    CHUNK_HEADER_LEN,
+};
+use tracing::{debug, error, info, span, trace, warn, Level};
+
+use super::{consts, logging, term_compat};
 
-use super::{consts, tty};
+const CTRL_C: u8 = 0x03;
 
-const JOIN_POLL_DUR: time::Duration = time::Duration::from_millis(100);
-const JOIN_HANGUP_DUR: time::Duration = time::Duration::from_millis(300);
+// The most iovecs we hand to a single writev call. POSIX only guarantees
+// 16, but every platform we care about allows 1024 (IOV_MAX), and going
+// over makes writev fail with EINVAL.
+const MAX_IOVECS: usize = 1024;
 
 /// The centralized encoding function that should be used for all protocol
 /// serialization.
@@ -79,34 +89,28 @@ impl<'data> ChunkExt<'data> for Chunk<'data> {
     where
         W: std::io::Write,
     {
-        w.write_u8(self.kind as u8)?;
-        if let ChunkKind::ExitStatus = self.kind {
-            assert!(self.buf.len() == 4);
-            // the caller should have already little-endian encoded
-            // the exit status and stuffed it into buf
-        } else {
-            w.write_u32::<LittleEndian>(self.buf.len() as u32)?;
-        }
-        w.write_all(self.buf)?;
-
-        Ok(())
+        write_chunks_vectored(w, std::slice::from_ref(self))
     }
 
     fn read_into<R>(r: &mut R, buf: &'data mut [u8]) -> anyhow::Result<Self>
     where
         R: std::io::Read,
     {
-        let kind = r.read_u8()?;
-        let kind = ChunkKind::try_from(kind)?;
+        // Every kind of chunk has at least CHUNK_HEADER_LEN bytes, so we can
+        // always grab that much in one go rather than issuing separate reads
+        // for the tag and the length.
+        let mut header = [0; CHUNK_HEADER_LEN];
+        r.read_exact(&mut header)?;
+        let kind = ChunkKind::try_from(header[0])?;
         if let ChunkKind::ExitStatus = kind {
             if 4 > buf.len() {
                 return Err(anyhow!("chunk of size 4 exceeds size limit of {} bytes", buf.len()));
             }
 
-            r.read_exact(&mut buf[..4])?;
+            buf[..4].copy_from_slice(&header[1..]);
             Ok(Chunk { kind, buf: &buf[..4] })
         } else {
-            let len = r.read_u32::<LittleEndian>()? as usize;
+            let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
             if len > buf.len() {
                 return Err(anyhow!(
                     "chunk of size {} exceeds size limit of {} bytes",
@@ -120,8 +124,52 @@ impl<'data> ChunkExt<'data> for Chunk<'data> {
     }
 }
 
+/// Write a sequence of chunks using vectored IO so that the headers and
+/// payloads of all the chunks go out in as few syscalls as possible,
+/// without first copying them into a single buffer.
+pub fn write_chunks_vectored<W>(w: &mut W, chunks: &[Chunk<'_>]) -> io::Result<()>
+where
+    W: std::io::Write,
+{
+    let headers: Vec<_> = chunks
+        .iter()
+        .map(|chunk| {
+            if let ChunkKind::ExitStatus = chunk.kind {
+                assert!(chunk.buf.len() == 4);
+            }
+            chunk.header()
+        })
+        .collect();
+
+    let mut slices = Vec::with_capacity(chunks.len() * 2);
+    for (chunk, (header, header_len)) in chunks.iter().zip(headers.iter()) {
+        slices.push(IoSlice::new(&header[..*header_len]));
+        if !chunk.buf.is_empty() {
+            slices.push(IoSlice::new(chunk.buf));
+        }
+    }
+
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let batch = cmp::min(slices.len(), MAX_IOVECS);
+        match w.write_vectored(&slices[..batch]) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write chunks"));
+            }
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
 pub struct Client {
     stream: UnixStream,
+    // What the daemon told us about itself, if we could make sense
+    // of it.
+    daemon: Option<VersionHeader>,
 }
 
 /// The result of creating a client, possibly with
@@ -147,30 +195,42 @@ impl Client {
     #[allow(clippy::new_ret_no_self)]
     pub fn new<P: AsRef<Path>>(sock: P) -> anyhow::Result<ClientResult> {
         let stream = UnixStream::connect(sock).context("connecting to shpool")?;
+        Self::handshake(stream)
+    }
 
+    /// Read the version header the daemon sends as soon as we connect
+    /// and work out whether we can talk to it.
+    pub fn handshake(stream: UnixStream) -> anyhow::Result<ClientResult> {
         let daemon_version: VersionHeader = match decode_from(&stream) {
             Ok(v) => v,
             Err(e) => {
                 warn!("error parsing VersionHeader: {:?}", e);
                 return Ok(ClientResult::VersionMismatch {
                     warning: String::from("could not get daemon version"),
-                    client: Client { stream },
+                    client: Client { stream, daemon: None },
                 });
             }
         };
         info!("read daemon version header: {:?}", daemon_version);
+        let client = Client { stream, daemon: Some(daemon_version.clone()) };
+
+        // Requests will get refused, but hand the client back anyway
+        // so the caller gets to print the warning.
+        if let Some(err) = client.incompatible() {
+            return Ok(ClientResult::VersionMismatch { warning: err, client });
+        }
 
         match Self::version_ord(shpool_protocol::VERSION, &daemon_version.version)
             .context("comparing versions")?
         {
-            cmp::Ordering::Equal => Ok(ClientResult::JustClient(Client { stream })),
+            cmp::Ordering::Equal => Ok(ClientResult::JustClient(client)),
             cmp::Ordering::Less => Ok(ClientResult::VersionMismatch {
                 warning: format!(
                     "client protocol (version {:?}) is older than daemon protocol (version {:?})",
                     shpool_protocol::VERSION,
                     daemon_version.version,
                 ),
-                client: Client { stream },
+                client,
             }),
             cmp::Ordering::Greater => Ok(ClientResult::VersionMismatch {
                 warning: format!(
@@ -178,12 +238,60 @@ impl Client {
                     shpool_protocol::VERSION,
                     daemon_version.version,
                 ),
-                client: Client { stream },
+                client,
             }),
         }
     }
 
+    /// If the daemon speaks a different wire protocol than we do, an
+    /// explanation of why we can't talk to it. Daemons from before the
+    /// protocol was versioned don't say, so we just hope for the best
+    /// with them.
+    pub fn incompatible(&self) -> Option<String> {
+        let daemon = self.daemon.as_ref()?;
+        if daemon.protocol == 0 || daemon.protocol == shpool_protocol::PROTOCOL_VERSION {
+            return None;
+        }
+        Some(format!(
+            "client v{} (protocol {}) / daemon v{} (protocol {}) incompatible, \
+             please restart the daemon",
+            shpool_protocol::VERSION,
+            shpool_protocol::PROTOCOL_VERSION,
+            daemon.version,
+            daemon.protocol,
+        ))
+    }
+
+    /// Make sure the daemon supports the given capability (see
+    /// shpool_protocol::capability) before asking it for something that
+    /// needs it. `what` describes the feature for the error.
+    pub fn require(&self, capability: u64, what: &str) -> anyhow::Result<()> {
+        match &self.daemon {
+            Some(daemon) if daemon.protocol != 0 && daemon.capabilities & capability == 0 => {
+                Err(anyhow!(
+                    "the daemon (v{}) does not support {}, please restart the daemon",
+                    daemon.version,
+                    what
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Give up the client wrapper, for protocols that go beyond a
+    /// simple reply.
+    pub fn into_stream(self) -> UnixStream {
+        self.stream
+    }
+
     pub fn write_connect_header(&self, header: ConnectHeader) -> anyhow::Result<()> {
+        // A takeover is how a daemon gets replaced by a newer one, so it
+        // has to keep working across protocol versions.
+        if let Some(err) = self.incompatible()
+            && !matches!(header, ConnectHeader::Takeover)
+        {
+            return Err(anyhow!(err));
+        }
         encode_to(&header, &self.stream).context("writing reply")?;
         Ok(())
     }
@@ -192,10 +300,53 @@ impl Client {
     where
         R: for<'de> serde::Deserialize<'de>,
     {
-        let reply: R = decode_from(&mut self.stream).context("parsing header")?;
+        let reply: R = decode_from(&mut self.stream)
+            .context("parsing header")
+            .with_context(|| self.mismatch_hint())?;
         Ok(reply)
     }
 
+    /// Replies that don't parse are most likely down to the daemon
+    /// being a different version, so point that out if it is.
+    fn mismatch_hint(&self) -> String {
+        match &self.daemon {
+            Some(daemon) if daemon.version != shpool_protocol::VERSION => format!(
+                "the daemon is running v{} and this is v{}, try restarting the daemon",
+                daemon.version,
+                shpool_protocol::VERSION
+            ),
+            Some(_) => String::from("reading reply from the daemon"),
+            None => String::from("the daemon's version is unknown, try restarting the daemon"),
+        }
+    }
+
+    /// Copy the data chunks of a one-way output stream (as sent after
+    /// an ExecReply) to the given writer until the daemon sends the
+    /// exit status that ends the stream, then return that status.
+    pub fn read_output<W: Write>(mut self, w: &mut W) -> anyhow::Result<i32> {
+        let mut buf = vec![0; consts::BUF_SIZE];
+        loop {
+            let chunk = Chunk::read_into(&mut self.stream, &mut buf).context("reading chunk")?;
+            match chunk.kind {
+                ChunkKind::Data => {
+                    w.write_all(chunk.buf).context("writing output")?;
+                    w.flush().context("flushing output")?;
+                }
+                ChunkKind::ExitStatus => {
+                    let mut status_reader = io::Cursor::new(chunk.buf);
+                    return status_reader
+                        .read_i32::<LittleEndian>()
+                        .context("reading exit status from exit status chunk");
+                }
+                ChunkKind::Heartbeat
+                | ChunkKind::Rename
+                | ChunkKind::Switch
+                | ChunkKind::Switched
+                | ChunkKind::Evicted => {}
+            }
+        }
+    }
+
     /// This is essentially just PartialOrd on client version strings
     /// with more descriptive errors (since PartialOrd gives an option)
     /// and without having to wrap in a newtype.
@@ -233,162 +384,261 @@ impl Client {
         Ok(client_parts[0].cmp(&daemon_parts[0]))
     }
 
-    /// pipe_bytes suffles bytes from std{in,out} to the unix
-    /// socket and back again. It is the main loop of
+    /// pipe_bytes shuffles bytes from the given stdin forwarder to the
+    /// unix socket and from the socket to stdout. It is the main loop of
     /// `shpool attach`.
     ///
-    /// Return value: the exit status that `shpool attach` should
-    /// exit with.
-    #[instrument(skip_all)]
-    pub fn pipe_bytes(self) -> anyhow::Result<i32> {
-        let tty_guard = tty::set_attach_flags()?;
-
-        let mut read_client_stream = self.stream.try_clone().context("cloning read stream")?;
-        let mut write_client_stream = self.stream.try_clone().context("cloning read stream")?;
+    /// A thread blocked reading stdin can't be interrupted, so stdin gets
+    /// read by a forwarder which outlives any one connection. That lets
+    /// the caller reconnect after a dropped connection or attach to
+    /// another session when the user switches without losing any input.
+    /// The caller is responsible for putting the tty in raw mode.
+    ///
+    /// If the daemon tells us the session has been renamed, or that it
+    /// has switched us over to another session in place, the new name
+    /// gets stored in `session_name`. Output gets run through `colors`
+    /// if the session needs its colors downgraded for our terminal.
+    pub fn pipe_bytes(
+        mut self,
+        session_name: &Mutex<String>,
+        stdin: &StdinForwarder,
+        term: &term_compat::Local,
+        colors: Option<term_compat::ColorFilter>,
+    ) -> anyhow::Result<PipeEnd> {
+        *stdin.sink.lock().unwrap() =
+            Some(self.stream.try_clone().context("cloning stream for stdin")?);
 
         let exit_status = AtomicI32::new(1);
-        thread::scope(|s| {
-            // stdin -> sock
-            let stdin_to_sock_h = s.spawn(|| -> anyhow::Result<()> {
-                let _s = span!(Level::INFO, "stdin->sock").entered();
-                let mut stdin = std::io::stdin().lock();
-                let mut buf = vec![0; consts::BUF_SIZE];
+        let got_exit = AtomicBool::new(false);
+        let switch_to = Mutex::new(None);
+        let evicted = Mutex::new(None);
+        let res = sock_to_stdout(
+            &mut self.stream,
+            session_name,
+            term,
+            colors,
+            &exit_status,
+            &got_exit,
+            &switch_to,
+            &evicted,
+        );
+        *stdin.sink.lock().unwrap() = None;
+
+        if !got_exit.load(Ordering::Acquire) {
+            info!("lost connection to daemon: {:?}", res);
+            return Ok(PipeEnd::ConnectionLost);
+        }
+        if let Some(notice) = evicted.into_inner().unwrap() {
+            return Ok(PipeEnd::Evicted(notice));
+        }
+        match switch_to.into_inner().unwrap() {
+            Some(target) => Ok(PipeEnd::Switch(target)),
+            None => Ok(PipeEnd::Exit(exit_status.load(Ordering::Acquire))),
+        }
+    }
+}
 
-                loop {
-                    let nread = stdin.read(&mut buf).context("reading stdin from user")?;
-                    if nread == 0 {
-                        continue;
-                    }
-                    debug!("read {} bytes", nread);
+/// How a call to `pipe_bytes` came to an end.
+#[derive(Debug)]
+pub enum PipeEnd {
+    /// The session is over (or we got detached), and `shpool attach`
+    /// should exit with the given status.
+    Exit(i32),
+    /// The user asked to switch to the given session.
+    Switch(String),
+    /// Another client took the session over, and the daemon said so
+    /// with the given message.
+    Evicted(String),
+    /// The connection to the daemon dropped without the daemon saying
+    /// goodbye.
+    ConnectionLost,
+}
 
-                    let to_write = &buf[..nread];
-                    trace!("created to_write='{}'", String::from_utf8_lossy(to_write));
+/// Copy chunks from the daemon to stdout until the connection ends,
+/// which it always does with an error.
+#[allow(clippy::too_many_arguments)]
+fn sock_to_stdout(
+    stream: &mut UnixStream,
+    session_name: &Mutex<String>,
+    term: &term_compat::Local,
+    mut colors: Option<term_compat::ColorFilter>,
+    exit_status: &AtomicI32,
+    got_exit: &AtomicBool,
+    switch_to: &Mutex<Option<String>>,
+    evicted: &Mutex<Option<String>>,
+) -> anyhow::Result<()> {
+    let _s = span!(Level::INFO, "sock->stdout").entered();
+
+    let mut stdout = std::io::stdout().lock();
+    let mut buf = vec![0; consts::BUF_SIZE];
+    let mut filtered = vec![];
+
+    loop {
+        let chunk = match Chunk::read_into(stream, &mut buf) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("reading chunk: {:?}", err);
+                return Err(err);
+            }
+        };
+
+        if !chunk.buf.is_empty() {
+            debug!(
+                "chunk='{}' kind={:?} len={}",
+                logging::payload(chunk.buf),
+                chunk.kind,
+                chunk.buf.len()
+            );
+        }
 
-                    write_client_stream.write_all(to_write)?;
-                    write_client_stream.flush().context("flushing client")?;
+        match chunk.kind {
+            ChunkKind::Heartbeat => {
+                trace!("got heartbeat chunk");
+            }
+            ChunkKind::Data => {
+                let out = match colors.as_mut() {
+                    Some(colors) => {
+                        filtered.clear();
+                        colors.filter(chunk.buf, &mut filtered);
+                        &filtered[..]
+                    }
+                    None => chunk.buf,
+                };
+                stdout.write_all(out).context("writing chunk to stdout")?;
+
+                if let Err(e) = stdout.flush()
+                    && e.kind() == std::io::ErrorKind::WouldBlock
+                {
+                    // If the fd is busy, we are likely just getting
+                    // flooded with output and don't need to worry about
+                    // flushing every last byte. Flushing is really
+                    // about interactive situations where we want to
+                    // see echoed bytes immediately.
+                    continue;
+                }
+                debug!("flushed stdout");
+            }
+            ChunkKind::ExitStatus => {
+                let mut status_reader = io::Cursor::new(chunk.buf);
+                let stat = status_reader
+                    .read_i32::<LittleEndian>()
+                    .context("reading exit status from exit status chunk")?;
+                info!("got exit status frame (status={})", stat);
+
+                // If detach, output a carriage return
+                // and newline to the original terminal so the shell prompt starts on a new line
+                let _ = stdout.write_all(b"\r\n\n");
+                let _ = stdout.flush();
+
+                exit_status.store(stat, Ordering::Release);
+                got_exit.store(true, Ordering::Release);
+            }
+            ChunkKind::Rename => {
+                let new_name = String::from_utf8_lossy(chunk.buf).into_owned();
+                info!("session renamed to '{}'", new_name);
+                *session_name.lock().unwrap() = new_name;
+            }
+            ChunkKind::Switch => {
+                let target = String::from_utf8_lossy(chunk.buf).into_owned();
+                info!("switching to '{}'", target);
+                *switch_to.lock().unwrap() = Some(target);
+            }
+            ChunkKind::Evicted => {
+                let notice = String::from_utf8_lossy(chunk.buf).into_owned();
+                info!("evicted: {}", notice);
+                *evicted.lock().unwrap() = Some(notice);
+            }
+            ChunkKind::Switched => {
+                let target = String::from_utf8_lossy(chunk.buf).into_owned();
+                let reply: AttachReplyHeader =
+                    decode_from(&mut *stream).context("reading switched attach reply")?;
+                match reply.status {
+                    AttachStatus::Attached { warnings } | AttachStatus::Created { warnings } => {
+                        info!("switched to '{}' in place", target);
+                        for warning in warnings.into_iter() {
+                            warn!("attaching to '{}': {}", target, warning);
+                        }
+                        let (warning, filter) = match &reply.term {
+                            Some(session_term) => term.negotiate(session_term),
+                            None => (None, None),
+                        };
+                        if let Some(warning) = warning {
+                            warn!("attaching to '{}': {}", target, warning);
+                        }
+                        colors = filter;
+                        *session_name.lock().unwrap() = target;
+                    }
+                    status => {
+                        // Dial back in the old fashioned way so that
+                        // attach gets to report the problem.
+                        info!("switching to '{}' in place failed: {:?}", target, status);
+                        *switch_to.lock().unwrap() = Some(target);
+                        got_exit.store(true, Ordering::Release);
+                        return Ok(());
+                    }
                 }
-            });
+            }
+        }
+    }
+}
+
+/// Reads stdin on a dedicated thread and forwards it to whichever daemon
+/// connection is current. A thread blocked reading stdin can't be
+/// interrupted, so when the client reconnects to the daemon it needs to
+/// keep using the same one. Input that arrives while there is no
+/// connection gets dropped, except that a ^C means the user wants to
+/// give up.
+pub struct StdinForwarder {
+    sink: Arc<Mutex<Option<UnixStream>>>,
+    interrupted: Arc<AtomicBool>,
+}
 
-            // sock -> stdout
-            let sock_to_stdout_h = s.spawn(|| -> anyhow::Result<()> {
-                let _s = span!(Level::INFO, "sock->stdout").entered();
+impl StdinForwarder {
+    pub fn spawn() -> anyhow::Result<Self> {
+        let sink: Arc<Mutex<Option<UnixStream>>> = Arc::new(Mutex::new(None));
+        let interrupted = Arc::new(AtomicBool::new(false));
 
-                let mut stdout = std::io::stdout().lock();
+        let thread_sink = Arc::clone(&sink);
+        let thread_interrupted = Arc::clone(&interrupted);
+        thread::Builder::new()
+            .name(String::from("stdin->sock"))
+            .spawn(move || -> anyhow::Result<()> {
+                let _s = span!(Level::INFO, "stdin->sock").entered();
+                let mut stdin = std::io::stdin().lock();
                 let mut buf = vec![0; consts::BUF_SIZE];
 
                 loop {
-                    let chunk = match Chunk::read_into(&mut read_client_stream, &mut buf) {
-                        Ok(c) => c,
-                        Err(err) => {
-                            error!("reading chunk: {:?}", err);
-                            return Err(err);
-                        }
-                    };
-
-                    if !chunk.buf.is_empty() {
-                        debug!(
-                            "chunk='{}' kind={:?} len={}",
-                            String::from_utf8_lossy(chunk.buf),
-                            chunk.kind,
-                            chunk.buf.len()
-                        );
+                    let nread = stdin.read(&mut buf).context("reading stdin from user")?;
+                    if nread == 0 {
+                        continue;
                     }
+                    debug!("read {} bytes", nread);
 
-                    match chunk.kind {
-                        ChunkKind::Heartbeat => {
-                            trace!("got heartbeat chunk");
-                        }
-                        ChunkKind::Data => {
-                            stdout.write_all(chunk.buf).context("writing chunk to stdout")?;
-
-                            if let Err(e) = stdout.flush()
-                                && e.kind() == std::io::ErrorKind::WouldBlock {
-                                    // If the fd is busy, we are likely just getting
-                                    // flooded with output and don't need to worry about
-                                    // flushing every last byte. Flushing is really
-                                    // about interactive situations where we want to
-                                    // see echoed bytes immediately.
-                                    continue;
-                                }
-                            debug!("flushed stdout");
-                        }
-                        ChunkKind::ExitStatus => {
-                            let mut status_reader = io::Cursor::new(chunk.buf);
-                            let stat = status_reader
-                                .read_i32::<LittleEndian>()
-                                .context("reading exit status from exit status chunk")?;
-                            info!("got exit status frame (status={})", stat);
-
-                            // If detach, output a carriage return
-                            // and newline to the original terminal so the shell prompt starts on a new line
+                    let mut sink = thread_sink.lock().unwrap();
+                    match sink.as_mut() {
+                        Some(stream) => {
+                            if let Err(e) =
+                                stream.write_all(&buf[..nread]).and_then(|_| stream.flush())
                             {
-                                use std::io::Write;
-                                let _ = io::stdout().write_all(b"\r\n\n");
-                                let _ = io::stdout().flush();
+                                info!("dropping stdin sink: {:?}", e);
+                                *sink = None;
                             }
-
-                            exit_status.store(stat, Ordering::Release);
                         }
-                    }
-                }
-            });
-
-            loop {
-                let mut nfinished_threads = 0;
-                if stdin_to_sock_h.is_finished() {
-                    nfinished_threads += 1;
-                }
-                if sock_to_stdout_h.is_finished() {
-                    nfinished_threads += 1;
-                }
-                if nfinished_threads > 0 {
-                    if nfinished_threads < 2 {
-                        thread::sleep(JOIN_HANGUP_DUR);
-                        nfinished_threads = 0;
-                        if stdin_to_sock_h.is_finished() {
-                            info!("recheck: stdin->sock thread done");
-                            nfinished_threads += 1;
-                        }
-                        if sock_to_stdout_h.is_finished() {
-                            info!("recheck: sock->stdout thread done");
-                            nfinished_threads += 1;
-                        }
-                        if nfinished_threads < 2 {
-                            // If one of the worker threads is done and the
-                            // other is not exiting, we are likely blocked on
-                            // some IO. Fortunately, since there isn't much else
-                            // going on in the client process and the thing to do
-                            // is to shut down at this point, we can resolve this
-                            // by just hard-exiting the whole process. This allows
-                            // us to use simple blocking IO.
-                            warn!(
-                                "exiting due to a stuck IO thread stdin_to_sock_finished={} sock_to_stdout_finished={}",
-                                stdin_to_sock_h.is_finished(),
-                                sock_to_stdout_h.is_finished()
-                            );
-                            // make sure that we restore the tty flags on the input
-                            // tty before exiting the process.
-                            drop(tty_guard);
-
-                            std::process::exit(exit_status.load(Ordering::Acquire));
+                        None if buf[..nread].contains(&CTRL_C) => {
+                            thread_interrupted.store(true, Ordering::Release);
                         }
+                        None => trace!("no connection, dropping {} bytes of input", nread),
                     }
-                    break;
                 }
-                thread::sleep(JOIN_POLL_DUR);
-            }
+            })
+            .context("spawning stdin thread")?;
 
-            match stdin_to_sock_h.join() {
-                Ok(v) => v?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
-            }
-            match sock_to_stdout_h.join() {
-                Ok(v) => v?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
-            }
+        Ok(StdinForwarder { sink, interrupted })
+    }
 
-            Ok(exit_status.load(Ordering::Acquire))
-        })
+    /// True once the user has hit ^C while we were disconnected.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Acquire)
     }
 }
 
@@ -396,6 +646,65 @@ impl Client {
 mod test {
     use super::*;
 
+    // Returns the daemon's end of the connection too, so that writes
+    // from the client have somewhere to go.
+    fn handshake(daemon: VersionHeader) -> anyhow::Result<(ClientResult, UnixStream)> {
+        let (client_end, mut daemon_end) = UnixStream::pair()?;
+        encode_to(&daemon, &mut daemon_end)?;
+        Ok((Client::handshake(client_end)?, daemon_end))
+    }
+
+    #[test]
+    fn handshake_same_protocol() -> anyhow::Result<()> {
+        let daemon = VersionHeader {
+            version: String::from(shpool_protocol::VERSION),
+            protocol: shpool_protocol::PROTOCOL_VERSION,
+            capabilities: shpool_protocol::capability::EXEC,
+        };
+        let (ClientResult::JustClient(client), _daemon_end) = handshake(daemon)? else {
+            panic!("expected a clean handshake");
+        };
+        assert!(client.incompatible().is_none());
+        client.require(shpool_protocol::capability::EXEC, "exec")?;
+        let err = client.require(shpool_protocol::capability::CAPTURE, "capture").unwrap_err();
+        assert!(err.to_string().contains("does not support capture"));
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_incompatible() -> anyhow::Result<()> {
+        let daemon = VersionHeader {
+            version: String::from("9.0.0"),
+            protocol: shpool_protocol::PROTOCOL_VERSION + 1,
+            capabilities: shpool_protocol::capability::ALL,
+        };
+        let (ClientResult::VersionMismatch { warning, client }, _daemon_end) = handshake(daemon)?
+        else {
+            panic!("expected a mismatch");
+        };
+        assert!(warning.contains("daemon v9.0.0"));
+        assert!(warning.contains("please restart the daemon"));
+        let err = client.write_connect_header(ConnectHeader::List).unwrap_err();
+        assert!(err.to_string().contains("incompatible"));
+        // still allowed so a new daemon can take over from an old one
+        client.write_connect_header(ConnectHeader::Takeover)?;
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_unversioned_daemon() -> anyhow::Result<()> {
+        // daemons from before the protocol was versioned only send
+        // their version string, and get the benefit of the doubt
+        let daemon =
+            VersionHeader { version: String::from(shpool_protocol::VERSION), ..Default::default() };
+        let (ClientResult::JustClient(client), _daemon_end) = handshake(daemon)? else {
+            panic!("expected a clean handshake");
+        };
+        client.require(shpool_protocol::capability::CAPTURE, "capture")?;
+        client.write_connect_header(ConnectHeader::List)?;
+        Ok(())
+    }
+
     #[test]
     fn chunk_round_trip() {
         let data: Vec<u8> = vec![0, 0, 0, 1, 5, 6];
@@ -403,6 +712,10 @@ mod test {
             Chunk { kind: ChunkKind::Data, buf: data.as_slice() },
             Chunk { kind: ChunkKind::Heartbeat, buf: &data[..0] },
             Chunk { kind: ChunkKind::ExitStatus, buf: &data[..4] },
+            Chunk { kind: ChunkKind::Rename, buf: b"new-name" },
+            Chunk { kind: ChunkKind::Switch, buf: b"other" },
+            Chunk { kind: ChunkKind::Switched, buf: b"other" },
+            Chunk { kind: ChunkKind::Evicted, buf: b"taken over" },
         ];
 
         let mut buf = vec![0; 256];
@@ -416,6 +729,46 @@ mod test {
         }
     }
 
+    #[test]
+    fn chunks_vectored_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let status = 7i32.to_le_bytes();
+        let mut chunks = vec![];
+        for block in data.chunks(10) {
+            chunks.push(Chunk { kind: ChunkKind::Data, buf: block });
+        }
+        chunks.push(Chunk { kind: ChunkKind::Heartbeat, buf: &[] });
+        chunks.push(Chunk { kind: ChunkKind::ExitStatus, buf: &status });
+
+        let mut vectored = vec![];
+        write_chunks_vectored(&mut vectored, &chunks).expect("vectored write to succeed");
+
+        let mut one_by_one = vec![];
+        for c in chunks.iter() {
+            c.write_to(&mut one_by_one).expect("write to succeed");
+        }
+        assert_eq!(vectored, one_by_one);
+
+        let mut file_obj = io::Cursor::new(vectored);
+        let mut buf = vec![0; 256];
+        for c in chunks.iter() {
+            let round_tripped =
+                Chunk::read_into(&mut file_obj, &mut buf).expect("parse to succeed");
+            assert_eq!(*c, round_tripped);
+        }
+    }
+
+    #[test]
+    fn chunks_vectored_over_iov_max() {
+        let data = vec![b'x'; MAX_IOVECS * 3];
+        let chunks: Vec<_> =
+            data.chunks(2).map(|block| Chunk { kind: ChunkKind::Data, buf: block }).collect();
+
+        let mut out = vec![];
+        write_chunks_vectored(&mut out, &chunks).expect("vectored write to succeed");
+        assert_eq!(out.len(), chunks.len() * 5 + data.len());
+    }
+
     #[test]
     fn version_ordering_noerr() {
         use std::cmp::Ordering;
@@ -0,0 +1,71 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, RenameReply, RenameRequest};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(from: String, to: String, socket: PathBuf) -> anyhow::Result<()> {
+    if to.is_empty() {
+        eprintln!("blank session names are not allowed");
+        return Err(anyhow!("blank session names are not allowed"));
+    }
+    if to.contains(char::is_whitespace) {
+        eprintln!("whitespace is not allowed in session names");
+        return Err(anyhow!("whitespace is not allowed in session names"));
+    }
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::RENAME, "renaming sessions")?;
+    client
+        .write_connect_header(ConnectHeader::Rename(RenameRequest {
+            from: from.clone(),
+            to: to.clone(),
+        }))
+        .context("writing rename request header")?;
+
+    let reply: RenameReply = client.read_reply().context("reading reply")?;
+    match reply {
+        RenameReply::Renamed => Ok(()),
+        RenameReply::NotFound => {
+            eprintln!("not found: {from}");
+            Err(anyhow!("not found: {}", from))
+        }
+        RenameReply::AlreadyExists => {
+            eprintln!("session '{to}' already exists");
+            Err(anyhow!("session '{}' already exists", to))
+        }
+        RenameReply::InvalidName => {
+            eprintln!("invalid session name '{to}'");
+            Err(anyhow!("invalid session name '{}'", to))
+        }
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A player for terminal recordings in the asciicast v2 format.
+
+  An asciicast v2 file is a line of JSON describing the recording
+  followed by one line per event, each an array of the time since the
+  start in seconds, the event type and its data. Output events ("o")
+  get written to the terminal as they come due, and markers ("m") are
+  places a recording can be paused at or jumped to. Input and resize
+  events don't change what is on screen, so they get skipped.
+
+  Seeking forward just writes out everything in between in one go.
+  Terminals can't go back, so seeking backward resets the terminal and
+  writes out everything from the start up to the new position.
+*/
+
+use std::{
+    fs,
+    io::{self, BufRead, Read as _, Write as _},
+    os::fd::{AsFd as _, AsRawFd as _},
+    path::Path,
+    time,
+};
+
+use anyhow::{anyhow, Context};
+use nix::{poll, unistd::isatty};
+use serde_derive::Deserialize;
+use shpool_protocol::TtySize;
+
+use crate::{tty, tty::TtySizeExt as _};
+
+/// How far the arrow keys seek, in seconds of recording time.
+const SEEK_STEP: f64 = 5.0;
+
+/// Resets the terminal, wiping the screen and any modes the recording
+/// turned on.
+const RESET: &[u8] = b"\x1bc";
+
+/// The longest to wait for input at a time, so that we get to check
+/// whether the next event has come due.
+const MAX_POLL_MS: u16 = 1000;
+
+pub fn run(file: &Path, speed: f64, pause_on_marker: bool) -> anyhow::Result<()> {
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(anyhow!("the speed must be a positive number"));
+    }
+    let f = fs::File::open(file).with_context(|| format!("opening {}", file.display()))?;
+    let cast = Cast::parse(io::BufReader::new(f))
+        .with_context(|| format!("reading recording {}", file.display()))?;
+
+    let stdin = io::stdin();
+    let interactive = isatty(stdin.as_fd()).unwrap_or(false);
+    if let Ok(size) = TtySize::from_fd(io::stdout().as_raw_fd())
+        && (size.cols < cast.width || size.rows < cast.height)
+    {
+        eprintln!(
+            "warning: the recording is {}x{}, but this terminal is only {}x{}",
+            cast.width, cast.height, size.cols, size.rows
+        );
+    }
+    if interactive {
+        eprintln!("space: pause, left/right: seek, ]: next marker, q: quit");
+    }
+
+    let _tty_guard = tty::set_attach_flags()?;
+    let mut stdout = io::stdout().lock();
+    let mut player = Player::new(cast.events);
+    let mut clock = Clock::new(speed);
+    let mut keys = [0; 64];
+    let mut out = vec![];
+
+    while !player.done() {
+        let wait = if clock.paused() {
+            None
+        } else {
+            Some(player.next_time().map(|t| clock.until(t)).unwrap_or(time::Duration::ZERO))
+        };
+
+        if interactive && wait != Some(time::Duration::ZERO) {
+            let timeout = match wait {
+                Some(wait) => poll::PollTimeout::from(
+                    u16::try_from(wait.as_millis()).unwrap_or(u16::MAX).clamp(1, MAX_POLL_MS),
+                ),
+                None => poll::PollTimeout::NONE,
+            };
+            let mut poll_fds = [poll::PollFd::new(stdin.as_fd(), poll::PollFlags::POLLIN)];
+            if poll::poll(&mut poll_fds, timeout).context("waiting for keys")? > 0 {
+                let len = stdin.lock().read(&mut keys).context("reading keys")?;
+                if len == 0 {
+                    // stdin went away, so just play the rest
+                    break;
+                }
+                for key in parse_keys(&keys[..len]) {
+                    match key {
+                        Key::Quit => return Ok(()),
+                        Key::Pause => clock.toggle_pause(),
+                        Key::Seek(delta) => {
+                            let to = (clock.now() + delta).max(0.0);
+                            player.seek(to, &mut out);
+                            clock.set(to);
+                        }
+                        Key::NextMarker => {
+                            if let Some(to) = player.skip_to_marker(&mut out) {
+                                clock.set(to);
+                            }
+                        }
+                    }
+                }
+                stdout.write_all(&out).and_then(|_| stdout.flush()).context("writing output")?;
+                out.clear();
+                continue;
+            }
+        } else if let Some(wait) = wait {
+            std::thread::sleep(wait);
+        }
+
+        if player.next_time().is_some_and(|t| t <= clock.now()) {
+            if let Some(Event::Marker(_)) = player.step(&mut out)
+                && pause_on_marker
+                && interactive
+            {
+                clock.toggle_pause();
+            }
+            stdout.write_all(&out).and_then(|_| stdout.flush()).context("writing output")?;
+            out.clear();
+        }
+    }
+
+    // Let the recording keep its place on screen.
+    stdout.write_all(b"\r\n").and_then(|_| stdout.flush()).context("writing output")?;
+    Ok(())
+}
+
+/// A parsed recording.
+#[derive(Debug)]
+struct Cast {
+    width: u16,
+    height: u16,
+    /// The events that matter for playback, along with the time since
+    /// the start of the recording at which they happened, in order.
+    events: Vec<(f64, Event)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Output(String),
+    Marker(String),
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+    width: u16,
+    height: u16,
+}
+
+impl Cast {
+    fn parse<R: BufRead>(r: R) -> anyhow::Result<Self> {
+        let mut lines = r
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map(|line| !line.trim().is_empty()).unwrap_or(true));
+        let (_, header) = lines.next().ok_or(anyhow!("the recording is empty"))?;
+        let header: Header =
+            serde_json::from_str(&header.context("reading header")?).context("parsing header")?;
+        if header.version != 2 {
+            return Err(anyhow!(
+                "only asciicast version 2 is supported, not version {}",
+                header.version
+            ));
+        }
+
+        let mut events = vec![];
+        for (i, line) in lines {
+            let line = line.with_context(|| format!("reading line {}", i + 1))?;
+            let (t, kind, data): (f64, String, String) = serde_json::from_str(&line)
+                .with_context(|| format!("parsing event on line {}", i + 1))?;
+            let event = match kind.as_str() {
+                "o" => Event::Output(data),
+                "m" => Event::Marker(data),
+                _ => continue,
+            };
+            events.push((t, event));
+        }
+        // Recordings are supposed to be in order already, but don't
+        // fall over if one isn't.
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Cast { width: header.width, height: header.height, events })
+    }
+}
+
+/// Where playback is at in a recording.
+struct Player {
+    events: Vec<(f64, Event)>,
+    /// The index of the next event to play.
+    next: usize,
+}
+
+impl Player {
+    fn new(events: Vec<(f64, Event)>) -> Self {
+        Player { events, next: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.next >= self.events.len()
+    }
+
+    /// When the next event is due, in recording time.
+    fn next_time(&self) -> Option<f64> {
+        self.events.get(self.next).map(|(t, _)| *t)
+    }
+
+    /// Play the next event, adding any output to `out`.
+    fn step(&mut self, out: &mut Vec<u8>) -> Option<&Event> {
+        let (_, event) = self.events.get(self.next)?;
+        self.next += 1;
+        if let Event::Output(data) = event {
+            out.extend_from_slice(data.as_bytes());
+        }
+        Some(event)
+    }
+
+    /// Move to the given recording time, adding whatever it takes to
+    /// get the screen there to `out`.
+    fn seek(&mut self, to: f64, out: &mut Vec<u8>) {
+        if self.next > 0 && to < self.events[self.next - 1].0 {
+            out.extend_from_slice(RESET);
+            self.next = 0;
+        }
+        while self.next_time().is_some_and(|t| t <= to) {
+            self.step(out);
+        }
+    }
+
+    /// Jump to just past the next marker, returning its time.
+    fn skip_to_marker(&mut self, out: &mut Vec<u8>) -> Option<f64> {
+        let offset =
+            self.events[self.next..].iter().position(|(_, e)| matches!(e, Event::Marker(_)))?;
+        let to = self.events[self.next + offset].0;
+        for _ in 0..=offset {
+            self.step(out);
+        }
+        Some(to)
+    }
+}
+
+/// Keeps track of the recording time, which runs `speed` times as
+/// fast as the real one.
+struct Clock {
+    speed: f64,
+    /// The recording time as of `since`.
+    at: f64,
+    /// When the clock was last set or resumed, or None if it is
+    /// paused.
+    since: Option<time::Instant>,
+}
+
+impl Clock {
+    fn new(speed: f64) -> Self {
+        Clock { speed, at: 0.0, since: Some(time::Instant::now()) }
+    }
+
+    fn now(&self) -> f64 {
+        match self.since {
+            Some(since) => self.at + since.elapsed().as_secs_f64() * self.speed,
+            None => self.at,
+        }
+    }
+
+    fn set(&mut self, at: f64) {
+        self.at = at;
+        if self.since.is_some() {
+            self.since = Some(time::Instant::now());
+        }
+    }
+
+    fn paused(&self) -> bool {
+        self.since.is_none()
+    }
+
+    fn toggle_pause(&mut self) {
+        self.at = self.now();
+        self.since = match self.since {
+            Some(_) => None,
+            None => Some(time::Instant::now()),
+        };
+    }
+
+    /// How long until the given recording time comes around.
+    fn until(&self, t: f64) -> time::Duration {
+        time::Duration::from_secs_f64(((t - self.now()) / self.speed).max(0.0))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Quit,
+    Pause,
+    Seek(f64),
+    NextMarker,
+}
+
+fn parse_keys(mut bytes: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+    while !bytes.is_empty() {
+        let (key, len) = match bytes {
+            [b'\x1b', b'[', b'C', ..] | [b'\x1b', b'O', b'C', ..] => {
+                (Some(Key::Seek(SEEK_STEP)), 3)
+            }
+            [b'\x1b', b'[', b'D', ..] | [b'\x1b', b'O', b'D', ..] => {
+                (Some(Key::Seek(-SEEK_STEP)), 3)
+            }
+            [b'q' | b'\x03', ..] => (Some(Key::Quit), 1),
+            [b' ', ..] => (Some(Key::Pause), 1),
+            [b']', ..] => (Some(Key::NextMarker), 1),
+            _ => (None, 1),
+        };
+        keys.extend(key);
+        bytes = &bytes[len..];
+    }
+    keys
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CAST: &str = r#"{"version": 2, "width": 80, "height": 24}
+[0.5, "o", "hello "]
+[1.0, "i", "x"]
+[1.5, "m", "middle"]
+
+[2.0, "o", "world"]
+"#;
+
+    #[test]
+    fn parse() -> anyhow::Result<()> {
+        let cast = Cast::parse(CAST.as_bytes())?;
+        assert_eq!((cast.width, cast.height), (80, 24));
+        assert_eq!(
+            cast.events,
+            vec![
+                (0.5, Event::Output(String::from("hello "))),
+                (1.5, Event::Marker(String::from("middle"))),
+                (2.0, Event::Output(String::from("world"))),
+            ]
+        );
+
+        assert!(Cast::parse(r#"{"version": 1, "width": 80, "height": 24}"#.as_bytes()).is_err());
+        assert!(Cast::parse("".as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn seek() -> anyhow::Result<()> {
+        let mut player = Player::new(Cast::parse(CAST.as_bytes())?.events);
+        let mut out = vec![];
+
+        player.seek(1.0, &mut out);
+        assert_eq!(out, b"hello ");
+
+        out.clear();
+        player.seek(3.0, &mut out);
+        assert_eq!(out, b"world");
+        assert!(player.done());
+
+        // going back replays everything from the start
+        out.clear();
+        player.seek(0.7, &mut out);
+        assert_eq!(out, b"\x1bchello ");
+
+        out.clear();
+        assert_eq!(player.skip_to_marker(&mut out), Some(1.5));
+        assert!(out.is_empty());
+        assert_eq!(player.skip_to_marker(&mut out), None);
+        Ok(())
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            parse_keys(b" \x1b[C\x1b[Dx]q"),
+            vec![
+                Key::Pause,
+                Key::Seek(SEEK_STEP),
+                Key::Seek(-SEEK_STEP),
+                Key::NextMarker,
+                Key::Quit
+            ]
+        );
+    }
+}
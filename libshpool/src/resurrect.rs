@@ -0,0 +1,359 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Recreating sessions after a reboot.
+
+  Shells can't survive a reboot, but the workspace they made up can be
+  set up again. The daemon keeps a manifest of its sessions in the
+  state dir ($XDG_STATE_HOME/shpool, or ~/.local/state/shpool), which
+  unlike the runtime dir sticks around across reboots. For each session
+  it records the name and tags, how the session was started (its
+  command, startup command and extra environment variables), and the
+  directory the shell was last in.
+
+  When a fresh daemon starts up, it moves the manifest the last daemon
+  left behind out of the way so that it does not get overwritten.
+  `shpool resurrect` then creates each of those sessions again,
+  detached, as a new shell in the same directory.
+
+  Sessions with a disk backed spool are the exception. If one of those
+  left a checkpoint behind in the runtime dir, which does not survive a
+  reboot, the last daemon crashed or got restarted, and the new daemon
+  recreates the session itself so its scrollback comes right back.
+*/
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use serde_derive::{Deserialize, Serialize};
+use shpool_protocol::{AttachHeader, AttachStatus};
+use tracing::{info, warn};
+
+use crate::{attach, config, session_restore, up};
+
+/// The manifest of the running daemon's sessions.
+pub const MANIFEST_FILE: &str = "sessions.json";
+/// The manifest left behind by the previous daemon, which is what
+/// `shpool resurrect` reads.
+pub const RESURRECT_FILE: &str = "resurrect.json";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    #[serde(default)]
+    pub sessions: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub recipe: Recipe,
+}
+
+/// How a session was started, which is what it takes to start it
+/// over again.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Recipe {
+    #[serde(default)]
+    pub cmd: Option<String>,
+    #[serde(default)]
+    pub startup: Option<String>,
+    /// The directory to start in. In the manifest, this is where the
+    /// shell was when the manifest was last written.
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// The variables the session was explicitly given, as opposed to
+    /// ones forwarded from whatever terminal attached to it.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// The shell the session ran, if it was not the default one.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// The restart policy for `cmd`, if it has one.
+    #[serde(default)]
+    pub restart: Option<String>,
+}
+
+impl Recipe {
+    pub fn from_header(header: &AttachHeader) -> Self {
+        Recipe {
+            cmd: header.cmd.clone(),
+            startup: header.startup.clone(),
+            dir: header.working_directory.clone(),
+            env: header.env.clone(),
+            shell: header.shell.clone(),
+            restart: header.restart.clone(),
+        }
+    }
+}
+
+/// The base directory for state that should survive a reboot.
+pub fn base_state_dir() -> anyhow::Result<PathBuf> {
+    let state_dir = match std::env::var("XDG_STATE_HOME") {
+        Ok(state_dir) if !state_dir.is_empty() => PathBuf::from(state_dir),
+        _ => PathBuf::from(std::env::var("HOME").context("no XDG_STATE_HOME or HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Ok(state_dir.join("shpool"))
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Option<Manifest>> {
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("reading manifest"),
+    };
+    Ok(Some(serde_json::from_str(&src).context("parsing manifest")?))
+}
+
+/// Write out the manifest. We write to a temporary file and rename it
+/// into place so that a crash or power loss halfway through never
+/// leaves a truncated manifest behind.
+pub fn save(path: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("creating state dir")?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .context("opening manifest")?;
+    let json = serde_json::to_string_pretty(manifest).context("formatting manifest")?;
+    file.write_all(json.as_bytes()).context("writing manifest")?;
+    file.sync_all().context("syncing manifest")?;
+    fs::rename(&tmp_path, path).context("moving manifest into place")?;
+    Ok(())
+}
+
+/// Move the manifest left behind by the previous daemon out of the
+/// way so that `shpool resurrect` can find it. A manifest without any
+/// sessions in it is left alone, so that a daemon that gets restarted
+/// before the user has had a chance to resurrect does not lose track
+/// of the sessions.
+pub fn set_aside(state_dir: &Path) {
+    let path = state_dir.join(MANIFEST_FILE);
+    match load(&path) {
+        Ok(Some(manifest)) if !manifest.sessions.is_empty() => {
+            info!("setting aside {} sessions to resurrect", manifest.sessions.len());
+            if let Err(e) = fs::rename(&path, state_dir.join(RESURRECT_FILE)) {
+                warn!("setting aside manifest: {:?}", e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("loading manifest: {:?}", e),
+    }
+}
+
+/// The names of the sessions waiting for `shpool resurrect`.
+pub fn pending(state_dir: &Path) -> HashSet<String> {
+    match load(&state_dir.join(RESURRECT_FILE)) {
+        Ok(manifest) => manifest
+            .map(|m| m.sessions.into_iter().map(|entry| entry.name).collect())
+            .unwrap_or_default(),
+        Err(e) => {
+            warn!("loading sessions to resurrect: {:?}", e);
+            HashSet::new()
+        }
+    }
+}
+
+/// Take the sessions that left a spool checkpoint behind in the given
+/// sessions dir out of the manifest waiting for `shpool resurrect`.
+/// Those went away with the last daemon rather than with a reboot, so
+/// the daemon brings them back itself, reloading their restore buffers
+/// from the checkpoints.
+pub fn take_checkpointed(state_dir: &Path, sessions_dir: &Path) -> Vec<AttachHeader> {
+    let path = state_dir.join(RESURRECT_FILE);
+    let manifest = match load(&path) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return vec![],
+        Err(e) => {
+            warn!("loading sessions to resurrect: {:?}", e);
+            return vec![];
+        }
+    };
+    let (checkpointed, rest): (Vec<_>, Vec<_>) = manifest.sessions.into_iter().partition(|entry| {
+        sessions_dir.join(&entry.name).join(session_restore::disk::CHECKPOINT_FILE).exists()
+    });
+    if checkpointed.is_empty() {
+        return vec![];
+    }
+
+    let res = if rest.is_empty() {
+        fs::remove_file(&path).context("removing manifest")
+    } else {
+        save(&path, &Manifest { sessions: rest })
+    };
+    if let Err(e) = res {
+        warn!("updating sessions to resurrect: {:?}", e);
+    }
+    headers(Manifest { sessions: checkpointed })
+}
+
+/// Create the given sessions again by dialing the daemon's own socket,
+/// the same way `shpool resurrect` would.
+pub fn recreate(config: &config::Config, headers: Vec<AttachHeader>, socket: &Path) {
+    let local_env = attach::local_env(config);
+    let tty_size = attach::local_tty_size();
+    let mut warned = false;
+    for mut header in headers {
+        header.local_env = local_env.clone();
+        header.local_tty_size = tty_size.clone();
+        let name = header.name.clone();
+        match up::create(socket, header, &mut warned) {
+            Ok(AttachStatus::Created { .. }) => info!("resurrected '{}'", name),
+            Ok(status) => warn!("resurrecting '{}': {:?}", name, status),
+            Err(e) => warn!("resurrecting '{}': {:?}", name, e),
+        }
+    }
+}
+
+pub fn run(
+    config_manager: config::Manager,
+    state_dir: PathBuf,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let path = state_dir.join(RESURRECT_FILE);
+    let manifest = load(&path)?.ok_or(anyhow!("no sessions to resurrect"))?;
+    if manifest.sessions.is_empty() {
+        return Err(anyhow!("no sessions to resurrect"));
+    }
+
+    up::create_detached(&config_manager.get(), headers(manifest), &socket)?;
+
+    // Everything is back up, so running this again should not
+    // recreate sessions the user has since killed.
+    fs::remove_file(&path).context("removing resurrected manifest")?;
+    Ok(())
+}
+
+fn headers(manifest: Manifest) -> Vec<AttachHeader> {
+    manifest
+        .sessions
+        .into_iter()
+        .map(|entry| AttachHeader {
+            name: entry.name,
+            tags: entry.tags,
+            cmd: entry.recipe.cmd,
+            startup: entry.recipe.startup,
+            env: entry.recipe.env,
+            shell: entry.recipe.shell,
+            restart: entry.recipe.restart,
+            // The directory might have been something like /tmp that
+            // did not survive the reboot, in which case the session
+            // starts in the default directory.
+            working_directory: entry.recipe.dir.filter(|dir| Path::new(dir).is_dir()),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            sessions: vec![
+                Entry {
+                    name: String::from("editor"),
+                    tags: vec![String::from("work")],
+                    recipe: Recipe {
+                        cmd: Some(String::from("nvim")),
+                        dir: Some(String::from("/")),
+                        env: vec![(String::from("EDITOR"), String::from("nvim"))],
+                        ..Default::default()
+                    },
+                },
+                Entry {
+                    name: String::from("scratch"),
+                    tags: vec![],
+                    recipe: Recipe {
+                        dir: Some(String::from("/does/not/exist")),
+                        ..Default::default()
+                    },
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn set_aside_and_load() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_dir = tmp_dir.path().join("shpool");
+
+        save(&state_dir.join(MANIFEST_FILE), &manifest())?;
+        set_aside(&state_dir);
+        assert!(!state_dir.join(MANIFEST_FILE).exists());
+        assert_eq!(load(&state_dir.join(RESURRECT_FILE))?, Some(manifest()));
+
+        // an empty manifest does not clobber the one set aside
+        save(&state_dir.join(MANIFEST_FILE), &Manifest::default())?;
+        set_aside(&state_dir);
+        assert!(state_dir.join(MANIFEST_FILE).exists());
+        assert_eq!(load(&state_dir.join(RESURRECT_FILE))?, Some(manifest()));
+
+        assert_eq!(load(&tmp_dir.path().join("nope.json"))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn take_checkpointed_sessions() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_dir = tmp_dir.path().join("shpool");
+        let sessions_dir = tmp_dir.path().join("sessions");
+        fs::create_dir_all(sessions_dir.join("scratch"))?;
+        fs::write(sessions_dir.join("scratch").join(session_restore::disk::CHECKPOINT_FILE), b"")?;
+
+        save(&state_dir.join(RESURRECT_FILE), &manifest())?;
+        let headers = take_checkpointed(&state_dir, &sessions_dir);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].name, "scratch");
+        assert_eq!(pending(&state_dir), HashSet::from([String::from("editor")]));
+
+        // taking the last one removes the manifest altogether
+        fs::create_dir_all(sessions_dir.join("editor"))?;
+        fs::write(sessions_dir.join("editor").join(session_restore::disk::CHECKPOINT_FILE), b"")?;
+        assert_eq!(take_checkpointed(&state_dir, &sessions_dir).len(), 1);
+        assert!(!state_dir.join(RESURRECT_FILE).exists());
+        assert!(take_checkpointed(&state_dir, &sessions_dir).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resurrect_headers() {
+        let headers = headers(manifest());
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].name, "editor");
+        assert_eq!(headers[0].tags, vec![String::from("work")]);
+        assert_eq!(headers[0].cmd.as_deref(), Some("nvim"));
+        assert_eq!(headers[0].working_directory.as_deref(), Some("/"));
+        assert_eq!(headers[0].env, vec![(String::from("EDITOR"), String::from("nvim"))]);
+        assert_eq!(headers[1].working_directory, None);
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Noticing when a full screen program is running.
+
+  Full screen programs like vim and htop switch the terminal over to the
+  alternate screen and then paint it with cursor movements and partial
+  updates. Replaying the raw output that built up such a screen onto a
+  terminal of a different size (or just in a different state) makes a
+  mess, so the raw output spools watch for the sequences that enter and
+  leave the alternate screen. While a program is on the alternate
+  screen, the restore buffer just switches the client over to a blank
+  alternate screen, and the resize that happens on every reattach
+  gets the program to repaint itself.
+*/
+
+/// What to send in place of the raw output while a program is on
+/// the alternate screen: switch to it, home the cursor and clear it.
+pub const REDRAW: &[u8] = b"\x1b[?1049h\x1b[H\x1b[2J";
+
+// The private modes that switch to the alternate screen. 1049 is what
+// modern programs use, the others are older variants.
+const ALT_SCREEN_MODES: [&[u8]; 3] = [b"1049", b"1047", b"47"];
+
+// Real mode setting sequences are short, so anything longer than this
+// is garbage we don't need to hang on to.
+const MAX_PARAMS_LEN: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    Csi,
+    PrivateCsi,
+}
+
+/// Tracks whether the output so far has left the terminal on the
+/// alternate screen. Sequences can be split across calls to process.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    state: State,
+    params: Vec<u8>,
+    active: bool,
+}
+
+impl Tracker {
+    /// True if the terminal is on the alternate screen.
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn process(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state = match (self.state, *byte) {
+                (_, 0x1b) => State::Esc,
+                (State::Ground, _) => State::Ground,
+                (State::Esc, b'[') => {
+                    self.params.clear();
+                    State::Csi
+                }
+                (State::Esc, _) => State::Ground,
+                (State::Csi, b'?') if self.params.is_empty() => State::PrivateCsi,
+                (State::Csi, 0x30..=0x3f) => State::Csi,
+                (State::Csi, _) => State::Ground,
+                (State::PrivateCsi, b'h' | b'l') => {
+                    if self.params.split(|b| *b == b';').any(|p| ALT_SCREEN_MODES.contains(&p)) {
+                        self.active = *byte == b'h';
+                    }
+                    State::Ground
+                }
+                (State::PrivateCsi, 0x30..=0x3f) if self.params.len() < MAX_PARAMS_LEN => {
+                    self.params.push(*byte);
+                    State::PrivateCsi
+                }
+                (State::PrivateCsi, _) => State::Ground,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enter_and_exit() {
+        let mut tracker = Tracker::default();
+        tracker.process(b"$ vim\r\n\x1b[?1049h\x1b[22;0;0t\x1b[H\x1b[2J");
+        assert!(tracker.active());
+        tracker.process(b"\x1b[?25l~\r\n~\r\n");
+        assert!(tracker.active());
+        tracker.process(b"\x1b[?1049l\x1b[23;0;0t$ ");
+        assert!(!tracker.active());
+    }
+
+    #[test]
+    fn split_sequences() {
+        let mut tracker = Tracker::default();
+        for chunk in [&b"\x1b"[..], b"[?", b"10", b"49", b"h"] {
+            tracker.process(chunk);
+        }
+        assert!(tracker.active());
+
+        tracker.process(b"\x1b[?1;47");
+        assert!(tracker.active());
+        tracker.process(b"l");
+        assert!(!tracker.active());
+    }
+
+    #[test]
+    fn ignores_other_modes() {
+        let mut tracker = Tracker::default();
+        tracker.process(b"\x1b[?2004h\x1b[1049h\x1b[?10490h?1049h");
+        assert!(!tracker.active());
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A restore spool that keeps its buffer compressed.
+
+  Terminal output compresses very well, so for users with large restore
+  buffers it is a waste to keep the raw bytes around in every session.
+  The CompressedSpool collects output into an uncompressed tail chunk,
+  and once that chunk fills up it gets compressed into a zstd frame and
+  pushed onto a queue of frames. Old frames are dropped from the front
+  of the queue as new output comes in, so trimming happens at chunk
+  granularity, and everything only gets decompressed when a client
+  actually needs the restore buffer.
+*/
+
+use std::collections::VecDeque;
+
+use shpool_protocol::TtySize;
+use tracing::{info, warn};
+
+use super::{alt_screen, clean_start, cursor, SessionSpool};
+
+// How much raw output to collect before compressing it into a frame.
+// Bigger chunks compress better but mean more uncompressed data sitting
+// around in the tail.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Terminal output is repetitive enough that a fast level does fine.
+const COMPRESSION_LEVEL: i32 = 3;
+
+struct Frame {
+    data: Vec<u8>,
+    raw_len: usize,
+}
+
+pub struct CompressedSpool {
+    frames: VecDeque<Frame>,
+    tail: Vec<u8>,
+    max_size: usize,
+    // the total uncompressed size of all the frames
+    frames_raw_len: usize,
+    alt_screen: alt_screen::Tracker,
+    cursor: cursor::Tracker,
+}
+
+impl CompressedSpool {
+    pub fn new(max_size: usize) -> Self {
+        CompressedSpool {
+            frames: VecDeque::new(),
+            tail: vec![],
+            max_size,
+            frames_raw_len: 0,
+            alt_screen: alt_screen::Tracker::default(),
+            cursor: cursor::Tracker::default(),
+        }
+    }
+
+    fn compress_tail(&mut self) {
+        match zstd::bulk::compress(&self.tail, COMPRESSION_LEVEL) {
+            Ok(mut data) => {
+                // the output buffer gets allocated at the worst case size
+                data.shrink_to_fit();
+                self.frames_raw_len += self.tail.len();
+                self.frames.push_back(Frame { data, raw_len: self.tail.len() });
+                self.tail = Vec::with_capacity(CHUNK_SIZE);
+            }
+            // leave the tail as it is and try again with the next chunk of output
+            Err(e) => warn!("compressing spool chunk: {:?}", e),
+        }
+    }
+
+    /// Drop whole frames from the front so long as we would still have
+    /// at least max_size bytes of output left over.
+    fn trim(&mut self) {
+        while let Some(front) = self.frames.front() {
+            if self.frames_raw_len + self.tail.len() - front.raw_len < self.max_size {
+                break;
+            }
+            self.frames_raw_len -= front.raw_len;
+            self.frames.pop_front();
+        }
+    }
+}
+
+impl SessionSpool for CompressedSpool {
+    fn resize(&mut self, size: TtySize) {
+        self.cursor.resize(&size);
+    }
+
+    fn restore_buffer(&self) -> Vec<u8> {
+        if self.alt_screen.active() {
+            info!("full screen program running, restoring a blank alternate screen");
+            return alt_screen::REDRAW.to_vec();
+        }
+        self.contents()
+    }
+
+    fn cursor_fixup(&self) -> Vec<u8> {
+        if self.alt_screen.active() {
+            vec![]
+        } else {
+            self.cursor.fixup()
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.frames_raw_len + self.tail.len());
+        for frame in self.frames.iter() {
+            match zstd::bulk::decompress(&frame.data, frame.raw_len) {
+                Ok(raw) => buf.extend_from_slice(&raw),
+                Err(e) => warn!("decompressing spool chunk: {:?}", e),
+            }
+        }
+        buf.extend_from_slice(&self.tail);
+        if buf.len() > self.max_size {
+            let start = buf.len() - self.max_size;
+            buf.drain(..start + clean_start(&buf[start..], buf[..start].ends_with(b"\n")));
+        }
+        info!(
+            "computing compressed restore buf with {} bytes from {} frames",
+            buf.len(),
+            self.frames.len()
+        );
+        buf
+    }
+
+    fn process(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.alt_screen.process(bytes);
+        self.cursor.process(bytes);
+        self.tail.extend_from_slice(bytes);
+        if self.tail.len() >= CHUNK_SIZE {
+            self.compress_tail();
+        }
+        self.trim();
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.tail.capacity() + self.frames.iter().map(|f| f.data.capacity()).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut spool = CompressedSpool::new(10 * 1024 * 1024);
+        let mut want = vec![];
+        for i in 0..10000 {
+            let line = format!("line {i}: some fairly repetitive shell output\r\n");
+            spool.process(line.as_bytes());
+            want.extend_from_slice(line.as_bytes());
+        }
+        assert!(!spool.frames.is_empty());
+        assert!(spool.memory_usage() < want.len() / 4);
+        assert_eq!(spool.restore_buffer(), want);
+    }
+
+    #[test]
+    fn trims_to_max_size() {
+        let mut spool = CompressedSpool::new(100);
+        spool.process(b"hello");
+        assert_eq!(spool.restore_buffer(), b"hello");
+
+        spool.process(&vec![b'x'; CHUNK_SIZE * 3]);
+        spool.process(b"end");
+        assert_eq!(spool.frames.len(), 1);
+        let buf = spool.restore_buffer();
+        assert_eq!(buf.len(), 100);
+        assert!(buf.ends_with(b"xxend"));
+    }
+}
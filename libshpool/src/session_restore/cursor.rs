@@ -0,0 +1,519 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Putting the cursor back where it was after a replay.
+
+  The raw output spools replay the most recent output as is, which
+  leaves the cursor wherever that output happens to leave it. That is
+  usually the bottom of the screen, but if the program had moved it
+  somewhere else using sequences that did not make it into the replay
+  (readline redrawing a multi-line prompt, say), the cursor ends up in
+  the wrong place, and so do the colors and other text attributes.
+
+  To fix that up, the raw output spools watch their output for the
+  sequences that move the cursor and set text attributes. This is
+  nowhere near a full terminal emulator: it does not keep the screen
+  contents around, and it treats every character as a single column
+  except for the common double width ranges. That is enough to work
+  out where the cursor is for ordinary shell output, and after the
+  replay the client gets sent the cursor position and attributes.
+*/
+
+use shpool_protocol::TtySize;
+
+// Real sequences have short parameter lists, so anything longer than
+// this is garbage we don't need to hang on to.
+const MAX_PARAMS_LEN: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    // an escape sequence with intermediate bytes, like a charset
+    // designation, which is waiting for its final byte
+    EscIntermediate,
+    Csi,
+    // an OSC, DCS or similar string, which runs until ST or BEL
+    Str,
+    StrEsc,
+}
+
+/// Tracks where the output so far has left the cursor, and the text
+/// attributes it has left in effect. Sequences can be split across
+/// calls to process.
+#[derive(Debug)]
+pub struct Tracker {
+    rows: u16,
+    cols: u16,
+    row: u16,
+    col: u16,
+    // Set after a character lands in the last column. The terminal
+    // only wraps once the next character comes in.
+    wrap_pending: bool,
+    // the bottom row of the scroll region
+    scroll_bottom: u16,
+    saved: (u16, u16),
+    hidden: bool,
+    attrs: Attrs,
+    state: State,
+    params: Vec<u8>,
+    private: bool,
+    // a partially decoded UTF-8 character, and how many more bytes
+    // of it are still to come
+    codepoint: u32,
+    utf8_remaining: u8,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Tracker {
+            rows: 24,
+            cols: 80,
+            row: 0,
+            col: 0,
+            wrap_pending: false,
+            scroll_bottom: 23,
+            saved: (0, 0),
+            hidden: false,
+            attrs: Attrs::default(),
+            state: State::Ground,
+            params: vec![],
+            private: false,
+            codepoint: 0,
+            utf8_remaining: 0,
+        }
+    }
+}
+
+impl Tracker {
+    pub fn resize(&mut self, size: &TtySize) {
+        // A zero sized tty, which we see if the client is not attached
+        // to a real one, is treated as a single cell.
+        self.rows = std::cmp::max(size.rows, 1);
+        self.cols = std::cmp::max(size.cols, 1);
+        self.row = std::cmp::min(self.row, self.rows - 1);
+        self.col = std::cmp::min(self.col, self.cols - 1);
+        self.scroll_bottom = self.rows - 1;
+        self.wrap_pending = false;
+    }
+
+    /// Sequences that move the cursor to where the output left it and
+    /// re-apply the text attributes that were in effect there.
+    pub fn fixup(&self) -> Vec<u8> {
+        let mut fixup = format!("\x1b[{};{}H", self.row + 1, self.col + 1);
+        fixup.push_str(if self.hidden { "\x1b[?25l" } else { "\x1b[?25h" });
+        fixup.push_str(&self.attrs.formatted());
+        fixup.into_bytes()
+    }
+
+    pub fn process(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state = match self.state {
+                State::Ground => self.ground(*byte),
+                State::Esc => self.esc(*byte),
+                State::EscIntermediate => match byte {
+                    0x20..=0x2f => State::EscIntermediate,
+                    _ => State::Ground,
+                },
+                State::Csi => self.csi(*byte),
+                State::Str => match byte {
+                    0x07 => State::Ground,
+                    0x1b => State::StrEsc,
+                    _ => State::Str,
+                },
+                // ESC \ is the string terminator, anything else starts
+                // a new escape sequence
+                State::StrEsc if *byte == b'\\' => State::Ground,
+                State::StrEsc => self.esc(*byte),
+            };
+        }
+    }
+
+    fn ground(&mut self, byte: u8) -> State {
+        match byte {
+            0x1b => return State::Esc,
+            b'\r' => {
+                self.col = 0;
+                self.wrap_pending = false;
+            }
+            b'\n' | 0x0b | 0x0c => self.line_feed(),
+            0x08 => {
+                self.col = self.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            b'\t' => {
+                self.col = std::cmp::min((self.col / 8 + 1) * 8, self.cols - 1);
+            }
+            0x20..=0x7e => self.print(1),
+            0x80..=0xbf if self.utf8_remaining > 0 => {
+                self.codepoint = (self.codepoint << 6) | (byte & 0x3f) as u32;
+                self.utf8_remaining -= 1;
+                if self.utf8_remaining == 0 {
+                    self.print(width(self.codepoint));
+                }
+            }
+            0xc0..=0xdf => self.start_utf8(byte & 0x1f, 1),
+            0xe0..=0xef => self.start_utf8(byte & 0x0f, 2),
+            0xf0..=0xf7 => self.start_utf8(byte & 0x07, 3),
+            // other control characters and stray bytes don't move the cursor
+            _ => {}
+        }
+        State::Ground
+    }
+
+    fn esc(&mut self, byte: u8) -> State {
+        match byte {
+            b'[' => {
+                self.params.clear();
+                self.private = false;
+                return State::Csi;
+            }
+            b']' | b'P' | b'X' | b'^' | b'_' => return State::Str,
+            0x20..=0x2f => return State::EscIntermediate,
+            b'7' => self.saved = (self.row, self.col),
+            b'8' => self.restore(),
+            b'D' => self.line_feed(),
+            b'E' => {
+                self.col = 0;
+                self.line_feed();
+            }
+            b'M' => self.row = self.row.saturating_sub(1),
+            b'c' => {
+                let size = TtySize { rows: self.rows, cols: self.cols, xpixel: 0, ypixel: 0 };
+                *self = Tracker::default();
+                self.resize(&size);
+            }
+            0x1b => return State::Esc,
+            _ => {}
+        }
+        State::Ground
+    }
+
+    fn csi(&mut self, byte: u8) -> State {
+        match byte {
+            b'?' | b'<' | b'=' | b'>' if self.params.is_empty() && !self.private => {
+                self.private = true;
+                // only DEC private modes are of any interest
+                if byte != b'?' {
+                    self.params.push(b'x');
+                }
+                State::Csi
+            }
+            0x30..=0x3f => {
+                if self.params.len() < MAX_PARAMS_LEN {
+                    self.params.push(byte);
+                }
+                State::Csi
+            }
+            0x20..=0x2f => State::Csi,
+            0x40..=0x7e => {
+                self.dispatch(byte);
+                State::Ground
+            }
+            0x1b => State::Esc,
+            _ => State::Csi,
+        }
+    }
+
+    fn dispatch(&mut self, final_byte: u8) {
+        if self.private {
+            if matches!(final_byte, b'h' | b'l') {
+                let set = final_byte == b'h';
+                for param in self.params.clone().split(|b| *b == b';') {
+                    match param {
+                        b"25" => self.hidden = !set,
+                        // entering the alternate screen saves the cursor,
+                        // and leaving it restores it
+                        b"1049" if set => self.saved = (self.row, self.col),
+                        b"1049" => self.restore(),
+                        _ => {}
+                    }
+                }
+            }
+            return;
+        }
+
+        let params = self.params.clone();
+        let n = |i: usize| -> u16 {
+            let param = params.split(|b| *b == b';').nth(i).unwrap_or_default();
+            match std::str::from_utf8(param).ok().and_then(|p| p.parse::<u16>().ok()) {
+                Some(0) | None => 1,
+                Some(n) => n,
+            }
+        };
+        let (max_row, max_col) = (self.rows - 1, self.cols - 1);
+        match final_byte {
+            b'H' | b'f' => {
+                self.row = std::cmp::min(n(0) - 1, max_row);
+                self.col = std::cmp::min(n(1) - 1, max_col);
+            }
+            b'A' => self.row = self.row.saturating_sub(n(0)),
+            b'B' | b'e' => self.row = std::cmp::min(self.row.saturating_add(n(0)), max_row),
+            b'C' | b'a' => self.col = std::cmp::min(self.col.saturating_add(n(0)), max_col),
+            b'D' => self.col = self.col.saturating_sub(n(0)),
+            b'E' => {
+                self.row = std::cmp::min(self.row.saturating_add(n(0)), max_row);
+                self.col = 0;
+            }
+            b'F' => {
+                self.row = self.row.saturating_sub(n(0));
+                self.col = 0;
+            }
+            b'G' | b'`' => self.col = std::cmp::min(n(0) - 1, max_col),
+            b'd' => self.row = std::cmp::min(n(0) - 1, max_row),
+            b's' => self.saved = (self.row, self.col),
+            b'u' => self.restore(),
+            b'r' => {
+                self.scroll_bottom =
+                    if params.contains(&b';') { std::cmp::min(n(1) - 1, max_row) } else { max_row };
+                self.row = 0;
+                self.col = 0;
+            }
+            b'm' => {
+                self.attrs.apply(&params);
+                return;
+            }
+            _ => return,
+        }
+        self.wrap_pending = false;
+    }
+
+    fn start_utf8(&mut self, bits: u8, remaining: u8) {
+        self.codepoint = bits as u32;
+        self.utf8_remaining = remaining;
+    }
+
+    fn print(&mut self, width: u16) {
+        if self.wrap_pending || self.col + width > self.cols {
+            self.col = 0;
+            self.line_feed();
+        }
+        self.col += width;
+        if self.col >= self.cols {
+            self.col = self.cols - 1;
+            self.wrap_pending = true;
+        }
+    }
+
+    fn line_feed(&mut self) {
+        // at the bottom of the scroll region, the screen scrolls
+        // instead of the cursor moving
+        if self.row != self.scroll_bottom && self.row < self.rows - 1 {
+            self.row += 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    fn restore(&mut self) {
+        (self.row, self.col) = self.saved;
+        self.row = std::cmp::min(self.row, self.rows - 1);
+        self.col = std::cmp::min(self.col, self.cols - 1);
+        self.wrap_pending = false;
+    }
+}
+
+/// How many columns a character takes up. Terminals look this up in
+/// the unicode tables, but the ranges below cover the double width
+/// characters people actually run into.
+fn width(codepoint: u32) -> u16 {
+    match codepoint {
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        // combining marks don't take up a column of their own
+        0x0300..=0x036f | 0x200b..=0x200f | 0xfe00..=0xfe0f => 0,
+        _ => 1,
+    }
+}
+
+// The kinds of text attributes, each of which overrides earlier
+// settings of the same kind.
+const INTENSITY: usize = 0;
+const ITALIC: usize = 1;
+const UNDERLINE: usize = 2;
+const BLINK: usize = 3;
+const REVERSE: usize = 4;
+const INVISIBLE: usize = 5;
+const STRIKE: usize = 6;
+const FG: usize = 7;
+const BG: usize = 8;
+
+/// The SGR parameters currently in effect, one slot for each kind of
+/// attribute.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Attrs {
+    slots: [Option<String>; 9],
+}
+
+impl Attrs {
+    fn apply(&mut self, params: &[u8]) {
+        let params = String::from_utf8_lossy(params);
+        let params = params.split(';').collect::<Vec<_>>();
+        let mut i = 0;
+        while i < params.len() {
+            let param = params[i];
+            i += 1;
+            // colon separated sub-parameters, like 38:2::255:0:0, are
+            // all part of a single parameter
+            let code = param.split(':').next().unwrap_or_default().parse::<u16>().unwrap_or(0);
+            let (slot, set) = match code {
+                0 => {
+                    self.slots = Default::default();
+                    continue;
+                }
+                1 | 2 => (INTENSITY, true),
+                22 => (INTENSITY, false),
+                3 => (ITALIC, true),
+                23 => (ITALIC, false),
+                4 | 21 => (UNDERLINE, true),
+                24 => (UNDERLINE, false),
+                5 | 6 => (BLINK, true),
+                25 => (BLINK, false),
+                7 => (REVERSE, true),
+                27 => (REVERSE, false),
+                8 => (INVISIBLE, true),
+                28 => (INVISIBLE, false),
+                9 => (STRIKE, true),
+                29 => (STRIKE, false),
+                30..=37 | 90..=97 => (FG, true),
+                39 => (FG, false),
+                40..=47 | 100..=107 => (BG, true),
+                49 => (BG, false),
+                38 | 48 => {
+                    let slot = if code == 38 { FG } else { BG };
+                    if param.contains(':') {
+                        (slot, true)
+                    } else {
+                        // 38;5;N for the 256 color palette, 38;2;R;G;B
+                        // for true color
+                        let extra = match params.get(i).copied() {
+                            Some("5") => 2,
+                            Some("2") => 4,
+                            _ => 0,
+                        };
+                        let end = std::cmp::min(i + extra, params.len());
+                        self.slots[slot] = Some(params[i - 1..end].join(";"));
+                        i = end;
+                        continue;
+                    }
+                }
+                _ => continue,
+            };
+            self.slots[slot] = if set { Some(String::from(param)) } else { None };
+        }
+    }
+
+    /// A single SGR sequence that resets everything and then sets what
+    /// is in effect.
+    fn formatted(&self) -> String {
+        let mut sgr = String::from("\x1b[0");
+        for param in self.slots.iter().flatten() {
+            sgr.push(';');
+            sgr.push_str(param);
+        }
+        sgr.push('m');
+        sgr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tracker(rows: u16, cols: u16) -> Tracker {
+        let mut tracker = Tracker::default();
+        tracker.resize(&TtySize { rows, cols, xpixel: 0, ypixel: 0 });
+        tracker
+    }
+
+    #[test]
+    fn plain_output() {
+        let mut tracker = tracker(3, 10);
+        tracker.process(b"one\r\ntwo\r\n$ ");
+        assert_eq!((tracker.row, tracker.col), (2, 2));
+
+        // the screen scrolls rather than the cursor leaving it
+        tracker.process(b"ls\r\nfoo\r\n$ ");
+        assert_eq!((tracker.row, tracker.col), (2, 2));
+    }
+
+    #[test]
+    fn wrapping() {
+        let mut tracker = tracker(5, 4);
+        tracker.process(b"abcd");
+        assert_eq!((tracker.row, tracker.col), (0, 3));
+        assert!(tracker.wrap_pending);
+        tracker.process(b"e");
+        assert_eq!((tracker.row, tracker.col), (1, 1));
+
+        // a double width character does not fit in the last column
+        tracker.process(b"xy");
+        assert_eq!((tracker.row, tracker.col), (1, 3));
+        tracker.process("中".as_bytes());
+        assert_eq!((tracker.row, tracker.col), (2, 2));
+    }
+
+    #[test]
+    fn cursor_movement() {
+        let mut tracker = tracker(24, 80);
+        tracker.process(b"\x1b[10;20H");
+        assert_eq!((tracker.row, tracker.col), (9, 19));
+        tracker.process(b"\x1b[2A\x1b[5C");
+        assert_eq!((tracker.row, tracker.col), (7, 24));
+        tracker.process(b"\x1b7\x1b[H\x1b[99B\x1b[999D");
+        assert_eq!((tracker.row, tracker.col), (23, 0));
+        tracker.process(b"\x1b8");
+        assert_eq!((tracker.row, tracker.col), (7, 24));
+
+        // sequences split across chunks
+        for chunk in [&b"\x1b"[..], b"[", b"3;", b"4", b"H"] {
+            tracker.process(chunk);
+        }
+        assert_eq!((tracker.row, tracker.col), (2, 3));
+
+        // titles and the like don't move the cursor
+        tracker.process(b"\x1b]0;some title\x07\x1b]2;other\x1b\\");
+        assert_eq!((tracker.row, tracker.col), (2, 3));
+    }
+
+    #[test]
+    fn attrs() {
+        let mut tracker = tracker(24, 80);
+        tracker.process(b"\x1b[1;31mred\x1b[4m");
+        assert_eq!(tracker.attrs.formatted(), "\x1b[0;1;4;31m");
+        tracker.process(b"\x1b[22;38;5;208m\x1b[48;2;0;0;255m");
+        assert_eq!(tracker.attrs.formatted(), "\x1b[0;4;38;5;208;48;2;0;0;255m");
+        tracker.process(b"\x1b[m");
+        assert_eq!(tracker.attrs.formatted(), "\x1b[0m");
+    }
+
+    #[test]
+    fn fixup() {
+        let mut tracker = tracker(24, 80);
+        tracker.process(b"\x1b[5;3H\x1b[?25l\x1b[7m");
+        assert_eq!(tracker.fixup(), b"\x1b[5;3H\x1b[?25l\x1b[0;7m");
+    }
+}
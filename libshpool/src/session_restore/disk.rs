@@ -0,0 +1,277 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A spool that survives daemon restarts.
+
+  The DiskSpool keeps the same bounded buffer a MemorySpool does, but
+  every so often it also writes that buffer out to a checkpoint file in
+  the session's runtime directory. If the daemon goes away without the
+  session ending (a crash, or a restart to pick up a new version), the
+  checkpoint is left behind. On startup, the new daemon recreates the
+  sessions listed in the resurrect manifest that have a checkpoint, and
+  when a session with the same name gets created its spool recovers the
+  old output so the user gets their scrollback back.
+
+  When the session ends normally, the shell->client thread calls
+  `discard` so that a future session with the same name starts fresh.
+  A fresh daemon calls `sweep` on startup to get rid of the
+  checkpoints that no session is ever going to pick up, which are the
+  ones that `shpool resurrect` does not know about.
+*/
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+    time,
+};
+
+use anyhow::Context;
+use shpool_protocol::TtySize;
+use tracing::{info, warn};
+
+use super::{MemorySpool, SessionSpool};
+
+/// The name of the checkpoint file within a session's runtime dir.
+pub const CHECKPOINT_FILE: &str = "spool.checkpoint";
+
+// How stale the checkpoint is allowed to get while the shell is
+// producing output. Anything more frequent would mean rewriting the
+// whole buffer over and over for chatty programs.
+const CHECKPOINT_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+pub struct DiskSpool {
+    inner: MemorySpool,
+    path: PathBuf,
+    // true if the in-memory buffer has changed since the last checkpoint
+    dirty: bool,
+    last_checkpoint: time::Instant,
+}
+
+impl DiskSpool {
+    pub fn new(max_size: usize, path: PathBuf) -> Self {
+        DiskSpool {
+            inner: MemorySpool::new(max_size),
+            path,
+            dirty: false,
+            last_checkpoint: time::Instant::now(),
+        }
+    }
+
+    /// Write the buffer out to the checkpoint file. We write to a
+    /// temporary file and rename it into place so a crash halfway
+    /// through never leaves a truncated checkpoint behind.
+    fn checkpoint(&mut self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("creating checkpoint dir")?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .context("opening checkpoint file")?;
+        for slice in self.inner.slices() {
+            file.write_all(slice).context("writing checkpoint")?;
+        }
+        fs::rename(&tmp_path, &self.path).context("moving checkpoint into place")?;
+
+        self.dirty = false;
+        self.last_checkpoint = time::Instant::now();
+        Ok(())
+    }
+
+    fn maybe_checkpoint(&mut self) {
+        if !self.dirty || self.last_checkpoint.elapsed() < CHECKPOINT_INTERVAL {
+            return;
+        }
+        if let Err(e) = self.checkpoint() {
+            warn!("checkpointing spool: {:?}", e);
+            // don't keep retrying on every chunk of output
+            self.last_checkpoint = time::Instant::now();
+        }
+    }
+}
+
+impl SessionSpool for DiskSpool {
+    fn resize(&mut self, size: TtySize) {
+        self.inner.resize(size);
+    }
+
+    fn restore_buffer(&self) -> Vec<u8> {
+        self.inner.restore_buffer()
+    }
+
+    fn cursor_fixup(&self) -> Vec<u8> {
+        self.inner.cursor_fixup()
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        self.inner.contents()
+    }
+
+    fn process(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.inner.process(bytes);
+        self.dirty = true;
+        self.maybe_checkpoint();
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage()
+    }
+
+    fn tick(&mut self) {
+        self.maybe_checkpoint();
+    }
+
+    fn recover(&mut self) -> Option<Vec<u8>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("reading spool checkpoint: {:?}", e);
+                return None;
+            }
+        };
+        if data.is_empty() {
+            return None;
+        }
+        info!("recovered {} bytes from {:?}", data.len(), self.path);
+        self.inner.process(&data);
+        // the checkpoint already holds exactly this, so no need to
+        // mark ourselves dirty
+        Some(self.inner.restore_buffer())
+    }
+}
+
+/// Remove the checkpoint file at the given path, if there is one.
+pub fn discard(path: &Path) {
+    if let Err(e) = fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        warn!("removing spool checkpoint: {:?}", e);
+    }
+}
+
+/// Remove the checkpoints under the given sessions dir that belong to
+/// sessions other than the ones named in `keep`.
+pub fn sweep(sessions_dir: &Path, keep: &HashSet<String>) {
+    let entries = match fs::read_dir(sessions_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("listing session dirs: {:?}", e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path().join(CHECKPOINT_FILE);
+        if !path.exists() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if keep.contains(&name) {
+            info!("keeping spool checkpoint for '{}' until it gets resurrected", name);
+        } else {
+            info!("removing stale spool checkpoint for '{}'", name);
+            discard(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checkpoint_and_recover() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("sessions").join("sess").join("spool.checkpoint");
+
+        let mut spool = DiskSpool::new(1024, path.clone());
+        spool.process(b"some output\r\n");
+        spool.checkpoint()?;
+        assert!(!spool.dirty);
+        assert_eq!(fs::read(&path)?, b"some output\r\n");
+
+        // a spool from a fresh daemon picks up where the old one left off
+        let mut recovered = DiskSpool::new(1024, path.clone());
+        assert_eq!(recovered.recover(), Some(b"some output\r\n".to_vec()));
+        assert_eq!(recovered.restore_buffer(), b"some output\r\n");
+
+        discard(&path);
+        assert!(!path.exists());
+        let mut fresh = DiskSpool::new(1024, path.clone());
+        assert_eq!(fresh.recover(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_respects_max_size() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("spool.checkpoint");
+        fs::write(&path, vec![b'x'; 200])?;
+
+        let mut spool = DiskSpool::new(100, path);
+        let buf = spool.recover().expect("checkpoint to be recovered");
+        assert_eq!(buf.len(), 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_checkpoints_when_dirty() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("spool.checkpoint");
+
+        let mut spool = DiskSpool::new(1024, path.clone());
+        spool.last_checkpoint -= CHECKPOINT_INTERVAL;
+        spool.tick();
+        assert!(!path.exists());
+
+        spool.process(b"data");
+        assert!(path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_keeps_resurrectable() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let sessions_dir = tmp_dir.path().join("sessions");
+        for name in ["keep", "stale"] {
+            fs::create_dir_all(sessions_dir.join(name))?;
+            fs::write(sessions_dir.join(name).join(CHECKPOINT_FILE), b"output")?;
+        }
+        fs::create_dir_all(sessions_dir.join("empty"))?;
+
+        sweep(&sessions_dir, &HashSet::from([String::from("keep")]));
+        assert!(sessions_dir.join("keep").join(CHECKPOINT_FILE).exists());
+        assert!(!sessions_dir.join("stale").join(CHECKPOINT_FILE).exists());
+        assert!(sessions_dir.join("empty").exists());
+
+        // no sessions dir at all is fine
+        sweep(&tmp_dir.path().join("nope"), &HashSet::new());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Restoring output a line at a time.
+
+  A byte budget restores wildly different amounts of output depending
+  on what the output is: a few screens of colorful compiler errors can
+  take up as much room as thousands of lines of plain text. With
+  `session_restore = "lines:50"`, the spool keeps the last 50 lines
+  instead, however many bytes they happen to take.
+
+  Lines are logical lines, so a long line that the terminal wraps still
+  counts as one, and the line the cursor is on counts even though it
+  has not been finished yet.
+*/
+
+use std::collections::VecDeque;
+
+use shpool_protocol::TtySize;
+use tracing::info;
+
+use super::{alt_screen, clean_start, cursor, SessionSpool};
+
+/// The most output a LineSpool holds on to no matter how few lines it
+/// has seen, so that a program that never prints a newline does not
+/// eat up all the daemon's memory. This matches the default restore
+/// size.
+pub const MAX_SIZE: usize = 5 * 1024 * 1024;
+
+/// A spool that keeps a fixed number of lines of output.
+pub struct LineSpool {
+    // The output. Everything before `start` has already been dropped,
+    // and just hasn't been cleared out yet.
+    buf: Vec<u8>,
+    start: usize,
+    // The offsets in `buf` just past each newline after `start`.
+    newlines: VecDeque<usize>,
+    lines: usize,
+    max_size: usize,
+    alt_screen: alt_screen::Tracker,
+    cursor: cursor::Tracker,
+}
+
+impl LineSpool {
+    pub fn new(lines: usize, max_size: usize) -> Self {
+        LineSpool {
+            buf: vec![],
+            start: 0,
+            newlines: VecDeque::new(),
+            lines,
+            max_size,
+            alt_screen: alt_screen::Tracker::default(),
+            cursor: cursor::Tracker::default(),
+        }
+    }
+
+    /// Drop the output before `start`. The dropped bytes only get
+    /// cleared out once they make up most of the buffer, so that
+    /// keeping a handful of lines does not mean shifting the whole
+    /// buffer along on every write.
+    fn drop_until(&mut self, start: usize) {
+        self.start = start;
+        while self.newlines.front().is_some_and(|nl| *nl <= start) {
+            self.newlines.pop_front();
+        }
+        if self.start > self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            for nl in self.newlines.iter_mut() {
+                *nl -= self.start;
+            }
+            self.start = 0;
+        }
+    }
+}
+
+impl SessionSpool for LineSpool {
+    fn resize(&mut self, size: TtySize) {
+        self.cursor.resize(&size);
+    }
+
+    fn restore_buffer(&self) -> Vec<u8> {
+        if self.alt_screen.active() {
+            info!("full screen program running, restoring a blank alternate screen");
+            return alt_screen::REDRAW.to_vec();
+        }
+        let restore_buf = self.contents();
+        info!("computing line restore buf with {} bytes", restore_buf.len());
+        restore_buf
+    }
+
+    fn cursor_fixup(&self) -> Vec<u8> {
+        // the program will repaint the alternate screen itself
+        if self.alt_screen.active() {
+            vec![]
+        } else {
+            self.cursor.fixup()
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        self.buf[self.start..].to_vec()
+    }
+
+    fn process(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.alt_screen.process(bytes);
+        self.cursor.process(bytes);
+
+        let offset = self.buf.len();
+        self.buf.extend_from_slice(bytes);
+        self.newlines.extend(
+            bytes.iter().enumerate().filter(|(_, b)| **b == b'\n').map(|(i, _)| offset + i + 1),
+        );
+
+        // The line the cursor is on is one of the lines we keep, so
+        // only the newlines ending the lines before it stick around.
+        let mut start = self.start;
+        if self.newlines.len() >= self.lines {
+            start = self.newlines[self.newlines.len() - self.lines];
+        }
+        if self.buf.len() - start > self.max_size {
+            let cut = self.buf.len() - self.max_size;
+            // make sure we didn't cut a character or escape sequence in half
+            start = cut + clean_start(&self.buf[cut..], self.buf[..cut].ends_with(b"\n"));
+        }
+        if start != self.start {
+            self.drop_until(start);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.buf.capacity() + self.newlines.capacity() * std::mem::size_of::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_last_lines() {
+        let mut spool = LineSpool::new(3, MAX_SIZE);
+        spool.process(b"one\r\ntwo\r\n");
+        assert_eq!(spool.restore_buffer(), b"one\r\ntwo\r\n");
+
+        spool.process(b"three\r\nfour\r\n$ ");
+        assert_eq!(spool.restore_buffer(), b"three\r\nfour\r\n$ ");
+
+        // a line split across writes is still one line
+        spool.process(b"ec");
+        spool.process(b"ho hi\r\nhi\r\n");
+        assert_eq!(spool.restore_buffer(), b"$ echo hi\r\nhi\r\n");
+    }
+
+    #[test]
+    fn ignores_escape_sequences() {
+        let colorful = "\x1b[1;31merror\x1b[0m: \x1b[4mbad\x1b[0m\r\n".repeat(10);
+        let mut spool = LineSpool::new(3, MAX_SIZE);
+        spool.process(colorful.as_bytes());
+        spool.process(b"plain\r\n");
+        assert_eq!(
+            spool.restore_buffer(),
+            b"\x1b[1;31merror\x1b[0m: \x1b[4mbad\x1b[0m\r\nplain\r\n"
+        );
+    }
+
+    #[test]
+    fn size_limit() {
+        let mut spool = LineSpool::new(3, 16);
+        spool.process(&b"y".repeat(100));
+        let buf = spool.restore_buffer();
+        assert_eq!(buf, b"y".repeat(16));
+        assert!(spool.memory_usage() >= buf.len());
+
+        // cutting the front off again starts at the next line
+        spool.process(b"\r\nnext\r\n");
+        assert_eq!(spool.restore_buffer(), b"next\r\n");
+    }
+
+    #[test]
+    fn drops_old_output() {
+        let mut spool = LineSpool::new(2, MAX_SIZE);
+        for i in 0..10000 {
+            spool.process(format!("line {i}\r\n").as_bytes());
+        }
+        assert_eq!(spool.restore_buffer(), b"line 9999\r\n");
+        assert!(spool.memory_usage() < 1024);
+    }
+}
@@ -12,38 +12,97 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::VecDeque;
+use std::{collections::VecDeque, path::Path};
 
 use shpool_protocol::TtySize;
-use tracing::info;
+use tracing::{info, trace};
 use anyhow::{anyhow, Result};
 
+use crate::{logging, size};
 
-/// Parse memory size string like "5MB", "1GB", "512KB" to bytes
-fn parse_memory_size(size_str: &str) -> Result<usize> {
-    if size_str == "0" {
-        return Ok(0);
-    }
-    
-    let size_str = size_str.trim().to_uppercase();
-    
-    if size_str.ends_with("KB") {
-        let num_str = &size_str[..size_str.len() - 2];
-        let num: usize = num_str.parse()?;
-        Ok(num * 1024)
-    } else if size_str.ends_with("MB") {
-        let num_str = &size_str[..size_str.len() - 2];
-        let num: usize = num_str.parse()?;
-        Ok(num * 1024 * 1024)
-    } else if size_str.ends_with("GB") {
-        let num_str = &size_str[..size_str.len() - 2];
-        let num: usize = num_str.parse()?;
-        Ok(num * 1024 * 1024 * 1024)
-    } else {
-        Err(anyhow!("Invalid memory size format: {}. Use formats like '5MB', '1GB', '512KB', or '0'", size_str))
+mod alt_screen;
+pub mod compressed;
+mod cursor;
+pub mod disk;
+pub mod lines;
+pub mod prompts;
+mod reflow;
+pub mod replay;
+pub mod swap;
+
+/// Parse the line count of a "lines:N" session_restore value.
+pub fn parse_line_count(count_str: &str) -> Result<usize> {
+    match count_str.trim().parse::<usize>() {
+        Ok(0) => Err(anyhow!("lines session_restore needs at least one line")),
+        Ok(lines) => Ok(lines),
+        Err(e) => Err(anyhow!("Invalid line count: {}: {}", count_str.trim(), e)),
+    }
+}
+
+/// Roughly how much output a spool made from a session_restore value
+/// can replay, for telling whether some of it got cut off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capacity {
+    /// The last this many bytes.
+    Bytes(usize),
+    /// The last this many lines, as long as they fit in `bytes`.
+    Lines { lines: usize, bytes: usize },
+    /// Whatever is on the screen.
+    Screen,
+    /// It depends on what the output looks like.
+    Unknown,
+}
+
+impl Capacity {
+    pub fn of(restore_config: &str) -> Self {
+        let trimmed = restore_config.trim();
+        if trimmed.eq_ignore_ascii_case("screen") {
+            return Capacity::Screen;
+        }
+        if let Some(lines) = trimmed.strip_prefix("lines:") {
+            return match parse_line_count(lines) {
+                Ok(lines) => Capacity::Lines { lines, bytes: lines::MAX_SIZE },
+                Err(_) => Capacity::Unknown,
+            };
+        }
+        let size =
+            trimmed.strip_prefix("disk:").or(trimmed.strip_prefix("zstd:")).unwrap_or(trimmed);
+        match size::parse(size) {
+            Ok(bytes) => Capacity::Bytes(bytes),
+            Err(_) => Capacity::Unknown,
+        }
+    }
+
+    /// Whether a spool this big still has all of `bytes` bytes and
+    /// `lines` lines of output, on a screen `rows` tall. None if there
+    /// is no telling.
+    pub fn holds(&self, bytes: u64, lines: u64, rows: u16) -> Option<bool> {
+        match *self {
+            Capacity::Bytes(max) => Some(bytes <= max as u64),
+            Capacity::Lines { lines: max_lines, bytes: max } => {
+                Some(lines <= max_lines as u64 && bytes <= max as u64)
+            }
+            Capacity::Screen => Some(lines < u64::from(rows)),
+            Capacity::Unknown => None,
+        }
     }
 }
 
+/// Cut the size in a session_restore value down to `max_size` bytes,
+/// for `limits.max_session_spool`. A disk backed spool keeps the same
+/// buffer in memory that it checkpoints, so it gets capped too. Spools
+/// that only hold a bounded number of lines are left alone.
+pub fn cap(restore_config: &str, max_size: usize) -> String {
+    let trimmed = restore_config.trim();
+    let (prefix, size) = ["zstd:", "disk:"]
+        .into_iter()
+        .find_map(|prefix| trimmed.strip_prefix(prefix).map(|size| (prefix, size)))
+        .unwrap_or(("", trimmed));
+    match size::parse(size) {
+        Ok(size) if size > max_size => format!("{prefix}{max_size}"),
+        _ => String::from(restore_config),
+    }
+}
 
 pub trait SessionSpool {
     /// Resizes the internal representation to new tty size.
@@ -61,8 +120,37 @@ pub trait SessionSpool {
     /// etc.
     fn restore_buffer(&self) -> Vec<u8>;
 
+    /// Sequences to send after replaying the restore buffer that put
+    /// the cursor back where it was and re-apply the text attributes
+    /// that were in effect. This only lines up if the replay covered
+    /// the whole screen. Spools whose restore buffer already takes care
+    /// of this don't need to return anything.
+    fn cursor_fixup(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Everything the spool is holding on to, for moving it over to
+    /// another spool. Unlike the restore buffer, this is the raw output
+    /// even when a full screen program is running.
+    fn contents(&self) -> Vec<u8> {
+        self.restore_buffer()
+    }
+
     /// Process bytes from pty master.
     fn process(&mut self, bytes: &[u8]);
+
+    /// Approximately how many bytes of heap memory the spool is holding on to.
+    fn memory_usage(&self) -> usize;
+
+    /// Called periodically while the shell is quiet, so spools that do
+    /// background work have a chance to catch up.
+    fn tick(&mut self) {}
+
+    /// Reload any state left behind by a previous daemon, returning
+    /// a restore buffer for it if there was any.
+    fn recover(&mut self) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 /// A spool that only sends SIGWINCH signals, no caching.
@@ -75,78 +163,364 @@ impl SessionSpool for SignalOnlySpool {
     }
 
     fn process(&mut self, _: &[u8]) {}
+
+    fn memory_usage(&self) -> usize {
+        0
+    }
+}
+
+// How far past a cut point to look for the start of a line, so that
+// trimmed output does not start in the middle of one.
+const CLEAN_START_WINDOW: usize = 4096;
+
+/// How many bytes to skip at the front of output that has had older
+/// output cut off of it so that it starts at a clean point: the start
+/// of the next line if there is one nearby, and otherwise the first
+/// byte that is not in the middle of a UTF-8 character or an escape
+/// sequence. If the cut landed right after a newline, the output
+/// already starts at the beginning of a line and nothing gets skipped.
+pub fn clean_start(buf: &[u8], cut_after_newline: bool) -> usize {
+    if cut_after_newline {
+        return 0;
+    }
+
+    let window = &buf[..buf.len().min(CLEAN_START_WINDOW)];
+    if let Some(i) = window.iter().position(|b| *b == b'\n') {
+        return i + 1;
+    }
+
+    // skip the rest of a UTF-8 character
+    let mut start = window.iter().position(|b| (*b & 0xc0) != 0x80).unwrap_or(window.len());
+
+    // skip the rest of a CSI sequence that lost its ESC, like "[1;32m"
+    // or "2;40H". We insist on some parameters so that ordinary text
+    // does not look like the tail of a sequence.
+    let rest = &window[start..];
+    let params_start = if rest.first() == Some(&b'[') { 1 } else { 0 };
+    let nparams =
+        rest[params_start..].iter().take_while(|b| b.is_ascii_digit() || **b == b';').count();
+    let final_at = params_start + nparams;
+    if nparams > 0 && rest.get(final_at).map(|b| (0x40..=0x7e).contains(b)).unwrap_or(false) {
+        start += final_at + 1;
+    }
+    start
 }
 
+// The size of the chunks a MemorySpool keeps its output in. Big enough
+// that a chunk of pty output usually lands in one or two of them, small
+// enough that trimming frees up memory in reasonable steps.
+const CHUNK_SIZE: usize = 16 * 1024;
+
 /// A memory-based spool that keeps a fixed-size buffer of terminal output.
+///
+/// The output lives in a queue of fixed-size chunks, only the last of
+/// which is ever partially full. New output gets copied onto the end
+/// in bulk, and trimming just moves the start offset forward, dropping
+/// chunks from the front once they have been used up. The most recently
+/// dropped chunk is kept around to be reused for the next one we need,
+/// so a spool that is full and busy does not touch the allocator.
 pub struct MemorySpool {
-    buffer: VecDeque<u8>,
+    chunks: VecDeque<Vec<u8>>,
+    // how far into the first chunk the output starts
+    start: usize,
+    spare: Option<Vec<u8>>,
+    chunk_size: usize,
     max_size: usize,
     current_size: usize,
+    alt_screen: alt_screen::Tracker,
+    cursor: cursor::Tracker,
 }
 
 impl MemorySpool {
     fn new(max_size: usize) -> Self {
         MemorySpool {
-            buffer: VecDeque::new(),
+            chunks: VecDeque::new(),
+            start: 0,
+            spare: None,
+            chunk_size: CHUNK_SIZE.min(max_size.max(1)),
             max_size,
             current_size: 0,
+            alt_screen: alt_screen::Tracker::default(),
+            cursor: cursor::Tracker::default(),
+        }
+    }
+
+    /// The buffered output, oldest first, in as many pieces as it
+    /// happens to be stored in.
+    fn slices(&self) -> impl Iterator<Item = &[u8]> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| if i == 0 { &chunk[self.start..] } else { &chunk[..] })
+    }
+
+    fn append(&mut self, mut bytes: &[u8]) {
+        self.current_size += bytes.len();
+        while !bytes.is_empty() {
+            let chunk_size = self.chunk_size;
+            let needs_chunk = self.chunks.back().map(|c| c.len() == chunk_size).unwrap_or(true);
+            if needs_chunk {
+                let chunk = self.spare.take().unwrap_or_else(|| Vec::with_capacity(chunk_size));
+                self.chunks.push_back(chunk);
+            }
+            let chunk = self.chunks.back_mut().expect("a chunk to append to");
+            let n = bytes.len().min(chunk_size - chunk.len());
+            chunk.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
         }
     }
+
+    /// Drop the oldest `n` bytes.
+    fn skip(&mut self, mut n: usize) {
+        n = n.min(self.current_size);
+        self.current_size -= n;
+        while n > 0 {
+            let front_len = self.chunks.front().map(|c| c.len()).unwrap_or(0);
+            let step = n.min(front_len - self.start);
+            self.start += step;
+            n -= step;
+            if self.start == front_len {
+                let mut chunk = self.chunks.pop_front().expect("a chunk to drop");
+                chunk.clear();
+                self.spare = Some(chunk);
+                self.start = 0;
+            }
+        }
+        if self.current_size == 0 {
+            // the last chunk might have been partly used
+            self.chunks.clear();
+            self.start = 0;
+        }
+    }
+
+    /// The first few KB of output, for finding a clean place to start.
+    fn head(&self) -> Vec<u8> {
+        let mut head = Vec::with_capacity(CLEAN_START_WINDOW.min(self.current_size));
+        for slice in self.slices() {
+            let n = slice.len().min(CLEAN_START_WINDOW - head.len());
+            head.extend_from_slice(&slice[..n]);
+            if head.len() == CLEAN_START_WINDOW {
+                break;
+            }
+        }
+        head
+    }
 }
 
 impl SessionSpool for MemorySpool {
-    fn resize(&mut self, _size: TtySize) {
-        // Memory-based spool doesn't need to handle resize
+    fn resize(&mut self, size: TtySize) {
+        self.cursor.resize(&size);
     }
 
     fn restore_buffer(&self) -> Vec<u8> {
-        let restore_buf: Vec<u8> = self.buffer.iter().copied().collect();
+        if self.alt_screen.active() {
+            info!("full screen program running, restoring a blank alternate screen");
+            return alt_screen::REDRAW.to_vec();
+        }
+        let restore_buf = self.contents();
         info!("computing memory restore buf with {} bytes", restore_buf.len());
         if restore_buf.is_empty() {
             info!("restore buffer is empty - no content to restore");
         } else {
-            info!("restore buffer content preview: {:?}", 
-                  String::from_utf8_lossy(&restore_buf[..std::cmp::min(100, restore_buf.len())]));
+            trace!(
+                "restore buffer content preview: {:?}",
+                logging::payload(&restore_buf[..std::cmp::min(100, restore_buf.len())]).to_string()
+            );
         }
         restore_buf
     }
 
+    fn cursor_fixup(&self) -> Vec<u8> {
+        // the program will repaint the alternate screen itself
+        if self.alt_screen.active() {
+            vec![]
+        } else {
+            self.cursor.fixup()
+        }
+    }
+
     fn process(&mut self, bytes: &[u8]) {
         if bytes.is_empty() {
             return;
         }
         
-        info!("MemorySpool processing {} bytes", bytes.len());
-        
-        // Add new bytes to the buffer
-        for &byte in bytes {
-            self.buffer.push_back(byte);
-            self.current_size += 1;
+        trace!("MemorySpool processing {} bytes", bytes.len());
+        self.alt_screen.process(bytes);
+        self.cursor.process(bytes);
+
+        // Output bigger than the whole spool would just get trimmed
+        // right back off.
+        let dropped = bytes.len().saturating_sub(self.max_size);
+        self.append(&bytes[dropped..]);
+
+        if dropped > 0 || self.current_size > self.max_size {
+            // hang on to the last byte we cut off so we can tell if we
+            // cut right at the start of a line
+            let excess = self.current_size.saturating_sub(self.max_size);
+            let last_cut = if dropped > 0 {
+                self.skip(excess);
+                bytes[dropped - 1]
+            } else {
+                self.skip(excess - 1);
+                let last_cut = self.slices().next().and_then(|s| s.first().copied());
+                self.skip(1);
+                last_cut.unwrap_or(0)
+            };
+            // make sure we didn't cut a character or escape sequence in half
+            let skip = clean_start(&self.head(), last_cut == b'\n');
+            self.skip(skip);
         }
-        
-        // Trim buffer if it exceeds max size
-        while self.current_size > self.max_size && !self.buffer.is_empty() {
-            self.buffer.pop_front();
-            self.current_size -= 1;
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::with_capacity(self.current_size);
+        for slice in self.slices() {
+            contents.extend_from_slice(slice);
         }
+        contents
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.chunks.iter().chain(self.spare.iter()).map(|c| c.capacity()).sum()
+    }
+}
+
+
+
+/// A spool that feeds output through a terminal emulator so that it can
+/// repaint exactly what was last on screen, rather than replaying raw
+/// output (stale escape sequences and all) the way MemorySpool does.
+pub struct Vt100Spool {
+    parser: shpool_vt100::Parser,
+}
+
+impl Vt100Spool {
+    fn new(size: &TtySize) -> Self {
+        // The emulator can't cope with a zero sized screen, which we
+        // will see if the client is not attached to a real tty.
+        let rows = std::cmp::max(size.rows, 1);
+        let cols = std::cmp::max(size.cols, 1);
+        Vt100Spool { parser: shpool_vt100::Parser::new(rows, cols, 0) }
     }
 }
 
+impl SessionSpool for Vt100Spool {
+    fn resize(&mut self, size: TtySize) {
+        let rows = std::cmp::max(size.rows, 1);
+        let cols = std::cmp::max(size.cols, 1);
+        let screen = self.parser.screen();
+        // Full screen programs redraw themselves at the new size, so
+        // there is nothing to reflow, and nothing to do at all if the
+        // width is staying the same.
+        if screen.size().1 == cols || screen.alternate_screen() {
+            self.parser.screen_mut().set_size(rows, cols);
+            return;
+        }
+        info!("reflowing screen to (rows={}, cols={})", rows, cols);
+        self.parser = reflow::reflow(screen, rows, cols);
+    }
+
+    fn restore_buffer(&self) -> Vec<u8> {
+        let (rows, cols) = self.parser.screen().size();
+        info!("computing screen restore buf with (rows={}, cols={})", rows, cols);
+        self.parser.screen().contents_formatted()
+    }
+
+    fn process(&mut self, bytes: &[u8]) {
+        self.parser.process(bytes);
+    }
 
+    fn memory_usage(&self) -> usize {
+        let (rows, cols) = self.parser.screen().size();
+        rows as usize * cols as usize * std::mem::size_of::<shpool_vt100::Cell>()
+    }
+}
 
-/// Creates a spool given a memory size string like "5MB", "1MB", or "0".
+/// Creates a spool given a session_restore config value. This is either
+/// "screen", a memory size string like "5MB", "1MB", or "0", a memory
+/// size prefixed with "disk:" to checkpoint the buffer to `checkpoint_path`
+/// or "zstd:" to keep the buffer compressed in memory, "prompts:N" to
+/// keep the output since the start of the last N prompts, or "lines:N"
+/// to keep the last N lines.
 pub fn new(
     restore_config: &str,
-    _size: &TtySize,
+    size: &TtySize,
+    checkpoint_path: &Path,
 ) -> Result<Box<dyn SessionSpool + 'static>> {
-    match parse_memory_size(restore_config) {
+    if restore_config.trim().eq_ignore_ascii_case("screen") {
+        info!("Creating Vt100Spool (rows={}, cols={})", size.rows, size.cols);
+        return Ok(Box::new(Vt100Spool::new(size)));
+    }
+
+    if let Some(prompts) = restore_config.trim().strip_prefix("prompts:") {
+        return match prompts.trim().parse::<usize>() {
+            Ok(0) => Err(anyhow!("prompts session_restore needs at least one prompt")),
+            Ok(prompts) => {
+                info!("Creating PromptSpool keeping {} prompts", prompts);
+                let mut spool = prompts::PromptSpool::new(prompts, prompts::MAX_SIZE);
+                spool.resize(size.clone());
+                Ok(Box::new(spool))
+            }
+            Err(e) => {
+                Err(anyhow!("Failed to parse session_restore config '{}': {}", restore_config, e))
+            }
+        };
+    }
+
+    if let Some(lines) = restore_config.trim().strip_prefix("lines:") {
+        return match parse_line_count(lines) {
+            Ok(lines) => {
+                info!("Creating LineSpool keeping {} lines", lines);
+                let mut spool = lines::LineSpool::new(lines, lines::MAX_SIZE);
+                spool.resize(size.clone());
+                Ok(Box::new(spool))
+            }
+            Err(e) => {
+                Err(anyhow!("Failed to parse session_restore config '{}': {}", restore_config, e))
+            }
+        };
+    }
+
+    if let Some(disk_size) = restore_config.trim().strip_prefix("disk:") {
+        return match size::parse(disk_size) {
+            Ok(0) => Err(anyhow!("disk session_restore needs a non-zero size")),
+            Ok(max_size) => {
+                info!("Creating DiskSpool with {} bytes limit at {:?}", max_size, checkpoint_path);
+                let mut spool = disk::DiskSpool::new(max_size, checkpoint_path.to_path_buf());
+                spool.resize(size.clone());
+                Ok(Box::new(spool))
+            }
+            Err(e) => {
+                Err(anyhow!("Failed to parse session_restore config '{}': {}", restore_config, e))
+            }
+        };
+    }
+
+    if let Some(zstd_size) = restore_config.trim().strip_prefix("zstd:") {
+        return match size::parse(zstd_size) {
+            Ok(0) => Err(anyhow!("zstd session_restore needs a non-zero size")),
+            Ok(max_size) => {
+                info!("Creating CompressedSpool with {} bytes limit", max_size);
+                let mut spool = compressed::CompressedSpool::new(max_size);
+                spool.resize(size.clone());
+                Ok(Box::new(spool))
+            }
+            Err(e) => {
+                Err(anyhow!("Failed to parse session_restore config '{}': {}", restore_config, e))
+            }
+        };
+    }
+
+    match size::parse(restore_config) {
         Ok(0) => {
             info!("Creating SignalOnlySpool (no caching, SIGWINCH only)");
             Ok(Box::new(SignalOnlySpool))
         },
         Ok(max_size) => {
             info!("Creating MemorySpool with {} bytes limit", max_size);
-            Ok(Box::new(MemorySpool::new(max_size)))
+            let mut spool = MemorySpool::new(max_size);
+            spool.resize(size.clone());
+            Ok(Box::new(spool))
         },
         Err(e) => {
             Err(anyhow!("Failed to parse session_restore config '{}': {}", restore_config, e))
@@ -159,43 +533,130 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_memory_size() {
-        // Test basic parsing
-        assert_eq!(parse_memory_size("0").unwrap(), 0);
-        assert_eq!(parse_memory_size("512KB").unwrap(), 512 * 1024);
-        assert_eq!(parse_memory_size("5MB").unwrap(), 5 * 1024 * 1024);
-        assert_eq!(parse_memory_size("2GB").unwrap(), 2 * 1024 * 1024 * 1024);
-
-        // Test case insensitivity
-        assert_eq!(parse_memory_size("1mb").unwrap(), 1024 * 1024);
-        assert_eq!(parse_memory_size("1Mb").unwrap(), 1024 * 1024);
-        assert_eq!(parse_memory_size("1kb").unwrap(), 1024);
+    fn test_cap() {
+        let max = 10 * 1024 * 1024;
+        assert_eq!(cap("50MB", max), "10485760");
+        assert_eq!(cap("zstd:1GB", max), "zstd:10485760");
+        assert_eq!(cap("5MB", max), "5MB");
+        assert_eq!(cap("0", max), "0");
+        assert_eq!(cap("disk:1GB", max), "disk:10485760");
+        assert_eq!(cap("disk:5MB", max), "disk:5MB");
+        assert_eq!(cap("screen", max), "screen");
+        assert_eq!(cap("lines:500", max), "lines:500");
+        assert_eq!(cap("5MB", 100), "100");
+    }
 
-        // Test with whitespace
-        assert_eq!(parse_memory_size("  5MB  ").unwrap(), 5 * 1024 * 1024);
+    #[test]
+    fn capacity() {
+        assert_eq!(Capacity::of("1KiB"), Capacity::Bytes(1024));
+        assert_eq!(Capacity::of("zstd:2KB"), Capacity::Bytes(2000));
+        assert_eq!(Capacity::of("disk:10"), Capacity::Bytes(10));
+        assert_eq!(Capacity::of("screen"), Capacity::Screen);
+        assert_eq!(Capacity::of("prompts:3"), Capacity::Unknown);
+        assert_eq!(
+            Capacity::of("lines:500"),
+            Capacity::Lines { lines: 500, bytes: lines::MAX_SIZE }
+        );
 
-        // Test error cases
-        assert!(parse_memory_size("invalid").is_err());
-        assert!(parse_memory_size("5TB").is_err());
-        assert!(parse_memory_size("5").is_err());
-        assert!(parse_memory_size("MB").is_err());
-        assert!(parse_memory_size("").is_err());
+        assert_eq!(Capacity::Bytes(1024).holds(1024, 0, 24), Some(true));
+        assert_eq!(Capacity::Bytes(1024).holds(1025, 0, 24), Some(false));
+        assert_eq!(Capacity::Bytes(0).holds(1, 0, 24), Some(false));
+        assert_eq!(Capacity::of("lines:10").holds(100, 11, 24), Some(false));
+        assert_eq!(Capacity::Screen.holds(5000, 23, 24), Some(true));
+        assert_eq!(Capacity::Screen.holds(5000, 24, 24), Some(false));
+        assert_eq!(Capacity::Unknown.holds(5000, 24, 24), None);
     }
 
     #[test]
     fn test_new_session_spool() {
         let tty_size = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let checkpoint = tmp_dir.path().join("spool.checkpoint");
 
         // Test creating SignalOnlySpool
-        let spool = new("0", &tty_size).unwrap();
+        let spool = new("0", &tty_size, &checkpoint).unwrap();
         assert_eq!(spool.restore_buffer().len(), 0);
 
         // Test creating MemorySpool
-        let spool = new("1MB", &tty_size).unwrap();
+        let spool = new("1MB", &tty_size, &checkpoint).unwrap();
         assert_eq!(spool.restore_buffer().len(), 0); // Initially empty
 
+        // Test creating Vt100Spool
+        let spool = new("screen", &tty_size, &checkpoint).unwrap();
+        assert!(spool.memory_usage() > 0);
+        assert!(new(" Screen ", &tty_size, &checkpoint).is_ok());
+
+        // Test creating DiskSpool
+        let spool = new("disk:1MB", &tty_size, &checkpoint).unwrap();
+        assert_eq!(spool.restore_buffer().len(), 0);
+        assert!(new("disk:0", &tty_size, &checkpoint).is_err());
+        assert!(new("disk:lots", &tty_size, &checkpoint).is_err());
+
+        // Test creating CompressedSpool
+        let spool = new("zstd:100MB", &tty_size, &checkpoint).unwrap();
+        assert_eq!(spool.restore_buffer().len(), 0);
+        assert!(new("zstd:0", &tty_size, &checkpoint).is_err());
+
+        // Test creating PromptSpool
+        let spool = new("prompts:3", &tty_size, &checkpoint).unwrap();
+        assert_eq!(spool.restore_buffer().len(), 0);
+        assert!(new("prompts:0", &tty_size, &checkpoint).is_err());
+        assert!(new("prompts:some", &tty_size, &checkpoint).is_err());
+
+        // Test creating LineSpool
+        let spool = new("lines:50", &tty_size, &checkpoint).unwrap();
+        assert_eq!(spool.restore_buffer().len(), 0);
+        assert!(new("lines:0", &tty_size, &checkpoint).is_err());
+        assert!(new("lines:few", &tty_size, &checkpoint).is_err());
+
         // Test error case
-        assert!(new("invalid", &tty_size).is_err());
+        assert!(new("invalid", &tty_size, &checkpoint).is_err());
+    }
+
+    #[test]
+    fn test_vt100_spool_restore() {
+        let size = TtySize { rows: 5, cols: 20, xpixel: 0, ypixel: 0 };
+        let mut spool = Vt100Spool::new(&size);
+        spool.process(b"one\r\ntwo\r\nthree\r\n");
+        // move the cursor into the middle of the second line
+        spool.process(b"\x1b[2;5H");
+
+        // The restore buffer should repaint the screen no matter what
+        // was on it before.
+        let mut replay = shpool_vt100::Parser::new(5, 20, 0);
+        replay.process(b"leftover\r\njunk\r\n");
+        replay.process(&spool.restore_buffer());
+        let screen = replay.screen();
+        assert_eq!(screen.contents(), "one\ntwo\nthree");
+        assert_eq!(screen.cursor_position(), (1, 4));
+    }
+
+    #[test]
+    fn test_vt100_spool_resize() {
+        let rows = |spool: &Vt100Spool| {
+            let (_, cols) = spool.parser.screen().size();
+            spool.parser.screen().rows(0, cols).collect::<Vec<_>>()
+        };
+        let size = TtySize { rows: 5, cols: 10, xpixel: 0, ypixel: 0 };
+        let mut spool = Vt100Spool::new(&size);
+        spool.process(b"$ abcdefghijklmn");
+
+        // narrowing rewraps the line and keeps the cursor at its end
+        spool.resize(TtySize { rows: 5, cols: 6, xpixel: 0, ypixel: 0 });
+        spool.process(b"o");
+        assert_eq!(rows(&spool), vec!["$ abcd", "efghij", "klmno", "", ""]);
+        assert_eq!(spool.parser.screen().cursor_position(), (2, 5));
+
+        // and so does widening it back out
+        spool.resize(TtySize { rows: 5, cols: 10, xpixel: 0, ypixel: 0 });
+        spool.process(b"p");
+        assert_eq!(rows(&spool), vec!["$ abcdefgh", "ijklmnop", "", "", ""]);
+        assert_eq!(spool.parser.screen().cursor_position(), (1, 8));
+
+        // a height change alone leaves the lines where they are
+        spool.resize(TtySize { rows: 3, cols: 10, xpixel: 0, ypixel: 0 });
+        assert_eq!(rows(&spool), vec!["$ abcdefgh", "ijklmnop", ""]);
+        assert_eq!(spool.parser.screen().cursor_position(), (1, 8));
     }
 
     #[test]
@@ -218,6 +679,83 @@ mod tests {
         // Should be trimmed to max_size
         assert!(buffer.len() <= 100);
         assert!(!buffer.is_empty());
+        assert!(spool.memory_usage() >= buffer.len());
+    }
+
+    #[test]
+    fn test_memory_spool_clean_trim() {
+        let mut spool = MemorySpool::new(10);
+        spool.process(b"first line\r\nsecond\r\n");
+        assert_eq!(spool.restore_buffer(), b"second\r\n");
+
+        // cut in the middle of a two byte character
+        let mut spool = MemorySpool::new(4);
+        spool.process("aé€".as_bytes());
+        assert_eq!(spool.restore_buffer(), "€".as_bytes());
+
+        // cut exactly on a line boundary, both when trimming old output
+        // and when dropping the front of a single big write
+        let mut spool = MemorySpool::new(8);
+        spool.process(b"first\r\n");
+        spool.process(b"second\r\n");
+        assert_eq!(spool.restore_buffer(), b"second\r\n");
+        let mut spool = MemorySpool::new(8);
+        spool.process(b"first\r\nsecond\r\n");
+        assert_eq!(spool.restore_buffer(), b"second\r\n");
+        spool.process(b"\r\nthird\r\n");
+        assert_eq!(spool.restore_buffer(), b"third\r\n");
+    }
+
+    #[test]
+    fn test_memory_spool_chunks() {
+        // check the chunked buffer against a plain one, with writes that
+        // straddle chunk boundaries and trims that free whole chunks
+        let max_size = CHUNK_SIZE * 3 + 17;
+        let mut spool = MemorySpool::new(max_size);
+        let mut expected: Vec<u8> = vec![];
+        for i in 0..200 {
+            let len = (i * 7919) % (CHUNK_SIZE * 2);
+            let write: Vec<u8> = (0..len).map(|j| b'a' + ((i + j) % 26) as u8).collect();
+            spool.process(&write);
+            expected.extend_from_slice(&write);
+            let keep = expected.len().min(max_size);
+            expected.drain(..expected.len() - keep);
+
+            assert_eq!(spool.contents(), expected);
+            assert_eq!(spool.current_size, expected.len());
+            assert!(spool.chunks.len() <= 5);
+            assert!(spool.memory_usage() <= CHUNK_SIZE * 6);
+        }
+
+        // writes bigger than the whole spool just keep the tail
+        let mut spool = MemorySpool::new(8);
+        spool.process(b"abcdefghijklmnop");
+        assert_eq!(spool.contents(), b"ijklmnop");
+        spool.process(b"qr");
+        assert_eq!(spool.contents(), b"klmnopqr");
+    }
+
+    #[test]
+    fn test_memory_spool_alt_screen() {
+        let mut spool = MemorySpool::new(1024);
+        spool.process(b"$ htop\r\n\x1b[?1049h\x1b[1;1Hsome cpu graphs");
+        assert_eq!(spool.restore_buffer(), alt_screen::REDRAW);
+        assert!(spool.contents().ends_with(b"some cpu graphs"));
+
+        spool.process(b"\x1b[?1049l$ ");
+        assert!(spool.restore_buffer().ends_with(b"\x1b[?1049l$ "));
+    }
+
+    #[test]
+    fn test_clean_start() {
+        assert_eq!(clean_start(b"abc\ndef", false), 4);
+        assert_eq!(clean_start(b"abc\ndef", true), 0);
+        assert_eq!(clean_start(b"plain text", false), 0);
+        assert_eq!(clean_start(&[0x82, 0xac, b'x'], false), 2);
+        assert_eq!(clean_start(b"1;32mgreen", false), 5);
+        assert_eq!(clean_start(b"[0mreset", false), 3);
+        assert_eq!(clean_start(b"[INFO] msg", false), 0);
+        assert_eq!(clean_start(b"", false), 0);
     }
 
     #[test]
@@ -0,0 +1,246 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Restoring output a prompt at a time.
+
+  Shells with shell integration turned on (or set up to emit it from
+  their prompt) mark the start of every prompt with an OSC 133;A
+  sequence. Those marks split the output up into the prompt, the
+  command typed at it, and the command's output, which makes for a
+  much more natural thing to replay on reattach than an arbitrary
+  number of bytes: with `session_restore = "prompts:3"`, the replay
+  starts right at the beginning of the third most recent prompt.
+
+  Output from shells that never emit the marks is handled as if it
+  were all one long prompt, so it still gets replayed, up to the size
+  limit.
+*/
+
+use std::collections::VecDeque;
+
+use shpool_protocol::TtySize;
+use tracing::info;
+
+use super::{alt_screen, clean_start, cursor, SessionSpool};
+
+/// The start of the sequence that marks the start of a prompt. It may
+/// be followed by parameters before the terminator.
+const PROMPT_MARK: &[u8] = b"\x1b]133;A";
+
+/// The most output a PromptSpool holds on to no matter how few prompts
+/// it has seen, so that a command that prints without end does not eat
+/// up all the daemon's memory. This matches the default restore size.
+pub const MAX_SIZE: usize = 5 * 1024 * 1024;
+
+/// The offset in `buf` of the start of the `n`th most recent prompt,
+/// or 0 if there are not that many prompts in it.
+pub fn last_prompts(buf: &[u8], n: usize) -> usize {
+    if n == 0 {
+        return buf.len();
+    }
+    buf.windows(PROMPT_MARK.len())
+        .enumerate()
+        .rev()
+        .filter(|(_, w)| *w == PROMPT_MARK)
+        .nth(n - 1)
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// A spool that keeps the output since the start of a fixed number of
+/// prompts.
+pub struct PromptSpool {
+    // The output split up at each prompt mark, oldest first. Anything
+    // before the first mark the spool saw is in a segment of its own.
+    segments: VecDeque<Vec<u8>>,
+    prompts: usize,
+    max_size: usize,
+    current_size: usize,
+    // how much of PROMPT_MARK the output has most recently ended with
+    matched: usize,
+    alt_screen: alt_screen::Tracker,
+    cursor: cursor::Tracker,
+}
+
+impl PromptSpool {
+    pub fn new(prompts: usize, max_size: usize) -> Self {
+        PromptSpool {
+            segments: VecDeque::new(),
+            prompts,
+            max_size,
+            current_size: 0,
+            matched: 0,
+            alt_screen: alt_screen::Tracker::default(),
+            cursor: cursor::Tracker::default(),
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.segments.is_empty() {
+            self.segments.push_back(vec![]);
+        }
+        let segment = self.segments.back_mut().unwrap();
+        segment.push(byte);
+        self.current_size += 1;
+
+        // ESC only ever appears at the start of the mark, so there is no
+        // need for anything fancier to find a partial match again.
+        self.matched = if byte == PROMPT_MARK[self.matched] {
+            self.matched + 1
+        } else if byte == PROMPT_MARK[0] {
+            1
+        } else {
+            0
+        };
+        if self.matched == PROMPT_MARK.len() {
+            self.matched = 0;
+            let prompt = segment.split_off(segment.len() - PROMPT_MARK.len());
+            if segment.is_empty() {
+                self.segments.pop_back();
+            }
+            self.segments.push_back(prompt);
+            while self.segments.len() > self.prompts {
+                if let Some(dropped) = self.segments.pop_front() {
+                    self.current_size -= dropped.len();
+                }
+            }
+        }
+    }
+
+    /// Drop the oldest output until the spool fits in its size limit.
+    fn trim(&mut self) {
+        while self.current_size > self.max_size {
+            let excess = self.current_size - self.max_size;
+            let nsegments = self.segments.len();
+            let Some(oldest) = self.segments.front_mut() else {
+                return;
+            };
+            if oldest.len() <= excess && nsegments > 1 {
+                self.current_size -= oldest.len();
+                self.segments.pop_front();
+                continue;
+            }
+            let cut = std::cmp::min(excess, oldest.len());
+            // make sure we didn't cut a character or escape sequence in half
+            let cut = cut + clean_start(&oldest[cut..], oldest[..cut].ends_with(b"\n"));
+            oldest.drain(..cut);
+            self.current_size -= cut;
+        }
+    }
+}
+
+impl SessionSpool for PromptSpool {
+    fn resize(&mut self, size: TtySize) {
+        self.cursor.resize(&size);
+    }
+
+    fn restore_buffer(&self) -> Vec<u8> {
+        if self.alt_screen.active() {
+            info!("full screen program running, restoring a blank alternate screen");
+            return alt_screen::REDRAW.to_vec();
+        }
+        let restore_buf = self.contents();
+        info!(
+            "computing prompt restore buf with {} bytes over {} prompts",
+            restore_buf.len(),
+            self.segments.len()
+        );
+        restore_buf
+    }
+
+    fn cursor_fixup(&self) -> Vec<u8> {
+        // the program will repaint the alternate screen itself
+        if self.alt_screen.active() {
+            vec![]
+        } else {
+            self.cursor.fixup()
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        self.segments.iter().flatten().copied().collect()
+    }
+
+    fn process(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.alt_screen.process(bytes);
+        self.cursor.process(bytes);
+        for byte in bytes {
+            self.push(*byte);
+        }
+        self.trim();
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.segments.iter().map(|s| s.capacity()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn prompt(cmd: &str, output: &str) -> Vec<u8> {
+        format!("\x1b]133;A\x07$ \x1b]133;B\x07{cmd}\r\n\x1b]133;C\x07{output}\x1b]133;D;0\x07")
+            .into_bytes()
+    }
+
+    #[test]
+    fn keeps_last_prompts() {
+        let mut spool = PromptSpool::new(2, MAX_SIZE);
+        spool.process(b"motd\r\n");
+        spool.process(&prompt("ls", "foo\r\n"));
+        assert!(spool.restore_buffer().starts_with(b"motd\r\n"));
+
+        spool.process(&prompt("pwd", "/home\r\n"));
+        spool.process(b"\x1b]133;A\x07$ ");
+        let mut want = prompt("pwd", "/home\r\n");
+        want.extend_from_slice(b"\x1b]133;A\x07$ ");
+        assert_eq!(spool.restore_buffer(), want);
+    }
+
+    #[test]
+    fn split_marks() {
+        let mut spool = PromptSpool::new(1, MAX_SIZE);
+        for chunk in [&b"old output\x1b]1"[..], b"33;", b"A\x07$ ", b"ls"] {
+            spool.process(chunk);
+        }
+        assert_eq!(spool.restore_buffer(), b"\x1b]133;A\x07$ ls");
+    }
+
+    #[test]
+    fn size_limit() {
+        let mut spool = PromptSpool::new(3, 16);
+        spool.process(&prompt("yes", &"y\r\n".repeat(100)));
+        let buf = spool.restore_buffer();
+        assert!(buf.len() <= 16);
+        assert!(buf.starts_with(b"y\r\n"));
+        assert!(spool.memory_usage() >= buf.len());
+    }
+
+    #[test]
+    fn finds_last_prompts() {
+        let mut buf = b"motd\r\n".to_vec();
+        buf.extend(prompt("ls", "foo\r\n"));
+        let second = buf.len();
+        buf.extend(prompt("pwd", "/home\r\n"));
+
+        assert_eq!(last_prompts(&buf, 1), second);
+        assert_eq!(last_prompts(&buf, 2), 6);
+        assert_eq!(last_prompts(&buf, 3), 0);
+        assert_eq!(last_prompts(b"no marks", 1), 0);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Reflowing a screen to a new width.
+
+  Terminal emulators just chop off or pad out rows when they get
+  resized, so text that got soft wrapped at the old width stays wrapped
+  there, and reattaching from a terminal with a different width would
+  repaint a mangled screen. To avoid that, the screen spool pulls the
+  lines the shell actually printed back out of the emulator, joining
+  up rows that were soft wrapped, and feeds them into a fresh emulator
+  of the new size, which wraps them at the right spot.
+
+  Raw output spools don't need any of this, since the client's terminal
+  wraps the replayed output itself.
+*/
+
+use std::io::Write as _;
+
+/// A line of output, possibly spanning several rows of the screen.
+#[derive(Debug, PartialEq, Eq)]
+struct Line {
+    /// The formatted contents of the line.
+    contents: Vec<u8>,
+    /// The screen row the line starts on.
+    first_row: u16,
+    /// How many screen rows the line spans.
+    nrows: u16,
+}
+
+/// Join up screen rows that were soft wrapped. `wrapped[i]` is true if
+/// row `i` continues onto row `i + 1`.
+fn unwrap_rows(rows: Vec<Vec<u8>>, wrapped: &[bool]) -> Vec<Line> {
+    let mut lines: Vec<Line> = vec![];
+    let mut continues = false;
+    for (row, contents) in rows.into_iter().enumerate() {
+        match lines.last_mut() {
+            Some(line) if continues => {
+                line.contents.extend_from_slice(&contents);
+                line.nrows += 1;
+            }
+            _ => lines.push(Line { contents, first_row: row as u16, nrows: 1 }),
+        }
+        continues = wrapped.get(row).copied().unwrap_or(false);
+    }
+    lines
+}
+
+/// How many columns of the line are taken up, counting from the start
+/// of its first row to the end of its last non-blank cell.
+fn line_width(screen: &shpool_vt100::Screen, line: &Line, cols: u16) -> usize {
+    for row in (line.first_row..line.first_row + line.nrows).rev() {
+        for col in (0..cols).rev() {
+            if screen.cell(row, col).map(|c| c.has_contents()).unwrap_or(false) {
+                return (row - line.first_row) as usize * cols as usize + col as usize + 1;
+            }
+        }
+    }
+    0
+}
+
+/// Build a parser of the given size holding the contents of the given
+/// screen, with soft wrapped lines rewrapped at the new width. The
+/// cursor ends up at the same spot in the text it was at before, and
+/// the input modes the shell turned on (like bracketed paste) carry
+/// over.
+pub fn reflow(screen: &shpool_vt100::Screen, rows: u16, cols: u16) -> shpool_vt100::Parser {
+    let (old_rows, old_cols) = screen.size();
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    let wrapped: Vec<bool> = (0..old_rows).map(|row| screen.row_wrapped(row)).collect();
+    let mut lines = unwrap_rows(screen.rows_formatted(0, old_cols).collect(), &wrapped);
+
+    // Blank rows below the cursor are just unused screen.
+    while lines.len() > 1
+        && lines.last().map(|l| l.first_row > cursor_row && l.contents.is_empty()).unwrap_or(false)
+    {
+        lines.pop();
+    }
+    let cursor_line = lines.iter().rposition(|l| l.first_row <= cursor_row).unwrap_or(0);
+
+    let mut parser = shpool_vt100::Parser::new(rows, cols, 0);
+    parser.process(&screen.input_mode_formatted());
+    if screen.hide_cursor() {
+        parser.process(b"\x1b[?25l");
+    }
+    let Some(line) = lines.get(cursor_line) else {
+        return parser;
+    };
+    let cursor_offset =
+        (cursor_row - line.first_row) as usize * old_cols as usize + cursor_col as usize;
+    let width = line_width(screen, line, old_cols);
+
+    // Each formatted row starts out from the default attributes, so reset
+    // them between lines rather than letting one line's colors bleed into
+    // the next.
+    for line in lines[..cursor_line].iter() {
+        parser.process(b"\x1b[m");
+        parser.process(&line.contents);
+        parser.process(b"\r\n");
+    }
+    let (start_row, _) = parser.screen().cursor_position();
+    parser.process(b"\x1b[m");
+    parser.process(&line.contents);
+
+    // If the line did not fit below where it started, the screen scrolled
+    // up to make room for it.
+    let new_cols = cols.max(1) as usize;
+    let rows_needed = width.div_ceil(new_cols).max(1);
+    let scrolled = (start_row as usize + rows_needed).saturating_sub(rows as usize);
+    let cursor_row =
+        (start_row as usize - scrolled.min(start_row as usize)) + cursor_offset / new_cols;
+    let cursor_row = cursor_row.min(rows.saturating_sub(1) as usize);
+    let cursor_col = cursor_offset % new_cols;
+
+    for line in lines[cursor_line + 1..].iter() {
+        parser.process(b"\r\n\x1b[m");
+        parser.process(&line.contents);
+    }
+    let mut cup = vec![];
+    let _ = write!(cup, "\x1b[{};{}H", cursor_row + 1, cursor_col + 1);
+    parser.process(&cup);
+    parser
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn joins_wrapped_rows() {
+        let rows = vec![b"abc".to_vec(), b"de".to_vec(), b"fg".to_vec(), vec![], b"$ ".to_vec()];
+        let wrapped = [true, false, false, false, false];
+        assert_eq!(
+            unwrap_rows(rows, &wrapped),
+            vec![
+                Line { contents: b"abcde".to_vec(), first_row: 0, nrows: 2 },
+                Line { contents: b"fg".to_vec(), first_row: 2, nrows: 1 },
+                Line { contents: vec![], first_row: 3, nrows: 1 },
+                Line { contents: b"$ ".to_vec(), first_row: 4, nrows: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn wrapped_last_row() {
+        let rows = vec![b"abc".to_vec(), b"def".to_vec()];
+        let wrapped = [true, true];
+        assert_eq!(
+            unwrap_rows(rows, &wrapped),
+            vec![Line { contents: b"abcdef".to_vec(), first_row: 0, nrows: 2 }]
+        );
+    }
+
+    fn reflowed(output: &[u8], from: (u16, u16), to: (u16, u16)) -> shpool_vt100::Parser {
+        let mut parser = shpool_vt100::Parser::new(from.0, from.1, 0);
+        parser.process(output);
+        reflow(parser.screen(), to.0, to.1)
+    }
+
+    fn rows(parser: &shpool_vt100::Parser) -> Vec<String> {
+        let (_, cols) = parser.screen().size();
+        parser.screen().rows(0, cols).collect()
+    }
+
+    #[test]
+    fn narrows_wrapped_line() {
+        let mut parser = reflowed(b"$ abcdefghijklmn", (5, 10), (5, 6));
+        assert_eq!(rows(&parser), vec!["$ abcd", "efghij", "klmn", "", ""]);
+        assert_eq!(parser.screen().cursor_position(), (2, 4));
+
+        // more typing picks up right where the line left off
+        parser.process(b"op");
+        assert_eq!(rows(&parser), vec!["$ abcd", "efghij", "klmnop", "", ""]);
+    }
+
+    #[test]
+    fn widens_wrapped_line() {
+        let mut parser = reflowed(b"$ abcdefghijklmn", (5, 6), (5, 10));
+        assert_eq!(rows(&parser), vec!["$ abcdefgh", "ijklmn", "", "", ""]);
+        assert_eq!(parser.screen().cursor_position(), (1, 6));
+
+        parser.process(b"op");
+        assert_eq!(rows(&parser), vec!["$ abcdefgh", "ijklmnop", "", "", ""]);
+    }
+
+    #[test]
+    fn cursor_mid_line() {
+        // the cursor was moved back over "ghij", as a line editor would
+        let parser = reflowed(b"$ abcdefghij\x1b[4D", (5, 8), (5, 5));
+        assert_eq!(rows(&parser), vec!["$ abc", "defgh", "ij", "", ""]);
+        assert_eq!(parser.screen().cursor_position(), (1, 3));
+    }
+
+    #[test]
+    fn scrolls_to_fit() {
+        let parser = reflowed(b"one\r\ntwo\r\n$ abcdefghijkl", (3, 10), (3, 5));
+        assert_eq!(rows(&parser), vec!["$ abc", "defgh", "ijkl"]);
+        assert_eq!(parser.screen().cursor_position(), (2, 4));
+    }
+
+    #[test]
+    fn keeps_modes() {
+        let parser = reflowed(b"\x1b[?2004h\x1b[?1h\x1b[?25l$ ", (5, 10), (5, 6));
+        assert!(parser.screen().bracketed_paste());
+        assert!(parser.screen().application_cursor());
+        assert!(parser.screen().hide_cursor());
+        assert_eq!(parser.screen().cursor_position(), (0, 2));
+    }
+}
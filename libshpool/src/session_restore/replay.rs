@@ -0,0 +1,329 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Limits on how much of the restore buffer gets replayed on reattach.
+
+  A big restore buffer is great for scrolling back through a noisy
+  build, but dumping tens of megabytes into a terminal emulator all at
+  once can lock it up for seconds. By default only the most recent
+  output gets replayed, and the replay can optionally be paced so that
+  the terminal has a chance to keep up.
+
+  The limits can also be overridden for a single attach with `shpool
+  attach --restore`, for example to skip the replay entirely.
+*/
+
+use std::{thread, time};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::TtySize;
+use tracing::warn;
+
+use super::{clean_start, prompts, SessionSpool as _, Vt100Spool};
+use crate::{config, size};
+
+/// How much of the restore buffer gets replayed if the user has not
+/// configured anything.
+pub const DEFAULT_MAX_REPLAY: usize = 2 * 1024 * 1024;
+
+// How often a paced replay wakes up to send the next batch.
+const PACE_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The most bytes to replay.
+    pub max_bytes: usize,
+    /// The most bytes to replay per second, if the replay is paced.
+    pub rate: Option<usize>,
+    /// The most lines to replay, if limited.
+    pub lines: Option<usize>,
+    /// The most prompts to replay, if limited.
+    pub prompts: Option<usize>,
+    /// If set, replay just what ended up on screen rather than the
+    /// raw output.
+    pub screen: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bytes: DEFAULT_MAX_REPLAY,
+            rate: None,
+            lines: None,
+            prompts: None,
+            screen: false,
+        }
+    }
+}
+
+/// A replay mode given with `attach --restore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Override {
+    /// Don't replay anything.
+    Nothing,
+    /// Replay at most this many bytes.
+    Bytes(usize),
+    /// Replay at most this many lines.
+    Lines(usize),
+    /// Replay the output since the start of this many prompts.
+    Prompts(usize),
+    /// Replay just the screen.
+    Screen,
+}
+
+impl Override {
+    /// Parse an override. This accepts everything the session_restore
+    /// config option does.
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        let src = src.trim();
+        if src.eq_ignore_ascii_case("screen") {
+            return Ok(Override::Screen);
+        }
+        if let Some(lines) = src.strip_prefix("lines:") {
+            let lines = lines.trim().parse().context("parsing line count")?;
+            return Ok(Override::Lines(lines));
+        }
+        if let Some(prompts) = src.strip_prefix("prompts:") {
+            return match prompts.trim().parse().context("parsing prompt count")? {
+                0 => Err(anyhow!("need at least one prompt")),
+                n => Ok(Override::Prompts(n)),
+            };
+        }
+        let size = src.strip_prefix("disk:").or_else(|| src.strip_prefix("zstd:")).unwrap_or(src);
+        match size::parse(size).map_err(|e| anyhow!("parsing restore mode: {}", e))? {
+            0 => Ok(Override::Nothing),
+            n => Ok(Override::Bytes(n)),
+        }
+    }
+}
+
+impl Limits {
+    /// Pull the replay limits out of the config, falling back to the
+    /// defaults for anything that does not parse.
+    pub fn from_config(config: &config::Config) -> Self {
+        let mut limits = Limits::default();
+        match config.session_restore_max_replay.as_deref().map(size::parse) {
+            Some(Ok(max_bytes)) => limits.max_bytes = max_bytes,
+            Some(Err(e)) => warn!("bad session_restore_max_replay, using default: {:?}", e),
+            None => {}
+        }
+        match config.session_restore_replay_rate.as_deref().map(parse_rate) {
+            Some(Ok(rate)) => limits.rate = rate,
+            Some(Err(e)) => warn!("bad session_restore_replay_rate, not pacing: {:?}", e),
+            None => {}
+        }
+        limits
+    }
+
+    /// Apply an override from `attach --restore`.
+    pub fn apply(&mut self, over: &Override) {
+        match over {
+            Override::Nothing => self.max_bytes = 0,
+            Override::Bytes(n) => self.max_bytes = *n,
+            Override::Lines(n) => self.lines = Some(*n),
+            Override::Prompts(n) => self.prompts = Some(*n),
+            Override::Screen => self.screen = true,
+        }
+    }
+
+    /// Cut the restore buffer down to what should get replayed to a
+    /// client with a tty of the given size.
+    pub fn trim(&self, mut buf: Vec<u8>, size: &TtySize) -> Vec<u8> {
+        if self.max_bytes == 0 || buf.is_empty() {
+            return vec![];
+        }
+        if self.screen {
+            let mut screen = Vt100Spool::new(size);
+            screen.process(&buf);
+            return screen.restore_buffer();
+        }
+        let buf = match self.prompts {
+            Some(prompts) => buf.split_off(prompts::last_prompts(&buf, prompts)),
+            None => buf,
+        };
+        let buf = match self.lines {
+            Some(lines) => last_lines(buf, lines),
+            None => buf,
+        };
+        self.trim_bytes(buf)
+    }
+
+    /// Cut the restore buffer down to the most recent `max_bytes`,
+    /// starting at a clean point.
+    fn trim_bytes(&self, mut buf: Vec<u8>) -> Vec<u8> {
+        if buf.len() <= self.max_bytes {
+            return buf;
+        }
+        let start = buf.len() - self.max_bytes;
+        buf.drain(..start + clean_start(&buf[start..], buf[..start].ends_with(b"\n")));
+        buf
+    }
+
+    /// Follow a trimmed restore buffer up with the spool's cursor fixup.
+    /// The fixup positions the cursor relative to the top of the screen,
+    /// so this only happens if the replay fills the client's screen and
+    /// the client's screen is the size the spool thinks it is.
+    pub fn fix_cursor(
+        &self,
+        mut buf: Vec<u8>,
+        fixup: &[u8],
+        spool_size: &TtySize,
+        client_size: &TtySize,
+    ) -> Vec<u8> {
+        if self.screen || fixup.is_empty() || buf.is_empty() {
+            return buf;
+        }
+        if (spool_size.rows, spool_size.cols) != (client_size.rows, client_size.cols) {
+            return buf;
+        }
+        let nlines = buf.iter().filter(|b| **b == b'\n').count();
+        if nlines < spool_size.rows as usize {
+            return buf;
+        }
+        buf.extend_from_slice(fixup);
+        buf
+    }
+
+    /// How many bytes to send per batch, and how long to wait between
+    /// batches, if the replay is paced.
+    pub fn batch(&self) -> Option<(usize, time::Duration)> {
+        let rate = self.rate?;
+        let per_batch = (rate as u128 * PACE_INTERVAL.as_millis() / 1000) as usize;
+        Some((per_batch.max(1), PACE_INTERVAL))
+    }
+}
+
+/// Keep just the last `lines` lines of output. The line the cursor is
+/// sitting on (usually the prompt) counts as one of them.
+fn last_lines(mut buf: Vec<u8>, lines: usize) -> Vec<u8> {
+    if lines == 0 {
+        return vec![];
+    }
+    let Some(start) = buf.iter().enumerate().rev().filter(|(_, b)| **b == b'\n').nth(lines - 1)
+    else {
+        return buf;
+    };
+    buf.drain(..start.0 + 1);
+    buf
+}
+
+/// Parse a rate like "1MB" (per second). "0" means no limit.
+pub fn parse_rate(src: &str) -> anyhow::Result<Option<usize>> {
+    let src = src.trim();
+    let size = src.strip_suffix("/s").unwrap_or(src);
+    let rate = size::parse(size).context("parsing rate")?;
+    Ok(if rate == 0 { None } else { Some(rate) })
+}
+
+/// Sleep off whatever is left of a pacing interval.
+pub fn pace(batch_started: time::Instant, interval: time::Duration) {
+    if let Some(left) = interval.checked_sub(batch_started.elapsed()) {
+        thread::sleep(left);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIZE: TtySize = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+
+    #[test]
+    fn trim_keeps_recent_output() {
+        let limits = Limits { max_bytes: 10, ..Default::default() };
+        assert_eq!(limits.trim(b"short".to_vec(), &SIZE), b"short");
+        assert_eq!(limits.trim(b"line one\nline two\nend".to_vec(), &SIZE), b"end");
+        assert_eq!(limits.trim(b"no newlines at all here".to_vec(), &SIZE), b"t all here");
+    }
+
+    #[test]
+    fn fix_cursor() {
+        let limits = Limits::default();
+        let small = TtySize { rows: 2, cols: 80, xpixel: 0, ypixel: 0 };
+        let fixup = b"\x1b[1;3H\x1b[m";
+
+        let full = b"one\r\ntwo\r\n$ ".to_vec();
+        let mut fixed = full.clone();
+        fixed.extend_from_slice(fixup);
+        assert_eq!(limits.fix_cursor(full.clone(), fixup, &small, &small), fixed);
+
+        // too short to fill the screen
+        let short = b"$ ".to_vec();
+        assert_eq!(limits.fix_cursor(short.clone(), fixup, &small, &small), short);
+
+        // the client's screen does not match the spool's
+        assert_eq!(limits.fix_cursor(full.clone(), fixup, &small, &SIZE), full);
+
+        // the screen replay already puts the cursor in the right place
+        let screen = Limits { screen: true, ..Default::default() };
+        assert_eq!(screen.fix_cursor(full.clone(), fixup, &small, &small), full);
+    }
+
+    #[test]
+    fn overrides() -> anyhow::Result<()> {
+        assert_eq!(Override::parse("0")?, Override::Nothing);
+        assert_eq!(Override::parse("1KiB")?, Override::Bytes(1024));
+        assert_eq!(Override::parse("zstd:1KB")?, Override::Bytes(1000));
+        assert_eq!(Override::parse("screen")?, Override::Screen);
+        assert_eq!(Override::parse("lines:100")?, Override::Lines(100));
+        assert!(Override::parse("lines:many").is_err());
+        assert_eq!(Override::parse("prompts:3")?, Override::Prompts(3));
+        assert!(Override::parse("prompts:0").is_err());
+        assert!(Override::parse("lots").is_err());
+
+        let buf = b"one\r\ntwo\r\nthree\r\n$ ".to_vec();
+        let mut limits = Limits::default();
+        limits.apply(&Override::Lines(2));
+        assert_eq!(limits.trim(buf.clone(), &SIZE), b"three\r\n$ ");
+
+        let buf = b"\x1b]133;A$ ls\r\nfoo\r\n\x1b]133;A$ pwd\r\n/\r\n\x1b]133;A$ ".to_vec();
+        let mut limits = Limits::default();
+        limits.apply(&Override::Prompts(2));
+        assert_eq!(limits.trim(buf, &SIZE), b"\x1b]133;A$ pwd\r\n/\r\n\x1b]133;A$ ");
+
+        let buf = b"one\r\ntwo\r\nthree\r\n$ ".to_vec();
+        let mut limits = Limits::default();
+        limits.apply(&Override::Nothing);
+        assert!(limits.trim(buf.clone(), &SIZE).is_empty());
+
+        let mut limits = Limits::default();
+        limits.apply(&Override::Screen);
+        let screen = limits.trim(buf, &SIZE);
+        assert!(String::from_utf8_lossy(&screen).contains("three"));
+        Ok(())
+    }
+
+    #[test]
+    fn rates() -> anyhow::Result<()> {
+        assert_eq!(parse_rate("1MB")?, Some(1000 * 1000));
+        assert_eq!(parse_rate("512KiB/s")?, Some(512 * 1024));
+        assert_eq!(parse_rate("0")?, None);
+        assert!(parse_rate("fast").is_err());
+
+        let limits = Limits { max_bytes: 100, rate: Some(1000), ..Default::default() };
+        assert_eq!(limits.batch(), Some((50, PACE_INTERVAL)));
+        assert_eq!(Limits::default().batch(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn from_config() {
+        let config = config::Config {
+            session_restore_max_replay: Some(String::from("1KiB")),
+            session_restore_replay_rate: Some(String::from("bogus")),
+            ..Default::default()
+        };
+        assert_eq!(Limits::from_config(&config), Limits { max_bytes: 1024, ..Default::default() });
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Swapping the spools of idle sessions out to disk.
+
+  A detached session that is not producing any output has no need to
+  keep its restore buffer in memory, so once it has been idle for long
+  enough the shell->client thread hands the restore buffer to a
+  SwapFile, which compresses it into the session's runtime directory,
+  and replaces the spool with an empty one. The next time the spool
+  is needed (either because the shell printed something or because a
+  client reattached), the buffer gets loaded back and replayed into
+  the fresh spool.
+*/
+
+use std::{
+    fs,
+    io::Write as _,
+    os::unix::fs::OpenOptionsExt as _,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+// Terminal output is very repetitive, so even the fastest levels
+// compress it well.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// A file that a spool can be swapped out to. The file is removed
+/// when the data is loaded back in or when the SwapFile is dropped.
+pub struct SwapFile {
+    path: PathBuf,
+    swapped: bool,
+}
+
+impl SwapFile {
+    pub fn new(path: PathBuf) -> Self {
+        SwapFile { path, swapped: false }
+    }
+
+    /// True if there is currently data swapped out to disk.
+    pub fn is_swapped(&self) -> bool {
+        self.swapped
+    }
+
+    /// Compress the given data into the swap file.
+    pub fn store(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("creating swap dir")?;
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)
+            .context("opening swap file")?;
+        let mut encoder =
+            zstd::Encoder::new(file, COMPRESSION_LEVEL).context("creating swap encoder")?;
+        encoder.write_all(data).context("writing swap file")?;
+        encoder.finish().context("finishing swap file")?;
+        self.swapped = true;
+        info!("swapped {} bytes out to {:?}", data.len(), self.path);
+
+        Ok(())
+    }
+
+    /// Load the swapped out data back into memory, removing the file.
+    /// The file is gone afterwards even if it could not be read, so a
+    /// broken swap file only costs the restore buffer once rather than
+    /// failing again every time the spool is needed.
+    pub fn load(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.swapped = false;
+        let data = fs::File::open(&self.path)
+            .context("opening swap file")
+            .and_then(|file| zstd::decode_all(file).context("decoding swap file"));
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("removing swap file: {:?}", e);
+        }
+        let data = data?;
+        info!("swapped {} bytes back in from {:?}", data.len(), self.path);
+
+        Ok(data)
+    }
+}
+
+impl Drop for SwapFile {
+    fn drop(&mut self) {
+        if self.swapped
+            && let Err(e) = fs::remove_file(&self.path)
+        {
+            warn!("cleaning up swap file: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("sessions").join("sess").join("spool.zst");
+        let data = b"some shell output\r\n".repeat(1000);
+
+        let mut swap = SwapFile::new(path.clone());
+        assert!(!swap.is_swapped());
+        swap.store(&data)?;
+        assert!(swap.is_swapped());
+        assert!(path.exists());
+        assert!(fs::metadata(&path)?.len() < data.len() as u64);
+
+        assert_eq!(swap.load()?, data);
+        assert!(!swap.is_swapped());
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_failure_gives_up() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("spool.zst");
+        let mut swap = SwapFile::new(path.clone());
+        swap.store(b"data")?;
+        fs::write(&path, b"not zstd")?;
+
+        assert!(swap.load().is_err());
+        assert!(!swap.is_swapped());
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cleans_up_on_drop() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("spool.zst");
+        {
+            let mut swap = SwapFile::new(path.clone());
+            swap.store(b"data")?;
+            assert!(path.exists());
+        }
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
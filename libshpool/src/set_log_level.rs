@@ -14,12 +14,18 @@
 
 use std::{io, path::PathBuf};
 
-use anyhow::Context;
-use shpool_protocol::{ConnectHeader, LogLevel, SetLogLevelReply, SetLogLevelRequest};
+use anyhow::{anyhow, Context};
+use clap::ValueEnum as _;
+use shpool_protocol::{capability, ConnectHeader, LogLevel, SetLogLevelReply, SetLogLevelRequest};
+use tracing_subscriber::filter::{LevelFilter, Targets};
 
 use crate::{protocol, protocol::ClientResult};
 
-pub fn run(level: LogLevel, socket: PathBuf) -> anyhow::Result<()> {
+pub fn run(filter: String, socket: PathBuf) -> anyhow::Result<()> {
+    let request = parse_request(&filter)?;
+    // catch typos before bothering the daemon
+    targets(&request)?;
+
     let mut client = match protocol::Client::new(socket) {
         Ok(ClientResult::JustClient(c)) => c,
         Ok(ClientResult::VersionMismatch { warning, client }) => {
@@ -35,10 +41,88 @@ pub fn run(level: LogLevel, socket: PathBuf) -> anyhow::Result<()> {
         }
     };
 
+    if request.filter.is_some() {
+        client.require(capability::LOG_FILTER, "per-module log filters")?;
+    }
     client
-        .write_connect_header(ConnectHeader::SetLogLevel(SetLogLevelRequest { level }))
+        .write_connect_header(ConnectHeader::SetLogLevel(request))
         .context("sending set-log-level header")?;
-    let _reply: SetLogLevelReply = client.read_reply().context("reading reply")?;
+    let reply: SetLogLevelReply = client.read_reply().context("reading reply")?;
+    if let Some(err) = reply.error {
+        eprintln!("shpool: {err}");
+        return Err(anyhow!("setting log level: {}", err));
+    }
 
     Ok(())
 }
+
+/// Turn the argument to `shpool set-log-level` into a request. A bare
+/// level gets sent the old way so that older daemons still understand
+/// it, anything else is sent as a filter.
+fn parse_request(filter: &str) -> anyhow::Result<SetLogLevelRequest> {
+    let filter = filter.trim();
+    if filter.is_empty() {
+        return Err(anyhow!("empty log filter"));
+    }
+    Ok(match LogLevel::from_str(filter, true) {
+        Ok(level) => SetLogLevelRequest { level, filter: None },
+        Err(_) => SetLogLevelRequest { level: LogLevel::default(), filter: Some(filter.into()) },
+    })
+}
+
+/// The filter the daemon should install for the given request.
+pub fn targets(request: &SetLogLevelRequest) -> anyhow::Result<Targets> {
+    match &request.filter {
+        Some(filter) => filter.parse().with_context(|| format!("parsing log filter '{filter}'")),
+        None => Ok(Targets::new().with_default(level_filter(&request.level))),
+    }
+}
+
+fn level_filter(level: &LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::OFF,
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tracing::Level;
+
+    use super::*;
+
+    #[test]
+    fn plain_level() -> anyhow::Result<()> {
+        let request = parse_request("Debug")?;
+        assert!(matches!(request.level, LogLevel::Debug));
+        assert_eq!(request.filter, None);
+
+        let targets = targets(&request)?;
+        assert!(targets.would_enable("libshpool::daemon::shell", &Level::DEBUG));
+        assert!(!targets.would_enable("libshpool::daemon::shell", &Level::TRACE));
+        Ok(())
+    }
+
+    #[test]
+    fn per_module() -> anyhow::Result<()> {
+        let request = parse_request("libshpool::daemon::shell=trace,info")?;
+        assert_eq!(request.filter.as_deref(), Some("libshpool::daemon::shell=trace,info"));
+
+        let targets = targets(&request)?;
+        assert!(targets.would_enable("libshpool::daemon::shell", &Level::TRACE));
+        assert!(!targets.would_enable("libshpool::daemon::server", &Level::DEBUG));
+        assert!(targets.would_enable("libshpool::daemon::server", &Level::INFO));
+        Ok(())
+    }
+
+    #[test]
+    fn bad_filter() {
+        assert!(parse_request("  ").is_err());
+        let request = parse_request("libshpool=loud").expect("any non-level to be a filter");
+        assert!(targets(&request).is_err());
+    }
+}
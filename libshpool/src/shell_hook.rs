@@ -0,0 +1,169 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Shell integration for refreshing the environment on reattach.
+
+  Every attach, the daemon rewrites the refresh scripts in the
+  session dir (see daemon/refresh_env.rs). `shpool shell-hook <shell>`
+  prints a snippet for the user's rc file which installs a prompt
+  hook that sources the right script whenever its generation line
+  changes, so the running shell picks up the new values at its next
+  prompt rather than only processes started by a fresh shell. Outside
+  of a shpool session the hook does nothing. The nushell and xonsh
+  hooks load the same values out of refresh.json instead, since
+  neither can source a script picked at runtime.
+*/
+
+/// The shells we can print integration for.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Nu,
+    Xonsh,
+}
+
+const BASH: &str = r#"# shpool shell integration, from `shpool shell-hook bash`
+__shpool_refresh() {
+    local ret=$? gen
+    if [ -n "$SHPOOL_SESSION_DIR" ] && [ -r "$SHPOOL_SESSION_DIR/refresh.env" ]; then
+        IFS= read -r gen < "$SHPOOL_SESSION_DIR/refresh.env"
+        if [ "$gen" != "$__shpool_refresh_gen" ]; then
+            __shpool_refresh_gen=$gen
+            . "$SHPOOL_SESSION_DIR/refresh.env"
+        fi
+    fi
+    return $ret
+}
+if [[ ";${PROMPT_COMMAND:-};" != *";__shpool_refresh;"* ]]; then
+    PROMPT_COMMAND="__shpool_refresh${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+fi
+"#;
+
+const ZSH: &str = r#"# shpool shell integration, from `shpool shell-hook zsh`
+__shpool_refresh() {
+    local gen
+    if [[ -n $SHPOOL_SESSION_DIR && -r $SHPOOL_SESSION_DIR/refresh.env ]]; then
+        IFS= read -r gen < "$SHPOOL_SESSION_DIR/refresh.env"
+        if [[ $gen != "$__shpool_refresh_gen" ]]; then
+            __shpool_refresh_gen=$gen
+            . "$SHPOOL_SESSION_DIR/refresh.env"
+        fi
+    fi
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd __shpool_refresh
+"#;
+
+const FISH: &str = r#"# shpool shell integration, from `shpool shell-hook fish`
+function __shpool_refresh --on-event fish_prompt
+    set -q SHPOOL_SESSION_DIR; or return
+    set -l script $SHPOOL_SESSION_DIR/refresh.fish
+    test -r $script; or return
+    read -l gen < $script
+    test "$gen" = "$__shpool_refresh_gen"; and return
+    set -g __shpool_refresh_gen $gen
+    source $script
+end
+"#;
+
+const NU: &str = r#"# shpool shell integration, from `shpool shell-hook nu`
+$env.config.hooks.pre_prompt = ($env.config.hooks.pre_prompt? | default [] | append {||
+    if ($env.SHPOOL_SESSION_DIR? | is-empty) { return }
+    let path = ($env.SHPOOL_SESSION_DIR | path join refresh.json)
+    if not ($path | path exists) { return }
+    let refresh = (open $path)
+    if $refresh.generation == ($env.__SHPOOL_REFRESH_GEN? | default 0) { return }
+    $env.__SHPOOL_REFRESH_GEN = $refresh.generation
+    load-env $refresh.set
+    hide-env -i ...$refresh.unset
+})
+"#;
+
+const XONSH: &str = r#"# shpool shell integration, from `shpool shell-hook xonsh`
+__shpool_refresh_gen = [None]
+@events.on_pre_prompt
+def __shpool_refresh(**_):
+    import json, os
+    session_dir = ${...}.get('SHPOOL_SESSION_DIR')
+    if not session_dir:
+        return
+    try:
+        with open(os.path.join(session_dir, 'refresh.json')) as f:
+            refresh = json.load(f)
+    except (OSError, ValueError):
+        return
+    if refresh['generation'] == __shpool_refresh_gen[0]:
+        return
+    __shpool_refresh_gen[0] = refresh['generation']
+    ${...}.update(refresh['set'])
+    for var in refresh['unset']:
+        ${...}.pop(var, None)
+"#;
+
+pub fn run(shell: Shell) -> anyhow::Result<()> {
+    print!("{}", snippet(shell));
+    Ok(())
+}
+
+fn snippet(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+        Shell::Nu => NU,
+        Shell::Xonsh => XONSH,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn bash_sources_new_generations() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let script = tmp_dir.path().join("refresh.env");
+        let test = format!(
+            r#"{}
+            printf '# shpool refresh 1\nexport DISPLAY=:1\n' > "$SHPOOL_SESSION_DIR/refresh.env"
+            __shpool_refresh; first=$DISPLAY
+            DISPLAY=changed-by-hand
+            __shpool_refresh; second=$DISPLAY
+            printf '# shpool refresh 2\nexport DISPLAY=:2\n' > "$SHPOOL_SESSION_DIR/refresh.env"
+            __shpool_refresh; echo "$first $second $DISPLAY""#,
+            snippet(Shell::Bash)
+        );
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(test)
+            .env("SHPOOL_SESSION_DIR", tmp_dir.path())
+            .output()?;
+        assert!(script.exists());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), ":1 changed-by-hand :2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn bash_keeps_exit_status() -> anyhow::Result<()> {
+        let test = format!("{}\nfalse; __shpool_refresh; echo $?", snippet(Shell::Bash));
+        let output =
+            Command::new("bash").arg("-c").arg(test).env_remove("SHPOOL_SESSION_DIR").output()?;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+        Ok(())
+    }
+}
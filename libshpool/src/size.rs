@@ -0,0 +1,129 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! A parser for the memory sizes that show up all over the config,
+  like `session_restore`, `log.max_size` and the `[limits]` table.
+
+  Sizes are a number, optionally with a fractional part, followed by
+  an optional unit. The decimal units (`KB`, `MB`, `GB`, `TB`) count in
+  powers of 1000 and the binary ones (`KiB`, `MiB`, `GiB`, `TiB`) in
+  powers of 1024. A bare number or one with a `B` suffix is a count of
+  bytes. Units are case insensitive.
+*/
+
+use anyhow::{anyhow, Context};
+
+/// Parse a size like "5MB", "1GiB", "512" or "1.5KB" into bytes.
+/// Fractional sizes get rounded down to a whole number of bytes.
+pub fn parse(src: &str) -> anyhow::Result<usize> {
+    let src = src.trim();
+    let split = src.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(src.len());
+    let (num, unit) = src.split_at(split);
+    let unit = unit.trim();
+    if num.is_empty() {
+        return Err(anyhow!(
+            "could not parse '{}' as a size, use formats like '5MB', '1GiB', '512KB' or '4096'",
+            src
+        ));
+    }
+    let multiplier = unit_size(unit).ok_or(anyhow!(
+        "unknown size unit '{}', use B, KB, MB, GB, TB, KiB, MiB, GiB or TiB",
+        unit
+    ))?;
+
+    let (whole, frac) = num.split_once('.').unwrap_or((num, ""));
+    let whole: u128 = match whole {
+        "" => 0,
+        whole => whole.parse().with_context(|| format!("parsing '{src}' as a size"))?,
+    };
+    let too_big = || anyhow!("'{}' is too big", src);
+    let mut bytes = whole.checked_mul(multiplier).ok_or_else(too_big)?;
+    if !frac.is_empty() {
+        let digits: u32 = frac.len().try_into().context("fractional part too long")?;
+        let scale = 10u128.checked_pow(digits).ok_or(anyhow!("fractional part too long"))?;
+        let frac: u128 = frac.parse().with_context(|| format!("parsing '{src}' as a size"))?;
+        bytes += frac.checked_mul(multiplier).ok_or_else(too_big)? / scale;
+    }
+    usize::try_from(bytes).map_err(|_| too_big())
+}
+
+/// How many bytes a unit stands for.
+fn unit_size(unit: &str) -> Option<u128> {
+    Some(match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "KIB" => 1 << 10,
+        "MIB" => 1 << 20,
+        "GIB" => 1 << 30,
+        "TIB" => 1 << 40,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn successes() {
+        let cases = vec![
+            ("0", 0),
+            ("512", 512),
+            ("512B", 512),
+            ("512KB", 512 * 1000),
+            ("5MB", 5 * 1000 * 1000),
+            ("2GB", 2 * 1000 * 1000 * 1000),
+            ("1TB", 1000 * 1000 * 1000 * 1000),
+            ("512KiB", 512 * 1024),
+            ("5MiB", 5 * 1024 * 1024),
+            ("1GiB", 1024 * 1024 * 1024),
+            ("1TiB", 1024 * 1024 * 1024 * 1024),
+            ("1.5KB", 1500),
+            ("1.5MiB", 3 * 512 * 1024),
+            ("0.5B", 0),
+            (".5KiB", 512),
+            // case insensitive
+            ("1mb", 1000 * 1000),
+            ("1Mb", 1000 * 1000),
+            ("1kib", 1024),
+            // surrounding and inner whitespace
+            ("  5MB  ", 5 * 1000 * 1000),
+            ("5 MiB", 5 * 1024 * 1024),
+        ];
+        for (src, want) in cases.into_iter() {
+            assert_eq!(parse(src).unwrap(), want, "parsing {src}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let cases = vec![
+            ("invalid", "could not parse"),
+            ("MB", "could not parse"),
+            ("", "could not parse"),
+            ("5PB", "unknown size unit"),
+            ("5M", "unknown size unit"),
+            ("1.2.3MB", "parsing"),
+            ("99999999999999999999999TB", "too big"),
+            ("999999999999999999999999999999999TiB", "too big"),
+        ];
+        for (src, err_substring) in cases.into_iter() {
+            let err = parse(src).unwrap_err();
+            assert!(format!("{err:?}").contains(err_substring), "parsing {src}: {err:?}");
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The ssh-attach subcommand is meant to be run by sshd, as the
+//! RemoteCommand in a client's ssh config or as a ForceCommand on the
+//! server, to put every interactive login into a shpool session. Since
+//! nobody is around to answer questions, it works out a session name by
+//! itself, and logins that are not interactive just get a plain shell
+//! so that things like scp and rsync keep working. File transfers that
+//! come in through a ForceCommand skip the shell entirely, since
+//! anything a shell rc file prints would corrupt them, and sshd's
+//! `internal-sftp` is not a program a shell could find anyway.
+
+use std::{
+    env,
+    io::{self, IsTerminal as _},
+    os::unix::process::CommandExt as _,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{Session, SessionStatus};
+use tracing::info;
+
+use crate::{attach, config, list, user};
+
+/// Where sshd installs sftp-server on the various distros, for clients
+/// asking for `internal-sftp`, which only exists inside sshd.
+const SFTP_SERVER_PATHS: &[&str] = &[
+    "/usr/lib/openssh/sftp-server",
+    "/usr/libexec/openssh/sftp-server",
+    "/usr/lib/ssh/sftp-server",
+    "/usr/libexec/sftp-server",
+];
+
+pub fn run(
+    config_manager: config::Manager,
+    name: Option<String>,
+    force: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    // With a ForceCommand, sshd stashes whatever the client asked to
+    // run here rather than running it.
+    if let Ok(cmd) = env::var("SSH_ORIGINAL_COMMAND")
+        && !cmd.trim().is_empty()
+    {
+        if let Some((program, args)) = transfer_command(&cmd) {
+            info!("running file transfer {:?} directly", program);
+            let err = process::Command::new(&program).args(args).exec();
+            return Err(anyhow!("running {}: {:?}", program.display(), err));
+        }
+        info!("running the command the client asked for");
+        return exec_shell(Some(&cmd));
+    }
+    if !io::stdin().is_terminal() {
+        info!("no tty, running a plain shell");
+        return exec_shell(None);
+    }
+    if env::var_os("SHPOOL_SESSION_NAME").is_some() {
+        info!("already in a session, running a plain shell");
+        return exec_shell(None);
+    }
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let base = match client_host(env::var("SSH_CONNECTION").ok().as_deref()) {
+                Some(host) => format!("ssh-{host}"),
+                None => String::from("ssh"),
+            };
+            let sessions = list::fetch(socket.clone(), &[]).context("listing sessions")?;
+            free_name(&base, &sessions)
+        }
+    };
+    info!("attaching to '{}'", name);
+    attach::run(config_manager, attach::AttachOptions { name, force, ..Default::default() }, socket)
+}
+
+/// The address of the client, given the value of $SSH_CONNECTION,
+/// which is the client address and port followed by the server address
+/// and port.
+fn client_host(ssh_connection: Option<&str>) -> Option<String> {
+    let host = ssh_connection?.split_whitespace().next()?;
+    // IPv6 addresses are fine in session names, but colons are a
+    // pain to type.
+    Some(host.replace(':', "-"))
+}
+
+/// `base`, or `base` with a number tacked on if there is already a
+/// terminal attached to `base`, so that logins from several terminals
+/// on the same machine each get their own session. Sessions whose
+/// shells have exited are skipped too.
+fn free_name(base: &str, sessions: &[Session]) -> String {
+    let taken = |name: &str| {
+        sessions.iter().any(|s| {
+            s.name == name
+                && (matches!(s.status, SessionStatus::Attached) || s.exit_status.is_some())
+        })
+    };
+    let mut name = String::from(base);
+    let mut n = 1;
+    while taken(&name) {
+        n += 1;
+        name = format!("{base}-{n}");
+    }
+    name
+}
+
+/// The program and arguments to run for `cmd` if it is a file
+/// transfer that should not go through the user's shell.
+fn transfer_command(cmd: &str) -> Option<(PathBuf, Vec<String>)> {
+    let words = shell_words::split(cmd).ok()?;
+    let (program, args) = words.split_first()?;
+    let program = match Path::new(program).file_name()?.to_str()? {
+        "internal-sftp" => SFTP_SERVER_PATHS.iter().map(PathBuf::from).find(|p| p.exists())?,
+        "sftp-server" | "scp" => PathBuf::from(program),
+        _ => return None,
+    };
+    Some((program, args.to_vec()))
+}
+
+/// Replace ourselves with the user's shell, either running `cmd` or
+/// as a login shell like sshd would have run.
+fn exec_shell(cmd: Option<&str>) -> anyhow::Result<()> {
+    let shell = match env::var("SHELL") {
+        Ok(shell) if !shell.is_empty() => shell,
+        _ => user::info().context("getting user info")?.default_shell,
+    };
+    let mut command = process::Command::new(&shell);
+    match cmd {
+        Some(cmd) => {
+            command.arg("-c").arg(cmd);
+        }
+        None => {
+            let base = Path::new(&shell).file_name().map(|n| n.to_string_lossy().into_owned());
+            command.arg0(format!("-{}", base.unwrap_or(shell.clone())));
+        }
+    }
+    let err = command.exec();
+    Err(anyhow!("running {}: {:?}", shell, err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shpool_protocol::TtySize;
+
+    #[test]
+    fn client_hosts() {
+        assert_eq!(client_host(Some("10.1.2.3 51234 10.1.2.4 22")).as_deref(), Some("10.1.2.3"));
+        assert_eq!(client_host(Some("fe80::1 51234 fe80::2 22")).as_deref(), Some("fe80--1"));
+        assert_eq!(client_host(Some("")), None);
+        assert_eq!(client_host(None), None);
+    }
+
+    #[test]
+    fn transfer_commands() {
+        assert_eq!(
+            transfer_command("scp -t 'my dir'"),
+            Some((PathBuf::from("scp"), vec![String::from("-t"), String::from("my dir")]))
+        );
+        assert_eq!(
+            transfer_command("/usr/lib/openssh/sftp-server -l INFO"),
+            Some((
+                PathBuf::from("/usr/lib/openssh/sftp-server"),
+                vec![String::from("-l"), String::from("INFO")]
+            ))
+        );
+        assert_eq!(transfer_command("rsync --server -e.iLsfxC . dir"), None);
+        assert_eq!(transfer_command("ls -l"), None);
+        assert_eq!(transfer_command("echo 'unbalanced"), None);
+        assert_eq!(transfer_command(""), None);
+    }
+
+    #[test]
+    fn free_names() {
+        let session = |name: &str, status, exit_status| Session {
+            name: String::from(name),
+            started_at_unix_ms: 0,
+            status,
+            pid: 1234,
+            tty_size: TtySize::default(),
+            tags: vec![],
+            exit_status,
+            throttled: false,
+            activity: false,
+            bell: false,
+            client_tty: None,
+            last_active_unix_ms: 0,
+            spool_bytes: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            attach_count: 1,
+            last_attached_unix_ms: None,
+            last_detached_unix_ms: None,
+            last_detach_reason: None,
+            locked: false,
+            cwd: None,
+            note: None,
+            watch_matches: 0,
+            last_watch_match: None,
+        };
+        assert_eq!(free_name("ssh-h", &[]), "ssh-h");
+        assert_eq!(
+            free_name("ssh-h", &[session("ssh-h", SessionStatus::Disconnected, None)]),
+            "ssh-h"
+        );
+        assert_eq!(
+            free_name(
+                "ssh-h",
+                &[
+                    session("ssh-h", SessionStatus::Attached, None),
+                    session("ssh-h-2", SessionStatus::Disconnected, Some(0)),
+                ]
+            ),
+            "ssh-h-3"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf, time};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, SessionStats, StatsReply};
+
+use crate::{output, output::Style, protocol, protocol::ClientResult, status::format_bytes};
+
+pub fn run(socket: PathBuf) -> anyhow::Result<()> {
+    let mut sessions = fetch(socket)?;
+
+    // busiest sessions first, since that is usually what you are looking for
+    sessions.sort_by(|a, b| b.pump_cpu_ns.cmp(&a.pump_cpu_ns).then(a.name.cmp(&b.name)));
+
+    println!("NAME\tPUMP_CPU\tIN\tOUT\tSPOOL\tATTACHES");
+    for session in sessions.iter() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            session.name,
+            cpu(session),
+            format_bytes(session.bytes_in),
+            format_bytes(session.bytes_out),
+            format_bytes(session.spool_bytes),
+            session.attach_count
+        );
+    }
+
+    Ok(())
+}
+
+/// Show everything we know about the resource usage of a single
+/// session.
+pub fn run_session(name: String, style: Style, socket: PathBuf) -> anyhow::Result<()> {
+    let sessions = fetch(socket)?;
+    let Some(session) = sessions.iter().find(|s| s.name == name) else {
+        eprintln!("not found: {name}");
+        return Err(anyhow!("not found: {}", name));
+    };
+    print!("{}", format_session(session, &style));
+
+    Ok(())
+}
+
+pub fn fetch(socket: PathBuf) -> anyhow::Result<Vec<SessionStats>> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::STATS, "stats")?;
+    client.write_connect_header(ConnectHeader::Stats).context("sending stats connect header")?;
+    let reply: StatsReply = client.read_reply().context("reading reply")?;
+
+    Ok(reply.sessions)
+}
+
+fn cpu(session: &SessionStats) -> String {
+    format_cpu(session.pump_cpu_ns)
+}
+
+pub fn format_cpu(ns: u64) -> String {
+    format!("{:.3}s", time::Duration::from_nanos(ns).as_secs_f64())
+}
+
+fn format_session(session: &SessionStats, style: &Style) -> String {
+    let mut fields = vec![
+        ("name", session.name.clone()),
+        ("pump_cpu", cpu(session)),
+        ("bytes_in", session.bytes_in.to_string()),
+        ("bytes_out", session.bytes_out.to_string()),
+        ("spool", format_bytes(session.spool_bytes)),
+        ("attaches", session.attach_count.to_string()),
+        ("queued", format_bytes(session.queued_bytes)),
+        ("dropped", format_bytes(session.dropped_bytes)),
+        ("resyncs", session.resyncs.to_string()),
+    ];
+    if let Some(procs) = &session.procs {
+        fields.push(("procs", procs.count.to_string()));
+        fields.push(("procs_cpu", format_cpu(procs.cpu_ns)));
+        fields.push(("procs_rss", format_bytes(procs.rss_bytes)));
+    }
+    if let Some(ms) = session.last_heartbeat_unix_ms {
+        fields.push(("last_heartbeat", style.timestamp(ms)));
+    }
+    output::fields(style, &fields)
+}
+
+#[cfg(test)]
+mod test {
+    use shpool_protocol::ProcUsage;
+
+    use super::*;
+
+    #[test]
+    fn session() {
+        let session = SessionStats {
+            name: String::from("main"),
+            pump_cpu_ns: 1_500_000_000,
+            bytes_in: 12,
+            bytes_out: 4096,
+            spool_bytes: 2048,
+            attach_count: 3,
+            queued_bytes: 0,
+            dropped_bytes: 3 * 1024 * 1024,
+            resyncs: 2,
+            procs: None,
+            last_heartbeat_unix_ms: None,
+        };
+        assert_eq!(
+            format_session(&session, &Style::default()),
+            "name:\tmain\npump_cpu:\t1.500s\nbytes_in:\t12\nbytes_out:\t4096\n\
+             spool:\t2.0 KiB\nattaches:\t3\nqueued:\t0 B\ndropped:\t3.0 MiB\nresyncs:\t2\n"
+        );
+
+        let session = SessionStats {
+            procs: Some(ProcUsage { count: 2, cpu_ns: 250_000_000, rss_bytes: 8192 }),
+            ..session
+        };
+        assert!(format_session(&session, &Style::default())
+            .ends_with("resyncs:\t2\nprocs:\t2\nprocs_cpu:\t0.250s\nprocs_rss:\t8.0 KiB\n"));
+
+        let session = SessionStats { last_heartbeat_unix_ms: Some(1_700_000_000_500), ..session };
+        assert!(format_session(&session, &Style::default())
+            .ends_with("procs_rss:\t8.0 KiB\nlast_heartbeat:\t2023-11-14T22:13:20.500+00:00\n"));
+    }
+}
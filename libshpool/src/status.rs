@@ -0,0 +1,107 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{
+    capability, ConnectHeader, HeapProfileReply, StatusReply, StatusRequest, SubsystemMemory,
+};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(memory: bool, heap_profile: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::STATUS, "status reports")?;
+    client
+        .write_connect_header(ConnectHeader::Status(StatusRequest { memory, heap_profile }))
+        .context("sending status connect header")?;
+    let reply: StatusReply = client.read_reply().context("reading reply")?;
+
+    println!("version:\t{}", reply.version);
+    println!("sessions:\t{}", reply.session_count);
+
+    if let Some(mem) = reply.memory {
+        let opt_bytes = |b: Option<u64>| b.map(format_bytes).unwrap_or(String::from("unknown"));
+
+        println!();
+        println!("allocator:\t{}", mem.allocator);
+        println!("allocated:\t{}", opt_bytes(mem.allocated_bytes));
+        println!("resident:\t{}", opt_bytes(mem.resident_bytes));
+        println!();
+        println!("SUBSYSTEM\tCOUNT\tESTIMATED_SIZE");
+        let rows: [(&str, &SubsystemMemory); 3] = [
+            ("spools", &mem.spools),
+            ("sessions", &mem.sessions),
+            ("connections", &mem.connections),
+        ];
+        for (name, sub) in rows {
+            println!("{}\t{}\t{}", name, sub.count, format_bytes(sub.bytes));
+        }
+    }
+
+    match reply.heap_profile {
+        Some(HeapProfileReply::Dumped(path)) => println!("\nwrote heap profile to {path}"),
+        Some(HeapProfileReply::Failed(err)) => {
+            return Err(anyhow!("dumping heap profile: {err}"));
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Format a byte count in human readable binary units.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_bytes_units() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 + 512 * 1024), "5.5 MiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
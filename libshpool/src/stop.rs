@@ -0,0 +1,67 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `daemon stop` subcommand shuts the daemon down gracefully. The
+//! daemon hangs up on every shell, waits out the grace period for them
+//! to exit, kills whatever is left and then exits itself, cleaning up
+//! its socket on the way out.
+
+use std::{io, path::PathBuf};
+
+use anyhow::Context;
+use shpool_protocol::{capability, ConnectHeader, StopReply, StopRequest};
+
+use crate::{duration, protocol, protocol::ClientResult};
+
+pub fn run(grace: String, socket: PathBuf) -> anyhow::Result<()> {
+    let grace = duration::parse(&grace).context("parsing grace period")?;
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::STOP, "stopping gracefully, use kill instead")?;
+    client
+        .write_connect_header(ConnectHeader::Stop(StopRequest {
+            grace_ms: grace.as_millis() as u64,
+        }))
+        .context("writing stop request header")?;
+
+    let reply: StopReply = client.read_reply().context("reading reply")?;
+    if reply.already_stopping {
+        println!("daemon is already stopping");
+        return Ok(());
+    }
+    for session in reply.killed.iter() {
+        println!("killed {session}, it did not exit in time");
+    }
+    println!(
+        "daemon stopped, {} sessions exited, {} killed",
+        reply.exited.len(),
+        reply.killed.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,190 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Restarting the command of a session when it dies.
+
+  Sessions created with `attach --cmd ... --restart <policy>` don't run
+  the command directly. Instead the daemon runs `shpool supervise` in
+  the session's pty, which runs the command and starts it again when it
+  exits, backing off exponentially if it keeps dying right away. Since
+  the supervisor holds on to the pty the whole time, the session stays
+  around and clients can stay attached across restarts.
+
+  ^C goes to the whole foreground process group, so the supervisor sees
+  it too. If the command exits after one, however it exits, the user is
+  taken to want it stopped, and it is not restarted. The same goes for
+  a ^C while waiting to restart.
+*/
+
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use signal_hook::consts::SIGINT;
+
+/// How long to wait before the first restart.
+const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+/// The longest we will wait between restarts.
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+/// A command which ran for this long before dying is taken to have
+/// been working, so the backoff starts over.
+const HEALTHY_AFTER: time::Duration = time::Duration::from_secs(60);
+/// How often to check for a ^C while backing off.
+const POLL_DUR: time::Duration = time::Duration::from_millis(100);
+
+/// When to restart a session's command.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Only when it exits with a non-zero status or gets killed.
+    OnFailure,
+    /// Whenever it exits.
+    Always,
+}
+
+impl Policy {
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        <Policy as clap::ValueEnum>::from_str(src, false)
+            .map_err(|_| anyhow!("unknown restart policy '{src}', want on-failure or always"))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Policy::OnFailure => "on-failure",
+            Policy::Always => "always",
+        }
+    }
+
+    fn should_restart(&self, status: process::ExitStatus) -> bool {
+        use std::os::unix::process::ExitStatusExt as _;
+        if status.signal() == Some(SIGINT) {
+            return false;
+        }
+        match self {
+            Policy::OnFailure => !status.success(),
+            Policy::Always => true,
+        }
+    }
+}
+
+/// How long to wait before restarting a command that ran for `ran_for`,
+/// given how long we waited last time.
+fn next_backoff(last: Option<time::Duration>, ran_for: time::Duration) -> time::Duration {
+    match last {
+        Some(last) if ran_for < HEALTHY_AFTER => (last * 2).min(MAX_BACKOFF),
+        _ => MIN_BACKOFF,
+    }
+}
+
+/// Run `cmd` until the policy says to stop, then exit with its status.
+pub fn run(policy: Policy, cmd: Vec<String>) -> anyhow::Result<()> {
+    let (program, args) = cmd.split_first().ok_or(anyhow!("no command to supervise"))?;
+    // A handler rather than ignoring the signal, so that the command
+    // gets the default behavior back when it execs.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&interrupted))
+        .context("registering SIGINT handler")?;
+
+    let display = shell_words::join(&cmd);
+    let mut backoff = None;
+    loop {
+        let started_at = time::Instant::now();
+        let status = process::Command::new(program)
+            .args(args)
+            .status()
+            .with_context(|| format!("running {display}"))?;
+        if interrupted.load(Ordering::Relaxed) || !policy.should_restart(status) {
+            process::exit(exit_code(status));
+        }
+
+        let wait = next_backoff(backoff, started_at.elapsed());
+        backoff = Some(wait);
+        eprintln!(
+            "\r\n[shpool: {display} {}, restarting in {}s]\r",
+            describe(status),
+            wait.as_secs()
+        );
+        let deadline = time::Instant::now() + wait;
+        while time::Instant::now() < deadline {
+            if interrupted.load(Ordering::Relaxed) {
+                eprintln!("\r\n[shpool: not restarting {display}]\r");
+                process::exit(exit_code(status));
+            }
+            thread::sleep(POLL_DUR);
+        }
+    }
+}
+
+fn exit_code(status: process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt as _;
+    status.code().or(status.signal().map(|signal| 128 + signal)).unwrap_or(1)
+}
+
+fn describe(status: process::ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt as _;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with status {code}"),
+        (None, Some(signal)) => format!("was killed by signal {signal}"),
+        (None, None) => String::from("exited"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt as _;
+
+    use super::*;
+
+    #[test]
+    fn restarts() {
+        let exited = |code: i32| process::ExitStatus::from_raw(code << 8);
+        let killed = process::ExitStatus::from_raw;
+        assert!(!Policy::OnFailure.should_restart(exited(0)));
+        assert!(Policy::OnFailure.should_restart(exited(1)));
+        assert!(Policy::OnFailure.should_restart(killed(libc::SIGTERM)));
+        assert!(Policy::Always.should_restart(exited(0)));
+        assert!(!Policy::Always.should_restart(killed(libc::SIGINT)));
+
+        assert_eq!(exit_code(exited(3)), 3);
+        assert_eq!(exit_code(killed(libc::SIGINT)), 130);
+        assert_eq!(describe(exited(3)), "exited with status 3");
+        assert_eq!(describe(killed(9)), "was killed by signal 9");
+    }
+
+    #[test]
+    fn backoff() {
+        let secs = time::Duration::from_secs;
+        assert_eq!(next_backoff(None, secs(0)), secs(1));
+        assert_eq!(next_backoff(Some(secs(1)), secs(0)), secs(2));
+        assert_eq!(next_backoff(Some(secs(32)), secs(5)), secs(60));
+        assert_eq!(next_backoff(Some(secs(60)), secs(5)), secs(60));
+        // it was up for a good while, so start over
+        assert_eq!(next_backoff(Some(secs(60)), secs(120)), secs(1));
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(Policy::parse("on-failure").unwrap(), Policy::OnFailure);
+        assert_eq!(Policy::parse("always").unwrap(), Policy::Always);
+        assert!(Policy::parse("never").is_err());
+        for policy in [Policy::OnFailure, Policy::Always] {
+            assert_eq!(Policy::parse(policy.as_str()).unwrap(), policy);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The switch subcommand moves the terminal attached to a session over
+//! to another session, the same way the switch keybinding does.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{
+    capability, ConnectHeader, SessionMessageReply, SessionMessageRequest,
+    SessionMessageRequestPayload, SwitchReply, SwitchRequest,
+};
+
+use crate::{common, protocol, protocol::ClientResult};
+
+/// The switch target that means the session the terminal was on
+/// before the current one, like `cd -`.
+pub const PREVIOUS_SESSION: &str = "-";
+
+pub fn run(
+    target: Option<String>,
+    previous: bool,
+    session: Option<String>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let target = if previous { String::from(PREVIOUS_SESSION) } else { target.unwrap_or_default() };
+    if target.is_empty() {
+        eprintln!("blank session names are not allowed");
+        return Err(anyhow!("blank session names are not allowed"));
+    }
+
+    let mut sessions: Vec<String> = session.into_iter().collect();
+    common::resolve_sessions(&mut sessions, "switch")?;
+    let session = sessions.remove(0);
+
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::SWITCH, "switching sessions")?;
+    client
+        .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
+            session_name: session.clone(),
+            payload: SessionMessageRequestPayload::Switch(SwitchRequest { target }),
+        }))
+        .context("writing switch request")?;
+
+    let reply: SessionMessageReply = client.read_reply().context("reading switch reply")?;
+    match reply {
+        SessionMessageReply::Switch(SwitchReply::Ok) => Ok(()),
+        SessionMessageReply::Switch(SwitchReply::NoPrevious) => {
+            eprintln!("'{session}' has not been switched to from another session");
+            Err(anyhow!("no previous session for '{}'", session))
+        }
+        SessionMessageReply::NotFound => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+        SessionMessageReply::NotAttached => {
+            eprintln!("not attached: {session}");
+            Err(anyhow!("not attached: {}", session))
+        }
+        reply => Err(anyhow!("unexpected switch reply: {:?}", reply)),
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf, process};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, TailReply, TailRequest};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(session: String, follow: bool, strip_ansi: bool, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::TAIL, "tailing output")?;
+    client
+        .write_connect_header(ConnectHeader::Tail(TailRequest { session: session.clone(), follow }))
+        .context("writing tail request header")?;
+
+    let reply: TailReply = client.read_reply().context("reading reply")?;
+    match reply {
+        TailReply::Streaming => {}
+        TailReply::NotFound => {
+            eprintln!("not found: {session}");
+            return Err(anyhow!("not found: {}", session));
+        }
+        TailReply::Locked => {
+            eprintln!("session '{session}' is locked");
+            return Err(anyhow!("session '{}' is locked", session));
+        }
+    }
+
+    // Output goes straight to stdout as it comes in. The terminal is
+    // left alone, so stdin and ^C keep working the way they normally do.
+    let stdout = io::stdout().lock();
+    let status = if strip_ansi {
+        client.read_output(&mut strip_ansi_escapes::Writer::new(stdout))
+    } else {
+        client.read_output(&mut { stdout })
+    }
+    .context("streaming output")?;
+    if status != 0 {
+        process::exit(status);
+    }
+
+    Ok(())
+}
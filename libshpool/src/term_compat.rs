@@ -0,0 +1,465 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Making up for a terminal that can do less than the session expects.
+
+  A session's shell is started with the TERM (and COLORTERM) of the
+  first terminal to attach, and programs running in it keep emitting
+  output for that terminal even after someone reattaches from a dumber
+  one. The client can't fix everything, but colors are the most common
+  problem, and they are easy to translate: true-color sequences get
+  mapped to the closest color in the 256 color palette, 256 color ones
+  to the 16 basic colors and so on, down to dropping color entirely.
+*/
+
+use std::env;
+
+use shpool_protocol::SessionTerm;
+
+use crate::config::TermMismatch;
+
+/// The longest escape sequence we will hold on to waiting for the
+/// rest of it to show up in the next chunk. Real color sequences are
+/// well under this.
+const MAX_PENDING: usize = 64;
+
+/// How many colors a terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    Mono,
+    Ansi8,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    /// The color depth of the given terminal, or None if we can't
+    /// tell.
+    pub fn of(term: Option<&str>, colorterm: Option<&str>) -> Option<Self> {
+        if matches!(colorterm, Some("truecolor" | "24bit")) {
+            return Some(ColorDepth::TrueColor);
+        }
+        let term = term.filter(|t| !t.is_empty())?;
+        if term.ends_with("-direct") {
+            return Some(ColorDepth::TrueColor);
+        }
+        let info = termini::TermInfo::from_name(term).ok()?;
+        let colors =
+            info.number_cap(termini::NumberCapability::MaxColors).map(i64::from).unwrap_or(0);
+        Some(match colors {
+            256.. => ColorDepth::Ansi256,
+            16.. => ColorDepth::Ansi16,
+            8.. => ColorDepth::Ansi8,
+            _ => ColorDepth::Mono,
+        })
+    }
+}
+
+/// The terminal `shpool attach` is running in.
+pub struct Local {
+    term: Option<String>,
+    depth: Option<ColorDepth>,
+    mode: TermMismatch,
+}
+
+impl Local {
+    pub fn from_env(mode: TermMismatch) -> Self {
+        let term = env::var("TERM").ok();
+        let colorterm = env::var("COLORTERM").ok();
+        let depth = ColorDepth::of(term.as_deref(), colorterm.as_deref());
+        Local { term, depth, mode }
+    }
+
+    /// Compare the terminal a session was started for with ours,
+    /// returning a warning for the user if they differ and a filter
+    /// to run the session's output through if it needs downgrading.
+    pub fn negotiate(&self, session: &SessionTerm) -> (Option<String>, Option<ColorFilter>) {
+        if self.mode == TermMismatch::Ignore {
+            return (None, None);
+        }
+        let Some(session_term) = session.term.as_deref().filter(|t| !t.is_empty()) else {
+            return (None, None);
+        };
+        let session_depth = ColorDepth::of(Some(session_term), session.colorterm.as_deref());
+        let downgrade = match (session_depth, self.depth) {
+            (Some(session_depth), Some(depth)) if depth < session_depth => Some(depth),
+            _ => None,
+        };
+        let local_term = self.term.as_deref().unwrap_or("");
+        if downgrade.is_none() && session_term == local_term {
+            return (None, None);
+        }
+
+        let mut warning = format!(
+            "this session was started for TERM={session_term}, but this terminal is TERM={local_term}"
+        );
+        match downgrade {
+            Some(depth) if self.mode == TermMismatch::Downgrade => {
+                warning.push_str(", downgrading its colors");
+                (Some(warning), Some(ColorFilter::new(depth)))
+            }
+            Some(_) => {
+                warning.push_str(", so colors may come out wrong");
+                (Some(warning), None)
+            }
+            None => (Some(warning), None),
+        }
+    }
+}
+
+/// Rewrites the color escape sequences in a stream of output to fit
+/// a terminal with the given color depth. Sequences split across
+/// chunks are held back until the rest of them arrives.
+pub struct ColorFilter {
+    depth: ColorDepth,
+    pending: Vec<u8>,
+}
+
+impl ColorFilter {
+    pub fn new(depth: ColorDepth) -> Self {
+        ColorFilter { depth, pending: vec![] }
+    }
+
+    /// Filter the next chunk of output into `out`.
+    pub fn filter(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        let joined;
+        let buf = if self.pending.is_empty() {
+            chunk
+        } else {
+            self.pending.extend_from_slice(chunk);
+            joined = std::mem::take(&mut self.pending);
+            &joined[..]
+        };
+
+        let mut i = 0;
+        while i < buf.len() {
+            let Some(esc) = buf[i..].iter().position(|b| *b == 0x1b) else {
+                out.extend_from_slice(&buf[i..]);
+                return;
+            };
+            out.extend_from_slice(&buf[i..i + esc]);
+            let start = i + esc;
+            match buf.get(start + 1) {
+                None => {
+                    self.pending.extend_from_slice(&buf[start..]);
+                    return;
+                }
+                Some(b'[') => {}
+                Some(_) => {
+                    out.push(0x1b);
+                    i = start + 1;
+                    continue;
+                }
+            }
+
+            // Look for the final byte of the control sequence.
+            let body = start + 2;
+            let end = buf[body..].iter().position(|b| !(0x20..=0x3f).contains(b));
+            match end.map(|end| (body + end, buf[body + end])) {
+                None if buf.len() - start <= MAX_PENDING => {
+                    self.pending.extend_from_slice(&buf[start..]);
+                    return;
+                }
+                None => {
+                    out.extend_from_slice(&buf[start..]);
+                    return;
+                }
+                Some((end, b'm')) => {
+                    match std::str::from_utf8(&buf[body..end]).ok().and_then(|p| self.sgr(p)) {
+                        Some(params) if params.is_empty() => {}
+                        Some(params) => {
+                            out.extend_from_slice(b"\x1b[");
+                            out.extend_from_slice(params.as_bytes());
+                            out.push(b'm');
+                        }
+                        None => out.extend_from_slice(&buf[start..=end]),
+                    }
+                    i = end + 1;
+                }
+                Some((end, _)) => {
+                    // Not SGR, or not a control sequence at all if the
+                    // final byte is out of range, which just means we
+                    // pass it along as is.
+                    out.extend_from_slice(&buf[start..=end]);
+                    i = end + 1;
+                }
+            }
+        }
+    }
+
+    /// Rewrite the parameters of an SGR sequence, returning None if
+    /// they can't be parsed and should be left alone. An empty result
+    /// means the sequence only set colors we have dropped, and should
+    /// be left out entirely (an empty SGR would reset everything).
+    fn sgr(&self, params: &str) -> Option<String> {
+        if params.is_empty()
+            || !params.bytes().all(|b| b.is_ascii_digit() || b == b';' || b == b':')
+        {
+            return None;
+        }
+
+        let params: Vec<&str> = params.split(';').collect();
+        let mut out: Vec<String> = vec![];
+        let mut i = 0;
+        while i < params.len() {
+            let param = params[i];
+            if param.contains(':') {
+                let parts: Vec<&str> = param.split(':').collect();
+                match (parts[0], extended_color(&parts[1..], true)) {
+                    ("38", Some((color, _))) => self.push_color(&mut out, color, false),
+                    ("48", Some((color, _))) => self.push_color(&mut out, color, true),
+                    _ => out.push(String::from(param)),
+                }
+                i += 1;
+                continue;
+            }
+
+            match param.parse::<u16>() {
+                Ok(code @ (38 | 48)) => match extended_color(&params[i + 1..], false) {
+                    Some((color, used)) => {
+                        self.push_color(&mut out, color, code == 48);
+                        i += used;
+                    }
+                    None => {
+                        // Malformed, leave the rest alone.
+                        out.extend(params[i..].iter().map(|p| String::from(*p)));
+                        break;
+                    }
+                },
+                Ok(30..=37 | 40..=47) if self.depth == ColorDepth::Mono => {}
+                Ok(code @ (90..=97 | 100..=107)) => match self.depth {
+                    ColorDepth::Mono => {}
+                    ColorDepth::Ansi8 => out.push((code - 60).to_string()),
+                    _ => out.push(String::from(param)),
+                },
+                _ => out.push(String::from(param)),
+            }
+            i += 1;
+        }
+        Some(out.join(";"))
+    }
+
+    fn push_color(&self, out: &mut Vec<String>, color: Color, background: bool) {
+        let (extended, basic, bright) = if background { (48, 40, 100) } else { (38, 30, 90) };
+        let index = match (color, self.depth) {
+            (_, ColorDepth::Mono) => return,
+            (Color::Rgb(r, g, b), ColorDepth::TrueColor) => {
+                out.push(format!("{extended};2;{r};{g};{b}"));
+                return;
+            }
+            (Color::Indexed(n), ColorDepth::TrueColor | ColorDepth::Ansi256) => n,
+            (Color::Rgb(r, g, b), ColorDepth::Ansi256) => rgb_to_256(r, g, b),
+            (color, ColorDepth::Ansi16) => nearest_basic(color.rgb(), 16),
+            (color, ColorDepth::Ansi8) => nearest_basic(color.rgb(), 8),
+        };
+        match (index, self.depth) {
+            (n, ColorDepth::TrueColor | ColorDepth::Ansi256) => {
+                out.push(format!("{extended};5;{n}"))
+            }
+            (n @ 0..8, _) => out.push((basic + n as u16).to_string()),
+            (n, _) => out.push((bright + n as u16 - 8).to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Rgb(r, g, b) => (r, g, b),
+            Color::Indexed(n) => palette(n),
+        }
+    }
+}
+
+/// Parse the color following a 38 or 48, either `5;n` or `2;r;g;b`,
+/// returning it along with the number of parameters used, not counting
+/// the 38 or 48. In the colon separated form, which is all one
+/// parameter, there may be a color space id before the components.
+fn extended_color(params: &[&str], colon: bool) -> Option<(Color, usize)> {
+    let num = |i: usize| params.get(i)?.parse::<u8>().ok();
+    match *params.first()? {
+        "5" => Some((Color::Indexed(num(1)?), 2)),
+        "2" if colon && params.len() >= 5 => Some((Color::Rgb(num(2)?, num(3)?, num(4)?), 5)),
+        "2" => Some((Color::Rgb(num(1)?, num(2)?, num(3)?), 4)),
+        _ => None,
+    }
+}
+
+/// The xterm default for the 16 basic colors.
+const BASIC: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The color at the given index of the xterm 256 color palette.
+fn palette(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..16 => BASIC[n as usize],
+        16..232 => {
+            let n = n - 16;
+            (CUBE[(n / 36) as usize], CUBE[(n / 6 % 6) as usize], CUBE[(n % 6) as usize])
+        }
+        _ => {
+            let level = 8 + 10 * (n - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// The closest color in the 6x6x6 cube or the grayscale ramp of the
+/// 256 color palette.
+fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| match v {
+        0..48 => 0,
+        48..115 => 1,
+        _ => (v - 35) / 40,
+    };
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray = if average > 238 { 255 } else { 232 + (average.saturating_sub(3) / 10) as u8 };
+    if distance(palette(gray), (r, g, b)) < distance(palette(cube), (r, g, b)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+/// The closest of the first `count` basic colors.
+fn nearest_basic(rgb: (u8, u8, u8), count: usize) -> u8 {
+    (0..count).min_by_key(|i| distance(BASIC[*i], rgb)).unwrap_or(0) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(depth: ColorDepth, chunks: &[&str]) -> String {
+        let mut filter = ColorFilter::new(depth);
+        let mut out = vec![];
+        for chunk in chunks {
+            filter.filter(chunk.as_bytes(), &mut out);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn palette_mapping() {
+        assert_eq!(rgb_to_256(0, 0, 0), 16);
+        assert_eq!(rgb_to_256(255, 0, 0), 196);
+        assert_eq!(rgb_to_256(255, 255, 255), 231);
+        assert_eq!(rgb_to_256(128, 128, 128), 244);
+        assert_eq!(rgb_to_256(95, 135, 175), 67);
+        assert_eq!(nearest_basic(palette(196), 16), 9);
+        assert_eq!(nearest_basic(palette(196), 8), 1);
+        assert_eq!(nearest_basic(palette(21), 16), 4);
+    }
+
+    #[test]
+    fn downgrades() {
+        let cases = [
+            (ColorDepth::Ansi256, "\x1b[38;2;255;0;0mred", "\x1b[38;5;196mred"),
+            (ColorDepth::Ansi256, "\x1b[1;48;2;0;0;0;4m", "\x1b[1;48;5;16;4m"),
+            (ColorDepth::Ansi256, "\x1b[38:2::255:0:0m", "\x1b[38;5;196m"),
+            (ColorDepth::Ansi256, "\x1b[38:2:255:0:0m", "\x1b[38;5;196m"),
+            (ColorDepth::Ansi256, "\x1b[38;5;100m", "\x1b[38;5;100m"),
+            (ColorDepth::TrueColor, "\x1b[38;2;1;2;3m", "\x1b[38;2;1;2;3m"),
+            (ColorDepth::Ansi16, "\x1b[38;5;196;48;5;21m", "\x1b[91;44m"),
+            (ColorDepth::Ansi16, "\x1b[38;5;3m", "\x1b[33m"),
+            (ColorDepth::Ansi16, "\x1b[0;91m", "\x1b[0;91m"),
+            (ColorDepth::Ansi8, "\x1b[38;2;255;0;0;101m", "\x1b[31;41m"),
+            (ColorDepth::Mono, "\x1b[1;31;48;5;3mbold\x1b[m", "\x1b[1mbold\x1b[m"),
+            (ColorDepth::Mono, "a\x1b[31mb", "ab"),
+            // Anything other than SGR goes through untouched.
+            (
+                ColorDepth::Mono,
+                "\x1b[2J\x1b[?25l\x1b]0;title\x07",
+                "\x1b[2J\x1b[?25l\x1b]0;title\x07",
+            ),
+            // Malformed extended colors get left alone.
+            (ColorDepth::Ansi16, "\x1b[1;38;7m", "\x1b[1;38;7m"),
+        ];
+        for (depth, input, want) in cases {
+            assert_eq!(run(depth, &[input]), want, "{depth:?} {input:?}");
+        }
+    }
+
+    #[test]
+    fn split_sequences() {
+        let input = "ab\x1b[38;2;255;0;0mcd\x1b[0m";
+        let want = "ab\x1b[38;5;196mcd\x1b[0m";
+        for split in 0..=input.len() {
+            let (first, second) = input.split_at(split);
+            assert_eq!(run(ColorDepth::Ansi256, &[first, second]), want, "split at {split}");
+        }
+
+        let long = format!("\x1b[{}", "1;".repeat(MAX_PENDING));
+        assert_eq!(run(ColorDepth::Ansi256, &[&long]), long);
+    }
+
+    #[test]
+    fn negotiation() {
+        let session = |term: &str, colorterm: Option<&str>| SessionTerm {
+            term: Some(String::from(term)),
+            colorterm: colorterm.map(String::from),
+        };
+        let local = |term: &str, depth, mode| Local { term: Some(String::from(term)), depth, mode };
+
+        let same = local("xterm-256color", Some(ColorDepth::Ansi256), TermMismatch::Downgrade);
+        let (warning, filter) = same.negotiate(&session("xterm-256color", None));
+        assert!(warning.is_none() && filter.is_none());
+
+        let (warning, filter) = same.negotiate(&session("xterm-256color", Some("truecolor")));
+        assert!(warning.unwrap().contains("downgrading"));
+        assert_eq!(filter.unwrap().depth, ColorDepth::Ansi256);
+
+        let warn = local("xterm-256color", Some(ColorDepth::Ansi256), TermMismatch::Warn);
+        let (warning, filter) = warn.negotiate(&session("xterm-direct", None));
+        assert!(warning.is_some() && filter.is_none());
+
+        let ignore = local("vt100", Some(ColorDepth::Mono), TermMismatch::Ignore);
+        let (warning, filter) = ignore.negotiate(&session("xterm-direct", None));
+        assert!(warning.is_none() && filter.is_none());
+
+        let (warning, filter) = same.negotiate(&SessionTerm::default());
+        assert!(warning.is_none() && filter.is_none());
+    }
+}
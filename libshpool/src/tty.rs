@@ -13,8 +13,12 @@
 // limitations under the License.
 
 use std::{
-    io,
-    os::{fd::BorrowedFd, unix::io::RawFd},
+    fs,
+    io::{self, BufRead as _, Write as _},
+    os::{
+        fd::{AsFd as _, BorrowedFd},
+        unix::io::RawFd,
+    },
 };
 
 use anyhow::Context;
@@ -88,6 +92,29 @@ pub fn disable_echo(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Ask for a passphrase on the controlling terminal without echoing
+/// what gets typed.
+pub fn read_passphrase(prompt: &str) -> anyhow::Result<String> {
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("opening controlling terminal")?;
+    let old = termios::tcgetattr(tty.as_fd()).context("grabbing term flags")?;
+    disable_echo(tty.as_fd())?;
+
+    let res = write!(tty, "{prompt}").and_then(|_| {
+        let mut line = String::new();
+        io::BufReader::new(&tty).read_line(&mut line)?;
+        Ok(line)
+    });
+
+    termios::tcsetattr(tty.as_fd(), SetArg::TCSANOW, &old).context("restoring term flags")?;
+    writeln!(tty).context("ending passphrase prompt")?;
+    let line = res.context("reading passphrase")?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
 pub fn set_attach_flags() -> anyhow::Result<AttachFlagsGuard<'static>> {
     // Safety: stdin is live for the whole program duration
     let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
@@ -0,0 +1,284 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*! Bringing up a declared set of sessions.
+
+  `shpool up` makes sure that every session listed in the `up` key of
+  the config exists, creating the missing ones detached. Alternatively
+  it can be pointed at a project file, which uses the same format as
+  the config but only the `up`, `start_directory` and `sessions` keys,
+  so that a project can carry its own set of sessions around with it.
+*/
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{AttachHeader, AttachReplyHeader, AttachStatus, ConnectHeader};
+use tracing::info;
+
+use crate::{
+    attach, config, duration,
+    protocol::{Client, ClientResult},
+};
+
+pub fn run(
+    config_manager: config::Manager,
+    file: Option<PathBuf>,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let config = config_manager.get();
+    let headers = match &file {
+        Some(file) => {
+            let src = std::fs::read_to_string(file).context("reading project file")?;
+            let project: config::Config = toml::from_str(&src).context("parsing project file")?;
+            let root = std::path::absolute(file)
+                .context("resolving project file path")?
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            project_headers(&project, &root)?
+        }
+        None => config_headers(&config)?,
+    };
+    if headers.is_empty() {
+        return Err(anyhow!("no sessions to bring up, list them in the 'up' config key"));
+    }
+
+    create_detached(&config, headers, &socket)
+}
+
+/// Create the sessions for the given headers detached, leaving alone
+/// any that are already running.
+pub fn create_detached(
+    config: &config::Config,
+    headers: Vec<AttachHeader>,
+    socket: &Path,
+) -> anyhow::Result<()> {
+    let local_env = attach::local_env(config);
+    let tty_size = attach::local_tty_size();
+    let mut warned = false;
+    let mut failed = vec![];
+    for mut header in headers {
+        header.local_env = local_env.clone();
+        header.local_tty_size = tty_size.clone();
+        let name = header.name.clone();
+        match create(socket, header, &mut warned)? {
+            AttachStatus::Created { .. } => println!("created {name}"),
+            AttachStatus::Attached { .. } | AttachStatus::Busy => {
+                println!("{name} is already running")
+            }
+            AttachStatus::Locked { .. } => println!("{name} is locked"),
+            AttachStatus::Forbidden(reason)
+            | AttachStatus::LimitReached(reason)
+            | AttachStatus::UnexpectedError(reason) => {
+                eprintln!("could not create {name}: {reason}");
+                failed.push(name);
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(anyhow!("could not create: {}", failed.join(", ")));
+    }
+    Ok(())
+}
+
+/// Create a single session detached, returning what the daemon made
+/// of the request.
+pub fn create(
+    socket: &Path,
+    mut header: AttachHeader,
+    warned: &mut bool,
+) -> anyhow::Result<AttachStatus> {
+    header.detached = true;
+    let name = header.name.clone();
+
+    let mut client = dial(socket, warned)?;
+    client.write_connect_header(ConnectHeader::Attach(header)).context("writing attach header")?;
+    let reply: AttachReplyHeader = client.read_reply().context("reading attach reply")?;
+    info!("'{}' reply.status={:?}", name, reply.status);
+    Ok(reply.status)
+}
+
+fn dial(socket: &Path, warned: &mut bool) -> anyhow::Result<Client> {
+    match Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => Ok(c),
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            if !*warned {
+                eprintln!("warning: {warning}, try restarting your daemon");
+                *warned = true;
+            }
+            Ok(client)
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            Err(io_err).context("connecting to daemon")
+        }
+    }
+}
+
+/// Headers for the sessions listed in the config. The daemon applies
+/// the rest of their settings itself, so all we have to work out here
+/// is where they start.
+fn config_headers(config: &config::Config) -> anyhow::Result<Vec<AttachHeader>> {
+    let mut headers = vec![];
+    for name in config.up.iter().flatten() {
+        check_name(name)?;
+        let dir = config
+            .session(name)
+            .and_then(|s| s.dir.as_deref())
+            .or(config.start_directory.as_deref());
+        let working_directory =
+            attach::resolve_working_directory(None, dir).context("resolving working directory")?;
+        headers.push(AttachHeader {
+            name: name.clone(),
+            working_directory: Some(working_directory.to_string_lossy().into()),
+            ..Default::default()
+        });
+    }
+    Ok(headers)
+}
+
+/// Headers for the sessions in a project file. The daemon only knows
+/// about its own config, so all of the settings get sent along. If the
+/// project does not list the sessions in an `up` key, all of the ones
+/// with a table of their own get created, in alphabetical order.
+///
+/// Relative directories are relative to the project file, which is
+/// also where sessions without a directory start.
+fn project_headers(project: &config::Config, root: &Path) -> anyhow::Result<Vec<AttachHeader>> {
+    let names = match &project.up {
+        Some(names) => names.clone(),
+        None => {
+            let mut names = project
+                .sessions
+                .iter()
+                .flatten()
+                .map(|(name, _)| name.clone())
+                .filter(|name| glob::Pattern::escape(name) == *name)
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        }
+    };
+
+    let mut headers = vec![];
+    for name in names {
+        check_name(&name)?;
+        let session = project.session(&name).cloned().unwrap_or_default();
+        let dir = match session.dir.as_deref().or(project.start_directory.as_deref()) {
+            Some(dir) => root.join(
+                attach::resolve_working_directory(None, Some(dir))
+                    .context("resolving working directory")?,
+            ),
+            None => root.to_path_buf(),
+        };
+        let ttl_secs = match &session.ttl {
+            Some(src) => Some(
+                duration::parse(src).with_context(|| format!("parsing ttl for {name}"))?.as_secs(),
+            ),
+            None => None,
+        };
+        let mut env = session.env.unwrap_or_default().into_iter().collect::<Vec<_>>();
+        env.sort();
+        headers.push(AttachHeader {
+            name,
+            working_directory: Some(dir.to_string_lossy().into()),
+            cmd: session.cmd,
+            startup: session.startup,
+            env,
+            ttl_secs,
+            restore_override: session.session_restore,
+            ..Default::default()
+        });
+    }
+    Ok(headers)
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("blank session names are not allowed"));
+    }
+    if name.contains(char::is_whitespace) {
+        return Err(anyhow!("whitespace is not allowed in session names: '{}'", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn project() -> anyhow::Result<()> {
+        let project: config::Config = toml::from_str(
+            r#"
+            [sessions.server]
+            dir = "backend"
+            cmd = "make serve"
+            ttl = "1h"
+            [sessions.editor]
+            startup = "nvim ."
+            [sessions.editor.env]
+            EDITOR = "nvim"
+            [sessions."test-*"]
+            dir = "/tmp"
+            "#,
+        )?;
+        let headers = project_headers(&project, Path::new("/src/project"))?;
+        let names = headers.iter().map(|h| h.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["editor", "server"]);
+
+        let editor = &headers[0];
+        assert_eq!(editor.working_directory.as_deref(), Some("/src/project"));
+        assert_eq!(editor.startup.as_deref(), Some("nvim ."));
+        assert_eq!(editor.env, vec![(String::from("EDITOR"), String::from("nvim"))]);
+
+        let server = &headers[1];
+        assert_eq!(server.working_directory.as_deref(), Some("/src/project/backend"));
+        assert_eq!(server.cmd.as_deref(), Some("make serve"));
+        assert_eq!(server.ttl_secs, Some(3600));
+
+        Ok(())
+    }
+
+    #[test]
+    fn project_up_list() -> anyhow::Result<()> {
+        let project: config::Config = toml::from_str(
+            r#"
+            up = ["server", "test-unit"]
+            start_directory = "/work"
+            [sessions."test-*"]
+            dir = "/tmp"
+            "#,
+        )?;
+        let headers = project_headers(&project, Path::new("/src/project"))?;
+        let dirs = headers
+            .iter()
+            .map(|h| (h.name.as_str(), h.working_directory.as_deref().unwrap_or("")))
+            .collect::<Vec<_>>();
+        assert_eq!(dirs, vec![("server", "/work"), ("test-unit", "/tmp")]);
+
+        let project: config::Config = toml::from_str(r#"up = ["two words"]"#)?;
+        assert!(project_headers(&project, Path::new("/")).is_err());
+
+        Ok(())
+    }
+}
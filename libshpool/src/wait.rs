@@ -0,0 +1,57 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf, process};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, WaitFor, WaitReply, WaitRequest};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(session: String, until: WaitFor, socket: PathBuf) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::WAIT, "waiting on sessions")?;
+    client
+        .write_connect_header(ConnectHeader::Wait(WaitRequest { session: session.clone(), until }))
+        .context("writing wait request header")?;
+
+    let reply: WaitReply = client.read_reply().context("reading reply")?;
+    match reply {
+        WaitReply::Activity => Ok(()),
+        // the whole point is to hand the exit status back to the caller
+        WaitReply::Exited { exit_status } if until == WaitFor::Exit => process::exit(exit_status),
+        WaitReply::Exited { exit_status } => {
+            eprintln!("'{session}' exited with status {exit_status}");
+            Err(anyhow!("'{}' exited with status {}", session, exit_status))
+        }
+        WaitReply::NotFound => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{capability, ConnectHeader, Watch, WatchReply, WatchRequest};
+
+use crate::{protocol, protocol::ClientResult};
+
+pub fn run(
+    session: String,
+    pattern: Option<String>,
+    exec: Option<String>,
+    clear: bool,
+    socket: PathBuf,
+) -> anyhow::Result<()> {
+    let mut client = match protocol::Client::new(socket) {
+        Ok(ClientResult::JustClient(c)) => c,
+        Ok(ClientResult::VersionMismatch { warning, client }) => {
+            eprintln!("warning: {warning}, try restarting your daemon");
+            client
+        }
+        Err(err) => {
+            let io_err = err.downcast::<io::Error>()?;
+            if io_err.kind() == io::ErrorKind::NotFound {
+                eprintln!("could not connect to daemon");
+            }
+            return Err(io_err).context("connecting to daemon");
+        }
+    };
+
+    client.require(capability::WATCH, "watching sessions")?;
+    let quiet = pattern.is_some() || clear;
+    client
+        .write_connect_header(ConnectHeader::Watch(WatchRequest {
+            session: session.clone(),
+            pattern,
+            exec,
+            clear,
+        }))
+        .context("writing watch request header")?;
+
+    let reply: WatchReply = client.read_reply().context("reading reply")?;
+    match reply {
+        WatchReply::Watches(watches) => {
+            if !quiet {
+                print!("{}", format_watches(&watches));
+            }
+            Ok(())
+        }
+        WatchReply::NotFound => {
+            eprintln!("not found: {session}");
+            Err(anyhow!("not found: {}", session))
+        }
+        WatchReply::BadPattern(err) => {
+            eprintln!("bad pattern: {err}");
+            Err(anyhow!("bad pattern: {}", err))
+        }
+        WatchReply::Locked => {
+            eprintln!("session '{session}' is locked");
+            Err(anyhow!("session '{}' is locked", session))
+        }
+    }
+}
+
+/// One watch per line, with the command it runs and where it came from.
+fn format_watches(watches: &[Watch]) -> String {
+    let mut out = String::new();
+    for watch in watches {
+        out.push_str(&format!(
+            "{}\t{}\t{}\n",
+            watch.pattern,
+            watch.exec.as_deref().unwrap_or("on_watch hook"),
+            if watch.from_config { "config" } else { "shpool watch" }
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let watches = vec![
+            Watch {
+                pattern: String::from("ERROR|panic"),
+                exec: Some(String::from("notify-send oops")),
+                from_config: false,
+            },
+            Watch { pattern: String::from("FAIL"), exec: None, from_config: true },
+        ];
+        assert_eq!(
+            format_watches(&watches),
+            "ERROR|panic\tnotify-send oops\tshpool watch\nFAIL\ton_watch hook\tconfig\n"
+        );
+    }
+}
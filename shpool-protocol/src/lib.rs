@@ -20,16 +20,109 @@ use serde_derive::{Deserialize, Serialize};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// The version of the wire protocol. This only gets bumped for changes
+/// that old clients or daemons can't cope with, like removing a field
+/// or changing what one means. Adding a field with a default, or a new
+/// kind of request that gets a new capability bit, doesn't need a bump.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The optional features a daemon can support, advertised as a bitset
+/// in the VersionHeader so that newer clients can check before asking
+/// an older daemon for something it doesn't know about.
+pub mod capability {
+    /// Attaching alongside another client with `attach --mirror`.
+    pub const MIRROR: u64 = 1 << 0;
+    /// Running a one-off command with `shpool exec`.
+    pub const EXEC: u64 = 1 << 1;
+    /// Dumping a session's output with `shpool capture`.
+    pub const CAPTURE: u64 = 1 << 2;
+    /// Renaming a session with `shpool rename`.
+    pub const RENAME: u64 = 1 << 3;
+    /// Handing the daemon's sessions over to a new daemon.
+    pub const TAKEOVER: u64 = 1 << 4;
+    /// Reporting memory usage with `shpool status`.
+    pub const STATUS: u64 = 1 << 5;
+    /// Reporting per session resource usage with `shpool stats`.
+    pub const STATS: u64 = 1 << 6;
+    /// Watching a session without typing into it with `attach --read-only`.
+    pub const READ_ONLY: u64 = 1 << 7;
+    /// Blocking until something happens in a session with `shpool wait`.
+    pub const WAIT: u64 = 1 << 8;
+    /// Per-module log filters with `shpool set-log-level`.
+    pub const LOG_FILTER: u64 = 1 << 9;
+    /// Picking the shell for a new session with `attach --shell`.
+    pub const SHELL: u64 = 1 << 10;
+    /// Moving an attached client to another session with `shpool switch`.
+    pub const SWITCH: u64 = 1 << 11;
+    /// Driving the daemon over line-delimited JSON with `shpool control`.
+    pub const CONTROL: u64 = 1 << 12;
+    /// Guarding a session with a passphrase with `shpool lock`.
+    pub const LOCK: u64 = 1 << 13;
+    /// Shutting the daemon down along with its sessions with
+    /// `shpool daemon stop`.
+    pub const STOP: u64 = 1 << 14;
+    /// Restarting a session's command when it exits with
+    /// `attach --restart`.
+    pub const RESTART: u64 = 1 << 15;
+    /// Moving a session over to another daemon with `shpool migrate`,
+    /// and adopting sessions moved over from one.
+    pub const MIGRATE: u64 = 1 << 16;
+    /// Checking that the daemon is alive and responsive with
+    /// `shpool ping`.
+    pub const PING: u64 = 1 << 17;
+    /// Streaming input into a session with `shpool pipe`.
+    pub const PIPE: u64 = 1 << 18;
+    /// Streaming a session's output with `shpool tail`.
+    pub const TAIL: u64 = 1 << 19;
+    /// Describing a session with `shpool note`.
+    pub const NOTE: u64 = 1 << 20;
+    /// Dumping the daemon's internal state with `shpool daemon dump-state`.
+    pub const DUMP_STATE: u64 = 1 << 21;
+    /// Watching detached output for patterns with `shpool watch`.
+    pub const WATCH: u64 = 1 << 22;
+
+    /// Everything this version of the daemon supports.
+    pub const ALL: u64 = MIRROR
+        | EXEC
+        | CAPTURE
+        | RENAME
+        | TAKEOVER
+        | STATUS
+        | STATS
+        | READ_ONLY
+        | WAIT
+        | LOG_FILTER
+        | SHELL
+        | SWITCH
+        | CONTROL
+        | LOCK
+        | STOP
+        | RESTART
+        | MIGRATE
+        | PING
+        | PIPE
+        | TAIL
+        | NOTE
+        | DUMP_STATE
+        | WATCH;
+}
+
 /// The header used to advertize daemon version.
 ///
 /// This header gets written by the daemon to every stream as
 /// soon as it is opened, which allows the client to compare
-/// version strings for protocol negotiation (basically just
-/// deciding if the user ought to be warned about mismatched
-/// versions).
-#[derive(Serialize, Deserialize, Debug)]
+/// versions and make sure it is not about to send the daemon
+/// something it won't understand.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct VersionHeader {
     pub version: String,
+    /// The daemon's PROTOCOL_VERSION, or 0 if the daemon is too old to
+    /// say.
+    #[serde(default)]
+    pub protocol: u32,
+    /// The daemon's capability bits. Only meaningful if protocol is set.
+    #[serde(default)]
+    pub capabilities: u64,
 }
 
 /// The blob of metadata that a client transmits when it
@@ -38,6 +131,9 @@ pub struct VersionHeader {
 /// It uses an enum to allow different connection types
 /// to be initiated on the same socket. The ConnectHeader is always prefixed
 /// with a 4 byte little endian unsigned word to indicate length.
+// Headers only get built once per connection, so the size of the
+// Attach variant does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ConnectHeader {
     /// Attach to the named session indicated by the given header.
@@ -63,6 +159,97 @@ pub enum ConnectHeader {
     Kill(KillRequest),
     // A request to set the log level to a new value.
     SetLogLevel(SetLogLevelRequest),
+    /// A request for information about the state of the
+    /// daemon itself rather than any particular session.
+    ///
+    /// Responds with a StatusReply.
+    Status(StatusRequest),
+    /// A request for per-session resource usage statistics.
+    ///
+    /// Responds with a StatsReply.
+    Stats,
+    /// A request to give a running session a new name.
+    ///
+    /// Responds with a RenameReply.
+    Rename(RenameRequest),
+    /// A request to type some input into a running session without
+    /// attaching to it, optionally streaming back the output.
+    ///
+    /// Responds with an ExecReply, which is followed by a stream of
+    /// chunks if the request asked for output.
+    Exec(ExecRequest),
+    /// A request for a copy of a session's restore buffer or
+    /// scrollback.
+    ///
+    /// Responds with a CaptureReply.
+    Capture(CaptureRequest),
+    /// A request from a newly started daemon to hand over the listening
+    /// socket and all running sessions so that it can take over from
+    /// this one without killing any shells. Only daemons send this.
+    ///
+    /// The reply is private to libshpool, since both sides are daemons.
+    Takeover,
+    /// A request to block until something happens in a session.
+    ///
+    /// Responds with a WaitReply once it does.
+    Wait(WaitRequest),
+    /// A request to switch the connection over to control mode.
+    ///
+    /// There is no reply. Everything after the header, in both
+    /// directions, is line-delimited JSON: ControlRequests from the
+    /// client and ControlEvents from the daemon.
+    Control(ControlHeader),
+    /// A request to lock a session so that attaching to it takes a
+    /// passphrase, or to take its passphrase away again.
+    ///
+    /// Responds with a LockReply.
+    Lock(LockRequest),
+    /// A request to shut the daemon down, giving the shells a chance
+    /// to exit first.
+    ///
+    /// Responds with a StopReply once the shells are gone, after which
+    /// the daemon exits.
+    Stop(StopRequest),
+    /// A request to move a running session over to another daemon
+    /// without killing its shell.
+    ///
+    /// Responds with a MigrateReply once the other daemon has the
+    /// session.
+    Migrate(MigrateRequest),
+    /// A daemon handing one of its sessions over to this one, see
+    /// Migrate. Only daemons send this.
+    ///
+    /// The rest of the exchange is private to libshpool, since both
+    /// sides are daemons.
+    Adopt,
+    /// A request to check that the daemon is alive and responsive,
+    /// optionally by way of a session.
+    ///
+    /// Responds with a PingReply.
+    Ping(PingRequest),
+    /// A request to stream input into a session's pty.
+    ///
+    /// Responds with a PipeReply, and if that is Ready, with another
+    /// one once the client has sent its input, see PipeRequest.
+    Pipe(PipeRequest),
+    /// A request to stream a session's output without attaching.
+    ///
+    /// Responds with a TailReply, see TailRequest.
+    Tail(TailRequest),
+    /// A request to set or clear a session's note.
+    ///
+    /// Responds with a NoteReply.
+    Note(NoteRequest),
+    /// A request for a snapshot of the daemon's internal state, for
+    /// debugging.
+    ///
+    /// Responds with a DumpStateReply.
+    DumpState,
+    /// A request to add or clear patterns to watch for in a session's
+    /// output while it is detached.
+    ///
+    /// Responds with a WatchReply.
+    Watch(WatchRequest),
 }
 
 /// KillRequest represents a request to kill
@@ -72,12 +259,54 @@ pub struct KillRequest {
     /// The sessions to detach
     #[serde(default)]
     pub sessions: Vec<String>,
+    /// Glob patterns, every session with a matching name gets killed.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// If true, kill every session.
+    #[serde(default)]
+    pub all: bool,
+    /// Kill every session with any of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// If set, send the sessions this signal, given by name like
+    /// "SIGHUP", rather than killing them.
+    #[serde(default)]
+    pub signal: Option<String>,
+    /// Which of the sessions' processes to send the signal to.
+    #[serde(default)]
+    pub signal_target: SignalTarget,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum SignalTarget {
+    /// The session's shell.
+    #[default]
+    Shell,
+    /// The foreground process group of the session's terminal, which
+    /// is whatever ^C would interrupt.
+    Foreground,
+    /// The shell and everything started from it.
+    Tree,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct KillReply {
     #[serde(default)]
     pub not_found_sessions: Vec<String>,
+    /// The names of all the sessions that actually got killed.
+    #[serde(default)]
+    pub killed: Vec<String>,
+    /// The names of all the sessions that got sent the signal, for
+    /// requests with one.
+    #[serde(default)]
+    pub signaled: Vec<String>,
+    /// Patterns which did not match any sessions.
+    #[serde(default)]
+    pub unmatched_patterns: Vec<String>,
+    /// Why the request could not be carried out at all, like a signal
+    /// the daemon does not know. Nothing gets killed or signaled then.
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 /// DetachRequest represents a request to detach
@@ -87,6 +316,14 @@ pub struct DetachRequest {
     /// The sessions to detach
     #[serde(default)]
     pub sessions: Vec<String>,
+    /// Detach every attached session with any of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Set when detaching a client to take its session over, to
+    /// describe the client doing the taking, like "pts/4 on host".
+    /// Detached clients that understand ChunkKind::Evicted get told.
+    #[serde(default)]
+    pub taken_over_by: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -100,6 +337,470 @@ pub struct DetachReply {
     pub not_attached_sessions: Vec<String>,
 }
 
+/// RenameRequest asks the daemon to rename a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameRequest {
+    /// The current name of the session.
+    #[serde(default)]
+    pub from: String,
+    /// The name to give the session.
+    #[serde(default)]
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RenameReply {
+    Renamed,
+    /// There is no session with the old name.
+    NotFound,
+    /// There is already a session with the new name.
+    AlreadyExists,
+    /// The new name can't be used as a session name.
+    InvalidName,
+}
+
+/// ExecRequest asks the daemon to write input to a session's pty.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExecRequest {
+    /// The session to send the input to.
+    #[serde(default)]
+    pub session: String,
+    /// The raw bytes to write to the pty.
+    #[serde(default)]
+    pub input: Vec<u8>,
+    /// If set, stream output back until this string shows up in it
+    /// (ignoring terminal escape codes).
+    #[serde(default)]
+    pub until: Option<String>,
+    /// If set, stream output back for at most this long.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// The reply to an ExecRequest. If output was requested and the input
+/// was sent, the reply is followed by Data chunks and then a final
+/// ExitStatus chunk, which holds 0 if the `until` marker was seen or
+/// there was none, and 1 if we timed out or the session went away
+/// before it showed up.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ExecReply {
+    Sent,
+    /// There is no session with the given name.
+    NotFound,
+    /// The session is locked, see LockRequest.
+    Locked,
+}
+
+/// CaptureRequest asks the daemon for the output a session has kept.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CaptureRequest {
+    /// The session to capture.
+    #[serde(default)]
+    pub session: String,
+    /// If true, capture the plain text scrollback rather than the
+    /// restore buffer.
+    #[serde(default)]
+    pub scrollback: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum CaptureReply {
+    /// The captured output.
+    Captured { data: Vec<u8> },
+    /// There is no session with the given name.
+    NotFound,
+    /// The session is locked, see LockRequest.
+    Locked,
+}
+
+/// WaitRequest asks the daemon to hold off on replying until something
+/// happens in a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WaitRequest {
+    /// The session to watch.
+    #[serde(default)]
+    pub session: String,
+    /// What to wait for.
+    #[serde(default)]
+    pub until: WaitFor,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum WaitFor {
+    /// The session producing output while no client is attached. If
+    /// it already has since a client was last attached, the wait is
+    /// over right away.
+    #[default]
+    Activity,
+    /// The session's shell exiting.
+    Exit,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum WaitReply {
+    /// The session produced output while detached.
+    Activity,
+    /// The session's shell exited with the given status, either
+    /// because that was what was being waited for or because it
+    /// happened first. A shell killed by a signal gets 128 plus the
+    /// signal number, like the shell itself would report.
+    Exited { exit_status: i32 },
+    /// There is no session with the given name.
+    NotFound,
+}
+
+/// LockRequest asks the daemon to lock a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LockRequest {
+    /// The session to lock.
+    #[serde(default)]
+    pub session: String,
+    /// The passphrase to lock the session with if it does not have
+    /// one yet, or its current passphrase when removing it.
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// If true, forget the session's passphrase and unlock it for
+    /// good rather than locking it.
+    #[serde(default)]
+    pub remove: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum LockReply {
+    /// The session is now locked.
+    Locked,
+    /// The session's passphrase is gone.
+    Removed,
+    /// There is no session with the given name.
+    NotFound,
+    /// The session has no passphrase yet, so the request needs to
+    /// carry one.
+    NeedPassphrase,
+    /// The passphrase given for removing the lock was wrong.
+    WrongPassphrase,
+}
+
+/// StopRequest asks the daemon to shut down.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopRequest {
+    /// How long to give the shells to exit after being hung up on
+    /// before they get killed, in milliseconds.
+    #[serde(default)]
+    pub grace_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopReply {
+    /// Sessions whose shells exited on their own within the grace
+    /// period.
+    #[serde(default)]
+    pub exited: Vec<String>,
+    /// Sessions whose shells had to be killed.
+    #[serde(default)]
+    pub killed: Vec<String>,
+    /// Set if another stop was already underway, in which case this
+    /// one did nothing.
+    #[serde(default)]
+    pub already_stopping: bool,
+}
+
+/// MigrateRequest asks the daemon to move a session over to the daemon
+/// listening on another socket.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MigrateRequest {
+    /// The session to move.
+    #[serde(default)]
+    pub session: String,
+    /// The socket of the daemon to move it to, as an absolute path.
+    #[serde(default)]
+    pub to_socket: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum MigrateReply {
+    Migrated,
+    /// There is no session with the given name.
+    NotFound,
+    /// The session's shell has already exited, so there is nothing
+    /// to move.
+    Exited,
+    /// The session is locked, see LockRequest.
+    Locked,
+    /// The handover did not work out. The session stays put.
+    Failed(String),
+}
+
+/// PingRequest asks the daemon to prove that it is alive.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PingRequest {
+    /// When the client sent the ping, in microseconds since the epoch.
+    /// The daemon sends it back untouched.
+    #[serde(default)]
+    pub sent_at_unix_us: i64,
+    /// A session to route the ping through, to check on the thread
+    /// that pumps its output.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Also type a space and a backspace into the session and time
+    /// how long it takes for the shell to echo something back. This
+    /// is only harmless when the session is sitting at a shell prompt.
+    #[serde(default)]
+    pub echo: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingReply {
+    /// PingRequest::sent_at_unix_us, echoed back.
+    #[serde(default)]
+    pub sent_at_unix_us: i64,
+    /// The version of the running daemon.
+    #[serde(default)]
+    pub version: String,
+    /// How long the daemon has been running, in milliseconds.
+    #[serde(default)]
+    pub uptime_ms: u64,
+    /// How the ping fared in the session, if it asked to go through
+    /// one.
+    #[serde(default)]
+    pub session: Option<SessionPing>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SessionPing {
+    /// The session answered. The latencies are in microseconds.
+    Answered {
+        /// How long the thread pumping the session's output took to
+        /// answer.
+        reader_us: u64,
+        /// How long the shell took to echo the input back, if the ping
+        /// asked for that and something came back in time.
+        #[serde(default)]
+        echo_us: Option<u64>,
+    },
+    /// There is no session with the given name.
+    NotFound,
+    /// The session's shell has exited.
+    Exited,
+    /// The session is locked, see LockRequest, so nothing got typed
+    /// into it.
+    Locked,
+    /// The thread pumping the session's output did not answer in time.
+    Unresponsive,
+}
+
+/// PipeRequest asks the daemon to write everything the client sends
+/// into a session's pty. Once the daemon replies Ready, the client
+/// sends the raw input and then shuts down its side of the connection,
+/// and the daemon replies again with Done, or Failed if it could not
+/// write all of the input.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PipeRequest {
+    /// The session to send the input to.
+    #[serde(default)]
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum PipeReply {
+    /// The daemon is ready for the input.
+    Ready,
+    /// There is no session with the given name.
+    NotFound,
+    /// The session's shell has exited.
+    Exited,
+    /// The session is locked, see LockRequest.
+    Locked,
+    /// All of the input has been written to the pty.
+    Done { bytes: u64 },
+    /// Writing the input failed part way through.
+    Failed(String),
+}
+
+/// TailRequest asks the daemon to stream a session's output back. If
+/// the reply is Streaming, it is followed by Data chunks holding the
+/// restore buffer and then, when following, the session's live output,
+/// and finally an ExitStatus chunk. That holds 0 when not following,
+/// the shell's exit status once it exits, or 1 if the daemon stopped
+/// following because the client fell too far behind.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TailRequest {
+    /// The session to stream the output of.
+    #[serde(default)]
+    pub session: String,
+    /// Keep streaming live output after the restore buffer.
+    #[serde(default)]
+    pub follow: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum TailReply {
+    Streaming,
+    /// There is no session with the given name.
+    NotFound,
+    /// The session is locked, see LockRequest.
+    Locked,
+}
+
+/// NoteRequest asks the daemon to give a session a free-form note
+/// saying what it is for.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct NoteRequest {
+    /// The session to note.
+    #[serde(default)]
+    pub session: String,
+    /// The new note, or None to clear it.
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum NoteReply {
+    Noted,
+    /// There is no session with the given name.
+    NotFound,
+}
+
+/// DumpStateReply carries a snapshot of the daemon's internal state.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DumpStateReply {
+    /// The snapshot as pretty printed JSON. Its layout is only meant
+    /// for people to read and may change between versions.
+    #[serde(default)]
+    pub state: String,
+}
+
+/// WatchRequest asks the daemon to look for a pattern in a session's
+/// output while nobody is attached. With no pattern and no clear it
+/// just asks which patterns are being watched for.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WatchRequest {
+    /// The session to watch.
+    #[serde(default)]
+    pub session: String,
+    /// A regular expression to match against each line of output.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// A shell command to run on the first match after a detach.
+    #[serde(default)]
+    pub exec: Option<String>,
+    /// Drop the watches added with `shpool watch` before adding
+    /// the new one, if there is one.
+    #[serde(default)]
+    pub clear: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum WatchReply {
+    /// Everything now being watched for in the session.
+    Watches(Vec<Watch>),
+    /// There is no session with the given name.
+    NotFound,
+    /// The pattern is not a valid regular expression.
+    BadPattern(String),
+    /// The session is locked, see LockRequest.
+    Locked,
+}
+
+/// A pattern being watched for in a session's output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Watch {
+    #[serde(default)]
+    pub pattern: String,
+    /// The command to run on a match, if it has its own.
+    #[serde(default)]
+    pub exec: Option<String>,
+    /// True for watches from the config file rather than `shpool watch`.
+    #[serde(default)]
+    pub from_config: bool,
+}
+
+/// ControlHeader opens a control mode connection.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ControlHeader {
+    /// The environment to give sessions created over the
+    /// connection, like AttachHeader::local_env.
+    #[serde(default)]
+    pub local_env: Vec<(String, String)>,
+}
+
+/// A command sent by a control mode client, as one line of JSON like
+/// `{"id": 1, "cmd": "send-input", "session": "main", "data": "ls\n"}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ControlRequest {
+    /// Echoed back in the Done or Error event for this request, so
+    /// the client can match them up.
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum ControlCommand {
+    /// Create a new session without attaching to it.
+    Create {
+        session: String,
+        /// The command to run instead of the user's shell.
+        #[serde(default)]
+        command: Option<String>,
+        /// The directory to start the session in.
+        #[serde(default)]
+        dir: Option<String>,
+    },
+    /// Start getting Output events for a session, beginning with
+    /// what a reattaching client would see. Unlike a real attach, this
+    /// does not stop a terminal from attaching to the session.
+    AttachProxy { session: String },
+    /// Stop getting Output events for a session.
+    DetachProxy { session: String },
+    /// Type into a session.
+    SendInput { session: String, data: String },
+    /// Resize a session's pty.
+    Resize { session: String, rows: u16, cols: u16 },
+}
+
+/// Something a control mode client gets told about, sent as one line
+/// of JSON like `{"event": "created", "session": "main"}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ControlEvent {
+    /// A session showed up. Sent for every existing session when the
+    /// connection opens, too.
+    Created { session: String },
+    /// A session's shell exited, or the session went away. Renaming a
+    /// session looks like the old name exiting and the new one getting
+    /// created.
+    Exited {
+        session: String,
+        #[serde(default)]
+        exit_status: Option<i32>,
+    },
+    /// A session's pty changed size.
+    Resized { session: String, rows: u16, cols: u16 },
+    /// A terminal attached to a session. Sent right after Created for
+    /// sessions that already have one attached, too.
+    Attached { session: String },
+    /// The terminal attached to a session went away.
+    Detached { session: String },
+    /// A session that is not being proxied produced output.
+    OutputAvailable { session: String },
+    /// Output from a session being proxied.
+    Output { session: String, data: String },
+    /// The request with the given id went through.
+    Done {
+        #[serde(default)]
+        id: Option<u64>,
+    },
+    /// The request with the given id failed, or a line could not
+    /// be parsed as a request at all.
+    Error {
+        #[serde(default)]
+        id: Option<u64>,
+        message: String,
+    },
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, ValueEnum, Clone)]
 pub enum LogLevel {
     #[default]
@@ -117,10 +818,160 @@ pub enum LogLevel {
 pub struct SetLogLevelRequest {
     #[serde(default)]
     pub level: LogLevel,
+    /// A filter in the form `target=level,...,level`, for example
+    /// `libshpool::daemon::shell=trace,info`. If set, this is used
+    /// instead of `level`.
+    #[serde(default)]
+    pub filter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetLogLevelReply {
+    /// Set if the daemon could not apply the new filter, in which
+    /// case the old one stays in effect.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// StatusRequest asks the daemon to report on its own state.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StatusRequest {
+    /// If true, include a breakdown of the daemon's memory usage.
+    #[serde(default)]
+    pub memory: bool,
+    /// If true, ask the daemon to dump a heap profile into its
+    /// runtime directory. This only works when the daemon was built
+    /// with an allocator that supports heap profiling.
+    #[serde(default)]
+    pub heap_profile: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StatusReply {
+    /// The version of the running daemon.
+    #[serde(default)]
+    pub version: String,
+    /// The number of sessions in the session table.
+    #[serde(default)]
+    pub session_count: u64,
+    /// Memory usage details, only populated if requested.
+    #[serde(default)]
+    pub memory: Option<MemoryReport>,
+    /// The outcome of a heap profile dump, only populated if requested.
+    #[serde(default)]
+    pub heap_profile: Option<HeapProfileReply>,
+}
+
+/// MemoryReport describes how much memory the daemon is using
+/// and roughly where it is going.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MemoryReport {
+    /// The name of the global allocator the daemon was built with.
+    #[serde(default)]
+    pub allocator: String,
+    /// Bytes currently allocated by the application, if the
+    /// allocator is able to report it.
+    #[serde(default)]
+    pub allocated_bytes: Option<u64>,
+    /// Resident set size of the daemon process, if known.
+    #[serde(default)]
+    pub resident_bytes: Option<u64>,
+    /// Session restore buffers.
+    #[serde(default)]
+    pub spools: SubsystemMemory,
+    /// Per-session bookkeeping and IO buffers.
+    #[serde(default)]
+    pub sessions: SubsystemMemory,
+    /// Buffers owned by currently attached client connections.
+    #[serde(default)]
+    pub connections: SubsystemMemory,
+}
+
+/// SubsystemMemory is an estimate of the memory used by one
+/// part of the daemon.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SubsystemMemory {
+    /// How many instances of the thing there are.
+    #[serde(default)]
+    pub count: u64,
+    /// Approximately how many bytes they take up in total.
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StatsReply {
+    #[serde(default)]
+    pub sessions: Vec<SessionStats>,
+}
+
+/// SessionStats holds resource usage numbers for a single session.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SessionStats {
+    #[serde(default)]
+    pub name: String,
+    /// CPU time the daemon has spent shuffling data between
+    /// the session's shell and its clients, in nanoseconds.
+    #[serde(default)]
+    pub pump_cpu_ns: u64,
+    /// Bytes of input written to the session's shell.
+    #[serde(default)]
+    pub bytes_in: u64,
+    /// Bytes of output the session's shell has produced.
+    #[serde(default)]
+    pub bytes_out: u64,
+    /// How many bytes the session's restore spool is holding on to.
+    #[serde(default)]
+    pub spool_bytes: u64,
+    /// How many times a client has attached to the session.
+    #[serde(default)]
+    pub attach_count: u64,
+    /// How much output is waiting to be written to the session's
+    /// clients.
+    #[serde(default)]
+    pub queued_bytes: u64,
+    /// How much output has been dropped because a client could not
+    /// keep up with it.
+    #[serde(default)]
+    pub dropped_bytes: u64,
+    /// How many times a client that fell behind has been sent a fresh
+    /// restore buffer.
+    #[serde(default)]
+    pub resyncs: u64,
+    /// What the processes running in the session are using, or None
+    /// if the daemon could not look them up.
+    #[serde(default)]
+    pub procs: Option<ProcUsage>,
+    /// When the session's attached client last took a heartbeat, in
+    /// milliseconds since the epoch, or None if no client ever has.
+    #[serde(default)]
+    pub last_heartbeat_unix_ms: Option<i64>,
+}
+
+/// The combined resource usage of the shell of a session and the
+/// processes running under it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcUsage {
+    /// How many processes there are.
+    #[serde(default)]
+    pub count: u32,
+    /// The CPU time they have used, including that of any finished
+    /// children they waited for, in nanoseconds.
+    #[serde(default)]
+    pub cpu_ns: u64,
+    /// Their combined resident set size. Memory shared between
+    /// processes gets counted once for each of them.
+    #[serde(default)]
+    pub rss_bytes: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct SetLogLevelReply {}
+pub enum HeapProfileReply {
+    /// The profile was written to the given path.
+    Dumped(String),
+    /// The profile could not be written.
+    Failed(String),
+}
 
 /// SessionMessageRequest represents a request that
 /// ought to be routed to the session indicated by
@@ -146,6 +997,9 @@ pub enum SessionMessageRequestPayload {
     /// by the server from a batch detach request.
     #[default]
     Detach,
+    /// Move the client attached to the session over to another
+    /// session. Generated by `shpool switch`.
+    Switch(SwitchRequest),
 }
 
 /// ResizeRequest resizes the pty for a named session.
@@ -160,6 +1014,16 @@ pub struct ResizeRequest {
     pub tty_size: TtySize,
 }
 
+/// SwitchRequest moves the client attached to a session over
+/// to another session, as if it had hit a switch keybinding.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SwitchRequest {
+    /// The session to switch to, or `-` for the session the
+    /// client was attached to before this one.
+    #[serde(default)]
+    pub target: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum SessionMessageReply {
     /// The session was not found in the session table
@@ -171,6 +1035,8 @@ pub enum SessionMessageReply {
     Resize(ResizeReply),
     /// The response to a detach message
     Detach(SessionMessageDetachReply),
+    /// The response to a switch message
+    Switch(SwitchReply),
 }
 
 /// A reply to a detach message
@@ -185,10 +1051,19 @@ pub enum ResizeReply {
     Ok,
 }
 
+/// A reply to a switch message
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SwitchReply {
+    Ok,
+    /// The target was `-`, but the client has not been attached
+    /// to any other session.
+    NoPrevious,
+}
+
 /// AttachHeader is the blob of metadata that a client transmits when it
 /// first dials into the shpool daemon indicating which shell it wants
 /// to attach to.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct AttachHeader {
     /// The name of the session to create or attach to.
     #[serde(default)]
@@ -210,9 +1085,19 @@ pub struct AttachHeader {
     /// session once the ttl is over.
     #[serde(default)]
     pub ttl_secs: Option<u64>,
+    /// If specified, overrides the configured auto_kill_after_idle value
+    /// for this session. The daemon kills the session once it has had no
+    /// client attached and produced no output for this long. Like
+    /// ttl_secs, this only has an effect when the session is first created.
+    #[serde(default)]
+    pub idle_ttl_secs: Option<u64>,
     /// If specified, a command to run instead of the users default shell.
     #[serde(default)]
     pub cmd: Option<String>,
+    /// When to start `cmd` again after it exits, "on-failure" or
+    /// "always". By default it is left dead.
+    #[serde(default)]
+    pub restart: Option<String>,
     /// The working directory to start the shell session in.
     /// If not specified, the daemon will use the user's home directory.
     #[serde(default)]
@@ -221,6 +1106,68 @@ pub struct AttachHeader {
     /// specific attachment. Used for the --restore command line parameter.
     #[serde(default)]
     pub restore_override: Option<String>,
+    /// If true and the session already has a client attached, attach
+    /// alongside it rather than reporting the session as busy.
+    #[serde(default)]
+    pub mirror: bool,
+    /// If true, drop all input from this client other than the detach
+    /// keybinding. A read-only client attaches alongside any client
+    /// that is already there, as if mirror were set.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Tags to add to the session.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// If specified, the directory to log the session's output to,
+    /// overriding the output_log.dir config value. Only used when the
+    /// session gets created.
+    #[serde(default)]
+    pub log_output: Option<String>,
+    /// If true, make sure the session exists without attaching to it.
+    /// A missing session gets created detached and the daemon hangs up
+    /// right after the reply, which is Created or, if the session was
+    /// already there, Attached.
+    #[serde(default)]
+    pub detached: bool,
+    /// Extra environment variables for the shell, layered on top of
+    /// the ones from the daemon's config. Only used when the session
+    /// gets created.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// A command to type into the shell once it has started,
+    /// overriding the session's configured startup command. Only
+    /// used when the session gets created.
+    #[serde(default)]
+    pub startup: Option<String>,
+    /// The shell to run, overriding the one from the daemon's config
+    /// and the user's login shell. Only used when the session gets
+    /// created.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// The path of the tty `shpool attach` is running in, if it is
+    /// running in one. Shown in `shpool list`.
+    #[serde(default)]
+    pub client_tty: Option<String>,
+    /// The client can follow a switch to another session over the
+    /// connection it already has, see ChunkKind::Switched. Clients that
+    /// don't set this get sent a ChunkKind::Switch and have to dial
+    /// back in themselves.
+    #[serde(default)]
+    pub switch_in_place: bool,
+    /// The session the client was attached to before this one, if it
+    /// got here by switching. Used to resolve a switch to `-`.
+    #[serde(default)]
+    pub previous: Option<String>,
+    /// The passphrase to unlock the session with, if it is locked.
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// If true, don't show the message of the day for this attach.
+    #[serde(default)]
+    pub no_motd: bool,
+    /// The client can show why it got detached when another client
+    /// takes the session over, see ChunkKind::Evicted.
+    #[serde(default)]
+    pub evicted_notice: bool,
 }
 
 impl AttachHeader {
@@ -236,6 +1183,24 @@ impl AttachHeader {
 pub struct AttachReplyHeader {
     #[serde(default)]
     pub status: AttachStatus,
+    /// A line for the client to print before the session output, from
+    /// the banner motd mode.
+    #[serde(default)]
+    pub banner: Option<String>,
+    /// The terminal the session's shell was started for, so that a
+    /// client with a less capable terminal can make up the difference.
+    /// Only set when attaching to a session that already existed.
+    #[serde(default)]
+    pub term: Option<SessionTerm>,
+}
+
+/// The terminal related variables a session's shell was started with.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionTerm {
+    #[serde(default)]
+    pub term: Option<String>,
+    #[serde(default)]
+    pub colorterm: Option<String>,
 }
 
 /// ListReply is contains a list of active sessions to be displayed to the user.
@@ -254,6 +1219,99 @@ pub struct Session {
     pub started_at_unix_ms: i64,
     #[serde(default)]
     pub status: SessionStatus,
+    /// The pid of the session's shell.
+    #[serde(default)]
+    pub pid: i32,
+    /// The size the session's pty was last set to.
+    #[serde(default)]
+    pub tty_size: TtySize,
+    /// The tags the session has been given.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// If the session's shell has exited but the session is being
+    /// kept around, the shell's exit status.
+    #[serde(default)]
+    pub exit_status: Option<i32>,
+    /// True if the session's output has been held back for going over
+    /// the detached output limit since a client was last attached.
+    #[serde(default)]
+    pub throttled: bool,
+    /// True if the session has produced output since a client was
+    /// last attached.
+    #[serde(default)]
+    pub activity: bool,
+    /// True if the session has rung the bell since a client was last
+    /// attached.
+    #[serde(default)]
+    pub bell: bool,
+    /// The tty of the attached client, if a client is attached and
+    /// said what its tty was.
+    #[serde(default)]
+    pub client_tty: Option<String>,
+    /// When the session last produced output, or was created if it
+    /// has not produced any yet. Typing into an attached session
+    /// usually counts, since the shell echoes it back.
+    #[serde(default)]
+    pub last_active_unix_ms: i64,
+    /// How many bytes the session's restore spool is holding on to.
+    #[serde(default)]
+    pub spool_bytes: u64,
+    /// Bytes of input written to the session's shell.
+    #[serde(default)]
+    pub bytes_in: u64,
+    /// Bytes of output the session's shell has produced.
+    #[serde(default)]
+    pub bytes_out: u64,
+    /// How many times a client has attached to the session.
+    #[serde(default)]
+    pub attach_count: u64,
+    /// When a client last attached to the session, if one ever has.
+    #[serde(default)]
+    pub last_attached_unix_ms: Option<i64>,
+    /// When a client last detached from the session, if one ever has.
+    #[serde(default)]
+    pub last_detached_unix_ms: Option<i64>,
+    /// Why the last client to detach from the session went away.
+    #[serde(default)]
+    pub last_detach_reason: Option<DetachReason>,
+    /// Attaching to the session currently takes a passphrase.
+    #[serde(default)]
+    pub locked: bool,
+    /// The shell's current working directory, if the daemon can tell.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// What the session is for, as set with `shpool note`.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// How many lines of output have matched a watch since a client
+    /// was last attached.
+    #[serde(default)]
+    pub watch_matches: u64,
+    /// The last line of output to match a watch since a client was
+    /// last attached.
+    #[serde(default)]
+    pub last_watch_match: Option<String>,
+}
+
+/// Why a client stopped being attached to a session.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetachReason {
+    /// The client was detached on purpose, with a keybinding or
+    /// `shpool detach`, or switched to another session.
+    Detached,
+    /// The connection to the client went away without a detach, for
+    /// example because its terminal was closed or its ssh connection
+    /// dropped.
+    HungUp,
+}
+
+impl fmt::Display for DetachReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetachReason::Detached => write!(f, "detached"),
+            DetachReason::HungUp => write!(f, "hung-up"),
+        }
+    }
 }
 
 /// Indicates if a shpool session currently has a client attached.
@@ -295,6 +1353,14 @@ pub enum AttachStatus {
     /// Forbidden indicates that the daemon has rejected the connection
     /// attempt for security reasons.
     Forbidden(String),
+    /// Locked indicates that the session needs a passphrase to attach
+    /// to it, and that the attach request either had none or had the
+    /// wrong one.
+    Locked { wrong_passphrase: bool },
+    /// LimitReached indicates that there was no session with the given
+    /// name, and creating one would go over one of the daemon's
+    /// configured limits. Holds a description of the limit.
+    LimitReached(String),
     /// Some unexpected error
     UnexpectedError(String),
 }
@@ -305,7 +1371,7 @@ impl Default for AttachStatus {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct TtySize {
     pub rows: u16,
     pub cols: u16,
@@ -327,6 +1393,24 @@ pub enum ChunkKind {
     /// have exactly 4 bytes of data, which will contain a little endian
     /// code indicating the child's exit status.
     ExitStatus = 2,
+    /// The session has been renamed. The chunk is length prefixed
+    /// like a data chunk and holds the new name as utf8.
+    Rename = 3,
+    /// The user asked to switch to another session. The chunk is length
+    /// prefixed like a data chunk and holds the name of the session to
+    /// attach to once the daemon closes the connection.
+    Switch = 4,
+    /// The user asked to switch to another session and the daemon has
+    /// moved the connection over to it. The chunk is length prefixed
+    /// like a data chunk and holds the name of the new session. It is
+    /// followed by an AttachReplyHeader for the new session, and then
+    /// by the new session's chunks.
+    Switched = 5,
+    /// Another client has taken the session over. The chunk is length
+    /// prefixed like a data chunk and holds a utf8 message saying who
+    /// and when, for the client to show once it has restored its
+    /// terminal. It is followed by an exit status chunk.
+    Evicted = 6,
 }
 
 impl TryFrom<u8> for ChunkKind {
@@ -337,6 +1421,10 @@ impl TryFrom<u8> for ChunkKind {
             0 => Ok(ChunkKind::Data),
             1 => Ok(ChunkKind::Heartbeat),
             2 => Ok(ChunkKind::ExitStatus),
+            3 => Ok(ChunkKind::Rename),
+            4 => Ok(ChunkKind::Switch),
+            5 => Ok(ChunkKind::Switched),
+            6 => Ok(ChunkKind::Evicted),
             _ => Err(anyhow!("unknown ChunkKind {}", v)),
         }
     }
@@ -351,8 +1439,55 @@ impl TryFrom<u8> for ChunkKind {
 /// little endian 4 byte word: length prefix
 /// N bytes: data
 /// ```
+///
+/// Unlike the control messages, chunks never go through serde. The
+/// header is encoded into a small fixed size array and the payload
+/// is borrowed, so the data can be handed to the OS as-is (for example
+/// with a vectored write) without ever being copied into an
+/// intermediate serialization buffer.
 #[derive(Debug, PartialEq)]
 pub struct Chunk<'data> {
     pub kind: ChunkKind,
     pub buf: &'data [u8],
 }
+
+/// The size of the fixed part of every chunk on the wire. For data and
+/// heartbeat chunks this is the kind tag plus the length prefix, for
+/// exit status chunks it is the kind tag plus the status itself.
+pub const CHUNK_HEADER_LEN: usize = 5;
+
+impl Chunk<'_> {
+    /// Encode the part of the chunk that comes before the payload,
+    /// returning the buffer and how many bytes of it are used.
+    pub fn header(&self) -> ([u8; CHUNK_HEADER_LEN], usize) {
+        let mut header = [self.kind as u8, 0, 0, 0, 0];
+        if let ChunkKind::ExitStatus = self.kind {
+            // The caller should have already little-endian encoded
+            // the exit status and stuffed it into buf, which serves
+            // as the whole body of the chunk.
+            (header, 1)
+        } else {
+            header[1..].copy_from_slice(&(self.buf.len() as u32).to_le_bytes());
+            (header, CHUNK_HEADER_LEN)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunk_header() {
+        let data = [1, 2, 3];
+        let (header, len) = Chunk { kind: ChunkKind::Data, buf: &data }.header();
+        assert_eq!(&header[..len], &[0, 3, 0, 0, 0]);
+
+        let (header, len) = Chunk { kind: ChunkKind::Heartbeat, buf: &[] }.header();
+        assert_eq!(&header[..len], &[1, 0, 0, 0, 0]);
+
+        let status = 3i32.to_le_bytes();
+        let (header, len) = Chunk { kind: ChunkKind::ExitStatus, buf: &status }.header();
+        assert_eq!(&header[..len], &[2]);
+    }
+}
@@ -15,20 +15,29 @@
 /// aims to provide a simpler user experience. See [the
 /// README](https://github.com/shell-pool/shpool) for more
 /// info.
-use clap::Parser;
+use clap::{CommandFactory as _, Parser};
 use std::env;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
 /// Extract config file path from command line arguments without full parsing
 fn extract_config_file(args: &[String]) -> Option<String> {
     let mut i = 1; // Skip binary name
     while i < args.len() {
         match args[i].as_str() {
-            "-c" | "--config-file" => {
-                if i + 1 < args.len() {
-                    return Some(args[i + 1].clone());
-                }
+            "-c" | "--config-file" if i + 1 < args.len() => {
+                return Some(args[i + 1].clone());
             }
             arg if arg.starts_with("--config-file=") => {
                 return Some(arg.strip_prefix("--config-file=").unwrap().to_string());
@@ -41,95 +50,112 @@ fn extract_config_file(args: &[String]) -> Option<String> {
 }
 
 /// Resolve command aliases by checking the first command argument against configured aliases.
-/// Returns modified command line arguments with the alias expanded.
-fn resolve_aliases() -> anyhow::Result<Vec<String>> {
-    let args: Vec<String> = env::args().collect();
-    
+/// Returns the command line arguments with the alias expanded, along with the
+/// config manager that was loaded to do so, if any, so that it can be handed
+/// on to libshpool rather than loaded a second time.
+fn resolve_aliases(
+    args: Vec<String>,
+) -> anyhow::Result<(Vec<String>, Option<libshpool::config::Manager>)> {
     if args.len() < 2 {
-        return Ok(args);
+        return Ok((args, None));
     }
-    
+
+    // `config check` has to be able to look at configs that fail to load
+    if command_pos(&args).is_some_and(|pos| args[pos] == "config") {
+        return Ok((args, None));
+    }
+
     // Extract config file path manually
     let config_file = extract_config_file(&args);
-    
-    // Load config to check for aliases
+
+    // Load config to check for aliases. If config loading fails, return the
+    // original args and let libshpool load it again to report the error.
     let config_manager = match libshpool::config::Manager::new(config_file.as_deref()) {
         Ok(manager) => manager,
-        Err(_) => return Ok(args), // If config loading fails, return original args
+        Err(_) => return Ok((args, None)),
     };
-    
-    let config = config_manager.get();
-    if let Some(aliases) = &config.aliases {
-        // Find the command position (usually after binary name and global flags)
-        let mut command_pos = None;
-        let mut i = 1; // Skip binary name
-        
-        while i < args.len() {
-            let arg = &args[i];
-            
-            // Skip global flags
-            if arg.starts_with('-') {
-                i += 1;
-                // Skip flag values for flags that take arguments
-                if matches!(arg.as_str(), "--log-file" | "-l" | "--socket" | "-s" | "--config-file" | "-c") {
-                    i += 1;
-                }
-                continue;
+
+    let resolved = {
+        let config = config_manager.get();
+        match (config.aliases.as_ref(), command_pos(&args)) {
+            (Some(aliases), Some(pos)) => {
+                let mut new_args = args[..pos].to_vec();
+                new_args.extend(libshpool::config::expand_aliases(aliases, &args[pos..])?);
+                Some(new_args)
             }
-            
-            // This should be the command
-            command_pos = Some(i);
-            break;
+            _ => None,
         }
-        
-        if let Some(pos) = command_pos {
-            let command = &args[pos];
-            if let Some(resolved_command) = aliases.get(command) {
-                let mut new_args = args.clone();
-                new_args[pos] = resolved_command.clone();
-                return Ok(new_args);
+    };
+
+    Ok((resolved.unwrap_or(args), Some(config_manager)))
+}
+
+/// Find the position of the subcommand, skipping the binary name and any
+/// global flags (along with the values of the flags that take one).
+/// Which flags take a value comes from the clap definition, so that new
+/// global flags get skipped properly without having to be listed here.
+fn command_pos(args: &[String]) -> Option<usize> {
+    let cmd = libshpool::Args::command();
+    let takes_value = |flag: &str| {
+        cmd.get_arguments().filter(|arg| arg.get_action().takes_values()).any(|arg| {
+            match flag.strip_prefix("--") {
+                Some(long) => arg.get_long() == Some(long),
+                None => {
+                    let mut chars = flag.chars().skip(1);
+                    chars.next() == arg.get_short() && chars.next().is_none()
+                }
             }
+        })
+    };
+
+    let mut i = 1; // Skip binary name
+    while i < args.len() {
+        let arg = &args[i];
+
+        if !arg.starts_with('-') {
+            return Some(i);
+        }
+
+        i += 1;
+        if takes_value(arg) {
+            i += 1;
         }
     }
-    
-    Ok(args)
+    None
 }
 
 fn main() -> anyhow::Result<()> {
-    // Resolve aliases first
-    let resolved_args = resolve_aliases()?;
-    
-    // Parse the resolved arguments
-    let args = match libshpool::Args::try_parse_from(&resolved_args) {
-        Ok(args) => args,
-        Err(e) => {
-            // If alias resolution caused a parsing error, show the error normally
-            // This handles help messages, version, and actual errors correctly
-            if resolved_args != env::args().collect::<Vec<_>>() {
-                // For help and version, clap exits with status 0, for errors it exits with 2
-                // We can check the error kind to handle appropriately
-                use clap::error::ErrorKind;
-                match e.kind() {
-                    ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
-                        print!("{e}");
-                        std::process::exit(0);
-                    }
-                    _ => {
-                        eprint!("{e}");
-                        std::process::exit(2);
-                    }
-                }
-            } else {
-                // Re-parse to get proper clap error handling
-                libshpool::Args::parse()
-            }
-        }
-    };
+    let (resolved_args, config_manager) = resolve_aliases(env::args().collect())?;
+
+    // Clap handles help, version and parse errors (including ones introduced
+    // by alias expansion) by printing and exiting with the right status.
+    let args = libshpool::Args::parse_from(&resolved_args);
 
     if args.version() {
         println!("shpool {VERSION}");
         return Ok(());
     }
 
-    libshpool::run(args, None)
+    libshpool::run_with_config(args, config_manager, None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pos(args: &[&str]) -> Option<usize> {
+        command_pos(&args.iter().map(|a| String::from(*a)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn command_pos_skips_flag_values() {
+        assert_eq!(pos(&["shpool", "attach", "main"]), Some(1));
+        assert_eq!(pos(&["shpool", "-v", "-s", "x.sock", "attach"]), Some(4));
+        assert_eq!(pos(&["shpool", "--socket=x.sock", "list"]), Some(2));
+        assert_eq!(
+            pos(&["shpool", "--connect", "h:443", "--tls-ca", "ca.pem", "--token-file", "t", "ls"]),
+            Some(7)
+        );
+        assert_eq!(pos(&["shpool", "-c", "config.toml"]), None);
+    }
 }
@@ -87,6 +87,35 @@ fn custom_cmd() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn custom_cmd_restart() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    cmd: Some(String::from("/bin/sh -c 'echo started-$$; sleep 0.5; exit 3'")),
+                    restart: Some(String::from("on-failure")),
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        line_matcher.scan_until_re("started-[0-9]+$")?;
+        line_matcher.scan_until_re(r"exited with status 3, restarting in 1s\]")?;
+
+        // the second run shows up in the same pty, without the client
+        // having to reattach
+        line_matcher.scan_until_re("started-[0-9]+$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn forward_env() -> anyhow::Result<()> {
@@ -183,6 +212,125 @@ fn symlink_ssh_auth_sock() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn symlink_forwarded_socket() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("forward_sockets.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        let old_tgt = daemon_proc.tmp_dir.join("gpg-agent-old.fake");
+        let new_tgt = daemon_proc.tmp_dir.join("gpg-agent-new.fake");
+        fs::File::create(&old_tgt)?;
+        fs::File::create(&new_tgt)?;
+        // the client needs the config too, so it knows to send the var
+        let attach_args = |tgt: &std::path::Path| AttachArgs {
+            config: Some(String::from("forward_sockets.toml")),
+            extra_env: vec![(String::from("GPG_AGENT_SOCK"), String::from(tgt.to_str().unwrap()))],
+            ..Default::default()
+        };
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", attach_args(&old_tgt)).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("ls -l $GPG_AGENT_SOCK")?;
+            line_matcher.scan_until_re(r#".*sh1/gpg-agent-sock.socket ->.*gpg-agent-old.fake$"#)?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        // the shell keeps the same path, but it now leads to the new socket
+        let mut attach_proc =
+            daemon_proc.attach("sh1", attach_args(&new_tgt)).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("ls -l $GPG_AGENT_SOCK")?;
+        line_matcher.scan_until_re(r#".*sh1/gpg-agent-sock.socket ->.*gpg-agent-new.fake$"#)?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn refreshes_display() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut attach_proc = daemon_proc
+                .attach(
+                    "sh1",
+                    AttachArgs {
+                        extra_env: vec![(String::from("DISPLAY"), String::from(":1"))],
+                        ..Default::default()
+                    },
+                )
+                .context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo $DISPLAY")?;
+            line_matcher.scan_until_re("^:1$")?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        // coming back from another login updates the script even though
+        // norc.toml has no refresh_env
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    extra_env: vec![(String::from("DISPLAY"), String::from(":2"))],
+                    ..Default::default()
+                },
+            )
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd(r#". "$SHPOOL_SESSION_DIR/refresh.env"; echo $DISPLAY"#)?;
+        line_matcher.scan_until_re("^:2$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn shell_hook_refreshes_running_shell() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+        let display = |d: &str| AttachArgs {
+            extra_env: vec![(String::from("DISPLAY"), String::from(d))],
+            ..Default::default()
+        };
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", display(":1")).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd(&format!(
+                r#"eval "$({} shell-hook bash)"; echo hooked"#,
+                support::shpool_bin()?.display()
+            ))?;
+            line_matcher.scan_until_re("^hooked$")?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", display(":2")).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        // the hook runs before the next prompt is drawn
+        attach_proc.run_cmd("true")?;
+        attach_proc.run_cmd("echo $DISPLAY")?;
+        line_matcher.scan_until_re("^:2$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn missing_ssh_auth_sock() -> anyhow::Result<()> {
@@ -483,6 +631,79 @@ fn force_attach() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn force_attach_tells_evicted() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let mut tty1 =
+            daemon_proc.attach("sh1", Default::default()).context("attaching from tty1")?;
+        let mut line_matcher1 = tty1.line_matcher()?;
+        let mut stderr_matcher1 = tty1.stderr_line_matcher()?;
+        tty1.run_cmd("echo foo")?;
+        line_matcher1.scan_until_re("foo$")?;
+
+        // force_attach makes stealing the default
+        let mut tty2 = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { config: Some(String::from("force_attach.toml")), ..Default::default() },
+            )
+            .context("attaching from tty2")?;
+        let mut line_matcher2 = tty2.line_matcher()?;
+        tty2.run_cmd("echo bar")?;
+        line_matcher2.scan_until_re("bar$")?;
+
+        stderr_matcher1.scan_until_re("session 'sh1' taken over by .* at [0-9:]+$")?;
+        let status = tty1.proc.wait()?;
+        assert!(status.success());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_steal() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let mut tty1 =
+            daemon_proc.attach("sh1", Default::default()).context("attaching from tty1")?;
+        let mut line_matcher1 = tty1.line_matcher()?;
+        tty1.run_cmd("echo foo")?;
+        line_matcher1.scan_until_re("foo$")?;
+
+        let mut tty2 = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs {
+                    config: Some(String::from("force_attach.toml")),
+                    no_steal: true,
+                    ..Default::default()
+                },
+            )
+            .context("attaching from tty2")?;
+        let mut line_matcher2 = tty2.stderr_line_matcher()?;
+        line_matcher2.scan_until_re("already has a terminal attached$")?;
+
+        // tty1 still has the session
+        tty1.run_cmd("echo still-here")?;
+        line_matcher1.scan_until_re("still-here$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn busy() -> anyhow::Result<()> {
@@ -508,6 +729,73 @@ fn busy() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn mirror() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let mut tty1 =
+            daemon_proc.attach("sh1", Default::default()).context("attaching from tty1")?;
+        let mut line_matcher1 = tty1.line_matcher()?;
+        tty1.run_cmd("echo foo")?; // make sure the shell is up and running
+        line_matcher1.scan_until_re("foo$")?;
+
+        let mut tty2 = daemon_proc
+            .attach("sh1", AttachArgs { mirror: true, ..Default::default() })
+            .context("attaching from tty2")?;
+        let mut line_matcher2 = tty2.line_matcher()?;
+
+        // input from either client shows up on both
+        tty2.run_cmd("echo from_tty2")?;
+        line_matcher1.scan_until_re("from_tty2$")?;
+        line_matcher2.scan_until_re("from_tty2$")?;
+
+        tty1.run_cmd("echo from_tty1")?;
+        line_matcher1.scan_until_re("from_tty1$")?;
+        line_matcher2.scan_until_re("from_tty1$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn read_only() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let mut tty1 =
+            daemon_proc.attach("sh1", Default::default()).context("attaching from tty1")?;
+        let mut line_matcher1 = tty1.line_matcher()?;
+        tty1.run_cmd("echo foo")?; // make sure the shell is up and running
+        line_matcher1.scan_until_re("foo$")?;
+
+        // a read-only client attaches alongside without needing --mirror
+        let mut tty2 = daemon_proc
+            .attach("sh1", AttachArgs { read_only: true, ..Default::default() })
+            .context("attaching from tty2")?;
+        let mut line_matcher2 = tty2.line_matcher()?;
+        line_matcher1.never_matches("from_tty2")?;
+
+        // its input never reaches the shell, but it still sees output
+        tty2.run_cmd("echo from_tty2")?;
+        tty1.run_cmd("echo from_tty1")?;
+        line_matcher1.scan_until_re("from_tty1$")?;
+        line_matcher2.scan_until_re("from_tty1$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn blank_session_not_allowed() -> anyhow::Result<()> {
@@ -765,6 +1053,208 @@ fn custom_keybinding_detach() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn detach_from_scroll_mode() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("scroll_keybinding.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        let mut a1 =
+            daemon_proc.attach("sess", Default::default()).context("starting attach proc")?;
+        let mut lm1 = a1.line_matcher()?;
+
+        a1.run_cmd("echo ready")?;
+        lm1.scan_until_re("ready$")?;
+
+        a1.run_raw(vec![22, 23, 7])?; // Ctrl-v Ctrl-w Ctrl-g
+        thread::sleep(time::Duration::from_millis(100));
+        a1.run_raw(vec![0, 17])?; // Ctrl-Space Ctrl-q
+        let exit_status = a1.proc.wait()?;
+        assert!(exit_status.success());
+
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn switch_in_place() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("switch_keybinding.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-done", "daemon-bidi-stream-enter"]);
+
+        let mut a1 =
+            daemon_proc.attach("sess", Default::default()).context("starting attach proc")?;
+        let mut lm1 = a1.line_matcher()?;
+
+        a1.run_cmd("echo $SHPOOL_SESSION_NAME")?;
+        lm1.scan_until_re("sess$")?;
+
+        a1.run_raw_cmd(vec![22, 23, 7])?; // Ctrl-v Ctrl-w Ctrl-g
+        waiter.wait_event("daemon-bidi-stream-done")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        // the same attach process is now talking to the new session
+        assert!(a1.proc.try_wait()?.is_none(), "attach exited when switching");
+        a1.run_cmd("echo $SHPOOL_SESSION_NAME")?;
+        lm1.scan_until_re("other$")?;
+
+        let out = daemon_proc.list()?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("sess"));
+        assert!(stdout.contains("other"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn switch_previous() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("switch_keybinding.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter([
+            "daemon-bidi-stream-done",
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-done",
+            "daemon-bidi-stream-enter",
+        ]);
+
+        let mut a1 =
+            daemon_proc.attach("sess", Default::default()).context("starting attach proc")?;
+        let mut lm1 = a1.line_matcher()?;
+
+        a1.run_cmd("echo $SHPOOL_SESSION_NAME")?;
+        lm1.scan_until_re("sess$")?;
+
+        // nowhere to go back to yet
+        let out = daemon_proc.switch("sess", "-")?;
+        assert!(!out.status.success(), "switched with no previous session");
+
+        a1.run_raw_cmd(vec![22, 23, 7])?; // Ctrl-v Ctrl-w Ctrl-g
+        waiter.wait_event("daemon-bidi-stream-done")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        a1.run_cmd("echo $SHPOOL_SESSION_NAME")?;
+        lm1.scan_until_re("other$")?;
+
+        let out = daemon_proc.switch("other", "-")?;
+        assert!(out.status.success(), "switch failed: {}", String::from_utf8_lossy(&out.stderr));
+        waiter.wait_event("daemon-bidi-stream-done")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        assert!(a1.proc.try_wait()?.is_none(), "attach exited when switching");
+        a1.run_cmd("echo $SHPOOL_SESSION_NAME")?;
+        lm1.scan_until_re("sess$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn attach_last() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-done", "daemon-bidi-stream-done"]);
+
+        for name in ["first", "second"] {
+            let mut a = daemon_proc.attach(name, Default::default()).context("attaching")?;
+            let mut lm = a.line_matcher()?;
+            a.run_cmd("echo hi")?;
+            lm.scan_until_re("hi$")?;
+            daemon_proc.detach(vec![String::from(name)])?;
+            a.proc.wait()?;
+            waiter.wait_event("daemon-bidi-stream-done")?;
+        }
+
+        let mut a = daemon_proc.attach("-", Default::default()).context("attaching to last")?;
+        let mut lm = a.line_matcher()?;
+        a.run_cmd("echo $SHPOOL_SESSION_NAME")?;
+        lm.scan_until_re("second$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn hangup_banner() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            // dropping the attach proc kills it without detaching
+            let mut a = daemon_proc.attach("sh1", Default::default()).context("attaching")?;
+            let mut lm = a.line_matcher()?;
+            a.run_cmd("echo hi")?;
+            lm.scan_until_re("hi$")?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+
+        let out = daemon_proc.list()?;
+        assert!(out.status.success(), "list proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("hung up"), "missing hung up note: {}", stdout);
+
+        let mut a = daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut lm = a.stderr_line_matcher()?;
+        lm.scan_until_re("previous client lost connection at .*, you were away [0-9]+s$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn reconnect_summary() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("reconnect_summary.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let bidi_done_w = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        {
+            let mut a = daemon_proc.attach("sh1", Default::default()).context("attaching")?;
+            let mut lm = a.line_matcher()?;
+            a.run_cmd("echo hi")?;
+            lm.scan_until_re("hi$")?;
+            a.run_cmd("sleep 1; echo one; echo two")?;
+            daemon_proc.detach(vec![String::from("sh1")])?;
+        }
+        daemon_proc.events = Some(bidi_done_w.wait_final_event("daemon-bidi-stream-done")?);
+        // give the session time to write its output while detached
+        thread::sleep(time::Duration::from_millis(1500));
+
+        let mut a = daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut lm = a.stderr_line_matcher()?;
+        lm.scan_until_re(
+            "you were away [0-9]+s, [0-9]+ B \\([0-9]+ lines\\) of output since you left, all of it replayed below$",
+        )?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn injects_term_even_with_env_config() -> anyhow::Result<()> {
@@ -796,6 +1286,48 @@ fn injects_term_even_with_env_config() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn session_config_overrides() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("session_overrides.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let mut special_proc =
+            daemon_proc.attach("special", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = special_proc.line_matcher()?;
+        special_proc.run_cmd("echo $SOME_CUSTOM_ENV_VAR:$PS1")?;
+        line_matcher.scan_until_re("specialvalue:prompt> $")?;
+
+        let mut plain_proc =
+            daemon_proc.attach("plain", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = plain_proc.line_matcher()?;
+        plain_proc.run_cmd("echo $SOME_CUSTOM_ENV_VAR")?;
+        line_matcher.scan_until_re("customvalue$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_config_templates() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("session_overrides.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let mut dev_proc =
+            daemon_proc.attach("dev-shpool", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = dev_proc.line_matcher()?;
+        dev_proc.run_cmd("echo $STARTUP_RAN:$PWD")?;
+        line_matcher.scan_until_re("yes:/tmp$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn injects_local_env_vars() -> anyhow::Result<()> {
@@ -1086,6 +1618,99 @@ fn ttl_no_hangup_yet() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn idle_ttl_reaps_detached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { idle_ttl: Some(time::Duration::from_secs(1)), ..Default::default() },
+            )
+            .context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        // an attached session is never idle, no matter how quiet it is
+        thread::sleep(time::Duration::from_millis(1500));
+        let listout = daemon_proc.list()?;
+        assert!(String::from_utf8_lossy(listout.stdout.as_slice()).contains("sh1"));
+
+        daemon_proc.detach(vec![String::from("sh1")])?;
+        daemon_proc.wait_until_list_matches(|listout| !listout.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn detach_policy_kill() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("detach_policy_kill.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        daemon_proc.detach(vec![String::from("sh1")])?;
+        daemon_proc.wait_until_list_matches(|listout| !listout.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn detach_policy_stop() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("detach_policy_stop.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let pid_file = tmp_dir.path().join("shell.pid");
+
+        // The process state letter, 'T' for stopped.
+        let state = |pid: &str| -> anyhow::Result<char> {
+            let out = Command::new("ps").args(["-o", "stat=", "-p", pid]).output()?;
+            let stat = String::from_utf8_lossy(&out.stdout[..]).trim().to_string();
+            stat.chars().next().ok_or(anyhow!("no state for pid {}", pid))
+        };
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd(&format!("echo $$ > {}; echo saved-$((1 + 1))", pid_file.display()))?;
+        line_matcher.scan_until_re("saved-2$")?;
+        let pid = fs::read_to_string(&pid_file)?.trim().to_string();
+        assert_ne!(state(&pid)?, 'T');
+
+        daemon_proc.detach(vec![String::from("sh1")])?;
+        support::wait_until(|| Ok(state(&pid)? == 'T'))?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        support::wait_until(|| Ok(state(&pid)? != 'T'))?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo resumed-$((2 + 2))")?;
+        line_matcher.scan_until_re("resumed-4$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn prompt_prefix_bash() -> anyhow::Result<()> {
@@ -1130,6 +1755,26 @@ fn prompt_prefix_bash() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn prompt_prefix_bash_keeps_prompt_command_local() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("prompt_prefix_bash.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        // a child shell does not have the hook function, so it must
+        // not inherit a PROMPT_COMMAND that calls it
+        attach_proc.run_cmd("bash -c 'echo pc=${PROMPT_COMMAND-unset}'")?;
+        line_matcher.scan_until_re("pc=unset$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn prompt_prefix_zsh() -> anyhow::Result<()> {
@@ -1803,6 +2448,29 @@ fn working_directory_cli_param() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn shell_cli_param() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let mut attach_proc = daemon_proc
+            .attach("sh1", AttachArgs { shell: Some(String::from("/bin/sh")), ..Default::default() })
+            .context("starting attach proc")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        // norc.toml says bash, but the flag wins. $SHELL only says what
+        // we asked for, so check the name the shell actually got run as.
+        let mut lm = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo $0")?;
+        lm.scan_until_re("^-?sh$")?;
+
+        Ok(())
+    })
+}
+
 #[test] 
 #[timeout(30000)]
 fn working_directory_config() -> anyhow::Result<()> {
@@ -2010,3 +2678,129 @@ fn restore_override_test() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn keeps_exited_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "reap_exited_never.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo ready")?;
+            line_matcher.scan_until_re("ready$")?;
+
+            // detach by dropping the attach proc before the shell exits
+            attach_proc.run_cmd("sleep 1; exit 3")?;
+        }
+
+        daemon_proc.wait_until_list_matches(|out| out.contains("exited(3)"))?;
+
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        line_matcher.scan_until_re("exited with status 3")?;
+        let exit_status = attach_proc.proc.wait().context("waiting for attach proc")?;
+        assert_eq!(exit_status.code(), Some(3));
+
+        let out = daemon_proc.kill(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "kill proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| !out.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn screen_lock_after_idle() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let config_file = tmp_dir.path().join("config.toml");
+        let config = fs::read_to_string(support::testdata_file("restore_lines.toml"))?;
+        fs::write(&config_file, format!("screen_lock_after_idle = \"1s\"\n{config}"))?;
+
+        let mut daemon_proc = support::daemon::Proc::new(
+            &config_file,
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo; echo before-$((1 + 1))")?;
+        line_matcher.scan_until_re("before-2$")?;
+        libshpool::client::Client::new(&daemon_proc.socket_path).lock("sh1", "hunter2")?;
+
+        // go idle long enough for the screen to lock, then wake it up and
+        // type the passphrase
+        thread::sleep(time::Duration::from_secs(2));
+        attach_proc.run_raw(b"x".to_vec())?;
+        thread::sleep(time::Duration::from_millis(200));
+        attach_proc.run_raw(b"hunter2\r".to_vec())?;
+
+        // The locked screen has no newlines in it, so it comes through
+        // along with the start of the redraw, which brings back the
+        // output from before the lock.
+        line_matcher.scan_until_re("Type its passphrase")?;
+        line_matcher.scan_until_re("before-2$")?;
+
+        attach_proc.run_cmd("echo after-$((2 + 2))")?;
+        line_matcher.scan_until_re("after-4$")?;
+
+        Ok(())
+    })
+}
+
+/// A field from the `shpool stat` output for sh1.
+fn stat_field(daemon_proc: &mut support::daemon::Proc, field: &str) -> anyhow::Result<String> {
+    let out = daemon_proc.stat("sh1")?;
+    let stdout = String::from_utf8_lossy(&out.stdout[..]).into_owned();
+    let prefix = format!("{field}:\t");
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix).map(String::from))
+        .ok_or(anyhow!("no {} in stat output: {}", field, stdout))
+}
+
+#[test]
+#[timeout(30000)]
+fn slow_client_resync() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("slow_client.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+        assert_eq!(stat_field(&mut daemon_proc, "resyncs")?, "0");
+
+        // Nothing reads the attach proc's output for now, so it soon
+        // stops reading from the daemon and the queue overflows.
+        attach_proc.run_cmd("seq 1 200000; echo flood-done-$((1 + 1))")?;
+        support::wait_until(|| Ok(stat_field(&mut daemon_proc, "dropped")? != "0 B"))?;
+
+        // Once the client catches up, its screen gets cleared and
+        // repainted from the restore buffer.
+        line_matcher.scan_until_re("\\x1b\\[H\\x1b\\[2J")?;
+        line_matcher.scan_until_re("flood-done-2$")?;
+        support::wait_until(|| Ok(stat_field(&mut daemon_proc, "resyncs")? != "0"))?;
+
+        attach_proc.run_cmd("echo after-$((2 + 2))")?;
+        line_matcher.scan_until_re("after-4$")?;
+
+        Ok(())
+    })
+}
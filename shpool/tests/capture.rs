@@ -0,0 +1,52 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn detached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo captured-$((1 + 1))")?;
+            line_matcher.scan_until_re("captured-2$")?;
+        }
+
+        let out = daemon_proc.capture("sh1", &["--scrollback"])?;
+        assert!(out.status.success(), "capture proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.lines().any(|l| l == "captured-2"));
+
+        let out_file = daemon_proc.tmp_dir.join("capture.out");
+        let out_file_arg = out_file.to_string_lossy().into_owned();
+        let out = daemon_proc.capture("sh1", &["--strip-ansi", "-o", &out_file_arg])?;
+        assert!(out.status.success(), "capture proc did not exit successfully");
+        let captured = std::fs::read(&out_file)?;
+        assert!(!captured.contains(&0x1b));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.capture("nope", &[])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"));
+
+        Ok(())
+    })
+}
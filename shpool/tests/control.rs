@@ -0,0 +1,62 @@
+use std::io::{BufRead, BufReader, Write};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+use serde_json::Value;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+// Read events until one matches, returning it.
+fn scan_until<R: BufRead>(events: &mut R, want: impl Fn(&Value) -> bool) -> anyhow::Result<Value> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if events.read_line(&mut line)? == 0 {
+            return Err(anyhow!("control proc hung up"));
+        }
+        let event: Value = serde_json::from_str(&line).context("parsing event")?;
+        if want(&event) {
+            return Ok(event);
+        }
+    }
+}
+
+#[test]
+#[timeout(30000)]
+fn create_and_proxy() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut control = daemon_proc.control()?;
+        let mut requests = control.stdin.take().unwrap();
+        let mut events = BufReader::new(control.stdout.take().unwrap());
+
+        writeln!(requests, r#"{{"id": 1, "cmd": "create", "session": "ide"}}"#)?;
+        scan_until(&mut events, |e| e["event"] == "done" && e["id"] == 1)?;
+        scan_until(&mut events, |e| e["event"] == "created" && e["session"] == "ide")?;
+
+        writeln!(requests, r#"{{"id": 2, "cmd": "attach-proxy", "session": "ide"}}"#)?;
+        scan_until(&mut events, |e| e["event"] == "done" && e["id"] == 2)?;
+        writeln!(
+            requests,
+            r#"{{"cmd": "send-input", "session": "ide", "data": "echo proxied-$((1 + 1))\n"}}"#
+        )?;
+        scan_until(&mut events, |e| {
+            e["event"] == "output" && e["data"].as_str().is_some_and(|d| d.contains("proxied-2"))
+        })?;
+
+        writeln!(requests, r#"{{"id": 3, "cmd": "attach-proxy", "session": "nope"}}"#)?;
+        scan_until(&mut events, |e| e["event"] == "error" && e["id"] == 3)?;
+
+        daemon_proc.kill(vec![String::from("ide")])?;
+        scan_until(&mut events, |e| e["event"] == "exited" && e["session"] == "ide")?;
+
+        drop(requests);
+        let status = control.wait()?;
+        assert!(status.success(), "control proc exited with {status}");
+
+        Ok(())
+    })
+}
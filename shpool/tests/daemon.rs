@@ -1,7 +1,10 @@
 use std::{
     fmt::Write,
-    io::Read,
-    os::unix::{net::UnixListener, process::CommandExt as _},
+    io::{BufRead as _, BufReader, Read, Write as _},
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        process::CommandExt as _,
+    },
     path,
     process::{Command, Stdio},
     time,
@@ -280,6 +283,66 @@ fn cleanup_socket() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn stop() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+
+        let out = daemon_proc.stop("5s")?;
+        assert!(out.status.success(), "stop proc exited with {}", out.status);
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("1 sessions exited, 0 killed"), "stdout: {stdout}");
+
+        daemon_proc.proc_wait()?;
+        assert!(!path::Path::new(&daemon_proc.socket_path).exists());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn dump_state() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+
+        let out = daemon_proc.dump_state()?;
+        assert!(out.status.success(), "dump-state proc exited with {}", out.status);
+        let state: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+        assert_eq!(state["sessions"][0]["name"], "sh1");
+        assert_eq!(state["sessions"][0]["attached"], true);
+        let threads = state["threads"].as_array().ok_or(anyhow!("no threads"))?;
+        assert!(threads.iter().any(|t| t["name"] == "s2c:sh1"), "threads: {threads:?}");
+
+        // a SIGUSR1 gets the same thing written out by the daemon
+        signal::kill(
+            Pid::from_raw(daemon_proc.proc.as_ref().unwrap().id() as i32),
+            Signal::SIGUSR1,
+        )?;
+        support::wait_until(|| {
+            let log = std::fs::read_to_string(&daemon_proc.log_file)?;
+            Ok(log.contains("wrote state dump to"))
+        })?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn echo_sentinel() -> anyhow::Result<()> {
@@ -331,3 +394,223 @@ fn allows_dynamic_log_adjustments() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn daemonize_pid_file() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let socket = tmp_dir.path().join("shpool.socket");
+        let pid_file = tmp_dir.path().join("shpool.pid");
+        // The pid file is given relative to the working directory, which
+        // the daemon leaves once it is in the background.
+        let daemonize = || -> anyhow::Result<std::process::Output> {
+            Command::new(support::shpool_bin()?)
+                .current_dir(tmp_dir.path())
+                .arg("--socket")
+                .arg(&socket)
+                .arg("--config-file")
+                .arg(support::testdata_file("norc.toml"))
+                .arg("daemon")
+                .arg("--daemonize")
+                .arg("--pid-file")
+                .arg("shpool.pid")
+                .output()
+                .context("running daemon")
+        };
+
+        let out = daemonize()?;
+        assert!(out.status.success(), "daemonizing failed: {out:?}");
+        support::wait_until(|| {
+            Ok(socket.exists()
+                && std::fs::read_to_string(&pid_file).is_ok_and(|pid| pid.ends_with('\n')))
+        })?;
+        let pid: i32 = std::fs::read_to_string(&pid_file)?.trim().parse()?;
+
+        // a second daemon with the same pid file refuses to start
+        let out = daemonize()?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("already running"), "stderr: {stderr}");
+
+        signal::kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+        support::wait_until(|| Ok(!pid_file.exists() && !socket.exists()))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn systemd_notify() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let notify_path = tmp_dir.path().join("notify.socket");
+        let notify_sock = std::os::unix::net::UnixDatagram::bind(&notify_path)?;
+        notify_sock.set_read_timeout(Some(time::Duration::from_secs(10)))?;
+
+        let mut child = Command::new(support::shpool_bin()?)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .env("NOTIFY_SOCKET", &notify_path)
+            .env("WATCHDOG_USEC", "200000")
+            .arg("--socket")
+            .arg(tmp_dir.path().join("shpool.socket"))
+            .arg("daemon")
+            .spawn()
+            .context("spawning daemon process")?;
+
+        let recv = || -> anyhow::Result<String> {
+            let mut buf = [0; 128];
+            let len = notify_sock.recv(&mut buf).context("waiting for notification")?;
+            Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+        };
+        assert_eq!(recv()?, "READY=1");
+        assert_eq!(recv()?, "WATCHDOG=1");
+        assert_eq!(recv()?, "WATCHDOG=1");
+
+        signal::kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM)?;
+        while recv()? != "STOPPING=1" {}
+        child.wait()?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn json_rpc() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let json_socket = tmp_dir.path().join("json.socket");
+        let config_file = tmp_dir.path().join("config.toml");
+        std::fs::write(
+            &config_file,
+            format!(
+                "norc = true\nprompt_prefix = \"\"\njson_socket = {:?}\n",
+                json_socket.to_string_lossy()
+            ),
+        )?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+
+        let mut stream = None;
+        for _ in 0..50 {
+            if let Ok(s) = UnixStream::connect(&json_socket) {
+                stream = Some(s);
+                break;
+            }
+            std::thread::sleep(time::Duration::from_millis(20));
+        }
+        let stream = stream.ok_or(anyhow!("could not connect to json socket"))?;
+        let mut replies = BufReader::new(stream.try_clone()?);
+        let mut call = |request: &str| -> anyhow::Result<serde_json::Value> {
+            writeln!(&stream, "{request}")?;
+            let mut line = String::new();
+            replies.read_line(&mut line)?;
+            Ok(serde_json::from_str(&line)?)
+        };
+
+        let reply = call(r#"{"jsonrpc": "2.0", "id": 1, "method": "list"}"#)?;
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"]["sessions"][0]["name"], "sh1");
+
+        // notifications get no reply, so the next line answers id 2
+        writeln!(&stream, r#"{{"jsonrpc": "2.0", "method": "list"}}"#)?;
+        let reply = call(r#"{"jsonrpc": "2.0", "id": 2, "method": "attach"}"#)?;
+        assert_eq!(reply["id"], 2);
+        assert_eq!(reply["error"]["code"], -32601);
+
+        let reply = call(
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "kill", "params": {"sessions": ["sh1"], "signal": "SIGNOPE"}}"#,
+        )?;
+        assert_eq!(reply["result"]["error"], "unknown signal 'SIGNOPE'");
+
+        let reply = call(
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "kill", "params": {"sessions": ["sh1"]}}"#,
+        )?;
+        assert_eq!(reply["result"]["killed"][0], "sh1");
+        daemon_proc.wait_until_list_matches(|out| !out.contains("sh1"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn disk_spool_survives_restart() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let config_file = tmp_dir.path().join("config.toml");
+        std::fs::write(
+            &config_file,
+            "norc = true\nnoecho = true\nshell = \"/bin/bash\"\nsession_restore = \"disk:1MB\"\n\
+             prompt_prefix = \"\"\n\n[env]\nPS1 = \"prompt> \"\nTERM = \"\"\n",
+        )?;
+        // Both daemons have to agree on where the checkpoints and the
+        // manifest live.
+        let daemon_args = || DaemonArgs {
+            listen_events: false,
+            extra_env: vec![
+                (
+                    String::from("XDG_RUNTIME_DIR"),
+                    tmp_dir.path().join("run").to_string_lossy().into_owned(),
+                ),
+                (
+                    String::from("XDG_STATE_HOME"),
+                    tmp_dir.path().join("state").to_string_lossy().into_owned(),
+                ),
+            ],
+            socket: Some(tmp_dir.path().join("shpool.socket")),
+            ..DaemonArgs::default()
+        };
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, daemon_args())
+            .context("starting daemon proc")?;
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo restored-$((20 + 3))")?;
+            line_matcher.scan_until_re("restored-23$")?;
+
+            // give the spool a chance to write out a checkpoint
+            std::thread::sleep(time::Duration::from_secs(6));
+            attach_proc.run_cmd("echo flushed")?;
+            line_matcher.scan_until_re("flushed$")?;
+        }
+        let out = daemon_proc.stop("5s")?;
+        assert!(out.status.success(), "stop proc exited with {}", out.status);
+        daemon_proc.proc_wait()?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, daemon_args())
+            .context("starting second daemon proc")?;
+        daemon_proc.wait_until_list_matches(|out| out.contains("sh1"))?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        line_matcher.scan_until_re("restored-23$")?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,89 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn runs_in_attached_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        let out = daemon_proc.exec("sh1", "echo from exec", &[])?;
+        assert!(out.status.success(), "exec proc did not exit successfully");
+        line_matcher.scan_until_re("from exec$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn until_marker() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.exec("sh1", "echo DONE-$((40 + 2))", &["--until", "DONE-42"])?;
+        assert!(out.status.success(), "exec proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("DONE-42"));
+
+        let out =
+            daemon_proc.exec("sh1", "true", &["--until", "never-shows", "--timeout", "1s"])?;
+        assert!(!out.status.success());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn until_marker_in_command() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        // The marker is spelled out in the command, so the shell's echo
+        // of it must not count.
+        let out = daemon_proc.exec("sh1", "sleep 1; echo FINISHED", &["--until", "FINISHED"])?;
+        assert!(out.status.success(), "exec proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout.matches("FINISHED").count(), 2, "unexpected output: {stdout:?}");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.exec("nope", "echo hi", &[])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"));
+
+        Ok(())
+    })
+}
@@ -7,7 +7,7 @@ use ntest::timeout;
 
 mod support;
 
-use crate::support::daemon::DaemonArgs;
+use crate::support::daemon::{AttachArgs, DaemonArgs};
 
 #[test]
 #[timeout(30000)]
@@ -351,3 +351,152 @@ fn missing() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn pattern() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc.events.take().unwrap().waiter([
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-enter",
+            "daemon-bidi-stream-enter",
+        ]);
+        let _sess1 =
+            daemon_proc.attach("ci-1", Default::default()).context("starting attach proc")?;
+        let _sess2 =
+            daemon_proc.attach("ci-2", Default::default()).context("starting attach proc")?;
+        let _sess3 =
+            daemon_proc.attach("main", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.kill(vec![String::from("ci-*"), String::from("nomatch-*")])?;
+        assert!(out.status.success());
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, "killed: ci-1 ci-2\n");
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no sessions matched: nomatch-*"));
+
+        let listout = daemon_proc.list()?;
+        let listout = String::from_utf8_lossy(listout.stdout.as_slice());
+        assert!(!listout.contains("ci-"));
+        assert!(listout.contains("main"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn all() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-enter"]);
+        let _sess1 =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let _sess2 =
+            daemon_proc.attach("sh2", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.kill(vec![String::from("--all")])?;
+        assert!(out.status.success());
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, "killed: sh1 sh2\n");
+
+        let listout = daemon_proc.list()?;
+        assert!(!String::from_utf8_lossy(listout.stdout.as_slice()).contains("sh"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn tag() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-enter"]);
+        let _sess1 = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { tags: vec![String::from("ci")], ..Default::default() },
+            )
+            .context("starting attach proc")?;
+        let _sess2 =
+            daemon_proc.attach("sh2", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.kill(vec![String::from("--tag"), String::from("ci")])?;
+        assert!(out.status.success());
+
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert_eq!(stdout, "killed: sh1\n");
+
+        let listout = daemon_proc.list()?;
+        let listout = String::from_utf8_lossy(listout.stdout.as_slice());
+        assert!(!listout.contains("sh1"));
+        assert!(listout.contains("sh2"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn signal() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut sess1 =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut lm1 = sess1.line_matcher()?;
+        // the arithmetic keeps the echoed commands from matching
+        sess1.run_cmd("trap 'echo got-$((1+1))' USR1; echo trapped-$((1+2))")?;
+        lm1.scan_until_re("trapped-3$")?;
+
+        let out = daemon_proc.kill(vec![
+            String::from("--signal"),
+            String::from("USR1"),
+            String::from("sh1"),
+        ])?;
+        assert!(out.status.success());
+
+        sess1.run_cmd("echo still-$((1+3))")?;
+        lm1.scan_until_re("got-2$")?;
+        lm1.scan_until_re("still-4$")?;
+
+        let listout = daemon_proc.list()?;
+        assert!(String::from_utf8_lossy(listout.stdout.as_slice()).contains("sh1"));
+
+        let out = daemon_proc.kill(vec![
+            String::from("--signal"),
+            String::from("NOPE"),
+            String::from("sh1"),
+        ])?;
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr[..]).contains("unknown signal 'SIGNOPE'"));
+
+        Ok(())
+    })
+}
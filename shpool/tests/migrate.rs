@@ -0,0 +1,161 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn moves_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut from_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut to_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting second daemon proc")?;
+
+        let mut waiter = from_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-done"]);
+        let mut attach_proc =
+            from_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("export MIGRATE_MARKER=still-here")?;
+        attach_proc.run_cmd("echo marked")?;
+        line_matcher.scan_until_re("marked$")?;
+
+        let to_socket = to_proc.socket_path.clone();
+        let out = from_proc.migrate("sh1", &to_socket)?;
+        assert!(out.status.success(), "migrate proc did not exit successfully");
+        from_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-done")?);
+        attach_proc.proc.wait()?;
+
+        let listout = from_proc.list()?;
+        assert!(!String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+        let listout = to_proc.list()?;
+        assert!(String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+
+        // the same shell keeps running under the other daemon
+        let mut attach_proc =
+            to_proc.attach("sh1", Default::default()).context("reattaching to migrated session")?;
+        line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo marker=$MIGRATE_MARKER")?;
+        line_matcher.scan_until_re("marker=still-here$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn locked() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut from_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let to_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting second daemon proc")?;
+
+        let mut attach_proc =
+            from_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+        libshpool::client::Client::new(&from_proc.socket_path).lock("sh1", "hunter2")?;
+
+        let out = from_proc.migrate("sh1", &to_proc.socket_path)?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'sh1' is locked"));
+
+        // the session stays put
+        let listout = from_proc.list()?;
+        assert!(String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn missing() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut from_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let to_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting second daemon proc")?;
+
+        let out = from_proc.migrate("missing", &to_proc.socket_path)?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: missing"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn name_taken() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut from_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut to_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting second daemon proc")?;
+
+        let from_waiter = from_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _from_attach =
+            from_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        from_proc.events = Some(from_waiter.wait_final_event("daemon-bidi-stream-enter")?);
+        let to_waiter = to_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _to_attach =
+            to_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        to_proc.events = Some(to_waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let to_socket = to_proc.socket_path.clone();
+        let out = from_proc.migrate("sh1", &to_socket)?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("could not migrate 'sh1'"));
+
+        // the session stays put
+        let listout = from_proc.list()?;
+        assert!(String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_limit() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut from_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut to_proc = support::daemon::Proc::new("one_session.toml", DaemonArgs::default())
+            .context("starting second daemon proc")?;
+
+        let from_waiter = from_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _from_attach =
+            from_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        from_proc.events = Some(from_waiter.wait_final_event("daemon-bidi-stream-enter")?);
+        let to_waiter = to_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _to_attach =
+            to_proc.attach("sh2", Default::default()).context("starting attach proc")?;
+        to_proc.events = Some(to_waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        // the target daemon only has room for the session it already has
+        let to_socket = to_proc.socket_path.clone();
+        let out = from_proc.migrate("sh1", &to_socket)?;
+        assert!(!out.status.success());
+
+        let listout = from_proc.list()?;
+        assert!(String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+        let listout = to_proc.list()?;
+        assert!(!String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,52 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn set_and_clear() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.note("sh1", &["bisecting the\nflaky test"])?;
+        assert!(out.status.success(), "note proc did not exit successfully");
+
+        let out = daemon_proc.note("sh1", &[])?;
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "bisecting the flaky test\n");
+
+        let out = daemon_proc.note("sh1", &["--clear"])?;
+        assert!(out.status.success());
+        let out = daemon_proc.note("sh1", &[])?;
+        assert!(out.status.success());
+        assert!(out.stdout.is_empty());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.note("nosuchsession", &["hi"])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nosuchsession"));
+
+        Ok(())
+    })
+}
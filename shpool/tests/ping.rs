@@ -0,0 +1,64 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.ping(&[])?;
+        assert!(out.status.success(), "ping proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.starts_with("pong from shpool v"), "unexpected output: {stdout}");
+        assert!(stdout.contains("ms"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        let out = daemon_proc.ping(&["--echo", "sh1"])?;
+        assert!(out.status.success(), "ping proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("session sh1: "), "unexpected output: {stdout}");
+        assert!(stdout.contains("for the shell to echo"), "unexpected output: {stdout}");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn missing_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.ping(&["missing"])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: missing"));
+
+        Ok(())
+    })
+}
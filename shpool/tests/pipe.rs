@@ -0,0 +1,103 @@
+use std::{io::Write as _, thread, time};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn runs_lines_in_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        let out = daemon_proc.pipe(
+            "sh1",
+            b"echo first-$((1 + 1))\necho second-$((2 + 2))",
+            &["--newline"],
+        )?;
+        assert!(out.status.success(), "pipe proc did not exit successfully");
+        line_matcher.scan_until_re("first-2$")?;
+        line_matcher.scan_until_re("second-4$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn eof_ends_input() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("wc -l; echo counted")?;
+
+        let out = daemon_proc.pipe("sh1", b"one\ntwo\nthree\n", &["--newline", "--eof"])?;
+        assert!(out.status.success(), "pipe proc did not exit successfully");
+        line_matcher.scan_until_re("^ *3$")?;
+        line_matcher.scan_until_re("counted$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.pipe("nope", b"echo hi\n", &[])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_exits() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        let mut pipe_proc = daemon_proc.pipe_proc("sh1", &["--newline"])?;
+        let mut stdin = pipe_proc.stdin.take().unwrap();
+        stdin.write_all(b"exit\n")?;
+        stdin.flush()?;
+        attach_proc.proc.wait()?;
+        // give the daemon a few polls to notice before hanging up, so
+        // that it doesn't just see the end of the input
+        thread::sleep(time::Duration::from_millis(500));
+        drop(stdin);
+
+        let out = pipe_proc.wait_with_output()?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'sh1' has exited"), "unexpected stderr: {stderr}");
+
+        Ok(())
+    })
+}
@@ -0,0 +1,106 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn attached() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.rename("sh1", "sh2")?;
+        assert!(out.status.success(), "rename proc did not exit successfully");
+
+        let listout = daemon_proc.list()?;
+        let listout = String::from_utf8_lossy(listout.stdout.as_slice());
+        assert!(listout.contains("sh2"));
+        assert!(!listout.contains("sh1"));
+
+        // the shell keeps running under its new name
+        attach_proc.run_cmd("echo still here")?;
+        line_matcher.scan_until_re("still here$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn taken() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-enter"]);
+        let _sess1 =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let _sess2 =
+            daemon_proc.attach("sh2", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let out = daemon_proc.rename("sh1", "sh2")?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'sh2' already exists"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn missing() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.rename("missing", "other")?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: missing"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn escaping_name() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _sess1 =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        for to in ["../x", "..", "a/b"] {
+            let out = daemon_proc.rename("sh1", to)?;
+            assert!(!out.status.success(), "renaming to '{to}' succeeded");
+            let stderr = String::from_utf8_lossy(&out.stderr[..]);
+            assert!(stderr.contains(&format!("invalid session name '{to}'")));
+        }
+
+        let listout = daemon_proc.list()?;
+        let listout = String::from_utf8_lossy(listout.stdout.as_slice());
+        assert!(listout.contains("sh1"));
+
+        Ok(())
+    })
+}
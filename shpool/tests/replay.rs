@@ -0,0 +1,65 @@
+use std::{fs, thread, time};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn replays_output_log() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let log_dir = tmp_dir.path().join("logs");
+        let config_file = tmp_dir.path().join("config.toml");
+        let config = fs::read_to_string(support::testdata_file("norc.toml"))?;
+        fs::write(
+            &config_file,
+            format!("{config}\n[output_log]\ndir = {:?}\nformat = \"asciicast\"\n", log_dir),
+        )?;
+
+        let mut daemon_proc = support::daemon::Proc::new(&config_file, DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo first-$((1 + 1))")?;
+        line_matcher.scan_until_re("first-2$")?;
+        let out = daemon_proc.note("sh1", &["half way"])?;
+        assert!(out.status.success(), "note proc did not exit successfully");
+        attach_proc.run_cmd("echo second-$((2 + 2))")?;
+        line_matcher.scan_until_re("second-4$")?;
+
+        // the log gets written by its own thread, so give it a moment
+        // to catch up
+        let mut cast = None;
+        for _ in 0..50 {
+            if let Some(entry) = fs::read_dir(&log_dir).ok().and_then(|mut d| d.next()) {
+                let path = entry?.path();
+                if fs::read_to_string(&path)?.contains("second-4") {
+                    cast = Some(path);
+                    break;
+                }
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        let cast = cast.ok_or(anyhow!("no recording with the output in it"))?;
+        assert_eq!(cast.extension().and_then(|e| e.to_str()), Some("cast"));
+        assert!(fs::read_to_string(&cast)?.contains(r#""m","half way"]"#));
+
+        let out = daemon_proc.replay(&cast, &["--speed", "100"])?;
+        assert!(out.status.success(), "replay proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("first-2"), "unexpected replay output: {stdout:?}");
+        assert!(stdout.contains("second-4"), "unexpected replay output: {stdout:?}");
+
+        Ok(())
+    })
+}
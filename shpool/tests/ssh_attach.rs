@@ -0,0 +1,64 @@
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt as _,
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn forced_file_transfer_skips_shell() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let sftp_server = tmp_dir.path().join("sftp-server");
+        fs::write(&sftp_server, "#!/bin/sh\necho \"sftp-server got: $*\"\n")?;
+        fs::set_permissions(&sftp_server, fs::Permissions::from_mode(0o755))?;
+        let path = format!("{}:{}", tmp_dir.path().display(), env::var("PATH")?);
+
+        // A shell that can't run anything, so the transfer only works
+        // if it gets run directly.
+        let out = Command::new(support::shpool_bin()?)
+            .stdin(Stdio::null())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("norc.toml"))
+            .arg("ssh-attach")
+            .env("SSH_ORIGINAL_COMMAND", "sftp-server -l 'INFO'")
+            .env("SHELL", "/bin/false")
+            .env("PATH", &path)
+            .output()
+            .context("running ssh-attach")?;
+        assert!(out.status.success(), "ssh-attach did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "sftp-server got: -l INFO\n");
+
+        // Anything else still goes through the shell.
+        let out = Command::new(support::shpool_bin()?)
+            .stdin(Stdio::null())
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("norc.toml"))
+            .arg("ssh-attach")
+            .env("SSH_ORIGINAL_COMMAND", "echo via-shell-$((1 + 1))")
+            .env("SHELL", "/bin/sh")
+            .output()
+            .context("running ssh-attach")?;
+        assert!(out.status.success(), "ssh-attach did not exit successfully");
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "via-shell-2\n");
+
+        Ok(())
+    })
+}
@@ -1,6 +1,7 @@
 use std::{
     default::Default,
     env,
+    io::Write as _,
     os::unix::{net::UnixStream, prelude::ExitStatusExt as _},
     path::{Path, PathBuf},
     process,
@@ -32,11 +33,14 @@ pub struct DaemonArgs {
     pub listen_events: bool,
     pub extra_env: Vec<(String, String)>,
     pub verbosity: i64,
+    /// Listen on this socket rather than one in the daemon's own tmp
+    /// dir, so that a new daemon can pick up where an old one left off.
+    pub socket: Option<PathBuf>,
 }
 
 impl std::default::Default for DaemonArgs {
     fn default() -> Self {
-        DaemonArgs { listen_events: true, extra_env: vec![], verbosity: 2 }
+        DaemonArgs { listen_events: true, extra_env: vec![], verbosity: 2, socket: None }
     }
 }
 
@@ -44,11 +48,18 @@ impl std::default::Default for DaemonArgs {
 pub struct AttachArgs {
     pub config: Option<String>,
     pub force: bool,
+    pub no_steal: bool,
+    pub mirror: bool,
+    pub read_only: bool,
     pub extra_env: Vec<(String, String)>,
     pub ttl: Option<time::Duration>,
+    pub idle_ttl: Option<time::Duration>,
     pub cmd: Option<String>,
+    pub restart: Option<String>,
     pub dir: Option<String>,
+    pub shell: Option<String>,
     pub restore: Option<String>,
+    pub tags: Vec<String>,
 }
 
 pub struct HooksRecorder {
@@ -110,7 +121,7 @@ impl Proc {
             .context("creating tmp dir")?;
         let tmp_dir = local_tmp_dir.path().to_path_buf();
 
-        let socket_path = tmp_dir.join("shpool.socket");
+        let socket_path = args.socket.clone().unwrap_or_else(|| tmp_dir.join("shpool.socket"));
         // Use shorter path on macOS to avoid SUN_LEN limit (104 chars)
         #[cfg(target_os = "macos")]
         let test_hook_socket_path = tmp_dir.join("hook.socket");
@@ -239,7 +250,12 @@ impl Proc {
             ),
             daemonize: false,
             no_daemonize: true,
-            command: libshpool::Commands::Daemon,
+            command: libshpool::Commands::Daemon {
+                takeover: false,
+                daemonize: false,
+                pid_file: None,
+                command: None,
+            },
             ..libshpool::Args::default()
         };
         let hooks_recorder = Box::new(HooksRecorder {
@@ -344,22 +360,47 @@ impl Proc {
         if args.force {
             cmd.arg("-f");
         }
+        if args.no_steal {
+            cmd.arg("--no-steal");
+        }
+        if args.mirror {
+            cmd.arg("--mirror");
+        }
+        if args.read_only {
+            cmd.arg("--read-only");
+        }
         if let Some(ttl) = args.ttl {
             cmd.arg("--ttl");
             cmd.arg(format!("{}s", ttl.as_secs()));
         }
+        if let Some(idle_ttl) = args.idle_ttl {
+            cmd.arg("--idle-ttl");
+            cmd.arg(format!("{}s", idle_ttl.as_secs()));
+        }
         if let Some(cmd_str) = &args.cmd {
             cmd.arg("-c");
             cmd.arg(cmd_str);
         }
+        if let Some(restart) = &args.restart {
+            cmd.arg("--restart");
+            cmd.arg(restart);
+        }
         if let Some(dir_str) = &args.dir {
             cmd.arg("--dir");
             cmd.arg(dir_str);
         }
+        if let Some(shell) = &args.shell {
+            cmd.arg("--shell");
+            cmd.arg(shell);
+        }
         if let Some(restore_str) = &args.restore {
             cmd.arg("--restore");
             cmd.arg(restore_str);
         }
+        for tag in args.tags.iter() {
+            cmd.arg("--tag");
+            cmd.arg(tag);
+        }
         let proc = cmd.arg(name).spawn().context(format!("spawning attach proc for {name}"))?;
 
         let events = Events::new(&test_hook_socket_path)?;
@@ -405,6 +446,42 @@ impl Proc {
         cmd.output().context("spawning kill proc")
     }
 
+    pub fn stop(&mut self, grace: &str) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("stop_{}.log", self.subproc_counter));
+        eprintln!("spawning stop proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("daemon")
+            .arg("stop")
+            .arg("--grace")
+            .arg(grace)
+            .output()
+            .context("spawning stop proc")
+    }
+
+    pub fn dump_state(&mut self) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("dump_state_{}.log", self.subproc_counter));
+        eprintln!("spawning dump-state proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("daemon")
+            .arg("dump-state")
+            .output()
+            .context("spawning dump-state proc")
+    }
+
     pub fn wait_until_list_matches<F>(&mut self, pred: F) -> anyhow::Result<()>
     where
         F: Fn(&str) -> bool,
@@ -439,6 +516,305 @@ impl Proc {
             .context("spawning list proc")
     }
 
+    pub fn switch(&mut self, session: &str, target: &str) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("switch_{}.log", self.subproc_counter));
+        eprintln!("spawning switch proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("switch")
+            .arg("--session")
+            .arg(session)
+            .arg(target)
+            .output()
+            .context("spawning switch proc")
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("rename_{}.log", self.subproc_counter));
+        eprintln!("spawning rename proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("rename")
+            .arg(from)
+            .arg(to)
+            .output()
+            .context("spawning rename proc")
+    }
+
+    pub fn note(&mut self, session: &str, args: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("note_{}.log", self.subproc_counter));
+        eprintln!("spawning note proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("note")
+            .arg(session)
+            .args(args)
+            .output()
+            .context("spawning note proc")
+    }
+
+    pub fn watch(&mut self, session: &str, args: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("watch_{}.log", self.subproc_counter));
+        eprintln!("spawning watch proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("watch")
+            .arg(session)
+            .args(args)
+            .output()
+            .context("spawning watch proc")
+    }
+
+    pub fn migrate(&mut self, session: &str, to_socket: &Path) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("migrate_{}.log", self.subproc_counter));
+        eprintln!("spawning migrate proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("migrate")
+            .arg("--to-socket")
+            .arg(to_socket)
+            .arg(session)
+            .output()
+            .context("spawning migrate proc")
+    }
+
+    // launches a `shpool capture` process
+    pub fn capture(&mut self, session: &str, flags: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("capture_{}.log", self.subproc_counter));
+        eprintln!("spawning capture proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("capture")
+            .args(flags)
+            .arg(session)
+            .output()
+            .context("spawning capture proc")
+    }
+
+    // launches a `shpool exec` process
+    pub fn exec(
+        &mut self,
+        session: &str,
+        cmd: &str,
+        flags: &[&str],
+    ) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("exec_{}.log", self.subproc_counter));
+        eprintln!("spawning exec proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("exec")
+            .args(flags)
+            .arg(session)
+            .arg(cmd)
+            .output()
+            .context("spawning exec proc")
+    }
+
+    // launches a `shpool tail` process
+    pub fn tail(&mut self, session: &str, flags: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("tail_{}.log", self.subproc_counter));
+        eprintln!("spawning tail proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("tail")
+            .args(flags)
+            .arg(session)
+            .output()
+            .context("spawning tail proc")
+    }
+
+    // launches a `shpool wait` process
+    pub fn wait(&mut self, session: &str, flags: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("wait_{}.log", self.subproc_counter));
+        eprintln!("spawning wait proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("wait")
+            .args(flags)
+            .arg(session)
+            .output()
+            .context("spawning wait proc")
+    }
+
+    pub fn ping(&mut self, flags: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("ping_{}.log", self.subproc_counter));
+        eprintln!("spawning ping proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("ping")
+            .args(flags)
+            .output()
+            .context("spawning ping proc")
+    }
+
+    // launches a `shpool pipe` process fed the given input
+    pub fn pipe(
+        &mut self,
+        session: &str,
+        input: &[u8],
+        flags: &[&str],
+    ) -> anyhow::Result<process::Output> {
+        let mut child = self.pipe_proc(session, flags)?;
+        // a pipe proc that bails early hangs up on its stdin
+        let _ = child.stdin.take().unwrap().write_all(input);
+        child.wait_with_output().context("waiting for pipe proc")
+    }
+
+    // launches a `shpool pipe` process and leaves its stdin open for
+    // the caller to write to
+    pub fn pipe_proc(&mut self, session: &str, flags: &[&str]) -> anyhow::Result<process::Child> {
+        let log_file = self.tmp_dir.join(format!("pipe_{}.log", self.subproc_counter));
+        eprintln!("spawning pipe proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("pipe")
+            .args(flags)
+            .arg(session)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("spawning pipe proc")
+    }
+
+    // launches a `shpool control` process with piped stdio
+    pub fn control(&mut self) -> anyhow::Result<process::Child> {
+        let log_file = self.tmp_dir.join(format!("control_{}.log", self.subproc_counter));
+        eprintln!("spawning control proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("control")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("spawning control proc")
+    }
+
+    // launches a `shpool up` process
+    pub fn up(&mut self, file: &Path) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("up_{}.log", self.subproc_counter));
+        eprintln!("spawning up proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("up")
+            .arg(file)
+            .output()
+            .context("spawning up proc")
+    }
+
+    pub fn stat(&mut self, session: &str) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("stat_{}.log", self.subproc_counter));
+        eprintln!("spawning stat proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("stat")
+            .arg(session)
+            .output()
+            .context("spawning stat proc")
+    }
+
+    pub fn replay(&mut self, file: &Path, flags: &[&str]) -> anyhow::Result<process::Output> {
+        let log_file = self.tmp_dir.join(format!("replay_{}.log", self.subproc_counter));
+        eprintln!("spawning replay proc with log {:?}", &log_file);
+        self.subproc_counter += 1;
+
+        Command::new(shpool_bin()?)
+            .arg("-vv")
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .arg("replay")
+            .args(flags)
+            .arg(file)
+            .output()
+            .context("spawning replay proc")
+    }
+
     // launches a `shpool set-log-level` process
     pub fn set_log_level(&mut self, level: &str) -> anyhow::Result<process::Output> {
         let log_file = self.tmp_dir.join(format!("set_log_level_{}.log", self.subproc_counter));
@@ -0,0 +1,60 @@
+use std::fs;
+
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn creates_project_sessions() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        let project_dir = daemon_proc.tmp_dir.join("project");
+        fs::create_dir_all(project_dir.join("sub"))?;
+        let project_file = project_dir.join("shpool.toml");
+        fs::write(
+            &project_file,
+            r#"
+            [sessions.proj-a]
+            startup = "export UP_STARTUP=yes"
+            [sessions.proj-b]
+            dir = "sub"
+            "#,
+        )?;
+
+        let out = daemon_proc.up(&project_file)?;
+        assert!(out.status.success(), "up proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("created proj-a"));
+        assert!(stdout.contains("created proj-b"));
+
+        let out = daemon_proc.list()?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("proj-a"));
+        assert!(stdout.contains("proj-b"));
+
+        let out =
+            daemon_proc.exec("proj-a", "echo marker-$UP_STARTUP", &["--until", "marker-yes"])?;
+        assert!(out.status.success(), "startup command did not run");
+        let marker = format!("dir-{}", project_dir.join("sub").display());
+        let out = daemon_proc.exec("proj-b", "echo dir-$PWD", &["--until", &marker])?;
+        assert!(out.status.success(), "session did not start in its dir");
+
+        // running it again leaves the sessions alone
+        let out = daemon_proc.up(&project_file)?;
+        assert!(out.status.success(), "second up proc did not exit successfully");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("proj-a is already running"));
+        assert!(stdout.contains("proj-b is already running"));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,101 @@
+use anyhow::Context;
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn exit_status() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo ready")?;
+        line_matcher.scan_until_re("ready$")?;
+
+        let out = daemon_proc.exec("sh1", "sleep 1; exit 3", &[])?;
+        assert!(out.status.success(), "exec proc did not exit successfully");
+
+        let out = daemon_proc.wait("sh1", &[])?;
+        assert_eq!(out.status.code(), Some(3));
+
+        // the attached client goes away with the same status
+        let exit_status = attach_proc.proc.wait().context("waiting for attach proc")?;
+        assert_eq!(exit_status.code(), Some(3));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn already_exited() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "reap_exited_never.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo ready")?;
+            line_matcher.scan_until_re("ready$")?;
+            attach_proc.run_cmd("sleep 1; exit 4")?;
+        }
+        daemon_proc.wait_until_list_matches(|out| out.contains("exited(4)"))?;
+
+        let out = daemon_proc.wait("sh1", &[])?;
+        assert_eq!(out.status.code(), Some(4));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn activity() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo ready")?;
+            line_matcher.scan_until_re("ready$")?;
+            attach_proc.run_cmd("sleep 1; echo done")?;
+        }
+
+        let out = daemon_proc.wait("sh1", &["--activity"])?;
+        assert!(out.status.success(), "wait proc did not exit successfully");
+        daemon_proc.wait_until_list_matches(|out| out.contains("(activity)"))?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn not_found() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = daemon_proc.wait("nope", &[])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nope"));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,111 @@
+use std::{thread, time};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+#[test]
+#[timeout(30000)]
+fn runs_exec_on_match() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let fired_file = daemon_proc.tmp_dir.join("fired");
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+            attach_proc.run_cmd("echo ready")?;
+            line_matcher.scan_until_re("ready$")?;
+
+            let exec = format!("echo \"$SHPOOL_WATCH_LINE\" > {}", fired_file.display());
+            let out = daemon_proc.watch("sh1", &["--pattern", "BO+M", "--exec", &exec])?;
+            assert!(out.status.success(), "watch proc did not exit successfully");
+
+            // the quotes keep the command line itself from matching
+            attach_proc.run_cmd("sleep 1; echo BO''OM")?;
+        }
+
+        daemon_proc.wait_until_list_matches(|out| out.contains("matched"))?;
+        let mut sleep_dur = time::Duration::from_millis(5);
+        while !fired_file.exists() {
+            if sleep_dur > time::Duration::from_secs(5) {
+                return Err(anyhow!("exec command never ran"));
+            }
+            thread::sleep(sleep_dur);
+            sleep_dur *= 2;
+        }
+
+        let out = daemon_proc.watch("sh1", &[])?;
+        assert!(out.status.success());
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.starts_with("BO+M\techo"), "unexpected watches: {stdout}");
+        assert!(stdout.ends_with("\tshpool watch\n"));
+
+        let out = daemon_proc.watch("sh1", &["--clear"])?;
+        assert!(out.status.success());
+        let out = daemon_proc.watch("sh1", &[])?;
+        assert!(out.stdout.is_empty());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn bad_pattern() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+
+        let out = daemon_proc.watch("sh1", &["--pattern", "(unclosed"])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("bad pattern"));
+
+        let out = daemon_proc.watch("nosuchsession", &["--pattern", "x"])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("not found: nosuchsession"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn locked() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo hi")?;
+        line_matcher.scan_until_re("hi$")?;
+        libshpool::client::Client::new(&daemon_proc.socket_path).lock("sh1", "hunter2")?;
+
+        let fired_file = daemon_proc.tmp_dir.join("fired");
+        let exec = format!("touch {}", fired_file.display());
+        let out = daemon_proc.watch("sh1", &["--pattern", "hi", "--exec", &exec])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("session 'sh1' is locked"));
+
+        let out = daemon_proc.watch("sh1", &[])?;
+        assert!(!out.status.success());
+
+        Ok(())
+    })
+}